@@ -38,6 +38,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         start_time,
         duration,
         candidates,
+        issuance_log: false,
+        token_scheme: String::new(),
+        anonymous_requests: false,
+        encrypted_tally: false,
+        assign_ids: false,
     });
 
     match client.add_election(request).await {
@@ -0,0 +1,195 @@
+/*! backup.rs — Recovery bundle of the EC
+Everything another machine needs to take over from an EC that failed
+mid-election: the RSA and Nostr keys as they are stored, a snapshot of the
+database and the configuration, in one file encrypted with the EC key
+passphrase (the same PBES2 format as the keys, under its own PEM label).
+Votes sent while the EC was down are picked up by the backfill once the
+restored EC starts. */
+
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::database::Database;
+use crate::identity::NOSTR_CONNECT_KEY_FILE;
+use crate::keystore::{PBKDF2_ITERATIONS, decrypt_pem, encrypt_pem_with};
+use crate::settings::CONFIG_FILE;
+use crate::signer::CONTENT_KEY_FILE;
+use crate::trustees::TRUSTEES_FILE;
+use crate::util::write_private_file;
+
+const BUNDLE_LABEL: &str = "CRIPTOCRACIA RECOVERY BUNDLE";
+
+const BUNDLE_VERSION: u32 = 1;
+
+/// Name of the database in the app directory.
+pub const DATABASE_FILE: &str = "elections.db";
+
+/// Name of the database of a rehearsal (`--dry-run`), never backed up.
+pub const REHEARSAL_DATABASE_FILE: &str = "rehearsal.db";
+
+/// Configuration files of the app directory carried in the bundle.
+const CONFIG_FILES: [&str; 4] = [CONFIG_FILE, TRUSTEES_FILE, NOSTR_CONNECT_KEY_FILE, CONTENT_KEY_FILE];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecoveryBundle {
+    pub version: u32,
+    pub created_at: u64,
+    /// RSA private key PEM as stored, maybe encrypted; none when tokens are
+    /// signed by a PKCS#11 token or by trustees
+    pub rsa_private_key: Option<String>,
+    pub rsa_public_key: String,
+    /// Nostr secret key as stored: hex, nsec or ncryptsec; none when it's
+    /// held by a remote signer
+    pub nostr_key: Option<String>,
+    /// SQLite snapshot of the database, in Base64
+    pub database: String,
+    /// SHA-256 of the snapshot, in hex
+    pub database_sha256: String,
+    /// Configuration files of the app directory, by name
+    pub files: BTreeMap<String, String>,
+    /// Command line flags the EC ran with that voters depend on, such as
+    /// its relays and event kinds
+    pub flags: Vec<String>,
+}
+
+impl RecoveryBundle {
+    /// Collects the bundle of the EC in `app_dir`, snapshotting its database.
+    pub async fn create(
+        app_dir: &Path,
+        db: &Database,
+        rsa_private_key: Option<String>,
+        rsa_public_key: String,
+        nostr_key: Option<String>,
+        flags: Vec<String>,
+    ) -> Result<Self> {
+        let snapshot_path = app_dir.join(format!("{}.snapshot", DATABASE_FILE));
+        let _ = fs::remove_file(&snapshot_path);
+        db.snapshot(&snapshot_path).await?;
+        let snapshot = fs::read(&snapshot_path);
+        let _ = fs::remove_file(&snapshot_path);
+        let snapshot = snapshot?;
+
+        let mut files = BTreeMap::new();
+        for name in CONFIG_FILES {
+            let path = app_dir.join(name);
+            if path.exists() {
+                files.insert(name.to_string(), fs::read_to_string(&path)?);
+            }
+        }
+        Ok(Self {
+            version: BUNDLE_VERSION,
+            created_at: chrono::Utc::now().timestamp() as u64,
+            rsa_private_key,
+            rsa_public_key,
+            nostr_key,
+            database_sha256: nostr_sdk::util::hex::encode(Sha256::digest(&snapshot)),
+            database: general_purpose::STANDARD.encode(snapshot),
+            files,
+            flags,
+        })
+    }
+
+    /// The bundle encrypted with the passphrase, as a PEM.
+    pub fn encrypt(&self, passphrase: &str) -> Result<String> {
+        self.encrypt_with(passphrase, PBKDF2_ITERATIONS)
+    }
+
+    fn encrypt_with(&self, passphrase: &str, iterations: u32) -> Result<String> {
+        Ok(encrypt_pem_with(BUNDLE_LABEL, &serde_json::to_vec(self)?, passphrase, iterations))
+    }
+
+    pub fn decrypt(pem: &str, passphrase: &str) -> Result<Self> {
+        let json = decrypt_pem(BUNDLE_LABEL, "recovery bundle", pem, passphrase)?;
+        // CBC has no integrity check: a wrong passphrase may also come this far
+        let bundle: Self = serde_json::from_slice(&json)
+            .map_err(|_| anyhow::anyhow!("Wrong passphrase for the recovery bundle"))?;
+        if bundle.version != BUNDLE_VERSION {
+            return Err(anyhow::anyhow!("Unsupported recovery bundle version {}", bundle.version));
+        }
+        Ok(bundle)
+    }
+
+    /// Writes the keys, database and configuration files into `app_dir`,
+    /// the Nostr key to `nostr_key_path`. Nothing is written if any of them
+    /// exists already.
+    pub fn restore(&self, app_dir: &Path, nostr_key_path: &Path) -> Result<Vec<PathBuf>> {
+        let database = general_purpose::STANDARD
+            .decode(&self.database)
+            .map_err(|_| anyhow::anyhow!("Invalid database snapshot in the recovery bundle"))?;
+        if nostr_sdk::util::hex::encode(Sha256::digest(&database)) != self.database_sha256 {
+            return Err(anyhow::anyhow!("The database snapshot of the recovery bundle is corrupt"));
+        }
+
+        let mut writes: Vec<(PathBuf, &[u8])> = vec![
+            (app_dir.join(DATABASE_FILE), &database),
+            (app_dir.join("ec_public.pem"), self.rsa_public_key.as_bytes()),
+        ];
+        if let Some(nostr_key) = &self.nostr_key {
+            writes.push((nostr_key_path.to_path_buf(), nostr_key.as_bytes()));
+        }
+        if let Some(pem) = &self.rsa_private_key {
+            writes.push((app_dir.join("ec_private.pem"), pem.as_bytes()));
+        }
+        for (name, contents) in &self.files {
+            if !CONFIG_FILES.contains(&name.as_str()) {
+                return Err(anyhow::anyhow!("Unexpected file {} in the recovery bundle", name));
+            }
+            writes.push((app_dir.join(name), contents.as_bytes()));
+        }
+        if let Some((path, _)) = writes.iter().find(|(path, _)| path.exists()) {
+            return Err(anyhow::anyhow!("{} already exists, restore into an empty directory", path.display()));
+        }
+        for (path, contents) in &writes {
+            write_private_file(path, contents)?;
+        }
+        Ok(writes.into_iter().map(|(path, _)| path).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::election::Election;
+    use crate::types::Candidate;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_recovery_bundle_roundtrip() {
+        let source = TempDir::new().unwrap();
+        let db = Database::new(source.path().join(DATABASE_FILE)).await.unwrap();
+        let election = Election::new("Backup".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+        fs::write(source.path().join(TRUSTEES_FILE), "{\"threshold\":2,\"trustees\":3}").unwrap();
+
+        let flags = vec!["--relay=wss://relay.example".to_string()];
+        let bundle = RecoveryBundle::create(source.path(), &db, None, "public".to_string(), Some("nsec1x".to_string()), flags)
+            .await
+            .unwrap();
+        assert!(!source.path().join(format!("{}.snapshot", DATABASE_FILE)).exists());
+        // Few iterations, to keep the test fast
+        let pem = bundle.encrypt_with("correct horse", 1_000).unwrap();
+        assert!(RecoveryBundle::decrypt(&pem, "battery staple").is_err());
+        let decrypted = RecoveryBundle::decrypt(&pem, "correct horse").unwrap();
+        assert_eq!(decrypted, bundle);
+
+        let target = TempDir::new().unwrap();
+        let nostr_key_path = target.path().join("nostr_key");
+        let paths = decrypted.restore(target.path(), &nostr_key_path).unwrap();
+        assert_eq!(paths.len(), 4);
+        assert!(!target.path().join("ec_private.pem").exists());
+        assert_eq!(fs::read_to_string(&nostr_key_path).unwrap(), "nsec1x");
+        let restored = Database::new(target.path().join(DATABASE_FILE)).await.unwrap();
+        assert_eq!(restored.load_all_elections().await.unwrap()[0].id, election.id);
+        // Never over an existing EC
+        assert!(decrypted.restore(target.path(), &nostr_key_path).is_err());
+
+        let mut corrupt = bundle.clone();
+        corrupt.database_sha256 = "00".repeat(32);
+        assert!(corrupt.restore(TempDir::new().unwrap().path(), &nostr_key_path).is_err());
+    }
+}
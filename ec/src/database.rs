@@ -1,10 +1,12 @@
 use anyhow::Result;
 use chrono::Utc;
-use sqlx::{Pool, Sqlite, SqlitePool, Row, ConnectOptions};
-use std::{fs, path::Path, str::FromStr};
+use sqlx::{Connection, Pool, Sqlite, SqliteConnection, SqlitePool, Row, ConnectOptions, Transaction};
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+use criptocracia_protocol::{EncryptedBallot, PublishedBallot, TallyProof, Turnout};
 
 use crate::election::{Election, Status};
-use crate::types::Candidate;
+use crate::types::{Candidate, Voter};
 
 /// Database connection pool
 pub struct Database {
@@ -21,6 +23,13 @@ pub struct ElectionRecord {
     pub end_time: i64,
     pub status: String,
     pub rsa_pub_key: String,
+    pub issuance_log: bool,
+    pub token_scheme: String,
+    pub bound_tokens: bool,
+    pub anonymous_requests: bool,
+    pub rehearsal: bool,
+    pub tally_key: Option<String>,
+    pub tally_proof: Option<String>,
     pub created_at: i64,
     pub updated_at: i64,
 }
@@ -41,9 +50,311 @@ pub struct CandidateRecord {
 #[allow(dead_code)]
 pub struct VoterRecord {
     pub id: Option<i64>,
+    pub election_id: String,
     pub pubkey: String,
-    pub reference: String,
+    pub name: String,
+    pub created_at: i64,
+}
+
+/// Processed gift wrap record for database
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct MessageLogRecord {
+    pub id: Option<i64>,
+    pub event_id: String,
+    pub election_id: Option<String>,
+    pub message_kind: Option<i64>,
+    pub outcome: String,
+    pub reason: Option<String>,
+    pub created_at: i64,
+}
+
+/// Tally snapshot record for database
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct ResultsSnapshotRecord {
+    pub id: Option<i64>,
+    pub election_id: String,
+    pub total_votes: i64,
+    pub results: Vec<(u8, u32)>,
+    pub created_at: i64,
+}
+
+/// Published Nostr event record for database
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct PublishedEventRecord {
+    pub id: Option<i64>,
+    pub event_id: String,
+    pub election_id: String,
+    pub kind: i64,
+    pub relay_url: String,
+    pub created_at: i64,
+}
+
+/// Event no relay accepted yet, kept until one does
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct OutboxRecord {
+    pub id: Option<i64>,
+    pub event_id: String,
+    pub kind: i64,
+    pub event_json: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub next_attempt_at: i64,
+    pub created_at: i64,
+}
+
+/// OpenTimestamps proof of an election's final results
+#[derive(Debug)]
+pub struct ResultsTimestampRecord {
+    pub election_id: String,
+    /// Final results event whose ID is timestamped
+    pub event_id: String,
+    /// Contents of the `.ots` file
+    pub proof: Vec<u8>,
+}
+
+/// Token request waiting for the signature shares of the trustees
+#[derive(Debug, Clone)]
+pub struct SignatureRequestRecord {
+    pub id: String,
+    pub election_id: String,
+    pub voter_pubkey: String,
+    /// Token request message, answered with the signature
+    pub message: String,
+    pub blinded_message: Vec<u8>,
     pub created_at: i64,
+    /// Shares submitted so far with their proofs, by trustee index
+    pub shares: Vec<(u32, Vec<u8>, Vec<u8>)>,
+}
+
+/// Token issuance log record for database
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct TokenIssuanceRecord {
+    pub id: Option<i64>,
+    pub election_id: String,
+    pub voter_pubkey: String,
+    pub issued_at: i64,
+}
+
+/// Number of rows removed when purging an election's data
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PurgeStats {
+    pub voters_removed: u64,
+    pub tokens_removed: u64,
+    pub messages_removed: u64,
+    pub issuances_removed: u64,
+}
+
+impl PurgeStats {
+    pub fn total(&self) -> u64 {
+        self.voters_removed + self.tokens_removed + self.messages_removed + self.issuances_removed
+    }
+}
+
+/// Tables holding rows that belong to an election
+const ELECTION_TABLES: &[&str] = &[
+    "candidates",
+    "election_voters",
+    "used_tokens",
+    "message_log",
+    "results_history",
+    "published_events",
+    "token_issuances",
+    "ballots",
+    "results_timestamps",
+    "signature_requests",
+    "key_images",
+];
+
+/// Tables copied to standby ECs, parents before their children
+pub const REPLICATED_TABLES: &[&str] = &[
+    "elections",
+    "candidates",
+    "election_voters",
+    "used_tokens",
+    "key_images",
+    "token_issuances",
+    "ballots",
+    "results_history",
+    "results_timestamps",
+    "signature_requests",
+    "signature_shares",
+    "message_log",
+    "outbox",
+];
+
+/// Change to a row of a replicated table
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplicatedChange {
+    /// Position in the replication log, 0 for the rows of a full copy
+    pub seq: i64,
+    pub table: String,
+    /// Deleted rows only have their primary key
+    pub deleted: bool,
+    /// JSON object of the columns, BLOBs in hex
+    pub row: String,
+}
+
+/// Election whose candidate vote counts do not match its used tokens
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoteCountMismatch {
+    pub election_id: String,
+    pub counted_votes: i64,
+    pub used_tokens: i64,
+}
+
+/// Anomalies found by the database integrity check
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IntegrityReport {
+    /// Tables with rows pointing at elections that do not exist
+    pub orphan_rows: Vec<(&'static str, u64)>,
+    pub vote_mismatches: Vec<VoteCountMismatch>,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.orphan_rows.is_empty() && self.vote_mismatches.is_empty()
+    }
+
+    pub fn orphan_total(&self) -> u64 {
+        self.orphan_rows.iter().map(|(_, count)| count).sum()
+    }
+}
+
+/// Tables that can be exported for external reporting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportTable {
+    Elections,
+    Voters,
+    Candidates,
+    UsedTokens,
+}
+
+impl ExportTable {
+    /// Parse the table name used by the export API
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "elections" => Some(ExportTable::Elections),
+            "voters" => Some(ExportTable::Voters),
+            "candidates" => Some(ExportTable::Candidates),
+            "used_tokens" => Some(ExportTable::UsedTokens),
+            _ => None,
+        }
+    }
+
+    /// Exported columns and whether they hold integers
+    fn columns(&self) -> &'static [(&'static str, bool)] {
+        match self {
+            ExportTable::Elections => &[
+                ("id", false),
+                ("name", false),
+                ("start_time", true),
+                ("end_time", true),
+                ("status", false),
+                ("rsa_pub_key", false),
+                ("issuance_log", true),
+                ("token_scheme", false),
+                ("bound_tokens", true),
+                ("anonymous_requests", true),
+                ("rehearsal", true),
+                ("tally_key", false),
+                ("tally_proof", false),
+                ("created_at", true),
+                ("updated_at", true),
+            ],
+            ExportTable::Voters => &[
+                ("election_id", false),
+                ("voter_pubkey", false),
+                ("name", false),
+                ("token_issued", true),
+                ("created_at", true),
+            ],
+            ExportTable::Candidates => &[
+                ("election_id", false),
+                ("candidate_id", true),
+                ("name", false),
+                ("vote_count", true),
+            ],
+            ExportTable::UsedTokens => &[
+                ("election_id", false),
+                ("token_hash", false),
+                ("created_at", true),
+            ],
+        }
+    }
+
+    /// Query selecting the exported columns, filtered by election ID
+    fn query(&self, filtered: bool) -> String {
+        let (table, election_column) = match self {
+            ExportTable::Elections => ("elections", "id"),
+            ExportTable::Voters => ("election_voters", "election_id"),
+            ExportTable::Candidates => ("candidates", "election_id"),
+            ExportTable::UsedTokens => ("used_tokens", "election_id"),
+        };
+        let columns: Vec<&str> = self.columns().iter().map(|(name, _)| *name).collect();
+        let filter = if filtered {
+            format!(" WHERE {} = ?", election_column)
+        } else {
+            String::new()
+        };
+        format!("SELECT {} FROM {}{} ORDER BY id", columns.join(", "), table, filter)
+    }
+}
+
+/// Rows of an exported table
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableExport {
+    pub columns: Vec<&'static str>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+}
+
+impl TableExport {
+    /// Render as CSV with a header line
+    pub fn to_csv(&self) -> String {
+        let mut csv = self.columns.join(",");
+        csv.push('\n');
+        for row in &self.rows {
+            let fields: Vec<String> = row
+                .iter()
+                .map(|value| match value {
+                    serde_json::Value::String(s) => csv_field(s),
+                    other => other.to_string(),
+                })
+                .collect();
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// Render as a JSON array of objects keyed by column name
+    pub fn to_json(&self) -> Result<String> {
+        let objects: Vec<serde_json::Map<String, serde_json::Value>> = self
+            .rows
+            .iter()
+            .map(|row| {
+                self.columns
+                    .iter()
+                    .map(|c| c.to_string())
+                    .zip(row.iter().cloned())
+                    .collect()
+            })
+            .collect();
+        Ok(serde_json::to_string(&objects)?)
+    }
+}
+
+/// Quote a CSV field when it contains separators, quotes or line breaks
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
 }
 
 impl Database {
@@ -89,6 +400,13 @@ impl Database {
                 end_time INTEGER NOT NULL,
                 status TEXT NOT NULL,
                 rsa_pub_key TEXT NOT NULL,
+                issuance_log INTEGER NOT NULL DEFAULT 0,
+                token_scheme TEXT NOT NULL DEFAULT 'rsa-pss-randomized',
+                bound_tokens INTEGER NOT NULL DEFAULT 0,
+                anonymous_requests INTEGER NOT NULL DEFAULT 0,
+                rehearsal INTEGER NOT NULL DEFAULT 0,
+                tally_key TEXT,
+                tally_proof TEXT,
                 created_at INTEGER NOT NULL,
                 updated_at INTEGER NOT NULL
             )
@@ -131,6 +449,23 @@ impl Database {
         .execute(&self.pool)
         .await?;
 
+        // Key images of the anonymous token requests served, one per voter
+        // and election, without telling which voter it is
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS key_images (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                election_id TEXT NOT NULL,
+                key_image TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY (election_id) REFERENCES elections(id),
+                UNIQUE(election_id, key_image)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
         // Create election_voters table to track authorized voters per election
         sqlx::query(
             r#"
@@ -138,8 +473,78 @@ impl Database {
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 election_id TEXT NOT NULL,
                 voter_pubkey TEXT NOT NULL,
+                name TEXT NOT NULL DEFAULT '',
+                token_issued INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY (election_id) REFERENCES elections(id),
+                UNIQUE(election_id, voter_pubkey)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create message_log table to record every processed gift wrap and its outcome
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS message_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                event_id TEXT NOT NULL UNIQUE,
+                election_id TEXT,
+                message_kind INTEGER,
+                outcome TEXT NOT NULL,
+                reason TEXT,
+                created_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create results_history table to keep tally snapshots over time
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS results_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                election_id TEXT NOT NULL,
+                total_votes INTEGER NOT NULL,
+                results TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY (election_id) REFERENCES elections(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create published_events table to record which relays accepted each EC event
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS published_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                event_id TEXT NOT NULL,
+                election_id TEXT NOT NULL,
+                kind INTEGER NOT NULL,
+                relay_url TEXT NOT NULL,
                 created_at INTEGER NOT NULL,
                 FOREIGN KEY (election_id) REFERENCES elections(id),
+                UNIQUE(event_id, relay_url)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create token_issuances table for elections that disclose an issuance log.
+        // Only the voter and the time are stored, never the token itself.
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS token_issuances (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                election_id TEXT NOT NULL,
+                voter_pubkey TEXT NOT NULL,
+                issued_at INTEGER NOT NULL,
+                FOREIGN KEY (election_id) REFERENCES elections(id),
                 UNIQUE(election_id, voter_pubkey)
             )
             "#,
@@ -147,53 +552,329 @@ impl Database {
         .execute(&self.pool)
         .await?;
 
+        // Create outbox table for events no relay accepted yet, retried until one does
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS outbox (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                event_id TEXT NOT NULL UNIQUE,
+                kind INTEGER NOT NULL,
+                event_json TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                last_error TEXT,
+                next_attempt_at INTEGER NOT NULL,
+                created_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create ballots table for the accepted ballots published on the bulletin
+        // board. They are the public record of the election and never purged.
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS ballots (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                election_id TEXT NOT NULL,
+                h_n TEXT NOT NULL,
+                choices TEXT NOT NULL,
+                encrypted TEXT,
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY (election_id) REFERENCES elections(id),
+                UNIQUE(election_id, h_n)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create results_timestamps table for the OpenTimestamps proofs of final
+        // results, kept pending until they reach a Bitcoin block
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS results_timestamps (
+                election_id TEXT PRIMARY KEY,
+                event_id TEXT NOT NULL,
+                proof BLOB NOT NULL,
+                attestation_event_id TEXT,
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY (election_id) REFERENCES elections(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create signature_requests table for the token requests waiting for
+        // the trustees, and signature_shares for the shares they submit
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS signature_requests (
+                id TEXT PRIMARY KEY,
+                election_id TEXT NOT NULL,
+                voter_pubkey TEXT NOT NULL,
+                message TEXT NOT NULL,
+                blinded_message BLOB NOT NULL,
+                created_at INTEGER NOT NULL,
+                completed_at INTEGER,
+                FOREIGN KEY (election_id) REFERENCES elections(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS signature_shares (
+                request_id TEXT NOT NULL,
+                trustee_index INTEGER NOT NULL,
+                share BLOB NOT NULL,
+                proof BLOB NOT NULL DEFAULT x'',
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (request_id, trustee_index),
+                FOREIGN KEY (request_id) REFERENCES signature_requests(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create relay_events table for the events stored by the local relay
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS relay_events (
+                event_id TEXT PRIMARY KEY,
+                event_json TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create ec_state table for values the EC keeps across restarts
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS ec_state (
+                key TEXT PRIMARY KEY,
+                value INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Create replication_log table for the changes to the replicated
+        // tables, streamed to the standby ECs
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS replication_log (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                table_name TEXT NOT NULL,
+                deleted INTEGER NOT NULL,
+                row TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Bring tables created by older versions up to date
+        self.migrate().await?;
+
         // Create indexes for better performance
         self.create_indexes().await?;
 
+        // Log the changes, with the columns the migrations left
+        self.create_replication_triggers().await?;
+
         log::info!("Database tables created successfully");
         Ok(())
     }
 
-    /// Create database indexes for better performance
-    async fn create_indexes(&self) -> Result<()> {
-        // Index for election_voters table - frequently queried by election_id
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_election_voters_election_id ON election_voters(election_id)")
-            .execute(&self.pool)
+    /// Add columns introduced after the initial schema to existing databases
+    async fn migrate(&self) -> Result<()> {
+        self.add_column_if_missing("election_voters", "name", "TEXT NOT NULL DEFAULT ''")
             .await?;
-
-        // Index for candidates table - frequently queried by election_id  
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_candidates_election_id ON candidates(election_id)")
-            .execute(&self.pool)
+        self.add_column_if_missing("election_voters", "token_issued", "INTEGER NOT NULL DEFAULT 0")
+            .await?;
+        self.add_column_if_missing("elections", "issuance_log", "INTEGER NOT NULL DEFAULT 0")
+            .await?;
+        self.add_column_if_missing("elections", "token_scheme", "TEXT NOT NULL DEFAULT 'rsa-pss-randomized'")
+            .await?;
+        self.add_column_if_missing("elections", "bound_tokens", "INTEGER NOT NULL DEFAULT 0")
+            .await?;
+        self.add_column_if_missing("elections", "anonymous_requests", "INTEGER NOT NULL DEFAULT 0")
+            .await?;
+        self.add_column_if_missing("elections", "rehearsal", "INTEGER NOT NULL DEFAULT 0")
+            .await?;
+        self.add_column_if_missing("elections", "tally_key", "TEXT").await?;
+        self.add_column_if_missing("elections", "tally_proof", "TEXT").await?;
+        self.add_column_if_missing("ballots", "encrypted", "TEXT").await?;
+        self.add_column_if_missing("signature_shares", "proof", "BLOB NOT NULL DEFAULT x''")
             .await?;
 
-        // Index for used_tokens table - frequently queried by election_id
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_used_tokens_election_id ON used_tokens(election_id)")
-            .execute(&self.pool)
+        Ok(())
+    }
+
+    /// Add a column to an existing table if it is not there yet
+    async fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) -> Result<()> {
+        let rows = sqlx::query(&format!("PRAGMA table_info({})", table))
+            .fetch_all(&self.pool)
             .await?;
 
-        // Index for election_voters voter_pubkey lookups
-        sqlx::query("CREATE INDEX IF NOT EXISTS idx_election_voters_pubkey ON election_voters(voter_pubkey)")
+        if rows.iter().any(|row| row.get::<String, _>("name") == column) {
+            return Ok(());
+        }
+
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
             .execute(&self.pool)
             .await?;
 
-        log::info!("Database indexes created successfully");
+        log::info!("Added column {} to table {}", column, table);
         Ok(())
     }
 
-    /// Insert or update an election
-    pub async fn upsert_election(&self, election: &Election) -> Result<()> {
-        let now = Utc::now().timestamp();
-        let status_str = match election.status {
+    /// Columns of a table: name, whether it holds a BLOB and whether it's
+    /// part of the primary key
+    async fn table_columns(&self, table: &str) -> Result<Vec<(String, bool, bool)>> {
+        let rows = sqlx::query(&format!("PRAGMA table_info({})", table))
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let kind: String = row.get("type");
+                (row.get("name"), kind.eq_ignore_ascii_case("BLOB"), row.get::<i64, _>("pk") > 0)
+            })
+            .collect())
+    }
+
+    /// Record every change to the replicated tables in the replication log.
+    /// The triggers are made again on each start, to cover added columns.
+    async fn create_replication_triggers(&self) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for table in REPLICATED_TABLES {
+            let columns = self.table_columns(table).await?;
+            let key: Vec<_> = columns.iter().filter(|(_, _, pk)| *pk).cloned().collect();
+            for (operation, deleted, row) in [
+                ("INSERT", 0, json_row(&columns, "NEW.")),
+                ("UPDATE", 0, json_row(&columns, "NEW.")),
+                ("DELETE", 1, json_row(&key, "OLD.")),
+            ] {
+                let trigger = format!("replicate_{}_{}", table, operation.to_lowercase());
+                sqlx::query(&format!("DROP TRIGGER IF EXISTS {}", trigger))
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query(&format!(
+                    r#"
+                    CREATE TRIGGER {trigger} AFTER {operation} ON {table}
+                    BEGIN
+                        INSERT INTO replication_log (table_name, deleted, row, created_at)
+                        VALUES ('{table}', {deleted}, {row}, CAST(strftime('%s', 'now') AS INTEGER));
+                    END
+                    "#
+                ))
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Create database indexes for better performance
+    async fn create_indexes(&self) -> Result<()> {
+        // Index for election_voters table - frequently queried by election_id
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_election_voters_election_id ON election_voters(election_id)")
+            .execute(&self.pool)
+            .await?;
+
+        // Index for candidates table - frequently queried by election_id  
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_candidates_election_id ON candidates(election_id)")
+            .execute(&self.pool)
+            .await?;
+
+        // Index for used_tokens table - frequently queried by election_id
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_used_tokens_election_id ON used_tokens(election_id)")
+            .execute(&self.pool)
+            .await?;
+
+        // Index for election_voters voter_pubkey lookups
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_election_voters_pubkey ON election_voters(voter_pubkey)")
+            .execute(&self.pool)
+            .await?;
+
+        // Index for message_log table - queried by election_id for audits
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_message_log_election_id ON message_log(election_id)")
+            .execute(&self.pool)
+            .await?;
+
+        // Index for results_history table - queried by election_id in time order
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_results_history_election_id ON results_history(election_id, created_at)")
+            .execute(&self.pool)
+            .await?;
+
+        // Index for token_issuances table - queried by election_id in time order
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_token_issuances_election_id ON token_issuances(election_id, issued_at)")
+            .execute(&self.pool)
+            .await?;
+
+        // Index for published_events table - queried by election_id and kind
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_published_events_election_id ON published_events(election_id, kind)")
+            .execute(&self.pool)
+            .await?;
+
+        // Index for ballots table - queried by election_id
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_ballots_election_id ON ballots(election_id)")
+            .execute(&self.pool)
+            .await?;
+
+        // Index for outbox table - queried by next attempt time
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_outbox_next_attempt_at ON outbox(next_attempt_at)")
+            .execute(&self.pool)
+            .await?;
+
+        // Candidate names are unique in an election, whatever their case.
+        // Databases from before may hold duplicates, which are left alone
+        let duplicates: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM (SELECT 1 FROM candidates GROUP BY election_id, lower(name) HAVING COUNT(*) > 1)",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        if duplicates == 0 {
+            sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS idx_candidates_election_name ON candidates(election_id, lower(name))")
+                .execute(&self.pool)
+                .await?;
+        } else {
+            log::warn!("{} candidate names are repeated within an election, they aren't made unique", duplicates);
+        }
+
+        log::info!("Database indexes created successfully");
+        Ok(())
+    }
+
+    /// Insert or update an election
+    pub async fn upsert_election(&self, election: &Election) -> Result<()> {
+        let now = Utc::now().timestamp();
+        let status_str = match election.status {
             Status::Open => "open",
             Status::InProgress => "in-progress",
             Status::Finished => "finished", 
             Status::Canceled => "canceled",
         };
 
+        // Election and candidates are written together or not at all
+        let mut tx = self.pool.begin().await?;
+
         // Check if election exists
         let exists = sqlx::query("SELECT 1 FROM elections WHERE id = ?")
             .bind(&election.id)
-            .fetch_optional(&self.pool)
+            .fetch_optional(&mut *tx)
             .await?
             .is_some();
 
@@ -203,7 +884,8 @@ impl Database {
                 r#"
                 UPDATE elections 
                 SET name = ?, start_time = ?, end_time = ?, status = ?, 
-                    rsa_pub_key = ?, updated_at = ?
+                    rsa_pub_key = ?, issuance_log = ?, token_scheme = ?, bound_tokens = ?, anonymous_requests = ?,
+                    rehearsal = ?, tally_key = ?, tally_proof = ?, updated_at = ?
                 WHERE id = ?
                 "#,
             )
@@ -212,19 +894,25 @@ impl Database {
             .bind(election.end_time as i64)
             .bind(status_str)
             .bind(&election.rsa_pub_key)
+            .bind(election.issuance_log)
+            .bind(election.token_scheme.as_str())
+            .bind(election.bound_tokens)
+            .bind(election.anonymous_requests)
+            .bind(election.rehearsal)
+            .bind(&election.tally_key)
+            .bind(election.tally_proof.as_ref().map(TallyProof::as_json))
             .bind(now)
             .bind(&election.id)
-            .execute(&self.pool)
+            .execute(&mut *tx)
             .await?;
-
-            log::info!("Updated election {} in database", election.id);
         } else {
             // Insert new election
             sqlx::query(
                 r#"
                 INSERT INTO elections 
-                (id, name, start_time, end_time, status, rsa_pub_key, created_at, updated_at)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                (id, name, start_time, end_time, status, rsa_pub_key, issuance_log, token_scheme, bound_tokens,
+                 anonymous_requests, rehearsal, tally_key, tally_proof, created_at, updated_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
                 "#,
             )
             .bind(&election.id)
@@ -233,22 +921,39 @@ impl Database {
             .bind(election.end_time as i64)
             .bind(status_str)
             .bind(&election.rsa_pub_key)
+            .bind(election.issuance_log)
+            .bind(election.token_scheme.as_str())
+            .bind(election.bound_tokens)
+            .bind(election.anonymous_requests)
+            .bind(election.rehearsal)
+            .bind(&election.tally_key)
+            .bind(election.tally_proof.as_ref().map(TallyProof::as_json))
             .bind(now)
             .bind(now)
-            .execute(&self.pool)
+            .execute(&mut *tx)
             .await?;
-
-            log::info!("Inserted new election {} into database", election.id);
         }
 
         // Insert/update candidates
-        self.upsert_candidates(&election.id, &election.candidates).await?;
+        Self::upsert_candidates_in(&mut tx, &election.id, &election.candidates).await?;
+
+        tx.commit().await?;
+
+        if exists {
+            log::info!("Updated election {} in database", election.id);
+        } else {
+            log::info!("Inserted new election {} into database", election.id);
+        }
 
         Ok(())
     }
 
-    /// Insert or update candidates for an election
-    pub async fn upsert_candidates(&self, election_id: &str, candidates: &[Candidate]) -> Result<()> {
+    /// Insert or update candidates for an election inside an open transaction
+    async fn upsert_candidates_in(
+        tx: &mut Transaction<'_, Sqlite>,
+        election_id: &str,
+        candidates: &[Candidate],
+    ) -> Result<()> {
         for candidate in candidates {
             sqlx::query(
                 r#"
@@ -261,7 +966,7 @@ impl Database {
             .bind(election_id)
             .bind(candidate.id as i64)
             .bind(&candidate.name)
-            .execute(&self.pool)
+            .execute(&mut **tx)
             .await?;
         }
 
@@ -269,8 +974,66 @@ impl Database {
         Ok(())
     }
 
-    /// Update candidate vote counts
-    pub async fn update_vote_counts(&self, election_id: &str, vote_counts: &[(u8, u32)]) -> Result<()> {
+    /// Record an accepted vote: the spent token, the ballot for the bulletin
+    /// board and the new candidate vote counts are committed in a single transaction
+    pub async fn record_vote(
+        &self,
+        election_id: &str,
+        token_hash: &str,
+        ballot: &PublishedBallot,
+        vote_counts: &[(u8, u32)],
+    ) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO used_tokens (election_id, token_hash, created_at)
+            VALUES (?, ?, ?)
+            "#,
+        )
+        .bind(election_id)
+        .bind(token_hash)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO ballots (election_id, h_n, choices, encrypted, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(election_id)
+        .bind(&ballot.h_n)
+        .bind(serde_json::to_string(&ballot.choices)?)
+        .bind(ballot.encrypted.as_ref().map(EncryptedBallot::as_json))
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        for (candidate_id, count) in vote_counts {
+            sqlx::query(
+                "UPDATE candidates SET vote_count = ? WHERE election_id = ? AND candidate_id = ?"
+            )
+            .bind(*count as i64)
+            .bind(election_id)
+            .bind(*candidate_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        log::info!("Recorded vote and updated vote counts for election {}", election_id);
+        Ok(())
+    }
+
+    /// Record the decrypted tally of an election with an encrypted tally: the
+    /// candidate vote counts and the proof they were decrypted with, together
+    pub async fn record_tally(&self, election_id: &str, vote_counts: &[(u8, u32)], proof: &TallyProof) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
         for (candidate_id, count) in vote_counts {
             sqlx::query(
                 "UPDATE candidates SET vote_count = ? WHERE election_id = ? AND candidate_id = ?"
@@ -278,14 +1041,185 @@ impl Database {
             .bind(*count as i64)
             .bind(election_id)
             .bind(*candidate_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        sqlx::query("UPDATE elections SET tally_proof = ?, updated_at = ? WHERE id = ?")
+            .bind(proof.as_json())
+            .bind(chrono::Utc::now().timestamp())
+            .bind(election_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        log::info!("Recorded the tally of election {}", election_id);
+        Ok(())
+    }
+
+    /// Record the key image of an anonymous token request. Returns false if
+    /// the voter it stands for was already served in the election.
+    pub async fn record_key_image(&self, election_id: &str, key_image: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "INSERT OR IGNORE INTO key_images (election_id, key_image, created_at) VALUES (?, ?, ?)"
+        )
+        .bind(election_id)
+        .bind(key_image)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Forget a key image whose token couldn't be issued, so the voter can ask again
+    pub async fn release_key_image(&self, election_id: &str, key_image: &str) -> Result<()> {
+        sqlx::query("DELETE FROM key_images WHERE election_id = ? AND key_image = ?")
+            .bind(election_id)
+            .bind(key_image)
             .execute(&self.pool)
             .await?;
+        Ok(())
+    }
+
+    /// Mark that a voter has been issued a blind signature token,
+    /// so the voter is not authorized again after a restart.
+    /// With `log_issuance` the voter and time are also added to the issuance log.
+    pub async fn mark_token_issued(
+        &self,
+        election_id: &str,
+        voter_pubkey: &str,
+        log_issuance: bool,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query(
+            "UPDATE election_voters SET token_issued = 1 WHERE election_id = ? AND voter_pubkey = ? AND token_issued = 0"
+        )
+        .bind(election_id)
+        .bind(voter_pubkey)
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            anyhow::bail!("Voter {} is not awaiting a token in election {}", voter_pubkey, election_id);
+        }
+
+        if log_issuance {
+            sqlx::query(
+                "INSERT INTO token_issuances (election_id, voter_pubkey, issued_at) VALUES (?, ?, ?)"
+            )
+            .bind(election_id)
+            .bind(voter_pubkey)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&mut *tx)
+            .await?;
         }
 
-        log::info!("Updated vote counts for election {}", election_id);
+        tx.commit().await?;
+
+        log::debug!("Marked token issued for a voter in election {}", election_id);
         Ok(())
     }
 
+    /// Get the token issuance log of an election, oldest first
+    pub async fn get_token_issuances(
+        &self,
+        election_id: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<TokenIssuanceRecord>> {
+        let limit = if limit == 0 { 100 } else { limit.min(1000) }; // Default limit, max 1000
+
+        let rows = sqlx::query(
+            "SELECT * FROM token_issuances WHERE election_id = ? ORDER BY issued_at, id LIMIT ? OFFSET ?"
+        )
+        .bind(election_id)
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let issuances = rows
+            .into_iter()
+            .map(|row| TokenIssuanceRecord {
+                id: Some(row.get("id")),
+                election_id: row.get("election_id"),
+                voter_pubkey: row.get("voter_pubkey"),
+                issued_at: row.get("issued_at"),
+            })
+            .collect();
+
+        Ok(issuances)
+    }
+
+    /// Get the accepted ballots of an election, in the order they were cast
+    pub async fn get_ballots(&self, election_id: &str) -> Result<Vec<PublishedBallot>> {
+        let rows = sqlx::query("SELECT h_n, choices, encrypted FROM ballots WHERE election_id = ? ORDER BY id")
+            .bind(election_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(PublishedBallot {
+                    election_id: election_id.to_string(),
+                    h_n: row.get("h_n"),
+                    choices: serde_json::from_str(row.get("choices"))?,
+                    encrypted: row
+                        .get::<Option<&str>, _>("encrypted")
+                        .map(EncryptedBallot::from_json)
+                        .transpose()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Count the tokens issued for an election
+    pub async fn count_issued_tokens(&self, election_id: &str) -> Result<u64> {
+        let row = sqlx::query(
+            "SELECT COUNT(*) AS count FROM election_voters WHERE election_id = ? AND token_issued = 1"
+        )
+        .bind(election_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.get::<i64, _>("count") as u64)
+    }
+
+    /// Count the ballots received in an election
+    pub async fn count_ballots(&self, election_id: &str) -> Result<u64> {
+        let row = sqlx::query("SELECT COUNT(*) AS count FROM ballots WHERE election_id = ?")
+            .bind(election_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.get::<i64, _>("count") as u64)
+    }
+
+    /// Turnout of an election: voters on its roll, tokens issued to them or
+    /// to anonymous requests, and ballots received
+    pub async fn get_turnout(&self, election_id: &str) -> Result<Turnout> {
+        let row = sqlx::query(
+            r#"
+            SELECT
+                (SELECT COUNT(*) FROM election_voters WHERE election_id = ?1) AS registered,
+                (SELECT COUNT(*) FROM election_voters WHERE election_id = ?1 AND token_issued = 1)
+                    + (SELECT COUNT(*) FROM key_images WHERE election_id = ?1) AS tokens_issued,
+                (SELECT COUNT(*) FROM ballots WHERE election_id = ?1) AS ballots
+            "#,
+        )
+        .bind(election_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(Turnout::new(
+            election_id,
+            row.get::<i64, _>("registered") as u64,
+            row.get::<i64, _>("tokens_issued") as u64,
+            row.get::<i64, _>("ballots") as u64,
+        ))
+    }
 
     /// Get all elections
     #[allow(dead_code)]
@@ -307,6 +1241,13 @@ impl Database {
                 end_time: row.get("end_time"),
                 status: row.get("status"),
                 rsa_pub_key: row.get("rsa_pub_key"),
+                issuance_log: row.get::<i64, _>("issuance_log") != 0,
+                token_scheme: row.get("token_scheme"),
+                bound_tokens: row.get::<i64, _>("bound_tokens") != 0,
+                anonymous_requests: row.get::<i64, _>("anonymous_requests") != 0,
+                rehearsal: row.get::<i64, _>("rehearsal") != 0,
+                tally_key: row.get("tally_key"),
+                tally_proof: row.get("tally_proof"),
                 created_at: row.get("created_at"),
                 updated_at: row.get("updated_at"),
             })
@@ -355,6 +1296,13 @@ impl Database {
                 end_time: row.get("end_time"),
                 status: row.get("status"),
                 rsa_pub_key: row.get("rsa_pub_key"),
+                issuance_log: row.get::<i64, _>("issuance_log") != 0,
+                token_scheme: row.get("token_scheme"),
+                bound_tokens: row.get::<i64, _>("bound_tokens") != 0,
+                anonymous_requests: row.get::<i64, _>("anonymous_requests") != 0,
+                rehearsal: row.get::<i64, _>("rehearsal") != 0,
+                tally_key: row.get("tally_key"),
+                tally_proof: row.get("tally_proof"),
                 created_at: row.get("created_at"),
                 updated_at: row.get("updated_at"),
             })
@@ -363,10 +1311,10 @@ impl Database {
         Ok(elections)
     }
 
-    /// Load authorized voters for an election
+    /// Load voters of an election that are still waiting for a token
     pub async fn load_election_voters(&self, election_id: &str) -> Result<Vec<String>> {
         let rows = sqlx::query(
-            "SELECT voter_pubkey FROM election_voters WHERE election_id = ?"
+            "SELECT voter_pubkey FROM election_voters WHERE election_id = ? AND token_issued = 0"
         )
         .bind(election_id)
         .fetch_all(&self.pool)
@@ -380,6 +1328,38 @@ impl Database {
         Ok(voters)
     }
 
+    /// Pubkeys of every voter registered in an election, issued a token or not
+    pub async fn load_election_roll(&self, election_id: &str) -> Result<Vec<String>> {
+        let rows = sqlx::query("SELECT voter_pubkey FROM election_voters WHERE election_id = ?")
+            .bind(election_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(|row| row.get("voter_pubkey")).collect())
+    }
+
+    /// Get authorized voters of an election with their names
+    pub async fn get_election_voters(&self, election_id: &str) -> Result<Vec<VoterRecord>> {
+        let rows = sqlx::query(
+            "SELECT * FROM election_voters WHERE election_id = ? ORDER BY id"
+        )
+        .bind(election_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let voters = rows
+            .into_iter()
+            .map(|row| VoterRecord {
+                id: Some(row.get("id")),
+                election_id: row.get("election_id"),
+                pubkey: row.get("voter_pubkey"),
+                name: row.get("name"),
+                created_at: row.get("created_at"),
+            })
+            .collect();
+
+        Ok(voters)
+    }
+
     /// Load used tokens for an election
     pub async fn load_used_tokens(&self, election_id: &str) -> Result<Vec<String>> {
         let rows = sqlx::query(
@@ -398,7 +1378,7 @@ impl Database {
     }
 
     /// Save authorized voters for an election
-    pub async fn save_election_voters(&self, election_id: &str, voters: &[String]) -> Result<()> {
+    pub async fn save_election_voters(&self, election_id: &str, voters: &[Voter]) -> Result<()> {
         if voters.is_empty() {
             return Ok(());
         }
@@ -412,13 +1392,15 @@ impl Database {
         for voter in voters {
             sqlx::query(
                 r#"
-                INSERT INTO election_voters (election_id, voter_pubkey, created_at)
-                VALUES (?, ?, ?)
-                ON CONFLICT(election_id, voter_pubkey) DO NOTHING
+                INSERT INTO election_voters (election_id, voter_pubkey, name, created_at)
+                VALUES (?, ?, ?, ?)
+                ON CONFLICT(election_id, voter_pubkey) DO UPDATE SET
+                name = excluded.name
                 "#,
             )
             .bind(election_id)
-            .bind(voter)
+            .bind(&voter.pubkey)
+            .bind(&voter.name)
             .bind(now)
             .execute(&mut *tx)
             .await?;
@@ -432,6 +1414,7 @@ impl Database {
     }
 
     /// Save used token for an election
+    #[allow(dead_code)]
     pub async fn save_used_token(&self, election_id: &str, token_hash: &str) -> Result<()> {
         let now = chrono::Utc::now().timestamp();
         
@@ -451,4 +1434,1368 @@ impl Database {
         log::debug!("Saved used token for election {}", election_id);
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Record a processed gift wrap event and its outcome.
+    /// The first outcome recorded for an event ID is kept.
+    pub async fn save_message_log(
+        &self,
+        event_id: &str,
+        election_id: Option<&str>,
+        message_kind: Option<u8>,
+        outcome: &str,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+
+        sqlx::query(
+            r#"
+            INSERT INTO message_log (event_id, election_id, message_kind, outcome, reason, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT(event_id) DO NOTHING
+            "#,
+        )
+        .bind(event_id)
+        .bind(election_id)
+        .bind(message_kind.map(|k| k as i64))
+        .bind(outcome)
+        .bind(reason)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        log::debug!("Saved message log entry for event {}", event_id);
+        Ok(())
+    }
+
+    /// Whether a gift wrap event was already processed
+    pub async fn is_message_logged(&self, event_id: &str) -> Result<bool> {
+        let row = sqlx::query("SELECT 1 FROM message_log WHERE event_id = ?")
+            .bind(event_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    /// Save a snapshot of the current tally of an election
+    pub async fn save_results_snapshot(&self, election_id: &str, results: &[(u8, u32)]) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let total_votes: u32 = results.iter().map(|(_, count)| count).sum();
+
+        sqlx::query(
+            r#"
+            INSERT INTO results_history (election_id, total_votes, results, created_at)
+            VALUES (?, ?, ?, ?)
+            "#,
+        )
+        .bind(election_id)
+        .bind(total_votes as i64)
+        .bind(serde_json::to_string(results)?)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        log::debug!("Saved results snapshot for election {}", election_id);
+        Ok(())
+    }
+
+    /// Get the timestamp of the latest tally snapshot of an election
+    pub async fn get_latest_results_snapshot_time(&self, election_id: &str) -> Result<Option<i64>> {
+        let row = sqlx::query("SELECT MAX(created_at) AS created_at FROM results_history WHERE election_id = ?")
+            .bind(election_id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.get("created_at"))
+    }
+
+    /// Get tally snapshots of an election, oldest first
+    pub async fn get_results_history(
+        &self,
+        election_id: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<ResultsSnapshotRecord>> {
+        let limit = if limit == 0 { 100 } else { limit.min(1000) }; // Default limit, max 1000
+
+        let rows = sqlx::query(
+            "SELECT * FROM results_history WHERE election_id = ? ORDER BY created_at, id LIMIT ? OFFSET ?"
+        )
+        .bind(election_id)
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut snapshots = Vec::with_capacity(rows.len());
+        for row in rows {
+            let results: String = row.get("results");
+            snapshots.push(ResultsSnapshotRecord {
+                id: Some(row.get("id")),
+                election_id: row.get("election_id"),
+                total_votes: row.get("total_votes"),
+                results: serde_json::from_str(&results)?,
+                created_at: row.get("created_at"),
+            });
+        }
+
+        Ok(snapshots)
+    }
+
+    /// Record the relays that accepted an event published by the EC
+    pub async fn save_published_event(
+        &self,
+        event_id: &str,
+        election_id: &str,
+        kind: u16,
+        relay_urls: &[String],
+    ) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let mut tx = self.pool.begin().await?;
+
+        for relay_url in relay_urls {
+            sqlx::query(
+                r#"
+                INSERT INTO published_events (event_id, election_id, kind, relay_url, created_at)
+                VALUES (?, ?, ?, ?, ?)
+                ON CONFLICT(event_id, relay_url) DO NOTHING
+                "#,
+            )
+            .bind(event_id)
+            .bind(election_id)
+            .bind(kind as i64)
+            .bind(relay_url)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        log::debug!(
+            "Saved event {} of election {} published to {} relay(s)",
+            event_id,
+            election_id,
+            relay_urls.len()
+        );
+        Ok(())
+    }
+
+    /// Get the events published for an election, newest first
+    pub async fn get_published_events(
+        &self,
+        election_id: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Vec<PublishedEventRecord>> {
+        let limit = if limit == 0 { 100 } else { limit.min(1000) }; // Default limit, max 1000
+
+        let rows = sqlx::query(
+            "SELECT * FROM published_events WHERE election_id = ? ORDER BY created_at DESC, id DESC LIMIT ? OFFSET ?"
+        )
+        .bind(election_id)
+        .bind(limit as i64)
+        .bind(offset as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let events = rows
+            .into_iter()
+            .map(|row| PublishedEventRecord {
+                id: Some(row.get("id")),
+                event_id: row.get("event_id"),
+                election_id: row.get("election_id"),
+                kind: row.get("kind"),
+                relay_url: row.get("relay_url"),
+                created_at: row.get("created_at"),
+            })
+            .collect();
+
+        Ok(events)
+    }
+
+    /// Record when the EC last processed a gift wrap
+    pub async fn set_last_processed_at(&self, timestamp: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO ec_state (key, value) VALUES ('last_processed_at', ?)
+            ON CONFLICT(key) DO UPDATE SET value = MAX(value, excluded.value)
+            "#,
+        )
+        .bind(timestamp)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// When the EC last processed a gift wrap, if it ever did
+    pub async fn get_last_processed_at(&self) -> Result<Option<i64>> {
+        let row = sqlx::query("SELECT value FROM ec_state WHERE key = 'last_processed_at'")
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|row| row.get("value")))
+    }
+
+    /// Keep the pending timestamp of an election's final results.
+    /// An election already timestamped keeps its first proof.
+    pub async fn save_results_timestamp(&self, election_id: &str, event_id: &str, proof: &[u8]) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        sqlx::query(
+            "INSERT OR IGNORE INTO results_timestamps (election_id, event_id, proof, created_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(election_id)
+        .bind(event_id)
+        .bind(proof)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Get the timestamps of final results not published yet
+    pub async fn get_pending_results_timestamps(&self) -> Result<Vec<ResultsTimestampRecord>> {
+        let rows = sqlx::query(
+            "SELECT election_id, event_id, proof FROM results_timestamps WHERE attestation_event_id IS NULL ORDER BY created_at",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ResultsTimestampRecord {
+                election_id: row.get("election_id"),
+                event_id: row.get("event_id"),
+                proof: row.get("proof"),
+            })
+            .collect())
+    }
+
+    /// Keep the complete proof of an election's final results and the event it was published in
+    pub async fn complete_results_timestamp(
+        &self,
+        election_id: &str,
+        proof: &[u8],
+        attestation_event_id: &str,
+    ) -> Result<()> {
+        sqlx::query("UPDATE results_timestamps SET proof = ?, attestation_event_id = ? WHERE election_id = ?")
+            .bind(proof)
+            .bind(attestation_event_id)
+            .bind(election_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Keep an event stored by the local relay, so it is served again after a restart
+    pub async fn save_relay_event(&self, event_id: &str, event_json: &str, created_at: i64) -> Result<()> {
+        sqlx::query("INSERT OR IGNORE INTO relay_events (event_id, event_json, created_at) VALUES (?, ?, ?)")
+            .bind(event_id)
+            .bind(event_json)
+            .bind(created_at)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Get the events stored by the local relay, oldest first so newer
+    /// replaceable events take the place of older ones when loaded
+    pub async fn load_relay_events(&self) -> Result<Vec<String>> {
+        let rows = sqlx::query("SELECT event_json FROM relay_events ORDER BY created_at, event_id")
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|row| row.get("event_json")).collect())
+    }
+
+    /// Keep an event no relay accepted, to publish it again later.
+    /// An event already in the outbox is kept as it is.
+    pub async fn save_outbox_event(&self, event_id: &str, kind: u16, event_json: &str) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        sqlx::query(
+            r#"
+            INSERT INTO outbox (event_id, kind, event_json, next_attempt_at, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(event_id) DO NOTHING
+            "#,
+        )
+        .bind(event_id)
+        .bind(kind as i64)
+        .bind(event_json)
+        .bind(now)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        log::debug!("Saved event {} of kind {} to the outbox", event_id, kind);
+        Ok(())
+    }
+
+    /// Get the outbox events due for another attempt at `now`, oldest first
+    pub async fn get_due_outbox_events(&self, now: i64, limit: u32) -> Result<Vec<OutboxRecord>> {
+        let rows = sqlx::query("SELECT * FROM outbox WHERE next_attempt_at <= ? ORDER BY id LIMIT ?")
+            .bind(now)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let events = rows
+            .into_iter()
+            .map(|row| OutboxRecord {
+                id: Some(row.get("id")),
+                event_id: row.get("event_id"),
+                kind: row.get("kind"),
+                event_json: row.get("event_json"),
+                attempts: row.get("attempts"),
+                last_error: row.get("last_error"),
+                next_attempt_at: row.get("next_attempt_at"),
+                created_at: row.get("created_at"),
+            })
+            .collect();
+
+        Ok(events)
+    }
+
+    /// Record a failed attempt to publish an outbox event and when to try again
+    pub async fn reschedule_outbox_event(&self, event_id: &str, next_attempt_at: i64, error: &str) -> Result<()> {
+        sqlx::query("UPDATE outbox SET attempts = attempts + 1, last_error = ?, next_attempt_at = ? WHERE event_id = ?")
+            .bind(error)
+            .bind(next_attempt_at)
+            .bind(event_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remove an event from the outbox once a relay accepted it
+    pub async fn delete_outbox_event(&self, event_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM outbox WHERE event_id = ?")
+            .bind(event_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Number of events waiting in the outbox
+    pub async fn count_outbox_events(&self) -> Result<u64> {
+        let row = sqlx::query("SELECT COUNT(*) AS count FROM outbox")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.get::<i64, _>("count") as u64)
+    }
+
+    /// Queue a token request for the trustees
+    pub async fn save_signature_request(&self, request: &SignatureRequestRecord) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO signature_requests (id, election_id, voter_pubkey, message, blinded_message, created_at) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&request.id)
+        .bind(&request.election_id)
+        .bind(&request.voter_pubkey)
+        .bind(&request.message)
+        .bind(&request.blinded_message)
+        .bind(request.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Get the token requests still waiting for signature shares, oldest first
+    pub async fn get_pending_signature_requests(&self) -> Result<Vec<SignatureRequestRecord>> {
+        let rows = sqlx::query(
+            "SELECT id, election_id, voter_pubkey, message, blinded_message, created_at FROM signature_requests WHERE completed_at IS NULL ORDER BY created_at, id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut requests = Vec::with_capacity(rows.len());
+        for row in rows {
+            let id: String = row.get("id");
+            let shares = self.get_signature_shares(&id).await?;
+            requests.push(SignatureRequestRecord {
+                id,
+                election_id: row.get("election_id"),
+                voter_pubkey: row.get("voter_pubkey"),
+                message: row.get("message"),
+                blinded_message: row.get("blinded_message"),
+                created_at: row.get("created_at"),
+                shares,
+            });
+        }
+        Ok(requests)
+    }
+
+    async fn get_signature_shares(&self, request_id: &str) -> Result<Vec<(u32, Vec<u8>, Vec<u8>)>> {
+        let rows = sqlx::query(
+            "SELECT trustee_index, share, proof FROM signature_shares WHERE request_id = ? ORDER BY trustee_index",
+        )
+        .bind(request_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get::<i64, _>("trustee_index") as u32, row.get("share"), row.get("proof")))
+            .collect())
+    }
+
+    /// Keep a trustee's signature share with its proof. A trustee's first
+    /// share for a request is the one kept; returns whether this one was.
+    pub async fn save_signature_share(&self, request_id: &str, trustee_index: u32, share: &[u8], proof: &[u8]) -> Result<bool> {
+        let now = chrono::Utc::now().timestamp();
+        let inserted = sqlx::query(
+            "INSERT OR IGNORE INTO signature_shares (request_id, trustee_index, share, proof, created_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(request_id)
+        .bind(trustee_index as i64)
+        .bind(share)
+        .bind(proof)
+        .bind(now)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(inserted > 0)
+    }
+
+    /// Mark a token request as signed
+    pub async fn complete_signature_request(&self, request_id: &str) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        sqlx::query("UPDATE signature_requests SET completed_at = ? WHERE id = ?")
+            .bind(now)
+            .bind(request_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Remove voter rolls, used tokens and message logs of an election.
+    /// The election itself and its candidate vote counts are kept.
+    pub async fn purge_election_data(&self, election_id: &str) -> Result<PurgeStats> {
+        let mut tx = self.pool.begin().await?;
+
+        let voters_removed = sqlx::query("DELETE FROM election_voters WHERE election_id = ?")
+            .bind(election_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        let tokens_removed = sqlx::query("DELETE FROM used_tokens WHERE election_id = ?")
+            .bind(election_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        sqlx::query("DELETE FROM key_images WHERE election_id = ?")
+            .bind(election_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let messages_removed = sqlx::query("DELETE FROM message_log WHERE election_id = ?")
+            .bind(election_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        let issuances_removed = sqlx::query("DELETE FROM token_issuances WHERE election_id = ?")
+            .bind(election_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        tx.commit().await?;
+
+        let stats = PurgeStats {
+            voters_removed,
+            tokens_removed,
+            messages_removed,
+            issuances_removed,
+        };
+        log::debug!("Purged data for election {}: {:?}", election_id, stats);
+        Ok(stats)
+    }
+
+    /// Check referential integrity and that vote counts match the used tokens
+    pub async fn check_integrity(&self) -> Result<IntegrityReport> {
+        let mut report = IntegrityReport::default();
+
+        for table in ELECTION_TABLES {
+            let row = sqlx::query(&format!(
+                "SELECT COUNT(*) AS count FROM {} WHERE election_id IS NOT NULL AND election_id NOT IN (SELECT id FROM elections)",
+                table
+            ))
+            .fetch_one(&self.pool)
+            .await?;
+
+            let count = row.get::<i64, _>("count") as u64;
+            if count > 0 {
+                report.orphan_rows.push((table, count));
+            }
+        }
+
+        let rows = sqlx::query(
+            r#"
+            SELECT e.id AS election_id,
+                (SELECT COALESCE(SUM(vote_count), 0) FROM candidates WHERE election_id = e.id) AS counted_votes,
+                (SELECT COUNT(*) FROM used_tokens WHERE election_id = e.id) AS used_tokens
+            FROM elections e
+            -- Encrypted ballots are counted at the tally
+            WHERE e.tally_key IS NULL OR e.tally_proof IS NOT NULL
+            ORDER BY e.id
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        for row in rows {
+            let counted_votes: i64 = row.get("counted_votes");
+            let used_tokens: i64 = row.get("used_tokens");
+            if counted_votes != used_tokens {
+                report.vote_mismatches.push(VoteCountMismatch {
+                    election_id: row.get("election_id"),
+                    counted_votes,
+                    used_tokens,
+                });
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Delete rows pointing at elections that do not exist
+    pub async fn repair_orphan_rows(&self) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut removed = 0;
+
+        for table in ELECTION_TABLES {
+            removed += sqlx::query(&format!(
+                "DELETE FROM {} WHERE election_id IS NOT NULL AND election_id NOT IN (SELECT id FROM elections)",
+                table
+            ))
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+
+        tx.commit().await?;
+
+        log::info!("Removed {} orphan rows", removed);
+        Ok(removed)
+    }
+
+    /// Wait for the queries in progress and close the connections, which
+    /// moves the write-ahead log into the database file.
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    /// Write a consistent copy of the whole database to a new file at
+    /// `path`, also while the EC is writing to it.
+    pub async fn snapshot(&self, path: &Path) -> Result<()> {
+        sqlx::query("VACUUM INTO ?")
+            .bind(path.to_string_lossy().to_string())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// ID of this database's replication log, made on first use. Standby ECs
+    /// check it to know if their cursor points into this log.
+    pub async fn get_replication_source(&self) -> Result<i64> {
+        let source = rand::Rng::gen_range(&mut rand::thread_rng(), 1..i64::MAX);
+        sqlx::query("INSERT OR IGNORE INTO ec_state (key, value) VALUES ('replication_id', ?)")
+            .bind(source)
+            .execute(&self.pool)
+            .await?;
+        let row = sqlx::query("SELECT value FROM ec_state WHERE key = 'replication_id'")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(row.get("value"))
+    }
+
+    /// Oldest change still in the replication log and the last one recorded,
+    /// the oldest being past the last when the log is empty
+    pub async fn get_replication_log_range(&self) -> Result<(i64, i64)> {
+        let row = sqlx::query(
+            r#"
+            SELECT (SELECT MIN(seq) FROM replication_log) AS first,
+                (SELECT seq FROM sqlite_sequence WHERE name = 'replication_log') AS last
+            "#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        let last = row.get::<Option<i64>, _>("last").unwrap_or(0);
+        let first = row.get::<Option<i64>, _>("first").unwrap_or(last + 1);
+        Ok((first, last))
+    }
+
+    /// Changes recorded after the change `after`, oldest first
+    pub async fn get_replication_changes(&self, after: i64, limit: u32) -> Result<Vec<ReplicatedChange>> {
+        let rows = sqlx::query("SELECT * FROM replication_log WHERE seq > ? ORDER BY seq LIMIT ?")
+            .bind(after)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ReplicatedChange {
+                seq: row.get("seq"),
+                table: row.get("table_name"),
+                deleted: row.get("deleted"),
+                row: row.get("row"),
+            })
+            .collect())
+    }
+
+    /// Rows of a replicated table after the one with `after_rowid`, as the
+    /// replication log has them, each with its rowid
+    pub async fn get_replicated_rows(&self, table: &str, after_rowid: i64, limit: u32) -> Result<Vec<(i64, String)>> {
+        if !REPLICATED_TABLES.contains(&table) {
+            return Err(anyhow::anyhow!("Table {} isn't replicated", table));
+        }
+        let columns = self.table_columns(table).await?;
+        let rows = sqlx::query(&format!(
+            "SELECT rowid AS row_id, {} AS row FROM {} WHERE rowid > ? ORDER BY rowid LIMIT ?",
+            json_row(&columns, ""),
+            table
+        ))
+        .bind(after_rowid)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| (row.get("row_id"), row.get("row"))).collect())
+    }
+
+    /// Remove the replication log entries recorded before `before`
+    pub async fn prune_replication_log(&self, before: i64) -> Result<u64> {
+        let removed = sqlx::query("DELETE FROM replication_log WHERE created_at < ?")
+            .bind(before)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+
+        Ok(removed)
+    }
+
+    /// Replication log a standby EC follows and the last change it applied,
+    /// once it has a full copy
+    pub async fn get_replication_cursor(&self) -> Result<Option<(i64, i64)>> {
+        let rows = sqlx::query("SELECT key, value FROM ec_state WHERE key IN ('replication_source', 'replication_cursor')")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let value = |key: &str| rows.iter().find(|row| row.get::<String, _>("key") == key).map(|row| row.get::<i64, _>("value"));
+        Ok(value("replication_source").zip(value("replication_cursor")))
+    }
+
+    /// Apply changes of the primary EC in one transaction. `reset` empties the
+    /// replicated tables first, for a full copy, and the cursor is only kept
+    /// once the copy is complete.
+    pub async fn apply_replication(
+        &self,
+        reset: bool,
+        changes: &[ReplicatedChange],
+        cursor: Option<(i64, i64)>,
+    ) -> Result<()> {
+        let mut columns = HashMap::new();
+        for table in REPLICATED_TABLES {
+            columns.insert(*table, self.table_columns(table).await?);
+        }
+
+        // A full copy is read while the primary writes, so children may come
+        // before their parents: the primary checked the foreign keys already
+        let mut conn = self.pool.acquire().await?;
+        sqlx::query("PRAGMA foreign_keys = OFF").execute(&mut *conn).await?;
+        let applied = apply_changes(&mut conn, &columns, reset, changes, cursor).await;
+        sqlx::query("PRAGMA foreign_keys = ON").execute(&mut *conn).await?;
+        applied
+    }
+
+    /// Export the rows of a table, optionally restricted to one election
+    pub async fn export_table(&self, table: ExportTable, election_id: Option<&str>) -> Result<TableExport> {
+        let sql = table.query(election_id.is_some());
+        let mut query = sqlx::query(&sql);
+        if let Some(election_id) = election_id {
+            query = query.bind(election_id);
+        }
+        let rows = query.fetch_all(&self.pool).await?;
+
+        let columns = table.columns();
+        let rows = rows
+            .into_iter()
+            .map(|row| {
+                columns
+                    .iter()
+                    .map(|(name, integer)| {
+                        if *integer {
+                            serde_json::Value::from(row.get::<i64, _>(*name))
+                        } else {
+                            serde_json::Value::from(row.get::<String, _>(*name))
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Ok(TableExport {
+            columns: columns.iter().map(|(name, _)| *name).collect(),
+            rows,
+        })
+    }
+
+    /// Remove message log entries not bound to any election created before `before`
+    pub async fn purge_unbound_message_log(&self, before: i64) -> Result<u64> {
+        let removed = sqlx::query("DELETE FROM message_log WHERE election_id IS NULL AND created_at < ?")
+            .bind(before)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+
+        Ok(removed)
+    }
+
+    /// Get processed message log entries, most recent first
+    #[allow(dead_code)]
+    pub async fn get_message_log(&self, limit: u32, offset: u32) -> Result<Vec<MessageLogRecord>> {
+        let limit = if limit == 0 { 100 } else { limit.min(1000) }; // Default limit, max 1000
+
+        let rows = sqlx::query("SELECT * FROM message_log ORDER BY id DESC LIMIT ? OFFSET ?")
+            .bind(limit as i64)
+            .bind(offset as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let entries = rows
+            .into_iter()
+            .map(|row| MessageLogRecord {
+                id: Some(row.get("id")),
+                event_id: row.get("event_id"),
+                election_id: row.get("election_id"),
+                message_kind: row.get("message_kind"),
+                outcome: row.get("outcome"),
+                reason: row.get("reason"),
+                created_at: row.get("created_at"),
+            })
+            .collect();
+
+        Ok(entries)
+    }
+}
+
+/// SQL building the JSON object of a row from its columns, with `prefix`
+/// (`NEW.` or `OLD.` in triggers) before each column. BLOBs go in hex.
+fn json_row(columns: &[(String, bool, bool)], prefix: &str) -> String {
+    let fields: Vec<String> = columns
+        .iter()
+        .map(|(name, blob, _)| match blob {
+            true => format!("'{0}', iif({1}{0} IS NULL, NULL, hex({1}{0}))", name, prefix),
+            false => format!("'{0}', {1}{0}", name, prefix),
+        })
+        .collect();
+    format!("json_object({})", fields.join(", "))
+}
+
+async fn apply_changes(
+    conn: &mut SqliteConnection,
+    columns: &HashMap<&str, Vec<(String, bool, bool)>>,
+    reset: bool,
+    changes: &[ReplicatedChange],
+    cursor: Option<(i64, i64)>,
+) -> Result<()> {
+    let mut tx = conn.begin().await?;
+    if reset {
+        for table in REPLICATED_TABLES.iter().rev() {
+            sqlx::query(&format!("DELETE FROM {}", table)).execute(&mut *tx).await?;
+        }
+        sqlx::query("DELETE FROM ec_state WHERE key IN ('replication_source', 'replication_cursor')")
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    for change in changes {
+        let Some(columns) = columns.get(change.table.as_str()) else {
+            return Err(anyhow::anyhow!("Table {} isn't replicated", change.table));
+        };
+        let row: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&change.row)?;
+        // Columns this version of the EC doesn't have are left out
+        let fields: Vec<_> = columns.iter().filter(|(name, _, _)| row.contains_key(name)).collect();
+        if fields.is_empty() {
+            return Err(anyhow::anyhow!("Change to {} without any known column", change.table));
+        }
+        let value = |blob: bool| if blob { "unhex(?)" } else { "?" };
+        let sql = if change.deleted {
+            let key: Vec<String> = fields.iter().map(|(name, blob, _)| format!("{} = {}", name, value(*blob))).collect();
+            format!("DELETE FROM {} WHERE {}", change.table, key.join(" AND "))
+        } else {
+            let names: Vec<&str> = fields.iter().map(|(name, _, _)| name.as_str()).collect();
+            let values: Vec<&str> = fields.iter().map(|(_, blob, _)| value(*blob)).collect();
+            format!("INSERT OR REPLACE INTO {} ({}) VALUES ({})", change.table, names.join(", "), values.join(", "))
+        };
+
+        let mut query = sqlx::query(&sql);
+        for (name, _, _) in &fields {
+            query = match &row[name.as_str()] {
+                serde_json::Value::Null => query.bind(None::<String>),
+                serde_json::Value::Bool(value) => query.bind(*value as i64),
+                serde_json::Value::Number(value) => match value.as_i64() {
+                    Some(value) => query.bind(value),
+                    None => query.bind(value.as_f64()),
+                },
+                serde_json::Value::String(value) => query.bind(value.clone()),
+                value => query.bind(value.to_string()),
+            };
+        }
+        query.execute(&mut *tx).await?;
+    }
+
+    if let Some((source, cursor)) = cursor {
+        for (key, value) in [("replication_source", source), ("replication_cursor", cursor)] {
+            sqlx::query("INSERT INTO ec_state (key, value) VALUES (?, ?) ON CONFLICT(key) DO UPDATE SET value = excluded.value")
+                .bind(key)
+                .bind(value)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    async fn create_test_db() -> (Database, NamedTempFile) {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db = Database::new(temp_file.path()).await.unwrap();
+        (db, temp_file)
+    }
+
+    #[tokio::test]
+    async fn test_message_log_keeps_first_outcome() {
+        let (db, _temp_file) = create_test_db().await;
+
+        db.save_message_log("event1", Some("abcd"), Some(1), "token_issued", None)
+            .await
+            .unwrap();
+        db.save_message_log("event2", None, None, "rejected", Some("Error parsing message"))
+            .await
+            .unwrap();
+        // A redelivered event must not overwrite the original outcome
+        db.save_message_log("event1", Some("abcd"), Some(1), "rejected", Some("duplicated"))
+            .await
+            .unwrap();
+
+        let entries = db.get_message_log(10, 0).await.unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let first = entries.iter().find(|e| e.event_id == "event1").unwrap();
+        assert_eq!(first.outcome, "token_issued");
+        assert_eq!(first.election_id.as_deref(), Some("abcd"));
+        assert_eq!(first.message_kind, Some(1));
+        assert!(first.reason.is_none());
+
+        let second = entries.iter().find(|e| e.event_id == "event2").unwrap();
+        assert_eq!(second.outcome, "rejected");
+        assert_eq!(second.reason.as_deref(), Some("Error parsing message"));
+
+        assert!(db.is_message_logged("event1").await.unwrap());
+        assert!(!db.is_message_logged("event3").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_purge_election_data() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let candidates = vec![Candidate::new(1, "Alice")];
+        let election = Election::new("Purged".to_string(), candidates.clone(), 1000, 3600, "key".to_string());
+        let other = Election::new("Kept".to_string(), candidates, 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+        db.upsert_election(&other).await.unwrap();
+
+        let voters = vec![
+            Voter::new("Alice", "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e"),
+            Voter::new("Bob", "3f55f3701e9b00dce27ab6cce6cf487fd5c4ba48f46d475926ebf916d53a9db1"),
+        ];
+        db.save_election_voters(&election.id, &voters).await.unwrap();
+        db.save_election_voters(&other.id, &voters[..1]).await.unwrap();
+        db.save_used_token(&election.id, "deadbeef").await.unwrap();
+        db.save_message_log("event1", Some(&election.id), Some(2), "vote_accepted", None)
+            .await
+            .unwrap();
+        db.save_message_log("event2", None, None, "rejected", Some("Error parsing message"))
+            .await
+            .unwrap();
+
+        let stats = db.purge_election_data(&election.id).await.unwrap();
+        assert_eq!(
+            stats,
+            PurgeStats {
+                voters_removed: 2,
+                tokens_removed: 1,
+                messages_removed: 1,
+                issuances_removed: 0,
+            }
+        );
+        assert!(db.load_election_voters(&election.id).await.unwrap().is_empty());
+        assert!(db.load_used_tokens(&election.id).await.unwrap().is_empty());
+
+        // Other elections are untouched
+        assert_eq!(db.load_election_voters(&other.id).await.unwrap().len(), 1);
+
+        // Names are kept alongside the pubkeys
+        let kept = db.get_election_voters(&other.id).await.unwrap();
+        assert_eq!(kept[0].name, "Alice");
+        assert_eq!(kept[0].pubkey, voters[0].pubkey);
+
+        // Purging twice is a no-op
+        assert_eq!(db.purge_election_data(&election.id).await.unwrap().total(), 0);
+
+        // Unbound entries are only removed once they are old enough
+        assert_eq!(db.purge_unbound_message_log(0).await.unwrap(), 0);
+        let future = chrono::Utc::now().timestamp() + 1;
+        assert_eq!(db.purge_unbound_message_log(future).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_save_election_voters_updates_name() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let election = Election::new("Names".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+
+        let pubkey = "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e";
+        db.save_election_voters(&election.id, &[Voter::new("Alice", pubkey)]).await.unwrap();
+        db.save_election_voters(&election.id, &[Voter::new("Alice Liddell", pubkey)]).await.unwrap();
+
+        let voters = db.get_election_voters(&election.id).await.unwrap();
+        assert_eq!(voters.len(), 1);
+        assert_eq!(voters[0].name, "Alice Liddell");
+        assert_eq!(voters[0].election_id, election.id);
+    }
+
+    #[tokio::test]
+    async fn test_results_history() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let election = Election::new("History".to_string(), vec![Candidate::new(1, "Alice"), Candidate::new(2, "Bob")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+
+        assert!(db.get_latest_results_snapshot_time(&election.id).await.unwrap().is_none());
+
+        db.save_results_snapshot(&election.id, &[(1, 1)]).await.unwrap();
+        db.save_results_snapshot(&election.id, &[(1, 1), (2, 1)]).await.unwrap();
+
+        let history = db.get_results_history(&election.id, 0, 0).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].total_votes, 1);
+        assert_eq!(history[1].total_votes, 2);
+        assert_eq!(history[1].results, vec![(1, 1), (2, 1)]);
+        assert!(db.get_latest_results_snapshot_time(&election.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_record_vote_is_atomic() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let election = Election::new("Votes".to_string(), vec![Candidate::new(1, "Alice"), Candidate::new(2, "Bob")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+
+        db.record_vote(&election.id, "aa", &PublishedBallot::new(&election.id, &[0xaa], vec![1]), &[(1, 1)]).await.unwrap();
+
+        // A reused token rolls back the whole write, vote counts included
+        assert!(db.record_vote(&election.id, "aa", &PublishedBallot::new(&election.id, &[0xab], vec![2]), &[(1, 1), (2, 1)]).await.is_err());
+
+        let candidates = db.get_candidates(&election.id).await.unwrap();
+        assert_eq!(candidates[0].vote_count, 1);
+        assert_eq!(candidates[1].vote_count, 0);
+        assert_eq!(db.load_used_tokens(&election.id).await.unwrap(), vec!["aa".to_string()]);
+        // Only the accepted ballot reaches the bulletin board
+        assert_eq!(db.get_ballots(&election.id).await.unwrap(), vec![PublishedBallot::new(&election.id, &[0xaa], vec![1])]);
+
+        // Ballots are the public record and survive a purge
+        db.purge_election_data(&election.id).await.unwrap();
+        assert_eq!(db.get_ballots(&election.id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unique_candidate_names() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let mut election = Election::new("Names".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+
+        // A name differing only in case is refused, with nothing written
+        election.candidates.push(Candidate::new(2, "ALICE"));
+        assert!(db.upsert_election(&election).await.is_err());
+        assert_eq!(db.get_candidates(&election.id).await.unwrap().len(), 1);
+
+        // Other elections may use it
+        let other = Election::new("Other".to_string(), vec![Candidate::new(1, "alice")], 1000, 3600, "key".to_string());
+        db.upsert_election(&other).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mark_token_issued() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let election = Election::new("Tokens".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+
+        let voters = vec![
+            Voter::new("Alice", "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e"),
+            Voter::new("Bob", "3f55f3701e9b00dce27ab6cce6cf487fd5c4ba48f46d475926ebf916d53a9db1"),
+        ];
+        db.save_election_voters(&election.id, &voters).await.unwrap();
+
+        db.mark_token_issued(&election.id, &voters[0].pubkey, false).await.unwrap();
+        // A voter can only be served once
+        assert!(db.mark_token_issued(&election.id, &voters[0].pubkey, false).await.is_err());
+        assert!(db.mark_token_issued(&election.id, "unknown", false).await.is_err());
+
+        // Served voters are not authorized again on restart but stay on the roll
+        assert_eq!(db.load_election_voters(&election.id).await.unwrap(), vec![voters[1].pubkey.clone()]);
+        assert_eq!(db.get_election_voters(&election.id).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_published_events() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let election = Election::new("Published".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+
+        let relays = vec!["wss://relay.one".to_string(), "wss://relay.two".to_string()];
+        db.save_published_event("ev1", &election.id, 35_000, &relays).await.unwrap();
+        db.save_published_event("ev2", &election.id, 35_001, &relays[..1]).await.unwrap();
+        // Re-sending an event to the same relay is recorded once
+        db.save_published_event("ev2", &election.id, 35_001, &relays[..1]).await.unwrap();
+
+        let events = db.get_published_events(&election.id, 0, 0).await.unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events.iter().filter(|e| e.kind == 35_000).count(), 2);
+        assert_eq!(events[0].event_id, "ev2");
+        assert_eq!(events[0].relay_url, "wss://relay.one");
+
+        assert!(db.get_published_events("none", 0, 0).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_results_timestamps() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let election = Election::new("Anchored".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+
+        db.save_results_timestamp(&election.id, "ev1", b"pending").await.unwrap();
+        // The first final results stay the timestamped ones
+        db.save_results_timestamp(&election.id, "ev2", b"other").await.unwrap();
+        let pending = db.get_pending_results_timestamps().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].event_id, "ev1");
+        assert_eq!(pending[0].proof, b"pending");
+
+        db.complete_results_timestamp(&election.id, b"complete", "ots1").await.unwrap();
+        assert!(db.get_pending_results_timestamps().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_signature_requests() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let election = Election::new("Trusted".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+
+        let request = SignatureRequestRecord {
+            id: "req1".to_string(),
+            election_id: election.id.clone(),
+            voter_pubkey: "voter".to_string(),
+            message: "{}".to_string(),
+            blinded_message: vec![1, 2, 3],
+            created_at: 100,
+            shares: vec![],
+        };
+        db.save_signature_request(&request).await.unwrap();
+
+        assert!(db.save_signature_share("req1", 2, b"two", b"proof 2").await.unwrap());
+        // A trustee can't replace its share
+        assert!(!db.save_signature_share("req1", 2, b"other", b"proof").await.unwrap());
+        assert!(db.save_signature_share("req1", 1, b"one", b"proof 1").await.unwrap());
+
+        let pending = db.get_pending_signature_requests().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].blinded_message, vec![1, 2, 3]);
+        assert_eq!(
+            pending[0].shares,
+            vec![(1, b"one".to_vec(), b"proof 1".to_vec()), (2, b"two".to_vec(), b"proof 2".to_vec())]
+        );
+
+        db.complete_signature_request("req1").await.unwrap();
+        assert!(db.get_pending_signature_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_last_processed_at() {
+        let (db, _temp_file) = create_test_db().await;
+        assert_eq!(db.get_last_processed_at().await.unwrap(), None);
+
+        db.set_last_processed_at(2_000).await.unwrap();
+        // Never moves back
+        db.set_last_processed_at(1_000).await.unwrap();
+        assert_eq!(db.get_last_processed_at().await.unwrap(), Some(2_000));
+        db.set_last_processed_at(3_000).await.unwrap();
+        assert_eq!(db.get_last_processed_at().await.unwrap(), Some(3_000));
+    }
+
+    #[tokio::test]
+    async fn test_outbox() {
+        let (db, _temp_file) = create_test_db().await;
+        let now = chrono::Utc::now().timestamp();
+
+        db.save_outbox_event("ev1", 35_000, "{}").await.unwrap();
+        db.save_outbox_event("ev2", 1059, "{}").await.unwrap();
+        // Saving an event twice keeps one entry
+        db.save_outbox_event("ev1", 35_000, "{}").await.unwrap();
+        assert_eq!(db.count_outbox_events().await.unwrap(), 2);
+
+        let due = db.get_due_outbox_events(now, 10).await.unwrap();
+        assert_eq!(due.len(), 2);
+        assert_eq!(due[0].event_id, "ev1");
+        assert_eq!(due[0].attempts, 0);
+
+        db.reschedule_outbox_event("ev1", now + 60, "relay down").await.unwrap();
+        let due = db.get_due_outbox_events(now, 10).await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].event_id, "ev2");
+        let later = db.get_due_outbox_events(now + 60, 10).await.unwrap();
+        assert_eq!(later[0].attempts, 1);
+        assert_eq!(later[0].last_error.as_deref(), Some("relay down"));
+
+        db.delete_outbox_event("ev2").await.unwrap();
+        assert_eq!(db.count_outbox_events().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_token_issuance_log() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let mut election = Election::new("Audited".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        election.issuance_log = true;
+        election.token_scheme = criptocracia_protocol::TokenScheme::RsaPssDeterministic;
+        db.upsert_election(&election).await.unwrap();
+        assert!(db.load_all_elections().await.unwrap()[0].issuance_log);
+        assert_eq!(db.load_all_elections().await.unwrap()[0].token_scheme, "rsa-pss-deterministic");
+        assert!(db.load_all_elections().await.unwrap()[0].bound_tokens);
+
+        let voters = vec![
+            Voter::new("Alice", "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e"),
+            Voter::new("Bob", "3f55f3701e9b00dce27ab6cce6cf487fd5c4ba48f46d475926ebf916d53a9db1"),
+        ];
+        db.save_election_voters(&election.id, &voters).await.unwrap();
+
+        db.mark_token_issued(&election.id, &voters[1].pubkey, true).await.unwrap();
+
+        let issuances = db.get_token_issuances(&election.id, 0, 0).await.unwrap();
+        assert_eq!(issuances.len(), 1);
+        assert_eq!(issuances[0].voter_pubkey, voters[1].pubkey);
+        assert_eq!(db.count_issued_tokens(&election.id).await.unwrap(), 1);
+
+        // The issuance log is voter data and goes with the retention purge
+        let stats = db.purge_election_data(&election.id).await.unwrap();
+        assert_eq!(stats.issuances_removed, 1);
+        assert!(db.get_token_issuances(&election.id, 0, 0).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rehearsal_election() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let mut election = Election::new("Drill".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+        assert!(!db.load_all_elections().await.unwrap()[0].rehearsal);
+        election.rehearsal = true;
+        db.upsert_election(&election).await.unwrap();
+        assert!(db.load_all_elections().await.unwrap()[0].rehearsal);
+    }
+
+    #[tokio::test]
+    async fn test_key_images() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let mut election = Election::new("Anonymous".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        election.anonymous_requests = true;
+        db.upsert_election(&election).await.unwrap();
+        assert!(db.load_all_elections().await.unwrap()[0].anonymous_requests);
+
+        assert!(db.record_key_image(&election.id, "02ab").await.unwrap());
+        assert!(!db.record_key_image(&election.id, "02ab").await.unwrap());
+        assert!(db.record_key_image(&election.id, "03cd").await.unwrap());
+
+        db.release_key_image(&election.id, "02ab").await.unwrap();
+        assert!(db.record_key_image(&election.id, "02ab").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_turnout() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let election = Election::new("Turnout".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+        assert_eq!(db.get_turnout(&election.id).await.unwrap(), Turnout::new(&election.id, 0, 0, 0));
+
+        let voters = vec![
+            Voter::new("Alice", "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e"),
+            Voter::new("Bob", "3f55f3701e9b00dce27ab6cce6cf487fd5c4ba48f46d475926ebf916d53a9db1"),
+            Voter::new("Carol", "7e7e9c42a91bfef19fa929e5fda1b72e0ebc1a4c1141673e2794234d86addf4e"),
+        ];
+        db.save_election_voters(&election.id, &voters).await.unwrap();
+        db.mark_token_issued(&election.id, &voters[0].pubkey, false).await.unwrap();
+        db.record_key_image(&election.id, "02ab").await.unwrap();
+        db.record_vote(&election.id, "aa", &PublishedBallot::new(&election.id, &[0xaa], vec![1]), &[(1, 1)]).await.unwrap();
+
+        let turnout = db.get_turnout(&election.id).await.unwrap();
+        assert_eq!((turnout.registered, turnout.tokens_issued, turnout.ballots), (3, 2, 1));
+        assert_eq!(turnout.turnout, 33.33);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_tally() {
+        let (db, _temp_file) = create_test_db().await;
+        let keys = nostr_sdk::Keys::generate();
+
+        let mut election = Election::new("Encrypted".to_string(), vec![Candidate::new(1, "Alice"), Candidate::new(2, "Bob")], 1000, 3600, "key".to_string());
+        election.enable_encrypted_tally(&keys);
+        db.upsert_election(&election).await.unwrap();
+        let key = criptocracia_protocol::tally::parse_tally_key(election.tally_key.as_ref().unwrap()).unwrap();
+        let encrypted = EncryptedBallot::encrypt(&key, 2, 1, &election.id, &[0xaa]).unwrap();
+        let ballot = PublishedBallot::new_encrypted(&election.id, &[0xaa], encrypted);
+        db.record_vote(&election.id, "aa", &ballot, &election.vote_counts()).await.unwrap();
+        assert_eq!(db.get_ballots(&election.id).await.unwrap(), vec![ballot.clone()]);
+        // Not counted until the tally
+        assert!(db.check_integrity().await.unwrap().is_clean());
+
+        election.decrypt_tally(&keys, &[ballot]).unwrap();
+        db.record_tally(&election.id, &election.vote_counts(), election.tally_proof.as_ref().unwrap()).await.unwrap();
+        assert!(db.check_integrity().await.unwrap().is_clean());
+        let record = db.load_all_elections().await.unwrap().remove(0);
+        assert_eq!(record.tally_key, election.tally_key);
+        let restored = Election::from_database(record, db.get_candidates(&election.id).await.unwrap(), vec![], vec!["aa".to_string()]);
+        assert_eq!(restored.tally_proof, election.tally_proof);
+        assert_eq!(restored.vote_counts(), vec![(2, 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_check_integrity_and_repair() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let election = Election::new("Checked".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+        db.record_vote(&election.id, "aa", &PublishedBallot::new(&election.id, &[0xaa], vec![1]), &[(1, 1)]).await.unwrap();
+        assert!(db.check_integrity().await.unwrap().is_clean());
+
+        // Rows left behind by versions that did not enforce foreign keys
+        let mut conn = db.pool.acquire().await.unwrap();
+        sqlx::query("PRAGMA foreign_keys = OFF").execute(&mut *conn).await.unwrap();
+        sqlx::query("INSERT INTO candidates (election_id, candidate_id, name) VALUES ('gone', 1, 'Ghost')")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO election_voters (election_id, voter_pubkey, created_at) VALUES ('gone', 'pk', 0)")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        sqlx::query("PRAGMA foreign_keys = ON").execute(&mut *conn).await.unwrap();
+        drop(conn);
+        db.save_used_token(&election.id, "bb").await.unwrap();
+
+        let report = db.check_integrity().await.unwrap();
+        assert_eq!(report.orphan_rows, vec![("candidates", 1), ("election_voters", 1)]);
+        assert_eq!(report.orphan_total(), 2);
+        assert_eq!(
+            report.vote_mismatches,
+            vec![VoteCountMismatch {
+                election_id: election.id.clone(),
+                counted_votes: 1,
+                used_tokens: 2,
+            }]
+        );
+
+        assert_eq!(db.repair_orphan_rows().await.unwrap(), 2);
+        let report = db.check_integrity().await.unwrap();
+        assert!(report.orphan_rows.is_empty());
+        // Vote mismatches are reported but never repaired automatically
+        assert_eq!(report.vote_mismatches.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_export_table() {
+        let (db, _temp_file) = create_test_db().await;
+
+        let election = Election::new("Export, \"quoted\"".to_string(), vec![Candidate::new(1, "Alice"), Candidate::new(2, "Bob")], 1000, 3600, "key".to_string());
+        let other = Election::new("Other".to_string(), vec![Candidate::new(1, "Carol")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+        db.upsert_election(&other).await.unwrap();
+        db.record_vote(&election.id, "aa", &PublishedBallot::new(&election.id, &[0xaa], vec![2]), &[(2, 1)]).await.unwrap();
+
+        let candidates = db.export_table(ExportTable::Candidates, Some(&election.id)).await.unwrap();
+        assert_eq!(candidates.columns, vec!["election_id", "candidate_id", "name", "vote_count"]);
+        assert_eq!(candidates.rows.len(), 2);
+        assert_eq!(
+            candidates.to_csv(),
+            format!("election_id,candidate_id,name,vote_count\n{0},1,Alice,0\n{0},2,Bob,1\n", election.id)
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&candidates.to_json().unwrap()).unwrap();
+        assert_eq!(json[1]["name"], "Bob");
+        assert_eq!(json[1]["vote_count"], 1);
+
+        // Without a filter every election is exported
+        assert_eq!(db.export_table(ExportTable::Candidates, None).await.unwrap().rows.len(), 3);
+
+        let elections = db.export_table(ExportTable::Elections, Some(&election.id)).await.unwrap();
+        assert!(elections.to_csv().contains(",\"Export, \"\"quoted\"\"\","));
+
+        let tokens = db.export_table(ExportTable::UsedTokens, None).await.unwrap();
+        assert_eq!(tokens.rows[0][1], "aa");
+
+        assert_eq!(ExportTable::from_name("voters"), Some(ExportTable::Voters));
+        assert_eq!(ExportTable::from_name("message_log"), None);
+    }
+
+    #[tokio::test]
+    async fn test_replication_log() {
+        let (db, _temp_file) = create_test_db().await;
+        assert_eq!(db.get_replication_log_range().await.unwrap(), (1, 0));
+        let source = db.get_replication_source().await.unwrap();
+        assert_eq!(db.get_replication_source().await.unwrap(), source);
+
+        let election = Election::new("Logged".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        db.upsert_election(&election).await.unwrap();
+        db.save_results_timestamp(&election.id, "event", &[0xab, 0xcd]).await.unwrap();
+        // Not replicated
+        db.set_last_processed_at(100).await.unwrap();
+
+        let changes = db.get_replication_changes(0, 100).await.unwrap();
+        let tables: Vec<&str> = changes.iter().map(|change| change.table.as_str()).collect();
+        assert_eq!(tables, vec!["elections", "candidates", "results_timestamps"]);
+        let row: serde_json::Value = serde_json::from_str(&changes[2].row).unwrap();
+        assert_eq!(row["proof"], "ABCD");
+        let (_, last) = db.get_replication_log_range().await.unwrap();
+        assert_eq!(last, changes[2].seq);
+        assert_eq!(db.get_replication_changes(changes[0].seq, 1).await.unwrap()[0], changes[1]);
+
+        let rows = db.get_replicated_rows("candidates", 0, 10).await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert!(db.get_replicated_rows("relay_events", 0, 10).await.is_err());
+
+        // Pruning keeps the position of the last change
+        assert_eq!(db.prune_replication_log(i64::MAX).await.unwrap(), 3);
+        assert_eq!(db.get_replication_log_range().await.unwrap(), (last + 1, last));
+    }
+}
@@ -1,15 +1,21 @@
 /*! election.rs — Electoral Commission logic
 Manages voter registration, issuance of blind tokens, vote reception, and counting. */
 
-use blind_rsa_signatures::{BlindSignature, BlindedMessage, Options, SecretKey as RSASecretKey};
+use blind_rsa_signatures::{BlindSignature, BlindedMessage};
 use nanoid::nanoid;
-use nostr_sdk::PublicKey;
+use nostr_sdk::secp256k1::{self, Secp256k1};
+use nostr_sdk::{Keys, PublicKey};
 use num_bigint_dig::BigUint;
-use rand::thread_rng;
-use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 
-use crate::Candidate;
+use crate::types::Candidate;
+use crate::signer::BlindSigner;
+use criptocracia_protocol::tally::parse_tally_key;
+use criptocracia_protocol::{
+    AnonymousTokenRequest, ElectionEvent, EncryptedBallot, ErrorCode, MerkleRoll, PROTOCOL_VERSION, PublishedBallot, TallyError,
+    TallyProof, TokenBinding, TokenScheme, VotingMethod,
+};
 use crate::database::{ElectionRecord, CandidateRecord};
 
 /// Blind signature petition made by a voter.
@@ -19,13 +25,88 @@ pub struct BlindTokenRequest {
     pub blinded_h_n: BlindedMessage,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, PartialEq, Clone, Copy)]
-pub enum Status {
-    Open,
-    InProgress,
-    Finished,
-    Canceled,
+/// Ballot of a vote: the candidate, or its encryption in elections with an
+/// encrypted tally.
+#[derive(Debug, Clone)]
+pub enum Ballot {
+    Plain(u8),
+    Encrypted(EncryptedBallot),
+}
+
+pub use criptocracia_protocol::Status;
+
+/// Why an election refuses a voter, a token request or a vote.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ElectionError {
+    /// Voters are only registered before the election starts
+    #[error("Cannot register voter: election is not open")]
+    NotOpen,
+    /// Votes are only received while the election is in progress
+    #[error("Cannot receive vote: election is not in progress")]
+    NotInProgress,
+    /// Tokens are only issued until the election is finished or canceled
+    #[error("Cannot issue token: election is closed")]
+    Closed,
+    /// The voter's pubkey is neither hex nor npub
+    #[error("Invalid pubkey format")]
+    InvalidPubkey,
+    /// The voter isn't on the roll, or was already issued a token
+    #[error("Unauthorized voter or nonce hash already issued")]
+    Unauthorized,
+    /// The election only takes token requests with a ring proof
+    #[error("The election only takes anonymous token requests")]
+    AnonymousOnly,
+    /// The election doesn't take token requests with a ring proof
+    #[error("The election doesn't take anonymous token requests")]
+    NotAnonymous,
+    /// A member of the ring of an anonymous request isn't on the roll
+    #[error("Ring member not on the roll")]
+    RingMemberNotOnRoll,
+    #[error("Invalid ring proof")]
+    InvalidRingProof,
+    /// The token wasn't bound to this election and its end
+    #[error("Token not bound to the election")]
+    UnboundToken,
+    /// The token was already used to vote
+    #[error("duplicated vote")]
+    DuplicateToken,
+    /// The vote is for a candidate the election doesn't have
+    #[error("No candidate {0} in the election")]
+    InvalidCandidate(u8),
+    /// A plain ballot in an election with an encrypted tally
+    #[error("The election only takes encrypted ballots")]
+    EncryptedOnly,
+    /// An encrypted ballot in an election without an encrypted tally
+    #[error("The election doesn't take encrypted ballots")]
+    PlainOnly,
+    /// The encrypted ballot isn't of a single candidate for the token
+    #[error("Invalid encrypted ballot")]
+    InvalidBallot,
+    /// The EC couldn't sign the token, or read its tally key
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl ElectionError {
+    /// Code of the error in the answer to the voter
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ElectionError::NotOpen | ElectionError::NotInProgress | ElectionError::Closed => ErrorCode::ElectionClosed,
+            ElectionError::DuplicateToken => ErrorCode::Duplicate,
+            ElectionError::InvalidPubkey
+            | ElectionError::InvalidCandidate(_)
+            | ElectionError::EncryptedOnly
+            | ElectionError::PlainOnly
+            | ElectionError::InvalidBallot => ErrorCode::BadFormat,
+            ElectionError::Unauthorized
+            | ElectionError::AnonymousOnly
+            | ElectionError::NotAnonymous
+            | ElectionError::RingMemberNotOnRoll
+            | ElectionError::InvalidRingProof
+            | ElectionError::UnboundToken => ErrorCode::Unauthorized,
+            ElectionError::Internal(_) => ErrorCode::Internal,
+        }
+    }
 }
 
 /// Commissioner of Elections (CE) manages the election process.
@@ -34,6 +115,7 @@ pub struct Election {
     pub id: String,
     pub name: String,
     pub authorized_voters: HashSet<String>, // allowed pubkeys
+    pub roll: HashSet<String>,              // every registered pubkey, issued a token or not
     pub used_tokens: HashSet<BigUint>,      // h_n already used
     pub votes: Vec<u8>,                     // votes received
     pub candidates: Vec<Candidate>,
@@ -41,6 +123,13 @@ pub struct Election {
     pub end_time: u64,
     pub status: Status,
     pub rsa_pub_key: String, // RSA public key for the EC
+    pub issuance_log: bool,  // record which voters were issued a token
+    pub token_scheme: TokenScheme, // blind signature scheme of the tokens
+    pub bound_tokens: bool,        // tokens sign the hash of nonce || id || end_time
+    pub anonymous_requests: bool,  // tokens are requested with ring proofs, not pubkeys
+    pub rehearsal: bool,           // a drill of an EC running a rehearsal (--dry-run)
+    pub tally_key: Option<String>, // ballots are encrypted to this key and counted at the end
+    pub tally_proof: Option<TallyProof>, // decryption of the encrypted tally, once counted
 }
 
 impl Election {
@@ -63,6 +152,7 @@ impl Election {
             id,
             name,
             authorized_voters: HashSet::new(),
+            roll: HashSet::new(),
             used_tokens: HashSet::new(),
             votes: vec![],
             candidates,
@@ -70,10 +160,25 @@ impl Election {
             end_time,
             status: Status::Open,
             rsa_pub_key,
+            issuance_log: false,
+            token_scheme: TokenScheme::default(),
+            bound_tokens: true,
+            anonymous_requests: false,
+            rehearsal: false,
+            tally_key: None,
+            tally_proof: None,
         }
     }
 
-    /// Restore an election from database records
+    /// Encrypt the ballots of the election to a tally key derived from the
+    /// EC's keys. They are counted once the election is over, with a proof.
+    pub fn enable_encrypted_tally(&mut self, keys: &Keys) {
+        let secret = tally_secret(keys, &self.id);
+        self.tally_key = Some(secret.public_key(&Secp256k1::new()).to_string());
+    }
+
+    /// Restore an election from database records. The roll is that of the
+    /// voters still authorized until it's set from every registered voter.
     pub fn from_database(
         election_record: ElectionRecord,
         candidate_records: Vec<CandidateRecord>,
@@ -88,6 +193,12 @@ impl Election {
             _ => Status::Open,
         };
 
+        // Rebuild the received votes from the persisted candidate vote counts
+        let votes = candidate_records
+            .iter()
+            .flat_map(|c| std::iter::repeat_n(c.candidate_id as u8, c.vote_count.max(0) as usize))
+            .collect();
+
         let candidates = candidate_records
             .into_iter()
             .map(|c| Candidate::new(c.candidate_id as u8, c.name))
@@ -111,96 +222,119 @@ impl Election {
         Self {
             id: election_record.id,
             name: election_record.name,
+            roll: authorized_voters_set.clone(),
             authorized_voters: authorized_voters_set,
             used_tokens: used_tokens_set,
-            votes: vec![], // We'll reconstruct votes from candidate vote counts if needed
+            votes,
             candidates,
             start_time: election_record.start_time as u64,
             end_time: election_record.end_time as u64,
             status,
             rsa_pub_key: election_record.rsa_pub_key,
+            issuance_log: election_record.issuance_log,
+            token_scheme: TokenScheme::parse(&election_record.token_scheme).unwrap_or_default(),
+            bound_tokens: election_record.bound_tokens,
+            anonymous_requests: election_record.anonymous_requests,
+            rehearsal: election_record.rehearsal,
+            tally_key: election_record.tally_key,
+            tally_proof: election_record.tally_proof.and_then(|proof| match TallyProof::from_json(&proof) {
+                Ok(proof) => Some(proof),
+                Err(e) => {
+                    log::warn!("Failed to parse tally proof: {}", e);
+                    None
+                }
+            }),
         }
     }
 
-    pub fn register_voter(&mut self, voter_pk: &str) {
+    /// Registers a voter, by hex or npub pubkey, while the election is open.
+    /// Registering a voter again changes nothing.
+    pub fn register_voter(&mut self, voter_pk: &str) -> Result<(), ElectionError> {
         if self.status != Status::Open {
             log::warn!("Cannot register voter: election is not open");
-            return;
+            return Err(ElectionError::NotOpen);
         }
         println!("🔑 Registering voter: {}", voter_pk);
 
         // Convert npub to hex format if needed
-        let hex_pubkey = if voter_pk.starts_with("npub") {
-            match PublicKey::parse(voter_pk) {
-                Ok(pk) => pk.to_hex(),
-                Err(e) => {
-                    log::warn!("Invalid npub format: {}", e);
-                    return;
-                }
-            }
-        } else {
-            // Validate hex format
-            match PublicKey::from_hex(voter_pk) {
-                Ok(pk) => pk.to_hex(),
-                Err(e) => {
-                    log::warn!("Invalid pubkey format: {}", e);
-                    return;
-                }
-            }
-        };
+        let hex_pubkey = parse_pubkey(voter_pk).inspect_err(|_| log::warn!("Invalid pubkey {}", voter_pk))?;
 
-        // 1) Check that the pubkey is not already registered.
-        if self.authorized_voters.contains(&hex_pubkey) {
+        // 1) Check that the pubkey is not already registered, nor issued a token.
+        if !self.roll.insert(hex_pubkey.clone()) {
             println!("⚠️ Voter already registered");
-            return;
+            return Ok(());
         }
         // 2) Add to the list of authorized voters in hex format.
         self.authorized_voters.insert(hex_pubkey);
+        Ok(())
     }
 
     /// Blindly signs the hash submitted by a voter.
     pub fn issue_token(
         &mut self,
         req: BlindTokenRequest,
-        secret_key: RSASecretKey,
-    ) -> Result<BlindSignature, &'static str> {
-        let options = Options::default();
-        let rng = &mut thread_rng();
+        signer: &dyn BlindSigner,
+    ) -> Result<BlindSignature, ElectionError> {
+        self.authorize_token(&req)?;
+        // 2) Sign it
+        let blind_sig = signer.blind_sign(&req.blinded_h_n).map_err(|e| {
+            log::error!("Blind signing failed: {}", e);
+            ElectionError::Internal("signing error".to_string())
+        })?;
+        log::info!("Blind signature issued");
+        Ok(blind_sig)
+    }
 
+    /// Checks that the voter may get a token and marks it as issued, for
+    /// tokens signed elsewhere, e.g. by the trustees.
+    pub fn authorize_token(&mut self, req: &BlindTokenRequest) -> Result<(), ElectionError> {
         // Convert voter_pk to hex format for comparison
-        let hex_pubkey = if req.voter_pk.starts_with("npub") {
-            match PublicKey::parse(&req.voter_pk) {
-                Ok(pk) => pk.to_hex(),
-                Err(_) => return Err("Invalid npub format"),
-            }
-        } else {
-            match PublicKey::from_hex(&req.voter_pk) {
-                Ok(pk) => pk.to_hex(),
-                Err(_) => return Err("Invalid pubkey format"),
-            }
-        };
+        let hex_pubkey = parse_pubkey(&req.voter_pk)?;
 
+        if self.anonymous_requests {
+            return Err(ElectionError::AnonymousOnly);
+        }
         // Check that the voter is authorized and has not previously requested it.
         if !self.authorized_voters.remove(&hex_pubkey) {
-            return Err("Unauthorized voter or nonce hash already issued");
+            return Err(ElectionError::Unauthorized);
         }
-        // 2) Sign it
-        let blind_sig = secret_key
-            .blind_sign(rng, &req.blinded_h_n, &options)
-            .map_err(|_| "signing error")?;
-        log::info!("Blind signature issued");
-        Ok(blind_sig)
+        Ok(())
     }
 
-    /// Receives a vote along with (h_n, token) and verifies validity.
-    pub fn receive_vote(&mut self, h_n: BigUint, vote: u8) -> Result<(), &'static str> {
+    /// Checks the ring proof of an anonymous token request: every member of
+    /// the ring is on the roll and one of them signed the blinded hash.
+    /// Returns the key image, which the caller must check wasn't used.
+    pub fn authorize_anonymous(&self, req: &AnonymousTokenRequest, blinded_h_n: &[u8]) -> Result<String, ElectionError> {
+        if !self.anonymous_requests {
+            return Err(ElectionError::NotAnonymous);
+        }
+        if !matches!(self.status, Status::Open | Status::InProgress) {
+            return Err(ElectionError::Closed);
+        }
+        if !req.proof.ring.iter().all(|pk| self.roll.contains(pk)) {
+            return Err(ElectionError::RingMemberNotOnRoll);
+        }
+        if !req.proof.verify(&self.id, blinded_h_n) {
+            return Err(ElectionError::InvalidRingProof);
+        }
+        Ok(req.proof.key_image.clone())
+    }
+
+    /// Receives a vote along with (h_n, token) and verifies validity. A vote
+    /// for a candidate the election doesn't have is refused before the token
+    /// is spent, so the voter can send a valid one.
+    pub fn receive_vote(&mut self, h_n: BigUint, vote: u8) -> Result<(), ElectionError> {
         if self.status != Status::InProgress {
-            return Err("Cannot receive vote: election is not in progress");
+            return Err(ElectionError::NotInProgress);
+        }
+        if !self.candidates.iter().any(|c| c.id == vote) {
+            log::warn!("Vote for unknown candidate {}", vote);
+            return Err(ElectionError::InvalidCandidate(vote));
         }
         // Avoid double voting.
         if !self.used_tokens.insert(h_n.clone()) {
             log::warn!("Duplicate token detected for h_n={}", h_n);
-            return Err("duplicated vote");
+            return Err(ElectionError::DuplicateToken);
         }
         // Store vote (for demo purposes it will be the candidate's number).
         self.votes.push(vote);
@@ -209,13 +343,124 @@ impl Election {
         Ok(())
     }
 
-    /// Save used token to database (to be called after receive_vote)
-    pub async fn save_used_token_to_db(&self, db: &crate::database::Database, h_n: &BigUint) -> Result<(), anyhow::Error> {
-        let token_hash = format!("{:x}", h_n);
-        db.save_used_token(&self.id, &token_hash).await?;
+    /// Receives an encrypted ballot, checked with `check_ballot`, along with
+    /// h_n. Nothing is counted until the tally.
+    pub fn receive_encrypted_vote(&mut self, h_n: BigUint) -> Result<(), ElectionError> {
+        if self.status != Status::InProgress {
+            return Err(ElectionError::NotInProgress);
+        }
+        if !self.used_tokens.insert(h_n.clone()) {
+            log::warn!("Duplicate token detected for h_n={}", h_n);
+            return Err(ElectionError::DuplicateToken);
+        }
+        log::debug!("Encrypted vote received");
+
+        Ok(())
+    }
+
+    /// Check that a ballot is of the kind the election takes, and that an
+    /// encrypted one holds a single choice among the candidates for h_n.
+    pub fn check_ballot(&self, h_n: &[u8], ballot: &Ballot) -> Result<(), ElectionError> {
+        match (ballot, &self.tally_key) {
+            (Ballot::Plain(_), None) => Ok(()),
+            (Ballot::Plain(_), Some(_)) => Err(ElectionError::EncryptedOnly),
+            (Ballot::Encrypted(_), None) => Err(ElectionError::PlainOnly),
+            (Ballot::Encrypted(ballot), Some(key)) => {
+                let key = parse_tally_key(key).map_err(|_| ElectionError::Internal("Invalid tally key".to_string()))?;
+                if !ballot.verify(&key, self.candidates.len(), &self.id, h_n) {
+                    return Err(ElectionError::InvalidBallot);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether the encrypted ballots of the election are still to be counted
+    pub fn awaiting_tally(&self) -> bool {
+        self.tally_key.is_some() && self.tally_proof.is_none()
+    }
+
+    /// Count the encrypted ballots of the election by decrypting their sum
+    /// with the tally secret, proving each count. The counts become the
+    /// votes of the election.
+    pub fn decrypt_tally(&mut self, keys: &Keys, ballots: &[PublishedBallot]) -> Result<(), TallyError> {
+        let encrypted: Vec<&EncryptedBallot> = ballots.iter().filter_map(|b| b.encrypted.as_ref()).collect();
+        if encrypted.len() != ballots.len() {
+            return Err(TallyError::InvalidBallot);
+        }
+        let proof = TallyProof::decrypt(&tally_secret(keys, &self.id), self.candidates.len(), &self.id, &encrypted)?;
+        let mut ids: Vec<u8> = self.candidates.iter().map(|c| c.id).collect();
+        ids.sort_unstable();
+        self.votes = ids
+            .iter()
+            .zip(&proof.decryptions)
+            .flat_map(|(id, decryption)| std::iter::repeat_n(*id, decryption.count as usize))
+            .collect();
+        self.tally_proof = Some(proof);
+        Ok(())
+    }
+
+    /// Check that a token was bound to this election and its end, so tokens
+    /// of other elections, or of an earlier election with the same ID, can't
+    /// be redeemed in it. Elections created before binding accept any token.
+    pub fn check_binding(&self, h_n: &[u8], binding: Option<&TokenBinding>) -> Result<(), ElectionError> {
+        if !self.bound_tokens {
+            return Ok(());
+        }
+        match binding {
+            Some(binding) if binding.expiry == self.end_time && binding.hash(&self.id) == h_n => {}
+            _ => return Err(ElectionError::UnboundToken),
+        }
         Ok(())
     }
 
+    /// Authorize a voter again after a token issuance could not be persisted
+    pub fn restore_voter(&mut self, hex_pubkey: &str) {
+        self.authorized_voters.insert(hex_pubkey.to_string());
+    }
+
+    /// Undo the last vote received with h_n after it could not be persisted
+    pub fn revert_vote(&mut self, h_n: &BigUint) {
+        // Encrypted ballots aren't kept in memory
+        if self.used_tokens.remove(h_n) && self.tally_key.is_none() {
+            self.votes.pop();
+        }
+    }
+
+    /// Candidate vote counts sorted by candidate id, as persisted and published
+    pub fn vote_counts(&self) -> Vec<(u8, u32)> {
+        let mut counts: Vec<(u8, u32)> = self
+            .tally()
+            .into_iter()
+            .map(|(candidate, count)| (candidate.id, count))
+            .collect();
+        counts.sort_unstable();
+        counts
+    }
+
+    /// Describe any mismatch between the restored votes and used tokens
+    pub fn consistency_issues(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+
+        if self.votes.len() != self.used_tokens.len() && !self.awaiting_tally() {
+            issues.push(format!(
+                "{} vote(s) counted but {} token(s) used",
+                self.votes.len(),
+                self.used_tokens.len()
+            ));
+        }
+
+        let unknown = self
+            .votes
+            .iter()
+            .filter(|v| !self.candidates.iter().any(|c| c.id == **v))
+            .count();
+        if unknown > 0 {
+            issues.push(format!("{} vote(s) for unknown candidates", unknown));
+        }
+
+        issues
+    }
 
     /// Check if election should be in progress based on current time
     pub fn should_be_in_progress(&self, current_time: u64) -> bool {
@@ -241,6 +486,20 @@ impl Election {
         old_status != self.status
     }
 
+    /// Check if the election data retention period has expired.
+    /// Only finished or canceled elections are eligible for purging.
+    pub fn retention_expired(&self, current_time: u64, retention_days: u64) -> bool {
+        matches!(self.status, Status::Finished | Status::Canceled)
+            && current_time >= self.end_time.saturating_add(retention_days * 24 * 60 * 60)
+    }
+
+    /// Drop voter roll and used tokens kept in memory after a data purge
+    pub fn clear_voter_data(&mut self) {
+        self.authorized_voters.clear();
+        self.roll.clear();
+        self.used_tokens.clear();
+    }
+
     /// Returns a map candidate → number of votes.
     pub fn tally(&self) -> HashMap<Candidate, u32> {
         let mut counts = HashMap::new();
@@ -252,35 +511,77 @@ impl Election {
         counts
     }
 
-    pub fn as_json(&self) -> Value {
-        let election_data = serde_json::json!({
-            "id": self.id.to_string(),
-            "name": self.name,
-            "start_time": self.start_time,
-            "end_time": self.end_time,
-            "candidates": self.candidates,
-            "status": match self.status {
-                Status::Open => "open",
-                Status::InProgress => "in-progress",
-                Status::Finished => "finished",
-                Status::Canceled => "canceled",
+    /// Content of the election event published on Nostr
+    pub fn to_event(&self) -> ElectionEvent {
+        ElectionEvent {
+            version: PROTOCOL_VERSION,
+            id: self.id.clone(),
+            name: self.name.clone(),
+            start_time: self.start_time,
+            end_time: self.end_time,
+            candidates: self.candidates.clone(),
+            status: self.status,
+            rsa_pub_key: self.rsa_pub_key.clone(),
+            voting_method: VotingMethod::Plurality,
+            issuance_log: self.issuance_log,
+            voter_roll: match self.anonymous_requests {
+                true => self.voter_roll().published(),
+                false => self.voter_roll().commitment(),
             },
-            "rsa_pub_key": self.rsa_pub_key,
-        });
-        election_data
+            token_scheme: self.token_scheme,
+            bound_tokens: self.bound_tokens,
+            anonymous_requests: self.anonymous_requests,
+            tally_key: self.tally_key.clone(),
+            rehearsal: self.rehearsal,
+            content_hash: None,
+            content_sig: None,
+        }
     }
 
+    /// Merkle tree of the registered voters, committed to in the election
+    /// event. Voters stay on it once issued a token, so it only changes while
+    /// the election is open.
+    pub fn voter_roll(&self) -> MerkleRoll {
+        MerkleRoll::new(&self.roll)
+    }
+
+    /// Canonical JSON of the election event, with its content hash
     pub fn as_json_string(&self) -> String {
-        let election_data = self.as_json();
-        serde_json::to_string(&election_data).unwrap()
+        self.to_event().as_canonical_json()
     }
 }
 
+/// Hex form of a voter's pubkey given in hex or as an npub
+fn parse_pubkey(voter_pk: &str) -> Result<String, ElectionError> {
+    let pk = match voter_pk.starts_with("npub") {
+        true => PublicKey::parse(voter_pk),
+        false => PublicKey::from_hex(voter_pk),
+    };
+    pk.map(|pk| pk.to_hex()).map_err(|_| ElectionError::InvalidPubkey)
+}
+
+/// Secret of the encrypted tally of an election, derived from the EC's
+/// Nostr key and the election ID so it needn't be stored.
+pub fn tally_secret(keys: &Keys, election_id: &str) -> secp256k1::SecretKey {
+    (0u32..)
+        .find_map(|counter| {
+            let mut hasher = Sha256::new();
+            hasher.update(b"criptocracia/tally-key");
+            hasher.update(keys.secret_key().secret_bytes());
+            hasher.update(election_id.as_bytes());
+            hasher.update(counter.to_be_bytes());
+            secp256k1::SecretKey::from_slice(&hasher.finalize()).ok()
+        })
+        .expect("hashes are almost always valid scalars")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::signer::LocalSigner;
     use crate::util::load_keys;
     use blind_rsa_signatures::Options;
+    use criptocracia_protocol::RingProof;
     use nostr_sdk::ToBech32;
     use num_bigint_dig::{BigUint, RandBigInt};
     use rand::rngs::OsRng;
@@ -311,6 +612,71 @@ mod tests {
         assert!(e.used_tokens.is_empty());
         assert_eq!(e.candidates.len(), 2);
         assert_eq!(e.id.len(), 4);
+        assert!(e.bound_tokens);
+    }
+
+    #[test]
+    fn test_authorize_anonymous() {
+        let mut e = make_election();
+        let voters: Vec<nostr_sdk::Keys> = (0..3).map(|_| nostr_sdk::Keys::generate()).collect();
+        for keys in &voters {
+            e.register_voter(&keys.public_key().to_hex()).unwrap();
+        }
+        let roll: Vec<String> = voters.iter().map(|k| k.public_key().to_hex()).collect();
+        let proof = RingProof::sign(voters[1].secret_key(), &roll, &e.id, b"blinded").unwrap();
+        let req = AnonymousTokenRequest { blinded_h_n: String::new(), proof };
+
+        assert!(e.authorize_anonymous(&req, b"blinded").is_err());
+        e.anonymous_requests = true;
+        assert_eq!(e.authorize_anonymous(&req, b"blinded").unwrap(), req.proof.key_image);
+        assert!(e.authorize_anonymous(&req, b"another").is_err());
+        // The roll is published for voters to pick their ring from
+        assert!(e.to_event().voter_roll.unwrap().pubkeys_match());
+
+        // Identified requests are refused, they would get a second token
+        let identified = BlindTokenRequest { voter_pk: roll[0].clone(), blinded_h_n: BlindedMessage::from(vec![1]) };
+        assert!(e.authorize_token(&identified).is_err());
+
+        e.roll.remove(&roll[2]);
+        assert_eq!(e.authorize_anonymous(&req, b"blinded"), Err(ElectionError::RingMemberNotOnRoll));
+    }
+
+    #[test]
+    fn test_authorize_anonymous_closed() {
+        let mut e = make_election();
+        e.anonymous_requests = true;
+        let keys = nostr_sdk::Keys::generate();
+        e.register_voter(&keys.public_key().to_hex()).unwrap();
+        let roll = vec![keys.public_key().to_hex()];
+        let proof = RingProof::sign(keys.secret_key(), &roll, &e.id, b"blinded").unwrap();
+        let req = AnonymousTokenRequest { blinded_h_n: String::new(), proof };
+
+        e.status = Status::InProgress;
+        assert!(e.authorize_anonymous(&req, b"blinded").is_ok());
+        for status in [Status::Finished, Status::Canceled] {
+            e.status = status;
+            let err = e.authorize_anonymous(&req, b"blinded").unwrap_err();
+            assert_eq!(err, ElectionError::Closed);
+            assert_eq!(err.code(), ErrorCode::ElectionClosed);
+        }
+    }
+
+    #[test]
+    fn test_check_binding() {
+        let mut e = make_election();
+        let binding = TokenBinding { nonce: vec![7; 16], expiry: e.end_time };
+        let h_n = binding.hash(&e.id);
+        assert!(e.check_binding(&h_n, Some(&binding)).is_ok());
+        assert!(e.check_binding(&h_n, None).is_err());
+
+        // A token bound to another election, or to an earlier one with the same ID
+        let other = binding.hash("ffff");
+        assert!(e.check_binding(&other, Some(&binding)).is_err());
+        let earlier = TokenBinding { expiry: e.start_time, ..binding.clone() };
+        assert!(e.check_binding(&earlier.hash(&e.id), Some(&earlier)).is_err());
+
+        e.bound_tokens = false;
+        assert!(e.check_binding(&other, None).is_ok());
     }
 
     #[test]
@@ -320,17 +686,17 @@ mod tests {
         let hex_pk = "e3f33350728580cd51db8f4048d614910d48a5c0d7f1af6811e83c07fc865a5c";
 
         // Register with hex format
-        e.register_voter(hex_pk);
+        e.register_voter(hex_pk).unwrap();
         assert_eq!(e.authorized_voters.len(), 1);
         assert!(e.authorized_voters.contains(hex_pk));
 
         // Re-registration with same hex does not duplicate
-        e.register_voter(hex_pk);
+        e.register_voter(hex_pk).unwrap();
         assert_eq!(e.authorized_voters.len(), 1);
 
         // Change status to InProgress and do not allow registration
         e.status = Status::InProgress;
-        e.register_voter("another_key");
+        assert_eq!(e.register_voter("another_key"), Err(ElectionError::NotOpen));
         assert_eq!(e.authorized_voters.len(), 1);
     }
 
@@ -344,14 +710,14 @@ mod tests {
         let npub_pk = pk.to_bech32().unwrap();
 
         // Register with npub format
-        e.register_voter(&npub_pk);
+        e.register_voter(&npub_pk).unwrap();
         assert_eq!(e.authorized_voters.len(), 1);
         // Should be stored in hex format
         assert!(e.authorized_voters.contains(hex_pk));
         assert!(!e.authorized_voters.contains(&npub_pk));
 
         // Re-registration with hex format of same key does not duplicate
-        e.register_voter(hex_pk);
+        e.register_voter(hex_pk).unwrap();
         assert_eq!(e.authorized_voters.len(), 1);
     }
 
@@ -363,12 +729,17 @@ mod tests {
         // Status Open → error
         assert_eq!(
             e.receive_vote(h1.clone(), 1).unwrap_err(),
-            "Cannot receive vote: election is not in progress"
+            ElectionError::NotInProgress
         );
 
         // change to InProgress
         e.status = Status::InProgress;
 
+        // A vote for a candidate the election doesn't have leaves the token unspent
+        assert_eq!(e.receive_vote(h1.clone(), 9), Err(ElectionError::InvalidCandidate(9)));
+        assert_eq!(ElectionError::InvalidCandidate(9).code(), ErrorCode::BadFormat);
+        assert!(e.used_tokens.is_empty() && e.votes.is_empty());
+
         // First valid vote
         assert!(e.receive_vote(h1.clone(), 2).is_ok());
         assert_eq!(e.votes, vec![2]);
@@ -376,8 +747,9 @@ mod tests {
         // Duplicated vote → error
         assert_eq!(
             e.receive_vote(h1.clone(), 2).unwrap_err(),
-            "duplicated vote"
+            ElectionError::DuplicateToken
         );
+        assert_eq!(ElectionError::DuplicateToken.code(), ErrorCode::Duplicate);
     }
 
     #[test]
@@ -396,6 +768,123 @@ mod tests {
         assert_eq!(counts, expected);
     }
 
+    #[test]
+    fn test_encrypted_tally() {
+        let keys = Keys::generate();
+        let mut e = make_election();
+        e.enable_encrypted_tally(&keys);
+        e.status = Status::InProgress;
+        let key = parse_tally_key(e.tally_key.as_ref().unwrap()).unwrap();
+        assert_eq!(e.to_event().tally_key, e.tally_key);
+
+        // Only valid encrypted ballots are taken
+        assert!(e.check_ballot(&[1], &Ballot::Plain(1)).is_err());
+        let ballots: Vec<PublishedBallot> = [1, 0, 1]
+            .iter()
+            .enumerate()
+            .map(|(i, choice)| {
+                let h_n = [i as u8 + 1];
+                let encrypted = EncryptedBallot::encrypt(&key, 2, *choice, &e.id, &h_n).unwrap();
+                assert!(e.check_ballot(&h_n, &Ballot::Encrypted(encrypted.clone())).is_ok());
+                assert!(e.check_ballot(&[9], &Ballot::Encrypted(encrypted.clone())).is_err());
+                e.receive_encrypted_vote(BigUint::from(i + 1)).unwrap();
+                PublishedBallot::new_encrypted(&e.id, &h_n, encrypted)
+            })
+            .collect();
+        assert!(e.votes.is_empty());
+        assert!(e.awaiting_tally());
+        assert!(e.consistency_issues().is_empty());
+        e.revert_vote(&BigUint::from(3u8));
+        assert!(e.used_tokens.len() == 2 && e.votes.is_empty());
+
+        e.decrypt_tally(&keys, &ballots).unwrap();
+        assert!(!e.awaiting_tally());
+        assert_eq!(e.vote_counts(), vec![(1, 1), (2, 2)]);
+        let proof = e.tally_proof.as_ref().unwrap();
+        assert_eq!(proof.verify(&key, 2, &e.id, &ballots.iter().filter_map(|b| b.encrypted.as_ref()).collect::<Vec<_>>()), Ok(vec![1, 2]));
+
+        // The key can't be derived without the EC's keys
+        let mut other = make_election();
+        other.id = e.id.clone();
+        other.enable_encrypted_tally(&Keys::generate());
+        assert_ne!(other.tally_key, e.tally_key);
+    }
+
+    #[test]
+    fn test_revert_vote_and_consistency() {
+        let mut e = make_election();
+        e.status = Status::InProgress;
+        let _ = e.receive_vote(BigUint::from(1u8), 1);
+        let _ = e.receive_vote(BigUint::from(2u8), 2);
+        assert_eq!(e.vote_counts(), vec![(1, 1), (2, 1)]);
+
+        e.revert_vote(&BigUint::from(2u8));
+        assert_eq!(e.vote_counts(), vec![(1, 1)]);
+        assert!(e.consistency_issues().is_empty());
+
+        // Reverting a token that was never used changes nothing
+        e.revert_vote(&BigUint::from(9u8));
+        assert_eq!(e.votes, vec![1]);
+
+        e.votes.push(7);
+        let issues = e.consistency_issues();
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0], "2 vote(s) counted but 1 token(s) used");
+        assert_eq!(issues[1], "1 vote(s) for unknown candidates");
+    }
+
+    #[test]
+    fn test_from_database_restores_votes() {
+        let record = ElectionRecord {
+            id: "abcd".to_string(),
+            name: "Restored".to_string(),
+            start_time: 1000,
+            end_time: 4600,
+            status: "in-progress".to_string(),
+            rsa_pub_key: "key".to_string(),
+            issuance_log: true,
+            token_scheme: "rsa-pss-deterministic".to_string(),
+            bound_tokens: false,
+            anonymous_requests: false,
+            rehearsal: true,
+            tally_key: None,
+            tally_proof: None,
+            created_at: 0,
+            updated_at: 0,
+        };
+        let candidates = vec![
+            CandidateRecord { id: Some(1), election_id: "abcd".to_string(), candidate_id: 1, name: "Alice".to_string(), vote_count: 2 },
+            CandidateRecord { id: Some(2), election_id: "abcd".to_string(), candidate_id: 2, name: "Bob".to_string(), vote_count: 1 },
+        ];
+        let e = Election::from_database(record, candidates, vec![], vec!["1".into(), "2".into(), "3".into()]);
+
+        assert_eq!(e.status, Status::InProgress);
+        assert!(e.issuance_log);
+        assert_eq!(e.token_scheme, TokenScheme::RsaPssDeterministic);
+        assert!(!e.bound_tokens);
+        assert!(e.rehearsal && e.to_event().rehearsal);
+        assert_eq!(e.vote_counts(), vec![(1, 2), (2, 1)]);
+        assert!(e.consistency_issues().is_empty());
+    }
+
+    #[test]
+    fn test_retention_expired() {
+        let mut e = make_election();
+        let day = 24 * 60 * 60;
+
+        // Elections still accepting voters or votes are never purged
+        assert!(!e.retention_expired(e.end_time + 10 * day, 1));
+        e.status = Status::InProgress;
+        assert!(!e.retention_expired(e.end_time + 10 * day, 1));
+
+        e.status = Status::Finished;
+        assert!(!e.retention_expired(e.end_time + day - 1, 1));
+        assert!(e.retention_expired(e.end_time + day, 1));
+
+        e.status = Status::Canceled;
+        assert!(e.retention_expired(e.end_time, 0));
+    }
+
     #[test]
     fn test_as_json_string_and_value() {
         let mut e = make_election();
@@ -406,11 +895,34 @@ mod tests {
         assert_eq!(v["status"], "finished");
         assert_eq!(v["start_time"], 1000);
         assert_eq!(v["end_time"], 4600);
+        assert_eq!(v["issuance_log"], false);
         // candidates
         let cands = v["candidates"].as_array().unwrap();
         assert_eq!(cands.len(), 2);
         assert_eq!(cands[0]["id"], 1);
         assert_eq!(cands[1]["name"], "Bob");
+        // No roll committed while it is empty
+        assert!(v.get("voter_roll").is_none());
+    }
+
+    #[test]
+    fn test_event_commits_to_voter_roll() {
+        let mut e = make_election();
+        let voters: Vec<String> = (0..3).map(|_| nostr_sdk::Keys::generate().public_key().to_hex()).collect();
+        for voter in &voters {
+            e.register_voter(voter).unwrap();
+        }
+        let roll = e.to_event().voter_roll.unwrap();
+        assert_eq!(roll.size, 3);
+
+        let proof = e.voter_roll().proof(&voters[1]).unwrap();
+        assert!(proof.verify(&roll.root, &voters[1]));
+
+        // Issuing a token leaves the commitment as it was
+        e.authorized_voters.remove(&voters[1]);
+        assert_eq!(e.to_event().voter_roll.unwrap(), roll);
+        e.register_voter(&voters[1]).unwrap();
+        assert!(!e.authorized_voters.contains(&voters[1]));
     }
 
     #[test]
@@ -461,6 +973,7 @@ mod tests {
         // Setup: Load RSA keys and create election
         let (pk, sk) =
             load_keys("ec_private.pem", "ec_public.pem").expect("Failed to load RSA keys");
+        let signer = LocalSigner::new(pk.clone(), sk.clone());
 
         let mut election = Election::new(
             "Complete Flow Test".to_string(),
@@ -479,9 +992,9 @@ mod tests {
         let voter2_pk = "a1b2c3d4e5f67890123456789012345678901234567890123456789012345678";
         let voter3_pk = "1234567890abcdef1234567890abcdef1234567890abcdef1234567890abcdef";
 
-        election.register_voter(voter1_pk);
-        election.register_voter(voter2_pk);
-        election.register_voter(voter3_pk);
+        election.register_voter(voter1_pk).unwrap();
+        election.register_voter(voter2_pk).unwrap();
+        election.register_voter(voter3_pk).unwrap();
 
         assert_eq!(election.authorized_voters.len(), 3);
 
@@ -513,7 +1026,7 @@ mod tests {
 
         // 3. EC issues blind signature
         let voter1_blind_sig = election
-            .issue_token(voter1_request, sk.clone())
+            .issue_token(voter1_request, &signer)
             .expect("Failed to issue token to voter 1");
 
         // Verify voter was removed from authorized list
@@ -568,7 +1081,7 @@ mod tests {
         };
 
         let voter2_blind_sig = election
-            .issue_token(voter2_request, sk.clone())
+            .issue_token(voter2_request, &signer)
             .expect("Failed to issue token to voter 2");
 
         let voter2_token = pk
@@ -618,7 +1131,7 @@ mod tests {
         };
 
         let voter3_blind_sig = election
-            .issue_token(voter3_request, sk.clone())
+            .issue_token(voter3_request, &signer)
             .expect("Failed to issue token to voter 3");
 
         let voter3_token = pk
@@ -687,6 +1200,7 @@ mod tests {
     fn test_voting_flow_error_cases() {
         let (pk, sk) =
             load_keys("ec_private.pem", "ec_public.pem").expect("Failed to load RSA keys");
+        let signer = LocalSigner::new(pk.clone(), sk.clone());
 
         let mut election = Election::new(
             "Error Cases Test".to_string(),
@@ -697,7 +1211,7 @@ mod tests {
         );
 
         let voter_pk = "e3f33350728580cd51db8f4048d614910d48a5c0d7f1af6811e83c07fc865a5c";
-        election.register_voter(voter_pk);
+        election.register_voter(voter_pk).unwrap();
 
         election.status = Status::InProgress;
 
@@ -718,14 +1232,14 @@ mod tests {
             blinded_h_n: blinding_result1.blind_msg.clone(),
         };
 
-        let unauthorized_result = election.issue_token(unauthorized_request, sk.clone());
+        let unauthorized_result = election.issue_token(unauthorized_request, &signer);
         assert!(
             unauthorized_result.is_err(),
             "Unauthorized voter should not receive token"
         );
         assert_eq!(
             unauthorized_result.unwrap_err(),
-            "Unauthorized voter or nonce hash already issued"
+            ElectionError::Unauthorized
         );
 
         // === Test 2: Successful token issuance ===
@@ -743,7 +1257,7 @@ mod tests {
         };
 
         let blind_sig = election
-            .issue_token(valid_request, sk.clone())
+            .issue_token(valid_request, &signer)
             .expect("Valid voter should receive token");
 
         let _token = pk
@@ -762,7 +1276,7 @@ mod tests {
 
         let vote_result2 = election.receive_vote(h_n2.clone(), 2);
         assert!(vote_result2.is_err(), "Double voting should be rejected");
-        assert_eq!(vote_result2.unwrap_err(), "duplicated vote");
+        assert_eq!(vote_result2.unwrap_err(), ElectionError::DuplicateToken);
 
         // === Test 4: Trying to request token again from same voter ===
         let nonce3: BigUint = OsRng.gen_biguint(128);
@@ -777,7 +1291,7 @@ mod tests {
             blinded_h_n: blinding_result3.blind_msg.clone(),
         };
 
-        let repeat_result = election.issue_token(repeat_request, sk.clone());
+        let repeat_result = election.issue_token(repeat_request, &signer);
         assert!(repeat_result.is_err(), "Voter should not get second token");
 
         println!("✅ Error cases test passed!");
@@ -787,6 +1301,7 @@ mod tests {
     fn test_election_isolation_token_issuance() {
         // Test that tokens issued for one election cannot be used in another election
         let (pk, sk) = load_keys("ec_private.pem", "ec_public.pem").expect("Failed to load RSA keys");
+        let signer = LocalSigner::new(pk.clone(), sk.clone());
 
         // Create two separate elections
         let mut election1 = Election::new(
@@ -807,7 +1322,7 @@ mod tests {
 
         // Voter is registered in election1 only
         let voter_pk = "e3f33350728580cd51db8f4048d614910d48a5c0d7f1af6811e83c07fc865a5c";
-        election1.register_voter(voter_pk);
+        election1.register_voter(voter_pk).unwrap();
         // Voter is NOT registered in election2
         
         assert_eq!(election1.authorized_voters.len(), 1);
@@ -826,13 +1341,13 @@ mod tests {
         };
 
         // Token should be issued for election1 (voter is registered)
-        let token_result1 = election1.issue_token(token_request.clone(), sk.clone());
+        let token_result1 = election1.issue_token(token_request.clone(), &signer);
         assert!(token_result1.is_ok(), "Token should be issued for election1");
 
         // Token should NOT be issued for election2 (voter is not registered)
-        let token_result2 = election2.issue_token(token_request, sk);
+        let token_result2 = election2.issue_token(token_request, &signer);
         assert!(token_result2.is_err(), "Token should NOT be issued for election2");
-        assert_eq!(token_result2.unwrap_err(), "Unauthorized voter or nonce hash already issued");
+        assert_eq!(token_result2.unwrap_err(), ElectionError::Unauthorized);
 
         println!("✅ Election isolation token issuance test passed!");
     }
@@ -841,6 +1356,7 @@ mod tests {
     fn test_election_isolation_vote_reception() {
         // Test that votes are isolated between elections
         let (pk, sk) = load_keys("ec_private.pem", "ec_public.pem").expect("Failed to load RSA keys");
+        let signer = LocalSigner::new(pk.clone(), sk.clone());
 
         // Create two elections with overlapping voter registration
         let mut election1 = Election::new(
@@ -861,8 +1377,8 @@ mod tests {
 
         // Register same voter in both elections
         let voter_pk = "e3f33350728580cd51db8f4048d614910d48a5c0d7f1af6811e83c07fc865a5c";
-        election1.register_voter(voter_pk);
-        election2.register_voter(voter_pk);
+        election1.register_voter(voter_pk).unwrap();
+        election2.register_voter(voter_pk).unwrap();
 
         // Set both elections to InProgress to allow voting
         election1.status = Status::InProgress;
@@ -883,7 +1399,7 @@ mod tests {
         };
 
         // Get token from election1
-        let blind_sig = election1.issue_token(token_request, sk).expect("Token should be issued");
+        let blind_sig = election1.issue_token(token_request, &signer).expect("Token should be issued");
         let _token = pk.finalize(
             &blind_sig,
             &blinding_result.secret,
@@ -916,6 +1432,7 @@ mod tests {
     fn test_cross_election_token_reuse_protection() {
         // Test that used tokens are properly isolated between elections
         let (pk, sk) = load_keys("ec_private.pem", "ec_public.pem").expect("Failed to load RSA keys");
+        let signer = LocalSigner::new(pk.clone(), sk.clone());
 
         let mut election1 = Election::new(
             "Election 1".to_string(),
@@ -935,8 +1452,8 @@ mod tests {
 
         // Register voter in both elections
         let voter_pk = "e3f33350728580cd51db8f4048d614910d48a5c0d7f1af6811e83c07fc865a5c";
-        election1.register_voter(voter_pk);
-        election2.register_voter(voter_pk);
+        election1.register_voter(voter_pk).unwrap();
+        election2.register_voter(voter_pk).unwrap();
 
         election1.status = Status::InProgress;
         election2.status = Status::InProgress;
@@ -956,7 +1473,7 @@ mod tests {
         };
 
         // Get token from election1 and vote
-        let _blind_sig = election1.issue_token(token_request, sk).expect("Token should be issued");
+        let _blind_sig = election1.issue_token(token_request, &signer).expect("Token should be issued");
         election1.receive_vote(h_n.clone(), 1).expect("Vote should be accepted");
 
         // Verify token is marked as used in election1
@@ -978,6 +1495,7 @@ mod tests {
     fn test_election_specific_voter_authorization() {
         // Test that voter authorization is properly isolated between elections
         let (pk, sk) = load_keys("ec_private.pem", "ec_public.pem").expect("Failed to load RSA keys");
+        let signer = LocalSigner::new(pk.clone(), sk.clone());
 
         let mut election1 = Election::new(
             "Election 1".to_string(),
@@ -999,8 +1517,8 @@ mod tests {
         let voter1_pk = "e3f33350728580cd51db8f4048d614910d48a5c0d7f1af6811e83c07fc865a5c";
         let voter2_pk = "a1b2c3d4e5f67890123456789012345678901234567890123456789012345678";
 
-        election1.register_voter(voter1_pk); // voter1 only in election1
-        election2.register_voter(voter2_pk); // voter2 only in election2
+        election1.register_voter(voter1_pk).unwrap(); // voter1 only in election1
+        election2.register_voter(voter2_pk).unwrap(); // voter2 only in election2
 
         // Generate token requests
         let rng = &mut rand::thread_rng();
@@ -1020,10 +1538,10 @@ mod tests {
             blinded_h_n: blinding_result1.blind_msg.clone(),
         };
 
-        let token1_from_election1 = election1.issue_token(request1.clone(), sk.clone());
+        let token1_from_election1 = election1.issue_token(request1.clone(), &signer);
         assert!(token1_from_election1.is_ok(), "voter1 should get token from election1");
 
-        let token1_from_election2 = election2.issue_token(request1, sk.clone());
+        let token1_from_election2 = election2.issue_token(request1, &signer);
         assert!(token1_from_election2.is_err(), "voter1 should NOT get token from election2");
 
         // voter2 should get token from election2 but not election1
@@ -1032,10 +1550,10 @@ mod tests {
             blinded_h_n: blinding_result2.blind_msg.clone(),
         };
 
-        let token2_from_election1 = election1.issue_token(request2.clone(), sk.clone());
+        let token2_from_election1 = election1.issue_token(request2.clone(), &signer);
         assert!(token2_from_election1.is_err(), "voter2 should NOT get token from election1");
 
-        let token2_from_election2 = election2.issue_token(request2, sk);
+        let token2_from_election2 = election2.issue_token(request2, &signer);
         assert!(token2_from_election2.is_ok(), "voter2 should get token from election2");
 
         // Verify authorized_voters are properly separated
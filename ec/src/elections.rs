@@ -0,0 +1,84 @@
+/*! elections.rs — The elections the EC runs, each behind its own lock
+Token requests, votes, the status checker and the admin API lock only the
+election they work on, so heavy traffic on one election doesn't hold up the
+others or the admin operations. The map itself is locked just long enough
+to look an election up or add one, never across an await. */
+
+use std::collections::HashMap;
+use std::sync::{Arc, PoisonError, RwLock as MapLock};
+use tokio::sync::RwLock;
+
+use crate::election::Election;
+
+/// An election shared between the tasks of the EC.
+pub type SharedElection = Arc<RwLock<Election>>;
+
+/// The running elections, by ID.
+#[derive(Debug, Default)]
+pub struct Elections {
+    map: MapLock<HashMap<String, SharedElection>>,
+}
+
+impl Elections {
+    pub fn new(elections: impl IntoIterator<Item = Election>) -> Self {
+        let map = elections
+            .into_iter()
+            .map(|election| (election.id.clone(), Arc::new(RwLock::new(election))))
+            .collect();
+        Self { map: MapLock::new(map) }
+    }
+
+    pub fn get(&self, election_id: &str) -> Option<SharedElection> {
+        self.map.read().unwrap_or_else(PoisonError::into_inner).get(election_id).cloned()
+    }
+
+    pub fn contains(&self, election_id: &str) -> bool {
+        self.map.read().unwrap_or_else(PoisonError::into_inner).contains_key(election_id)
+    }
+
+    /// Adds an election, replacing any with the same ID.
+    pub fn insert(&self, election: Election) -> SharedElection {
+        let id = election.id.clone();
+        let shared = Arc::new(RwLock::new(election));
+        self.map
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id, Arc::clone(&shared));
+        shared
+    }
+
+    /// Every election, sorted by ID, to go through without holding the map.
+    pub fn all(&self) -> Vec<SharedElection> {
+        let map = self.map.read().unwrap_or_else(PoisonError::into_inner);
+        let mut ids: Vec<&String> = map.keys().collect();
+        ids.sort();
+        ids.into_iter().map(|id| Arc::clone(&map[id])).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Candidate;
+
+    #[tokio::test]
+    async fn test_elections_lock_separately() {
+        let election = |name: &str| Election::new(name.to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        let (first, second) = (election("First"), election("Second"));
+        let elections = Elections::new([first.clone()]);
+        assert!(elections.contains(&first.id));
+        assert!(elections.get(&second.id).is_none());
+
+        // A busy election doesn't hold up the others or adding one
+        let busy = elections.get(&first.id).unwrap();
+        let _guard = busy.write().await;
+        elections.insert(second.clone());
+        assert_eq!(elections.get(&second.id).unwrap().read().await.name, "Second");
+        assert!(busy.try_read().is_err());
+
+        let all = elections.all();
+        assert_eq!(all.len(), 2);
+        let index = if first.id < second.id { 0 } else { 1 };
+        assert!(Arc::ptr_eq(&all[index], &busy));
+    }
+}
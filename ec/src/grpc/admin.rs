@@ -1,50 +1,96 @@
 use anyhow::Result;
-use nostr_sdk::{Client, Keys, PublicKey};
-use std::collections::HashMap;
+use futures_util::Stream;
+use nostr_sdk::PublicKey;
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::watch;
 use tonic::{Request, Response, Status};
 
-use crate::database::Database;
-use crate::election::{Election, Status as ElectionStatus};
+use crate::database::{Database, ExportTable};
+use crate::election::{Election, ElectionError, Status as ElectionStatus};
+use crate::elections::Elections;
 use crate::grpc::admin_proto::admin_service_server::AdminService;
 use crate::grpc::admin_proto::*;
-use crate::types::Candidate;
+use crate::identity::EcIdentity;
+use crate::relays::RelayManager;
+use crate::replication;
+use crate::types::{Candidate, Voter};
+use criptocracia_protocol::TokenScheme;
+use criptocracia_protocol::tally::MAX_TALLY_CANDIDATES;
+
+/// Seconds an election may start before the EC's clock, for the admin's.
+const START_TIME_SKEW: u64 = 300;
+
+/// Furthest an election may be scheduled ahead, in seconds.
+const MAX_START_AHEAD: u64 = 365 * 86_400;
+
+/// Longest election, in seconds.
+const MAX_DURATION: u64 = 90 * 86_400;
+
+impl From<ElectionError> for Status {
+    fn from(e: ElectionError) -> Self {
+        let message = e.to_string();
+        match e {
+            ElectionError::NotOpen | ElectionError::NotInProgress | ElectionError::Closed => Status::failed_precondition(message),
+            ElectionError::DuplicateToken => Status::already_exists(message),
+            ElectionError::Unauthorized
+            | ElectionError::AnonymousOnly
+            | ElectionError::NotAnonymous
+            | ElectionError::RingMemberNotOnRoll
+            | ElectionError::InvalidRingProof
+            | ElectionError::UnboundToken => Status::permission_denied(message),
+            ElectionError::InvalidPubkey
+            | ElectionError::InvalidCandidate(_)
+            | ElectionError::EncryptedOnly
+            | ElectionError::PlainOnly
+            | ElectionError::InvalidBallot => Status::invalid_argument(message),
+            ElectionError::Internal(_) => Status::internal(message),
+        }
+    }
+}
 
 /// Implementation of the AdminService gRPC service
 pub struct AdminServiceImpl {
     db: Arc<Database>,
-    elections: Arc<Mutex<HashMap<String, Election>>>,
-    rsa_public_key: String, // DER-encoded base64 RSA public key
-    client: Arc<Client>,    // Nostr client for publishing events
-    keys: Arc<Keys>,        // Nostr keys for signing events
+    elections: Arc<Elections>,
+    rsa_public_key: String,    // DER-encoded base64 RSA public key
+    relays: Arc<RelayManager>, // Nostr relays for publishing events
+    identity: Arc<EcIdentity>, // Nostr identity signing the events
+    shutdown: Option<watch::Receiver<bool>>, // Ends the replication streams
 }
 
 impl AdminServiceImpl {
     /// Create a new AdminServiceImpl instance
     pub fn new(
         db: Arc<Database>,
-        elections: Arc<Mutex<HashMap<String, Election>>>,
+        elections: Arc<Elections>,
         rsa_public_key: String,
-        client: Arc<Client>,
-        keys: Arc<Keys>,
+        relays: Arc<RelayManager>,
+        identity: Arc<EcIdentity>,
     ) -> Self {
         Self {
             db,
             elections,
             rsa_public_key,
-            client,
-            keys,
+            relays,
+            identity,
+            shutdown: None,
         }
     }
 
+    /// Ends the replication streams to the standby ECs on shutdown
+    pub fn with_shutdown(mut self, shutdown: watch::Receiver<bool>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
     #[cfg(test)]
     pub fn get_db(&self) -> &Arc<Database> {
         &self.db
     }
 
     #[cfg(test)]
-    pub fn get_elections(&self) -> &Arc<Mutex<HashMap<String, Election>>> {
+    pub fn get_elections(&self) -> &Arc<Elections> {
         &self.elections
     }
 
@@ -84,6 +130,11 @@ impl AdminServiceImpl {
             created_at: 0, // TODO: Add created_at to Election struct
             updated_at: 0, // TODO: Add updated_at to Election struct
             total_votes: election.votes.len() as u32,
+            issuance_log: election.issuance_log,
+            token_scheme: election.token_scheme.as_str().to_string(),
+            anonymous_requests: election.anonymous_requests,
+            tally_key: election.tally_key.clone().unwrap_or_default(),
+            rehearsal: election.rehearsal,
         }
     }
 
@@ -126,9 +177,10 @@ impl AdminServiceImpl {
         Ok(())
     }
 
-    /// Validate candidate data
-    fn validate_candidate(candidate_id: u32, name: &str) -> Result<(), Box<Status>> {
-        if candidate_id == 0 {
+    /// Validate candidate data, with an ID of 0 left to be assigned when
+    /// `assign_ids` is set
+    fn validate_candidate(candidate_id: u32, name: &str, assign_ids: bool) -> Result<(), Box<Status>> {
+        if candidate_id == 0 && !assign_ids {
             return Err(Box::new(Status::invalid_argument(
                 "Candidate ID must be greater than 0",
             )));
@@ -155,14 +207,169 @@ impl AdminServiceImpl {
         Ok(())
     }
 
+    /// Give the candidates with ID 0 the smallest IDs that neither the
+    /// `existing` candidates nor the others have, in order
+    fn assign_candidate_ids(existing: &[Candidate], candidates: &mut [Candidate]) -> Result<(), Box<Status>> {
+        for i in 0..candidates.len() {
+            if candidates[i].id != 0 {
+                continue;
+            }
+            let taken = |id: u8| existing.iter().chain(candidates.iter()).any(|c| c.id == id);
+            match (1..=255).find(|id| !taken(*id)) {
+                Some(id) => candidates[i].id = id,
+                None => return Err(Box::new(Status::resource_exhausted("No candidate IDs left"))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuse candidates with the ID, or the name whatever its case, of an
+    /// `existing` candidate or of another one of them
+    fn check_candidate_conflicts(existing: &[Candidate], candidates: &[Candidate]) -> Result<(), Box<Status>> {
+        for (i, candidate) in candidates.iter().enumerate() {
+            let name = candidate.name.to_lowercase();
+            let earlier = &candidates[..i];
+            if existing.iter().any(|c| c.id == candidate.id) {
+                return Err(Box::new(Status::already_exists("Candidate ID already exists")));
+            }
+            if earlier.iter().any(|c| c.id == candidate.id) {
+                return Err(Box::new(Status::already_exists(format!(
+                    "Candidate ID {} is given twice",
+                    candidate.id
+                ))));
+            }
+            if existing.iter().any(|c| c.name.to_lowercase() == name) {
+                return Err(Box::new(Status::already_exists(format!(
+                    "A candidate named '{}' already exists",
+                    candidate.name
+                ))));
+            }
+            if earlier.iter().any(|c| c.name.to_lowercase() == name) {
+                return Err(Box::new(Status::already_exists(format!(
+                    "Candidate name '{}' is given twice",
+                    candidate.name
+                ))));
+            }
+        }
+        Ok(())
+    }
+
+    /// Election an AddElection request creates, with warnings about its
+    /// schedule, or why it can't be created
+    async fn draft_election(&self, req: &AddElectionRequest) -> Result<(Election, Vec<String>), String> {
+        // Validate input
+        if let Err(e) = Self::validate_election_name(&req.name) {
+            return Err(format!("Invalid election name: {}", e.message()));
+        }
+
+        if req.start_time == 0 {
+            return Err("Election start time cannot be zero".to_string());
+        }
+
+        if req.duration == 0 {
+            return Err("Election duration cannot be zero".to_string());
+        }
+
+        // Schedule, with some room for the admin's clock
+        let now = chrono::Utc::now().timestamp() as u64;
+        if req.start_time < now.saturating_sub(START_TIME_SKEW) {
+            return Err("Election start time is in the past".to_string());
+        }
+        if req.start_time > now + MAX_START_AHEAD {
+            return Err(format!("Election start time is more than {} days ahead", MAX_START_AHEAD / 86_400));
+        }
+        if req.duration > MAX_DURATION {
+            return Err(format!("Election duration is over the {} day limit", MAX_DURATION / 86_400));
+        }
+
+        if req.candidates.is_empty() {
+            return Err("Election must have at least one candidate".to_string());
+        }
+
+        // Validate candidates
+        for candidate in &req.candidates {
+            if let Err(e) = Self::validate_candidate(candidate.id, &candidate.name, req.assign_ids) {
+                return Err(format!("Invalid candidate: {}", e.message()));
+            }
+        }
+
+        if req.encrypted_tally && req.candidates.len() > MAX_TALLY_CANDIDATES {
+            return Err(format!("Encrypted tallies take up to {} candidates", MAX_TALLY_CANDIDATES));
+        }
+        // The tally key is derived from the EC's local Nostr key
+        let tally_keys = self.identity.local_keys();
+        if req.encrypted_tally && tally_keys.is_none() {
+            return Err("Encrypted tallies need the EC's Nostr key, not a remote signer".to_string());
+        }
+
+        let token_scheme = if req.token_scheme.is_empty() {
+            TokenScheme::default()
+        } else {
+            match TokenScheme::parse(&req.token_scheme) {
+                Some(scheme) => scheme,
+                None => {
+                    return Err(format!("Unknown token scheme: {}", req.token_scheme));
+                }
+            }
+        };
+
+        // Convert candidates, numbering those without an ID
+        let mut candidates: Vec<Candidate> = req
+            .candidates
+            .iter()
+            .map(|c| Candidate::new(c.id as u8, &c.name))
+            .collect();
+        let checked = Self::assign_candidate_ids(&[], &mut candidates)
+            .and_then(|()| Self::check_candidate_conflicts(&[], &candidates));
+        if let Err(e) = checked {
+            return Err(format!("Invalid candidate: {}", e.message()));
+        }
+
+        // Create election using EC's RSA public key
+        let mut election = Election::new(
+            req.name.clone(),
+            candidates,
+            req.start_time,
+            req.duration,
+            self.rsa_public_key.clone(),
+        );
+        election.issuance_log = req.issuance_log;
+        election.token_scheme = token_scheme;
+        election.anonymous_requests = req.anonymous_requests;
+        // Elections of a rehearsal say they are drills
+        election.rehearsal = self.relays.events().rehearsal;
+        if let Some(keys) = tally_keys.filter(|_| req.encrypted_tally) {
+            election.enable_encrypted_tally(keys);
+        }
+
+        // Voters on the roll of an election running at the same time get both
+        // at once, and messages without an election ID are refused meanwhile
+        let mut warnings = Vec::new();
+        for shared in self.elections.all() {
+            let other = shared.read().await;
+            let over = matches!(other.status, ElectionStatus::Finished | ElectionStatus::Canceled);
+            if !over && other.start_time < election.end_time && election.start_time < other.end_time {
+                warnings.push(format!(
+                    "Voting overlaps election {} ({}), with {} voters on its roll",
+                    other.id,
+                    other.name,
+                    other.authorized_voters.len()
+                ));
+            }
+        }
+        Ok((election, warnings))
+    }
+
     /// Publish election to Nostr using the existing publish_election_event function
     async fn publish_election_to_nostr(&self, election: &Election) -> Result<(), anyhow::Error> {
-        crate::publish_election_event(&self.client, &self.keys, election, &self.db).await
+        crate::publish_election_event(&self.relays, &self.identity, election, &self.db).await
     }
 }
 
 #[tonic::async_trait]
 impl AdminService for AdminServiceImpl {
+    type ReplicateStream = Pin<Box<dyn Stream<Item = Result<ReplicationBatch, Status>> + Send>>;
+
     async fn add_voter(
         &self,
         request: Request<AddVoterRequest>,
@@ -201,41 +408,39 @@ impl AdminService for AdminServiceImpl {
             }));
         }
 
+        // Voters are stored by hex pubkey so token issuance can be matched later
+        let pubkey_hex = PublicKey::parse(&req.pubkey)
+            .map(|pk| pk.to_hex())
+            .unwrap_or_else(|_| req.pubkey.clone());
+
         // Check if election exists
-        {
-            let elections_guard = self.elections.lock().await;
-            if !elections_guard.contains_key(&req.election_id) {
-                return Ok(Response::new(AddVoterResponse {
-                    success: false,
-                    message: "Election not found".to_string(),
-                    voter_id: String::new(),
-                }));
-            }
-        }
+        let Some(shared) = self.elections.get(&req.election_id) else {
+            return Ok(Response::new(AddVoterResponse {
+                success: false,
+                message: "Election not found".to_string(),
+                voter_id: String::new(),
+            }));
+        };
+
+        // Add voter to the in-memory election's authorized_voters, while it's open
+        let registered = {
+            let mut election = shared.write().await;
+            let registered = election.roll.contains(&pubkey_hex);
+            election.register_voter(&req.pubkey).map_err(Status::from)?;
+            registered
+        };
 
         // Add voter to election_voters table
         match self
             .db
-            .save_election_voters(&req.election_id, &[req.pubkey.clone()])
+            .save_election_voters(&req.election_id, &[Voter::new(&req.name, &pubkey_hex)])
             .await
         {
             Ok(()) => {
-                // Also add voter to in-memory election's authorized_voters HashSet
-                {
-                    let mut elections_guard = self.elections.lock().await;
-                    if let Some(election) = elections_guard.get_mut(&req.election_id) {
-                        election.register_voter(&req.pubkey);
-                        log::info!(
-                            "Added voter {} to in-memory election {}",
-                            req.pubkey,
-                            req.election_id
-                        );
-                    } else {
-                        log::error!(
-                            "Election {} not found in memory after database save",
-                            req.election_id
-                        );
-                    }
+                // Publish the new voter roll commitment
+                let election = shared.read().await.clone();
+                if let Err(e) = self.publish_election_to_nostr(&election).await {
+                    log::error!("Failed to publish election voter roll to Nostr: {}", e);
                 }
 
                 log::info!(
@@ -251,6 +456,11 @@ impl AdminService for AdminServiceImpl {
             }
             Err(e) => {
                 log::error!("Failed to add voter to election: {}", e);
+                if !registered {
+                    let mut election = shared.write().await;
+                    election.authorized_voters.remove(&pubkey_hex);
+                    election.roll.remove(&pubkey_hex);
+                }
                 Ok(Response::new(AddVoterResponse {
                     success: false,
                     message: format!("Failed to add voter to election: {}", e),
@@ -268,75 +478,26 @@ impl AdminService for AdminServiceImpl {
 
         log::info!("Adding election: {}", req.name);
 
-        // Validate input
-        if let Err(e) = Self::validate_election_name(&req.name) {
-            return Ok(Response::new(AddElectionResponse {
-                success: false,
-                message: format!("Invalid election name: {}", e.message()),
-                election_id: String::new(),
-            }));
-        }
-
-        if req.start_time == 0 {
-            return Ok(Response::new(AddElectionResponse {
-                success: false,
-                message: "Election start time cannot be zero".to_string(),
-                election_id: String::new(),
-            }));
-        }
-
-        if req.duration == 0 {
-            return Ok(Response::new(AddElectionResponse {
-                success: false,
-                message: "Election duration cannot be zero".to_string(),
-                election_id: String::new(),
-            }));
-        }
-
-        if req.candidates.is_empty() {
-            return Ok(Response::new(AddElectionResponse {
-                success: false,
-                message: "Election must have at least one candidate".to_string(),
-                election_id: String::new(),
-            }));
-        }
-
-        // Validate candidates
-        for candidate in &req.candidates {
-            if let Err(e) = Self::validate_candidate(candidate.id, &candidate.name) {
+        let (election, warnings) = match self.draft_election(&req).await {
+            Ok(draft) => draft,
+            Err(message) => {
                 return Ok(Response::new(AddElectionResponse {
                     success: false,
-                    message: format!("Invalid candidate: {}", e.message()),
+                    message,
                     election_id: String::new(),
+                    warnings: Vec::new(),
                 }));
             }
+        };
+        for warning in &warnings {
+            log::warn!("Election {}: {}", req.name, warning);
         }
-
-        // Convert candidates
-        let candidates: Vec<Candidate> = req
-            .candidates
-            .iter()
-            .map(|c| Candidate::new(c.id as u8, &c.name))
-            .collect();
-
         let election_name = req.name.clone();
 
-        // Create election using EC's RSA public key
-        let election = Election::new(
-            req.name,
-            candidates,
-            req.start_time,
-            req.duration,
-            self.rsa_public_key.clone(),
-        );
-
         let election_id = election.id.clone();
 
-        // Add election to HashMap
-        {
-            let mut elections_guard = self.elections.lock().await;
-            elections_guard.insert(election_id.clone(), election.clone());
-        }
+        // Add election to the running ones
+        self.elections.insert(election.clone());
 
         // Add to database
         match self.db.upsert_election(&election).await {
@@ -353,6 +514,7 @@ impl AdminService for AdminServiceImpl {
                     success: true,
                     message: "Election added successfully".to_string(),
                     election_id,
+                    warnings,
                 }))
             }
             Err(e) => {
@@ -361,11 +523,32 @@ impl AdminService for AdminServiceImpl {
                     success: false,
                     message: format!("Failed to add election: {}", e),
                     election_id: String::new(),
+                    warnings,
                 }))
             }
         }
     }
 
+    async fn validate_election(
+        &self,
+        request: Request<AddElectionRequest>,
+    ) -> Result<Response<ValidateElectionResponse>, Status> {
+        let req = request.into_inner();
+
+        Ok(Response::new(match self.draft_election(&req).await {
+            Ok((_, warnings)) => ValidateElectionResponse {
+                valid: true,
+                message: "Election is valid".to_string(),
+                warnings,
+            },
+            Err(message) => ValidateElectionResponse {
+                valid: false,
+                message,
+                warnings: Vec::new(),
+            },
+        }))
+    }
+
     async fn add_candidate(
         &self,
         request: Request<AddCandidateRequest>,
@@ -379,41 +562,58 @@ impl AdminService for AdminServiceImpl {
         );
 
         // Validate input
-        if let Err(e) = Self::validate_candidate(req.candidate_id, &req.name) {
+        if let Err(e) = Self::validate_candidate(req.candidate_id, &req.name, req.assign_ids) {
             return Ok(Response::new(AddCandidateResponse {
                 success: false,
                 message: format!("Invalid candidate: {}", e.message()),
+                candidate_id: 0,
             }));
         }
 
         // Check if election exists and add candidate
+        let candidate_id;
         let election_clone = {
-            let mut elections_guard = self.elections.lock().await;
-
-            let election = match elections_guard.get_mut(&req.election_id) {
+            let shared = match self.elections.get(&req.election_id) {
                 Some(e) => e,
                 None => {
                     return Ok(Response::new(AddCandidateResponse {
                         success: false,
                         message: "Election not found".to_string(),
+                        candidate_id: 0,
                     }));
                 }
             };
+            let mut election = shared.write().await;
 
-            // Check if candidate ID already exists
-            if election
-                .candidates
-                .iter()
-                .any(|c| c.id == req.candidate_id as u8)
+            // Number the candidate if asked, then check its ID and name are its own
+            let mut added = [Candidate::new(req.candidate_id as u8, &req.name)];
+            let checked = Self::assign_candidate_ids(&election.candidates, &mut added)
+                .and_then(|()| Self::check_candidate_conflicts(&election.candidates, &added));
+            if let Err(e) = checked {
+                return Ok(Response::new(AddCandidateResponse {
+                    success: false,
+                    message: e.message().to_string(),
+                    candidate_id: 0,
+                }));
+            }
+
+            // Encrypted ballots hold a ciphertext per candidate, fixed once voting starts
+            if election.tally_key.is_some()
+                && (election.status != ElectionStatus::Open || election.candidates.len() >= MAX_TALLY_CANDIDATES)
             {
                 return Ok(Response::new(AddCandidateResponse {
                     success: false,
-                    message: "Candidate ID already exists".to_string(),
+                    message: format!(
+                        "Candidates of an encrypted tally are added before voting starts, up to {}",
+                        MAX_TALLY_CANDIDATES
+                    ),
+                    candidate_id: 0,
                 }));
             }
 
             // Add candidate
-            let candidate = Candidate::new(req.candidate_id as u8, &req.name);
+            let [candidate] = added;
+            candidate_id = candidate.id;
             election.candidates.push(candidate);
 
             election.clone()
@@ -421,17 +621,23 @@ impl AdminService for AdminServiceImpl {
 
         match self.db.upsert_election(&election_clone).await {
             Ok(()) => {
-                log::info!("Successfully added candidate: {}", req.name);
+                log::info!("Successfully added candidate {}: {}", candidate_id, req.name);
                 Ok(Response::new(AddCandidateResponse {
                     success: true,
                     message: "Candidate added successfully".to_string(),
+                    candidate_id: candidate_id as u32,
                 }))
             }
             Err(e) => {
                 log::error!("Failed to add candidate: {}", e);
+                // The database has the last word, e.g. on unique names
+                if let Some(shared) = self.elections.get(&req.election_id) {
+                    shared.write().await.candidates.retain(|c| c.id != candidate_id);
+                }
                 Ok(Response::new(AddCandidateResponse {
                     success: false,
                     message: format!("Failed to add candidate: {}", e),
+                    candidate_id: 0,
                 }))
             }
         }
@@ -445,9 +651,7 @@ impl AdminService for AdminServiceImpl {
 
         log::info!("Getting election: {}", req.election_id);
 
-        let elections_guard = self.elections.lock().await;
-
-        let election = match elections_guard.get(&req.election_id) {
+        let election = match self.elections.get(&req.election_id) {
             Some(e) => e,
             None => {
                 return Ok(Response::new(GetElectionResponse {
@@ -458,7 +662,7 @@ impl AdminService for AdminServiceImpl {
             }
         };
 
-        let election_info = Self::election_to_info(election);
+        let election_info = Self::election_to_info(&*election.read().await);
 
         Ok(Response::new(GetElectionResponse {
             success: true,
@@ -492,8 +696,7 @@ impl AdminService for AdminServiceImpl {
 
         // Check if election exists
         {
-            let elections_guard = self.elections.lock().await;
-            if !elections_guard.contains_key(&req.election_id) {
+            if !self.elections.contains(&req.election_id) {
                 return Ok(Response::new(ListVotersResponse {
                     success: false,
                     message: "Election not found".to_string(),
@@ -504,8 +707,8 @@ impl AdminService for AdminServiceImpl {
         }
 
         // Get voters for the specific election
-        match self.db.load_election_voters(&req.election_id).await {
-            Ok(voter_pubkeys) => {
+        match self.db.get_election_voters(&req.election_id).await {
+            Ok(voters) => {
                 // Apply pagination
                 let offset = req.offset as usize;
                 let limit = if req.limit == 0 {
@@ -514,23 +717,27 @@ impl AdminService for AdminServiceImpl {
                     req.limit.min(1000)
                 } as usize;
 
-                let paginated_voters: Vec<String> =
-                    voter_pubkeys.into_iter().skip(offset).take(limit).collect();
-
-                let voter_infos: Vec<VoterInfo> = paginated_voters
-                    .iter()
-                    .map(|pubkey| VoterInfo {
-                        name: format!("Voter_{}", &pubkey[..8]), // Use first 8 chars as name placeholder
-                        pubkey: pubkey.clone(),
-                        created_at: 0, // We don't store created_at for election voters currently
+                let voter_infos: Vec<VoterInfo> = voters
+                    .into_iter()
+                    .skip(offset)
+                    .take(limit)
+                    .map(|voter| VoterInfo {
+                        // Voters added before names were stored have no name
+                        name: if voter.name.is_empty() {
+                            format!("Voter_{}", voter.pubkey.get(..8).unwrap_or(&voter.pubkey))
+                        } else {
+                            voter.name
+                        },
+                        pubkey: voter.pubkey,
+                        created_at: voter.created_at as u64,
                     })
                     .collect();
 
                 Ok(Response::new(ListVotersResponse {
                     success: true,
                     message: "Voters retrieved successfully".to_string(),
+                    total_count: voter_infos.len() as u32,
                     voters: voter_infos,
-                    total_count: paginated_voters.len() as u32,
                 }))
             }
             Err(e) => {
@@ -576,6 +783,11 @@ impl AdminService for AdminServiceImpl {
                         created_at: e.created_at as u64,
                         updated_at: e.updated_at as u64,
                         total_votes: 0, // TODO: Load vote count from database
+                        issuance_log: e.issuance_log,
+                        token_scheme: e.token_scheme.clone(),
+                        anonymous_requests: e.anonymous_requests,
+                        tally_key: e.tally_key.clone().unwrap_or_default(),
+                        rehearsal: e.rehearsal,
                     })
                     .collect();
 
@@ -616,9 +828,7 @@ impl AdminService for AdminServiceImpl {
 
         // Update election status in memory and get election for publishing
         let election_clone = {
-            let mut elections_guard = self.elections.lock().await;
-
-            let election = match elections_guard.get_mut(&req.election_id) {
+            let shared = match self.elections.get(&req.election_id) {
                 Some(e) => e,
                 None => {
                     return Ok(Response::new(CancelElectionResponse {
@@ -627,6 +837,7 @@ impl AdminService for AdminServiceImpl {
                     }));
                 }
             };
+            let mut election = shared.write().await;
 
             // Check if election is already canceled
             if election.status == ElectionStatus::Canceled {
@@ -668,4 +879,557 @@ impl AdminService for AdminServiceImpl {
             }
         }
     }
+
+    async fn purge_election_data(
+        &self,
+        request: Request<PurgeElectionDataRequest>,
+    ) -> Result<Response<PurgeElectionDataResponse>, Status> {
+        let req = request.into_inner();
+
+        log::info!("Purging data for election: {}", req.election_id);
+
+        // Validate election_id
+        if req.election_id.is_empty() {
+            return Ok(Response::new(PurgeElectionDataResponse {
+                success: false,
+                message: "Election ID cannot be empty".to_string(),
+                ..Default::default()
+            }));
+        }
+
+        // Only finished or canceled elections can be purged
+        let status = match self.elections.get(&req.election_id) {
+            Some(election) => Some(election.read().await.status),
+            None => None,
+        };
+        match status {
+            Some(ElectionStatus::Finished | ElectionStatus::Canceled) => {}
+            Some(_) => {
+                return Ok(Response::new(PurgeElectionDataResponse {
+                    success: false,
+                    message: "Only finished or canceled elections can be purged".to_string(),
+                    ..Default::default()
+                }));
+            }
+            None => {
+                return Ok(Response::new(PurgeElectionDataResponse {
+                    success: false,
+                    message: "Election not found".to_string(),
+                    ..Default::default()
+                }));
+            }
+        }
+
+        match self.db.purge_election_data(&req.election_id).await {
+            Ok(stats) => {
+                if let Some(election) = self.elections.get(&req.election_id) {
+                    election.write().await.clear_voter_data();
+                }
+
+                log::info!(
+                    "Successfully purged data for election {}: {:?}",
+                    req.election_id,
+                    stats
+                );
+                Ok(Response::new(PurgeElectionDataResponse {
+                    success: true,
+                    message: "Election data purged successfully".to_string(),
+                    voters_removed: stats.voters_removed,
+                    tokens_removed: stats.tokens_removed,
+                    messages_removed: stats.messages_removed,
+                    issuances_removed: stats.issuances_removed,
+                }))
+            }
+            Err(e) => {
+                log::error!("Failed to purge election {} data: {}", req.election_id, e);
+                Ok(Response::new(PurgeElectionDataResponse {
+                    success: false,
+                    message: format!("Failed to purge election data: {}", e),
+                    ..Default::default()
+                }))
+            }
+        }
+    }
+
+    async fn get_results_history(
+        &self,
+        request: Request<GetResultsHistoryRequest>,
+    ) -> Result<Response<GetResultsHistoryResponse>, Status> {
+        let req = request.into_inner();
+
+        log::info!(
+            "Getting results history for election: {} with limit: {}, offset: {}",
+            req.election_id,
+            req.limit,
+            req.offset
+        );
+
+        // Candidate names come from the in-memory election
+        let candidates = {
+            match self.elections.get(&req.election_id) {
+                Some(election) => election.read().await.candidates.clone(),
+                None => {
+                    return Ok(Response::new(GetResultsHistoryResponse {
+                        success: false,
+                        message: "Election not found".to_string(),
+                        snapshots: vec![],
+                    }));
+                }
+            }
+        };
+
+        match self
+            .db
+            .get_results_history(&req.election_id, req.limit, req.offset)
+            .await
+        {
+            Ok(records) => {
+                let snapshots = records
+                    .into_iter()
+                    .map(|record| ResultsSnapshot {
+                        created_at: record.created_at as u64,
+                        total_votes: record.total_votes as u32,
+                        candidates: candidates
+                            .iter()
+                            .map(|c| CandidateInfo {
+                                id: c.id as u32,
+                                name: c.name.clone(),
+                                vote_count: record
+                                    .results
+                                    .iter()
+                                    .find(|(id, _)| *id == c.id)
+                                    .map(|(_, count)| *count)
+                                    .unwrap_or(0),
+                            })
+                            .collect(),
+                    })
+                    .collect();
+
+                Ok(Response::new(GetResultsHistoryResponse {
+                    success: true,
+                    message: "Results history retrieved successfully".to_string(),
+                    snapshots,
+                }))
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to get results history for election {}: {}",
+                    req.election_id,
+                    e
+                );
+                Ok(Response::new(GetResultsHistoryResponse {
+                    success: false,
+                    message: format!("Failed to get results history: {}", e),
+                    snapshots: vec![],
+                }))
+            }
+        }
+    }
+
+    async fn list_published_events(
+        &self,
+        request: Request<ListPublishedEventsRequest>,
+    ) -> Result<Response<ListPublishedEventsResponse>, Status> {
+        let req = request.into_inner();
+
+        log::info!(
+            "Listing published events for election: {} with limit: {}, offset: {}",
+            req.election_id,
+            req.limit,
+            req.offset
+        );
+
+        {
+            if !self.elections.contains(&req.election_id) {
+                return Ok(Response::new(ListPublishedEventsResponse {
+                    success: false,
+                    message: "Election not found".to_string(),
+                    events: vec![],
+                }));
+            }
+        }
+
+        match self
+            .db
+            .get_published_events(&req.election_id, req.limit, req.offset)
+            .await
+        {
+            Ok(records) => {
+                let events = records
+                    .into_iter()
+                    .map(|record| PublishedEvent {
+                        event_id: record.event_id,
+                        kind: record.kind as u32,
+                        relay_url: record.relay_url,
+                        created_at: record.created_at as u64,
+                    })
+                    .collect();
+
+                Ok(Response::new(ListPublishedEventsResponse {
+                    success: true,
+                    message: "Published events retrieved successfully".to_string(),
+                    events,
+                }))
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to list published events for election {}: {}",
+                    req.election_id,
+                    e
+                );
+                Ok(Response::new(ListPublishedEventsResponse {
+                    success: false,
+                    message: format!("Failed to list published events: {}", e),
+                    events: vec![],
+                }))
+            }
+        }
+    }
+
+    async fn get_issuance_log(
+        &self,
+        request: Request<GetIssuanceLogRequest>,
+    ) -> Result<Response<GetIssuanceLogResponse>, Status> {
+        let req = request.into_inner();
+
+        log::info!(
+            "Getting issuance log for election: {} with limit: {}, offset: {}",
+            req.election_id,
+            req.limit,
+            req.offset
+        );
+
+        let issuance_log = {
+            match self.elections.get(&req.election_id) {
+                Some(election) => election.read().await.issuance_log,
+                None => {
+                    return Ok(Response::new(GetIssuanceLogResponse {
+                        success: false,
+                        message: "Election not found".to_string(),
+                        ..Default::default()
+                    }));
+                }
+            }
+        };
+
+        let total_issued = match self.db.count_issued_tokens(&req.election_id).await {
+            Ok(total) => total,
+            Err(e) => {
+                log::error!(
+                    "Failed to count issued tokens for election {}: {}",
+                    req.election_id,
+                    e
+                );
+                return Ok(Response::new(GetIssuanceLogResponse {
+                    success: false,
+                    message: format!("Failed to get issuance log: {}", e),
+                    ..Default::default()
+                }));
+            }
+        };
+
+        // Without a disclosed issuance log only the total is reported
+        if !issuance_log {
+            return Ok(Response::new(GetIssuanceLogResponse {
+                success: true,
+                message: "Issuance log is not enabled for this election".to_string(),
+                issuance_log,
+                total_issued,
+                issuances: vec![],
+            }));
+        }
+
+        match self
+            .db
+            .get_token_issuances(&req.election_id, req.limit, req.offset)
+            .await
+        {
+            Ok(records) => {
+                let issuances = records
+                    .into_iter()
+                    .map(|record| TokenIssuance {
+                        voter_pubkey: record.voter_pubkey,
+                        issued_at: record.issued_at as u64,
+                    })
+                    .collect();
+
+                Ok(Response::new(GetIssuanceLogResponse {
+                    success: true,
+                    message: "Issuance log retrieved successfully".to_string(),
+                    issuance_log,
+                    total_issued,
+                    issuances,
+                }))
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to get issuance log for election {}: {}",
+                    req.election_id,
+                    e
+                );
+                Ok(Response::new(GetIssuanceLogResponse {
+                    success: false,
+                    message: format!("Failed to get issuance log: {}", e),
+                    ..Default::default()
+                }))
+            }
+        }
+    }
+
+    async fn get_turnout(
+        &self,
+        request: Request<GetTurnoutRequest>,
+    ) -> Result<Response<GetTurnoutResponse>, Status> {
+        let req = request.into_inner();
+
+        log::info!("Getting turnout for election: {}", req.election_id);
+
+        let election_ids: Vec<String> = if req.election_id.is_empty() {
+            let mut ids = Vec::new();
+            for election in self.elections.all() {
+                ids.push(election.read().await.id.clone());
+            }
+            ids
+        } else if self.elections.get(&req.election_id).is_some() {
+            vec![req.election_id]
+        } else {
+            return Ok(Response::new(GetTurnoutResponse {
+                success: false,
+                message: "Election not found".to_string(),
+                ..Default::default()
+            }));
+        };
+
+        // Counted in the database, as published
+        let mut turnouts = Vec::new();
+        for election_id in election_ids {
+            match self.db.get_turnout(&election_id).await {
+                Ok(turnout) => turnouts.push(ElectionTurnout {
+                    election_id: turnout.election_id,
+                    registered: turnout.registered,
+                    tokens_issued: turnout.tokens_issued,
+                    ballots: turnout.ballots,
+                    turnout: turnout.turnout,
+                }),
+                Err(e) => {
+                    log::error!("Failed to count turnout for election {}: {}", election_id, e);
+                    return Ok(Response::new(GetTurnoutResponse {
+                        success: false,
+                        message: format!("Failed to get turnout: {}", e),
+                        ..Default::default()
+                    }));
+                }
+            }
+        }
+
+        Ok(Response::new(GetTurnoutResponse {
+            success: true,
+            message: "Turnout retrieved successfully".to_string(),
+            turnouts,
+        }))
+    }
+
+    async fn export_table(
+        &self,
+        request: Request<ExportTableRequest>,
+    ) -> Result<Response<ExportTableResponse>, Status> {
+        let req = request.into_inner();
+
+        log::info!(
+            "Exporting table: {} as {} for election: {}",
+            req.table,
+            req.format,
+            req.election_id
+        );
+
+        let Some(table) = ExportTable::from_name(&req.table) else {
+            return Ok(Response::new(ExportTableResponse {
+                success: false,
+                message: format!("Unknown table: {}", req.table),
+                ..Default::default()
+            }));
+        };
+
+        let json = match req.format.as_str() {
+            "" | "csv" => false,
+            "json" => true,
+            other => {
+                return Ok(Response::new(ExportTableResponse {
+                    success: false,
+                    message: format!("Unsupported format: {}", other),
+                    ..Default::default()
+                }));
+            }
+        };
+
+        let election_id = (!req.election_id.is_empty()).then_some(req.election_id.as_str());
+
+        let export = match self.db.export_table(table, election_id).await {
+            Ok(export) => export,
+            Err(e) => {
+                log::error!("Failed to export table {}: {}", req.table, e);
+                return Ok(Response::new(ExportTableResponse {
+                    success: false,
+                    message: format!("Failed to export table: {}", e),
+                    ..Default::default()
+                }));
+            }
+        };
+
+        let content = if json {
+            match export.to_json() {
+                Ok(content) => content,
+                Err(e) => {
+                    log::error!("Failed to serialize table {}: {}", req.table, e);
+                    return Ok(Response::new(ExportTableResponse {
+                        success: false,
+                        message: format!("Failed to export table: {}", e),
+                        ..Default::default()
+                    }));
+                }
+            }
+        } else {
+            export.to_csv()
+        };
+
+        Ok(Response::new(ExportTableResponse {
+            success: true,
+            message: "Table exported successfully".to_string(),
+            content,
+            row_count: export.rows.len() as u32,
+        }))
+    }
+
+    /// Get the voter roll commitment of an election and the Merkle proofs of its voters
+    async fn get_voter_roll_proofs(
+        &self,
+        request: Request<GetVoterRollProofsRequest>,
+    ) -> Result<Response<GetVoterRollProofsResponse>, Status> {
+        let req = request.into_inner();
+
+        log::info!(
+            "Getting voter roll proofs for election: {} voter: {}",
+            req.election_id,
+            req.voter_pubkey
+        );
+
+        let roll = {
+            match self.elections.get(&req.election_id) {
+                Some(election) => election.read().await.voter_roll(),
+                None => {
+                    return Ok(Response::new(GetVoterRollProofsResponse {
+                        success: false,
+                        message: "Election not found".to_string(),
+                        ..Default::default()
+                    }));
+                }
+            }
+        };
+
+        // Proofs of one voter, or of the whole roll
+        let pubkeys = if req.voter_pubkey.is_empty() {
+            roll.pubkeys().to_vec()
+        } else {
+            match PublicKey::parse(&req.voter_pubkey) {
+                Ok(pubkey) => vec![pubkey.to_hex()],
+                Err(e) => {
+                    return Ok(Response::new(GetVoterRollProofsResponse {
+                        success: false,
+                        message: format!("Invalid voter public key: {}", e),
+                        ..Default::default()
+                    }));
+                }
+            }
+        };
+
+        let mut proofs = Vec::with_capacity(pubkeys.len());
+        for pubkey in pubkeys {
+            let Some(proof) = roll.proof(&pubkey) else {
+                return Ok(Response::new(GetVoterRollProofsResponse {
+                    success: false,
+                    message: "Voter not found in the election roll".to_string(),
+                    ..Default::default()
+                }));
+            };
+            proofs.push(VoterRollProof {
+                voter_pubkey: pubkey,
+                index: proof.index,
+                steps: proof
+                    .steps
+                    .into_iter()
+                    .map(|step| RollProofStep { hash: step.hash, left: step.left })
+                    .collect(),
+            });
+        }
+
+        Ok(Response::new(GetVoterRollProofsResponse {
+            success: true,
+            message: "Voter roll proofs retrieved successfully".to_string(),
+            root: roll.root().unwrap_or_default(),
+            size: roll.len() as u32,
+            proofs,
+        }))
+    }
+
+    /// Stream the changes to the EC's state to a standby EC
+    async fn replicate(
+        &self,
+        request: Request<ReplicateRequest>,
+    ) -> Result<Response<Self::ReplicateStream>, Status> {
+        let standby = request.remote_addr();
+        let req = request.into_inner();
+
+        log::info!("Standby EC {:?} following from change {} of log {}", standby, req.after, req.source);
+
+        match replication::changes(Arc::clone(&self.db), req, self.shutdown.clone()).await {
+            Ok(changes) => Ok(Response::new(Box::pin(changes))),
+            Err(e) => {
+                log::error!("Failed to start the replication: {}", e);
+                Err(Status::internal(format!("Failed to start the replication: {}", e)))
+            }
+        }
+    }
+
+    /// Get the EC public keys and the health of its relays
+    async fn server_info(
+        &self,
+        _request: Request<ServerInfoRequest>,
+    ) -> Result<Response<ServerInfoResponse>, Status> {
+        let relays = self
+            .relays
+            .reports()
+            .await
+            .into_iter()
+            .map(|report| RelayInfo {
+                url: report.url,
+                status: report.status,
+                healthy: report.healthy,
+                consecutive_failures: report.failures,
+                latency_ms: report.latency_ms,
+                pending_events: report.pending_events as u32,
+                last_error: report.last_error.unwrap_or_default(),
+            })
+            .collect();
+
+        let outbox_events = match self.db.count_outbox_events().await {
+            Ok(count) => count,
+            Err(e) => {
+                log::error!("Failed to count outbox events: {}", e);
+                return Ok(Response::new(ServerInfoResponse {
+                    success: false,
+                    message: format!("Failed to get server info: {}", e),
+                    ..Default::default()
+                }));
+            }
+        };
+
+        Ok(Response::new(ServerInfoResponse {
+            success: true,
+            message: "Server info retrieved successfully".to_string(),
+            nostr_public_key: self.identity.public_key.to_hex(),
+            rsa_public_key: self.rsa_public_key.clone(),
+            relays,
+            outbox_events,
+        }))
+    }
 }
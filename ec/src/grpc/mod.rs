@@ -1,5 +1,6 @@
 pub mod admin;
 pub mod server;
+pub mod trustee;
 #[cfg(test)]
 mod tests;
 
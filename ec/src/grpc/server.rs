@@ -1,55 +1,100 @@
 use anyhow::Result;
-use nostr_sdk::{Client, Keys};
-use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
 use tonic::transport::Server;
+use tonic::transport::server::TcpIncoming;
 
 use crate::database::Database;
-use crate::election::Election;
+use crate::elections::Elections;
 use crate::grpc::admin::AdminServiceImpl;
 use crate::grpc::admin_proto::admin_service_server::AdminServiceServer;
+use crate::grpc::admin_proto::trustee_service_server::TrusteeServiceServer;
+use crate::grpc::trustee::TrusteeServiceImpl;
+use crate::identity::EcIdentity;
+use crate::relays::RelayManager;
+use crate::trustees::Trustees;
 
 /// gRPC server configuration
 pub struct GrpcServer {
     pub port: u16,
     pub addr: SocketAddr,
+    trustees: Option<(Arc<Trustees>, SocketAddr)>,
+    listener: Option<TcpListener>,
+    trustee_listener: Option<TcpListener>,
 }
 
 impl GrpcServer {
     /// Create a new gRPC server instance
-    pub fn new(port: u16) -> Self {
-        let bind_ip = std::env::var("GRPC_BIND_IP")
-            .unwrap_or_else(|_| "127.0.0.1".to_string());
-        
-        let addr = match bind_ip.parse::<IpAddr>() {
-            Ok(ip) => SocketAddr::new(ip, port),
-            Err(_) => {
-                log::warn!("Invalid GRPC_BIND_IP '{}', defaulting to localhost", bind_ip);
-                SocketAddr::from(([127, 0, 0, 1], port))
-            }
-        };
-        
-        Self { port, addr }
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { port: addr.port(), addr, trustees: None, listener: None, trustee_listener: None }
     }
 
-    /// Start the gRPC server
+    /// Serves the token requests queued for the trustees on `addr`, apart
+    /// from the admin API
+    pub fn with_trustees(mut self, trustees: Option<Arc<Trustees>>, addr: SocketAddr) -> Self {
+        self.trustees = trustees.map(|trustees| (trustees, addr));
+        self
+    }
+
+    /// Listen on the server's addresses already, before it's started
+    pub async fn bind(mut self) -> Result<Self> {
+        self.listener = Some(listen(self.addr).await?);
+        if let Some((_, addr)) = &self.trustees {
+            self.trustee_listener = Some(listen(*addr).await?);
+        }
+        Ok(self)
+    }
+
+    /// Start the gRPC server, which stops taking requests and ends the
+    /// replication streams once `shutdown` is signaled
     pub async fn start(
-        &self,
+        mut self,
         db: Arc<Database>,
-        elections: Arc<Mutex<HashMap<String, Election>>>,
+        elections: Arc<Elections>,
         rsa_public_key: String,
-        client: Arc<Client>,
-        keys: Arc<Keys>,
+        relays: Arc<RelayManager>,
+        identity: Arc<EcIdentity>,
+        mut shutdown: watch::Receiver<bool>,
     ) -> Result<()> {
-        let admin_service = AdminServiceImpl::new(db, elections, rsa_public_key, client, keys);
+        let admin_service = AdminServiceImpl::new(db, elections, rsa_public_key, relays, identity)
+            .with_shutdown(shutdown.clone());
+        if let Some((trustees, addr)) = self.trustees.take() {
+            let listener = match self.trustee_listener.take() {
+                Some(listener) => listener,
+                None => listen(addr).await?,
+            };
+            let incoming = TcpIncoming::from_listener(listener, true, None)
+                .map_err(|e| anyhow::anyhow!("Trustee API failed: {}", e))?;
+            let mut shutdown = shutdown.clone();
+            log::info!("Starting the trustee API on {}", addr);
+            tokio::spawn(async move {
+                let served = Server::builder()
+                    .add_service(TrusteeServiceServer::new(TrusteeServiceImpl::new(trustees)))
+                    .serve_with_incoming_shutdown(incoming, async move {
+                        let _ = shutdown.changed().await;
+                    })
+                    .await;
+                if let Err(e) = served {
+                    log::error!("Trustee API failed: {}", e);
+                }
+            });
+        }
         
         log::info!("Starting gRPC server on {}", self.addr);
         
+        let listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => TcpListener::bind(self.addr).await?,
+        };
+        let incoming = TcpIncoming::from_listener(listener, true, None)
+            .map_err(|e| anyhow::anyhow!("gRPC server failed: {}", e))?;
         Server::builder()
             .add_service(AdminServiceServer::new(admin_service))
-            .serve(self.addr)
+            .serve_with_incoming_shutdown(incoming, async move {
+                let _ = shutdown.changed().await;
+            })
             .await
             .map_err(|e| anyhow::anyhow!("gRPC server failed: {}", e))?;
         
@@ -57,8 +102,14 @@ impl GrpcServer {
     }
 }
 
+async fn listen(addr: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", addr, e))
+}
+
 impl Default for GrpcServer {
     fn default() -> Self {
-        Self::new(50001)
+        Self::new(SocketAddr::from(([127, 0, 0, 1], 50001)))
     }
 }
\ No newline at end of file
@@ -5,11 +5,12 @@ mod admin_service_tests {
     use super::super::admin_proto::*;
     use crate::database::Database;
     use crate::election::Election;
-    use crate::types::Candidate;
-    use std::collections::HashMap;
+    use crate::elections::Elections;
+    use crate::identity::EcIdentity;
+    use crate::relays::RelayManager;
+    use crate::types::{Candidate, Voter};
     use std::sync::Arc;
     use tempfile::NamedTempFile;
-    use tokio::sync::Mutex;
     use tonic::Request;
     use nostr_sdk::{Client, Keys};
 
@@ -32,21 +33,21 @@ mod admin_service_tests {
         // Save election to database first
         db.upsert_election(&election).await.unwrap();
         
-        // Create elections HashMap
-        let mut elections = HashMap::new();
-        elections.insert(election_id.clone(), election);
-        let elections = Arc::new(Mutex::new(elections));
+        // Create the running elections
+        let elections = Arc::new(Elections::new([election]));
         
         // Create mock Nostr client and keys for testing
         let keys = Keys::generate();
         let client = Client::new(keys.clone());
         
+        let relays = Arc::new(RelayManager::new(client, db.clone()));
+
         let service = AdminServiceImpl::new(
             db,
             elections,
             "test_rsa_key".to_string(),
-            Arc::new(client),
-            Arc::new(keys),
+            relays,
+            Arc::new(EcIdentity::local(keys)),
         );
         
         (service, temp_file, election_id)
@@ -70,6 +71,23 @@ mod admin_service_tests {
         assert!(!inner.voter_id.is_empty());
     }
 
+    #[tokio::test]
+    async fn test_add_voter_to_started_election() {
+        let (service, _temp_file, election_id) = create_test_service().await;
+        let election = service.get_elections().get(&election_id).unwrap();
+        election.write().await.status = crate::election::Status::InProgress;
+
+        let request = Request::new(AddVoterRequest {
+            name: "Late Voter".to_string(),
+            pubkey: "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e".to_string(),
+            election_id: election_id.clone(),
+        });
+        let status = service.add_voter(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        // Nor is the voter stored
+        assert!(service.get_db().load_election_voters(&election_id).await.unwrap().is_empty());
+    }
+
     #[tokio::test]
     async fn test_add_voter_empty_name() {
         let (service, _temp_file, election_id) = create_test_service().await;
@@ -125,9 +143,14 @@ mod admin_service_tests {
 
         let request = Request::new(AddElectionRequest {
             name: "New Test Election".to_string(),
-            start_time: 1234567890,
+            start_time: chrono::Utc::now().timestamp() as u64 + 3600,
             duration: 3600,
             candidates,
+            issuance_log: false,
+            token_scheme: String::new(),
+            anonymous_requests: false,
+            encrypted_tally: false,
+            assign_ids: false,
         });
 
         let response = service.add_election(request).await.unwrap();
@@ -138,15 +161,88 @@ mod admin_service_tests {
         assert!(!inner.election_id.is_empty());
     }
 
+    #[tokio::test]
+    async fn test_add_election_token_scheme() {
+        let (service, _temp_file, _election_id) = create_test_service().await;
+        let request = |token_scheme: &str| {
+            Request::new(AddElectionRequest {
+                name: "Short Tokens".to_string(),
+                start_time: chrono::Utc::now().timestamp() as u64 + 3600,
+                duration: 3600,
+                candidates: vec![CandidateInfo { id: 1, name: "Alice".to_string(), vote_count: 0 }],
+                issuance_log: false,
+                token_scheme: token_scheme.to_string(),
+                anonymous_requests: false,
+                encrypted_tally: false,
+                assign_ids: false,
+            })
+        };
+
+        let inner = service.add_election(request("rsa-pss-deterministic")).await.unwrap().into_inner();
+        assert!(inner.success);
+        let election = service
+            .get_election(Request::new(GetElectionRequest { election_id: inner.election_id }))
+            .await
+            .unwrap()
+            .into_inner()
+            .election
+            .unwrap();
+        assert_eq!(election.token_scheme, "rsa-pss-deterministic");
+
+        let inner = service.add_election(request("schnorr")).await.unwrap().into_inner();
+        assert!(!inner.success);
+        assert_eq!(inner.message, "Unknown token scheme: schnorr");
+    }
+
+    #[tokio::test]
+    async fn test_add_election_encrypted_tally() {
+        let (service, _temp_file, _election_id) = create_test_service().await;
+        let request = |candidates: u32| {
+            Request::new(AddElectionRequest {
+                name: "Encrypted".to_string(),
+                start_time: chrono::Utc::now().timestamp() as u64 + 3600,
+                duration: 3600,
+                candidates: (1..=candidates)
+                    .map(|id| CandidateInfo { id, name: format!("Candidate {}", id), vote_count: 0 })
+                    .collect(),
+                issuance_log: false,
+                token_scheme: String::new(),
+                anonymous_requests: false,
+                encrypted_tally: true,
+                assign_ids: false,
+            })
+        };
+
+        let inner = service.add_election(request(2)).await.unwrap().into_inner();
+        assert!(inner.success);
+        let election = service
+            .get_election(Request::new(GetElectionRequest { election_id: inner.election_id }))
+            .await
+            .unwrap()
+            .into_inner()
+            .election
+            .unwrap();
+        assert_eq!(election.tally_key.len(), 66);
+
+        let inner = service.add_election(request(33)).await.unwrap().into_inner();
+        assert!(!inner.success);
+        assert_eq!(inner.message, "Encrypted tallies take up to 32 candidates");
+    }
+
     #[tokio::test]
     async fn test_add_election_empty_name() {
         let (service, _temp_file, _election_id) = create_test_service().await;
 
         let request = Request::new(AddElectionRequest {
             name: "".to_string(),
-            start_time: 1234567890,
+            start_time: chrono::Utc::now().timestamp() as u64 + 3600,
             duration: 3600,
             candidates: vec![],
+            issuance_log: false,
+            token_scheme: String::new(),
+            anonymous_requests: false,
+            encrypted_tally: false,
+            assign_ids: false,
         });
 
         let response = service.add_election(request).await.unwrap();
@@ -163,9 +259,14 @@ mod admin_service_tests {
 
         let request = Request::new(AddElectionRequest {
             name: "Test Election".to_string(),
-            start_time: 1234567890,
+            start_time: chrono::Utc::now().timestamp() as u64 + 3600,
             duration: 3600,
             candidates: vec![],
+            issuance_log: false,
+            token_scheme: String::new(),
+            anonymous_requests: false,
+            encrypted_tally: false,
+            assign_ids: false,
         });
 
         let response = service.add_election(request).await.unwrap();
@@ -176,6 +277,46 @@ mod admin_service_tests {
         assert!(inner.election_id.is_empty());
     }
 
+    #[tokio::test]
+    async fn test_election_schedule() {
+        let (service, _temp_file, _election_id) = create_test_service().await;
+        let now = chrono::Utc::now().timestamp() as u64;
+        let request = |start_time, duration| AddElectionRequest {
+            name: "Scheduled".to_string(),
+            start_time,
+            duration,
+            candidates: vec![CandidateInfo { id: 1, name: "Alice".to_string(), vote_count: 0 }],
+            issuance_log: false,
+            token_scheme: String::new(),
+            anonymous_requests: false,
+            encrypted_tally: false,
+            assign_ids: false,
+        };
+
+        // Past starts, beyond the clock skew, and absurd schedules are refused
+        let inner = service.add_election(Request::new(request(now - 3600, 3600))).await.unwrap().into_inner();
+        assert!(!inner.success);
+        assert_eq!(inner.message, "Election start time is in the past");
+        let inner = service.validate_election(Request::new(request(now + 60, 365 * 86_400))).await.unwrap().into_inner();
+        assert!(!inner.valid);
+        assert_eq!(inner.message, "Election duration is over the 90 day limit");
+        let inner = service.validate_election(Request::new(request(now + 2 * 365 * 86_400, 3600))).await.unwrap().into_inner();
+        assert!(!inner.valid);
+        assert!(service.validate_election(Request::new(request(now - 60, 3600))).await.unwrap().into_inner().valid);
+
+        // Overlapping elections are created, with a warning
+        let inner = service.add_election(Request::new(request(now + 3600, 7200))).await.unwrap().into_inner();
+        assert!(inner.success);
+        assert!(inner.warnings.is_empty());
+        let inner = service.validate_election(Request::new(request(now + 7200, 7200))).await.unwrap().into_inner();
+        assert!(inner.valid);
+        assert_eq!(inner.warnings.len(), 1);
+        assert!(inner.warnings[0].starts_with("Voting overlaps election"));
+        // Checking creates nothing
+        assert_eq!(service.get_elections().all().len(), 2);
+        assert!(service.validate_election(Request::new(request(now + 10_800, 3600))).await.unwrap().into_inner().warnings.is_empty());
+    }
+
     #[tokio::test]
     async fn test_add_candidate_success() {
         let (service, _temp_file, election_id) = create_test_service().await;
@@ -184,6 +325,7 @@ mod admin_service_tests {
             election_id,
             candidate_id: 3,
             name: "New Candidate".to_string(),
+            assign_ids: false,
         });
 
         let response = service.add_candidate(request).await.unwrap();
@@ -201,6 +343,7 @@ mod admin_service_tests {
             election_id: "nonexistent_election".to_string(),
             candidate_id: 3,
             name: "New Candidate".to_string(),
+            assign_ids: false,
         });
 
         let response = service.add_candidate(request).await.unwrap();
@@ -218,6 +361,7 @@ mod admin_service_tests {
             election_id,
             candidate_id: 1, // This ID already exists in the test election
             name: "Duplicate Candidate".to_string(),
+            assign_ids: false,
         });
 
         let response = service.add_candidate(request).await.unwrap();
@@ -227,6 +371,82 @@ mod admin_service_tests {
         assert_eq!(inner.message, "Candidate ID already exists");
     }
 
+    #[tokio::test]
+    async fn test_add_candidate_duplicate_name() {
+        let (service, _temp_file, election_id) = create_test_service().await;
+
+        let request = Request::new(AddCandidateRequest {
+            election_id: election_id.clone(),
+            candidate_id: 3,
+            name: "alice".to_string(),
+            assign_ids: false,
+        });
+
+        let inner = service.add_candidate(request).await.unwrap().into_inner();
+        assert!(!inner.success);
+        assert_eq!(inner.message, "A candidate named 'alice' already exists");
+        let election = service.get_elections().get(&election_id).unwrap();
+        assert_eq!(election.read().await.candidates.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_add_candidate_assigned_id() {
+        let (service, _temp_file, election_id) = create_test_service().await;
+
+        let request = Request::new(AddCandidateRequest {
+            election_id: election_id.clone(),
+            candidate_id: 0,
+            name: "Carol".to_string(),
+            assign_ids: true,
+        });
+
+        let inner = service.add_candidate(request).await.unwrap().into_inner();
+        assert!(inner.success);
+        assert_eq!(inner.candidate_id, 3);
+        let candidates = service.get_db().get_candidates(&election_id).await.unwrap();
+        assert_eq!((candidates[2].candidate_id, candidates[2].name.as_str()), (3, "Carol"));
+    }
+
+    #[tokio::test]
+    async fn test_add_election_candidate_ids() {
+        let (service, _temp_file, _election_id) = create_test_service().await;
+        let request = |candidates: &[(u32, &str)], assign_ids| {
+            Request::new(AddElectionRequest {
+                name: "Candidates".to_string(),
+                start_time: chrono::Utc::now().timestamp() as u64 + 3600,
+                duration: 3600,
+                candidates: candidates
+                    .iter()
+                    .map(|(id, name)| CandidateInfo { id: *id, name: name.to_string(), vote_count: 0 })
+                    .collect(),
+                issuance_log: false,
+                token_scheme: String::new(),
+                anonymous_requests: false,
+                encrypted_tally: false,
+                assign_ids,
+            })
+        };
+
+        // IDs of 0 are numbered around the given ones
+        let inner = service.add_election(request(&[(0, "Alice"), (1, "Bob"), (0, "Carol")], true)).await.unwrap().into_inner();
+        assert!(inner.success, "{}", inner.message);
+        let candidates = service.get_elections().get(&inner.election_id).unwrap().read().await.candidates.clone();
+        let ids: Vec<(u8, &str)> = candidates.iter().map(|c| (c.id, c.name.as_str())).collect();
+        assert_eq!(ids, vec![(2, "Alice"), (1, "Bob"), (3, "Carol")]);
+
+        // Only when asked
+        let inner = service.add_election(request(&[(0, "Alice")], false)).await.unwrap().into_inner();
+        assert!(inner.message.contains("Candidate ID must be greater than 0"));
+
+        // Conflicting candidates
+        let inner = service.add_election(request(&[(1, "Alice"), (1, "Bob")], false)).await.unwrap().into_inner();
+        assert!(!inner.success);
+        assert_eq!(inner.message, "Invalid candidate: Candidate ID 1 is given twice");
+        let inner = service.add_election(request(&[(1, "Alice"), (0, "ALICE")], true)).await.unwrap().into_inner();
+        assert!(!inner.success);
+        assert_eq!(inner.message, "Invalid candidate: Candidate name 'ALICE' is given twice");
+    }
+
     #[tokio::test]
     async fn test_get_election_success() {
         let (service, _temp_file, election_id) = create_test_service().await;
@@ -265,11 +485,11 @@ mod admin_service_tests {
         let (service, _temp_file, election_id) = create_test_service().await;
 
         // Add some test voters to the election first
-        let voter_pubkeys = vec![
-            "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e".to_string(),
-            "3f55f3701e9b00dce27ab6cce6cf487fd5c4ba48f46d475926ebf916d53a9db1".to_string(),
+        let voters = vec![
+            Voter::new("Alice", "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e"),
+            Voter::new("Bob", "3f55f3701e9b00dce27ab6cce6cf487fd5c4ba48f46d475926ebf916d53a9db1"),
         ];
-        service.get_db().save_election_voters(&election_id, &voter_pubkeys).await.unwrap();
+        service.get_db().save_election_voters(&election_id, &voters).await.unwrap();
 
         let request = Request::new(ListVotersRequest {
             limit: 10,
@@ -283,6 +503,31 @@ mod admin_service_tests {
         assert!(inner.success);
         assert_eq!(inner.message, "Voters retrieved successfully");
         assert_eq!(inner.voters.len(), 2);
+        assert_eq!(inner.voters[0].name, "Alice");
+        assert_eq!(inner.voters[1].name, "Bob");
+        assert!(inner.voters[0].created_at > 0);
+    }
+
+    #[tokio::test]
+    async fn test_add_voter_stores_name() {
+        let (service, _temp_file, election_id) = create_test_service().await;
+
+        let request = Request::new(AddVoterRequest {
+            name: "Alice in Wonderland".to_string(),
+            pubkey: "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e".to_string(),
+            election_id: election_id.clone(),
+        });
+        assert!(service.add_voter(request).await.unwrap().into_inner().success);
+
+        let request = Request::new(ListVotersRequest {
+            limit: 10,
+            offset: 0,
+            election_id,
+        });
+        let inner = service.list_voters(request).await.unwrap().into_inner();
+
+        assert_eq!(inner.voters.len(), 1);
+        assert_eq!(inner.voters[0].name, "Alice in Wonderland");
     }
 
     #[tokio::test]
@@ -313,6 +558,7 @@ mod admin_service_tests {
             election_id,
             candidate_id: 0, // Invalid ID
             name: "Test Candidate".to_string(),
+            assign_ids: false,
         });
 
         let response = service.add_candidate(request).await.unwrap();
@@ -334,6 +580,7 @@ mod admin_service_tests {
             election_id,
             candidate_id: 256, // Too large
             name: "Test Candidate".to_string(),
+            assign_ids: false,
         });
 
         let response = service.add_candidate(request).await.unwrap();
@@ -351,6 +598,7 @@ mod admin_service_tests {
             election_id,
             candidate_id: 5,
             name: "".to_string(), // Empty name
+            assign_ids: false,
         });
 
         let response = service.add_candidate(request).await.unwrap();
@@ -375,9 +623,8 @@ mod admin_service_tests {
         assert_eq!(inner.message, "Election canceled successfully");
 
         // Verify the election status was updated in memory
-        let elections_guard = service.get_elections().lock().await;
-        let election = elections_guard.get(&election_id).unwrap();
-        assert_eq!(election.status, crate::election::Status::Canceled);
+        let election = service.get_elections().get(&election_id).unwrap();
+        assert_eq!(election.read().await.status, crate::election::Status::Canceled);
     }
 
     #[tokio::test]
@@ -453,4 +700,377 @@ mod admin_service_tests {
             .unwrap();
         assert_eq!(canceled_election.status, "canceled");
     }
+
+    #[tokio::test]
+    async fn test_purge_election_data_requires_finished_election() {
+        let (service, _temp_file, election_id) = create_test_service().await;
+
+        let request = Request::new(PurgeElectionDataRequest {
+            election_id: election_id.clone(),
+        });
+
+        let response = service.purge_election_data(request).await.unwrap();
+        let inner = response.into_inner();
+
+        assert!(!inner.success);
+        assert_eq!(inner.message, "Only finished or canceled elections can be purged");
+    }
+
+    #[tokio::test]
+    async fn test_purge_election_data_success() {
+        let (service, _temp_file, election_id) = create_test_service().await;
+
+        let voters = vec![
+            Voter::new("Alice", "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e"),
+        ];
+        service.get_db().save_election_voters(&election_id, &voters).await.unwrap();
+        service.get_db().save_used_token(&election_id, "deadbeef").await.unwrap();
+        {
+            let election = service.get_elections().get(&election_id).unwrap();
+            let mut election = election.write().await;
+            election.register_voter(&voters[0].pubkey).unwrap();
+            election.status = crate::election::Status::Finished;
+        }
+
+        let request = Request::new(PurgeElectionDataRequest {
+            election_id: election_id.clone(),
+        });
+
+        let response = service.purge_election_data(request).await.unwrap();
+        let inner = response.into_inner();
+
+        assert!(inner.success);
+        assert_eq!(inner.voters_removed, 1);
+        assert_eq!(inner.tokens_removed, 1);
+        assert_eq!(inner.messages_removed, 0);
+
+        let election = service.get_elections().get(&election_id).unwrap();
+        assert!(election.read().await.authorized_voters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_results_history() {
+        let (service, _temp_file, election_id) = create_test_service().await;
+
+        service.get_db().save_results_snapshot(&election_id, &[(2, 1)]).await.unwrap();
+        service.get_db().save_results_snapshot(&election_id, &[(1, 1), (2, 1)]).await.unwrap();
+
+        let request = Request::new(GetResultsHistoryRequest {
+            election_id,
+            limit: 0,
+            offset: 0,
+        });
+
+        let response = service.get_results_history(request).await.unwrap();
+        let inner = response.into_inner();
+
+        assert!(inner.success);
+        assert_eq!(inner.snapshots.len(), 2);
+        assert_eq!(inner.snapshots[0].total_votes, 1);
+        assert_eq!(inner.snapshots[0].candidates[0].name, "Alice");
+        assert_eq!(inner.snapshots[0].candidates[0].vote_count, 0);
+        assert_eq!(inner.snapshots[1].total_votes, 2);
+        assert_eq!(inner.snapshots[1].candidates[1].vote_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_results_history_election_not_found() {
+        let (service, _temp_file, _election_id) = create_test_service().await;
+
+        let request = Request::new(GetResultsHistoryRequest {
+            election_id: "nonexistent_election".to_string(),
+            limit: 0,
+            offset: 0,
+        });
+
+        let response = service.get_results_history(request).await.unwrap();
+        let inner = response.into_inner();
+
+        assert!(!inner.success);
+        assert_eq!(inner.message, "Election not found");
+    }
+
+    #[tokio::test]
+    async fn test_list_published_events() {
+        let (service, _temp_file, election_id) = create_test_service().await;
+
+        let relays = vec!["wss://relay.one".to_string()];
+        service.get_db().save_published_event("ev1", &election_id, 35_000, &relays).await.unwrap();
+
+        let request = Request::new(ListPublishedEventsRequest {
+            election_id,
+            limit: 0,
+            offset: 0,
+        });
+
+        let response = service.list_published_events(request).await.unwrap();
+        let inner = response.into_inner();
+
+        assert!(inner.success);
+        assert_eq!(inner.events.len(), 1);
+        assert_eq!(inner.events[0].event_id, "ev1");
+        assert_eq!(inner.events[0].kind, 35_000);
+        assert_eq!(inner.events[0].relay_url, "wss://relay.one");
+
+        let request = Request::new(ListPublishedEventsRequest {
+            election_id: "nonexistent_election".to_string(),
+            limit: 0,
+            offset: 0,
+        });
+        let inner = service.list_published_events(request).await.unwrap().into_inner();
+        assert!(!inner.success);
+        assert_eq!(inner.message, "Election not found");
+    }
+
+    #[tokio::test]
+    async fn test_get_issuance_log() {
+        let (service, _temp_file, election_id) = create_test_service().await;
+
+        let voters = vec![
+            Voter::new("Alice", "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e"),
+            Voter::new("Bob", "3f55f3701e9b00dce27ab6cce6cf487fd5c4ba48f46d475926ebf916d53a9db1"),
+        ];
+        service.get_db().save_election_voters(&election_id, &voters).await.unwrap();
+        service.get_db().mark_token_issued(&election_id, &voters[0].pubkey, false).await.unwrap();
+
+        // Without a disclosed log only the total is reported
+        let request = Request::new(GetIssuanceLogRequest {
+            election_id: election_id.clone(),
+            limit: 0,
+            offset: 0,
+        });
+        let inner = service.get_issuance_log(request).await.unwrap().into_inner();
+        assert!(inner.success);
+        assert!(!inner.issuance_log);
+        assert_eq!(inner.total_issued, 1);
+        assert!(inner.issuances.is_empty());
+
+        {
+            let election = service.get_elections().get(&election_id).unwrap();
+            election.write().await.issuance_log = true;
+        }
+        service.get_db().mark_token_issued(&election_id, &voters[1].pubkey, true).await.unwrap();
+
+        let request = Request::new(GetIssuanceLogRequest {
+            election_id,
+            limit: 0,
+            offset: 0,
+        });
+        let inner = service.get_issuance_log(request).await.unwrap().into_inner();
+        assert!(inner.success);
+        assert!(inner.issuance_log);
+        assert_eq!(inner.total_issued, 2);
+        assert_eq!(inner.issuances.len(), 1);
+        assert_eq!(inner.issuances[0].voter_pubkey, voters[1].pubkey);
+    }
+
+    #[tokio::test]
+    async fn test_get_issuance_log_election_not_found() {
+        let (service, _temp_file, _election_id) = create_test_service().await;
+
+        let request = Request::new(GetIssuanceLogRequest {
+            election_id: "nonexistent_election".to_string(),
+            limit: 0,
+            offset: 0,
+        });
+        let inner = service.get_issuance_log(request).await.unwrap().into_inner();
+
+        assert!(!inner.success);
+        assert_eq!(inner.message, "Election not found");
+    }
+
+    #[tokio::test]
+    async fn test_get_turnout() {
+        let (service, _temp_file, election_id) = create_test_service().await;
+
+        let voters = vec![
+            Voter::new("Alice", "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e"),
+            Voter::new("Bob", "3f55f3701e9b00dce27ab6cce6cf487fd5c4ba48f46d475926ebf916d53a9db1"),
+        ];
+        service.get_db().save_election_voters(&election_id, &voters).await.unwrap();
+        service.get_db().mark_token_issued(&election_id, &voters[0].pubkey, false).await.unwrap();
+
+        let request = Request::new(GetTurnoutRequest { election_id: election_id.clone() });
+        let inner = service.get_turnout(request).await.unwrap().into_inner();
+        assert!(inner.success);
+        assert_eq!(inner.turnouts.len(), 1);
+        let turnout = &inner.turnouts[0];
+        assert_eq!((turnout.registered, turnout.tokens_issued, turnout.ballots), (2, 1, 0));
+        assert_eq!(turnout.turnout, 0.0);
+
+        // Every election without an ID
+        let inner = service.get_turnout(Request::new(GetTurnoutRequest::default())).await.unwrap().into_inner();
+        assert!(inner.success);
+        assert_eq!(inner.turnouts.len(), service.get_elections().all().len());
+        assert!(inner.turnouts.iter().any(|t| t.election_id == election_id));
+
+        let request = Request::new(GetTurnoutRequest { election_id: "nonexistent_election".to_string() });
+        let inner = service.get_turnout(request).await.unwrap().into_inner();
+        assert!(!inner.success);
+        assert_eq!(inner.message, "Election not found");
+    }
+
+    #[tokio::test]
+    async fn test_export_table() {
+        let (service, _temp_file, election_id) = create_test_service().await;
+
+        let request = Request::new(ExportTableRequest {
+            table: "candidates".to_string(),
+            format: String::new(),
+            election_id: election_id.clone(),
+        });
+        let inner = service.export_table(request).await.unwrap().into_inner();
+
+        assert!(inner.success);
+        assert_eq!(inner.row_count, 2);
+        assert!(inner.content.starts_with("election_id,candidate_id,name,vote_count\n"));
+
+        let request = Request::new(ExportTableRequest {
+            table: "elections".to_string(),
+            format: "json".to_string(),
+            election_id: String::new(),
+        });
+        let inner = service.export_table(request).await.unwrap().into_inner();
+
+        assert!(inner.success);
+        let rows: serde_json::Value = serde_json::from_str(&inner.content).unwrap();
+        assert_eq!(rows[0]["id"], election_id);
+        assert_eq!(rows[0]["name"], "Test Election");
+    }
+
+    #[tokio::test]
+    async fn test_export_table_invalid_request() {
+        let (service, _temp_file, _election_id) = create_test_service().await;
+
+        let request = Request::new(ExportTableRequest {
+            table: "message_log".to_string(),
+            format: "csv".to_string(),
+            election_id: String::new(),
+        });
+        let inner = service.export_table(request).await.unwrap().into_inner();
+        assert!(!inner.success);
+        assert_eq!(inner.message, "Unknown table: message_log");
+
+        let request = Request::new(ExportTableRequest {
+            table: "voters".to_string(),
+            format: "xml".to_string(),
+            election_id: String::new(),
+        });
+        let inner = service.export_table(request).await.unwrap().into_inner();
+        assert!(!inner.success);
+        assert_eq!(inner.message, "Unsupported format: xml");
+    }
+
+    #[tokio::test]
+    async fn test_get_voter_roll_proofs() {
+        let (service, _temp_file, election_id) = create_test_service().await;
+        let voters = [
+            "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e",
+            "e07773a92a610a28da20748fdd98bfb5af694b0cad085224801265594a98108a",
+        ];
+        for (i, pubkey) in voters.iter().enumerate() {
+            let request = Request::new(AddVoterRequest {
+                name: format!("Voter {}", i),
+                pubkey: pubkey.to_string(),
+                election_id: election_id.clone(),
+            });
+            assert!(service.add_voter(request).await.unwrap().into_inner().success);
+        }
+
+        let request = Request::new(GetVoterRollProofsRequest {
+            election_id: election_id.clone(),
+            voter_pubkey: String::new(),
+        });
+        let inner = service.get_voter_roll_proofs(request).await.unwrap().into_inner();
+        assert!(inner.success);
+        assert_eq!(inner.size, 2);
+        assert_eq!(inner.proofs.len(), 2);
+        for proof in inner.proofs {
+            let proof_steps = proof
+                .steps
+                .into_iter()
+                .map(|s| criptocracia_protocol::roll::ProofStep { hash: s.hash, left: s.left })
+                .collect();
+            let roll_proof = criptocracia_protocol::RollProof { index: proof.index, steps: proof_steps };
+            assert!(roll_proof.verify(&inner.root, &proof.voter_pubkey));
+        }
+
+        let request = Request::new(GetVoterRollProofsRequest {
+            election_id,
+            voter_pubkey: "0000000000000000000000000000000000000000000000000000000000000001".to_string(),
+        });
+        let inner = service.get_voter_roll_proofs(request).await.unwrap().into_inner();
+        assert!(!inner.success);
+    }
+
+    #[tokio::test]
+    async fn test_server_info() {
+        let (service, _temp_file, _election_id) = create_test_service().await;
+
+        let inner = service
+            .server_info(Request::new(ServerInfoRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert!(inner.success);
+        assert_eq!(inner.rsa_public_key, "test_rsa_key");
+        assert_eq!(inner.nostr_public_key.len(), 64);
+        assert!(inner.relays.is_empty());
+        assert_eq!(inner.outbox_events, 0);
+    }
+}
+
+#[cfg(test)]
+mod trustee_service_tests {
+    use super::super::admin_proto::trustee_service_server::TrusteeService;
+    use super::super::admin_proto::*;
+    use super::super::trustee::TrusteeServiceImpl;
+    use crate::database::Database;
+    use crate::trustees::{self, TrusteeSet, Trustees};
+    use std::sync::Arc;
+    use tempfile::{NamedTempFile, TempDir};
+    use tokio::sync::mpsc;
+    use tonic::Request;
+
+    #[tokio::test]
+    async fn test_calls_signed_by_the_trustee() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db = Arc::new(Database::new(temp_file.path()).await.unwrap());
+        let set = TrusteeSet::parse("2/3").unwrap();
+        let (pk, shares) = trustees::deal(256, set).unwrap();
+        let dir = TempDir::new().unwrap();
+        trustees::write_dealing(dir.path(), &pk, set, &shares).unwrap();
+        let keys = TrusteeSet::load_keys(dir.path()).unwrap();
+        let verification = TrusteeSet::load_verification(dir.path()).unwrap();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let service = TrusteeServiceImpl::new(Arc::new(Trustees::new(pk, set, keys, verification, db, tx).unwrap()));
+
+        // Claiming a trustee's index isn't enough
+        let timestamp = chrono::Utc::now().timestamp() as u64;
+        let list = |share: &trustees::TrusteeShare| ListSignatureRequestsRequest {
+            trustee_index: 1,
+            timestamp,
+            signature: share.authenticate("list", &[&timestamp.to_be_bytes()]).unwrap(),
+        };
+        let inner = service.list_signature_requests(Request::new(list(&shares[1]))).await.unwrap().into_inner();
+        assert!(!inner.success);
+        let inner = service.list_signature_requests(Request::new(list(&shares[0]))).await.unwrap().into_inner();
+        assert!(inner.success);
+        assert!(inner.requests.is_empty());
+
+        let inner = service
+            .submit_signature_share(Request::new(SubmitSignatureShareRequest {
+                request_id: "request".to_string(),
+                trustee_index: 1,
+                share: vec![1, 2, 3],
+                proof: vec![4, 5, 6],
+                signature: String::new(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!inner.success);
+        assert!(!inner.completed);
+    }
 }
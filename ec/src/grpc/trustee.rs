@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+
+use crate::grpc::admin_proto::trustee_service_server::TrusteeService;
+use crate::grpc::admin_proto::*;
+use crate::trustees::Trustees;
+
+/// Implementation of the TrusteeService gRPC service, served apart from the
+/// admin API so it can be reached by the trustees alone
+pub struct TrusteeServiceImpl {
+    trustees: Arc<Trustees>,
+}
+
+impl TrusteeServiceImpl {
+    pub fn new(trustees: Arc<Trustees>) -> Self {
+        Self { trustees }
+    }
+}
+
+#[tonic::async_trait]
+impl TrusteeService for TrusteeServiceImpl {
+    /// List the token requests waiting for a trustee's signature share
+    async fn list_signature_requests(
+        &self,
+        request: Request<ListSignatureRequestsRequest>,
+    ) -> Result<Response<ListSignatureRequestsResponse>, Status> {
+        let req = request.into_inner();
+
+        match self.trustees.pending(req.trustee_index, req.timestamp, &req.signature).await {
+            Ok(pending) => Ok(Response::new(ListSignatureRequestsResponse {
+                success: true,
+                message: "Signature requests retrieved successfully".to_string(),
+                requests: pending
+                    .into_iter()
+                    .map(|request| SignatureRequest {
+                        id: request.id,
+                        election_id: request.election_id,
+                        voter_pubkey: request.voter_pubkey,
+                        blinded_message: request.blinded_message,
+                        created_at: request.created_at as u64,
+                    })
+                    .collect(),
+            })),
+            Err(e) => {
+                log::warn!("Signature requests not listed for trustee {}: {}", req.trustee_index, e);
+                Ok(Response::new(ListSignatureRequestsResponse {
+                    success: false,
+                    message: format!("Failed to list signature requests: {}", e),
+                    ..Default::default()
+                }))
+            }
+        }
+    }
+
+    /// Submit a trustee's signature share of a token request
+    async fn submit_signature_share(
+        &self,
+        request: Request<SubmitSignatureShareRequest>,
+    ) -> Result<Response<SubmitSignatureShareResponse>, Status> {
+        let req = request.into_inner();
+
+        log::info!(
+            "Signature share from trustee {} for request {}",
+            req.trustee_index,
+            req.request_id
+        );
+
+        match self
+            .trustees
+            .submit_share(&req.request_id, req.trustee_index, &req.share, &req.proof, &req.signature)
+            .await
+        {
+            Ok(completed) => Ok(Response::new(SubmitSignatureShareResponse {
+                success: true,
+                message: "Signature share accepted".to_string(),
+                completed,
+            })),
+            Err(e) => Ok(Response::new(SubmitSignatureShareResponse {
+                success: false,
+                message: e,
+                completed: false,
+            })),
+        }
+    }
+}
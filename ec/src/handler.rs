@@ -0,0 +1,1450 @@
+/*! handler.rs — Processing of gift-wrapped voter messages
+Unwraps incoming gift wraps, dispatches token requests and votes, and records
+the outcome of every processed event in the message log. */
+
+use base64::{Engine as _, engine::general_purpose};
+use blind_rsa_signatures::{BlindSignature, BlindedMessage, MessageRandomizer, Signature as RSASignature};
+use nostr_sdk::prelude::*;
+use num_bigint_dig::BigUint;
+use rsa::PublicKeyParts;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::database::{Database, SignatureRequestRecord};
+use crate::election::{Ballot, BlindTokenRequest, Election, Status};
+use crate::elections::{Elections, SharedElection};
+use crate::identity::EcIdentity;
+use crate::logging::voter_hash;
+use crate::relays::{EventConfig, RelayManager};
+use crate::signer::BlindSigner;
+use crate::timestamp::Timestamper;
+use crate::trustees::Trustees;
+use crate::types::Message;
+use crate::verifier::{BatchVerifier, VoteToken};
+use criptocracia_protocol::{
+    AnonymousTokenRequest, ErrorCode, ErrorPayload, PublishedBallot, ResultsDelta, TokenBinding, VoteAck, VotePayload,
+};
+use criptocracia_protocol::board::{BALLOTS_TAG, ballots_hash};
+use criptocracia_protocol::election::encode_results;
+use criptocracia_protocol::tally::TALLY_TAG;
+use criptocracia_protocol::{MessageError, Transport};
+use criptocracia_protocol::message::{DM_EVENT_KIND, kind};
+
+/// Seconds in which an empty bucket of the rate limiter fills up again.
+const RATE_LIMIT_WINDOW: u64 = 60;
+
+/// Senders tracked before those whose bucket is full again are forgotten.
+const RATE_LIMIT_SENDERS: usize = 10_000;
+
+/// Largest event content taken, in bytes: far more than the biggest vote
+/// with an encrypted ballot, gift wrapped.
+const MAX_EVENT_SIZE: usize = 64 * 1024;
+
+/// Publications of an election's results with deltas between two full
+/// results events.
+const RESULTS_EVERY: u32 = 10;
+
+/// Result of processing a single gift-wrapped message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageOutcome {
+    TokenIssued,
+    /// Token request waiting for the trustees' signatures
+    TokenQueued,
+    VoteAccepted,
+    EligibilityChecked,
+    Rejected(ErrorCode, String),
+}
+
+impl MessageOutcome {
+    /// Outcome name as stored in the message log
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageOutcome::TokenIssued => "token_issued",
+            MessageOutcome::TokenQueued => "token_queued",
+            MessageOutcome::VoteAccepted => "vote_accepted",
+            MessageOutcome::EligibilityChecked => "eligibility_checked",
+            MessageOutcome::Rejected(..) => "rejected",
+        }
+    }
+
+    /// Rejection reason, if any
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            MessageOutcome::Rejected(_, reason) => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+/// Token issued right away, or queued for the trustees to sign.
+enum Issued {
+    Signed(BlindSignature),
+    Queued,
+}
+
+/// Gift wrap IDs remembered in memory to skip redelivered events without a
+/// database lookup.
+const RECENT_EVENTS_CAPACITY: usize = 10_000;
+
+/// The most recently processed event IDs, the oldest forgotten first.
+#[derive(Debug, Default)]
+struct RecentEvents {
+    ids: HashSet<EventId>,
+    order: VecDeque<EventId>,
+    capacity: usize,
+}
+
+impl RecentEvents {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, ..Default::default() }
+    }
+
+    /// Remember an event ID, false if it already was
+    fn insert(&mut self, id: EventId) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Token bucket of each sender: a sender may send `limit` messages at once,
+/// then one more each time the bucket refills by one, `limit` per window.
+#[derive(Debug, Default)]
+struct RateLimiter {
+    /// Size of the buckets and messages allowed per window (0 = no limit)
+    limit: u32,
+    /// Time the sender's bucket was last filled, and the messages left in it
+    senders: HashMap<PublicKey, (u64, f64)>,
+}
+
+impl RateLimiter {
+    fn new(limit: u32) -> Self {
+        Self { limit, ..Default::default() }
+    }
+
+    /// Messages left in a bucket at `now`
+    fn fill(&self, (filled_at, left): (u64, f64), now: u64) -> f64 {
+        let refill = now.saturating_sub(filled_at) as f64 * self.limit as f64 / RATE_LIMIT_WINDOW as f64;
+        (left + refill).min(self.limit as f64)
+    }
+
+    /// Take a message of `sender` at `now` from its bucket, false if it's empty
+    fn allow(&mut self, sender: PublicKey, now: u64) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        if self.senders.len() >= RATE_LIMIT_SENDERS {
+            let full = self.limit as f64;
+            let senders = std::mem::take(&mut self.senders);
+            self.senders = senders.into_iter().filter(|(_, bucket)| self.fill(*bucket, now) < full).collect();
+        }
+        let bucket = self.senders.get(&sender).copied().unwrap_or((now, self.limit as f64));
+        let left = self.fill(bucket, now);
+        let allowed = left >= 1.0;
+        self.senders.insert(sender, (now, if allowed { left - 1.0 } else { left }));
+        allowed
+    }
+}
+
+/// Event to publish with the results of an election.
+#[derive(Debug, Clone, PartialEq)]
+enum Publication {
+    Results(Vec<(u8, u32)>),
+    Delta(ResultsDelta),
+}
+
+/// Tally of an election and what has been published of it.
+#[derive(Debug, Default)]
+struct ResultsState {
+    /// Latest tally, as `(candidate_id, votes)` sorted by candidate ID
+    current: Vec<(u8, u32)>,
+    /// Tally in the last results or delta event
+    published: Option<Vec<(u8, u32)>>,
+    /// Delta events published since the last results event
+    deltas: u32,
+}
+
+impl ResultsState {
+    /// What to publish next, if anything. With `deltas`, the changes are
+    /// published as deltas, with full results every `RESULTS_EVERY`
+    /// publications and once the election is `closed`.
+    fn next(&mut self, election_id: &str, deltas: bool, closed: bool) -> Option<Publication> {
+        let up_to_date = self.published.as_ref() == Some(&self.current);
+        if up_to_date && (self.deltas == 0 || !closed) {
+            return None;
+        }
+        let publication = match &self.published {
+            Some(published) if deltas && !closed && self.deltas + 1 < RESULTS_EVERY => {
+                self.deltas += 1;
+                Publication::Delta(ResultsDelta::between(election_id, published, &self.current))
+            }
+            _ => {
+                self.deltas = 0;
+                Publication::Results(self.current.clone())
+            }
+        };
+        self.published = Some(self.current.clone());
+        Some(publication)
+    }
+}
+
+/// Handles gift wraps addressed to the Electoral Commission.
+pub struct MessageHandler {
+    relays: Arc<RelayManager>,
+    identity: EcIdentity,
+    db: Arc<Database>,
+    elections: Arc<Elections>,
+    /// Holder of the RSA key the tokens are signed with
+    signer: Arc<dyn BlindSigner>,
+    /// Minimum seconds between tally snapshots (0 = snapshot every accepted vote)
+    results_snapshot_interval: u64,
+    /// Seconds between results publications (0 = publish every accepted vote)
+    results_interval: u64,
+    /// Whether the changes between full results are published as deltas
+    results_deltas: bool,
+    results: Mutex<HashMap<String, ResultsState>>,
+    /// Anchors the final results of each election, when configured
+    timestamper: Option<Arc<Timestamper>>,
+    /// Maximum seconds between the time a message was written and now (0 = no limit)
+    freshness_window: u64,
+    recent_events: Mutex<RecentEvents>,
+    rate_limiter: Mutex<RateLimiter>,
+    /// NIP-13 difficulty required on gift wraps (0 = none)
+    min_pow: u8,
+    /// Whether token requests are taken in direct messages
+    direct_messages: bool,
+    /// Trustees that sign the tokens instead of `signer`, when configured
+    trustees: Option<Arc<Trustees>>,
+    /// Verifies vote tokens in batches instead of one at a time, when configured
+    verifier: Option<BatchVerifier>,
+    /// Whether messages without an election ID go to the only election not over
+    legacy_messages: bool,
+}
+
+impl MessageHandler {
+    /// Handler of the messages to the EC's identity, issuing tokens with
+    /// `signer` and publishing through `relays`, with every option off.
+    pub fn new(
+        relays: Arc<RelayManager>,
+        identity: EcIdentity,
+        db: Arc<Database>,
+        elections: Arc<Elections>,
+        signer: Arc<dyn BlindSigner>,
+    ) -> Self {
+        Self {
+            relays,
+            identity,
+            db,
+            elections,
+            signer,
+            results_snapshot_interval: 0,
+            results_interval: 0,
+            results_deltas: false,
+            results: Mutex::new(HashMap::new()),
+            timestamper: None,
+            freshness_window: 0,
+            recent_events: Mutex::new(RecentEvents::new(RECENT_EVENTS_CAPACITY)),
+            rate_limiter: Mutex::new(RateLimiter::default()),
+            min_pow: 0,
+            direct_messages: false,
+            trustees: None,
+            verifier: None,
+            legacy_messages: false,
+        }
+    }
+
+    /// Set the minimum number of seconds between tally snapshots
+    pub fn with_results_snapshot_interval(mut self, seconds: u64) -> Self {
+        self.results_snapshot_interval = seconds;
+        self
+    }
+
+    /// Set how often the results are published, and whether as deltas
+    pub fn with_results_publishing(mut self, interval: u64, deltas: bool) -> Self {
+        self.results_interval = interval;
+        self.results_deltas = deltas;
+        self
+    }
+
+    /// Anchor the final results of the elections with OpenTimestamps
+    pub fn with_timestamper(mut self, timestamper: Arc<Timestamper>) -> Self {
+        self.timestamper = Some(timestamper);
+        self
+    }
+
+    /// Set how old, or how far in the future, a message may be written
+    pub fn with_freshness_window(mut self, seconds: u64) -> Self {
+        self.freshness_window = seconds;
+        self
+    }
+
+    /// Set how many messages a sender may send per minute
+    pub fn with_rate_limit(mut self, messages_per_minute: u32) -> Self {
+        self.rate_limiter = Mutex::new(RateLimiter::new(messages_per_minute));
+        self
+    }
+
+    /// Set the proof of work difficulty required on gift wraps
+    pub fn with_min_pow(mut self, difficulty: u8) -> Self {
+        self.min_pow = difficulty;
+        self
+    }
+
+    /// Take token requests in NIP-44 direct messages too
+    pub fn with_direct_messages(mut self, enabled: bool) -> Self {
+        self.direct_messages = enabled;
+        self
+    }
+
+    /// Take messages without an election ID, from clients that predate them,
+    /// for the only election that isn't over
+    pub fn with_legacy_messages(mut self, enabled: bool) -> Self {
+        self.legacy_messages = enabled;
+        self
+    }
+
+    /// Queue token requests for the trustees to sign
+    pub fn with_trustees(mut self, trustees: Arc<Trustees>) -> Self {
+        self.trustees = Some(trustees);
+        self
+    }
+
+    /// Verify vote tokens in parallel batches
+    pub fn with_batch_verifier(mut self, verifier: BatchVerifier) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Whether a gift wrap was already processed, remembering it if not.
+    /// Relays redeliver events, and the subscription asks again for past ones.
+    async fn is_duplicate(&self, event: &Event) -> bool {
+        if !self.recent_events.lock().await.insert(event.id) {
+            return true;
+        }
+        match self.db.is_message_logged(&event.id.to_hex()).await {
+            Ok(logged) => logged,
+            Err(e) => {
+                log::error!("Failed to look up event {} in the message log: {}", event.id, e);
+                false
+            }
+        }
+    }
+
+    /// Sender, content, writing time and transport of a gift wrap or a direct message
+    async fn open_event(&self, event: &Event) -> Result<(PublicKey, String, Timestamp, Transport), String> {
+        if event.kind.as_u16() == DM_EVENT_KIND {
+            if !self.direct_messages {
+                return Err("Direct messages are not enabled".to_string());
+            }
+            let content = self
+                .identity
+                .signer
+                .nip44_decrypt(&event.pubkey, &event.content)
+                .await
+                .map_err(|e| format!("Error decrypting direct message: {}", e))?;
+            return Ok((event.pubkey, content, event.created_at, Transport::Nip44));
+        }
+        let unwrapped = nip59::extract_rumor(&self.identity.signer, event)
+            .await
+            .map_err(|e| format!("Error unwrapping gift: {}", e))?;
+        Ok((unwrapped.sender, unwrapped.rumor.content, unwrapped.rumor.created_at, Transport::GiftWrap))
+    }
+
+    /// Decodes a Base64 blinded message, which must be as long as the RSA
+    /// modulus. Longer encodings are refused before being decoded.
+    fn decode_blinded(&self, encoded: &str) -> Result<Vec<u8>, String> {
+        let size = self.signer.public_key().as_ref().size();
+        if encoded.len() > size.div_ceil(3) * 4 {
+            return Err(format!("Blinded message longer than the {} byte modulus", size));
+        }
+        let bytes = general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|e| format!("Error decoding content: {}", e))?;
+        if bytes.len() != size {
+            return Err(format!("Blinded message of {} bytes, expected {}", bytes.len(), size));
+        }
+        Ok(bytes)
+    }
+
+    /// Process a gift wrap or direct message received from a relay and record its outcome.
+    pub async fn handle_event(&self, event: &Event) {
+        // Spam without enough work is dropped before any other check
+        if !event.check_pow(self.min_pow) {
+            log::debug!(event_id:% = event.id; "Event {} below the required proof of work – ignored", event.id);
+            return;
+        }
+        if event.content.len() > MAX_EVENT_SIZE {
+            log::warn!(event_id:% = event.id; "Event of {} bytes is over the size limit – ignored", event.content.len());
+            return;
+        }
+        // Validate event signature
+        if event.verify().is_err() {
+            log::warn!(event_id:% = event.id; "Event failed signature verification – ignored");
+            return;
+        };
+        if self.is_duplicate(event).await {
+            log::debug!(event_id:% = event.id; "Event {} already processed – ignored", event.id);
+            return;
+        }
+        let (voter, content, created_at, transport) = match self.open_event(event).await {
+            Ok(opened) => opened,
+            Err(e) => {
+                log::warn!(event_id:% = event.id; "{}", e);
+                let outcome = MessageOutcome::Rejected(ErrorCode::BadFormat, e);
+                self.record_outcome(event, None, &outcome).await;
+                return;
+            }
+        };
+        let now = Timestamp::now().as_u64();
+        // Not recorded nor answered, so flooding costs the EC as little as possible
+        if !self.rate_limiter.lock().await.allow(voter, now) {
+            log::warn!(event_id:% = event.id, voter = voter_hash(&voter); "Sender is over the rate limit – message ignored");
+            return;
+        }
+        let mut message = match Message::from_json(&content) {
+            Ok(m) => m,
+            Err(e) => {
+                log::warn!(event_id:% = event.id; "Error parsing message: {}", e);
+                let outcome = MessageOutcome::Rejected(ErrorCode::BadFormat, format!("Error parsing message: {}", e));
+                self.record_outcome(event, None, &outcome).await;
+                // Without a message to answer, the error goes with no ID
+                let message = Message::new(String::new(), kind::ERROR, String::new()).with_transport(transport);
+                self.send_error(&voter, &message, &outcome).await;
+                return;
+            }
+        };
+        // Answers go the way the message came unless it asks otherwise
+        let transport = *message.transport.get_or_insert(transport);
+
+        // Fields out of bounds are refused before any decoding or cryptography
+        if let Err(e) = message.validate() {
+            log::warn!(event_id:% = event.id; "Invalid message: {}", e);
+            let outcome = MessageOutcome::Rejected(ErrorCode::BadFormat, e.to_string());
+            // Invalid IDs aren't logged nor echoed back
+            if e == MessageError::Id {
+                message.id.clear();
+            }
+            if e == MessageError::ElectionId {
+                message.election_id = None;
+            }
+            self.record_outcome(event, Some(&message), &outcome).await;
+            self.send_error(&voter, &message, &outcome).await;
+            return;
+        }
+
+        // Direct messages show who sends them, so votes are never sent that way
+        if transport == Transport::Nip44 && message.kind != kind::TOKEN_REQUEST {
+            log::warn!("Message {} of kind {} by direct message", message.id, message.kind);
+            let outcome = MessageOutcome::Rejected(
+                ErrorCode::BadFormat,
+                "Only token requests can be sent by direct message".to_string(),
+            );
+            self.record_outcome(event, Some(&message), &outcome).await;
+            self.send_error(&voter, &message, &outcome).await;
+            return;
+        }
+
+        if !is_fresh(created_at.as_u64(), now, self.freshness_window) {
+            log::warn!("Message {} written at {} is outside the freshness window", message.id, created_at);
+            let outcome = MessageOutcome::Rejected(ErrorCode::Expired, "Message outside the freshness window".to_string());
+            self.record_outcome(event, Some(&message), &outcome).await;
+            self.send_error(&voter, &message, &outcome).await;
+            return;
+        }
+
+        let outcome = match message.kind {
+            kind::TOKEN_REQUEST => self.handle_token_request(voter, &message).await,
+            kind::ANONYMOUS_TOKEN_REQUEST => self.handle_anonymous_token_request(voter, &message).await,
+            kind::VOTE => self.handle_vote(voter, &message).await,
+            kind::ELIGIBILITY => self.handle_eligibility_check(voter, &message).await,
+            _ => {
+                log::warn!("Unknown message kind: {}", message.kind);
+                MessageOutcome::Rejected(ErrorCode::BadFormat, format!("Unknown message kind: {}", message.kind))
+            }
+        };
+
+        self.record_outcome(event, Some(&message), &outcome).await;
+        self.send_error(&voter, &message, &outcome).await;
+    }
+
+    /// Tell the sender of a rejected message why, in a kind 3 message with
+    /// the same ID and election.
+    async fn send_error(&self, sender: &PublicKey, message: &Message, outcome: &MessageOutcome) {
+        let MessageOutcome::Rejected(code, reason) = outcome else {
+            return;
+        };
+        let response = message.reply(kind::ERROR, ErrorPayload::new(*code, reason.as_str()).as_json());
+        let recipient = reply_pubkey(sender, message);
+        if let Err(e) = self.send_to_voter(&recipient, &response).await {
+            log::error!("Failed to send error to {}: {}", recipient, e);
+        }
+    }
+
+    /// Acknowledge an accepted vote with a receipt event signed by the EC,
+    /// sent to the reply key of the vote message.
+    async fn send_ack(&self, sender: &PublicKey, message: &Message, election_id: &str, h_n: &[u8]) {
+        let ack = VoteAck {
+            election_id: election_id.to_string(),
+            h_n: general_purpose::STANDARD.encode(h_n),
+            accepted_at: Timestamp::now().as_u64(),
+        };
+        let receipt = match EventBuilder::text_note(ack.as_json())
+            .tags(self.relays.events().rehearsal_tag())
+            .sign(&self.identity.signer)
+            .await
+        {
+            Ok(event) => event,
+            Err(e) => {
+                log::error!("Failed to sign vote receipt: {}", e);
+                return;
+            }
+        };
+        let response = message.reply(kind::ACK, receipt.as_json());
+        let recipient = reply_pubkey(sender, message);
+        if let Err(e) = self.send_to_voter(&recipient, &response).await {
+            log::error!("Failed to send vote acknowledgment to {}: {}", recipient, e);
+        }
+    }
+
+    /// Save the outcome of a processed gift wrap to the message log
+    async fn record_outcome(
+        &self,
+        event: &Event,
+        message: Option<&Message>,
+        outcome: &MessageOutcome,
+    ) {
+        if let Err(e) = self
+            .db
+            .save_message_log(
+                &event.id.to_hex(),
+                message.and_then(|m| m.election_id.as_deref()),
+                message.map(|m| m.kind),
+                outcome.as_str(),
+                outcome.reason(),
+            )
+            .await
+        {
+            log::error!("Failed to save message log for event {}: {}", event.id, e);
+        }
+        // Where the backfill starts after a restart
+        let now = Timestamp::now().as_u64() as i64;
+        if let Err(e) = self.db.set_last_processed_at(now).await {
+            log::error!("Failed to save the last processed time: {}", e);
+        }
+    }
+
+    /// Issue a blind signature for an authorized voter and send it back
+    async fn handle_token_request(&self, voter: PublicKey, message: &Message) -> MessageOutcome {
+        log::info!(election_id = message.election_id.as_deref(), voter = voter_hash(&voter); "Token request received");
+        log::debug!("Token request: {:#?}", message);
+        let blinded_bytes = match self.decode_blinded(&message.payload) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                log::warn!("{}", e);
+                return MessageOutcome::Rejected(ErrorCode::BadFormat, e);
+            }
+        };
+        let blinded_h_n = BlindedMessage::from(blinded_bytes);
+        let req = BlindTokenRequest {
+            voter_pk: voter.to_string(),
+            blinded_h_n,
+        };
+        let election = match self.election_of(message).await {
+            Ok(election) => election,
+            Err((code, reason)) => {
+                log::warn!(voter = voter_hash(&voter); "Token request refused: {}", reason);
+                return MessageOutcome::Rejected(code, reason);
+            }
+        };
+        let mut election = election.write().await;
+        let blind_sig = match self.issue_token(&mut election, &req, &voter, message).await {
+            Ok(Issued::Signed(sig)) => {
+                log::info!(election_id = election.id.as_str(), voter = voter_hash(&voter); "Token issued for election {}", election.id);
+                sig
+            }
+            Ok(Issued::Queued) => return MessageOutcome::TokenQueued,
+            Err((code, reason)) => {
+                log::warn!(election_id = election.id.as_str(), voter = voter_hash(&voter); "Token request failed for election {}: {}", election.id, reason);
+                return MessageOutcome::Rejected(code, reason);
+            }
+        };
+        drop(election);
+        self.send_token(&voter, message, &blind_sig).await;
+
+        MessageOutcome::TokenIssued
+    }
+
+    /// Issue a blind signature to a voter who proves to be on the roll with a
+    /// ring proof, and send it back to the throwaway keys the request came from
+    async fn handle_anonymous_token_request(&self, sender: PublicKey, message: &Message) -> MessageOutcome {
+        let Some(election_id) = &message.election_id else {
+            return MessageOutcome::Rejected(ErrorCode::BadFormat, "Token request without election ID".to_string());
+        };
+        let request = match AnonymousTokenRequest::from_json(&message.payload) {
+            Ok(request) => request,
+            Err(e) => {
+                log::warn!("Invalid anonymous token request: {}", e);
+                return MessageOutcome::Rejected(ErrorCode::BadFormat, format!("Invalid token request: {}", e));
+            }
+        };
+        let blinded_bytes = match self.decode_blinded(&request.blinded_h_n) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                log::warn!("{}", e);
+                return MessageOutcome::Rejected(ErrorCode::BadFormat, e);
+            }
+        };
+
+        let issued = {
+            let Some(shared) = self.elections.get(election_id) else {
+                return MessageOutcome::Rejected(ErrorCode::UnknownElection, format!("Election {} not found", election_id));
+            };
+            let election = shared.read().await;
+            let key_image = match election.authorize_anonymous(&request, &blinded_bytes) {
+                Ok(key_image) => key_image,
+                Err(e) => {
+                    log::warn!("Anonymous token request refused for election {}: {}", election_id, e);
+                    return MessageOutcome::Rejected(e.code(), e.to_string());
+                }
+            };
+            match self.db.record_key_image(election_id, &key_image).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    log::warn!("Second anonymous token request of a voter in election {}", election_id);
+                    return MessageOutcome::Rejected(
+                        ErrorCode::Unauthorized,
+                        "Token already issued to this voter".to_string(),
+                    );
+                }
+                Err(e) => {
+                    log::error!("Failed to record key image for election {}: {}", election_id, e);
+                    return MessageOutcome::Rejected(ErrorCode::Internal, "Failed to record token issuance".to_string());
+                }
+            }
+
+            let blinded_h_n = BlindedMessage::from(blinded_bytes);
+            let issued = match &self.trustees {
+                Some(trustees) => trustees
+                    .request(election_id, &sender.to_hex(), &message.as_json(), &blinded_h_n)
+                    .await
+                    .map(|_| Issued::Queued),
+                None => self.signer.blind_sign(&blinded_h_n).map(Issued::Signed).map_err(anyhow::Error::msg),
+            };
+            if let Err(e) = &issued {
+                log::error!("Failed to issue anonymous token for election {}: {}", election_id, e);
+                if let Err(e) = self.db.release_key_image(election_id, &key_image).await {
+                    log::error!("Failed to release key image: {}", e);
+                }
+            }
+            issued
+        };
+
+        match issued {
+            Ok(Issued::Signed(blind_sig)) => {
+                log::info!(election_id = election_id.as_str(); "Anonymous token issued for election {}", election_id);
+                self.send_token(&sender, message, &blind_sig).await;
+                MessageOutcome::TokenIssued
+            }
+            Ok(Issued::Queued) => MessageOutcome::TokenQueued,
+            Err(_) => MessageOutcome::Rejected(ErrorCode::Internal, "Failed to issue token".to_string()),
+        }
+    }
+
+    /// Send a blind signature to the voter, in reply to the token request
+    async fn send_token(&self, voter: &PublicKey, message: &Message, blind_sig: &BlindSignature) {
+        // Encode token to Base64
+        let blind_sig_b64 = general_purpose::STANDARD.encode(blind_sig);
+        let response = message.reply(kind::TOKEN_REQUEST, blind_sig_b64);
+        match self.send_to_voter(voter, &response).await {
+            Ok(()) => log::info!(voter = voter_hash(voter); "Blind signature sent"),
+            Err(e) => log::error!("Failed to send blind signature: {}", e),
+        }
+    }
+
+    /// Send a token the trustees signed to the voter who requested it
+    pub async fn deliver_token(&self, request: &SignatureRequestRecord, blind_sig: &BlindSignature) {
+        let (voter, message) = match (PublicKey::from_hex(&request.voter_pubkey), Message::from_json(&request.message)) {
+            (Ok(voter), Ok(message)) => (voter, message),
+            _ => {
+                log::error!("Token request {} can't be answered", request.id);
+                return;
+            }
+        };
+        self.send_token(&voter, &message, blind_sig).await;
+    }
+
+    /// Tell a voter whether they can still request a token for an election.
+    /// The answer is gift wrapped to the voter's own key, so the roll stays private.
+    async fn handle_eligibility_check(&self, voter: PublicKey, message: &Message) -> MessageOutcome {
+        let Some(election_id) = &message.election_id else {
+            return MessageOutcome::Rejected(ErrorCode::BadFormat, "Eligibility check without election ID".to_string());
+        };
+        let eligible = match self.elections.get(election_id) {
+            Some(election) => election.read().await.authorized_voters.contains(&voter.to_hex()),
+            None => {
+                return MessageOutcome::Rejected(ErrorCode::UnknownElection, format!("Election {} not found", election_id));
+            }
+        };
+        let payload = if eligible { "eligible" } else { "not_eligible" };
+        let response = message.reply(kind::ELIGIBILITY, payload.to_string());
+        if let Err(e) = self.send_to_voter(&voter, &response).await {
+            log::error!("Failed to send eligibility answer to {}: {}", voter, e);
+        }
+
+        MessageOutcome::EligibilityChecked
+    }
+
+    /// Gift wrap a message to a voter, or encrypt it in a direct message when
+    /// its transport says so, and send it to the relays
+    async fn send_to_voter(&self, voter: &PublicKey, message: &Message) -> anyhow::Result<()> {
+        let event = match message.transport {
+            Some(Transport::Nip44) => {
+                let content = self.identity.signer.nip44_encrypt(voter, &message.as_json()).await?;
+                EventBuilder::new(Kind::from(DM_EVENT_KIND), content)
+                    .tag(Tag::public_key(*voter))
+                    .sign(&self.identity.signer)
+                    .await?
+            }
+            _ => {
+                let rumor: UnsignedEvent =
+                    EventBuilder::text_note(message.as_json()).build(self.identity.public_key);
+                EventBuilder::gift_wrap(&self.identity.signer, voter, rumor, None).await?
+            }
+        };
+        self.relays.send_event(&event).await?;
+        Ok(())
+    }
+
+    /// Verify a vote token, record the vote and publish the updated results
+    async fn handle_vote(&self, sender: PublicKey, message: &Message) -> MessageOutcome {
+        let vote_payload = match VotePayload::parse(&message.payload) {
+            Ok(p) => p,
+            Err(e) => {
+                log::warn!("{}: {}", e, message.payload);
+                return MessageOutcome::Rejected(ErrorCode::BadFormat, e.to_string());
+            }
+        };
+        // The EC counts plurality votes only, encrypted or not
+        let ballot = match (vote_payload.encrypted, &vote_payload.choices[..]) {
+            (Some(encrypted), _) => Ballot::Encrypted(encrypted),
+            (None, [vote]) => Ballot::Plain(*vote),
+            _ => {
+                log::warn!("Vote with {} choices", vote_payload.choices.len());
+                return MessageOutcome::Rejected(
+                    ErrorCode::BadFormat,
+                    "Failed to parse vote: a single candidate is expected".to_string(),
+                );
+            }
+        };
+        // Checked before any RSA operation on it
+        let modulus_size = self.signer.public_key().as_ref().size();
+        if vote_payload.token.len() != modulus_size {
+            log::warn!("Vote token of {} bytes", vote_payload.token.len());
+            return MessageOutcome::Rejected(
+                ErrorCode::BadFormat,
+                format!("Vote token of {} bytes, expected {}", vote_payload.token.len(), modulus_size),
+            );
+        }
+        let binding = vote_payload.binding;
+        let h_n_bytes = vote_payload.h_n;
+        let h_n = BigUint::from_bytes_be(&h_n_bytes);
+        let token: RSASignature = RSASignature::from(vote_payload.token);
+        let msg_rand = vote_payload.r.map(MessageRandomizer::from);
+        let shared = match self.election_of(message).await {
+            Ok(election) => election,
+            Err((code, reason)) => {
+                log::warn!("Vote refused: {}", reason);
+                return MessageOutcome::Rejected(code, reason);
+            }
+        };
+        // Tokens are verified in their election's scheme, legacy votes too
+        let scheme = shared.read().await.token_scheme;
+        // Verify the signature on the raw h_n_bytes
+        let valid = match &self.verifier {
+            Some(verifier) => {
+                let vote_token = VoteToken { scheme, token, msg_randomizer: msg_rand, msg: h_n_bytes.clone() };
+                verifier.verify(vote_token).await
+            }
+            // RSA verification is CPU bound, keep it off the async workers
+            None => {
+                let signer = Arc::clone(&self.signer);
+                let msg = h_n_bytes.clone();
+                tokio::task::spawn_blocking(move || signer.verify(scheme, &token, msg_rand, &msg))
+                    .await
+                    .unwrap_or_else(|e| {
+                        log::error!("Vote token verification failed: {}", e);
+                        false
+                    })
+            }
+        };
+        if !valid {
+            log::warn!("Invalid token signature");
+            return MessageOutcome::Rejected(ErrorCode::Unauthorized, "Invalid token signature".to_string());
+        }
+
+        let (election_id, tally, published) = {
+            let mut election = shared.write().await;
+            match self.accept_vote(&mut election, &h_n, &h_n_bytes, binding.as_ref(), &ballot).await {
+                Ok(published) => {
+                    log::info!(election_id = election.id.as_str(); "Vote accepted for election {}", election.id);
+                    // Get tally for this election, unless it is counted at the end
+                    let tally = election.tally_key.is_none().then(|| election.tally());
+                    (election.id.clone(), tally, published)
+                }
+                Err((code, reason)) => {
+                    log::warn!(election_id = election.id.as_str(); "Vote rejected for election {}: {}", election.id, reason);
+                    return MessageOutcome::Rejected(code, reason);
+                }
+            }
+        };
+
+        self.send_ack(&sender, message, &election_id, &h_n_bytes).await;
+        self.publish_ballot(&published).await;
+        if let Some(tally) = tally {
+            self.update_results(&election_id, &tally).await;
+        }
+
+        MessageOutcome::VoteAccepted
+    }
+
+    /// Save a tally snapshot unless the previous one is more recent than the configured interval
+    async fn save_results_snapshot(
+        &self,
+        election_id: &str,
+        results: &[(u8, u32)],
+    ) -> anyhow::Result<()> {
+        if self.results_snapshot_interval > 0 {
+            let now = chrono::Utc::now().timestamp();
+            if let Some(last) = self.db.get_latest_results_snapshot_time(election_id).await? {
+                if now - last < self.results_snapshot_interval as i64 {
+                    return Ok(());
+                }
+            }
+        }
+        self.db.save_results_snapshot(election_id, results).await
+    }
+
+    /// Election a token request or vote is for. Messages without an election
+    /// ID, from clients that predate them, only go to the single election
+    /// that isn't over, and only when legacy messages are taken: with more,
+    /// the token or vote could land in an election the voter didn't mean.
+    async fn election_of(&self, message: &Message) -> Result<SharedElection, (ErrorCode, String)> {
+        if let Some(election_id) = &message.election_id {
+            return self
+                .elections
+                .get(election_id)
+                .ok_or_else(|| (ErrorCode::UnknownElection, format!("Election {} not found", election_id)));
+        }
+        if !self.legacy_messages {
+            return Err((ErrorCode::BadFormat, "Message without election ID".to_string()));
+        }
+        let mut running = Vec::new();
+        for shared in self.elections.all() {
+            if !matches!(shared.read().await.status, Status::Finished | Status::Canceled) {
+                running.push(shared);
+            }
+        }
+        match running.len() {
+            1 => Ok(running.remove(0)),
+            0 => Err((ErrorCode::UnknownElection, "No election for a message without election ID".to_string())),
+            n => Err((
+                ErrorCode::BadFormat,
+                format!("Message without election ID, with {} elections running: the election ID is needed", n),
+            )),
+        }
+    }
+
+    /// Issue a blind signature, or queue the request for the trustees, and
+    /// mark the voter as served in the database. The voter is authorized
+    /// again if the database write fails.
+    async fn issue_token(
+        &self,
+        election: &mut Election,
+        req: &BlindTokenRequest,
+        voter: &PublicKey,
+        message: &Message,
+    ) -> Result<Issued, (ErrorCode, String)> {
+        let voter_hex = voter.to_hex();
+        let (issued, queued) = match &self.trustees {
+            Some(trustees) => {
+                election.authorize_token(req).map_err(|e| (e.code(), e.to_string()))?;
+                match trustees.request(&election.id, &voter_hex, &message.as_json(), &req.blinded_h_n).await {
+                    Ok(id) => (Issued::Queued, Some(id)),
+                    Err(e) => {
+                        log::error!("Failed to queue token request for election {}: {}", election.id, e);
+                        election.restore_voter(&voter_hex);
+                        return Err((ErrorCode::Internal, "Failed to queue token request".to_string()));
+                    }
+                }
+            }
+            None => {
+                let token = election
+                    .issue_token(req.clone(), self.signer.as_ref())
+                    .map_err(|e| (e.code(), e.to_string()))?;
+                (Issued::Signed(token), None)
+            }
+        };
+
+        if let Err(e) = self
+            .db
+            .mark_token_issued(&election.id, &voter_hex, election.issuance_log)
+            .await {
+            log::error!("Failed to persist token issuance for election {}: {}", election.id, e);
+            election.restore_voter(&voter_hex);
+            // The trustees mustn't sign a request the voter can make again
+            if let Some(id) = queued {
+                if let Err(e) = self.db.complete_signature_request(&id).await {
+                    log::error!("Failed to withdraw token request {}: {}", id, e);
+                }
+            }
+            return Err((ErrorCode::Internal, "Failed to record token issuance".to_string()));
+        }
+
+        Ok(issued)
+    }
+
+    /// Receive a vote and commit the used token, the ballot and vote counts
+    /// together. The vote is reverted in memory if the database write fails.
+    async fn accept_vote(
+        &self,
+        election: &mut Election,
+        h_n: &BigUint,
+        h_n_bytes: &[u8],
+        binding: Option<&TokenBinding>,
+        ballot: &Ballot,
+    ) -> Result<PublishedBallot, (ErrorCode, String)> {
+        election
+            .check_binding(h_n_bytes, binding)
+            .map_err(|e| (e.code(), e.to_string()))?;
+        election
+            .check_ballot(h_n_bytes, ballot)
+            .map_err(|e| (e.code(), e.to_string()))?;
+        let received = match ballot {
+            Ballot::Plain(vote) => election.receive_vote(h_n.clone(), *vote),
+            Ballot::Encrypted(_) => election.receive_encrypted_vote(h_n.clone()),
+        };
+        received.map_err(|e| (e.code(), e.to_string()))?;
+
+        let token_hash = format!("{:x}", h_n);
+        let ballot = match ballot {
+            Ballot::Plain(vote) => PublishedBallot::new(&election.id, h_n_bytes, vec![*vote]),
+            Ballot::Encrypted(encrypted) => PublishedBallot::new_encrypted(&election.id, h_n_bytes, encrypted.clone()),
+        };
+        if let Err(e) = self
+            .db
+            .record_vote(&election.id, &token_hash, &ballot, &election.vote_counts())
+            .await
+        {
+            log::error!("Failed to persist vote for election {}: {}", election.id, e);
+            election.revert_vote(h_n);
+            return Err((ErrorCode::Internal, "Failed to record vote".to_string()));
+        }
+
+        Ok(ballot)
+    }
+
+    /// Publish an accepted ballot on the bulletin board, in a ballot event
+    /// pointing at its election. Relays that miss it get it from the outbox.
+    async fn publish_ballot(&self, ballot: &PublishedBallot) {
+        let events = self.relays.events();
+        let election = Coordinate::new(Kind::Custom(events.kinds.election), self.identity.public_key)
+            .identifier(&ballot.election_id);
+
+        match EventBuilder::new(Kind::Custom(events.kinds.ballot), ballot.as_json())
+            .tag(Tag::identifier(format!("{}:{}", ballot.election_id, ballot.h_n)))
+            .tag(Tag::coordinate(election, None))
+            .tag(EventConfig::expiration(events.ballot_ttl_days))
+            .tags(events.rehearsal_tag())
+            .sign(&self.identity.signer)
+            .await
+        {
+            Ok(event) => match self.relays.send_event(&event).await {
+                Ok(relays) => log::info!("Ballot published to {} relay(s)", relays.len()),
+                Err(e) => log::error!("Failed to publish ballot: {}", e),
+            },
+            Err(e) => log::error!("Failed to sign ballot event: {}", e),
+        }
+    }
+
+    /// Save a tally snapshot and publish the new results now, or with the
+    /// next batch when they are published every few seconds
+    async fn update_results(&self, election_id: &str, tally: &HashMap<crate::types::Candidate, u32>) {
+        let mut results = String::new();
+        let mut json_results: Vec<(u8, u32)> = Vec::new();
+        for (cand, count) in tally {
+            results.push_str(&format!("{}: {} vote(s)\n", cand.name, count));
+            json_results.push((cand.id, *count));
+        }
+        json_results.sort_unstable();
+        println!("🗳️ Election's result: \n\n{}", results);
+
+        // Keep the tally over time for turnout charts
+        if let Err(err) = self.save_results_snapshot(election_id, &json_results).await {
+            log::error!("Failed to save results snapshot: {}", err);
+        }
+
+        let publication = {
+            let mut states = self.results.lock().await;
+            let state = states.entry(election_id.to_string()).or_default();
+            state.current = json_results;
+            match self.results_interval {
+                0 => state.next(election_id, self.results_deltas, false),
+                _ => None,
+            }
+        };
+        if let Some(publication) = publication {
+            self.publish(election_id, publication).await;
+        }
+    }
+
+    /// Queue results the relays miss, for the results publisher to publish
+    /// them in full on its next round
+    pub async fn queue_results(&self, election_id: &str, results: Vec<(u8, u32)>) {
+        let mut states = self.results.lock().await;
+        let state = states.entry(election_id.to_string()).or_default();
+        state.current = results;
+        state.published = None;
+    }
+
+    /// Count the encrypted ballots of the finished elections not counted yet,
+    /// and queue their final results with the proof of the count
+    async fn tally_encrypted(&self) {
+        // The tally key is derived from the local Nostr key
+        let Some(keys) = self.identity.local_keys() else {
+            return;
+        };
+        let mut pending = Vec::new();
+        for election in self.elections.all() {
+            let election = election.read().await;
+            if election.status == Status::Finished && election.awaiting_tally() {
+                pending.push(election.id.clone());
+            }
+        }
+        for election_id in pending {
+            let ballots = match self.db.get_ballots(&election_id).await {
+                Ok(ballots) => ballots,
+                Err(e) => {
+                    log::error!("Failed to load ballots of election {}: {}", election_id, e);
+                    continue;
+                }
+            };
+            let tallied = {
+                let Some(shared) = self.elections.get(&election_id) else {
+                    continue;
+                };
+                let mut election = shared.write().await;
+                match election.decrypt_tally(keys, &ballots) {
+                    Ok(()) => election.clone(),
+                    Err(e) => {
+                        log::error!("Failed to count the encrypted ballots of election {}: {}", election_id, e);
+                        continue;
+                    }
+                }
+            };
+            let (counts, proof) = (tallied.vote_counts(), tallied.tally_proof.as_ref());
+            if let Some(proof) = proof {
+                if let Err(e) = self.db.record_tally(&election_id, &counts, proof).await {
+                    // Counted again on the next round
+                    log::error!("Failed to record the tally of election {}: {}", election_id, e);
+                    if let Some(election) = self.elections.get(&election_id) {
+                        election.write().await.tally_proof = None;
+                    }
+                    continue;
+                }
+            }
+            log::info!("Counted {} encrypted ballot(s) of election {}", ballots.len(), election_id);
+            self.update_results(&election_id, &tallied.tally()).await;
+        }
+    }
+
+    /// Publish the results that changed since the last batch, and the final
+    /// results of the elections closed since
+    pub async fn flush_results(&self) {
+        self.tally_encrypted().await;
+        let mut closed = HashSet::new();
+        for election in self.elections.all() {
+            let election = election.read().await;
+            if matches!(election.status, Status::Finished | Status::Canceled) {
+                closed.insert(election.id.clone());
+            }
+        }
+        let publications: Vec<(String, Publication)> = {
+            let mut states = self.results.lock().await;
+            let publications = states
+                .iter_mut()
+                .filter_map(|(id, state)| {
+                    let is_closed = closed.contains(id);
+                    let publication = match state.next(id, self.results_deltas, is_closed) {
+                        Some(publication) => publication,
+                        // The final results are published again to be anchored
+                        None if is_closed && self.timestamper.is_some() => Publication::Results(state.current.clone()),
+                        None => return None,
+                    };
+                    Some((id.clone(), publication))
+                })
+                .collect();
+            // Nothing changes once the final results are out
+            states.retain(|id, _| !closed.contains(id));
+            publications
+        };
+        for (election_id, publication) in publications {
+            let event = self.publish(&election_id, publication).await;
+            if let (Some(timestamper), Some(event)) = (&self.timestamper, event) {
+                if closed.contains(&election_id) {
+                    if let Err(e) = timestamper.stamp_results(&election_id, &event).await {
+                        log::error!("Failed to timestamp final results: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Publish the results or a delta, returning the results event
+    async fn publish(&self, election_id: &str, publication: Publication) -> Option<Event> {
+        match publication {
+            Publication::Results(results) => self.publish_results(election_id, &results).await,
+            Publication::Delta(delta) => {
+                self.publish_delta(&delta).await;
+                None
+            }
+        }
+    }
+
+    /// Publish the changes to the results in a delta event pointing at the
+    /// election, identified by the number of votes counted
+    async fn publish_delta(&self, delta: &ResultsDelta) {
+        let events = self.relays.events();
+        let election = Coordinate::new(Kind::Custom(events.kinds.election), self.identity.public_key)
+            .identifier(&delta.election_id);
+
+        match EventBuilder::new(Kind::Custom(events.kinds.delta), delta.as_json())
+            .tag(Tag::identifier(format!("{}:{}", delta.election_id, delta.to)))
+            .tag(Tag::coordinate(election, None))
+            .tag(EventConfig::expiration(events.results_ttl_days))
+            .tags(events.rehearsal_tag())
+            .sign(&self.identity.signer)
+            .await
+        {
+            Ok(event) => match self.relays.send_event(&event).await {
+                Ok(relays) => log::info!("Results delta published to {} relay(s)", relays.len()),
+                Err(e) => log::error!("Failed to publish results delta: {}", e),
+            },
+            Err(e) => log::error!("Failed to sign results delta event: {}", e),
+        }
+    }
+
+    /// Publish the results in a results event, returned once signed
+    async fn publish_results(&self, election_id: &str, results: &[(u8, u32)]) -> Option<Event> {
+        let json_string = encode_results(results);
+        let events = self.relays.events();
+
+        // The ballots counted so far, so anyone can check them against the bulletin board
+        let ballots_tag = match self.db.get_ballots(election_id).await {
+            Ok(ballots) => Some(Tag::custom(
+                TagKind::custom(BALLOTS_TAG),
+                [ballots.len().to_string(), ballots_hash(&ballots)],
+            )),
+            Err(e) => {
+                log::error!("Failed to load ballots of election {}: {}", election_id, e);
+                None
+            }
+        };
+
+        // Encrypted tallies come with the proof of their decryption
+        let tally_tag = match self.elections.get(election_id) {
+            Some(election) => election
+                .read()
+                .await
+                .tally_proof
+                .as_ref()
+                .map(|proof| Tag::custom(TagKind::custom(TALLY_TAG), [proof.as_json()])),
+            None => None,
+        };
+
+        // We publish the results in a custom event with the results kind (35_001 by default)
+        let event = match EventBuilder::new(Kind::Custom(events.kinds.results), json_string)
+            .tag(Tag::identifier(election_id.to_string()))
+            .tags(ballots_tag)
+            .tags(tally_tag)
+            .tag(EventConfig::expiration(events.results_ttl_days))
+            .tags(events.rehearsal_tag())
+            .sign(&self.identity.signer)
+            .await
+        {
+            Ok(event) => event,
+            Err(e) => {
+                log::error!("Failed to sign results event: {}", e);
+                return None;
+            }
+        };
+
+        // Publish the event to the relay
+        match self.relays.send_event(&event).await {
+            Ok(relays) => {
+                log::info!("Election results published to {} relay(s)", relays.len());
+                if let Err(e) = self
+                    .db
+                    .save_published_event(&event.id.to_hex(), election_id, events.kinds.results, &relays)
+                    .await
+                {
+                    log::error!("Failed to record published results event: {}", e);
+                }
+            }
+            Err(e) => log::error!("Failed to publish results: {}", e),
+        }
+        Some(event)
+    }
+}
+
+/// Whether a message written at `created_at` is at most `window` seconds
+/// older or newer than `now`. A window of 0 accepts any time.
+fn is_fresh(created_at: u64, now: u64, window: u64) -> bool {
+    window == 0 || created_at.abs_diff(now) <= window
+}
+
+/// Key the answers to a message go to: its reply key, or else its sender.
+fn reply_pubkey(sender: &PublicKey, message: &Message) -> PublicKey {
+    match message.reply_to.as_deref().map(PublicKey::from_hex) {
+        Some(Ok(pubkey)) => pubkey,
+        Some(Err(e)) => {
+            log::warn!("Invalid reply key in message {}: {}", message.id, e);
+            *sender
+        }
+        None => *sender,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signer::LocalSigner;
+
+    #[test]
+    fn test_message_outcome_log_fields() {
+        assert_eq!(MessageOutcome::TokenIssued.as_str(), "token_issued");
+        assert_eq!(MessageOutcome::TokenQueued.as_str(), "token_queued");
+        assert_eq!(MessageOutcome::VoteAccepted.as_str(), "vote_accepted");
+        assert!(MessageOutcome::VoteAccepted.reason().is_none());
+        assert_eq!(MessageOutcome::EligibilityChecked.as_str(), "eligibility_checked");
+
+        let rejected = MessageOutcome::Rejected(ErrorCode::Duplicate, "duplicated vote".to_string());
+        assert_eq!(rejected.as_str(), "rejected");
+        assert_eq!(rejected.reason(), Some("duplicated vote"));
+    }
+
+    #[test]
+    fn test_recent_events() {
+        let ids: Vec<EventId> = (0..3u8).map(|i| EventId::from_byte_array([i; 32])).collect();
+        let mut recent = RecentEvents::new(2);
+        assert!(recent.insert(ids[0]));
+        assert!(!recent.insert(ids[0]));
+        assert!(recent.insert(ids[1]));
+        // The oldest is forgotten past the capacity
+        assert!(recent.insert(ids[2]));
+        assert!(recent.insert(ids[0]));
+        assert!(!recent.insert(ids[2]));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let (alice, bob) = (Keys::generate().public_key(), Keys::generate().public_key());
+        let mut limiter = RateLimiter::new(2);
+        assert!(limiter.allow(alice, 1_000));
+        assert!(limiter.allow(alice, 1_010));
+        assert!(!limiter.allow(alice, 1_020));
+        // Each sender has its own bucket
+        assert!(limiter.allow(bob, 1_020));
+        // The bucket fills up again over the window
+        assert!(limiter.allow(alice, 1_000 + RATE_LIMIT_WINDOW));
+
+        // A burst as big as the bucket, then one message per refill
+        let mut limiter = RateLimiter::new(60);
+        assert!((0..60).all(|_| limiter.allow(alice, 1_000)));
+        assert!(!limiter.allow(alice, 1_000));
+        assert!(limiter.allow(alice, 1_001));
+        assert!(!limiter.allow(alice, 1_001));
+
+        let mut unlimited = RateLimiter::new(0);
+        assert!((0..100).all(|_| unlimited.allow(alice, 1_000)));
+    }
+
+    #[test]
+    fn test_results_publications() {
+        let mut state = ResultsState { current: vec![(1, 1)], ..Default::default() };
+        // The first publication has the full results
+        assert_eq!(state.next("e1", true, false), Some(Publication::Results(vec![(1, 1)])));
+        assert_eq!(state.next("e1", true, false), None);
+
+        // Then the changes, with full results every few publications
+        for votes in 2..=RESULTS_EVERY {
+            state.current = vec![(1, votes)];
+            let delta = ResultsDelta { election_id: "e1".into(), from: votes - 1, to: votes, changes: vec![(1, 1)] };
+            assert_eq!(state.next("e1", true, false), Some(Publication::Delta(delta)));
+        }
+        state.current = vec![(1, 20)];
+        assert_eq!(state.next("e1", true, false), Some(Publication::Results(vec![(1, 20)])));
+
+        // And once the election is closed
+        state.current = vec![(1, 21)];
+        assert!(matches!(state.next("e1", true, false), Some(Publication::Delta(_))));
+        assert_eq!(state.next("e1", true, true), Some(Publication::Results(vec![(1, 21)])));
+        assert_eq!(state.next("e1", true, true), None);
+
+        // Without deltas, every publication has the full results
+        state.current = vec![(1, 22)];
+        assert_eq!(state.next("e1", false, false), Some(Publication::Results(vec![(1, 22)])));
+    }
+
+    async fn create_test_handler() -> (MessageHandler, tempfile::NamedTempFile) {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db = Arc::new(Database::new(temp_file.path()).await.unwrap());
+        let keys = Keys::generate();
+        let relays = Arc::new(RelayManager::new(Client::new(keys.clone()), db.clone()));
+        let (pk, sk) = crate::util::load_keys_from_pem(
+            include_str!("../ec_private.pem"),
+            include_str!("../ec_public.pem"),
+        )
+        .unwrap();
+        let signer = Arc::new(LocalSigner::new(pk, sk));
+        let handler = MessageHandler::new(relays, EcIdentity::local(keys), db, Arc::new(Elections::default()), signer);
+        (handler, temp_file)
+    }
+
+    #[tokio::test]
+    async fn test_open_direct_message() {
+        let (handler, _temp_file) = create_test_handler().await;
+        let voter = Keys::generate();
+        let message = Message::new("t".to_string(), kind::TOKEN_REQUEST, "p".to_string());
+        let content = nip44::encrypt(
+            voter.secret_key(),
+            &handler.identity.public_key,
+            message.as_json(),
+            nip44::Version::V2,
+        )
+        .unwrap();
+        let dm = EventBuilder::new(Kind::from(DM_EVENT_KIND), content)
+            .tag(Tag::public_key(handler.identity.public_key))
+            .sign_with_keys(&voter)
+            .unwrap();
+
+        // Only taken when enabled
+        assert!(handler.open_event(&dm).await.is_err());
+        let handler = handler.with_direct_messages(true);
+        let (sender, content, _, transport) = handler.open_event(&dm).await.unwrap();
+        assert_eq!(sender, voter.public_key());
+        assert_eq!(Message::from_json(&content).unwrap(), message);
+        assert_eq!(transport, Transport::Nip44);
+
+        let rumor = EventBuilder::text_note(message.as_json()).build(voter.public_key());
+        let gift_wrap = EventBuilder::gift_wrap(&voter, &handler.identity.public_key, rumor, None).await.unwrap();
+        let (sender, _, _, transport) = handler.open_event(&gift_wrap).await.unwrap();
+        assert_eq!((sender, transport), (voter.public_key(), Transport::GiftWrap));
+    }
+
+    #[tokio::test]
+    async fn test_election_of() {
+        let (handler, _temp_file) = create_test_handler().await;
+        let candidates = vec![crate::types::Candidate::new(1, "Alice")];
+        let first = handler.elections.insert(Election::new("First".to_string(), candidates.clone(), 1_000, 3_600, "key".to_string()));
+        let first_id = first.read().await.id.clone();
+        let legacy = Message::new("m".to_string(), kind::VOTE, "p".to_string());
+        let scoped = Message::new_with_election("m".to_string(), kind::VOTE, "p".to_string(), first_id.clone());
+        let unknown = Message::new_with_election("m".to_string(), kind::VOTE, "p".to_string(), "none".to_string());
+
+        // Refused unless legacy messages are taken
+        assert_eq!(handler.election_of(&legacy).await.unwrap_err().0, ErrorCode::BadFormat);
+        assert_eq!(handler.election_of(&unknown).await.unwrap_err().0, ErrorCode::UnknownElection);
+        let handler = handler.with_legacy_messages(true);
+        assert_eq!(handler.election_of(&legacy).await.unwrap().read().await.id, first_id);
+
+        // Never guessed between running elections
+        let second = handler.elections.insert(Election::new("Second".to_string(), candidates, 1_000, 3_600, "key".to_string()));
+        assert_eq!(handler.election_of(&legacy).await.unwrap_err().0, ErrorCode::BadFormat);
+        assert_eq!(handler.election_of(&scoped).await.unwrap().read().await.id, first_id);
+        second.write().await.status = Status::Finished;
+        assert_eq!(handler.election_of(&legacy).await.unwrap().read().await.id, first_id);
+        first.write().await.status = Status::Canceled;
+        assert_eq!(handler.election_of(&legacy).await.unwrap_err().0, ErrorCode::UnknownElection);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_vote_token_scheme() {
+        use criptocracia_protocol::{BlindTokenScheme, TokenScheme};
+
+        let (handler, _temp_file) = create_test_handler().await;
+        let handler = handler.with_legacy_messages(true);
+        let (pk, sk) = crate::util::load_keys_from_pem(
+            include_str!("../ec_private.pem"),
+            include_str!("../ec_public.pem"),
+        )
+        .unwrap();
+        let now = chrono::Utc::now().timestamp() as u64;
+        let candidates = vec![crate::types::Candidate::new(1, "Alice")];
+        let mut election = Election::new("Deterministic".to_string(), candidates, now - 60, 3_600, "key".to_string());
+        election.status = Status::InProgress;
+        election.token_scheme = TokenScheme::RsaPssDeterministic;
+        let binding = TokenBinding { nonce: vec![7; 32], expiry: election.end_time };
+        let h_n = binding.hash(&election.id);
+        handler.db.upsert_election(&election).await.unwrap();
+        handler.elections.insert(election);
+
+        // A token of the election's scheme, in a vote without the election ID
+        let scheme = TokenScheme::RsaPssDeterministic;
+        let blinding = scheme.blind(&pk, &h_n).unwrap();
+        let blind_sig = scheme.sign(&sk, &blinding.blind_msg).unwrap();
+        let token = scheme.unblind(&pk, &blind_sig, &blinding.secret, None, &h_n).unwrap();
+        let payload = VotePayload {
+            h_n,
+            token: token.to_vec(),
+            r: None,
+            choices: vec![1],
+            binding: Some(binding),
+            encrypted: None,
+        };
+        let message = Message::new("m".to_string(), kind::VOTE, payload.encode());
+        let outcome = handler.handle_vote(Keys::generate().public_key(), &message).await;
+        assert_eq!(outcome.as_str(), "vote_accepted", "{:?}", outcome.reason());
+    }
+
+    #[tokio::test]
+    async fn test_decode_blinded() {
+        let (handler, _temp_file) = create_test_handler().await;
+        let size = handler.signer.public_key().as_ref().size();
+        let b64 = &general_purpose::STANDARD;
+        assert_eq!(handler.decode_blinded(&b64.encode(vec![7u8; size])).unwrap().len(), size);
+        assert!(handler.decode_blinded(&b64.encode(vec![7u8; size - 1])).is_err());
+        assert!(handler.decode_blinded("not base64!").is_err());
+        // Giant blobs are refused without being decoded
+        let giant = "A".repeat(1024 * 1024);
+        assert!(handler.decode_blinded(&giant).unwrap_err().contains("longer than"));
+    }
+
+    #[test]
+    fn test_is_fresh() {
+        assert!(is_fresh(1_000, 1_300, 300));
+        assert!(is_fresh(1_300, 1_000, 300));
+        assert!(!is_fresh(1_000, 1_301, 300));
+        assert!(!is_fresh(1_301, 1_000, 300));
+        assert!(is_fresh(0, 1_000_000, 0));
+    }
+
+    #[test]
+    fn test_reply_pubkey() {
+        let sender = Keys::generate().public_key();
+        let reply = Keys::generate().public_key();
+        let message = Message::new("vote_1".to_string(), kind::VOTE, String::new());
+        assert_eq!(reply_pubkey(&sender, &message), sender);
+        let message = message.with_reply_to(reply.to_hex());
+        assert_eq!(reply_pubkey(&sender, &message), reply);
+        let message = Message::new("vote_2".to_string(), kind::VOTE, String::new()).with_reply_to("bad".to_string());
+        assert_eq!(reply_pubkey(&sender, &message), sender);
+    }
+}
@@ -0,0 +1,167 @@
+/*! identity.rs — The EC's Nostr identity
+Every event the EC publishes is signed, and every message sent to it is
+decrypted, through `EcIdentity`: the local Nostr key, or a NIP-46 remote
+signer (`--bunker`) so the key is never loaded into the EC's memory. Each
+gift wrap then takes two round trips to the signer. Encrypted tallies derive
+their key from the local Nostr key, so they aren't available with a remote
+signer. Once `with_content_signer` is set, the content of election events
+is also signed by `ContentSigner`, with the dedicated key of `ec_content.pem`. */
+
+use anyhow::{Context, Result};
+use base64::engine::{Engine, general_purpose};
+use criptocracia_protocol::ElectionEvent;
+use nostr_connect::prelude::*;
+use nostr_sdk::prelude::{ConnectionMode, RelayOptions};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::signer::ContentSigner;
+use crate::util::write_private_file;
+
+/// File in the app directory with the keys the EC talks to its remote signer with.
+pub const NOSTR_CONNECT_KEY_FILE: &str = "nostr-connect.key";
+
+/// Time the remote signer is given to answer each request.
+const REMOTE_SIGNER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The EC's Nostr identity, which signs its events and decrypts the messages
+/// sent to it: the local key, or a NIP-46 remote signer ("bunker") so the
+/// key is never loaded into the EC's memory.
+#[derive(Clone)]
+pub struct EcIdentity {
+    pub signer: Arc<dyn NostrSigner>,
+    pub public_key: PublicKey,
+    /// The local keys, none with a remote signer
+    keys: Option<Keys>,
+    /// Signer of the content of election events, if any
+    content_signer: Option<Arc<ContentSigner>>,
+}
+
+impl std::fmt::Debug for EcIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EcIdentity")
+            .field("public_key", &self.public_key)
+            .field("local", &self.keys.is_some())
+            .field("signs_content", &self.content_signer.is_some())
+            .finish()
+    }
+}
+
+impl EcIdentity {
+    pub fn local(keys: Keys) -> Self {
+        Self {
+            signer: Arc::new(keys.clone()),
+            public_key: keys.public_key(),
+            keys: Some(keys),
+            content_signer: None,
+        }
+    }
+
+    /// Connects to the remote signer of a `bunker://` URI, through the SOCKS5
+    /// `proxy` if any, and asks for the EC's public key. The keys the EC
+    /// talks to the signer with are kept in `app_dir`, so the signer
+    /// remembers its approval across restarts.
+    pub async fn remote(bunker_uri: &str, app_dir: &Path, proxy: Option<SocketAddr>) -> Result<Self> {
+        let uri = NostrConnectURI::parse(bunker_uri).context("Invalid bunker URI")?;
+        if !uri.is_bunker() {
+            return Err(anyhow::anyhow!("Not a bunker URI: {}", bunker_uri));
+        }
+        let app_keys = load_app_keys(&app_dir.join(NOSTR_CONNECT_KEY_FILE))?;
+        let opts = proxy.map(|addr| RelayOptions::new().connection_mode(ConnectionMode::proxy(addr)));
+        let mut connect = NostrConnect::new(uri, app_keys, REMOTE_SIGNER_TIMEOUT, opts)?;
+        connect.auth_url_handler(AuthUrl);
+        let public_key = connect
+            .get_public_key()
+            .await
+            .context("The remote signer didn't answer")?;
+        log::info!("Remote signer connected for {}", public_key);
+        Ok(Self {
+            signer: Arc::new(connect),
+            public_key,
+            keys: None,
+            content_signer: None,
+        })
+    }
+
+    /// The EC's own keys, unless they are held by a remote signer
+    pub fn local_keys(&self) -> Option<&Keys> {
+        self.keys.as_ref()
+    }
+
+    /// Signs the content of election events with the content key of `signer`
+    pub fn with_content_signer(mut self, signer: Arc<ContentSigner>) -> Self {
+        self.content_signer = Some(signer);
+        self
+    }
+
+    /// Content of the election event as published: canonical JSON, with the
+    /// RSA signature of the content if there is a content signer. If the
+    /// signer fails the event is published without it.
+    pub fn election_content(&self, mut event: ElectionEvent) -> String {
+        if let Some(signer) = &self.content_signer {
+            match signer.sign(event.signed_content().as_bytes()) {
+                Ok(sig) => event.content_sig = Some(general_purpose::STANDARD.encode(&sig.0)),
+                Err(e) => log::warn!("Failed to sign the content of election {}: {}", event.id, e),
+            }
+        }
+        event.as_canonical_json()
+    }
+}
+
+/// Logs the URL given by the remote signer to approve a request.
+#[derive(Debug, Clone)]
+struct AuthUrl;
+
+impl AuthUrlHandler for AuthUrl {
+    fn on_auth_url(&self, auth_url: Url) -> BoxedFuture<'_, Result<(), Box<dyn std::error::Error>>> {
+        Box::pin(async move {
+            log::warn!("Approve the remote signer request at {}", auth_url);
+            println!("Approve the remote signer request at {}", auth_url);
+            Ok(())
+        })
+    }
+}
+
+/// Reads the keys the EC uses to talk to its remote signer, creating them
+/// the first time.
+fn load_app_keys(path: &Path) -> Result<Keys> {
+    if let Ok(secret) = fs::read_to_string(path) {
+        return Keys::parse(secret.trim()).with_context(|| format!("Invalid keys in {}", path.display()));
+    }
+    let keys = Keys::generate();
+    write_private_file(path, keys.secret_key().to_secret_hex().as_bytes())?;
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_identity() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(NOSTR_CONNECT_KEY_FILE);
+        let created = load_app_keys(&path).unwrap();
+        assert_eq!(load_app_keys(&path).unwrap().public_key(), created.public_key());
+
+        let local = EcIdentity::local(created.clone());
+        assert_eq!(local.public_key, created.public_key());
+        assert_eq!(local.signer.get_public_key().await.unwrap(), created.public_key());
+        assert!(local.local_keys().is_some());
+
+        // Election content is signed with the content key once there is a signer
+        let event = crate::election::Election::new("Test".to_string(), Vec::new(), 1_000, 3_600, "key".to_string()).to_event();
+        assert!(ElectionEvent::from_json(&local.election_content(event.clone())).unwrap().content_sig.is_none());
+        let content_signer = Arc::new(ContentSigner::load(dir.path()).unwrap());
+        let signed = local.clone().with_content_signer(content_signer.clone());
+        let published = signed.election_content(event);
+        assert!(ElectionEvent::content_sig_matches(&published, content_signer.public_key()));
+
+        assert!(EcIdentity::remote("nsec1abc", dir.path(), None).await.is_err());
+        let nostrconnect = format!("nostrconnect://{}?relay=wss://relay.nsec.app&metadata=%7B%22name%22%3A%22ec%22%7D", created.public_key());
+        assert!(EcIdentity::remote(&nostrconnect, dir.path(), None).await.is_err());
+    }
+}
@@ -0,0 +1,409 @@
+//! Electoral Commission of Criptocracia: election lifecycle, blind token
+//! issuance, vote verification and tallying, behind Nostr relays. The `ec`
+//! binary runs it as a daemon; a service embedding it builds the same pieces:
+//!
+//! - [`Database`] keeps the elections, voter rolls, used tokens and ballots,
+//!   restored with [`load_elections_from_database`] into [`Elections`]
+//! - [`Election`] checks voters and tokens and counts the votes
+//! - [`MessageHandler`] is the message-processing pipeline: it opens the gift
+//!   wrapped messages of the voters, issues tokens with a [`BlindSigner`],
+//!   verifies and records the votes, answers the voters and publishes the
+//!   results through a [`RelayManager`]
+//! - [`update_election_statuses`] opens and closes the elections on time
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use criptocracia_ec::{
+//!     Database, EcIdentity, Elections, LocalSigner, MessageHandler, RelayManager, load_elections_from_database,
+//!     util::load_keys,
+//! };
+//! use nostr_sdk::prelude::*;
+//! use std::sync::Arc;
+//!
+//! let db = Arc::new(Database::new("ec.db").await?);
+//! let elections = Arc::new(Elections::new(load_elections_from_database(&db).await?));
+//! let (pk, sk) = load_keys("ec_private.pem", "ec_public.pem")?;
+//! let identity = EcIdentity::local(Keys::generate());
+//! let client = Client::builder().signer(identity.signer.clone()).build();
+//! client.add_relay("wss://relay.example.com").await?;
+//! client.connect().await;
+//! let relays = Arc::new(RelayManager::new(client.clone(), Arc::clone(&db)));
+//! let handler = MessageHandler::new(relays, identity, db, elections, Arc::new(LocalSigner::new(pk, sk)));
+//! // Then hand the events of the EC's subscription to `handler.handle_event`
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod backup;
+pub mod database;
+pub mod election;
+pub mod elections;
+pub mod grpc;
+pub mod handler;
+pub mod identity;
+pub mod keystore;
+pub mod local_relay;
+pub mod logging;
+#[cfg(unix)]
+pub mod pkcs11;
+pub mod reconcile;
+pub mod relays;
+pub mod replication;
+pub mod results_page;
+pub mod settings;
+pub mod signer;
+pub mod simulate;
+pub mod systemd;
+pub mod timestamp;
+pub mod trustees;
+pub mod types;
+pub mod util;
+pub mod verifier;
+pub mod voter_import;
+pub mod workers;
+
+pub use database::{Database, IntegrityReport};
+pub use election::{Election, Status};
+pub use elections::Elections;
+pub use handler::MessageHandler;
+pub use identity::EcIdentity;
+pub use relays::{EventConfig, RelayManager};
+pub use signer::{BlindSigner, LocalSigner};
+
+use anyhow::Result;
+use criptocracia_protocol::{EcDescriptor, Turnout};
+use criptocracia_protocol::descriptor::{DESCRIPTOR_EVENT_ID, DESCRIPTOR_EVENT_KIND};
+use criptocracia_protocol::election::{KINDS_EVENT_ID, KINDS_EVENT_KIND};
+use criptocracia_protocol::message::DM_EVENT_KIND;
+use nostr_sdk::prelude::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// NIP-59 backdates gift wraps up to two days, so the backfill looks that much
+/// further back than the last processed message.
+const GIFT_WRAP_WINDOW: u64 = 2 * 24 * 60 * 60;
+
+/// Time given to the relays to connect and answer the backfill.
+pub const BACKFILL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Report database integrity anomalies, optionally deleting orphan rows
+pub async fn check_database_integrity(db: &Database, repair: bool) -> Result<IntegrityReport> {
+    let report = db.check_integrity().await?;
+
+    for (table, count) in &report.orphan_rows {
+        log::warn!("Integrity check: {} orphan row(s) in {}", count, table);
+    }
+    for mismatch in &report.vote_mismatches {
+        log::warn!(
+            "Integrity check: election {} counts {} vote(s) but has {} used token(s)",
+            mismatch.election_id,
+            mismatch.counted_votes,
+            mismatch.used_tokens
+        );
+    }
+
+    if repair && report.orphan_total() > 0 {
+        let removed = db.repair_orphan_rows().await?;
+        log::info!("Integrity check: removed {} orphan row(s)", removed);
+    }
+
+    Ok(report)
+}
+
+/// Purge the data of finished elections whose retention period has expired
+pub async fn purge_expired_election_data(
+    db: &Database,
+    elections: &Elections,
+    retention_days: u64,
+) -> Result<()> {
+    let current_time = chrono::Utc::now().timestamp() as u64;
+    let mut expired = Vec::new();
+    for election in elections.all() {
+        let election = election.read().await;
+        if election.retention_expired(current_time, retention_days) {
+            expired.push(election.id.clone());
+        }
+    }
+
+    for election_id in expired {
+        let stats = db.purge_election_data(&election_id).await?;
+        if stats.total() > 0 {
+            log::info!(
+                "Retention period expired for election {}, purged: {:?}",
+                election_id,
+                stats
+            );
+        }
+        if let Some(election) = elections.get(&election_id) {
+            election.write().await.clear_voter_data();
+        }
+    }
+
+    let cutoff = current_time.saturating_sub(retention_days * 24 * 60 * 60) as i64;
+    let removed = db.purge_unbound_message_log(cutoff).await?;
+    if removed > 0 {
+        log::info!("Purged {} expired message log entries", removed);
+    }
+
+    Ok(())
+}
+
+/// Load elections from database and restore their state
+pub async fn load_elections_from_database(db: &Database) -> Result<Vec<Election>> {
+    let election_records = db.load_all_elections().await?;
+    let mut elections = Vec::new();
+
+    for election_record in election_records {
+        // Load candidates for this election
+        let candidate_records = db.get_candidates(&election_record.id).await?;
+
+        // Load authorized voters for this election
+        let authorized_voters = db.load_election_voters(&election_record.id).await?;
+
+        // Load used tokens for this election
+        let used_tokens = db.load_used_tokens(&election_record.id).await?;
+
+        // Restore the election from database records
+        let mut election = Election::from_database(
+            election_record,
+            candidate_records,
+            authorized_voters,
+            used_tokens,
+        );
+        election.roll = db.load_election_roll(&election.id).await?.into_iter().collect();
+
+        // Report any state left inconsistent by an interrupted write
+        for issue in election.consistency_issues() {
+            log::warn!("Election {} is inconsistent: {}", election.id, issue);
+        }
+
+        log::info!("Loaded election: {} (ID: {})", election.name, election.id);
+        elections.push(election);
+    }
+
+    Ok(elections)
+}
+
+/// Update the status of the elections that started or ended since the last
+/// check, then save and publish them
+pub async fn update_election_statuses(
+    elections: &Elections,
+    db: &Database,
+    relays: &RelayManager,
+    identity: &EcIdentity,
+) {
+    let current_time = chrono::Utc::now().timestamp() as u64;
+    let mut elections_to_update = Vec::new();
+
+    // Check and update election statuses
+    for election in elections.all() {
+        let mut election = election.write().await;
+        if election.update_status_based_on_time(current_time) {
+            log::info!(
+                "Election {} status changed to {:?}",
+                election.id,
+                election.status
+            );
+            elections_to_update.push(election.clone());
+        }
+    }
+
+    // Persist status changes and publish to Nostr
+    for election in elections_to_update {
+        // Save to database
+        if let Err(e) = db.upsert_election(&election).await {
+            log::error!(
+                "Failed to update election {} in database: {}",
+                election.id,
+                e
+            );
+        }
+
+        // Publish to Nostr
+        if let Err(e) = publish_election_event(relays, identity, &election, db).await {
+            log::error!(
+                "Failed to publish election {} status update to Nostr: {}",
+                election.id,
+                e
+            );
+        }
+    }
+}
+
+/// Publish the state of the election
+pub async fn publish_election_event(
+    relays: &RelayManager,
+    identity: &EcIdentity,
+    election: &Election,
+    db: &Database,
+) -> Result<()> {
+    log::info!(
+        "Publishing election {} status: {:?}",
+        election.id,
+        election.status
+    );
+    // Old election events are expired after the configured days (15 by default)
+    let events = relays.events();
+    let event = EventBuilder::new(Kind::Custom(events.kinds.election), identity.election_content(election.to_event()))
+        .tag(Tag::identifier(election.id.to_string()))
+        .tag(EventConfig::expiration(events.election_ttl_days))
+        .tags(events.rehearsal_tag())
+        .sign(&identity.signer)
+        .await?;
+
+    let accepted = relays.send_event(&event).await?;
+    log::info!(
+        "Event with election {} status {:?} broadcast to Nostr relays!",
+        election.id,
+        election.status
+    );
+
+    // Save election to database
+    db.upsert_election(election).await?;
+    log::info!("Election {} saved to database", election.id);
+
+    // Keep track of the relays holding this announcement
+    db.save_published_event(&event.id.to_hex(), &election.id, events.kinds.election, &accepted)
+        .await?;
+
+    Ok(())
+}
+
+/// Publish the turnout of an election
+pub async fn publish_turnout(relays: &RelayManager, identity: &EcIdentity, turnout: &Turnout, db: &Database) -> Result<()> {
+    // Turnout is as short-lived as the results
+    let events = relays.events();
+    let election = Coordinate::new(Kind::Custom(events.kinds.election), identity.public_key)
+        .identifier(&turnout.election_id);
+    let event = EventBuilder::new(Kind::Custom(events.kinds.turnout), turnout.as_json())
+        .tag(Tag::identifier(turnout.election_id.to_string()))
+        .tag(Tag::coordinate(election, None))
+        .tag(EventConfig::expiration(events.results_ttl_days))
+        .tags(events.rehearsal_tag())
+        .sign(&identity.signer)
+        .await?;
+
+    let accepted = relays.send_event(&event).await?;
+    log::info!("Turnout of election {} broadcast: {}%", turnout.election_id, turnout.turnout);
+    db.save_published_event(&event.id.to_hex(), &turnout.election_id, events.kinds.turnout, &accepted)
+        .await?;
+    Ok(())
+}
+
+/// Publish the turnout of the elections in progress that changed since it
+/// was last published, as counted in the database. `published` holds the
+/// last turnout published of each election; elections that finish get
+/// their final turnout published once.
+pub async fn publish_turnouts(
+    elections: &Elections,
+    db: &Database,
+    relays: &RelayManager,
+    identity: &EcIdentity,
+    published: &mut HashMap<String, Turnout>,
+) {
+    for election in elections.all() {
+        let (id, status) = {
+            let election = election.read().await;
+            (election.id.clone(), election.status)
+        };
+        let finished = status == Status::Finished;
+        if status != Status::InProgress && !(finished && published.contains_key(&id)) {
+            continue;
+        }
+        let turnout = match db.get_turnout(&id).await {
+            Ok(turnout) => turnout,
+            Err(e) => {
+                log::error!("Failed to count the turnout of election {}: {}", id, e);
+                continue;
+            }
+        };
+        if published.get(&id) != Some(&turnout) {
+            if let Err(e) = publish_turnout(relays, identity, &turnout, db).await {
+                log::error!("Failed to publish the turnout of election {}: {}", id, e);
+                continue;
+            }
+        }
+        if finished {
+            published.remove(&id);
+        } else {
+            published.insert(id, turnout);
+        }
+    }
+}
+
+/// Advertise the kinds of the EC's events in a NIP-89 handler information
+/// event, so clients find them when they are not the default ones.
+pub async fn publish_event_kinds(relays: &RelayManager, identity: &EcIdentity) -> Result<()> {
+    let kinds = relays.events().kinds;
+    let event = EventBuilder::new(Kind::Custom(KINDS_EVENT_KIND), kinds.as_json())
+        .tag(Tag::identifier(KINDS_EVENT_ID))
+        .tags(kinds.all().map(|kind| Tag::custom(TagKind::k(), [kind.to_string()])))
+        .tags(relays.events().rehearsal_tag())
+        .sign(&identity.signer)
+        .await?;
+    let accepted = relays.send_event(&event).await?;
+    log::info!("Event kinds {} advertised to {} relay(s)", kinds.as_json(), accepted.len());
+    Ok(())
+}
+
+/// Kinds of the messages the EC takes: gift wraps, and direct messages if enabled.
+pub fn message_kinds(direct_messages: bool) -> Vec<Kind> {
+    let mut kinds = vec![Kind::GiftWrap];
+    if direct_messages {
+        kinds.push(Kind::from(DM_EVENT_KIND));
+    }
+    kinds
+}
+
+/// Publish the EC's NIP-01 profile and its descriptor, from which clients
+/// get its RSA public key and protocol versions.
+pub async fn publish_profile(
+    relays: &RelayManager,
+    identity: &EcIdentity,
+    metadata: &Metadata,
+    descriptor: &EcDescriptor,
+) -> Result<()> {
+    let rehearsal = relays.events().rehearsal_tag();
+    let profile = EventBuilder::metadata(metadata).tags(rehearsal.clone()).sign(&identity.signer).await?;
+    relays.send_event(&profile).await?;
+    let event = EventBuilder::new(Kind::Custom(DESCRIPTOR_EVENT_KIND), descriptor.as_json())
+        .tag(Tag::identifier(DESCRIPTOR_EVENT_ID))
+        .tags(rehearsal)
+        .sign(&identity.signer)
+        .await?;
+    let accepted = relays.send_event(&event).await?;
+    log::info!(
+        "Descriptor with RSA key {} published to {} relay(s)",
+        descriptor.fingerprint().unwrap_or_default(),
+        accepted.len()
+    );
+    Ok(())
+}
+
+/// Process, oldest first, the messages sent to the EC while it was down.
+/// Nothing is fetched the first time the EC runs.
+pub async fn backfill_messages(
+    client: &Client,
+    handler: &MessageHandler,
+    pubkey: PublicKey,
+    kinds: Vec<Kind>,
+    db: &Database,
+) -> Result<()> {
+    let Some(last_processed_at) = db.get_last_processed_at().await? else {
+        log::info!("No message processed yet, nothing to backfill");
+        return Ok(());
+    };
+    let since = Timestamp::from((last_processed_at as u64).saturating_sub(GIFT_WRAP_WINDOW));
+    let filter = Filter::new().pubkey(pubkey).kinds(kinds).since(since);
+
+    client.wait_for_connection(BACKFILL_TIMEOUT).await;
+    let mut events: Vec<Event> = client.fetch_events(filter, BACKFILL_TIMEOUT).await?.into_iter().collect();
+    events.sort_by_key(|event| (event.created_at, event.id));
+    log::info!("Backfilling {} message(s) since {}", events.len(), since);
+
+    // Those already processed are skipped by the replay protection
+    for event in &events {
+        handler.handle_event(event).await;
+    }
+    Ok(())
+}
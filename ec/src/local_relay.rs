@@ -0,0 +1,260 @@
+/*! local_relay.rs — Embedded Nostr relay for LAN-only elections
+A minimal NIP-01 relay the EC can run for elections without internet access,
+e.g. in a community center: the EC and the voters on the local network all
+connect to it. Events are kept in memory and in the EC's database, so they
+are served again after a restart. */
+
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use nostr_sdk::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use crate::database::Database;
+
+/// Most stored events sent to a subscription before its EOSE.
+const MAX_STORED_EVENTS: usize = 5_000;
+
+/// Most open subscriptions of a connection.
+const MAX_SUBSCRIPTIONS: usize = 20;
+
+/// New events buffered for the connections; a slow one misses the oldest.
+const BROADCAST_CAPACITY: usize = 1_024;
+
+/// Relay serving the events published to it over WebSocket.
+pub struct LocalRelay {
+    events: MemoryDatabase,
+    db: Arc<Database>,
+    new_events: broadcast::Sender<Event>,
+}
+
+impl LocalRelay {
+    /// Relay holding the events stored by previous runs.
+    pub async fn new(db: Arc<Database>) -> Result<Self> {
+        let events = MemoryDatabase::with_opts(MemoryDatabaseOptions { events: true, max_events: None });
+        let stored = db.load_relay_events().await?;
+        for json in &stored {
+            match Event::from_json(json) {
+                Ok(event) => {
+                    events.save_event(&event).await?;
+                }
+                Err(e) => log::warn!("Skipping invalid local relay event: {}", e),
+            }
+        }
+        log::info!("Local relay loaded {} stored event(s)", stored.len());
+        let (new_events, _) = broadcast::channel(BROADCAST_CAPACITY);
+        Ok(Self { events, db, new_events })
+    }
+
+    /// Accepts connections for as long as it runs.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let relay = Arc::clone(&self);
+                    tokio::spawn(async move {
+                        if let Err(e) = relay.handle_connection(stream).await {
+                            log::debug!("Local relay connection from {} closed: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => log::warn!("Local relay failed to accept a connection: {}", e),
+            }
+        }
+    }
+
+    async fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        let (mut sink, mut stream) = tokio_tungstenite::accept_async(stream).await?.split();
+        let mut subscriptions: HashMap<SubscriptionId, Filter> = HashMap::new();
+        let mut new_events = self.new_events.subscribe();
+        loop {
+            tokio::select! {
+                message = stream.next() => {
+                    let text = match message {
+                        Some(Ok(WsMessage::Text(text))) => text,
+                        Some(Ok(WsMessage::Close(_))) | None => return Ok(()),
+                        // Pings are answered by tungstenite
+                        Some(Ok(_)) => continue,
+                        Some(Err(e)) => return Err(e.into()),
+                    };
+                    for reply in self.handle_message(&text, &mut subscriptions).await {
+                        sink.send(WsMessage::Text(reply.as_json().into())).await?;
+                    }
+                }
+                event = new_events.recv() => {
+                    let event = match event {
+                        Ok(event) => event,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            log::warn!("Local relay connection missed {} new event(s)", missed);
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                    };
+                    for (id, filter) in &subscriptions {
+                        if filter.match_event(&event) {
+                            let reply = RelayMessage::event(id.clone(), event.clone());
+                            sink.send(WsMessage::Text(reply.as_json().into())).await?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Answers a client message, updating the subscriptions of its connection.
+    async fn handle_message(
+        &self,
+        text: &str,
+        subscriptions: &mut HashMap<SubscriptionId, Filter>,
+    ) -> Vec<RelayMessage<'static>> {
+        let message = match ClientMessage::from_json(text) {
+            Ok(message) => message,
+            Err(e) => return vec![RelayMessage::notice(format!("invalid: {}", e))],
+        };
+        match message {
+            ClientMessage::Event(event) => vec![self.save(event.into_owned()).await],
+            ClientMessage::Req { subscription_id, filter } => {
+                let subscription_id = subscription_id.into_owned();
+                if subscriptions.len() >= MAX_SUBSCRIPTIONS && !subscriptions.contains_key(&subscription_id) {
+                    return vec![RelayMessage::closed(subscription_id, "error: too many subscriptions")];
+                }
+                let filter = filter.into_owned();
+                let stored = match self.events.query(filter.clone()).await {
+                    Ok(events) => events,
+                    Err(e) => return vec![RelayMessage::closed(subscription_id, format!("error: {}", e))],
+                };
+                let mut replies: Vec<RelayMessage> = stored
+                    .into_iter()
+                    .take(MAX_STORED_EVENTS)
+                    .map(|event| RelayMessage::event(subscription_id.clone(), event))
+                    .collect();
+                replies.push(RelayMessage::eose(subscription_id.clone()));
+                subscriptions.insert(subscription_id, filter);
+                replies
+            }
+            ClientMessage::Close(subscription_id) => {
+                subscriptions.remove(&subscription_id);
+                Vec::new()
+            }
+            _ => vec![RelayMessage::notice("unsupported: message not supported by this relay")],
+        }
+    }
+
+    /// Stores a valid event and sends it to the open subscriptions.
+    /// Ephemeral events are only sent.
+    async fn save(&self, event: Event) -> RelayMessage<'static> {
+        if event.verify().is_err() {
+            return RelayMessage::ok(event.id, false, "invalid: bad signature");
+        }
+        match self.events.save_event(&event).await {
+            Ok(SaveEventStatus::Success) => {
+                let stored = self
+                    .db
+                    .save_relay_event(&event.id.to_hex(), &event.as_json(), event.created_at.as_u64() as i64)
+                    .await;
+                if let Err(e) = stored {
+                    log::error!("Failed to store local relay event {}: {}", event.id, e);
+                }
+            }
+            Ok(SaveEventStatus::Rejected(RejectedReason::Ephemeral)) => {}
+            Ok(SaveEventStatus::Rejected(RejectedReason::Duplicate)) => {
+                return RelayMessage::ok(event.id, true, "duplicate: already have this event");
+            }
+            Ok(SaveEventStatus::Rejected(reason)) => {
+                return RelayMessage::ok(event.id, false, format!("blocked: {:?}", reason));
+            }
+            Err(e) => return RelayMessage::ok(event.id, false, format!("error: {}", e)),
+        }
+        // Nobody listening is not an error
+        let _ = self.new_events.send(event.clone());
+        RelayMessage::ok(event.id, true, "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn create_test_relay() -> (LocalRelay, Arc<Database>, tempfile::NamedTempFile) {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db = Arc::new(Database::new(temp_file.path()).await.unwrap());
+        (LocalRelay::new(db.clone()).await.unwrap(), db, temp_file)
+    }
+
+    #[tokio::test]
+    async fn test_stores_and_serves_events() {
+        let (relay, db, _temp_file) = create_test_relay().await;
+        let keys = Keys::generate();
+        let mut subscriptions = HashMap::new();
+
+        let event = EventBuilder::text_note("hello").sign_with_keys(&keys).unwrap();
+        let replies = relay.handle_message(&ClientMessage::event(event.clone()).as_json(), &mut subscriptions).await;
+        assert_eq!(replies, vec![RelayMessage::ok(event.id, true, "")]);
+        let replies = relay.handle_message(&ClientMessage::event(event.clone()).as_json(), &mut subscriptions).await;
+        assert!(matches!(&replies[0], RelayMessage::Ok { status: true, message, .. } if message.starts_with("duplicate")));
+
+        let req = ClientMessage::req(SubscriptionId::new("sub"), Filter::new().author(keys.public_key()));
+        let replies = relay.handle_message(&req.as_json(), &mut subscriptions).await;
+        assert_eq!(
+            replies,
+            vec![
+                RelayMessage::event(SubscriptionId::new("sub"), event.clone()),
+                RelayMessage::eose(SubscriptionId::new("sub")),
+            ]
+        );
+        assert_eq!(subscriptions.len(), 1);
+        relay.handle_message(&ClientMessage::close(SubscriptionId::new("sub")).as_json(), &mut subscriptions).await;
+        assert!(subscriptions.is_empty());
+
+        // A restarted relay serves the stored events
+        let restarted = LocalRelay::new(db).await.unwrap();
+        assert_eq!(restarted.events.query(Filter::new()).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_serves_nostr_clients() {
+        let (relay, _db, _temp_file) = create_test_relay().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(Arc::new(relay).serve(listener));
+
+        let keys = Keys::generate();
+        let client = Client::new(keys.clone());
+        client.add_relay(&url).await.unwrap();
+        client.connect().await;
+        client.wait_for_connection(Duration::from_secs(5)).await;
+
+        let output = client.send_event_builder(EventBuilder::text_note("offline")).await.unwrap();
+        assert_eq!(output.success.len(), 1);
+        let events = client
+            .fetch_events(Filter::new().author(keys.public_key()), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(events.first().unwrap().content, "offline");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_invalid_messages() {
+        let (relay, _db, _temp_file) = create_test_relay().await;
+        let mut subscriptions = HashMap::new();
+
+        let replies = relay.handle_message("not json", &mut subscriptions).await;
+        assert!(matches!(&replies[0], RelayMessage::Notice(_)));
+
+        let event = EventBuilder::text_note("hello").sign_with_keys(&Keys::generate()).unwrap();
+        let mut json: serde_json::Value = serde_json::from_str(&ClientMessage::event(event).as_json()).unwrap();
+        json[1]["content"] = "tampered".into();
+        let replies = relay.handle_message(&json.to_string(), &mut subscriptions).await;
+        assert!(matches!(&replies[0], RelayMessage::Ok { status: false, .. }));
+
+        for i in 0..=MAX_SUBSCRIPTIONS {
+            let req = ClientMessage::req(SubscriptionId::new(i.to_string()), Filter::new());
+            relay.handle_message(&req.as_json(), &mut subscriptions).await;
+        }
+        assert_eq!(subscriptions.len(), MAX_SUBSCRIPTIONS);
+    }
+}
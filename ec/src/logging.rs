@@ -0,0 +1,180 @@
+/*! logging.rs — Log output of the EC
+Plain text lines, or one JSON object per line for Loki or ELK (`log_format =
+"json"`). Log statements attach structured fields with the `log` key-value
+syntax, `election_id`, `event_id` and `voter` mainly, which are written as
+JSON fields or appended as `key=value`. Voters are logged by `voter_hash`,
+never by public key. Levels can be set per module (`[log_levels]`), and
+changed while the EC runs with `set_levels`. The log
+file is the protocol crate's `RotatingFile`, shared with the voter TUI,
+rotated by size or age (`[log_rotation]`), keeping the newest few. */
+
+use chrono::Local;
+use criptocracia_protocol::log_file::RotatingFile;
+use fern::Dispatch;
+use log::kv::{self, VisitSource};
+use nostr_sdk::prelude::PublicKey;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::sync::{PoisonError, RwLock};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Level of every module, and those of the modules logged at their own
+/// level, as set by `set_levels`.
+static LEVELS: RwLock<(log::LevelFilter, Vec<(String, log::LevelFilter)>)> =
+    RwLock::new((log::LevelFilter::Info, Vec::new()));
+
+/// Short, stable pseudonym of a voter for the logs: the first 8 bytes of
+/// the SHA-256 of their public key, in hex.
+pub fn voter_hash(pubkey: &PublicKey) -> String {
+    nostr_sdk::util::hex::encode(&Sha256::digest(pubkey.to_bytes())[..8])
+}
+
+/// Initialize the logger, writing to `log_file` at `level`, or at the
+/// level given for a module in `modules`.
+pub fn setup_logger(
+    level: log::LevelFilter,
+    modules: &[(String, log::LevelFilter)],
+    format: LogFormat,
+    log_file: RotatingFile,
+) -> Result<(), fern::InitError> {
+    Dispatch::new()
+        .format(move |out, message, record| {
+            out.finish(format_args!("{}", format_record(format, message, record)))
+        })
+        .filter(enabled)
+        .chain(Box::new(log_file) as Box<dyn Write + Send>)
+        .apply()?;
+    set_levels(level, modules);
+    Ok(())
+}
+
+/// Changes the levels the logger writes at.
+pub fn set_levels(level: log::LevelFilter, modules: &[(String, log::LevelFilter)]) {
+    let max = modules.iter().map(|(_, level)| *level).fold(level, Ord::max);
+    *LEVELS.write().unwrap_or_else(PoisonError::into_inner) = (level, modules.to_vec());
+    log::set_max_level(max);
+}
+
+/// Whether a record is at or above the level of its module, the longest
+/// module given matching its target.
+fn enabled(metadata: &log::Metadata) -> bool {
+    let levels = LEVELS.read().unwrap_or_else(PoisonError::into_inner);
+    let target = metadata.target();
+    let level = levels
+        .1
+        .iter()
+        .filter(|(module, _)| {
+            target.strip_prefix(module.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        })
+        .max_by_key(|(module, _)| module.len())
+        .map_or(levels.0, |(_, level)| *level);
+    metadata.level() <= level
+}
+
+/// One line of the log for `record`
+fn format_record(format: LogFormat, message: &std::fmt::Arguments, record: &log::Record) -> String {
+    let mut fields = Fields(Vec::new());
+    let _ = record.key_values().visit(&mut fields);
+    let timestamp = Local::now();
+    match format {
+        LogFormat::Text => {
+            let mut line = format!("[{}] [{}] - {}", timestamp.format("%Y-%m-%d %H:%M:%S"), record.level(), message);
+            for (key, value) in fields.0 {
+                match value {
+                    Value::String(value) => write!(line, " {}={}", key, value),
+                    value => write!(line, " {}={}", key, value),
+                }
+                .expect("writing to a String");
+            }
+            line
+        }
+        LogFormat::Json => {
+            let mut line = Map::new();
+            line.insert("timestamp".into(), timestamp.to_rfc3339().into());
+            line.insert("level".into(), record.level().as_str().into());
+            line.insert("target".into(), record.target().into());
+            line.insert("message".into(), message.to_string().into());
+            line.extend(fields.0);
+            Value::Object(line).to_string()
+        }
+    }
+}
+
+/// Collects the key-value pairs of a record as JSON values, in order.
+struct Fields(Vec<(String, Value)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            Value::from(n)
+        } else if let Some(n) = value.to_i64() {
+            Value::from(n)
+        } else if let Some(b) = value.to_bool() {
+            Value::from(b)
+        } else {
+            Value::from(value.to_string())
+        };
+        self.0.push((key.as_str().to_string(), value));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::prelude::Keys;
+
+    #[test]
+    fn test_format_record() {
+        let voter = voter_hash(&Keys::generate().public_key());
+        assert_eq!(voter.len(), 16);
+        let fields: [(&str, kv::Value); 3] =
+            [("election_id", "e1".into()), ("voter", voter.as_str().into()), ("choices", 2u64.into())];
+        let message = format_args!("Vote accepted");
+        let record = log::Record::builder()
+            .level(log::Level::Info)
+            .target("ec::handler")
+            .args(message)
+            .key_values(&fields)
+            .build();
+
+        let text = format_record(LogFormat::Text, &message, &record);
+        assert!(text.ends_with(&format!("[INFO] - Vote accepted election_id=e1 voter={} choices=2", voter)));
+
+        let json: Value = serde_json::from_str(&format_record(LogFormat::Json, &message, &record)).unwrap();
+        assert_eq!(json["message"], "Vote accepted");
+        assert_eq!(json["target"], "ec::handler");
+        assert_eq!(json["election_id"], "e1");
+        assert_eq!(json["voter"], voter);
+        assert_eq!(json["choices"], 2);
+    }
+
+    #[test]
+    fn test_levels() {
+        let metadata = |level, target| log::Metadata::builder().level(level).target(target).build();
+        let modules = [("ec::handler".to_string(), log::LevelFilter::Debug), ("sqlx".to_string(), log::LevelFilter::Error)];
+        set_levels(log::LevelFilter::Info, &modules);
+        assert_eq!(log::max_level(), log::LevelFilter::Debug);
+        assert!(enabled(&metadata(log::Level::Debug, "ec::handler")));
+        assert!(!enabled(&metadata(log::Level::Debug, "ec::handlers")));
+        assert!(!enabled(&metadata(log::Level::Debug, "ec::relays")));
+        assert!(!enabled(&metadata(log::Level::Warn, "sqlx::query")));
+        assert!(enabled(&metadata(log::Level::Info, "ec")));
+
+        // Changed while running
+        set_levels(log::LevelFilter::Warn, &[]);
+        assert!(!enabled(&metadata(log::Level::Info, "ec")));
+        assert!(!enabled(&metadata(log::Level::Debug, "ec::handler")));
+        assert!(enabled(&metadata(log::Level::Warn, "sqlx::query")));
+    }
+}
@@ -1,108 +1,552 @@
-mod database;
-mod election;
-mod grpc;
-mod types;
-mod util;
-
-use crate::database::Database;
-use crate::election::Election;
-use crate::grpc::server::GrpcServer;
-use crate::util::{load_keys, load_keys_from_pem, setup_logger, validate_required_files};
+use criptocracia_ec::backup::{DATABASE_FILE, REHEARSAL_DATABASE_FILE, RecoveryBundle};
+use criptocracia_ec::database::Database;
+use criptocracia_ec::elections::Elections;
+use criptocracia_ec::grpc::server::GrpcServer;
+use criptocracia_ec::handler::MessageHandler;
+use criptocracia_ec::identity::EcIdentity;
+use criptocracia_ec::local_relay::LocalRelay;
+use criptocracia_ec::logging::{LogFormat, set_levels, setup_logger};
+use criptocracia_ec::relays::{EventConfig, RelayManager};
+use criptocracia_ec::results_page::ResultsPage;
+use criptocracia_ec::settings::Settings;
+use criptocracia_ec::signer::{BlindSigner, ContentSigner, LocalSigner};
+use criptocracia_ec::timestamp::Timestamper;
+use criptocracia_ec::trustees::{TrusteeSet, Trustees};
+use criptocracia_ec::util::{
+    DirLock, generate_keys, key_fingerprint, load_keys, load_keys_from_pem, load_public_key, local_relay_url, parse_key_size,
+    parse_relays, validate_required_files,
+};
+use criptocracia_ec::verifier::BatchVerifier;
+use criptocracia_ec::workers::WorkerPool;
 
 use anyhow::Result;
+use criptocracia_ec::{
+    BACKFILL_TIMEOUT, backfill_messages, check_database_integrity, keystore, load_elections_from_database, message_kinds,
+    publish_election_event, publish_event_kinds, publish_profile, publish_turnouts, purge_expired_election_data, reconcile, replication,
+    simulate, systemd, trustees, update_election_statuses, util, voter_import,
+};
+use criptocracia_protocol::log_file::{LogRotation, RotatingFile};
+use criptocracia_protocol::{EcDescriptor, EventKinds};
 use base64::{Engine as _, engine::general_purpose};
-use blind_rsa_signatures::{BlindedMessage, MessageRandomizer, Options, Signature as RSASignature};
-use clap::Parser;
-use election::BlindTokenRequest;
+use clap::{Parser, Subcommand};
+use futures_util::FutureExt;
 use nostr_sdk::prelude::*;
-use num_bigint_dig::BigUint;
-use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::{collections::HashMap, fs, net::SocketAddr, path::PathBuf, sync::Arc};
 use tokio::{
-    sync::{Mutex, mpsc},
+    sync::{broadcast, mpsc, watch},
+    task::JoinSet,
     time::Duration,
 };
-use types::{Candidate, Message};
 
-#[derive(Parser, Debug)]
+/// Time the running tasks are given to stop on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Relay used when none is configured
+const DEFAULT_RELAY: &str = "wss://relay.mostro.network";
+
+/// Seconds between checks for closed elections whose final results are
+/// not published yet, when every vote is published as it comes.
+const RESULTS_CHECK_INTERVAL: u64 = 30;
+
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Directory to store application data and keys
     #[arg(short, long, default_value = "")]
     dir: String,
-}
 
-/// Load elections from database and restore their state
-async fn load_elections_from_database(db: &Database) -> Result<Vec<Election>> {
-    let election_records = db.load_all_elections().await?;
-    let mut elections = Vec::new();
+    /// Generate the RSA keypair in the directory if it has none, with 2048 (default) or 4096 bits
+    #[arg(long, value_name = "BITS", num_args = 0..=1, default_missing_value = "2048", value_parser = parse_key_size)]
+    generate_keys: Option<usize>,
 
-    for election_record in election_records {
-        // Load candidates for this election
-        let candidate_records = db.get_candidates(&election_record.id).await?;
+    /// Encrypt ec_private.pem and the Nostr key with a passphrase (from the
+    /// ec-key-passphrase systemd credential, EC_KEY_PASSPHRASE or a prompt) and exit
+    #[arg(long)]
+    encrypt_keys: bool,
 
-        // Load authorized voters for this election
-        let authorized_voters = db.load_election_voters(&election_record.id).await?;
+    /// PKCS#11 module of an HSM or YubiKey holding the RSA private key, which then
+    /// signs the tokens in place of ec_private.pem
+    #[arg(long, value_name = "PATH", env = "EC_PKCS11_MODULE")]
+    pkcs11_module: Option<PathBuf>,
 
-        // Load used tokens for this election
-        let used_tokens = db.load_used_tokens(&election_record.id).await?;
+    /// PKCS#11 slot of the token (default: the first slot with a token)
+    #[arg(long, value_name = "SLOT", env = "EC_PKCS11_SLOT", requires = "pkcs11_module")]
+    pkcs11_slot: Option<u64>,
 
-        // Restore the election from database records
-        let election = Election::from_database(
-            election_record,
-            candidate_records,
-            authorized_voters,
-            used_tokens,
-        );
+    /// User PIN of the PKCS#11 token
+    #[arg(long, value_name = "PIN", env = "EC_PKCS11_PIN", hide_env_values = true, requires = "pkcs11_module")]
+    pkcs11_pin: Option<String>,
+
+    /// Generate an RSA key (of --generate-keys BITS, default 2048) split into shares
+    /// for N trustees, T of whom sign each token, write them to the directory and exit
+    #[arg(long, value_name = "T/N", value_parser = TrusteeSet::parse)]
+    deal_trustees: Option<TrusteeSet>,
+
+    /// Have the trustees in trustees.json sign the tokens, over the trustee API
+    #[arg(long, conflicts_with = "pkcs11_module")]
+    trustees: bool,
+
+    /// Address the trustee API listens on, with --trustees, apart from the admin API
+    #[arg(long, value_name = "ADDR", env = "EC_TRUSTEE_API", default_value = "127.0.0.1:50003")]
+    trustee_api: SocketAddr,
+
+    /// Run as the trustee holding this share, signing the EC's token requests
+    #[arg(long, value_name = "SHARE_FILE")]
+    trustee: Option<PathBuf>,
+
+    /// Trustee API of the EC, for --trustee
+    #[arg(long, value_name = "URL", env = "EC_TRUSTEE_API_URL", default_value = "http://127.0.0.1:50003")]
+    trustee_api_url: String,
+
+    /// File holding the Nostr private key (nsec, hex or NIP-49 ncryptsec), used when
+    /// NOSTR_PRIVATE_KEY is not set [default: <dir>/nostr_key]; also the nostr_key_file setting
+    #[arg(long, value_name = "PATH")]
+    nostr_key_file: Option<PathBuf>,
+
+    /// NIP-46 remote signer holding the Nostr key (bunker:// URI), so it's never loaded
+    /// into the EC; encrypted tallies need the key itself. Also the bunker setting
+    #[arg(long, value_name = "URI")]
+    bunker: Option<String>,
+
+    /// Relay to publish events to and receive messages from, repeatable, replacing the
+    /// relays setting (wss://relay.mostro.network if none is given and there is no local relay)
+    #[arg(long = "relay", value_name = "URL", value_delimiter = ',')]
+    relays: Vec<String>,
+
+    /// Run a local relay on this address, e.g. 0.0.0.0:7000, for elections without internet access
+    #[arg(long, value_name = "ADDR", env = "EC_LOCAL_RELAY")]
+    local_relay: Option<SocketAddr>,
+
+    /// Serve the elections and their live tallies, read only, as JSON and HTML on this
+    /// address, e.g. 0.0.0.0:8080, for observers without a Nostr client
+    #[arg(long, value_name = "ADDR", env = "EC_HTTP")]
+    http: Option<SocketAddr>,
+
+    /// Add the voters of a JSON file, mapping election IDs to their voters, to the rolls
+    /// of those open elections at startup (see voter_import.rs for the format)
+    #[arg(long, value_name = "FILE")]
+    import_voters: Option<PathBuf>,
+
+    /// SOCKS5 proxy every relay connection goes through, e.g. Tor at 127.0.0.1:9050 (needed for .onion relays)
+    #[arg(long, value_name = "ADDR", env = "EC_PROXY")]
+    proxy: Option<SocketAddr>,
+
+    /// Name shown in the EC's Nostr profile
+    #[arg(long, env = "EC_NAME", default_value = "Criptocracia Electoral Commission")]
+    name: String,
+
+    /// Description shown in the EC's Nostr profile
+    #[arg(long, env = "EC_ABOUT")]
+    about: Option<String>,
+
+    /// How voters reach the people running the EC (email address, npub...), published in its descriptor
+    #[arg(long, env = "EC_CONTACT")]
+    contact: Option<String>,
+
+    /// Kind of the election events
+    #[arg(long, default_value_t = EventKinds::default().election)]
+    election_kind: u16,
+
+    /// Kind of the results events
+    #[arg(long, default_value_t = EventKinds::default().results)]
+    results_kind: u16,
+
+    /// Kind of the ballot events of the bulletin board
+    #[arg(long, default_value_t = EventKinds::default().ballot)]
+    ballot_kind: u16,
+
+    /// Kind of the results delta events
+    #[arg(long, default_value_t = EventKinds::default().delta)]
+    delta_kind: u16,
+
+    /// Kind of the turnout events
+    #[arg(long, default_value_t = EventKinds::default().turnout)]
+    turnout_kind: u16,
+
+    /// Days the relays keep each election event before it expires
+    #[arg(long, default_value_t = EventConfig::default().election_ttl_days)]
+    election_ttl_days: u64,
+
+    /// Days the relays keep each results event before it expires
+    #[arg(long, default_value_t = EventConfig::default().results_ttl_days)]
+    results_ttl_days: u64,
+
+    /// Days the relays keep each ballot event before it expires
+    #[arg(long, default_value_t = EventConfig::default().ballot_ttl_days)]
+    ballot_ttl_days: u64,
+
+    /// Days to keep voter rolls, used tokens and message logs after an election finishes
+    /// (0 disables purging) [default: 0]
+    #[arg(long)]
+    retention_days: Option<u64>,
 
-        log::info!("Loaded election: {} (ID: {})", election.name, election.id);
-        elections.push(election);
+    /// Seconds between results publications, batching the votes in between (0 publishes on
+    /// every accepted vote) [default: 0]
+    #[arg(long)]
+    results_interval: Option<u64>,
+
+    /// Verify vote tokens in parallel batches of up to this many, with at least that many
+    /// message workers (0 verifies each vote as it comes)
+    #[arg(long, value_name = "VOTES", default_value_t = 0)]
+    verify_batch: usize,
+
+    /// Messages handled at once (0 = one per CPU core) [default: 0]
+    #[arg(long)]
+    workers: Option<usize>,
+
+    /// Milliseconds a batch of vote tokens waits to fill before it's verified
+    #[arg(long, value_name = "MS")]
+    verify_window_ms: Option<u64>,
+
+    /// Publish the changes to the results as delta events, with full results every few publications and at closure
+    #[arg(long)]
+    results_deltas: bool,
+
+    /// Seconds between turnout publications of the elections in progress (0 disables them) [default: 300]
+    #[arg(long)]
+    turnout_interval: Option<u64>,
+
+    /// OpenTimestamps calendar the final results of each election are anchored in, repeatable
+    #[arg(long = "ots-calendar", value_name = "URL", env = "EC_OTS_CALENDARS", value_delimiter = ',')]
+    ots_calendars: Vec<String>,
+
+    /// Minimum seconds between results history snapshots (0 snapshots every accepted vote) [default: 0]
+    #[arg(long)]
+    results_snapshot_interval: Option<u64>,
+
+    /// Reject messages written more than this many seconds before or after now (0 accepts any time)
+    #[arg(long, default_value_t = 86_400)]
+    freshness_window: u64,
+
+    /// Messages per minute a sender may send, in bursts of up to as many, the rest are
+    /// dropped (0 = no limit)
+    #[arg(long, default_value_t = 30)]
+    rate_limit: u32,
+
+    /// NIP-13 proof of work difficulty required on incoming gift wraps (0 = none)
+    #[arg(long, default_value_t = 0)]
+    min_pow: u8,
+
+    /// Also take token requests in NIP-44 encrypted direct messages, from clients that can't do gift wraps
+    #[arg(long, env = "EC_DIRECT_MESSAGES")]
+    direct_messages: bool,
+
+    /// Refuse token requests and votes without an election ID; otherwise those of older
+    /// clients go to the only election that isn't over, and are refused with more than one
+    #[arg(long, env = "EC_NO_LEGACY_MESSAGES")]
+    no_legacy_messages: bool,
+
+    /// Run a rehearsal: messages are handled as usual, but with a database of its own
+    /// (rehearsal.db) and every published event tagged as a rehearsal
+    #[arg(long, env = "EC_DRY_RUN", conflicts_with_all = ["backup", "restore"])]
+    dry_run: bool,
+
+    /// Start even if another EC seems to run in the directory (see ec.lock)
+    #[arg(long)]
+    force: bool,
+
+    /// Run as a hot standby of the EC whose admin API is at URL: its state is copied into
+    /// this EC's database, and no messages are taken until this EC takes over
+    #[arg(long, value_name = "URL", env = "EC_STANDBY_OF", conflicts_with_all = ["backup", "restore", "check"])]
+    standby_of: Option<String>,
+
+    /// Take over once the primary has been unreachable for this many seconds (without it,
+    /// the standby takes over when started without --standby-of)
+    #[arg(long, value_name = "SECONDS", requires = "standby_of")]
+    takeover_after: Option<u64>,
+
+    /// Check database integrity, report anomalies and exit
+    #[arg(long)]
+    check: bool,
+
+    /// With --check, delete rows that point at elections that do not exist
+    #[arg(long, requires = "check")]
+    repair: bool,
+
+    /// Write an encrypted recovery bundle of the keys, database and configuration to FILE and exit
+    #[arg(long, value_name = "FILE", conflicts_with = "restore")]
+    backup: Option<PathBuf>,
+
+    /// Restore the keys, database and configuration of a recovery bundle into the directory and exit
+    #[arg(long, value_name = "FILE")]
+    restore: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Run a throwaway election end to end against a local relay: generated voters
+    /// request tokens and vote, then the throughput, errors and tally are reported
+    Simulate {
+        /// Voters taking part
+        #[arg(long, default_value_t = 100)]
+        voters: usize,
+
+        /// Seconds over which the voters start voting
+        #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+        duration: u64,
+
+        /// Bits of the throwaway RSA key
+        #[arg(long, value_name = "BITS", default_value = "2048", value_parser = parse_key_size)]
+        key_bits: usize,
+    },
+}
+
+/// Flags of the EC that voters depend on, to be given again to an EC
+/// restored from a recovery bundle.
+fn recovery_flags(args: &Args, settings: &Settings) -> Vec<String> {
+    let mut flags: Vec<String> = settings.relays.iter().map(|relay| format!("--relay={}", relay)).collect();
+    let (kinds, config) = (EventKinds::default(), EventConfig::default());
+    for (name, value, default) in [
+        ("election-kind", args.election_kind as u64, kinds.election as u64),
+        ("results-kind", args.results_kind as u64, kinds.results as u64),
+        ("ballot-kind", args.ballot_kind as u64, kinds.ballot as u64),
+        ("delta-kind", args.delta_kind as u64, kinds.delta as u64),
+        ("turnout-kind", args.turnout_kind as u64, kinds.turnout as u64),
+        ("election-ttl-days", args.election_ttl_days, config.election_ttl_days),
+        ("results-ttl-days", args.results_ttl_days, config.results_ttl_days),
+        ("ballot-ttl-days", args.ballot_ttl_days, config.ballot_ttl_days),
+    ] {
+        if value != default {
+            flags.push(format!("--{}={}", name, value));
+        }
+    }
+    if args.min_pow > 0 {
+        flags.push(format!("--min-pow={}", args.min_pow));
+    }
+    if args.direct_messages {
+        flags.push("--direct-messages".to_string());
+    }
+    if args.trustees {
+        flags.push("--trustees".to_string());
     }
+    if args.no_legacy_messages {
+        flags.push("--no-legacy-messages".to_string());
+    }
+    if let Some(bunker) = &settings.bunker {
+        flags.push(format!("--bunker={}", bunker));
+    }
+    flags
+}
 
-    Ok(elections)
+/// Loads the settings of the EC in `app_dir`, with the command line flags
+/// over them.
+fn load_settings(args: &Args, app_dir: &std::path::Path) -> Result<Settings> {
+    let mut settings = Settings::load(app_dir)?;
+    if !args.relays.is_empty() {
+        settings.relays = args.relays.clone();
+    }
+    settings.nostr_key_file = args.nostr_key_file.clone().or(settings.nostr_key_file);
+    settings.bunker = args.bunker.clone().or(settings.bunker);
+    settings.retention_days = args.retention_days.unwrap_or(settings.retention_days);
+    settings.results_interval = args.results_interval.unwrap_or(settings.results_interval);
+    settings.results_snapshot_interval = args.results_snapshot_interval.unwrap_or(settings.results_snapshot_interval);
+    settings.turnout_interval = args.turnout_interval.unwrap_or(settings.turnout_interval);
+    settings.verify_window_ms = args.verify_window_ms.unwrap_or(settings.verify_window_ms);
+    settings.workers = args.workers.unwrap_or(settings.workers);
+    Ok(settings)
 }
 
-/// Publish the state of the election
-async fn publish_election_event(
-    client: &Client,
-    keys: &Keys,
-    election: &Election,
-    db: &Database,
+/// Write the recovery bundle of the EC in `app_dir` to `path`.
+async fn write_backup(
+    args: &Args,
+    settings: &Settings,
+    app_dir: &std::path::Path,
+    nostr_key_path: &std::path::Path,
+    path: &std::path::Path,
 ) -> Result<()> {
-    log::info!(
-        "Publishing election {} status: {:?}",
-        election.id,
-        election.status
-    );
-    // Old election events are expired after 15 days
-    let expire_ts = chrono::Utc::now()
-        .checked_add_signed(chrono::Duration::days(15))
-        .unwrap()
-        .timestamp() as u64;
-    let future_ts = Timestamp::from(expire_ts);
-    let event = EventBuilder::new(Kind::Custom(35_000), election.as_json_string())
-        .tag(Tag::identifier(election.id.to_string()))
-        .tag(Tag::expiration(future_ts))
-        .sign(keys)
-        .await?;
+    let db_path = app_dir.join(DATABASE_FILE);
+    if !db_path.exists() {
+        return Err(anyhow::anyhow!("No database in {} to back up", app_dir.display()));
+    }
+    // A remote signer keeps the Nostr key, the bundle only has its URI in the flags
+    let nostr_key = match &settings.nostr_private_key {
+        _ if settings.bunker.is_some() => None,
+        Some(value) => Some(value.trim().to_string()),
+        None => Some(
+            fs::read_to_string(nostr_key_path)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", nostr_key_path.display(), e))?
+                .trim()
+                .to_string(),
+        ),
+    };
+    let private_path = app_dir.join("ec_private.pem");
+    let rsa_private_key = match std::env::var("EC_PRIVATE_KEY") {
+        Ok(pem) => Some(pem),
+        Err(_) if private_path.exists() => Some(fs::read_to_string(&private_path)?),
+        Err(_) => None,
+    };
+    let rsa_public_key = match std::env::var("EC_PUBLIC_KEY") {
+        Ok(pem) => pem,
+        Err(_) => fs::read_to_string(app_dir.join("ec_public.pem"))?,
+    };
+    let pk = load_public_key(app_dir)?;
 
-    client.send_event(&event).await?;
-    log::info!(
-        "Event with election {} status {:?} broadcast to Nostr relays!",
-        election.id,
-        election.status
-    );
+    let db = Database::new(&db_path).await?;
+    let bundle =
+        RecoveryBundle::create(app_dir, &db, rsa_private_key, rsa_public_key, nostr_key, recovery_flags(args, settings)).await?;
+    let pem = bundle.encrypt(&keystore::new_passphrase()?)?;
+    util::write_private_file(path, pem.as_bytes())?;
+    println!("💾 Wrote the recovery bundle to {}", path.display());
+    println!("🔑 RSA public key fingerprint: {}", key_fingerprint(&pk)?);
+    if bundle.rsa_private_key.is_none() {
+        println!("The RSA private key isn't in the bundle: it's on the PKCS#11 token or with the trustees");
+    }
+    Ok(())
+}
+
+/// Tasks of the EC that run until the shutdown, by name.
+type Tasks = JoinSet<(&'static str, Result<()>)>;
+
+/// Runs `task` until the shutdown, a panic turned into its error.
+fn spawn_task(tasks: &mut Tasks, name: &'static str, task: impl Future<Output = Result<()>> + Send + 'static) {
+    tasks.spawn(async move {
+        let result = AssertUnwindSafe(task)
+            .catch_unwind()
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("panicked")));
+        (name, result)
+    });
+}
+
+/// Follows the primary EC until this EC takes over, returning true, or until
+/// a signal stops it, returning false.
+async fn follow_primary(db: &Database, primary: &str, takeover_after: Option<Duration>) -> Result<bool> {
+    log::info!("Standby of the EC at {}", primary);
+    systemd::notify(systemd::READY);
+    let watchdog = systemd::watchdog_interval();
+    let period = watchdog.unwrap_or(Duration::from_secs(3600));
+    let mut watchdog_ticks = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let follow = replication::follow(db, primary, takeover_after);
+    let shutdown = shutdown_signal();
+    tokio::pin!(follow, shutdown);
+    loop {
+        tokio::select! {
+            result = &mut follow => break result.map(|()| true),
+            _ = &mut shutdown => {
+                systemd::notify(systemd::STOPPING);
+                break Ok(false);
+            }
+            _ = watchdog_ticks.tick(), if watchdog.is_some() => systemd::notify(systemd::WATCHDOG),
+        }
+    }
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM from systemd or `docker stop`.
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
 
-    // Save election to database
-    db.upsert_election(election).await?;
-    log::info!("Election {} saved to database", election.id);
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            log::error!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
 
+/// Resolves on Ctrl-C.
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Relays the EC connects to: those of the settings and its local relay,
+/// or the default relay when there are none.
+fn ec_relays(settings: &Settings, args: &Args) -> Result<Vec<RelayUrl>> {
+    let mut urls = settings.relays.clone();
+    if let Some(addr) = args.local_relay {
+        urls.push(local_relay_url(addr));
+    } else if urls.is_empty() {
+        urls.push(DEFAULT_RELAY.to_string());
+    }
+    let relays = parse_relays(&urls)?;
+    if let Some(relay) = relays.iter().find(|relay| relay.is_onion()) {
+        if args.proxy.is_none() {
+            return Err(anyhow::anyhow!("Onion relay {} needs a proxy, set --proxy", relay));
+        }
+    }
+    Ok(relays)
+}
+
+/// Reloads the settings in `app_dir` and applies the relays and log levels,
+/// only once they are all valid. The relays kept stay subscribed, so no
+/// message is missed.
+async fn reload_settings(args: &Args, app_dir: &std::path::Path, relays: &RelayManager, subscription: Filter) -> Result<()> {
+    let settings = load_settings(args, app_dir)?;
+    let urls = ec_relays(&settings, args)?;
+    let (added, removed) = relays.set_relays(&urls, subscription).await?;
+    for relay in added {
+        log::info!("Using relay {}", relay);
+    }
+    for relay in removed {
+        log::info!("No longer using relay {}", relay);
+    }
+    set_levels(settings.log_level_filter(), &settings.module_levels());
+    log::info!("Settings reloaded, logging at {}", settings.log_level);
     Ok(())
 }
 
+/// Signer using the RSA private key of a PKCS#11 token.
+#[cfg(unix)]
+fn open_pkcs11_signer(
+    module: &std::path::Path,
+    slot: Option<u64>,
+    pin: Option<&str>,
+    pk: blind_rsa_signatures::PublicKey,
+) -> Result<criptocracia_ec::pkcs11::Pkcs11Signer> {
+    let pin = pin.ok_or_else(|| anyhow::anyhow!("--pkcs11-pin or EC_PKCS11_PIN is required with a PKCS#11 module"))?;
+    let signer = criptocracia_ec::pkcs11::Pkcs11Signer::new(module, slot, pin, pk)?;
+    log::info!("Signing tokens with the PKCS#11 module {}", module.display());
+    Ok(signer)
+}
+
+#[cfg(not(unix))]
+fn open_pkcs11_signer(
+    _module: &std::path::Path,
+    _slot: Option<u64>,
+    _pin: Option<&str>,
+    _pk: blind_rsa_signatures::PublicKey,
+) -> Result<LocalSigner> {
+    Err(anyhow::anyhow!("PKCS#11 modules are only supported on Unix"))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse command line arguments
     let args = Args::parse();
+    if let Some(Command::Simulate { voters, duration, key_bits }) = args.command {
+        let report = simulate::run(voters, Duration::from_secs(duration), key_bits).await?;
+        report.print();
+        if !report.tally_correct() {
+            return Err(anyhow::anyhow!("The EC's tally doesn't match the votes it acknowledged"));
+        }
+        return Ok(());
+    }
+    let event_config = EventConfig {
+        kinds: EventKinds {
+            election: args.election_kind,
+            results: args.results_kind,
+            ballot: args.ballot_kind,
+            delta: args.delta_kind,
+            turnout: args.turnout_kind,
+        },
+        election_ttl_days: args.election_ttl_days,
+        results_ttl_days: args.results_ttl_days,
+        ballot_ttl_days: args.ballot_ttl_days,
+        rehearsal: args.dry_run,
+    };
+    event_config.validate()?;
 
     // Determine the application directory
     let app_dir = if args.dir.is_empty() {
@@ -110,7 +554,7 @@ async fn main() -> Result<()> {
         let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
         PathBuf::from(home_dir).join(".ec")
     } else {
-        PathBuf::from(args.dir)
+        PathBuf::from(&args.dir)
     };
 
     // Create the directory if it doesn't exist
@@ -119,501 +563,667 @@ async fn main() -> Result<()> {
         println!("Created directory: {}", app_dir.display());
     }
 
+    let settings = load_settings(&args, &app_dir)?;
+    let nostr_key_path = settings.nostr_key_file.clone().unwrap_or_else(|| app_dir.join("nostr_key"));
+
+    // Take over from a failed EC, before anything else is created in the directory
+    if let Some(path) = &args.restore {
+        let pem = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let bundle = RecoveryBundle::decrypt(&pem, &keystore::passphrase()?)?;
+        for path in bundle.restore(&app_dir, &nostr_key_path)? {
+            println!("📦 {}", path.display());
+        }
+        println!("🔑 RSA public key fingerprint: {}", key_fingerprint(&load_public_key(&app_dir)?)?);
+        println!(
+            "Restored the backup of {}",
+            chrono::DateTime::from_timestamp(bundle.created_at as i64, 0).unwrap_or_default().to_rfc3339()
+        );
+        if !bundle.flags.is_empty() {
+            println!("Start the EC with: {}", bundle.flags.join(" "));
+        }
+        return Ok(());
+    }
+
+    // Split a new RSA key among the trustees, keeping only its public half
+    if let Some(set) = args.deal_trustees {
+        if app_dir.join("ec_private.pem").exists() || app_dir.join("ec_public.pem").exists() {
+            return Err(anyhow::anyhow!("{} already has an RSA key", app_dir.display()));
+        }
+        let bits = args.generate_keys.unwrap_or(2048);
+        println!("Generating a {}-bit RSA key for {} of {} trustees...", bits, set.threshold, set.trustees);
+        let (pk, shares) = trustees::deal(bits, set)?;
+        for path in trustees::write_dealing(&app_dir, &pk, set, &shares)? {
+            println!("🔏 {}", path.display());
+        }
+        println!("🔑 RSA public key fingerprint: {}", key_fingerprint(&pk)?);
+        println!("Hand each share to its trustee and delete it from this machine.");
+        return Ok(());
+    }
+
+    // Sign token requests as one of the trustees
+    if let Some(share) = &args.trustee {
+        let log_file = RotatingFile::open(app_dir.join("trustee.log"), LogRotation::default())?;
+        setup_logger(log::LevelFilter::Info, &[], LogFormat::Text, log_file).expect("Can't initialize logger");
+        return trustees::run_trustee(share, args.trustee_api_url.clone()).await;
+    }
+
+    // Generate the RSA keys on the first run, when asked to
+    let mut generated_bits = None;
+    if let Some(bits) = args.generate_keys {
+        let keys_in_env = std::env::var("EC_PRIVATE_KEY").is_ok() && std::env::var("EC_PUBLIC_KEY").is_ok();
+        let (private_exists, public_exists) =
+            (app_dir.join("ec_private.pem").exists(), app_dir.join("ec_public.pem").exists());
+        if !keys_in_env && !private_exists && !public_exists {
+            println!("Generating a {}-bit RSA keypair...", bits);
+            generate_keys(&app_dir, bits)?;
+            generated_bits = Some(bits);
+        }
+    }
+
     // Validate that all required files exist
-    validate_required_files(&app_dir)?;
+    validate_required_files(&app_dir, args.pkcs11_module.is_none() && !args.trustees)?;
+
+    if args.encrypt_keys {
+        let nostr_keys = match &settings.nostr_private_key {
+            Some(value) => Some(keystore::parse_nostr_keys(value)?),
+            None => None,
+        };
+        keystore::encrypt_key_files(&app_dir, nostr_keys.as_ref(), &nostr_key_path)?;
+        return Ok(());
+    }
+
+    if let Some(path) = &args.backup {
+        return write_backup(&args, &settings, &app_dir, &nostr_key_path, path).await;
+    }
+
+    // Only one EC runs in a directory, held until it exits
+    let _dir_lock = if args.force {
+        None
+    } else {
+        Some(DirLock::acquire(&app_dir)?)
+    };
 
     // Initialize logger
-    setup_logger(log::LevelFilter::Info, app_dir.join("app.log")).expect("Can't initialize logger");
+    let log_file = settings.log_file.clone().unwrap_or_else(|| app_dir.join("app.log"));
+    let log_file = RotatingFile::open(log_file, settings.log_rotation)?;
+    setup_logger(settings.log_level_filter(), &settings.module_levels(), settings.log_format, log_file).expect("Can't initialize logger");
     log::info!("Criptocracia started");
     log::info!("Using directory: {}", app_dir.display());
+    if args.force {
+        log::warn!("Started with --force: {} isn't locked against another EC", app_dir.display());
+    }
+    if let Some(bits) = generated_bits {
+        log::info!("Generated a {}-bit RSA keypair in {}", bits, app_dir.display());
+    }
 
-    // Initialize database
-    let db = Arc::new(Database::new(app_dir.join("elections.db")).await?);
+    // Initialize database, a separate one for a rehearsal
+    let database_file = if args.dry_run {
+        log::warn!("Rehearsal: using {} and tagging every published event as a rehearsal", REHEARSAL_DATABASE_FILE);
+        println!("🧪 Rehearsal: the elections are drills, kept in {}", REHEARSAL_DATABASE_FILE);
+        REHEARSAL_DATABASE_FILE
+    } else {
+        DATABASE_FILE
+    };
+    let db = Arc::new(Database::new(app_dir.join(database_file)).await?);
     log::info!("Database initialized successfully");
 
-    // Load Nostr keys from environment variable
-    let keys = if let Ok(nostr_private_key) = std::env::var("NOSTR_PRIVATE_KEY") {
-        Keys::parse(&nostr_private_key)?
+    // Validate the database before restoring any state from it
+    let report = check_database_integrity(&db, args.repair).await?;
+    if args.check {
+        if report.is_clean() {
+            println!("✅ Database integrity check passed");
+        } else {
+            for (table, count) in &report.orphan_rows {
+                println!("⚠️ {} orphan row(s) in {}", count, table);
+            }
+            for mismatch in &report.vote_mismatches {
+                println!(
+                    "⚠️ Election {} counts {} vote(s) but has {} used token(s)",
+                    mismatch.election_id, mismatch.counted_votes, mismatch.used_tokens
+                );
+            }
+            if args.repair && report.orphan_total() > 0 {
+                println!("🧹 Removed {} orphan row(s)", report.orphan_total());
+            }
+        }
+        return Ok(());
+    }
+
+    // Connect to the remote signer holding the Nostr key, or load it from the
+    // settings, or fall back to the key file
+    let identity = if let Some(bunker) = &settings.bunker {
+        EcIdentity::remote(bunker, &app_dir, args.proxy).await?
+    } else if let Some(nostr_private_key) = &settings.nostr_private_key {
+        EcIdentity::local(keystore::parse_nostr_keys(nostr_private_key)?)
+    } else if nostr_key_path.exists() {
+        EcIdentity::local(keystore::parse_nostr_keys(&fs::read_to_string(&nostr_key_path)?)?)
     } else {
-        return Err(anyhow::anyhow!("NOSTR_PRIVATE_KEY environment variable is required"));
+        return Err(anyhow::anyhow!(
+            "--bunker, the nostr_private_key setting (NOSTR_PRIVATE_KEY) or the {} key file is required",
+            nostr_key_path.display()
+        ));
     };
 
     // 1. Load the keys from environment variables or fallback to files
-    let (pk, sk) = if let (Ok(private_pem), Ok(public_pem)) = (
-        std::env::var("EC_PRIVATE_KEY"),
-        std::env::var("EC_PUBLIC_KEY"),
-    ) {
-        // Load keys from environment variables
-        load_keys_from_pem(&private_pem, &public_pem)?
+    let (signed_tx, mut signed_rx) = mpsc::unbounded_channel();
+    let mut trustees = None;
+    let signer: Arc<dyn BlindSigner> = if args.trustees {
+        let set = TrusteeSet::load(&app_dir)?;
+        let keys = TrusteeSet::load_keys(&app_dir)?;
+        let verification = TrusteeSet::load_verification(&app_dir)?;
+        let coordinator = Arc::new(Trustees::new(
+            load_public_key(&app_dir)?,
+            set,
+            keys,
+            verification,
+            Arc::clone(&db),
+            signed_tx,
+        )?);
+        log::info!("Tokens are signed by {} of {} trustees", set.threshold, set.trustees);
+        trustees = Some(Arc::clone(&coordinator));
+        coordinator
+    } else if let Some(module) = &args.pkcs11_module {
+        Arc::new(open_pkcs11_signer(module, args.pkcs11_slot, args.pkcs11_pin.as_deref(), load_public_key(&app_dir)?)?)
     } else {
-        // Fallback to loading from files in app directory
-        load_keys(
-            app_dir.join("ec_private.pem"),
-            app_dir.join("ec_public.pem"),
-        )?
+        let (pk, sk) = if let (Ok(private_pem), Ok(public_pem)) = (
+            std::env::var("EC_PRIVATE_KEY"),
+            std::env::var("EC_PUBLIC_KEY"),
+        ) {
+            // Load keys from environment variables
+            load_keys_from_pem(&private_pem, &public_pem)?
+        } else {
+            // Fallback to loading from files in app directory
+            load_keys(
+                app_dir.join("ec_private.pem"),
+                app_dir.join("ec_public.pem"),
+            )?
+        };
+        Arc::new(LocalSigner::new(pk, sk))
     };
+    // Election content is signed with a key of its own, never the token key
+    let content_signer = Arc::new(ContentSigner::load(&app_dir)?);
+    let content_key = general_purpose::STANDARD.encode(content_signer.public_key().to_der()?);
+    let identity = identity.with_content_signer(content_signer);
+    let pk = signer.public_key().clone();
+    let fingerprint = key_fingerprint(&pk)?;
+    log::info!("RSA public key fingerprint: {}", fingerprint);
+    println!("🔑 RSA public key fingerprint: {}", fingerprint);
     let pk_der = pk.to_der()?;
     // We need to encode the RSA public key in Base64 to publish it on Nostr
     let pk_der_b64 = general_purpose::STANDARD.encode(&pk_der);
 
     println!(
         "🔑 Electoral Commission Nostr Public key: {}",
-        keys.public_key()
+        identity.public_key
     );
 
-    // Build the signing client
-    let client = Client::builder().signer(keys.clone()).build();
+    // A standby follows the primary, with the keys to take over from it
+    if let Some(primary) = &args.standby_of {
+        println!("🔁 Standby of the EC at {}", primary);
+        if !follow_primary(&db, primary, args.takeover_after.map(Duration::from_secs)).await? {
+            db.close().await;
+            log::info!("Criptocracia stopped");
+            println!("Stopped");
+            return Ok(());
+        }
+        println!("⚡ Taking over from the EC at {}", primary);
+    }
+
+    // Build the signing client, going through the proxy if one is set
+    let mut opts = Options::new();
+    if let Some(proxy) = args.proxy {
+        log::info!("Connecting to the relays through the SOCKS5 proxy {}", proxy);
+        opts = opts.connection(Connection::new().proxy(proxy).target(ConnectionTarget::All));
+    }
+    let client = Client::builder().signer(identity.signer.clone()).opts(opts).build();
 
-    // Add the Mostro relay and connect
-    client.add_relay("wss://relay.mostro.network").await?;
-    client.connect().await;
+    // Start the local relay, the EC being one of its clients
+    let relay_urls = ec_relays(&settings, &args)?;
+    if let Some(addr) = args.local_relay {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let local_relay = Arc::new(LocalRelay::new(Arc::clone(&db)).await?);
+        tokio::spawn(local_relay.serve(listener));
+        println!("📡 Local relay listening on ws://{}", addr);
+    }
 
-    // Load elections from database and store in HashMap
-    let elections_vec = load_elections_from_database(&db).await?;
-    let mut elections_map = HashMap::new();
+    // Public results page, for observers without a Nostr client
+    if let Some(addr) = args.http {
+        let listener = std::net::TcpListener::bind(addr)?;
+        let results_page = Arc::new(ResultsPage::new(Arc::clone(&db)));
+        tokio::spawn(async move {
+            if let Err(e) = results_page.serve(listener).await {
+                log::error!("Results page stopped: {}", e);
+            }
+        });
+        println!("🌐 Results page at http://{}", addr);
+    }
+
+    // Add every configured relay and connect
+    for relay in relay_urls {
+        log::info!("Using relay {}", relay);
+        client.add_relay(relay).await?;
+    }
+    client.connect().await;
 
-    for election in elections_vec {
-        elections_map.insert(election.id.clone(), election);
+    // Watch the relays, reconnecting them and republishing missed events
+    let relays = Arc::new(RelayManager::new(client.clone(), Arc::clone(&db)).with_events(event_config));
+    tokio::spawn(Arc::clone(&relays).monitor());
+    if let Err(e) = publish_event_kinds(&relays, &identity).await {
+        log::error!("Failed to advertise the event kinds: {}", e);
     }
+    let mut metadata = Metadata::new().name(&args.name);
+    if let Some(about) = &args.about {
+        metadata = metadata.about(about);
+    }
+    let descriptor = EcDescriptor::new(pk_der_b64.clone(), args.contact.clone()).with_content_key(content_key);
+    if let Err(e) = publish_profile(&relays, &identity, &metadata, &descriptor).await {
+        log::error!("Failed to publish the EC profile: {}", e);
+    }
+
+    // Voters imported from a file join the rolls before the elections are loaded
+    let imported = match &args.import_voters {
+        Some(path) => voter_import::import_voters(&db, path).await?,
+        None => Vec::new(),
+    };
 
-    if elections_map.is_empty() {
+    // Load elections from database, each behind its own lock
+    let elections_vec = load_elections_from_database(&db).await?;
+
+    if elections_vec.is_empty() {
         log::info!("No elections found in database. Starting with empty state.");
         println!(
             "🗳️ Electoral Commission started with no elections. Use gRPC admin API to create elections."
         );
     } else {
-        log::info!("Loaded {} elections from database", elections_map.len());
+        log::info!("Loaded {} elections from database", elections_vec.len());
         println!(
             "🗳️ Electoral Commission started with {} elections loaded from database",
-            elections_map.len()
+            elections_vec.len()
         );
 
         // Display loaded elections
-        for (id, election) in &elections_map {
+        for election in &elections_vec {
             println!(
                 "📋 Election: {} (ID: {}, Status: {:?})",
-                election.name, id, election.status
+                election.name, election.id, election.status
             );
         }
     }
 
-    let elections = Arc::new(Mutex::new(elections_map));
+    let elections = Arc::new(Elections::new(elections_vec));
+
+    // Publish the new roll commitments of the elections voters were imported into
+    for (election_id, count) in &imported {
+        println!("👥 Imported {} voters into election {}", count, election_id);
+        if let Some(election) = elections.get(election_id) {
+            let election = election.read().await.clone();
+            if let Err(e) = publish_election_event(&relays, &identity, &election, &db).await {
+                log::error!("Failed to publish election {} after importing voters: {}", election_id, e);
+            }
+        }
+    }
+
+    // Every long-running task stops at the next iteration once shutdown is
+    // signaled, and is waited for before the EC exits
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut tasks = Tasks::new();
 
     // Start periodic election status checker
     {
         let elections_clone = Arc::clone(&elections);
         let db_clone = Arc::clone(&db);
-        let client_clone = client.clone();
-        let keys_clone = keys.clone();
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(Duration::from_secs(30));
+        let relays_clone = Arc::clone(&relays);
+        let identity_clone = identity.clone();
+        let mut shutdown = shutdown_rx.clone();
+        let period = Duration::from_secs(settings.status_check_interval);
+        spawn_task(&mut tasks, "status checker", async move {
+            let mut interval = tokio::time::interval(period);
             loop {
-                interval.tick().await;
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+                update_election_statuses(&elections_clone, &db_clone, &relays_clone, &identity_clone).await;
+            }
+            Ok(())
+        });
+    }
 
-                let current_time = chrono::Utc::now().timestamp() as u64;
-                let mut elections_to_update = Vec::new();
+    // Start the turnout publisher
+    if settings.turnout_interval > 0 {
+        let elections_clone = Arc::clone(&elections);
+        let db_clone = Arc::clone(&db);
+        let relays_clone = Arc::clone(&relays);
+        let identity_clone = identity.clone();
+        let mut shutdown = shutdown_rx.clone();
+        let period = Duration::from_secs(settings.turnout_interval);
+        spawn_task(&mut tasks, "turnout publisher", async move {
+            let mut published = HashMap::new();
+            let mut interval = tokio::time::interval(period);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+                publish_turnouts(&elections_clone, &db_clone, &relays_clone, &identity_clone, &mut published).await;
+            }
+            Ok(())
+        });
+    }
 
-                // Check and update election statuses
+    // Start periodic data retention maintenance
+    if settings.retention_days > 0 {
+        let elections_clone = Arc::clone(&elections);
+        let db_clone = Arc::clone(&db);
+        let retention_days = settings.retention_days;
+        log::info!("Data retention enabled: {} day(s) after election end", retention_days);
+        let mut shutdown = shutdown_rx.clone();
+        spawn_task(&mut tasks, "data retention", async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+                if let Err(e) =
+                    purge_expired_election_data(&db_clone, &elections_clone, retention_days).await
                 {
-                    let mut elections_guard = elections_clone.lock().await;
-                    for (election_id, election) in elections_guard.iter_mut() {
-                        if election.update_status_based_on_time(current_time) {
-                            log::info!(
-                                "Election {} status changed to {:?}",
-                                election_id,
-                                election.status
-                            );
-                            elections_to_update.push(election.clone());
-                        }
-                    }
+                    log::error!("Failed to purge expired election data: {}", e);
                 }
-
-                // Persist status changes and publish to Nostr
-                for election in elections_to_update {
-                    // Save to database
-                    if let Err(e) = db_clone.upsert_election(&election).await {
-                        log::error!(
-                            "Failed to update election {} in database: {}",
-                            election.id,
-                            e
-                        );
-                    }
-
-                    // Publish to Nostr
-                    if let Err(e) =
-                        publish_election_event(&client_clone, &keys_clone, &election, &db_clone)
-                            .await
-                    {
-                        log::error!(
-                            "Failed to publish election {} status update to Nostr: {}",
-                            election.id,
-                            e
-                        );
-                    }
+            }
+            Ok(())
+        });
+    }
+    // Drop the changes standby ECs no longer stream, a standby further behind
+    // getting a full copy
+    {
+        let db = Arc::clone(&db);
+        let mut shutdown = shutdown_rx.clone();
+        spawn_task(&mut tasks, "replication log pruning", async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+                let before = chrono::Utc::now().timestamp() - replication::LOG_RETENTION.as_secs() as i64;
+                match db.prune_replication_log(before).await {
+                    Ok(0) => {}
+                    Ok(removed) => log::debug!("Pruned {} replication log entries", removed),
+                    Err(e) => log::error!("Failed to prune the replication log: {}", e),
                 }
             }
+            Ok(())
         });
     }
+
+    // Listen before subscribing, so the live events that arrive during the
+    // backfill wait for it
+    let mut notifications = client.notifications();
     let subscription = Filter::new()
-        .pubkey(keys.public_key())
-        .kind(Kind::GiftWrap)
+        .pubkey(identity.public_key)
+        .kinds(message_kinds(args.direct_messages))
         .limit(0);
     // Client subscription
-    client.subscribe(subscription, None).await?;
-    // Set up channel for real-time order updates
-    let (tx, mut rx) = mpsc::channel(100);
+    client.subscribe(subscription.clone(), None).await?;
+    let mut handler = MessageHandler::new(
+        Arc::clone(&relays),
+        identity.clone(),
+        Arc::clone(&db),
+        Arc::clone(&elections),
+        Arc::clone(&signer),
+    )
+    .with_results_snapshot_interval(settings.results_snapshot_interval)
+    .with_results_publishing(settings.results_interval, args.results_deltas)
+    .with_freshness_window(args.freshness_window)
+    .with_rate_limit(args.rate_limit)
+    .with_min_pow(args.min_pow)
+    .with_direct_messages(args.direct_messages)
+    .with_legacy_messages(!args.no_legacy_messages);
+    if let Some(trustees) = &trustees {
+        handler = handler.with_trustees(Arc::clone(trustees));
+    }
+    if args.verify_batch > 0 {
+        log::info!("Vote tokens are verified in batches of up to {}", args.verify_batch);
+        let window = Duration::from_millis(settings.verify_window_ms);
+        handler = handler.with_batch_verifier(BatchVerifier::new(Arc::clone(&signer), args.verify_batch, window));
+    }
+
+    // Anchor the final results in OpenTimestamps and publish the proofs once
+    // they reach Bitcoin
+    if !args.ots_calendars.is_empty() {
+        log::info!("Final results are timestamped with {}", args.ots_calendars.join(", "));
+        let timestamper = Arc::new(Timestamper::new(
+            args.ots_calendars.clone(),
+            Arc::clone(&db),
+            Arc::clone(&relays),
+            identity.clone(),
+        ));
+        let run = Arc::clone(&timestamper).run(shutdown_rx.clone());
+        spawn_task(&mut tasks, "results timestamper", async move {
+            run.await;
+            Ok(())
+        });
+        handler = handler.with_timestamper(timestamper);
+    }
+    let handler = Arc::new(handler);
+
+    // Republish what the relays lost while the EC was down
     {
         let client = client.clone();
+        let relays = Arc::clone(&relays);
+        let identity = identity.clone();
+        let db = Arc::clone(&db);
         let elections = Arc::clone(&elections);
-        let keys = keys.clone();
-        let tx = tx.clone();
+        let handler = Arc::clone(&handler);
+        let mut shutdown = shutdown_rx.clone();
+        spawn_task(&mut tasks, "relay reconciliation", async move {
+            tokio::select! {
+                result = reconcile::run(&client, &relays, &identity, &db, &elections, &handler) => {
+                    if let Err(e) = result {
+                        log::error!("Failed to reconcile with the relays: {}", e);
+                    }
+                }
+                _ = shutdown.changed() => return Ok(()),
+            }
+            // Done once, but a task that ends stops the EC
+            let _ = shutdown.changed().await;
+            Ok(())
+        });
+    }
+
+    // Send the voters the tokens the trustees signed
+    {
+        let handler = Arc::clone(&handler);
+        let mut shutdown = shutdown_rx.clone();
+        spawn_task(&mut tasks, "token delivery", async move {
+            loop {
+                let (request, blind_sig) = tokio::select! {
+                    signed = signed_rx.recv() => match signed {
+                        Some(signed) => signed,
+                        None => break,
+                    },
+                    _ = shutdown.changed() => break,
+                };
+                handler.deliver_token(&request, &blind_sig).await;
+            }
+            Ok(())
+        });
+    }
+
+    // Start the results publisher, which also publishes the final results
+    // of the elections that close
+    {
+        let handler = Arc::clone(&handler);
+        let seconds = match settings.results_interval {
+            0 => RESULTS_CHECK_INTERVAL,
+            seconds => seconds,
+        };
+        let mut shutdown = shutdown_rx.clone();
+        spawn_task(&mut tasks, "results publisher", async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(seconds));
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = shutdown.changed() => break,
+                }
+                handler.flush_results().await;
+            }
+            Ok(())
+        });
+    }
+    {
+        let client = client.clone();
+        let handler = Arc::clone(&handler);
         let db = Arc::clone(&db);
-        // Spawn a task to handle Nostr events
-        tokio::spawn(async move {
-            let mut notifications = client.notifications();
-            while let Ok(notification) = notifications.recv().await {
-                if let RelayPoolNotification::Event { event, .. } = notification {
-                    // Validate event signature
-                    if event.verify().is_err() {
-                        log::warn!("Event failed signature verification – ignored");
+        let pubkey = identity.public_key;
+        let kinds = message_kinds(args.direct_messages);
+        // Enough workers for their votes to fill the verification batches
+        let workers = settings.worker_count().max(args.verify_batch);
+        log::info!("Handling messages with {} workers", workers);
+        let pool = {
+            let handler = Arc::clone(&handler);
+            WorkerPool::start(workers, settings.queue_size, move |event: Box<Event>| {
+                let handler = Arc::clone(&handler);
+                async move { handler.handle_event(&event).await }
+            })
+        };
+        let mut shutdown = shutdown_rx.clone();
+        // Spawn a task to handle Nostr events, missed ones first
+        spawn_task(&mut tasks, "message listener", async move {
+            if let Err(e) = backfill_messages(&client, &handler, pubkey, kinds, &db).await {
+                log::error!("Failed to backfill messages: {}", e);
+            }
+            let mut closed = false;
+            loop {
+                let received = tokio::select! {
+                    received = notifications.recv() => received,
+                    _ = shutdown.changed() => break,
+                };
+                let notification = match received {
+                    Ok(notification) => notification,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        log::warn!("Skipped {} relay notifications", skipped);
                         continue;
-                    };
-                    let event = match nip59::extract_rumor(&keys, &event).await {
-                        Ok(u) => u,
-                        Err(e) => {
-                            log::warn!("Error unwrapping gift: {}", e);
-                            continue;
-                        }
-                    };
-                    let voter = event.sender;
-                    let message = match Message::from_json(&event.rumor.content) {
-                        Ok(m) => m,
-                        Err(e) => {
-                            log::warn!("Error parsing message: {}", e);
-                            continue;
-                        }
-                    };
-                    // Check if the message is a token request
-                    match message.kind {
-                        1 => {
-                            log::info!("Token request received: {:#?}", message);
-                            let blinded_bytes =
-                                match general_purpose::STANDARD.decode(message.payload) {
-                                    Ok(bytes) => bytes,
-                                    Err(e) => {
-                                        log::warn!("Error decoding content: {}", e);
-                                        continue;
-                                    }
-                                };
-                            let blinded_h_n = BlindedMessage::from(blinded_bytes);
-                            let req = BlindTokenRequest {
-                                voter_pk: voter.to_string(),
-                                blinded_h_n,
-                            };
-                            // Handle election-specific or legacy token requests
-                            let mut blind_sig = None;
-                            {
-                                let mut elections_guard = elections.lock().await;
-                                
-                                if let Some(election_id) = &message.election_id {
-                                    // New protocol: election-specific token request
-                                    if let Some(election) = elections_guard.get_mut(election_id) {
-                                        let req_copy = BlindTokenRequest {
-                                            voter_pk: req.voter_pk.clone(),
-                                            blinded_h_n: req.blinded_h_n.clone(),
-                                        };
-                                        match election.issue_token(req_copy, sk.clone()) {
-                                            Ok(token) => {
-                                                blind_sig = Some(token);
-                                                log::info!("Token issued for election {}", election_id);
-                                            }
-                                            Err(e) => {
-                                                log::warn!("Token request failed for election {}: {}", election_id, e);
-                                            }
-                                        }
-                                    } else {
-                                        log::warn!("Election {} not found", election_id);
-                                    }
-                                } else {
-                                    // Legacy protocol: try all elections (for backward compatibility)
-                                    log::warn!("Legacy token request without election_id - trying all elections");
-                                    for (_election_id, election) in elections_guard.iter_mut() {
-                                        let req_copy = BlindTokenRequest {
-                                            voter_pk: req.voter_pk.clone(),
-                                            blinded_h_n: req.blinded_h_n.clone(),
-                                        };
-                                        match election.issue_token(req_copy, sk.clone()) {
-                                            Ok(token) => {
-                                                blind_sig = Some(token);
-                                                break;
-                                            }
-                                            Err(_) => continue, // Try next election
-                                        }
-                                    }
-                                }
-                            }
-
-                            let blind_sig = match blind_sig {
-                                Some(sig) => sig,
-                                None => {
-                                    if message.election_id.is_some() {
-                                        log::warn!("Voter {} not authorized for election {:?}", voter, message.election_id);
-                                    } else {
-                                        log::warn!("Voter {} not authorized for any election", voter);
-                                    }
-                                    continue;
-                                }
-                            };
-                            // Encode token to Base64
-                            let blind_sig_b64 = general_purpose::STANDARD.encode(blind_sig);
-                            let response = if let Some(election_id) = &message.election_id {
-                                Message::new_with_election(message.id.clone(), 1, blind_sig_b64.clone(), election_id.clone())
-                            } else {
-                                // Fallback for legacy messages without election_id
-                                Message::new(message.id.clone(), 1, blind_sig_b64.clone())
-                            };
-                            // Creates a "rumor" with the hash of the nonce.
-                            let rumor: UnsignedEvent = EventBuilder::text_note(response.as_json())
-                                .build(keys.public_key());
-
-                            // Wraps the rumor in a Gift Wrap.
-                            let gift_wrap =
-                                match EventBuilder::gift_wrap(&keys, &voter, rumor, None).await {
-                                    Ok(ev) => ev,
-                                    Err(e) => {
-                                        log::warn!("Unable to build GiftWrap for {}: {}", voter, e);
-                                        continue;
-                                    }
-                                };
-
-                            match client.send_event(&gift_wrap).await {
-                                Ok(_) => log::info!("Blind signature sent to: {}", voter),
-                                Err(e) => log::error!("Failed to send blind signature: {}", e),
-                            }
-                        }
-                        2 => {
-                            // Split the incoming vote message into parts.
-                            let parts: Vec<&str> = message.payload.split(':').collect();
-                            if parts.len() != 4 {
-                                log::warn!("Invalid vote format: {}", message.payload);
-                                continue;
-                            }
-
-                            // Decode h_n from Base64
-                            let h_n_bytes = match general_purpose::STANDARD.decode(parts[0]) {
-                                Ok(bytes) => bytes,
-                                Err(e) => {
-                                    log::warn!("Failed to decode h_n: {}", e);
-                                    continue;
-                                }
-                            };
-                            let h_n = BigUint::from_bytes_be(&h_n_bytes);
-
-                            // Decode token from Base64
-                            let token_bytes = match general_purpose::STANDARD.decode(parts[1]) {
-                                Ok(b) => b,
-                                Err(_) => continue,
-                            };
-                            let token: RSASignature = RSASignature::from(token_bytes.clone());
-
-                            // Decode MessageRandomizer from Base64
-                            let r_bytes = match general_purpose::STANDARD.decode(parts[2]) {
-                                Ok(b) => b,
-                                Err(_) => continue,
-                            };
-                            let rand_arr: [u8; 32] = match <[u8; 32]>::try_from(&r_bytes[..]) {
-                                Ok(arr) => arr,
-                                Err(_) => {
-                                    log::warn!("Invalid randomizer length");
-                                    continue;
-                                }
-                            };
-                            let msg_rand = MessageRandomizer::from(rand_arr);
-
-                            // Parse vote as an integer
-                            let vote = match parts[3].parse::<u8>() {
-                                Ok(v) => v,
-                                Err(e) => {
-                                    log::warn!("Failed to parse vote: {}", e);
-                                    continue;
-                                }
-                            };
-                            let options = Options::default();
-                            // Verify the signature on the raw h_n_bytes
-                            if token
-                                .verify(&pk, Some(msg_rand), &h_n_bytes, &options)
-                                .is_err()
-                            {
-                                log::warn!("Invalid token signature");
-                                continue;
-                            }
-
-                            // Handle election-specific or legacy vote submission
-                            let mut vote_accepted = false;
-                            let mut tally = HashMap::new();
-                            let mut election_id_for_results = String::new();
-                            {
-                                let mut elections_guard = elections.lock().await;
-                                
-                                if let Some(election_id) = &message.election_id {
-                                    // New protocol: election-specific vote submission
-                                    if let Some(election) = elections_guard.get_mut(election_id) {
-                                        match election.receive_vote(h_n.clone(), vote) {
-                                            Ok(()) => {
-                                                vote_accepted = true;
-                                                election_id_for_results = election_id.clone();
-                                                log::info!("Vote accepted for election {}", election_id);
-
-                                                // Save used token to database
-                                                if let Err(e) =
-                                                    election.save_used_token_to_db(&db, &h_n).await
-                                                {
-                                                    log::error!(
-                                                        "Failed to save used token to database: {}",
-                                                        e
-                                                    );
-                                                }
-
-                                                // Get tally for this election
-                                                tally = election.tally();
-                                            }
-                                            Err(e) => {
-                                                log::warn!("Vote rejected for election {}: {}", election_id, e);
-                                            }
-                                        }
-                                    } else {
-                                        log::warn!("Election {} not found for vote submission", election_id);
-                                    }
-                                } else {
-                                    // Legacy protocol: try all elections (for backward compatibility)
-                                    log::warn!("Legacy vote submission without election_id - trying all elections");
-                                    for (election_id, election) in elections_guard.iter_mut() {
-                                        match election.receive_vote(h_n.clone(), vote) {
-                                            Ok(()) => {
-                                                vote_accepted = true;
-                                                election_id_for_results = election_id.clone();
-
-                                                // Save used token to database
-                                                if let Err(e) =
-                                                    election.save_used_token_to_db(&db, &h_n).await
-                                                {
-                                                    log::error!(
-                                                        "Failed to save used token to database: {}",
-                                                        e
-                                                    );
-                                                }
-
-                                                // Get tally for this election
-                                                tally = election.tally();
-                                                break;
-                                            }
-                                            Err(_) => continue, // Try next election
-                                        }
-                                    }
-                                }
-                            }
-
-                            if !vote_accepted {
-                                if message.election_id.is_some() {
-                                    log::warn!("Vote not accepted for election {:?}", message.election_id);
-                                } else {
-                                    log::warn!("Vote not accepted by any election");
-                                }
-                                continue;
-                            }
-
-                            let election_id = election_id_for_results;
-
-                            let mut results = String::new();
-                            let mut json_results: Vec<(u8, u32)> = Vec::new();
-                            for (cand, count) in &tally {
-                                results.push_str(&format!("{}: {} vote(s)\n", cand.name, count));
-                                json_results.push((cand.id, *count));
-                            }
-                            let json_string = match serde_json::to_string(&json_results) {
-                                Ok(json) => json,
-                                Err(err) => {
-                                    log::error!(
-                                        "Failed to serialize election results to JSON: {}",
-                                        err
-                                    );
-                                    continue;
-                                }
-                            };
-
-                            let expire_ts = chrono::Utc::now()
-                                .checked_add_signed(chrono::Duration::days(5))
-                                .unwrap()
-                                .timestamp() as u64;
-                            let future_ts = Timestamp::from(expire_ts);
-                            println!("🗳️ Election's result: \n\n{}", results);
-
-                            // Update vote counts in database
-                            if let Err(err) =
-                                db.update_vote_counts(&election_id, &json_results).await
-                            {
-                                log::error!("Failed to update vote counts in database: {}", err);
-                            }
-
-                            // We publish the results in a custom event with kind 35_001
-                            match EventBuilder::new(Kind::Custom(35_001), json_string)
-                                .tag(Tag::identifier(election_id.clone()))
-                                .tag(Tag::expiration(future_ts))
-                                .sign(&keys)
-                                .await
-                            {
-                                Ok(event) => {
-                                    // Publish the event to the relay
-                                    match client.send_event(&event).await {
-                                        Ok(_) => {
-                                            log::info!("Election results published successfully")
-                                        }
-                                        Err(e) => log::error!("Failed to publish results: {}", e),
-                                    }
-                                }
-                                Err(e) => log::error!("Failed to sign results event: {}", e),
-                            };
-                        }
-                        _ => {
-                            log::warn!("Unknown message kind: {}", message.kind);
-                            continue;
-                        }
                     }
-                    let _ = tx.send(event).await;
+                    Err(broadcast::error::RecvError::Closed) => {
+                        closed = true;
+                        break;
+                    }
+                };
+                if let RelayPoolNotification::Event { event, .. } = notification {
+                    pool.push(event).await;
                 }
             }
+            // Let the queued messages be handled
+            pool.close().await;
+            if closed {
+                return Err(anyhow::anyhow!("The relay notifications stopped"));
+            }
+            Ok(())
         });
     }
 
-    // Start gRPC server for admin operations
+    // Reload the relays and log levels on SIGHUP
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let args = args.clone();
+        let app_dir = app_dir.clone();
+        let relays = Arc::clone(&relays);
+        let mut shutdown = shutdown_rx.clone();
+        spawn_task(&mut tasks, "settings reloader", async move {
+            loop {
+                tokio::select! {
+                    _ = hangup.recv() => {}
+                    _ = shutdown.changed() => break,
+                }
+                if let Err(e) = reload_settings(&args, &app_dir, &relays, subscription.clone()).await {
+                    log::error!("Settings not reloaded, keeping the running ones: {}", e);
+                }
+            }
+            Ok(())
+        });
+    }
+
+    // Start gRPC server for admin operations, listening before the EC is ready
+    let grpc_server = GrpcServer::new(settings.grpc_addr())
+        .with_trustees(trustees.clone(), args.trustee_api)
+        .bind()
+        .await?;
     {
         let db_clone = Arc::clone(&db);
         let elections_clone = Arc::clone(&elections);
         let pk_der_b64_clone = pk_der_b64.clone();
-        let client_clone = Arc::new(client.clone());
-        let keys_clone = Arc::new(keys.clone());
-        tokio::spawn(async move {
-            let grpc_server = GrpcServer::default(); // Uses port 50001
+        let relays_clone = Arc::clone(&relays);
+        let identity_clone = Arc::new(identity.clone());
+        let shutdown = shutdown_rx.clone();
+        spawn_task(&mut tasks, "gRPC server", async move {
             log::info!("Starting gRPC admin server on port {}", grpc_server.port);
-            if let Err(e) = grpc_server
-                .start(
-                    db_clone,
-                    elections_clone,
-                    pk_der_b64_clone,
-                    client_clone,
-                    keys_clone,
-                )
+            grpc_server
+                .start(db_clone, elections_clone, pk_der_b64_clone, relays_clone, identity_clone, shutdown)
                 .await
-            {
-                log::error!("gRPC server failed: {}", e);
-            }
         });
     }
 
-    loop {
-        // Check for new orders without blocking
-        while let Ok(_event) = rx.try_recv() {
-            // log::info!("New event rx: {:#?}", event);
+    // Ready once connected to the relays, or when they are given up on
+    client.wait_for_connection(BACKFILL_TIMEOUT).await;
+    systemd::notify(systemd::READY);
+    log::info!("Electoral Commission ready");
+
+    // Run until asked to stop, or until a task fails: then the EC stops as
+    // it would on a signal, and exits with the task's error. Meanwhile the
+    // systemd watchdog is told the EC is alive.
+    let watchdog = systemd::watchdog_interval();
+    let period = watchdog.unwrap_or(Duration::from_secs(3600));
+    let mut watchdog_ticks = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let failure = loop {
+        tokio::select! {
+            _ = &mut shutdown => break None,
+            Some(Ok((name, result))) = tasks.join_next() => break Some(match result {
+                Ok(()) => anyhow::anyhow!("The {} stopped unexpectedly", name),
+                Err(e) => e.context(format!("The {} failed", name)),
+            }),
+            _ = watchdog_ticks.tick(), if watchdog.is_some() => systemd::notify(systemd::WATCHDOG),
+        }
+    };
+    systemd::notify(systemd::STOPPING);
+    match &failure {
+        Some(e) => {
+            eprintln!("{:#}, shutting down...", e);
+            log::error!("{:#}, shutting down: no new messages or admin requests are taken", e);
         }
+        None => {
+            println!("Shutting down...");
+            log::info!("Shutting down: no new messages or admin requests are taken");
+        }
+    }
+    let _ = shutdown_tx.send(true);
+    let stopped = async {
+        while let Some(joined) = tasks.join_next().await {
+            if let Ok((name, Err(e))) = joined {
+                log::error!("The {} failed: {:#}", name, e);
+            }
+        }
+    };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, stopped).await.is_err() {
+        log::warn!("Some tasks didn't stop within {} seconds", SHUTDOWN_TIMEOUT.as_secs());
+    }
+
+    // Publish what is due before going offline: the status of the elections
+    // that started or ended, the pending results and the outbox
+    update_election_statuses(&elections, &db, &relays, &identity).await;
+    handler.flush_results().await;
+    if let Err(e) = relays.flush_outbox().await {
+        log::error!("Failed to flush the outbox: {}", e);
     }
+    client.disconnect().await;
+    db.close().await;
+    log::info!("Criptocracia stopped");
+    println!("Stopped");
+    failure.map_or(Ok(()), Err)
 }
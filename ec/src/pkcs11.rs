@@ -0,0 +1,472 @@
+/*! pkcs11.rs — Blind signing with a key held in an HSM
+`Pkcs11Signer` loads a PKCS#11 module (SoftHSM, OpenSC for YubiKeys or an HSM
+vendor's library), logs in to a token and signs the blinded messages with raw
+RSA (`CKM_RSA_X_509`), so the private key never leaves the device. The key is
+the token's private key whose modulus matches the EC's RSA public key. */
+
+use anyhow::Result;
+use blind_rsa_signatures::{BlindSignature, PublicKey as RSAPublicKey};
+use num_bigint_dig::BigUint;
+use rsa::PublicKeyParts;
+use std::ffi::{CString, c_void};
+use std::os::raw::c_ulong;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::sync::Mutex;
+
+use crate::signer::BlindSigner;
+
+type CkUlong = c_ulong;
+type CkRv = CkUlong;
+type CkSlotId = CkUlong;
+type CkSessionHandle = CkUlong;
+type CkObjectHandle = CkUlong;
+
+const CKR_OK: CkRv = 0;
+const CKR_USER_ALREADY_LOGGED_IN: CkRv = 0x100;
+const CKR_CRYPTOKI_ALREADY_INITIALIZED: CkRv = 0x191;
+const CKF_OS_LOCKING_OK: CkUlong = 0x2;
+const CKF_SERIAL_SESSION: CkUlong = 0x4;
+const CKU_USER: CkUlong = 1;
+const CKA_CLASS: CkUlong = 0x0;
+const CKA_MODULUS: CkUlong = 0x120;
+const CKO_PRIVATE_KEY: CkUlong = 3;
+const CKM_RSA_X_509: CkUlong = 0x3;
+
+/// Most private keys of a token looked at for the EC's one.
+const MAX_KEYS: usize = 64;
+
+#[repr(C)]
+struct CkVersion {
+    major: u8,
+    minor: u8,
+}
+
+#[repr(C)]
+struct CkAttribute {
+    kind: CkUlong,
+    value: *mut c_void,
+    len: CkUlong,
+}
+
+#[repr(C)]
+struct CkMechanism {
+    mechanism: CkUlong,
+    parameter: *mut c_void,
+    len: CkUlong,
+}
+
+#[repr(C)]
+struct CkInitializeArgs {
+    create_mutex: *mut c_void,
+    destroy_mutex: *mut c_void,
+    lock_mutex: *mut c_void,
+    unlock_mutex: *mut c_void,
+    flags: CkUlong,
+    reserved: *mut c_void,
+}
+
+/// Leading part of CK_FUNCTION_LIST, up to C_Sign; the functions the signer
+/// doesn't call are left opaque.
+#[repr(C)]
+struct CkFunctionList {
+    version: CkVersion,
+    initialize: Option<unsafe extern "C" fn(*mut c_void) -> CkRv>,
+    finalize: Option<unsafe extern "C" fn(*mut c_void) -> CkRv>,
+    _info: [*const c_void; 2],
+    get_slot_list: Option<unsafe extern "C" fn(u8, *mut CkSlotId, *mut CkUlong) -> CkRv>,
+    _slots: [*const c_void; 7],
+    open_session:
+        Option<unsafe extern "C" fn(CkSlotId, CkUlong, *mut c_void, *mut c_void, *mut CkSessionHandle) -> CkRv>,
+    close_session: Option<unsafe extern "C" fn(CkSessionHandle) -> CkRv>,
+    _sessions: [*const c_void; 4],
+    login: Option<unsafe extern "C" fn(CkSessionHandle, CkUlong, *const u8, CkUlong) -> CkRv>,
+    _objects: [*const c_void; 5],
+    get_attribute_value: Option<unsafe extern "C" fn(CkSessionHandle, CkObjectHandle, *mut CkAttribute, CkUlong) -> CkRv>,
+    _set_attribute_value: *const c_void,
+    find_objects_init: Option<unsafe extern "C" fn(CkSessionHandle, *mut CkAttribute, CkUlong) -> CkRv>,
+    find_objects: Option<unsafe extern "C" fn(CkSessionHandle, *mut CkObjectHandle, CkUlong, *mut CkUlong) -> CkRv>,
+    find_objects_final: Option<unsafe extern "C" fn(CkSessionHandle) -> CkRv>,
+    _crypt: [*const c_void; 13],
+    sign_init: Option<unsafe extern "C" fn(CkSessionHandle, *mut CkMechanism, CkObjectHandle) -> CkRv>,
+    sign: Option<unsafe extern "C" fn(CkSessionHandle, *const u8, CkUlong, *mut u8, *mut CkUlong) -> CkRv>,
+}
+
+/// Calls a function of the module, failing if it's missing or returns an error.
+macro_rules! call {
+    ($functions:expr, $name:ident ( $($arg:expr),* )) => {
+        call!($functions, $name($($arg),*), [])
+    };
+    ($functions:expr, $name:ident ( $($arg:expr),* ), [$($allowed:expr),*]) => {{
+        let function = (*$functions)
+            .$name
+            .ok_or_else(|| anyhow::anyhow!("PKCS#11 module lacks {}", stringify!($name)))?;
+        let rv = function($($arg),*);
+        if rv != CKR_OK $(&& rv != $allowed)* {
+            return Err(anyhow::anyhow!("PKCS#11 {} failed with error {:#x}", stringify!($name), rv));
+        }
+    }};
+}
+
+/// Signer using the RSA private key of a PKCS#11 token.
+pub struct Pkcs11Signer {
+    pk: RSAPublicKey,
+    library: *mut c_void,
+    functions: *const CkFunctionList,
+    /// Sessions run one operation at a time
+    session: Mutex<CkSessionHandle>,
+    key: CkObjectHandle,
+}
+
+// SAFETY: the module is initialized with CKF_OS_LOCKING_OK, so it may be called
+// from any thread, and the session is only used under its mutex.
+unsafe impl Send for Pkcs11Signer {}
+unsafe impl Sync for Pkcs11Signer {}
+
+impl Pkcs11Signer {
+    /// Opens a session on the token in `slot` (the first one with a token if
+    /// `None`), logs in with `pin` and finds the private key of `pk`.
+    pub fn new(module: &Path, slot: Option<u64>, pin: &str, pk: RSAPublicKey) -> Result<Self> {
+        let path = CString::new(module.as_os_str().as_bytes())?;
+        // SAFETY: dlopen and dlsym are given valid C strings, and
+        // C_GetFunctionList has the signature set by the PKCS#11 standard
+        let (library, functions) = unsafe {
+            let library = libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+            if library.is_null() {
+                return Err(anyhow::anyhow!("Failed to load the PKCS#11 module {}", module.display()));
+            }
+            let symbol = libc::dlsym(library, c"C_GetFunctionList".as_ptr());
+            if symbol.is_null() {
+                libc::dlclose(library);
+                return Err(anyhow::anyhow!("{} is not a PKCS#11 module", module.display()));
+            }
+            let get_function_list: unsafe extern "C" fn(*mut *const CkFunctionList) -> CkRv =
+                std::mem::transmute(symbol);
+            let mut functions = ptr::null();
+            if get_function_list(&mut functions) != CKR_OK || functions.is_null() {
+                libc::dlclose(library);
+                return Err(anyhow::anyhow!("C_GetFunctionList of {} failed", module.display()));
+            }
+            (library, functions)
+        };
+        // SAFETY: the function list is the module's, which stays loaded
+        unsafe { Self::with_functions(library, functions, slot, pin, pk) }
+    }
+
+    /// Signer calling `functions`, owning `library` if not null.
+    unsafe fn with_functions(
+        library: *mut c_void,
+        functions: *const CkFunctionList,
+        slot: Option<u64>,
+        pin: &str,
+        pk: RSAPublicKey,
+    ) -> Result<Self> {
+        let mut signer = Self { pk, library, functions, session: Mutex::new(0), key: 0 };
+        // SAFETY: the function list is called as the standard says
+        unsafe { signer.open(slot, pin)? };
+        Ok(signer)
+    }
+
+    unsafe fn open(&mut self, slot: Option<u64>, pin: &str) -> Result<()> {
+        let functions = self.functions;
+        unsafe {
+            let mut args = CkInitializeArgs {
+                create_mutex: ptr::null_mut(),
+                destroy_mutex: ptr::null_mut(),
+                lock_mutex: ptr::null_mut(),
+                unlock_mutex: ptr::null_mut(),
+                flags: CKF_OS_LOCKING_OK,
+                reserved: ptr::null_mut(),
+            };
+            call!(
+                functions,
+                initialize(&mut args as *mut CkInitializeArgs as *mut c_void),
+                [CKR_CRYPTOKI_ALREADY_INITIALIZED]
+            );
+
+            let slot = match slot {
+                Some(slot) => slot as CkSlotId,
+                None => {
+                    let mut count: CkUlong = 0;
+                    call!(functions, get_slot_list(1, ptr::null_mut(), &mut count));
+                    let mut slots = vec![0; count as usize];
+                    call!(functions, get_slot_list(1, slots.as_mut_ptr(), &mut count));
+                    *slots
+                        .first()
+                        .ok_or_else(|| anyhow::anyhow!("No PKCS#11 slot has a token"))?
+                }
+            };
+
+            let mut session: CkSessionHandle = 0;
+            call!(
+                functions,
+                open_session(slot, CKF_SERIAL_SESSION, ptr::null_mut(), ptr::null_mut(), &mut session)
+            );
+            *self.session.get_mut().unwrap() = session;
+            call!(
+                functions,
+                login(session, CKU_USER, pin.as_ptr(), pin.len() as CkUlong),
+                [CKR_USER_ALREADY_LOGGED_IN]
+            );
+
+            // The private key with the EC's modulus
+            let mut class = CKO_PRIVATE_KEY;
+            let mut template = [CkAttribute {
+                kind: CKA_CLASS,
+                value: &mut class as *mut CkUlong as *mut c_void,
+                len: std::mem::size_of::<CkUlong>() as CkUlong,
+            }];
+            call!(functions, find_objects_init(session, template.as_mut_ptr(), 1));
+            let mut keys = vec![0; MAX_KEYS];
+            let mut found: CkUlong = 0;
+            let result: Result<()> = (|| {
+                call!(functions, find_objects(session, keys.as_mut_ptr(), MAX_KEYS as CkUlong, &mut found));
+                Ok(())
+            })();
+            call!(functions, find_objects_final(session));
+            result?;
+            keys.truncate(found as usize);
+
+            let modulus = self.pk.as_ref().n().to_bytes_be();
+            for key in keys {
+                let mut value = vec![0u8; modulus.len() + 1];
+                let mut attribute = CkAttribute {
+                    kind: CKA_MODULUS,
+                    value: value.as_mut_ptr() as *mut c_void,
+                    len: value.len() as CkUlong,
+                };
+                let get = (*functions)
+                    .get_attribute_value
+                    .ok_or_else(|| anyhow::anyhow!("PKCS#11 module lacks get_attribute_value"))?;
+                if get(session, key, &mut attribute, 1) != CKR_OK {
+                    continue;
+                }
+                value.truncate(attribute.len as usize);
+                if BigUint::from_bytes_be(&value) == BigUint::from_bytes_be(&modulus) {
+                    self.key = key;
+                    return Ok(());
+                }
+            }
+        }
+        Err(anyhow::anyhow!("The PKCS#11 token has no private key for the EC's RSA public key"))
+    }
+
+    fn sign(&self, blind_msg: &[u8]) -> Result<Vec<u8>> {
+        let session = *self.session.lock().unwrap();
+        let functions = self.functions;
+        let mut signature = vec![0u8; blind_msg.len()];
+        let mut len = signature.len() as CkUlong;
+        let mut mechanism = CkMechanism { mechanism: CKM_RSA_X_509, parameter: ptr::null_mut(), len: 0 };
+        // SAFETY: the session lock is held for the whole operation, and the
+        // buffers outlive the calls
+        unsafe {
+            call!(functions, sign_init(session, &mut mechanism, self.key));
+            call!(
+                functions,
+                sign(session, blind_msg.as_ptr(), blind_msg.len() as CkUlong, signature.as_mut_ptr(), &mut len)
+            );
+        }
+        signature.truncate(len as usize);
+        Ok(signature)
+    }
+}
+
+impl BlindSigner for Pkcs11Signer {
+    fn public_key(&self) -> &RSAPublicKey {
+        &self.pk
+    }
+
+    fn blind_sign(&self, blind_msg: &[u8]) -> Result<BlindSignature, String> {
+        let n = BigUint::from_bytes_be(&self.pk.as_ref().n().to_bytes_be());
+        let exponent = BigUint::from_bytes_be(&self.pk.as_ref().e().to_bytes_be());
+        let modulus_bytes = self.pk.as_ref().size();
+        let msg = BigUint::from_bytes_be(blind_msg);
+        if blind_msg.len() != modulus_bytes || msg >= n {
+            return Err("unsupported blinded message".to_string());
+        }
+        let signature = self.sign(blind_msg).map_err(|e| e.to_string())?;
+        // Check the token's answer, like the in-memory signer does
+        let s = BigUint::from_bytes_be(&signature);
+        if s.modpow(&exponent, &n) != msg {
+            return Err("the PKCS#11 token returned an invalid signature".to_string());
+        }
+        let mut padded = vec![0u8; modulus_bytes - signature.len().min(modulus_bytes)];
+        padded.extend_from_slice(&signature);
+        Ok(BlindSignature(padded))
+    }
+}
+
+impl Drop for Pkcs11Signer {
+    fn drop(&mut self) {
+        let session = *self.session.get_mut().unwrap();
+        // SAFETY: nothing uses the module once the signer is gone
+        unsafe {
+            if let Some(close_session) = (*self.functions).close_session {
+                close_session(session);
+            }
+            if let Some(finalize) = (*self.functions).finalize {
+                finalize(ptr::null_mut());
+            }
+            if !self.library.is_null() {
+                libc::dlclose(self.library);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signer::LocalSigner;
+    use blind_rsa_signatures::{Options, SecretKey as RSASecretKey};
+    use criptocracia_protocol::TokenScheme;
+    use std::sync::OnceLock;
+
+    /// Key of the mock token
+    fn token_keys() -> &'static (RSAPublicKey, RSASecretKey) {
+        static KEYS: OnceLock<(RSAPublicKey, RSASecretKey)> = OnceLock::new();
+        KEYS.get_or_init(|| {
+            crate::util::load_keys_from_pem(include_str!("../ec_private.pem"), include_str!("../ec_public.pem"))
+                .unwrap()
+        })
+    }
+
+    unsafe extern "C" fn ok(_: *mut c_void) -> CkRv {
+        CKR_OK
+    }
+
+    unsafe extern "C" fn get_slot_list(_: u8, slots: *mut CkSlotId, count: *mut CkUlong) -> CkRv {
+        unsafe {
+            if !slots.is_null() {
+                *slots = 7;
+            }
+            *count = 1;
+        }
+        CKR_OK
+    }
+
+    unsafe extern "C" fn open_session(
+        slot: CkSlotId,
+        _: CkUlong,
+        _: *mut c_void,
+        _: *mut c_void,
+        session: *mut CkSessionHandle,
+    ) -> CkRv {
+        assert_eq!(slot, 7);
+        unsafe { *session = 1 };
+        CKR_OK
+    }
+
+    unsafe extern "C" fn close_session(_: CkSessionHandle) -> CkRv {
+        CKR_OK
+    }
+
+    unsafe extern "C" fn login(_: CkSessionHandle, _: CkUlong, pin: *const u8, len: CkUlong) -> CkRv {
+        let pin = unsafe { std::slice::from_raw_parts(pin, len as usize) };
+        if pin == b"1234" { CKR_OK } else { 0xa0 }
+    }
+
+    unsafe extern "C" fn find_objects_init(_: CkSessionHandle, _: *mut CkAttribute, _: CkUlong) -> CkRv {
+        CKR_OK
+    }
+
+    /// Two private keys: another one, then the EC's
+    unsafe extern "C" fn find_objects(
+        _: CkSessionHandle,
+        objects: *mut CkObjectHandle,
+        _: CkUlong,
+        count: *mut CkUlong,
+    ) -> CkRv {
+        unsafe {
+            *objects = 10;
+            *objects.add(1) = 11;
+            *count = 2;
+        }
+        CKR_OK
+    }
+
+    unsafe extern "C" fn find_objects_final(_: CkSessionHandle) -> CkRv {
+        CKR_OK
+    }
+
+    unsafe extern "C" fn get_attribute_value(
+        _: CkSessionHandle,
+        key: CkObjectHandle,
+        attribute: *mut CkAttribute,
+        _: CkUlong,
+    ) -> CkRv {
+        let modulus = if key == 11 { token_keys().0.as_ref().n().to_bytes_be() } else { vec![0xff; 256] };
+        unsafe {
+            std::ptr::copy_nonoverlapping(modulus.as_ptr(), (*attribute).value as *mut u8, modulus.len());
+            (*attribute).len = modulus.len() as CkUlong;
+        }
+        CKR_OK
+    }
+
+    unsafe extern "C" fn sign_init(_: CkSessionHandle, mechanism: *mut CkMechanism, key: CkObjectHandle) -> CkRv {
+        assert_eq!(unsafe { (*mechanism).mechanism }, CKM_RSA_X_509);
+        assert_eq!(key, 11);
+        CKR_OK
+    }
+
+    unsafe extern "C" fn sign(
+        _: CkSessionHandle,
+        data: *const u8,
+        len: CkUlong,
+        signature: *mut u8,
+        signature_len: *mut CkUlong,
+    ) -> CkRv {
+        let data = unsafe { std::slice::from_raw_parts(data, len as usize) };
+        let (pk, sk) = token_keys();
+        let signed = LocalSigner::new(pk.clone(), sk.clone()).blind_sign(data).unwrap();
+        unsafe {
+            std::ptr::copy_nonoverlapping(signed.0.as_ptr(), signature, signed.0.len());
+            *signature_len = signed.0.len() as CkUlong;
+        }
+        CKR_OK
+    }
+
+    fn mock_functions() -> CkFunctionList {
+        CkFunctionList {
+            version: CkVersion { major: 2, minor: 40 },
+            initialize: Some(ok),
+            finalize: Some(ok),
+            _info: [ptr::null(); 2],
+            get_slot_list: Some(get_slot_list),
+            _slots: [ptr::null(); 7],
+            open_session: Some(open_session),
+            close_session: Some(close_session),
+            _sessions: [ptr::null(); 4],
+            login: Some(login),
+            _objects: [ptr::null(); 5],
+            get_attribute_value: Some(get_attribute_value),
+            _set_attribute_value: ptr::null(),
+            find_objects_init: Some(find_objects_init),
+            find_objects: Some(find_objects),
+            find_objects_final: Some(find_objects_final),
+            _crypt: [ptr::null(); 13],
+            sign_init: Some(sign_init),
+            sign: Some(sign),
+        }
+    }
+
+    #[test]
+    fn test_signs_with_the_token_key() {
+        let functions = mock_functions();
+        let pk = token_keys().0.clone();
+        assert!(unsafe { Pkcs11Signer::with_functions(ptr::null_mut(), &functions, None, "0000", pk.clone()) }.is_err());
+        let signer = unsafe { Pkcs11Signer::with_functions(ptr::null_mut(), &functions, None, "1234", pk.clone()) }.unwrap();
+        assert_eq!(signer.key, 11);
+
+        // A voter's token signed by the token verifies with the EC's public key
+        let options = Options::default();
+        let msg = b"voter nonce hash";
+        let blinding = pk.blind(&mut rand::thread_rng(), msg, true, &options).unwrap();
+        let blind_sig = signer.blind_sign(&blinding.blind_msg).unwrap();
+        let token = pk.finalize(&blind_sig, &blinding.secret, blinding.msg_randomizer, msg, &options).unwrap();
+        assert!(signer.verify(TokenScheme::default(), &token, blinding.msg_randomizer, msg));
+
+        assert!(signer.blind_sign(&[0u8; 3]).is_err());
+    }
+}
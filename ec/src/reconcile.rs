@@ -0,0 +1,233 @@
+/*! reconcile.rs — Startup reconciliation of the relays with the database
+Once the EC is up, it fetches the election and results events it published
+before and compares them with its database, which is the record: elections
+missing on the relays, or with an older status there, are announced again,
+and results missing or behind the local tally are published again. What
+republishing can't fix, such as relays ahead of the database or elections
+it doesn't know, is logged as a warning to be looked into. */
+
+use anyhow::Result;
+use criptocracia_protocol::election::{REHEARSAL_TAG, parse_results};
+use criptocracia_protocol::ElectionEvent;
+use nostr_sdk::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::database::Database;
+use crate::election::{Election, Status};
+use crate::elections::Elections;
+use crate::handler::MessageHandler;
+use crate::identity::EcIdentity;
+use crate::relays::{EventConfig, RelayManager};
+
+/// Time given to the relays to connect and send the EC's events.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What to publish again, and what to warn about.
+#[derive(Debug, Default, PartialEq)]
+pub struct Reconciliation {
+    /// Elections missing on the relays or with an older status there
+    pub announce: Vec<String>,
+    /// Results missing on the relays or behind the local tally, by election
+    pub results: Vec<(String, Vec<(u8, u32)>)>,
+    /// Differences republishing doesn't fix
+    pub warnings: Vec<String>,
+}
+
+/// Fetches the EC's election and results events, then republishes what the
+/// relays miss and logs what they disagree on.
+pub async fn run(
+    client: &Client,
+    relays: &RelayManager,
+    identity: &EcIdentity,
+    db: &Database,
+    elections: &Elections,
+    handler: &Arc<MessageHandler>,
+) -> Result<()> {
+    let config = relays.events();
+    let filter = Filter::new()
+        .author(identity.public_key)
+        .kinds([Kind::Custom(config.kinds.election), Kind::Custom(config.kinds.results)]);
+    client.wait_for_connection(FETCH_TIMEOUT).await;
+    let events: Vec<Event> = client.fetch_events(filter, FETCH_TIMEOUT).await?.into_iter().collect();
+    let mut local = Vec::new();
+    for election in elections.all() {
+        local.push(election.read().await.clone());
+    }
+
+    let reconciliation = compare(&local, &events, config);
+    for warning in &reconciliation.warnings {
+        log::warn!("Relays and database disagree: {}", warning);
+    }
+    for election_id in &reconciliation.announce {
+        let Some(election) = local.iter().find(|election| &election.id == election_id) else {
+            continue;
+        };
+        log::info!(election_id = election_id.as_str(); "Announcing election {} again", election_id);
+        if let Err(e) = crate::publish_election_event(relays, identity, election, db).await {
+            log::error!("Failed to announce election {} again: {}", election_id, e);
+        }
+    }
+    for (election_id, results) in reconciliation.results {
+        log::info!(election_id = election_id.as_str(); "Publishing the results of election {} again", election_id);
+        handler.queue_results(&election_id, results).await;
+    }
+    log::info!("Reconciled {} election(s) with {} event(s) from the relays", local.len(), events.len());
+    Ok(())
+}
+
+/// Compares the elections of the database with the events on the relays.
+pub fn compare(local: &[Election], events: &[Event], config: &EventConfig) -> Reconciliation {
+    // The newest event of each election, of this EC's rehearsal or production runs only
+    let mut announced: HashMap<String, (Timestamp, ElectionEvent)> = HashMap::new();
+    let mut results: HashMap<String, (Timestamp, Vec<(u8, u32)>)> = HashMap::new();
+    for event in events {
+        let rehearsal = event.tags.hashtags().any(|tag| tag == REHEARSAL_TAG);
+        let Some(id) = event.tags.identifier() else {
+            continue;
+        };
+        if rehearsal != config.rehearsal {
+            continue;
+        }
+        if event.kind == Kind::Custom(config.kinds.election) {
+            if let Ok(election) = ElectionEvent::from_json(&event.content) {
+                if announced.get(id).is_none_or(|(at, _)| *at < event.created_at) {
+                    announced.insert(id.to_string(), (event.created_at, election));
+                }
+            }
+        } else if event.kind == Kind::Custom(config.kinds.results) {
+            if let Ok(counts) = parse_results(&event.content) {
+                if results.get(id).is_none_or(|(at, _)| *at < event.created_at) {
+                    results.insert(id.to_string(), (event.created_at, counts));
+                }
+            }
+        }
+    }
+
+    let mut reconciliation = Reconciliation::default();
+    for election in local {
+        match announced.remove(&election.id) {
+            None => reconciliation.announce.push(election.id.clone()),
+            Some((_, remote)) if remote.status != election.status => {
+                if stage(remote.status) > stage(election.status) {
+                    reconciliation.warnings.push(format!(
+                        "election {} is {} on the relays but {} in the database",
+                        election.id,
+                        remote.status.as_str(),
+                        election.status.as_str()
+                    ));
+                } else {
+                    reconciliation.announce.push(election.id.clone());
+                }
+            }
+            Some(_) => {}
+        }
+
+        // Encrypted ballots have no results until they are counted
+        let counts = election.vote_counts();
+        if election.awaiting_tally() || counts.is_empty() {
+            continue;
+        }
+        match results.get(&election.id) {
+            Some((_, remote)) if *remote == counts => {}
+            Some((_, remote)) if total(remote) > total(&counts) => reconciliation.warnings.push(format!(
+                "election {} has {} vote(s) on the relays but {} in the database",
+                election.id,
+                total(remote),
+                total(&counts)
+            )),
+            _ => reconciliation.results.push((election.id.clone(), counts)),
+        }
+    }
+    let mut unknown: Vec<String> = announced.into_keys().collect();
+    unknown.sort();
+    for id in unknown {
+        reconciliation.warnings.push(format!("election {} is on the relays but not in the database", id));
+    }
+    reconciliation
+}
+
+/// How far along an election is, statuses only moving forward
+fn stage(status: Status) -> u8 {
+    match status {
+        Status::Open => 0,
+        Status::InProgress => 1,
+        Status::Finished | Status::Canceled => 2,
+    }
+}
+
+fn total(counts: &[(u8, u32)]) -> u32 {
+    counts.iter().map(|(_, votes)| votes).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Candidate;
+    use criptocracia_protocol::election::encode_results;
+    use num_bigint_dig::BigUint;
+
+    fn election(name: &str, status: Status, votes: &[u8]) -> Election {
+        let candidates = vec![Candidate::new(1, "Alice"), Candidate::new(2, "Bob")];
+        let mut election = Election::new(name.to_string(), candidates, 1000, 3600, "key".to_string());
+        election.status = Status::InProgress;
+        for (i, vote) in votes.iter().enumerate() {
+            election.receive_vote(BigUint::from(i), *vote).unwrap();
+        }
+        election.status = status;
+        election
+    }
+
+    fn event(keys: &Keys, kind: u16, id: &str, content: String, at: u64, config: &EventConfig) -> Event {
+        EventBuilder::new(Kind::Custom(kind), content)
+            .tag(Tag::identifier(id))
+            .tags(config.rehearsal_tag())
+            .custom_created_at(Timestamp::from(at))
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_compare() {
+        let keys = Keys::generate();
+        let config = EventConfig::default();
+        let (election_kind, results_kind) = (config.kinds.election, config.kinds.results);
+        let missing = election("Missing", Status::Open, &[]);
+        let stale = election("Stale", Status::Finished, &[1, 2, 2]);
+        let ahead = election("Ahead", Status::InProgress, &[1]);
+        let synced = election("Synced", Status::InProgress, &[1]);
+        let announce = |election: &Election, status: Status, at: u64| {
+            let mut remote = election.to_event();
+            remote.status = status;
+            event(&keys, election_kind, &election.id, remote.as_json(), at, &config)
+        };
+        let rehearsal = EventConfig { rehearsal: true, ..EventConfig::default() };
+        let events = vec![
+            // An older announcement is superseded by a newer one on another relay
+            announce(&stale, Status::Open, 100),
+            announce(&stale, Status::InProgress, 200),
+            event(&keys, results_kind, &stale.id, encode_results(&[(1, 1), (2, 1)]), 200, &config),
+            announce(&ahead, Status::Finished, 200),
+            event(&keys, results_kind, &ahead.id, encode_results(&[(1, 2)]), 200, &config),
+            announce(&synced, Status::InProgress, 200),
+            event(&keys, results_kind, &synced.id, encode_results(&[(1, 1)]), 200, &config),
+            event(&keys, election_kind, "gone", synced.to_event().as_json(), 200, &config),
+            // Events of a rehearsal are not the production ones
+            event(&keys, election_kind, &missing.id, missing.as_json_string(), 300, &rehearsal),
+        ];
+
+        let local = [missing.clone(), stale.clone(), ahead.clone(), synced];
+        let reconciliation = compare(&local, &events, &config);
+        assert_eq!(reconciliation.announce, vec![missing.id.clone(), stale.id.clone()]);
+        assert_eq!(reconciliation.results, vec![(stale.id.clone(), vec![(1, 1), (2, 2)])]);
+        assert_eq!(reconciliation.warnings.len(), 3);
+        assert!(reconciliation.warnings[0].contains(&format!("{} is finished on the relays", ahead.id)));
+        assert!(reconciliation.warnings[1].contains("2 vote(s) on the relays but 1"));
+        assert!(reconciliation.warnings[2].contains("gone is on the relays but not in the database"));
+
+        // The rehearsal only sees its own events
+        let reconciliation = compare(std::slice::from_ref(&missing), &events, &rehearsal);
+        assert_eq!(reconciliation, Reconciliation::default());
+    }
+}
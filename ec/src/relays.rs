@@ -0,0 +1,513 @@
+/*! relays.rs — Relay health monitoring and failover
+Sends the EC's events to every relay, keeps track of the relays that didn't
+accept each one, reconnects unhealthy relays with backoff and publishes the
+missed events again once a relay is back. Events no relay accepted go to the
+outbox table, so they are retried even after a restart. */
+
+use anyhow::Result;
+use nostr_sdk::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+
+use crate::database::Database;
+use criptocracia_protocol::EventKinds;
+use criptocracia_protocol::election::REHEARSAL_TAG;
+
+/// Time between health checks of the relays.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time given to an unhealthy relay to connect.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// First and longest wait between reconnection attempts of a relay.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Most events kept for a relay that is down, the oldest dropped first.
+const MAX_PENDING_EVENTS: usize = 500;
+
+/// Most outbox events retried on each health check.
+const OUTBOX_BATCH: u32 = 100;
+
+/// Kinds of the events the EC publishes and the days each one is kept by
+/// the relays before it expires.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventConfig {
+    pub kinds: EventKinds,
+    pub election_ttl_days: u64,
+    pub results_ttl_days: u64,
+    pub ballot_ttl_days: u64,
+    /// Whether the events are those of a rehearsal (`--dry-run`)
+    pub rehearsal: bool,
+}
+
+impl Default for EventConfig {
+    fn default() -> Self {
+        Self {
+            kinds: EventKinds::default(),
+            election_ttl_days: 15,
+            results_ttl_days: 5,
+            ballot_ttl_days: 15,
+            rehearsal: false,
+        }
+    }
+}
+
+impl EventConfig {
+    /// Checks the kinds and that no event expires as soon as it is published
+    pub fn validate(&self) -> Result<()> {
+        self.kinds.validate().map_err(|e| anyhow::anyhow!(e))?;
+        if self.election_ttl_days == 0 || self.results_ttl_days == 0 || self.ballot_ttl_days == 0 {
+            return Err(anyhow::anyhow!("Event lifetimes must be at least one day"));
+        }
+        Ok(())
+    }
+
+    /// Tag marking the events of a rehearsal, none otherwise
+    pub fn rehearsal_tag(&self) -> Option<Tag> {
+        self.rehearsal.then(|| Tag::hashtag(REHEARSAL_TAG))
+    }
+
+    /// Expiration tag of an event kept `days` from now
+    pub fn expiration(days: u64) -> Tag {
+        Tag::expiration(Timestamp::now() + days * 24 * 60 * 60)
+    }
+}
+
+/// What the EC knows about one relay.
+#[derive(Debug, Default)]
+struct RelayHealth {
+    healthy: bool,
+    /// Health checks failed in a row
+    failures: u32,
+    last_error: Option<String>,
+    /// Unix time of the next reconnection attempt
+    next_attempt: u64,
+    /// Events the relay didn't accept, published again when it recovers
+    pending: Vec<Event>,
+}
+
+impl RelayHealth {
+    fn queue(&mut self, event: &Event) {
+        if self.pending.iter().any(|e| e.id == event.id) {
+            return;
+        }
+        if self.pending.len() >= MAX_PENDING_EVENTS {
+            let dropped = self.pending.remove(0);
+            log::warn!("Too many events pending, dropped {}", dropped.id);
+        }
+        self.pending.push(event.clone());
+    }
+}
+
+/// Health of a relay, as reported by the ServerInfo RPC.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelayReport {
+    pub url: String,
+    /// Connection status of the relay pool
+    pub status: String,
+    pub healthy: bool,
+    pub failures: u32,
+    /// Average ping latency
+    pub latency_ms: Option<u64>,
+    pub pending_events: usize,
+    pub last_error: Option<String>,
+}
+
+/// Nostr client of the EC with the health of each of its relays.
+pub struct RelayManager {
+    client: Client,
+    /// Records the relays that accept election and results events
+    db: Arc<Database>,
+    health: Mutex<HashMap<String, RelayHealth>>,
+    events: EventConfig,
+}
+
+impl RelayManager {
+    pub fn new(client: Client, db: Arc<Database>) -> Self {
+        Self {
+            client,
+            db,
+            health: Mutex::new(HashMap::new()),
+            events: EventConfig::default(),
+        }
+    }
+
+    /// Kinds and lifetimes of the events to publish
+    pub fn with_events(mut self, events: EventConfig) -> Self {
+        self.events = events;
+        self
+    }
+
+    pub fn events(&self) -> &EventConfig {
+        &self.events
+    }
+
+    /// Sends an event to every relay and returns the URLs of those that
+    /// accepted it, sorted. The others get it again once they are healthy.
+    /// When no relay accepts it the event is kept in the outbox instead, and
+    /// only failing to store it there is an error.
+    pub async fn send_event(&self, event: &Event) -> Result<Vec<String>> {
+        let relays = match self.client.send_event(event).await {
+            Ok(output) if !output.success.is_empty() => self.accepted(event, &output).await,
+            Ok(output) => {
+                let errors: Vec<String> = output.failed.values().cloned().collect();
+                self.queue_in_outbox(event, &errors.join(", ")).await?;
+                Vec::new()
+            }
+            Err(e) => {
+                self.queue_in_outbox(event, &e.to_string()).await?;
+                Vec::new()
+            }
+        };
+        Ok(relays)
+    }
+
+    /// Keeps the event for the relays that didn't accept it and returns the
+    /// URLs of those that did, sorted.
+    async fn accepted(&self, event: &Event, output: &Output<EventId>) -> Vec<String> {
+        let mut health = self.health.lock().await;
+        for (url, error) in &output.failed {
+            log::warn!("Relay {} didn't accept event {}: {}", url, event.id, error);
+            let relay = health.entry(url.to_string()).or_default();
+            relay.last_error = Some(error.clone());
+            relay.queue(event);
+        }
+        let mut relays: Vec<String> = output.success.iter().map(|url| url.to_string()).collect();
+        relays.sort();
+        relays
+    }
+
+    async fn queue_in_outbox(&self, event: &Event, error: &str) -> Result<()> {
+        log::error!("No relay accepted event {}, keeping it in the outbox: {}", event.id, error);
+        self.db
+            .save_outbox_event(&event.id.to_hex(), event.kind.as_u16(), &event.as_json())
+            .await
+    }
+
+    /// Publishes the outbox events that are due again. Those a relay accepts
+    /// leave the outbox, the others wait longer after each attempt. Expired
+    /// events are dropped.
+    pub async fn flush_outbox(&self) -> Result<()> {
+        let now = Timestamp::now();
+        for record in self.db.get_due_outbox_events(now.as_u64() as i64, OUTBOX_BATCH).await? {
+            let event = match Event::from_json(&record.event_json) {
+                Ok(event) => event,
+                Err(e) => {
+                    log::error!("Dropping unreadable outbox event {}: {}", record.event_id, e);
+                    self.db.delete_outbox_event(&record.event_id).await?;
+                    continue;
+                }
+            };
+            if event.is_expired_at(&now) {
+                log::warn!("Dropping expired outbox event {}", event.id);
+                self.db.delete_outbox_event(&record.event_id).await?;
+                continue;
+            }
+
+            let error = match self.client.send_event(&event).await {
+                Ok(output) if !output.success.is_empty() => {
+                    log::info!("Outbox event {} published after {} attempt(s)", event.id, record.attempts + 1);
+                    for url in &output.success {
+                        self.record_published(&event, url).await;
+                    }
+                    self.accepted(&event, &output).await;
+                    self.db.delete_outbox_event(&record.event_id).await?;
+                    continue;
+                }
+                Ok(output) => output.failed.values().cloned().collect::<Vec<_>>().join(", "),
+                Err(e) => e.to_string(),
+            };
+            let attempts = u32::try_from(record.attempts).unwrap_or(u32::MAX);
+            let next_attempt = now.as_u64() + backoff(attempts).as_secs();
+            log::warn!("Outbox event {} still not accepted: {}", event.id, error);
+            self.db
+                .reschedule_outbox_event(&record.event_id, next_attempt as i64, &error)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Checks the connection of every relay. Healthy relays get the events
+    /// they missed; unhealthy ones are reconnected, waiting longer after each
+    /// failed check.
+    pub async fn check(&self) {
+        let now = Timestamp::now().as_u64();
+        for (url, relay) in self.client.relays().await {
+            let key = url.to_string();
+            if relay.status() == RelayStatus::Connected {
+                let pending = {
+                    let mut health = self.health.lock().await;
+                    let entry = health.entry(key.clone()).or_default();
+                    if !entry.healthy {
+                        log::info!("Relay {} is healthy", url);
+                    }
+                    entry.healthy = true;
+                    entry.failures = 0;
+                    std::mem::take(&mut entry.pending)
+                };
+                self.republish(&url, pending).await;
+                continue;
+            }
+
+            let reconnect = {
+                let mut health = self.health.lock().await;
+                let entry = health.entry(key).or_default();
+                if entry.healthy {
+                    log::warn!("Relay {} is unhealthy ({})", url, relay.status());
+                }
+                entry.healthy = false;
+                entry.failures = entry.failures.saturating_add(1);
+                let due = now >= entry.next_attempt;
+                if due {
+                    entry.next_attempt = now + backoff(entry.failures - 1).as_secs();
+                }
+                due
+            };
+            if reconnect {
+                log::info!("Reconnecting relay {}", url);
+                if let Err(e) = relay.try_connect(CONNECT_TIMEOUT).await {
+                    log::warn!("Failed to reconnect relay {}: {}", url, e);
+                    if let Some(entry) = self.health.lock().await.get_mut(url.as_str()) {
+                        entry.last_error = Some(e.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Publishes again on a relay the events it missed, keeping those it
+    /// still doesn't accept.
+    async fn republish(&self, url: &RelayUrl, events: Vec<Event>) {
+        for event in events {
+            match self.client.send_event_to([url.clone()], &event).await {
+                Ok(output) if output.success.contains(url) => {
+                    log::info!("Event {} published again on {}", event.id, url);
+                    self.record_published(&event, url).await;
+                }
+                result => {
+                    let error = match result {
+                        Ok(output) => output.failed.get(url).cloned().unwrap_or_default(),
+                        Err(e) => e.to_string(),
+                    };
+                    log::warn!("Event {} still not accepted by {}: {}", event.id, url, error);
+                    let mut health = self.health.lock().await;
+                    let entry = health.entry(url.to_string()).or_default();
+                    entry.last_error = Some(error);
+                    entry.queue(&event);
+                }
+            }
+        }
+    }
+
+    /// Adds a relay to the ones holding an election or results event.
+    async fn record_published(&self, event: &Event, url: &RelayUrl) {
+        let kind = event.kind.as_u16();
+        if kind != self.events.kinds.election && kind != self.events.kinds.results {
+            return;
+        }
+        let Some(election_id) = event.tags.identifier() else {
+            return;
+        };
+        if let Err(e) = self
+            .db
+            .save_published_event(&event.id.to_hex(), election_id, kind, &[url.to_string()])
+            .await
+        {
+            log::error!("Failed to record event {} published on {}: {}", event.id, url, e);
+        }
+    }
+
+    /// Checks the relays and flushes the outbox for as long as it runs.
+    pub async fn monitor(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.check().await;
+            if let Err(e) = self.flush_outbox().await {
+                log::error!("Failed to flush the outbox: {}", e);
+            }
+        }
+    }
+
+    /// Health of every relay of the client, sorted by URL.
+    pub async fn reports(&self) -> Vec<RelayReport> {
+        let health = self.health.lock().await;
+        let mut reports: Vec<RelayReport> = self
+            .client
+            .relays()
+            .await
+            .iter()
+            .map(|(url, relay)| {
+                let entry = health.get(url.as_str());
+                RelayReport {
+                    url: url.to_string(),
+                    status: relay.status().to_string(),
+                    healthy: entry.is_some_and(|e| e.healthy),
+                    failures: entry.map_or(0, |e| e.failures),
+                    latency_ms: relay.stats().latency().map(|l| l.as_millis() as u64),
+                    pending_events: entry.map_or(0, |e| e.pending.len()),
+                    last_error: entry.and_then(|e| e.last_error.clone()),
+                }
+            })
+            .collect();
+        reports.sort_by(|a, b| a.url.cmp(&b.url));
+        reports
+    }
+
+    /// Replaces the relays of the client with `urls`, subscribing the new
+    /// ones to `subscription`. The relays kept stay connected, so no message
+    /// sent to them is missed. Returns the relays added and removed.
+    pub async fn set_relays(&self, urls: &[RelayUrl], subscription: Filter) -> Result<(Vec<RelayUrl>, Vec<RelayUrl>)> {
+        if urls.is_empty() {
+            return Err(anyhow::anyhow!("At least one relay is required"));
+        }
+        let current: Vec<RelayUrl> = self.client.relays().await.into_keys().collect();
+        let added: Vec<RelayUrl> = urls.iter().filter(|url| !current.contains(url)).cloned().collect();
+        let removed: Vec<RelayUrl> = current.into_iter().filter(|url| !urls.contains(url)).collect();
+        for url in &added {
+            self.client.add_relay(url).await?;
+            self.client.connect_relay(url).await?;
+        }
+        if !added.is_empty() {
+            self.client.subscribe_to(added.clone(), subscription, None).await?;
+        }
+        let mut health = self.health.lock().await;
+        for url in &removed {
+            self.client.force_remove_relay(url).await?;
+            health.remove(url.as_str());
+        }
+        Ok((added, removed))
+    }
+}
+
+/// Wait before the given reconnection attempt, starting at 0:
+/// doubles from `MIN_BACKOFF` up to `MAX_BACKOFF`.
+fn backoff(attempt: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        assert_eq!(backoff(0), MIN_BACKOFF);
+        assert_eq!(backoff(2), MIN_BACKOFF * 4);
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_pending_events_are_bounded() {
+        let keys = Keys::generate();
+        let mut health = RelayHealth::default();
+        let events: Vec<Event> = (0..=MAX_PENDING_EVENTS)
+            .map(|i| EventBuilder::text_note(i.to_string()).sign_with_keys(&keys).unwrap())
+            .collect();
+        for event in &events {
+            health.queue(event);
+        }
+        health.queue(&events[MAX_PENDING_EVENTS]);
+        assert_eq!(health.pending.len(), MAX_PENDING_EVENTS);
+        assert_eq!(health.pending[0].id, events[1].id);
+    }
+
+    #[test]
+    fn test_event_config() {
+        let config = EventConfig::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.kinds.election, 35_000);
+
+        let kinds = EventKinds { election: 36_000, results: 36_001, ballot: 36_002, delta: 36_003, turnout: 36_004 };
+        assert!(EventConfig { kinds, ..EventConfig::default() }.validate().is_ok());
+        assert!(EventConfig { kinds: EventKinds { ballot: 36_000, ..kinds }, ..EventConfig::default() }.validate().is_err());
+        assert!(EventConfig { results_ttl_days: 0, ..EventConfig::default() }.validate().is_err());
+
+        assert!(config.rehearsal_tag().is_none());
+        let rehearsal = EventConfig { rehearsal: true, ..EventConfig::default() }.rehearsal_tag().unwrap();
+        assert_eq!(rehearsal.as_slice(), ["t", REHEARSAL_TAG]);
+
+        let expiration = EventConfig::expiration(5);
+        assert_eq!(expiration.kind(), TagKind::Expiration);
+        let expires_at: u64 = expiration.content().unwrap().parse().unwrap();
+        assert!(expires_at >= Timestamp::now().as_u64() + 5 * 24 * 60 * 60 - 1);
+    }
+
+    #[tokio::test]
+    async fn test_reports_of_new_relays() {
+        let client = Client::default();
+        client.add_relay("wss://relay.mostro.network").await.unwrap();
+        client.add_relay("wss://nos.lol").await.unwrap();
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db = Arc::new(Database::new(temp_file.path()).await.unwrap());
+        let relays = RelayManager::new(client, db);
+
+        let reports = relays.reports().await;
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].url, "wss://nos.lol");
+        assert_eq!(reports[0].status, "Initialized");
+        assert!(!reports[0].healthy);
+        assert_eq!(reports[1].pending_events, 0);
+    }
+
+    #[tokio::test]
+    async fn test_unaccepted_events_go_to_outbox() {
+        let keys = Keys::generate();
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db = Arc::new(Database::new(temp_file.path()).await.unwrap());
+        // No relays, so nothing is accepted
+        let relays = RelayManager::new(Client::default(), db.clone());
+
+        let event = EventBuilder::text_note("results").sign_with_keys(&keys).unwrap();
+        assert!(relays.send_event(&event).await.unwrap().is_empty());
+        assert_eq!(db.count_outbox_events().await.unwrap(), 1);
+
+        relays.flush_outbox().await.unwrap();
+        let now = Timestamp::now().as_u64() as i64;
+        assert!(db.get_due_outbox_events(now, 10).await.unwrap().is_empty());
+        let later = db.get_due_outbox_events(now + MIN_BACKOFF.as_secs() as i64, 10).await.unwrap();
+        assert_eq!(later[0].attempts, 1);
+        assert!(later[0].last_error.is_some());
+
+        let expired = EventBuilder::text_note("old")
+            .tag(Tag::expiration(Timestamp::from(1)))
+            .sign_with_keys(&keys)
+            .unwrap();
+        relays.send_event(&expired).await.unwrap();
+        relays.flush_outbox().await.unwrap();
+        assert_eq!(db.count_outbox_events().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_set_relays() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db = Arc::new(Database::new(temp_file.path()).await.unwrap());
+        let mut urls = Vec::new();
+        for _ in 0..2 {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            urls.push(RelayUrl::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap());
+            let relay = Arc::new(crate::local_relay::LocalRelay::new(Arc::clone(&db)).await.unwrap());
+            tokio::spawn(relay.serve(listener));
+        }
+        let client = Client::new(Keys::generate());
+        client.add_relay(&urls[0]).await.unwrap();
+        client.connect().await;
+        let relays = RelayManager::new(client.clone(), db);
+        let filter = Filter::new().kind(Kind::GiftWrap).limit(0);
+
+        let (added, removed) = relays.set_relays(&urls, filter.clone()).await.unwrap();
+        assert_eq!((added, removed), (vec![urls[1].clone()], vec![]));
+        assert_eq!(client.relay(&urls[1]).await.unwrap().subscriptions().await.len(), 1);
+
+        let (added, removed) = relays.set_relays(&urls[1..], filter.clone()).await.unwrap();
+        assert_eq!((added, removed), (vec![], vec![urls[0].clone()]));
+        assert_eq!(client.relays().await.into_keys().collect::<Vec<_>>(), vec![urls[1].clone()]);
+        assert!(relays.set_relays(&[], filter).await.is_err());
+    }
+}
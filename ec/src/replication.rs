@@ -0,0 +1,326 @@
+/*! replication.rs — Hot standby replication
+Every change to the elections, voter rolls, used tokens, ballots and the
+rest of the state an EC needs is recorded in its replication log. A standby
+EC, started with `--standby-of <admin URL>`, streams that log from the
+primary over gRPC and applies it to its own database, after a full copy when
+it's new or too far behind. Meanwhile it takes no messages and serves no
+admin API. With `--takeover-after`, once the primary has been unreachable
+that long, the standby starts as the EC, with the same keys; otherwise it
+takes over when restarted without `--standby-of`. */
+
+use anyhow::Result;
+use futures_util::Stream;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tonic::{Status, Streaming};
+use tonic::transport::Endpoint;
+
+use crate::database::{Database, REPLICATED_TABLES, ReplicatedChange};
+use crate::grpc::admin_proto::admin_service_client::AdminServiceClient;
+use crate::grpc::admin_proto::{ReplicateRequest, ReplicationBatch, RowChange};
+
+/// Rows sent in one batch
+const BATCH_SIZE: u32 = 500;
+
+/// Time between looks at the replication log for new changes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// An idle primary sends an empty batch this often, to show it's alive
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A standby hearing nothing from the primary for this long reconnects
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Time between the standby's attempts to reach the primary
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long changes stay in the replication log. A standby further behind
+/// gets a full copy.
+pub const LOG_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Stream of changes sent to a standby
+struct Changes {
+    db: Arc<Database>,
+    shutdown: Option<watch::Receiver<bool>>,
+    source: i64,
+    /// Last change sent, or to send once the full copy is complete
+    cursor: i64,
+    /// Table and rowid the full copy is at
+    copy: Option<(usize, i64)>,
+    reset: bool,
+    last_sent: Instant,
+}
+
+/// Streams the changes after the standby's cursor, with a full copy first
+/// when the cursor isn't into this EC's replication log, until `shutdown`.
+pub async fn changes(
+    db: Arc<Database>,
+    request: ReplicateRequest,
+    shutdown: Option<watch::Receiver<bool>>,
+) -> Result<impl Stream<Item = Result<ReplicationBatch, Status>>> {
+    let source = db.get_replication_source().await?;
+    let (first, last) = db.get_replication_log_range().await?;
+    let following = request.source == source && request.after >= first - 1 && request.after <= last;
+    let changes = Changes {
+        db,
+        shutdown,
+        source,
+        cursor: if following { request.after } else { last },
+        copy: (!following).then_some((0, 0)),
+        reset: !following,
+        last_sent: Instant::now(),
+    };
+    Ok(futures_util::stream::unfold(Some(changes), |changes| async move {
+        let mut changes = changes?;
+        match changes.next().await {
+            Ok(Some(batch)) => Some((Ok(batch), Some(changes))),
+            Ok(None) => None,
+            Err(e) => Some((Err(Status::internal(format!("Replication failed: {}", e))), None)),
+        }
+    }))
+}
+
+impl Changes {
+    /// Next batch of the full copy or of the log, None once shut down
+    async fn next(&mut self) -> Result<Option<ReplicationBatch>> {
+        if let Some((table, after)) = self.copy {
+            return self.copy_rows(table, after).await.map(Some);
+        }
+        loop {
+            if self.shutdown.as_ref().is_some_and(|shutdown| *shutdown.borrow()) {
+                return Ok(None);
+            }
+            let changes = self.db.get_replication_changes(self.cursor, BATCH_SIZE).await?;
+            if let Some(change) = changes.last() {
+                self.cursor = change.seq;
+            }
+            if !changes.is_empty() || self.last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+                return Ok(Some(self.batch(changes, false)));
+            }
+            let stopped = async {
+                match &mut self.shutdown {
+                    Some(shutdown) => {
+                        let _ = shutdown.changed().await;
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                _ = stopped => return Ok(None),
+            }
+        }
+    }
+
+    /// Next rows of the full copy, table after table
+    async fn copy_rows(&mut self, mut table: usize, mut after: i64) -> Result<ReplicationBatch> {
+        while let Some(name) = REPLICATED_TABLES.get(table) {
+            let rows = self.db.get_replicated_rows(name, after, BATCH_SIZE).await?;
+            let Some((rowid, _)) = rows.last() else {
+                table += 1;
+                after = 0;
+                continue;
+            };
+            self.copy = Some((table, *rowid));
+            let changes = rows
+                .into_iter()
+                .map(|(_, row)| ReplicatedChange { seq: 0, table: name.to_string(), deleted: false, row })
+                .collect();
+            return Ok(self.batch(changes, true));
+        }
+        // The changes made during the copy follow it
+        self.copy = None;
+        Ok(self.batch(Vec::new(), false))
+    }
+
+    fn batch(&mut self, changes: Vec<ReplicatedChange>, partial: bool) -> ReplicationBatch {
+        self.last_sent = Instant::now();
+        ReplicationBatch {
+            source: self.source,
+            reset: std::mem::take(&mut self.reset),
+            changes: changes
+                .into_iter()
+                .map(|change| RowChange { table: change.table, deleted: change.deleted, row: change.row })
+                .collect(),
+            cursor: self.cursor,
+            partial,
+        }
+    }
+}
+
+/// Follows the primary EC whose admin API is at `primary`, applying its
+/// changes. Returns once the primary has been unreachable for
+/// `takeover_after`, for this EC to take over; without it, follows until
+/// the future is dropped.
+pub async fn follow(db: &Database, primary: &str, takeover_after: Option<Duration>) -> Result<()> {
+    let mut last_contact = Instant::now();
+    loop {
+        match receive(db, primary, &mut last_contact).await {
+            Ok(()) => log::warn!("The primary EC at {} ended the replication", primary),
+            Err(e) => log::warn!("Lost the primary EC at {}: {:#}", primary, e),
+        }
+        if let Some(after) = takeover_after {
+            let unreachable = last_contact.elapsed();
+            if unreachable >= after {
+                if db.get_replication_cursor().await?.is_some() {
+                    log::warn!("The primary EC was unreachable for {} seconds, taking over", unreachable.as_secs());
+                    return Ok(());
+                }
+                log::error!("Can't take over from the primary EC without a full copy of its state");
+            }
+        }
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
+
+/// Applies the changes streamed by the primary until the stream ends or
+/// fails, `last_contact` being when the primary was last there
+async fn receive(db: &Database, primary: &str, last_contact: &mut Instant) -> Result<()> {
+    let channel = Endpoint::from_shared(primary.to_string())?
+        .connect_timeout(SILENCE_TIMEOUT)
+        .connect()
+        .await?;
+    let (source, after) = db.get_replication_cursor().await?.unwrap_or((0, 0));
+    let request = ReplicateRequest { source, after };
+    let mut stream = AdminServiceClient::new(channel).replicate(request).await?.into_inner();
+    log::info!("Following the primary EC at {}", primary);
+    let streamed = apply_stream(db, &mut stream).await;
+    *last_contact = Instant::now();
+    streamed
+}
+
+async fn apply_stream(db: &Database, stream: &mut Streaming<ReplicationBatch>) -> Result<()> {
+    let mut copying = false;
+    loop {
+        let batch = match tokio::time::timeout(SILENCE_TIMEOUT, stream.message()).await {
+            Ok(batch) => batch?,
+            Err(_) => {
+                return Err(anyhow::anyhow!("Nothing heard for {} seconds", SILENCE_TIMEOUT.as_secs()));
+            }
+        };
+        let Some(batch) = batch else {
+            return Ok(());
+        };
+        if batch.reset {
+            log::info!("Copying the state of the primary EC");
+            copying = true;
+        }
+        apply(db, &batch).await?;
+        if copying && !batch.partial {
+            log::info!("Copied the state of the primary EC, following its changes");
+            copying = false;
+        }
+    }
+}
+
+/// Applies a batch of the primary's changes to the database
+async fn apply(db: &Database, batch: &ReplicationBatch) -> Result<()> {
+    let changes: Vec<ReplicatedChange> = batch
+        .changes
+        .iter()
+        .map(|change| ReplicatedChange {
+            seq: 0,
+            table: change.table.clone(),
+            deleted: change.deleted,
+            row: change.row.clone(),
+        })
+        .collect();
+    let cursor = (!batch.partial).then_some((batch.source, batch.cursor));
+    db.apply_replication(batch.reset, &changes, cursor).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::SignatureRequestRecord;
+    use crate::election::Election;
+    use crate::types::{Candidate, Voter};
+    use futures_util::StreamExt;
+    use tempfile::NamedTempFile;
+
+    async fn create_test_db() -> (Arc<Database>, NamedTempFile) {
+        let temp_file = NamedTempFile::new().unwrap();
+        let db = Database::new(temp_file.path()).await.unwrap();
+        (Arc::new(db), temp_file)
+    }
+
+    #[tokio::test]
+    async fn test_replicate() {
+        let (primary, _primary_file) = create_test_db().await;
+        let (standby, _standby_file) = create_test_db().await;
+
+        let election = Election::new("Replicated".to_string(), vec![Candidate::new(1, "Alice")], 1000, 3600, "key".to_string());
+        primary.upsert_election(&election).await.unwrap();
+        let voters = vec![Voter::new("Alice", "00001001063e6bf1b28f6514ac651afef7f51b2a792f0416a5e8273daa9eea6e")];
+        primary.save_election_voters(&election.id, &voters).await.unwrap();
+        let request = SignatureRequestRecord {
+            id: "req1".to_string(),
+            election_id: election.id.clone(),
+            voter_pubkey: "voter".to_string(),
+            message: "{}".to_string(),
+            blinded_message: vec![0, 1, 2],
+            created_at: 100,
+            shares: vec![],
+        };
+        primary.save_signature_request(&request).await.unwrap();
+        primary.save_signature_share("req1", 1, b"", b"proof").await.unwrap();
+        // Rows the standby has but the primary doesn't go with the full copy
+        let gone = Election::new("Gone".to_string(), vec![Candidate::new(1, "Bob")], 1000, 3600, "key".to_string());
+        standby.upsert_election(&gone).await.unwrap();
+
+        // A new standby gets a full copy first
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut stream = Box::pin(changes(Arc::clone(&primary), ReplicateRequest::default(), Some(shutdown_rx.clone())).await.unwrap());
+        let first = stream.next().await.unwrap().unwrap();
+        assert!(first.reset && first.partial);
+        apply(&standby, &first).await.unwrap();
+        loop {
+            let batch = stream.next().await.unwrap().unwrap();
+            apply(&standby, &batch).await.unwrap();
+            if !batch.partial {
+                break;
+            }
+        }
+        let elections = standby.load_all_elections().await.unwrap();
+        assert_eq!(elections.len(), 1);
+        assert_eq!(elections[0].id, election.id);
+        assert_eq!(standby.get_election_voters(&election.id).await.unwrap()[0].name, "Alice");
+        let pending = standby.get_pending_signature_requests().await.unwrap();
+        assert_eq!(pending[0].blinded_message, vec![0, 1, 2]);
+        assert_eq!(pending[0].shares, vec![(1, Vec::new(), b"proof".to_vec())]);
+
+        // Then the changes, deletions included
+        primary.save_used_token(&election.id, "beef").await.unwrap();
+        primary.complete_signature_request("req1").await.unwrap();
+        primary.purge_election_data(&election.id).await.unwrap();
+        let batch = stream.next().await.unwrap().unwrap();
+        assert!(!batch.reset && !batch.partial);
+        apply(&standby, &batch).await.unwrap();
+        assert!(standby.get_election_voters(&election.id).await.unwrap().is_empty());
+        assert!(standby.get_pending_signature_requests().await.unwrap().is_empty());
+        let (source, cursor) = standby.get_replication_cursor().await.unwrap().unwrap();
+        assert_eq!((source, cursor), (batch.source, primary.get_replication_log_range().await.unwrap().1));
+
+        // Shutting down ends the stream
+        shutdown_tx.send(true).unwrap();
+        assert!(stream.next().await.is_none());
+
+        // A standby that is following picks up where it was
+        let request = ReplicateRequest { source, after: cursor };
+        let mut stream = Box::pin(changes(Arc::clone(&primary), request, None).await.unwrap());
+        primary.save_used_token(&election.id, "f00d").await.unwrap();
+        let batch = stream.next().await.unwrap().unwrap();
+        assert!(!batch.reset);
+        apply(&standby, &batch).await.unwrap();
+        // The purge removed the one used before
+        assert_eq!(standby.load_used_tokens(&election.id).await.unwrap(), vec!["f00d".to_string()]);
+
+        // One following another log, or past this one, starts over
+        for request in [ReplicateRequest { source: source + 1, after: cursor }, ReplicateRequest { source, after: cursor + 100 }] {
+            let mut stream = Box::pin(changes(Arc::clone(&primary), request, None).await.unwrap());
+            assert!(stream.next().await.unwrap().unwrap().reset);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+/*! results_page.rs — Public read-only results over HTTP
+An optional small HTTP server for observers without a Nostr client, e.g. on
+election night: it serves the elections and their current tallies, read
+from the database, as JSON and as a minimal HTML page that refreshes itself.
+Elections with an encrypted tally show no counts until they are decrypted.
+Nothing can be changed through it. */
+
+use anyhow::Result;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{Value, json};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::database::Database;
+
+/// Most elections listed, newest first.
+const MAX_ELECTIONS: u32 = 100;
+
+/// Seconds between reloads of the HTML page.
+const REFRESH_SECS: u32 = 30;
+
+/// Read-only view of the elections in the database.
+pub struct ResultsPage {
+    db: Arc<Database>,
+}
+
+impl ResultsPage {
+    pub fn new(db: Arc<Database>) -> Self {
+        Self { db }
+    }
+
+    /// Serves `/` (HTML), `/elections` and `/elections/<id>` (JSON) for as
+    /// long as it runs.
+    pub async fn serve(self: Arc<Self>, listener: std::net::TcpListener) -> Result<()> {
+        listener.set_nonblocking(true)?;
+        let make_service = make_service_fn(move |_| {
+            let page = Arc::clone(&self);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let page = Arc::clone(&page);
+                    async move { Ok::<_, Infallible>(page.handle(req).await) }
+                }))
+            }
+        });
+        Server::from_tcp(listener)?.serve(make_service).await?;
+        Ok(())
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if req.method() != Method::GET {
+            return response(StatusCode::METHOD_NOT_ALLOWED, "text/plain", "Method not allowed".into());
+        }
+        let path = req.uri().path().trim_end_matches('/');
+        let result = match path {
+            "" => self.elections().await.map(|elections| Some(("text/html; charset=utf-8", html(&elections)))),
+            "/elections" => self.elections().await.map(|elections| Some(("application/json", json!(elections).to_string()))),
+            _ => match path.strip_prefix("/elections/") {
+                Some(id) => self.elections().await.map(|elections| {
+                    elections
+                        .into_iter()
+                        .find(|e| e["id"] == id)
+                        .map(|e| ("application/json", e.to_string()))
+                }),
+                None => Ok(None),
+            },
+        };
+        match result {
+            Ok(Some((content_type, body))) => response(StatusCode::OK, content_type, body),
+            Ok(None) => response(StatusCode::NOT_FOUND, "text/plain", "Not found".into()),
+            Err(e) => {
+                log::error!("Failed to load the results page: {}", e);
+                response(StatusCode::INTERNAL_SERVER_ERROR, "text/plain", "Internal error".into())
+            }
+        }
+    }
+
+    /// The elections with their candidates, ballots and current votes
+    async fn elections(&self) -> Result<Vec<Value>> {
+        let mut elections = Vec::new();
+        for election in self.db.get_elections(MAX_ELECTIONS, 0).await? {
+            // Encrypted ballots are only counted once the election is over
+            let counted = election.tally_key.is_none() || election.tally_proof.is_some();
+            let candidates: Vec<Value> = self
+                .db
+                .get_candidates(&election.id)
+                .await?
+                .into_iter()
+                .map(|c| json!({ "id": c.candidate_id, "name": c.name, "votes": counted.then_some(c.vote_count) }))
+                .collect();
+            elections.push(json!({
+                "id": election.id,
+                "name": election.name,
+                "status": election.status,
+                "start_time": election.start_time,
+                "end_time": election.end_time,
+                "encrypted_tally": election.tally_key.is_some(),
+                "ballots": self.db.count_ballots(&election.id).await?,
+                "candidates": candidates,
+            }));
+        }
+        Ok(elections)
+    }
+}
+
+fn response(status: StatusCode, content_type: &str, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .header(CACHE_CONTROL, "no-cache")
+        .body(Body::from(body))
+        .unwrap_or_default()
+}
+
+/// Page listing the elections, most voted candidates first
+fn html(elections: &[Value]) -> String {
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"{}\">\
+         <title>Election results</title></head><body>\n<h1>Election results</h1>\n",
+        REFRESH_SECS
+    );
+    if elections.is_empty() {
+        page.push_str("<p>No elections yet.</p>\n");
+    }
+    for election in elections {
+        let _ = write!(
+            page,
+            "<h2>{} <small>({}, {})</small></h2>\n<p>{} ballot(s) received</p>\n",
+            escape(election["name"].as_str().unwrap_or_default()),
+            escape(election["id"].as_str().unwrap_or_default()),
+            escape(election["status"].as_str().unwrap_or_default()),
+            election["ballots"],
+        );
+        let mut candidates: Vec<&Value> = election["candidates"].as_array().map(|c| c.iter().collect()).unwrap_or_default();
+        if candidates.iter().any(|c| c["votes"].is_null()) {
+            page.push_str("<p>Encrypted ballots, counted when the election ends.</p>\n");
+        }
+        candidates.sort_by_key(|c| std::cmp::Reverse(c["votes"].as_i64()));
+        page.push_str("<table><tr><th>Candidate</th><th>Votes</th></tr>\n");
+        for candidate in candidates {
+            let votes = candidate["votes"].as_i64().map_or("-".to_string(), |v| v.to_string());
+            let _ = writeln!(
+                page,
+                "<tr><td>{}</td><td>{}</td></tr>",
+                escape(candidate["name"].as_str().unwrap_or_default()),
+                votes
+            );
+        }
+        page.push_str("</table>\n");
+    }
+    page.push_str("</body></html>\n");
+    page
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::election::Election;
+    use crate::types::Candidate;
+    use criptocracia_protocol::PublishedBallot;
+
+    async fn get(page: &ResultsPage, path: &str) -> (StatusCode, String) {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        let response = page.handle(req).await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_serves_live_results() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db = Arc::new(Database::new(temp_file.path()).await.unwrap());
+        let election = Election::new(
+            "<Board>".to_string(),
+            vec![Candidate::new(1, "Alice"), Candidate::new(2, "Bob")],
+            1_000,
+            3_600,
+            "key".to_string(),
+        );
+        db.upsert_election(&election).await.unwrap();
+        let ballot = PublishedBallot::new(&election.id, &[0xaa], vec![2]);
+        db.record_vote(&election.id, "aa", &ballot, &[(2, 1)]).await.unwrap();
+        let page = ResultsPage::new(db);
+
+        let (status, body) = get(&page, "/elections").await;
+        assert_eq!(status, StatusCode::OK);
+        let elections: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(elections[0]["id"], election.id.as_str());
+        assert_eq!(elections[0]["ballots"], 1);
+        assert_eq!(elections[0]["candidates"][1]["votes"], 1);
+
+        let (status, body) = get(&page, &format!("/elections/{}", election.id)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["name"], "<Board>");
+        assert_eq!(get(&page, "/elections/none").await.0, StatusCode::NOT_FOUND);
+
+        let (status, body) = get(&page, "/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("&lt;Board&gt;"));
+        assert!(body.contains("<tr><td>Bob</td><td>1</td></tr>"));
+
+        let post = Request::post("/elections").body(Body::empty()).unwrap();
+        assert_eq!(page.handle(post).await.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,236 @@
+/*! settings.rs — EC configuration file
+The EC reads `<dir>/config.toml`, if there is one. Environment variables
+named after the settings with the `EC_` prefix override it, e.g.
+`EC_RELAYS` (comma separated) or `EC_GRPC_PORT`, and the command line flags
+override both. `NOSTR_PRIVATE_KEY` and `GRPC_BIND_IP` are still read for
+the deployments that set them. */
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use crate::logging::LogFormat;
+use criptocracia_protocol::log_file::LogRotation;
+
+/// Name of the configuration file in the app directory.
+pub const CONFIG_FILE: &str = "config.toml";
+
+/// Prefix of the environment variables that override the settings.
+pub const ENV_PREFIX: &str = "EC";
+
+/// Log levels accepted in the settings
+pub const LOG_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
+
+/// Environment variables read before the configuration file existed, and
+/// the setting each one sets.
+const LEGACY_ENV: [(&str, &str); 2] = [("NOSTR_PRIVATE_KEY", "nostr_private_key"), ("GRPC_BIND_IP", "grpc_bind_ip")];
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Nostr private key (hex, nsec or NIP-49 ncryptsec)
+    pub nostr_private_key: Option<String>,
+    /// File holding the Nostr private key, used when `nostr_private_key`
+    /// isn't set [default: <dir>/nostr_key]
+    pub nostr_key_file: Option<PathBuf>,
+    /// NIP-46 remote signer (`bunker://` URI) holding the Nostr key
+    pub bunker: Option<String>,
+    /// Relays to publish events to and receive messages from
+    pub relays: Vec<String>,
+    /// Address the gRPC admin API listens on
+    pub grpc_bind_ip: String,
+    pub grpc_port: u16,
+    pub log_level: String,
+    /// Levels of single modules, e.g. `"ec::handler" = "debug"` or `sqlx = "warn"`
+    pub log_levels: BTreeMap<String, String>,
+    /// `text`, or `json` for one object per line
+    pub log_format: LogFormat,
+    /// Size and age the log file is rotated at, and rotated files kept
+    pub log_rotation: LogRotation,
+    /// [default: <dir>/app.log]
+    pub log_file: Option<PathBuf>,
+    /// Seconds between results publications (0 publishes every accepted vote)
+    pub results_interval: u64,
+    /// Minimum seconds between results history snapshots (0 snapshots every accepted vote)
+    pub results_snapshot_interval: u64,
+    /// Seconds between turnout publications (0 publishes none)
+    pub turnout_interval: u64,
+    /// Days after an election ends before its voter data is purged (0 keeps it)
+    pub retention_days: u64,
+    /// Seconds between checks for elections that start or end
+    pub status_check_interval: u64,
+    /// Most milliseconds a vote token waits for its verification batch to fill
+    pub verify_window_ms: u64,
+    /// Messages handled at once (0 = one per CPU core)
+    pub workers: usize,
+    /// Messages waiting for a free worker before the relays are read again
+    pub queue_size: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            nostr_private_key: None,
+            nostr_key_file: None,
+            bunker: None,
+            relays: Vec::new(),
+            grpc_bind_ip: "127.0.0.1".to_string(),
+            grpc_port: 50001,
+            log_level: "info".to_string(),
+            log_levels: BTreeMap::new(),
+            log_format: LogFormat::Text,
+            log_rotation: LogRotation::default(),
+            log_file: None,
+            results_interval: 0,
+            results_snapshot_interval: 0,
+            turnout_interval: 300,
+            retention_days: 0,
+            status_check_interval: 30,
+            verify_window_ms: 20,
+            workers: 0,
+            queue_size: 1024,
+        }
+    }
+}
+
+impl Settings {
+    /// Loads `config.toml` from `app_dir`, if it exists, with the
+    /// environment variables over it.
+    pub fn load(app_dir: &Path) -> Result<Self> {
+        load(&app_dir.join(CONFIG_FILE), env::vars().collect())
+    }
+
+    /// Checks the gRPC address, log level and intervals
+    pub fn validate(&self) -> Result<(), String> {
+        self.grpc_bind_ip
+            .parse::<IpAddr>()
+            .map_err(|e| format!("Invalid gRPC bind address {}: {}", self.grpc_bind_ip, e))?;
+        for level in self.log_levels.values().chain([&self.log_level]) {
+            if !LOG_LEVELS.contains(&level.as_str()) {
+                return Err(format!("Invalid log level {}, expected one of: {}", level, LOG_LEVELS.join(", ")));
+            }
+        }
+        if self.queue_size == 0 {
+            return Err("The message queue must hold at least one message".into());
+        }
+        if self.status_check_interval == 0 {
+            return Err("The status check interval must be at least one second".into());
+        }
+        Ok(())
+    }
+
+    /// Address of the gRPC admin API
+    pub fn grpc_addr(&self) -> SocketAddr {
+        let ip = self.grpc_bind_ip.parse().unwrap_or(IpAddr::from([127, 0, 0, 1]));
+        SocketAddr::new(ip, self.grpc_port)
+    }
+
+    /// Number of message workers
+    pub fn worker_count(&self) -> usize {
+        match self.workers {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            workers => workers,
+        }
+    }
+
+    pub fn log_level_filter(&self) -> log::LevelFilter {
+        self.log_level.parse().unwrap_or(log::LevelFilter::Info)
+    }
+
+    /// Modules logged at their own level
+    pub fn module_levels(&self) -> Vec<(String, log::LevelFilter)> {
+        self.log_levels
+            .iter()
+            .map(|(module, level)| (module.clone(), level.parse().unwrap_or(log::LevelFilter::Info)))
+            .collect()
+    }
+}
+
+fn load(file: &Path, env_vars: HashMap<String, String>) -> Result<Settings> {
+    let mut builder = config::Config::builder().add_source(config::File::from(file).required(false));
+    for (var, key) in LEGACY_ENV {
+        if let Some(value) = env_vars.get(var) {
+            builder = builder.set_override(key, value.as_str())?;
+        }
+    }
+    builder = builder.add_source(
+        config::Environment::with_prefix(ENV_PREFIX)
+            .try_parsing(true)
+            .list_separator(",")
+            .with_list_parse_key("relays")
+            .source(Some(env_vars)),
+    );
+    let cfg = builder.build().with_context(|| format!("{} malformed", file.display()))?;
+    let settings: Settings = cfg
+        .try_deserialize()
+        .with_context(|| format!("Error deserializing {}", file.display()))?;
+    settings.validate().map_err(|e| anyhow::anyhow!(e))?;
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_settings() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = dir.path().join(CONFIG_FILE);
+        // Without a file, the defaults
+        assert_eq!(load(&file, HashMap::new()).unwrap(), Settings::default());
+
+        std::fs::write(
+            &file,
+            "relays = [\"wss://relay.mostro.network\"]\ngrpc_port = 50002\nresults_interval = 10\nlog_level = \"debug\"\n",
+        )
+        .unwrap();
+        let settings = load(&file, HashMap::new()).unwrap();
+        assert_eq!(settings.relays, vec!["wss://relay.mostro.network"]);
+        assert_eq!(settings.grpc_addr(), "127.0.0.1:50002".parse().unwrap());
+        assert_eq!((settings.results_interval, settings.log_level_filter()), (10, log::LevelFilter::Debug));
+        assert_eq!(settings.status_check_interval, 30);
+        assert!(settings.worker_count() >= 1);
+        assert_eq!(settings.log_format, LogFormat::Text);
+
+        // The environment overrides the file
+        let vars = HashMap::from([
+            ("EC_RELAYS".to_string(), "wss://nos.lol,wss://relay.damus.io".to_string()),
+            ("EC_RESULTS_INTERVAL".to_string(), "60".to_string()),
+            ("NOSTR_PRIVATE_KEY".to_string(), "nsec1abc".to_string()),
+            ("GRPC_BIND_IP".to_string(), "0.0.0.0".to_string()),
+            ("EC_LOG_FORMAT".to_string(), "json".to_string()),
+            // Other EC_ variables are not settings
+            ("EC_PUBLIC_KEY".to_string(), "-----BEGIN PUBLIC KEY-----".to_string()),
+        ]);
+        let settings = load(&file, vars).unwrap();
+        assert_eq!(settings.relays, vec!["wss://nos.lol", "wss://relay.damus.io"]);
+        assert_eq!(settings.results_interval, 60);
+        assert_eq!(settings.nostr_private_key.as_deref(), Some("nsec1abc"));
+        assert_eq!(settings.grpc_addr(), "0.0.0.0:50002".parse().unwrap());
+        assert_eq!(settings.log_format, LogFormat::Json);
+
+        std::fs::write(&file, "[log_levels]\n\"ec::handler\" = \"debug\"\nsqlx = \"warn\"\n").unwrap();
+        let settings = load(&file, HashMap::new()).unwrap();
+        assert_eq!(
+            settings.module_levels(),
+            vec![("ec::handler".to_string(), log::LevelFilter::Debug), ("sqlx".to_string(), log::LevelFilter::Warn)]
+        );
+        std::fs::write(&file, "[log_rotation]\nmax_age_hours = 24\n").unwrap();
+        let rotation = load(&file, HashMap::new()).unwrap().log_rotation;
+        assert_eq!(rotation, LogRotation { max_age_hours: 24, ..LogRotation::default() });
+        std::fs::write(&file, "[log_levels]\nsqlx = \"loud\"\n").unwrap();
+        assert!(load(&file, HashMap::new()).is_err());
+
+        std::fs::write(&file, "log_level = \"verbose\"\n").unwrap();
+        assert!(load(&file, HashMap::new()).unwrap_err().to_string().contains("log level"));
+        std::fs::write(&file, "workers = 8\n").unwrap();
+        assert_eq!(load(&file, HashMap::new()).unwrap().worker_count(), 8);
+        std::fs::write(&file, "queue_size = 0\n").unwrap();
+        assert!(load(&file, HashMap::new()).is_err());
+        std::fs::write(&file, "grpc_port = \"many\"\n").unwrap();
+        assert!(load(&file, HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+/*! signer.rs — Blind signing of voting tokens
+Token issuance and vote verification go through `BlindSigner`, so the RSA
+private key can be held in memory (`LocalSigner`) or in an HSM or YubiKey
+(`Pkcs11Signer`, see pkcs11.rs). That key signs any blinded message sent to
+it, so the content of election events is signed with a key of its own
+(`ContentSigner`) that never signs blinded messages. */
+
+use anyhow::Context;
+use blind_rsa_signatures::{
+    BlindSignature, KeyPair, MessageRandomizer, Options, PublicKey as RSAPublicKey, SecretKey as RSASecretKey,
+    Signature as RSASignature,
+};
+use criptocracia_protocol::{BlindTokenScheme, TokenScheme};
+use rand::thread_rng;
+use rsa::pss::BlindedSigningKey;
+use rsa::signature::RandomizedSigner;
+use sha2::Sha384;
+use std::fs;
+use std::path::Path;
+
+use crate::util::write_private_file;
+
+/// File in the app directory with the RSA key election content is signed with.
+pub const CONTENT_KEY_FILE: &str = "ec_content.pem";
+
+/// Size of the content key, in bits.
+const CONTENT_KEY_BITS: usize = 2048;
+
+/// Holder of the EC's RSA private key.
+pub trait BlindSigner: Send + Sync {
+    /// Public key the tokens verify with.
+    fn public_key(&self) -> &RSAPublicKey;
+
+    /// Signs a blinded message, which must be as long as the modulus.
+    fn blind_sign(&self, blind_msg: &[u8]) -> Result<BlindSignature, String>;
+
+    /// Whether `token` is a valid signature of `msg` in the election's token scheme.
+    fn verify(
+        &self,
+        scheme: TokenScheme,
+        token: &RSASignature,
+        msg_randomizer: Option<MessageRandomizer>,
+        msg: &[u8],
+    ) -> bool {
+        scheme.verify(self.public_key(), token, msg_randomizer, msg)
+    }
+}
+
+/// Signer holding the RSA private key in memory.
+pub struct LocalSigner {
+    pk: RSAPublicKey,
+    sk: RSASecretKey,
+}
+
+impl LocalSigner {
+    pub fn new(pk: RSAPublicKey, sk: RSASecretKey) -> Self {
+        Self { pk, sk }
+    }
+}
+
+impl BlindSigner for LocalSigner {
+    fn public_key(&self) -> &RSAPublicKey {
+        &self.pk
+    }
+
+    fn blind_sign(&self, blind_msg: &[u8]) -> Result<BlindSignature, String> {
+        self.sk
+            .blind_sign(&mut thread_rng(), blind_msg, &Options::default())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Holder of the EC's content key, which signs the content of its election
+/// events and nothing else, published in its descriptor.
+pub struct ContentSigner {
+    pk: RSAPublicKey,
+    sk: RSASecretKey,
+}
+
+impl ContentSigner {
+    pub fn new(sk: RSASecretKey) -> anyhow::Result<Self> {
+        Ok(Self { pk: sk.public_key()?, sk })
+    }
+
+    /// Loads the content key of `app_dir`, creating it the first time.
+    pub fn load(app_dir: &Path) -> anyhow::Result<Self> {
+        let path = app_dir.join(CONTENT_KEY_FILE);
+        if let Ok(pem) = fs::read_to_string(&path) {
+            let sk = RSASecretKey::from_pem(&pem).with_context(|| format!("Invalid content key in {}", path.display()))?;
+            return Self::new(sk);
+        }
+        let keys = KeyPair::generate(&mut thread_rng(), CONTENT_KEY_BITS)?;
+        write_private_file(&path, keys.sk.to_pem()?.as_bytes())?;
+        log::info!("Content key created in {}", path.display());
+        Self::new(keys.sk)
+    }
+
+    /// Public key the content verifies with.
+    pub fn public_key(&self) -> &RSAPublicKey {
+        &self.pk
+    }
+
+    /// Signs `msg` with RSA-PSS, SHA-384 and no salt, see `content_sig_options`.
+    pub fn sign(&self, msg: &[u8]) -> Result<RSASignature, String> {
+        let key = BlindedSigningKey::<Sha384>::new_with_salt_len(self.sk.0.clone(), 0);
+        let sig = key.try_sign_with_rng(&mut thread_rng(), msg).map_err(|e| e.to_string())?;
+        Ok(RSASignature(sig.as_ref().to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use criptocracia_protocol::ElectionEvent;
+    use criptocracia_protocol::election::content_sig_options;
+
+    #[test]
+    fn test_content_signer() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let signer = ContentSigner::load(dir.path()).unwrap();
+        assert_eq!(ContentSigner::load(dir.path()).unwrap().public_key(), signer.public_key());
+
+        let msg = b"canonical content";
+        let sig = signer.sign(msg).unwrap();
+        assert!(sig.verify(signer.public_key(), None, msg, &content_sig_options()).is_ok());
+        assert_eq!(signer.sign(msg).unwrap().0, sig.0);
+    }
+
+    #[test]
+    fn test_blind_oracle_cant_sign_content() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let content = ContentSigner::load(dir.path()).unwrap();
+        let keys = KeyPair::generate(&mut thread_rng(), 2048).unwrap();
+        let tokens = LocalSigner::new(keys.pk.clone(), keys.sk);
+
+        // A voter has the token key sign a forged election as a token request
+        let mut forged = ElectionEvent::from_json(
+            r#"{"id":"a1b2","name":"Forged","start_time":1,"end_time":2,"candidates":[],"status":"canceled","rsa_pub_key":"key"}"#,
+        )
+        .unwrap();
+        let msg = forged.signed_content();
+        let scheme = TokenScheme::RsaPssDeterministic;
+        let blinding = scheme.blind(tokens.public_key(), msg.as_bytes()).unwrap();
+        let blind_sig = tokens.blind_sign(&blinding.blind_msg).unwrap();
+        let sig = scheme.unblind(tokens.public_key(), &blind_sig, &blinding.secret, None, msg.as_bytes()).unwrap();
+        forged.content_sig = Some(base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &sig.0));
+        let forged = forged.as_canonical_json();
+        assert!(ElectionEvent::content_sig_matches(&forged, tokens.public_key()));
+        assert!(!ElectionEvent::content_sig_matches(&forged, content.public_key()));
+
+        // And published content signatures are no tokens
+        let mut event = ElectionEvent::from_json(&forged).unwrap();
+        event.content_sig = None;
+        let sig = content.sign(event.signed_content().as_bytes()).unwrap();
+        assert!(!tokens.verify(scheme, &sig, None, event.signed_content().as_bytes()));
+    }
+}
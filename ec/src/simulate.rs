@@ -0,0 +1,462 @@
+/*! simulate.rs — End-to-end simulation of an election
+`ec simulate` runs a throwaway EC, with its own directory, database, RSA
+and Nostr keys, behind a local relay on the loopback interface. It creates
+an election, generates the voters' keys and has each of them request a
+token and vote through the real gift wrapped, blind signature flow, spread
+over the given time. It reports the throughput, the latency of each step,
+the errors by kind, and whether the EC's tally, in memory and as stored,
+matches the votes acknowledged. Nothing outside the directory is touched,
+and the directory is removed at the end. */
+
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose};
+use blind_rsa_signatures::{BlindSignature, PublicKey as RSAPublicKey};
+use criptocracia_protocol::message::kind;
+use criptocracia_protocol::{BlindTokenScheme, ErrorPayload, Message, TokenBinding, VoteAck, VotePayload};
+use nostr_sdk::prelude::*;
+use rand::RngCore;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{Mutex, broadcast, oneshot};
+use tokio::task::JoinSet;
+use tokio::time::{Duration, Instant};
+
+use crate::database::Database;
+use crate::election::{Election, Status};
+use crate::elections::Elections;
+use crate::handler::MessageHandler;
+use crate::identity::EcIdentity;
+use crate::local_relay::LocalRelay;
+use crate::relays::RelayManager;
+use crate::signer::LocalSigner;
+use crate::types::{Candidate, Voter};
+use crate::workers::WorkerPool;
+
+/// Time a voter waits for each answer of the EC.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time given to the clients to connect to the local relay.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Candidates of the simulated election, the votes spread over them.
+const CANDIDATES: [&str; 3] = ["Alice", "Bob", "Carol"];
+
+/// What one simulated voter went through: the time each step took, the
+/// candidate it voted for once acknowledged, and the error that stopped it.
+#[derive(Debug, Default)]
+struct VoterRun {
+    token: Option<Duration>,
+    vote: Option<Duration>,
+    counted: Option<u8>,
+    error: Option<(String, String)>,
+}
+
+/// Latency percentiles of a step, in milliseconds.
+#[derive(Debug, PartialEq)]
+pub struct Latency {
+    pub count: usize,
+    pub p50: u128,
+    pub p90: u128,
+    pub p99: u128,
+    pub max: u128,
+}
+
+impl Latency {
+    fn of(mut durations: Vec<Duration>) -> Option<Self> {
+        if durations.is_empty() {
+            return None;
+        }
+        durations.sort();
+        // Nearest rank
+        let percentile = |p: usize| durations[(durations.len() * p).div_ceil(100).max(1) - 1].as_millis();
+        Some(Self {
+            count: durations.len(),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: durations[durations.len() - 1].as_millis(),
+        })
+    }
+}
+
+/// Outcome of a simulation.
+#[derive(Debug)]
+pub struct Report {
+    pub voters: usize,
+    pub voted: usize,
+    pub elapsed: Duration,
+    pub token: Option<Latency>,
+    pub vote: Option<Latency>,
+    /// Failed voters by kind of error, with the first error of each kind
+    pub errors: BTreeMap<String, (usize, String)>,
+    /// Votes by candidate, from the votes the EC acknowledged
+    pub expected: Vec<(u8, u32)>,
+    /// Votes by candidate, as counted by the EC
+    pub tallied: Vec<(u8, u32)>,
+    /// Votes by candidate, of the election loaded again from the database
+    pub stored: Vec<(u8, u32)>,
+}
+
+impl Report {
+    /// Whether the EC counted exactly the votes it acknowledged.
+    pub fn tally_correct(&self) -> bool {
+        self.tallied == self.expected && self.stored == self.expected
+    }
+
+    pub fn print(&self) {
+        let failed = self.voters - self.voted;
+        println!(
+            "{} of {} voters voted in {:.1}s, {:.1} votes/s ({:.1}% errors)",
+            self.voted,
+            self.voters,
+            self.elapsed.as_secs_f64(),
+            self.voted as f64 / self.elapsed.as_secs_f64().max(0.001),
+            failed as f64 * 100.0 / self.voters.max(1) as f64
+        );
+        for (step, latency) in [("Token", &self.token), ("Vote", &self.vote)] {
+            match latency {
+                Some(l) => println!(
+                    "  {:<6} {:>5} done  p50 {} ms  p90 {} ms  p99 {} ms  max {} ms",
+                    step, l.count, l.p50, l.p90, l.p99, l.max
+                ),
+                None => println!("  {:<6} none done", step),
+            }
+        }
+        for (kind, (count, first)) in &self.errors {
+            println!("  {:>5} {}: {}", count, kind, first);
+        }
+        let counts = |counts: &[(u8, u32)]| {
+            counts.iter().map(|(id, votes)| format!("{}={}", id, votes)).collect::<Vec<_>>().join(" ")
+        };
+        if self.tally_correct() {
+            println!("✅ Tally correct: {}", counts(&self.expected));
+        } else {
+            println!("❌ Tally wrong: expected {}", counts(&self.expected));
+            println!("   counted {}, stored {}", counts(&self.tallied), counts(&self.stored));
+        }
+    }
+}
+
+/// Runs an election of `voters` voters, who start voting one after the other
+/// over `spread`, against a throwaway EC with a `key_bits` RSA key.
+pub async fn run(voters: usize, spread: Duration, key_bits: usize) -> Result<Report> {
+    let dir = std::env::temp_dir().join(format!("ec-simulate-{}-{}", std::process::id(), rand::thread_rng().next_u32()));
+    std::fs::create_dir_all(&dir)?;
+    let report = simulate(&dir, voters, spread, key_bits).await;
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        log::warn!("Failed to remove {}: {}", dir.display(), e);
+    }
+    report
+}
+
+async fn simulate(dir: &Path, voters: usize, spread: Duration, key_bits: usize) -> Result<Report> {
+    let (pk, sk) = crate::util::generate_keys(dir, key_bits)?;
+    let db = Arc::new(Database::new(dir.join("simulation.db")).await?);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let relay_url = format!("ws://{}", listener.local_addr()?);
+    let relay = tokio::spawn(Arc::new(LocalRelay::new(Arc::clone(&db)).await?).serve(listener));
+
+    // The election is in progress until every voter had time to vote
+    let voter_keys: Vec<Keys> = (0..voters).map(|_| Keys::generate()).collect();
+    let candidates: Vec<Candidate> = CANDIDATES.iter().zip(1..).map(|(name, id)| Candidate::new(id, *name)).collect();
+    let now = Timestamp::now().as_u64();
+    let length = spread + ANSWER_TIMEOUT * 2 + Duration::from_secs(60);
+    let pk_der_b64 = general_purpose::STANDARD.encode(pk.to_der()?);
+    let mut election = Election::new("Simulation".to_string(), candidates, now, length.as_secs(), pk_der_b64);
+    election.authorized_voters.extend(voter_keys.iter().map(|keys| keys.public_key().to_hex()));
+    election.roll = election.authorized_voters.clone();
+    election.status = Status::InProgress;
+    db.upsert_election(&election).await?;
+    let roll: Vec<Voter> =
+        voter_keys.iter().enumerate().map(|(i, keys)| Voter::new(format!("Voter {}", i + 1), keys.public_key().to_hex())).collect();
+    db.save_election_voters(&election.id, &roll).await?;
+    let elections = Arc::new(Elections::new([election.clone()]));
+
+    // The EC, handling its messages as it does when running
+    let identity = EcIdentity::local(Keys::generate());
+    let client = Client::builder().signer(identity.signer.clone()).build();
+    client.add_relay(&relay_url).await?;
+    client.connect().await;
+    client.wait_for_connection(CONNECT_TIMEOUT).await;
+    let relays = Arc::new(RelayManager::new(client.clone(), Arc::clone(&db)));
+    let signer = Arc::new(LocalSigner::new(pk.clone(), sk));
+    let handler = Arc::new(MessageHandler::new(relays, identity.clone(), Arc::clone(&db), Arc::clone(&elections), signer));
+    let workers = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let pool = WorkerPool::start(workers, 1_024, move |event: Box<Event>| {
+        let handler = Arc::clone(&handler);
+        async move { handler.handle_event(&event).await }
+    });
+    let mut notifications = client.notifications();
+    client.subscribe(Filter::new().pubkey(identity.public_key).kind(Kind::GiftWrap).limit(0), None).await?;
+    let listener = tokio::spawn(async move {
+        loop {
+            match notifications.recv().await {
+                Ok(RelayPoolNotification::Event { event, .. }) => pool.push(event).await,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+        pool.close().await;
+    });
+
+    // The voters, on a connection of their own
+    let voting = Arc::new(Voting::connect(&relay_url, identity.public_key, pk, election.clone()).await?);
+    println!("🗳️ Simulating {} voters on election {} over {}s", voters, election.id, spread.as_secs());
+    let started = Instant::now();
+    let mut tasks = JoinSet::new();
+    for (i, keys) in voter_keys.into_iter().enumerate() {
+        let voting = Arc::clone(&voting);
+        let delay = spread.mul_f64(i as f64 / voters.max(1) as f64);
+        tasks.spawn(async move {
+            tokio::time::sleep(delay).await;
+            let mut run = VoterRun::default();
+            let choice = (i % CANDIDATES.len()) as u8 + 1;
+            if let Err((kind, e)) = voting.vote(keys, choice, &mut run).await {
+                log::warn!("Voter {} failed: {}: {}", i + 1, kind, e);
+                run.error = Some((kind, e));
+            }
+            run
+        });
+    }
+    let mut runs = Vec::new();
+    while let Some(run) = tasks.join_next().await {
+        runs.push(run?);
+    }
+    let elapsed = started.elapsed();
+
+    voting.client.disconnect().await;
+    client.disconnect().await;
+    listener.abort();
+    relay.abort();
+    let tallied = match elections.get(&election.id) {
+        Some(election) => election.read().await.vote_counts(),
+        None => Vec::new(),
+    };
+    let stored = crate::load_elections_from_database(&db)
+        .await?
+        .into_iter()
+        .find(|stored| stored.id == election.id)
+        .map(|stored| stored.vote_counts())
+        .unwrap_or_default();
+    db.close().await;
+    Ok(report(&runs, elapsed, tallied, stored))
+}
+
+fn report(runs: &[VoterRun], elapsed: Duration, tallied: Vec<(u8, u32)>, stored: Vec<(u8, u32)>) -> Report {
+    let mut errors: BTreeMap<String, (usize, String)> = BTreeMap::new();
+    for (kind, e) in runs.iter().filter_map(|r| r.error.as_ref()) {
+        errors.entry(kind.clone()).or_insert_with(|| (0, e.clone())).0 += 1;
+    }
+    let mut expected: BTreeMap<u8, u32> = BTreeMap::new();
+    for choice in runs.iter().filter_map(|r| r.counted) {
+        *expected.entry(choice).or_default() += 1;
+    }
+    Report {
+        voters: runs.len(),
+        voted: runs.iter().filter(|r| r.counted.is_some()).count(),
+        elapsed,
+        token: Latency::of(runs.iter().filter_map(|r| r.token).collect()),
+        vote: Latency::of(runs.iter().filter_map(|r| r.vote).collect()),
+        errors,
+        expected: expected.into_iter().collect(),
+        tallied,
+        stored,
+    }
+}
+
+/// Why a voter failed, by kind, and what happened.
+type VoterError = (String, String);
+
+/// Voters waiting for an answer of the EC, by the key it's gift wrapped to.
+type Waiting = Arc<Mutex<HashMap<PublicKey, (Keys, oneshot::Sender<Message>)>>>;
+
+fn failed(kind: &str, e: impl std::fmt::Display) -> VoterError {
+    (kind.to_string(), e.to_string())
+}
+
+/// Connection of the voters to the relay, handing each one the answers of
+/// the EC gift wrapped to the keys it waits with.
+struct Voting {
+    client: Client,
+    ec_pubkey: PublicKey,
+    rsa_pubkey: RSAPublicKey,
+    election: Election,
+    waiting: Waiting,
+}
+
+impl Voting {
+    async fn connect(relay_url: &str, ec_pubkey: PublicKey, rsa_pubkey: RSAPublicKey, election: Election) -> Result<Self> {
+        let client = Client::default();
+        client.add_relay(relay_url).await?;
+        client.connect().await;
+        client.wait_for_connection(CONNECT_TIMEOUT).await;
+        let waiting: Waiting = Arc::default();
+        let mut notifications = client.notifications();
+        client.subscribe(Filter::new().kind(Kind::GiftWrap).limit(0), None).await?;
+        {
+            let waiting = Arc::clone(&waiting);
+            tokio::spawn(async move {
+                loop {
+                    let event = match notifications.recv().await {
+                        Ok(RelayPoolNotification::Event { event, .. }) => event,
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    };
+                    let Some(recipient) = event.tags.public_keys().next().copied() else {
+                        continue;
+                    };
+                    let Some((keys, answer)) = waiting.lock().await.remove(&recipient) else {
+                        continue;
+                    };
+                    match nip59::extract_rumor(&keys, &event).await {
+                        Ok(unwrapped) => match Message::from_json(&unwrapped.rumor.content) {
+                            Ok(message) => {
+                                let _ = answer.send(message);
+                            }
+                            Err(e) => log::warn!("Unreadable answer of the EC: {}", e),
+                        },
+                        Err(e) => log::warn!("Failed to unwrap the answer of the EC: {}", e),
+                    }
+                }
+            });
+        }
+        Ok(Self { client, ec_pubkey, rsa_pubkey, election, waiting })
+    }
+
+    /// Gift wraps a message from `keys` to the EC and waits for its answer
+    /// to them, failing if the EC rejects the message.
+    async fn send(&self, keys: &Keys, message: &Message) -> Result<Message, VoterError> {
+        let (answer, answered) = oneshot::channel();
+        self.waiting.lock().await.insert(keys.public_key(), (keys.clone(), answer));
+        let rumor = EventBuilder::text_note(message.as_json()).build(keys.public_key());
+        let sent = match EventBuilder::gift_wrap(keys, &self.ec_pubkey, rumor, None).await {
+            Ok(gift_wrap) => self.client.send_event(&gift_wrap).await.map_err(|e| failed("send", e)),
+            Err(e) => Err(failed("send", e)),
+        };
+        let answer = match sent {
+            Ok(_) => tokio::time::timeout(ANSWER_TIMEOUT, answered).await,
+            Err(e) => {
+                self.waiting.lock().await.remove(&keys.public_key());
+                return Err(e);
+            }
+        };
+        let answer = match answer {
+            Ok(Ok(answer)) => answer,
+            _ => {
+                self.waiting.lock().await.remove(&keys.public_key());
+                return Err(failed("timeout", format!("No answer to {} in {}s", message.id, ANSWER_TIMEOUT.as_secs())));
+            }
+        };
+        if answer.kind == kind::ERROR {
+            return Err(match ErrorPayload::from_json(&answer.payload) {
+                Ok(error) => failed(error.code.as_str(), error.reason),
+                Err(_) => failed("rejected", answer.payload),
+            });
+        }
+        Ok(answer)
+    }
+
+    /// Requests a token with the voter's keys, then votes for `choice` with
+    /// throwaway keys and checks the EC's receipt.
+    async fn vote(&self, keys: Keys, choice: u8, run: &mut VoterRun) -> Result<(), VoterError> {
+        let election = &self.election;
+        let scheme = election.token_scheme;
+        let mut nonce = vec![0u8; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let binding = TokenBinding { nonce, expiry: election.end_time };
+        let h_n = binding.hash(&election.id);
+        let blinding = scheme.blind(&self.rsa_pubkey, &h_n).map_err(|e| failed("token", e))?;
+
+        let started = Instant::now();
+        let message = Message::new_with_election(
+            format!("token_request_{}", keys.public_key().to_hex()),
+            kind::TOKEN_REQUEST,
+            general_purpose::STANDARD.encode(&blinding.blind_msg),
+            election.id.clone(),
+        );
+        let answer = self.send(&keys, &message).await?;
+        let blind_sig = general_purpose::STANDARD.decode(&answer.payload).map_err(|e| failed("token", e))?;
+        let token = scheme
+            .unblind(&self.rsa_pubkey, &BlindSignature::from(blind_sig), &blinding.secret, blinding.msg_randomizer, &h_n)
+            .map_err(|e| failed("token", e))?;
+        run.token = Some(started.elapsed());
+
+        let started = Instant::now();
+        let payload = VotePayload {
+            h_n: h_n.clone(),
+            token: token.to_vec(),
+            r: blinding.msg_randomizer.map(|r| r.0),
+            choices: vec![choice],
+            binding: Some(binding),
+            encrypted: None,
+        };
+        let vote_keys = Keys::generate();
+        let message = Message::new_with_election(
+            format!("vote_{}", vote_keys.public_key().to_hex()),
+            kind::VOTE,
+            payload.encode(),
+            election.id.clone(),
+        )
+        .with_reply_to(vote_keys.public_key().to_hex());
+        let answer = self.send(&vote_keys, &message).await?;
+        if answer.kind != kind::ACK {
+            return Err(failed("receipt", format!("Answer of kind {} to the vote", answer.kind)));
+        }
+        self.check_receipt(&answer.payload, &h_n).map_err(|e| failed("receipt", e))?;
+        run.vote = Some(started.elapsed());
+        run.counted = Some(choice);
+        Ok(())
+    }
+
+    /// Checks that the receipt of a vote is signed by the EC, for this
+    /// election and token.
+    fn check_receipt(&self, receipt: &str, h_n: &[u8]) -> Result<()> {
+        let event = Event::from_json(receipt)?;
+        event.verify()?;
+        let ack = VoteAck::from_json(&event.content)?;
+        if event.pubkey != self.ec_pubkey
+            || ack.election_id != self.election.id
+            || ack.h_n != general_purpose::STANDARD.encode(h_n)
+        {
+            return Err(anyhow::anyhow!("Receipt of another vote or not signed by the EC"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Directories of the simulations of this process left behind
+    fn simulation_dirs() -> Vec<PathBuf> {
+        let prefix = format!("ec-simulate-{}-", std::process::id());
+        std::fs::read_dir(std::env::temp_dir())
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(&prefix)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_latency() {
+        assert_eq!(Latency::of(Vec::new()), None);
+        let latency = Latency::of((1..=100).rev().map(Duration::from_millis).collect()).unwrap();
+        assert_eq!(latency, Latency { count: 100, p50: 50, p90: 90, p99: 99, max: 100 });
+    }
+
+    #[tokio::test]
+    async fn test_simulation() {
+        let report = run(4, Duration::from_millis(200), 1024).await.unwrap();
+        assert_eq!(report.voted, 4, "{:?}", report.errors);
+        assert!(report.errors.is_empty());
+        assert_eq!(report.expected, vec![(1, 2), (2, 1), (3, 1)]);
+        assert!(report.tally_correct());
+        assert!(simulation_dirs().is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+/*! systemd.rs — Readiness and watchdog notifications to systemd
+Run as a `Type=notify` service, the EC tells systemd it's ready once its
+elections are loaded, the relays connected and the gRPC API listening, and
+that it's stopping on shutdown. With `WatchdogSec=` it pings the watchdog
+from its main loop, so a hung EC is restarted. Without `NOTIFY_SOCKET`, as
+outside systemd, nothing is sent. */
+
+use std::env;
+use std::time::Duration;
+
+/// The EC is up and serving
+pub const READY: &str = "READY=1";
+
+/// The EC is shutting down
+pub const STOPPING: &str = "STOPPING=1";
+
+/// The EC is alive
+pub const WATCHDOG: &str = "WATCHDOG=1";
+
+/// Sends `state` to systemd, if the EC runs under it.
+pub fn notify(state: &str) {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&socket, state) {
+        log::warn!("Failed to notify systemd of {}: {}", state, e);
+    }
+}
+
+#[cfg(unix)]
+fn send(socket: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let path = socket.as_bytes();
+    let addr = match path.strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name)?
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(std::io::Error::other("abstract sockets are only on Linux")),
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_socket: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Time between watchdog pings, half the `WatchdogSec=` of the service, if
+/// its watchdog is on and meant for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_send() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        send(path.as_os_str(), READY).unwrap();
+        let mut buf = [0u8; 64];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        assert!(send(dir.path().join("missing").as_os_str(), STOPPING).is_err());
+    }
+}
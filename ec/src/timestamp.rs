@@ -0,0 +1,572 @@
+/*! timestamp.rs — OpenTimestamps anchoring of final results
+When an election closes, the ID of its final results event is sent to
+OpenTimestamps calendars, which commit it to the Bitcoin blockchain within a
+few hours. Pending proofs are kept in the database and upgraded until one
+reaches a Bitcoin block; the complete proof is then published in a NIP-03
+attestation event, so anyone can check that the results existed by then. */
+
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use hyper::{Body, Request, StatusCode};
+use nostr_sdk::prelude::*;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+use crate::database::Database;
+use crate::identity::EcIdentity;
+use crate::relays::RelayManager;
+
+/// Kind of the NIP-03 OpenTimestamps attestation events.
+pub const OTS_ATTESTATION_KIND: u16 = 1040;
+
+/// Start of every `.ots` file.
+const HEADER_MAGIC: &[u8] = b"\x00OpenTimestamps\x00\x00Proof\x00\xbf\x89\xe2\xe8\x84\xe8\x92\x94";
+
+/// Version of the `.ots` format written.
+const FORMAT_VERSION: u8 = 1;
+
+const BITCOIN_TAG: [u8; 8] = [0x05, 0x88, 0x96, 0x0d, 0x73, 0xd7, 0x19, 0x01];
+const PENDING_TAG: [u8; 8] = [0x83, 0xdf, 0xe3, 0x0d, 0x2e, 0xf9, 0x0c, 0x8e];
+
+/// Largest argument of an operation, and message an operation may produce.
+const MAX_MESSAGE_LENGTH: usize = 4_096;
+
+/// Deepest nesting of operations read from a proof.
+const MAX_DEPTH: usize = 256;
+
+/// Time given to a calendar to answer.
+const CALENDAR_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time between attempts to upgrade the pending proofs. Bitcoin blocks come
+/// every ten minutes, and calendars wait for a few before committing.
+const UPGRADE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Operation turning a message into the next one in a proof.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Op {
+    Sha256,
+    Append(Vec<u8>),
+    Prepend(Vec<u8>),
+}
+
+impl Op {
+    fn apply(&self, message: &[u8]) -> Vec<u8> {
+        match self {
+            Op::Sha256 => Sha256::digest(message).to_vec(),
+            Op::Append(suffix) => [message, suffix].concat(),
+            Op::Prepend(prefix) => [prefix, message].concat(),
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Op::Sha256 => out.push(0x08),
+            Op::Append(arg) => {
+                out.push(0xf0);
+                write_bytes(out, arg);
+            }
+            Op::Prepend(arg) => {
+                out.push(0xf1);
+                write_bytes(out, arg);
+            }
+        }
+    }
+}
+
+/// What a proof ends in: a Bitcoin block, or a calendar to ask again later.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Attestation {
+    Bitcoin(u64),
+    Pending(String),
+    Unknown([u8; 8], Vec<u8>),
+}
+
+impl Attestation {
+    fn write(&self, out: &mut Vec<u8>) {
+        let (tag, payload) = match self {
+            Attestation::Bitcoin(height) => {
+                let mut payload = Vec::new();
+                write_varuint(&mut payload, *height);
+                (BITCOIN_TAG, payload)
+            }
+            Attestation::Pending(uri) => {
+                let mut payload = Vec::new();
+                write_bytes(&mut payload, uri.as_bytes());
+                (PENDING_TAG, payload)
+            }
+            Attestation::Unknown(tag, payload) => (*tag, payload.clone()),
+        };
+        out.extend_from_slice(&tag);
+        write_bytes(out, &payload);
+    }
+}
+
+/// Proof that a message existed by the time of its attestations: a tree of
+/// operations leading from the message to them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Timestamp {
+    message: Vec<u8>,
+    attestations: Vec<Attestation>,
+    ops: Vec<(Op, Timestamp)>,
+}
+
+impl Timestamp {
+    fn new(message: Vec<u8>) -> Self {
+        Self { message, attestations: Vec::new(), ops: Vec::new() }
+    }
+
+    /// Reads the timestamp of `message` serialized in `bytes`.
+    fn read(reader: &mut Reader, message: Vec<u8>, depth: usize) -> Result<Self> {
+        if depth > MAX_DEPTH {
+            return Err(anyhow!("Timestamp nested too deep"));
+        }
+        let mut timestamp = Self::new(message);
+        let mut tag = reader.byte()?;
+        while tag == 0xff {
+            let next = reader.byte()?;
+            timestamp.read_branch(reader, next, depth)?;
+            tag = reader.byte()?;
+        }
+        timestamp.read_branch(reader, tag, depth)?;
+        Ok(timestamp)
+    }
+
+    fn read_branch(&mut self, reader: &mut Reader, tag: u8, depth: usize) -> Result<()> {
+        let op = match tag {
+            0x00 => {
+                let kind: [u8; 8] = reader.take(8)?.try_into()?;
+                let payload = reader.bytes()?;
+                let attestation = match kind {
+                    BITCOIN_TAG => Attestation::Bitcoin(Reader::new(&payload).varuint()?),
+                    PENDING_TAG => Attestation::Pending(String::from_utf8(Reader::new(&payload).bytes()?)?),
+                    _ => Attestation::Unknown(kind, payload),
+                };
+                self.attestations.push(attestation);
+                return Ok(());
+            }
+            0x08 => Op::Sha256,
+            0xf0 => Op::Append(reader.bytes()?),
+            0xf1 => Op::Prepend(reader.bytes()?),
+            _ => return Err(anyhow!("Unsupported timestamp operation {:#04x}", tag)),
+        };
+        let result = op.apply(&self.message);
+        if result.len() > MAX_MESSAGE_LENGTH {
+            return Err(anyhow!("Timestamp message too long"));
+        }
+        let child = Timestamp::read(reader, result, depth + 1)?;
+        self.ops.push((op, child));
+        Ok(())
+    }
+
+    /// Serializes the timestamp, as the reference implementation does: every
+    /// branch but the last preceded by `0xff`, attestations first.
+    fn write(&self, out: &mut Vec<u8>) {
+        let mut attestations = self.attestations.clone();
+        attestations.sort();
+        let mut ops: Vec<&(Op, Timestamp)> = self.ops.iter().collect();
+        ops.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let branches = attestations.len() + ops.len();
+        let mut written = 0;
+        for attestation in &attestations {
+            written += 1;
+            if written < branches {
+                out.push(0xff);
+            }
+            out.push(0x00);
+            attestation.write(out);
+        }
+        for (op, child) in ops {
+            written += 1;
+            if written < branches {
+                out.push(0xff);
+            }
+            op.write(out);
+            child.write(out);
+        }
+    }
+
+    /// Adds the attestations and operations of another timestamp of the same message.
+    fn merge(&mut self, other: Timestamp) {
+        for attestation in other.attestations {
+            if !self.attestations.contains(&attestation) {
+                self.attestations.push(attestation);
+            }
+        }
+        for (op, child) in other.ops {
+            match self.ops.iter_mut().find(|(existing, _)| *existing == op) {
+                Some((_, existing)) => existing.merge(child),
+                None => self.ops.push((op, child)),
+            }
+        }
+    }
+
+    /// Whether the timestamp reaches a Bitcoin block.
+    fn is_complete(&self) -> bool {
+        self.attestations.iter().any(|a| matches!(a, Attestation::Bitcoin(_)))
+            || self.ops.iter().any(|(_, child)| child.is_complete())
+    }
+
+    /// Calendars still to be asked about a message of the timestamp.
+    fn pending(&self) -> Vec<(String, Vec<u8>)> {
+        let mut pending: Vec<(String, Vec<u8>)> = self
+            .attestations
+            .iter()
+            .filter_map(|a| match a {
+                Attestation::Pending(uri) => Some((uri.clone(), self.message.clone())),
+                _ => None,
+            })
+            .collect();
+        for (_, child) in &self.ops {
+            pending.extend(child.pending());
+        }
+        pending
+    }
+
+    /// Replaces the pending attestation of `uri` on `message` with what the
+    /// calendar answered.
+    fn upgrade(&mut self, uri: &str, message: &[u8], upgraded: &Timestamp) {
+        if self.message == message {
+            let pending = Attestation::Pending(uri.to_string());
+            if self.attestations.contains(&pending) {
+                self.attestations.retain(|a| *a != pending);
+                self.merge(upgraded.clone());
+            }
+        }
+        for (_, child) in &mut self.ops {
+            child.upgrade(uri, message, upgraded);
+        }
+    }
+}
+
+/// Contents of an `.ots` file timestamping a SHA-256 digest.
+fn ots_file(timestamp: &Timestamp) -> Vec<u8> {
+    let mut out = HEADER_MAGIC.to_vec();
+    out.push(FORMAT_VERSION);
+    out.push(0x08);
+    out.extend_from_slice(&timestamp.message);
+    timestamp.write(&mut out);
+    out
+}
+
+/// Reads an `.ots` file of a SHA-256 digest.
+fn read_ots_file(bytes: &[u8]) -> Result<Timestamp> {
+    let mut reader = Reader::new(bytes);
+    if reader.take(HEADER_MAGIC.len())? != HEADER_MAGIC {
+        return Err(anyhow!("Not an OpenTimestamps proof"));
+    }
+    if reader.varuint()? != FORMAT_VERSION as u64 {
+        return Err(anyhow!("Unsupported OpenTimestamps proof version"));
+    }
+    if reader.byte()? != 0x08 {
+        return Err(anyhow!("Only SHA-256 digests are supported"));
+    }
+    let digest = reader.take(32)?.to_vec();
+    Timestamp::read(&mut reader, digest, 0)
+}
+
+fn write_varuint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varuint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Cursor over serialized proofs.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            return Err(anyhow!("Truncated timestamp"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varuint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(anyhow!("Varuint too long"))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.varuint()? as usize;
+        if len > MAX_MESSAGE_LENGTH {
+            return Err(anyhow!("Timestamp argument too long"));
+        }
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+/// Sends a request to a calendar, over TLS for `https` URLs.
+async fn calendar_request(request: Request<Body>) -> Result<(StatusCode, Vec<u8>)> {
+    let uri = request.uri().clone();
+    let host = uri.host().ok_or_else(|| anyhow!("Calendar URL without host: {}", uri))?.to_string();
+    let https = uri.scheme_str() == Some("https");
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let tcp = TcpStream::connect((host.as_str(), port)).await?;
+
+    let response = if https {
+        let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+        let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let tls = TlsConnector::from(Arc::new(config)).connect(ServerName::try_from(host)?, tcp).await?;
+        let (mut sender, connection) = hyper::client::conn::handshake(tls).await?;
+        tokio::spawn(connection);
+        sender.send_request(request).await?
+    } else {
+        let (mut sender, connection) = hyper::client::conn::handshake(tcp).await?;
+        tokio::spawn(connection);
+        sender.send_request(request).await?
+    };
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok((status, body.to_vec()))
+}
+
+fn calendar_builder(method: &str, url: &str) -> Result<hyper::http::request::Builder> {
+    let uri: hyper::Uri = url.parse()?;
+    let host = uri.authority().ok_or_else(|| anyhow!("Calendar URL without host: {}", url))?.to_string();
+    Ok(Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(hyper::header::HOST, host)
+        .header(hyper::header::ACCEPT, "application/vnd.opentimestamps.v1")
+        .header(hyper::header::USER_AGENT, "criptocracia-ec"))
+}
+
+/// Submits a digest to a calendar, which answers with a pending timestamp.
+async fn submit(calendar: &str, digest: &[u8]) -> Result<Timestamp> {
+    let request = calendar_builder("POST", &format!("{}/digest", calendar.trim_end_matches('/')))?
+        .body(Body::from(digest.to_vec()))?;
+    let (status, body) = tokio::time::timeout(CALENDAR_TIMEOUT, calendar_request(request)).await??;
+    if !status.is_success() {
+        return Err(anyhow!("Calendar {} answered {}", calendar, status));
+    }
+    Timestamp::read(&mut Reader::new(&body), digest.to_vec(), 0)
+}
+
+/// Asks a calendar for the timestamp of a commitment, `None` while it is
+/// not in a Bitcoin block yet.
+async fn fetch_upgrade(calendar: &str, commitment: &[u8]) -> Result<Option<Timestamp>> {
+    let hex: String = commitment.iter().map(|b| format!("{:02x}", b)).collect();
+    let request = calendar_builder("GET", &format!("{}/timestamp/{}", calendar.trim_end_matches('/'), hex))?
+        .body(Body::empty())?;
+    let (status, body) = tokio::time::timeout(CALENDAR_TIMEOUT, calendar_request(request)).await??;
+    match status {
+        StatusCode::OK => Ok(Some(Timestamp::read(&mut Reader::new(&body), commitment.to_vec(), 0)?)),
+        StatusCode::NOT_FOUND => Ok(None),
+        status => Err(anyhow!("Calendar {} answered {}", calendar, status)),
+    }
+}
+
+/// Anchors final results in OpenTimestamps calendars and publishes the proofs.
+pub struct Timestamper {
+    calendars: Vec<String>,
+    db: Arc<Database>,
+    relays: Arc<RelayManager>,
+    identity: EcIdentity,
+}
+
+impl Timestamper {
+    pub fn new(calendars: Vec<String>, db: Arc<Database>, relays: Arc<RelayManager>, identity: EcIdentity) -> Self {
+        Self { calendars, db, relays, identity }
+    }
+
+    /// Submits the ID of an election's final results event to the calendars
+    /// and keeps the pending proof. Each election is anchored once.
+    pub async fn stamp_results(&self, election_id: &str, event: &Event) -> Result<()> {
+        let digest = event.id.as_bytes().to_vec();
+        let mut timestamp = Timestamp::new(digest.clone());
+        for calendar in &self.calendars {
+            match submit(calendar, &digest).await {
+                Ok(pending) => timestamp.merge(pending),
+                Err(e) => log::warn!("Failed to submit results of election {} to {}: {}", election_id, calendar, e),
+            }
+        }
+        if timestamp.pending().is_empty() {
+            return Err(anyhow!("No calendar accepted the results of election {}", election_id));
+        }
+        self.db
+            .save_results_timestamp(election_id, &event.id.to_hex(), &ots_file(&timestamp))
+            .await?;
+        log::info!("Results of election {} submitted for timestamping", election_id);
+        Ok(())
+    }
+
+    /// Upgrades the pending proofs, publishing those that reached Bitcoin.
+    pub async fn upgrade_pending(&self) -> Result<()> {
+        for record in self.db.get_pending_results_timestamps().await? {
+            let mut timestamp = read_ots_file(&record.proof)?;
+            for (calendar, commitment) in timestamp.pending() {
+                // Only the calendars the EC was configured with are trusted
+                if !self.calendars.iter().any(|c| c.trim_end_matches('/') == calendar.trim_end_matches('/')) {
+                    continue;
+                }
+                match fetch_upgrade(&calendar, &commitment).await {
+                    Ok(Some(upgraded)) => timestamp.upgrade(&calendar, &commitment, &upgraded),
+                    Ok(None) => {}
+                    Err(e) => log::warn!("Failed to upgrade timestamp from {}: {}", calendar, e),
+                }
+            }
+            if !timestamp.is_complete() {
+                continue;
+            }
+
+            let proof = ots_file(&timestamp);
+            let event_id = EventId::from_hex(&record.event_id)?;
+            let event = EventBuilder::new(Kind::Custom(OTS_ATTESTATION_KIND), general_purpose::STANDARD.encode(&proof))
+                .tag(Tag::event(event_id))
+                .tag(Tag::custom(TagKind::k(), [self.relays.events().kinds.results.to_string()]))
+                .tags(self.relays.events().rehearsal_tag())
+                .sign(&self.identity.signer)
+                .await?;
+            let relays = self.relays.send_event(&event).await?;
+            log::info!(
+                "Timestamp of the results of election {} published to {} relay(s)",
+                record.election_id,
+                relays.len()
+            );
+            self.db
+                .complete_results_timestamp(&record.election_id, &proof, &event.id.to_hex())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Upgrades the pending proofs until `shutdown`.
+    pub async fn run(self: Arc<Self>, mut shutdown: watch::Receiver<bool>) {
+        let mut interval = tokio::time::interval(UPGRADE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.changed() => break,
+            }
+            if let Err(e) = self.upgrade_pending().await {
+                log::error!("Failed to upgrade results timestamps: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+
+    /// Pending timestamp as a calendar answers it, and its commitment.
+    fn pending(digest: &[u8], uri: &str) -> (Vec<u8>, Vec<u8>) {
+        let mut out = Vec::new();
+        Op::Append(vec![7; 16]).write(&mut out);
+        Op::Sha256.write(&mut out);
+        out.push(0x00);
+        Attestation::Pending(uri.to_string()).write(&mut out);
+        let commitment = Sha256::digest([digest, &[7; 16]].concat()).to_vec();
+        (out, commitment)
+    }
+
+    #[test]
+    fn test_ots_file_roundtrip() {
+        let digest = vec![1u8; 32];
+        let (calendar, _) = pending(&digest, "https://a.example");
+        let mut timestamp = Timestamp::read(&mut Reader::new(&calendar), digest.clone(), 0).unwrap();
+        let (other, _) = pending(&digest, "https://b.example");
+        timestamp.merge(Timestamp::read(&mut Reader::new(&other), digest.clone(), 0).unwrap());
+        timestamp.ops.push((Op::Prepend(vec![9]), Timestamp {
+            attestations: vec![Attestation::Bitcoin(800_000)],
+            ..Timestamp::new([&[9], &digest[..]].concat())
+        }));
+
+        let file = ots_file(&timestamp);
+        assert!(file.starts_with(HEADER_MAGIC));
+        let read = read_ots_file(&file).unwrap();
+        assert_eq!(ots_file(&read), file);
+        assert_eq!(read.pending().len(), 2);
+        assert!(read.is_complete());
+
+        assert!(read_ots_file(&file[..file.len() - 1]).is_err());
+        assert!(read_ots_file(b"not a proof").is_err());
+    }
+
+    #[test]
+    fn test_upgrade_replaces_pending_attestation() {
+        let digest = vec![2u8; 32];
+        let (calendar, commitment) = pending(&digest, "https://a.example");
+        let mut timestamp = Timestamp::read(&mut Reader::new(&calendar), digest, 0).unwrap();
+        assert!(!timestamp.is_complete());
+        assert_eq!(timestamp.pending(), vec![("https://a.example".to_string(), commitment.clone())]);
+
+        let mut upgraded = Timestamp::new(commitment.clone());
+        upgraded.ops.push((Op::Sha256, Timestamp {
+            attestations: vec![Attestation::Bitcoin(850_000)],
+            ..Timestamp::new(Sha256::digest(&commitment).to_vec())
+        }));
+        timestamp.upgrade("https://a.example", &commitment, &upgraded);
+        assert!(timestamp.is_complete());
+        assert!(timestamp.pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_calendar_round_trip() {
+        // A calendar that commits every digest it gets to block 850000
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let path = request.uri().path().to_string();
+                let response = if path == "/digest" {
+                    let digest = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                    Body::from(pending(&digest, "http://calendar.test").0)
+                } else {
+                    let mut out = Vec::new();
+                    out.push(0x00);
+                    Attestation::Bitcoin(850_000).write(&mut out);
+                    Body::from(out)
+                };
+                Ok::<_, Infallible>(hyper::Response::new(response))
+            }))
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let digest = vec![3u8; 32];
+        let timestamp = submit(&url, &digest).await.unwrap();
+        let (uri, commitment) = timestamp.pending().remove(0);
+        assert_eq!(uri, "http://calendar.test");
+        let upgraded = fetch_upgrade(&url, &commitment).await.unwrap().unwrap();
+        assert_eq!(upgraded.attestations, vec![Attestation::Bitcoin(850_000)]);
+    }
+}
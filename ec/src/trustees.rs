@@ -0,0 +1,936 @@
+/*! trustees.rs — Threshold signing of voting tokens
+With trustees, no single operator can sign voting tokens. The RSA key is
+generated and split into Shoup threshold shares (`ec --deal-trustees T/N`),
+and each token needs the signature shares of T of the N trustees. The EC
+queues every authorized token request. The trustees (`ec --trustee <share>`)
+fetch the pending requests over the trustee API and submit their shares, and
+the EC combines them into the blind signature it sends to the voter. The
+combined signature is an ordinary RSA signature, so voters see no difference.
+
+Shares are dealt by one process that holds the key only in memory, as in
+V. Shoup, "Practical Threshold Signatures" (EUROCRYPT 2000). Each share comes
+with a Nostr key the trustee signs its calls to the EC with, and with a
+verification key its signature shares are proven against; the public keys
+are kept in trustees.json, so no one else can fetch requests or submit shares
+in a trustee's name, and a bad share is refused rather than combined. */
+
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose};
+use blind_rsa_signatures::{BlindSignature, PublicKey as RSAPublicKey};
+use nostr_sdk::secp256k1::{self, schnorr};
+use nostr_sdk::{Keys, PublicKey, SECP256K1};
+use num_bigint_dig::prime::probably_prime;
+use num_bigint_dig::{BigInt, BigUint, ExtendedGcd, ModInverse, RandBigInt, Sign};
+use num_traits::{One, Signed, Zero};
+use rand::Rng;
+use rsa::PublicKeyParts;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::database::{Database, SignatureRequestRecord};
+use crate::grpc::admin_proto::trustee_service_client::TrusteeServiceClient;
+use crate::grpc::admin_proto::{ListSignatureRequestsRequest, SubmitSignatureShareRequest};
+use crate::signer::BlindSigner;
+use crate::util::write_private_file;
+
+/// Public exponent of threshold keys; a prime larger than any trustee count.
+const PUBLIC_EXPONENT: u32 = 65_537;
+
+/// Largest trustee set, which keeps `PUBLIC_EXPONENT` larger than it.
+pub const MAX_TRUSTEES: u32 = 64;
+
+/// File in the app directory holding the public parameters of the trustee set.
+pub const TRUSTEES_FILE: &str = "trustees.json";
+
+/// Seconds between a trustee's checks for new token requests.
+const POLL_INTERVAL: u64 = 5;
+
+/// Small primes sieved out before the primality tests of safe prime candidates.
+const SIEVE_PRIMES: usize = 2_000;
+
+/// Bits of the challenges of share proofs, which are SHA-256 hashes.
+const PROOF_HASH_BITS: usize = 256;
+
+/// Seconds a trustee's signed listing of the token requests is taken for,
+/// either way, so a listing seen on the wire can't be replayed later.
+const AUTH_WINDOW: u64 = 300;
+
+/// Calls of the trustee API, as signed by the trustees.
+const LIST_CALL: &str = "list";
+const SUBMIT_CALL: &str = "submit";
+
+/// Public parameters of the trustee set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrusteeSet {
+    /// Shares needed to sign a token
+    pub threshold: u32,
+    pub trustees: u32,
+}
+
+impl TrusteeSet {
+    /// Parses `T/N`, e.g. `2/3`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (threshold, trustees) = s.split_once('/').ok_or("expected THRESHOLD/TRUSTEES, e.g. 2/3")?;
+        let set = Self {
+            threshold: threshold.trim().parse().map_err(|_| "invalid threshold")?,
+            trustees: trustees.trim().parse().map_err(|_| "invalid number of trustees")?,
+        };
+        if set.threshold == 0 || set.threshold > set.trustees || set.trustees > MAX_TRUSTEES {
+            return Err(format!("the threshold must be between 1 and the number of trustees (at most {})", MAX_TRUSTEES));
+        }
+        Ok(set)
+    }
+
+    pub fn load(app_dir: &Path) -> Result<Self> {
+        let set = TrusteesFile::load(app_dir)?.set;
+        Self::parse(&format!("{}/{}", set.threshold, set.trustees)).map_err(|e| anyhow::anyhow!(e))
+    }
+
+    /// Keys the trustees sign their calls with, by index from 1.
+    pub fn load_keys(app_dir: &Path) -> Result<Vec<PublicKey>> {
+        let file = TrusteesFile::load(app_dir)?;
+        if file.keys.len() != file.set.trustees as usize {
+            return Err(anyhow::anyhow!("{} has no keys of the trustees: deal the shares again", TRUSTEES_FILE));
+        }
+        file.keys.iter().map(|key| Ok(PublicKey::from_hex(key)?)).collect()
+    }
+
+    /// Keys the trustees' signature shares are proven against.
+    pub fn load_verification(app_dir: &Path) -> Result<VerificationKeys> {
+        let file = TrusteesFile::load(app_dir)?;
+        if file.verification_base.is_empty() || file.verification_keys.len() != file.set.trustees as usize {
+            return Err(anyhow::anyhow!(
+                "{} has no verification keys of the trustees: deal the shares again",
+                TRUSTEES_FILE
+            ));
+        }
+        Ok(VerificationKeys {
+            base: decode(&file.verification_base)?,
+            keys: file.verification_keys.iter().map(|key| decode(key)).collect::<Result<_>>()?,
+        })
+    }
+
+    /// n! of the trustee count, which clears the denominators of the Lagrange
+    /// coefficients.
+    fn delta(&self) -> BigUint {
+        (1..=self.trustees).fold(BigUint::one(), |acc, i| acc * BigUint::from(i))
+    }
+}
+
+/// Contents of trustees.json: the trustee set and the trustees' keys.
+#[derive(Debug, Serialize, Deserialize)]
+struct TrusteesFile {
+    #[serde(flatten)]
+    set: TrusteeSet,
+    /// Public keys of the trustees, hex
+    #[serde(default)]
+    keys: Vec<String>,
+    /// Base of the verification keys, big-endian in Base64
+    #[serde(default)]
+    verification_base: String,
+    /// Verification keys of the trustees' shares, big-endian in Base64
+    #[serde(default)]
+    verification_keys: Vec<String>,
+}
+
+impl TrusteesFile {
+    fn load(app_dir: &Path) -> Result<Self> {
+        let path = app_dir.join(TRUSTEES_FILE);
+        let json = fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        Ok(serde_json::from_str(&json)?)
+    }
+}
+
+/// Secret share of the RSA private exponent held by one trustee.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrusteeShare {
+    /// Index of the trustee, from 1
+    pub index: u32,
+    pub threshold: u32,
+    pub trustees: u32,
+    /// RSA modulus, big-endian in Base64
+    pub modulus: String,
+    /// Share of the private exponent, big-endian in Base64
+    pub share: String,
+    /// Nostr secret key the trustee signs its calls to the EC with, hex
+    #[serde(default)]
+    pub auth_key: String,
+    /// Base of the verification keys, big-endian in Base64
+    #[serde(default)]
+    pub verification_base: String,
+}
+
+impl TrusteeShare {
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    fn set(&self) -> TrusteeSet {
+        TrusteeSet { threshold: self.threshold, trustees: self.trustees }
+    }
+
+    /// Signature share of a blinded message, x^(2·Δ·s) mod N, with the proof
+    /// that it was made with the share behind the trustee's verification key.
+    pub fn sign(&self, blind_msg: &[u8]) -> Result<(Vec<u8>, ShareProof)> {
+        let n = decode(&self.modulus)?;
+        let share = decode(&self.share)?;
+        let base = self.verification_base()?;
+        let msg = BigUint::from_bytes_be(blind_msg);
+        if msg.is_zero() || msg >= n {
+            return Err(anyhow::anyhow!("The blinded message is out of range"));
+        }
+        let delta = self.set().delta();
+        let signature_share = msg.modpow(&(BigUint::from(2u32) * &delta * &share), &n);
+        let proof = ShareProof::new(&n, &delta, &base, &msg, &share, &signature_share);
+        Ok((signature_share.to_bytes_be(), proof))
+    }
+
+    fn verification_base(&self) -> Result<BigUint> {
+        if self.verification_base.is_empty() {
+            return Err(anyhow::anyhow!("The share has no verification key: deal the shares again"));
+        }
+        decode(&self.verification_base)
+    }
+
+    /// Verification key of the share: v^s mod N.
+    fn verification_key(&self) -> Result<BigUint> {
+        Ok(self.verification_base()?.modpow(&decode(&self.share)?, &decode(&self.modulus)?))
+    }
+
+    fn auth_keys(&self) -> Result<Keys> {
+        Keys::parse(&self.auth_key).map_err(|_| anyhow::anyhow!("The share has no key to sign calls with: deal the shares again"))
+    }
+
+    /// Signature of a call to the EC with its arguments, see `auth_message`.
+    pub fn authenticate(&self, call: &str, args: &[&[u8]]) -> Result<String> {
+        Ok(self.auth_keys()?.sign_schnorr(&auth_message(call, self.index, args)).to_string())
+    }
+}
+
+/// What a trustee signs to authenticate a call: the call, the trustee's
+/// index and the call's arguments, each prefixed with its length.
+fn auth_message(call: &str, index: u32, args: &[&[u8]]) -> secp256k1::Message {
+    let mut hasher = Sha256::new();
+    hasher.update(b"criptocracia-trustee");
+    let index = index.to_be_bytes();
+    for part in [call.as_bytes(), index.as_slice()].into_iter().chain(args.iter().copied()) {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    secp256k1::Message::from_digest(hasher.finalize().into())
+}
+
+/// Verification keys of the trustees' shares: a square v mod N, and v^s_i
+/// for the share s_i of each trustee i.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerificationKeys {
+    base: BigUint,
+    /// By trustee index from 1
+    keys: Vec<BigUint>,
+}
+
+/// Proof that a signature share x_i of x was made with the share s_i behind
+/// the verification key v_i = v^s_i, that is log_v(v_i) = log_x̃(x_i²) with
+/// x̃ = x^(4·Δ): the challenge c and the response z of Shoup's proof.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareProof {
+    c: BigUint,
+    z: BigUint,
+}
+
+impl ShareProof {
+    fn new(n: &BigUint, delta: &BigUint, base: &BigUint, msg: &BigUint, share: &BigUint, signature_share: &BigUint) -> Self {
+        let x_tilde = msg.modpow(&(BigUint::from(4u32) * delta), n);
+        let key = base.modpow(share, n);
+        let square = signature_share.modpow(&BigUint::from(2u32), n);
+        let r = rand::thread_rng().gen_biguint(n.bits() + 2 * PROOF_HASH_BITS);
+        let c = proof_challenge(&[n, base, &x_tilde, &key, &square, &base.modpow(&r, n), &x_tilde.modpow(&r, n)]);
+        let z = share * &c + r;
+        Self { c, z }
+    }
+
+    /// Whether the proof holds for the signature share of `msg` and the
+    /// verification key `key`: c = H(v, x̃, v_i, x_i², v^z·v_i^-c, x̃^z·x_i^-2c).
+    fn verify(&self, n: &BigUint, delta: &BigUint, base: &BigUint, key: &BigUint, msg: &BigUint, signature_share: &BigUint) -> bool {
+        // z is below 2^(bits(N) + 2·256 + 1) for an honest trustee
+        if self.z.bits() > n.bits() + 2 * PROOF_HASH_BITS + 1 {
+            return false;
+        }
+        let x_tilde = msg.modpow(&(BigUint::from(4u32) * delta), n);
+        let square = signature_share.modpow(&BigUint::from(2u32), n);
+        let minus_c = -BigInt::from_biguint(Sign::Plus, self.c.clone());
+        let (Some(key_c), Some(square_c)) = (pow_signed(key, &minus_c, n), pow_signed(&square, &minus_c, n)) else {
+            return false;
+        };
+        let base_r = base.modpow(&self.z, n) * key_c % n;
+        let x_tilde_r = x_tilde.modpow(&self.z, n) * square_c % n;
+        proof_challenge(&[n, base, &x_tilde, key, &square, &base_r, &x_tilde_r]) == self.c
+    }
+
+    /// The challenge in 32 bytes, then the response, big-endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let c = self.c.to_bytes_be();
+        let mut bytes = vec![0u8; PROOF_HASH_BITS / 8 - c.len()];
+        bytes.extend(c);
+        bytes.extend(self.z.to_bytes_be());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() <= PROOF_HASH_BITS / 8 {
+            return None;
+        }
+        let (c, z) = bytes.split_at(PROOF_HASH_BITS / 8);
+        Some(Self { c: BigUint::from_bytes_be(c), z: BigUint::from_bytes_be(z) })
+    }
+}
+
+/// Challenge of a share proof: SHA-256 over the values, each prefixed with
+/// its length.
+fn proof_challenge(values: &[&BigUint]) -> BigUint {
+    let mut hasher = Sha256::new();
+    hasher.update(b"criptocracia-trustee-share");
+    for value in values {
+        let bytes = value.to_bytes_be();
+        hasher.update((bytes.len() as u64).to_be_bytes());
+        hasher.update(bytes);
+    }
+    BigUint::from_bytes_be(&hasher.finalize())
+}
+
+fn encode(value: &BigUint) -> String {
+    general_purpose::STANDARD.encode(value.to_bytes_be())
+}
+
+fn decode(value: &str) -> Result<BigUint> {
+    Ok(BigUint::from_bytes_be(&general_purpose::STANDARD.decode(value)?))
+}
+
+/// Generates an RSA key of `bits` from safe primes and splits its private
+/// exponent among the trustees. The private key is never written anywhere.
+pub fn deal(bits: usize, set: TrusteeSet) -> Result<(RSAPublicKey, Vec<TrusteeShare>)> {
+    let (n, shares) = deal_with(&mut rand::thread_rng(), bits, set);
+    let rsa_key = rsa::RsaPublicKey::new(n, BigUint::from(PUBLIC_EXPONENT))?;
+    Ok((RSAPublicKey(rsa_key), shares))
+}
+
+fn deal_with<R: Rng>(rng: &mut R, bits: usize, set: TrusteeSet) -> (BigUint, Vec<TrusteeShare>) {
+    let e = BigUint::from(PUBLIC_EXPONENT);
+    // N = p·q with p = 2p'+1 and q = 2q'+1; the shares are taken mod m = p'·q'
+    let (n, m) = loop {
+        let p = gen_safe_prime(rng, bits / 2);
+        let q = gen_safe_prime(rng, bits - bits / 2);
+        let n = &p * &q;
+        if p == q || n.bits() != bits {
+            continue;
+        }
+        let m = (&p >> 1) * (&q >> 1);
+        if (&m % &e).is_zero() {
+            continue;
+        }
+        break (n, m);
+    };
+    let d = e.clone().mod_inverse(&m).and_then(|d| d.to_biguint()).expect("e is invertible mod m");
+
+    // f(X) = d + a1·X + ... + a(t-1)·X^(t-1) mod m, and share i is f(i)
+    let coefficients: Vec<BigUint> = std::iter::once(d)
+        .chain((1..set.threshold).map(|_| rng.gen_biguint_below(&m)))
+        .collect();
+    // A random square generates the squares mod N with overwhelming probability
+    let base = rng.gen_biguint_below(&n).modpow(&BigUint::from(2u32), &n);
+    let shares = (1..=set.trustees)
+        .map(|i| {
+            let x = BigUint::from(i);
+            let value = coefficients
+                .iter()
+                .rev()
+                .fold(BigUint::zero(), |acc, coefficient| (acc * &x + coefficient) % &m);
+            TrusteeShare {
+                index: i,
+                threshold: set.threshold,
+                trustees: set.trustees,
+                modulus: encode(&n),
+                share: encode(&value),
+                auth_key: Keys::generate().secret_key().to_secret_hex(),
+                verification_base: encode(&base),
+            }
+        })
+        .collect();
+    (n, shares)
+}
+
+/// Random safe prime p = 2p'+1 of `bits`, with p' prime too.
+fn gen_safe_prime<R: Rng>(rng: &mut R, bits: usize) -> BigUint {
+    let small_primes = small_primes(SIEVE_PRIMES);
+    loop {
+        // p' with its top bit set, so p has exactly `bits`
+        let half = rng.gen_biguint(bits - 2) | (BigUint::one() << (bits - 2)) | BigUint::one();
+        let p = (&half << 1) + 1u32;
+        let sieved = small_primes.iter().all(|&r| {
+            let r = BigUint::from(r);
+            (half <= r || !(&half % &r).is_zero()) && (p <= r || !(&p % &r).is_zero())
+        });
+        if sieved && probably_prime(&half, 20) && probably_prime(&p, 20) {
+            return p;
+        }
+    }
+}
+
+fn small_primes(count: usize) -> Vec<u32> {
+    let mut primes: Vec<u32> = Vec::with_capacity(count);
+    let mut candidate = 3u32;
+    while primes.len() < count {
+        if primes.iter().take_while(|&&p| p * p <= candidate).all(|&p| candidate % p != 0) {
+            primes.push(candidate);
+        }
+        candidate += 2;
+    }
+    primes
+}
+
+/// Combines signature shares of a blinded message into its RSA signature.
+/// Each share is checked with its proof against its trustee's verification
+/// key, so bad shares are dropped, and the first `threshold` good shares of
+/// different trustees are combined.
+pub fn combine(
+    n: &BigUint,
+    set: TrusteeSet,
+    verification: &VerificationKeys,
+    msg: &BigUint,
+    shares: &[(u32, BigUint, ShareProof)],
+) -> Option<BigUint> {
+    let delta = set.delta();
+    let mut good: Vec<(u32, BigUint)> = Vec::with_capacity(set.threshold as usize);
+    for (index, share, proof) in shares {
+        if good.len() == set.threshold as usize {
+            break;
+        }
+        if good.iter().all(|(i, _)| i != index) && verify_share(n, &delta, verification, msg, *index, share, proof) {
+            good.push((*index, share.clone()));
+        }
+    }
+    if good.len() < set.threshold as usize {
+        return None;
+    }
+    let signature = combine_shares(n, set, msg, &good)?;
+    (signature.modpow(&BigUint::from(PUBLIC_EXPONENT), n) == *msg).then_some(signature)
+}
+
+/// Whether a signature share of `msg` was made with the share of trustee
+/// `index`, by its proof.
+fn verify_share(
+    n: &BigUint,
+    delta: &BigUint,
+    verification: &VerificationKeys,
+    msg: &BigUint,
+    index: u32,
+    share: &BigUint,
+    proof: &ShareProof,
+) -> bool {
+    let Some(key) = index.checked_sub(1).and_then(|i| verification.keys.get(i as usize)) else {
+        return false;
+    };
+    !share.is_zero() && share < n && proof.verify(n, delta, &verification.base, key, msg, share)
+}
+
+/// Combines shares of different trustees, as many as the threshold.
+fn combine_shares(n: &BigUint, set: TrusteeSet, msg: &BigUint, shares: &[(u32, BigUint)]) -> Option<BigUint> {
+    let delta = BigInt::from_biguint(Sign::Plus, set.delta());
+    // w = Π x_i^(2·λ_i), with λ_i = Δ·Π j/(j-i) the Lagrange coefficient at 0
+    let mut w = BigUint::one();
+    for (i, x) in shares {
+        let mut numerator = delta.clone();
+        let mut denominator = BigInt::one();
+        for (j, _) in shares {
+            if j != i {
+                numerator *= BigInt::from(*j);
+                denominator *= BigInt::from(*j as i64 - *i as i64);
+            }
+        }
+        let lambda = numerator / denominator * BigInt::from(2);
+        w = (w * pow_signed(x, &lambda, n)?) % n;
+    }
+    // w^e = x^(4Δ²), and a·4Δ² + b·e = 1 gives y = w^a·x^b with y^e = x
+    let e_prime = BigInt::from(4) * &delta * &delta;
+    let (gcd, a, b) = e_prime.extended_gcd(&BigInt::from(PUBLIC_EXPONENT));
+    if !gcd.is_one() {
+        return None;
+    }
+    Some((pow_signed(&w, &a, n)? * pow_signed(msg, &b, n)?) % n)
+}
+
+/// base^exponent mod n, for negative exponents too.
+fn pow_signed(base: &BigUint, exponent: &BigInt, n: &BigUint) -> Option<BigUint> {
+    let magnitude = exponent.abs().to_biguint()?;
+    if exponent.is_negative() {
+        let inverse = base.clone().mod_inverse(n)?.to_biguint()?;
+        Some(inverse.modpow(&magnitude, n))
+    } else {
+        Some(base.modpow(&magnitude, n))
+    }
+}
+
+/// Writes the trustee shares next to the public key, the trustee set and the
+/// trustees' public keys in `app_dir`. Each share is meant to be handed to its
+/// trustee and removed.
+pub fn write_dealing(app_dir: &Path, pk: &RSAPublicKey, set: TrusteeSet, shares: &[TrusteeShare]) -> Result<Vec<PathBuf>> {
+    let keys = shares
+        .iter()
+        .map(|share| Ok(share.auth_keys()?.public_key().to_hex()))
+        .collect::<Result<Vec<_>>>()?;
+    let verification_keys = shares
+        .iter()
+        .map(|share| Ok(encode(&share.verification_key()?)))
+        .collect::<Result<Vec<_>>>()?;
+    let verification_base = shares.first().map(|share| share.verification_base.clone()).unwrap_or_default();
+    write_private_file(&app_dir.join("ec_public.pem"), pk.to_pem()?.as_bytes())?;
+    write_private_file(
+        &app_dir.join(TRUSTEES_FILE),
+        serde_json::to_string_pretty(&TrusteesFile { set, keys, verification_base, verification_keys })?.as_bytes(),
+    )?;
+    let mut paths = Vec::with_capacity(shares.len());
+    for share in shares {
+        let path = app_dir.join(format!("trustee_{}.json", share.index));
+        write_private_file(&path, serde_json::to_string_pretty(share)?.as_bytes())?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Token request combined from the trustees' shares, to be sent to the voter.
+pub type SignedRequest = (SignatureRequestRecord, BlindSignature);
+
+/// Queue of the token requests the trustees sign, which combines their
+/// shares. As the EC's `BlindSigner` it only verifies tokens.
+pub struct Trustees {
+    pk: RSAPublicKey,
+    modulus: BigUint,
+    set: TrusteeSet,
+    /// Keys the trustees sign their calls with, by index from 1
+    keys: Vec<PublicKey>,
+    verification: VerificationKeys,
+    db: Arc<Database>,
+    signed: mpsc::UnboundedSender<SignedRequest>,
+}
+
+impl Trustees {
+    pub fn new(
+        pk: RSAPublicKey,
+        set: TrusteeSet,
+        keys: Vec<PublicKey>,
+        verification: VerificationKeys,
+        db: Arc<Database>,
+        signed: mpsc::UnboundedSender<SignedRequest>,
+    ) -> Result<Self> {
+        let modulus = pk.0.n().clone();
+        if *pk.0.e() != BigUint::from(PUBLIC_EXPONENT) {
+            return Err(anyhow::anyhow!("Threshold keys have the public exponent {}", PUBLIC_EXPONENT));
+        }
+        if keys.len() != set.trustees as usize {
+            return Err(anyhow::anyhow!("{} trustee keys for {} trustees", keys.len(), set.trustees));
+        }
+        if verification.keys.len() != set.trustees as usize {
+            return Err(anyhow::anyhow!(
+                "{} verification keys for {} trustees",
+                verification.keys.len(),
+                set.trustees
+            ));
+        }
+        Ok(Self { pk, modulus, set, keys, verification, db, signed })
+    }
+
+    /// Checks that trustee `index` signed the call with these arguments.
+    fn authenticate(&self, index: u32, call: &str, args: &[&[u8]], signature: &str) -> Result<(), String> {
+        let key = index
+            .checked_sub(1)
+            .and_then(|i| self.keys.get(i as usize))
+            .ok_or_else(|| format!("Trustee index must be between 1 and {}", self.set.trustees))?;
+        let message = auth_message(call, index, args);
+        let valid = schnorr::Signature::from_str(signature)
+            .ok()
+            .zip(key.xonly().ok())
+            .is_some_and(|(signature, key)| SECP256K1.verify_schnorr(&signature, &message, &key).is_ok());
+        if !valid {
+            return Err(format!("The call isn't signed by trustee {}", index));
+        }
+        Ok(())
+    }
+
+    /// Queues an authorized token request for the trustees.
+    pub async fn request(&self, election_id: &str, voter_pubkey: &str, message: &str, blinded_message: &[u8]) -> Result<String> {
+        let request = SignatureRequestRecord {
+            id: nanoid::nanoid!(),
+            election_id: election_id.to_string(),
+            voter_pubkey: voter_pubkey.to_string(),
+            message: message.to_string(),
+            blinded_message: blinded_message.to_vec(),
+            created_at: chrono::Utc::now().timestamp(),
+            shares: vec![],
+        };
+        self.db.save_signature_request(&request).await?;
+        log::info!("Token request {} queued for the trustees", request.id);
+        Ok(request.id)
+    }
+
+    /// Pending token requests the trustee hasn't signed yet, for a listing
+    /// the trustee signed at `timestamp`.
+    pub async fn pending(&self, trustee_index: u32, timestamp: u64, signature: &str) -> Result<Vec<SignatureRequestRecord>, String> {
+        self.authenticate(trustee_index, LIST_CALL, &[&timestamp.to_be_bytes()], signature)?;
+        let now = chrono::Utc::now().timestamp() as u64;
+        if now.abs_diff(timestamp) > AUTH_WINDOW {
+            return Err("The listing was signed too long ago, or the trustee's clock is off".to_string());
+        }
+        Ok(self
+            .db
+            .get_pending_signature_requests()
+            .await
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .filter(|request| request.shares.iter().all(|(index, ..)| *index != trustee_index))
+            .collect())
+    }
+
+    /// Keeps a trustee's share once its proof holds and, once there are
+    /// enough shares, combines them and completes the request. Returns
+    /// whether it's complete.
+    pub async fn submit_share(
+        &self,
+        request_id: &str,
+        trustee_index: u32,
+        share: &[u8],
+        proof: &[u8],
+        signature: &str,
+    ) -> Result<bool, String> {
+        self.authenticate(trustee_index, SUBMIT_CALL, &[request_id.as_bytes(), share, proof], signature)?;
+        let pending = self.db.get_pending_signature_requests().await.map_err(|e| e.to_string())?;
+        let Some(request) = pending.iter().find(|request| request.id == request_id) else {
+            return Err(format!("No pending token request {}", request_id));
+        };
+        let msg = BigUint::from_bytes_be(&request.blinded_message);
+        let valid = ShareProof::from_bytes(proof).is_some_and(|proof| {
+            let share = BigUint::from_bytes_be(share);
+            verify_share(&self.modulus, &self.set.delta(), &self.verification, &msg, trustee_index, &share, &proof)
+        });
+        if !valid {
+            return Err(format!("The share of trustee {} doesn't match its verification key", trustee_index));
+        }
+        if !self.db.save_signature_share(request_id, trustee_index, share, proof).await.map_err(|e| e.to_string())? {
+            return Err(format!("Trustee {} already signed request {}", trustee_index, request_id));
+        }
+
+        let Some(mut request) = self
+            .db
+            .get_pending_signature_requests()
+            .await
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .find(|request| request.id == request_id)
+        else {
+            return Ok(true);
+        };
+        if request.shares.len() < self.set.threshold as usize {
+            return Ok(false);
+        }
+        let shares: Vec<(u32, BigUint, ShareProof)> = request
+            .shares
+            .iter()
+            .filter_map(|(index, share, proof)| Some((*index, BigUint::from_bytes_be(share), ShareProof::from_bytes(proof)?)))
+            .collect();
+        let Some(signature) = combine(&self.modulus, self.set, &self.verification, &msg, &shares) else {
+            log::warn!("The {} shares of token request {} don't combine yet", shares.len(), request_id);
+            return Ok(false);
+        };
+        self.db.complete_signature_request(request_id).await.map_err(|e| e.to_string())?;
+        log::info!("Token request {} signed by the trustees", request_id);
+
+        let mut bytes = signature.to_bytes_be();
+        let modulus_bytes = self.modulus.to_bytes_be().len();
+        let mut padded = vec![0u8; modulus_bytes.saturating_sub(bytes.len())];
+        padded.append(&mut bytes);
+        request.shares.clear();
+        let _ = self.signed.send((request, BlindSignature(padded)));
+        Ok(true)
+    }
+}
+
+impl BlindSigner for Trustees {
+    fn public_key(&self) -> &RSAPublicKey {
+        &self.pk
+    }
+
+    fn blind_sign(&self, _blind_msg: &[u8]) -> Result<BlindSignature, String> {
+        Err("tokens are signed by the trustees".to_string())
+    }
+}
+
+/// Signs the EC's pending token requests with a trustee's share, once per
+/// voter of each election, for as long as it runs. The voters already signed
+/// for are kept in `<share file>.ledger`, so a restarted trustee can't be
+/// made to sign a second token for one of them.
+pub async fn run_trustee(share_path: &Path, api_url: String) -> Result<()> {
+    let share = TrusteeShare::load(share_path)?;
+    share.auth_keys()?;
+    share.verification_base()?;
+    let ledger_path = share_path.with_extension("ledger");
+    let mut signed: HashMap<(String, String), String> = match fs::read_to_string(&ledger_path) {
+        Ok(ledger) => ledger
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                Some(((fields.next()?.to_string(), fields.next()?.to_string()), fields.next()?.to_string()))
+            })
+            .collect(),
+        Err(_) => HashMap::new(),
+    };
+    println!(
+        "🔏 Trustee {} of {} ({} needed) signing token requests from {}",
+        share.index, share.trustees, share.threshold, api_url
+    );
+
+    loop {
+        if let Err(e) = sign_pending(&share, &api_url, &ledger_path, &mut signed).await {
+            log::warn!("Trustee failed to sign the pending token requests: {}", e);
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(POLL_INTERVAL)).await;
+    }
+}
+
+async fn sign_pending(
+    share: &TrusteeShare,
+    api_url: &str,
+    ledger_path: &Path,
+    signed: &mut HashMap<(String, String), String>,
+) -> Result<()> {
+    let mut client = TrusteeServiceClient::connect(api_url.to_string()).await?;
+    let timestamp = chrono::Utc::now().timestamp() as u64;
+    let response = client
+        .list_signature_requests(ListSignatureRequestsRequest {
+            trustee_index: share.index,
+            timestamp,
+            signature: share.authenticate(LIST_CALL, &[&timestamp.to_be_bytes()])?,
+        })
+        .await?
+        .into_inner();
+    if !response.success {
+        return Err(anyhow::anyhow!(response.message));
+    }
+    let requests = response.requests;
+    for request in requests {
+        let key = (request.election_id.clone(), request.voter_pubkey.clone());
+        match signed.get(&key) {
+            Some(id) if *id == request.id => {}
+            Some(id) => {
+                log::warn!(
+                    "Refusing token request {}: voter {} of election {} was already signed for in request {}",
+                    request.id, request.voter_pubkey, request.election_id, id
+                );
+                continue;
+            }
+            None => {
+                // Recorded before signing, so a crash can't lead to a second signature
+                let line = format!("{} {} {}\n", request.election_id, request.voter_pubkey, request.id);
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(ledger_path)
+                    .and_then(|mut file| std::io::Write::write_all(&mut file, line.as_bytes()))?;
+                signed.insert(key, request.id.clone());
+            }
+        }
+        let (signature_share, proof) = share.sign(&request.blinded_message)?;
+        let proof = proof.to_bytes();
+        let signature = share.authenticate(SUBMIT_CALL, &[request.id.as_bytes(), &signature_share, &proof])?;
+        let response = client
+            .submit_signature_share(SubmitSignatureShareRequest {
+                request_id: request.id.clone(),
+                trustee_index: share.index,
+                share: signature_share,
+                proof,
+                signature,
+            })
+            .await?
+            .into_inner();
+        if response.success {
+            println!("✍️ Signed token request {} for election {}", request.id, request.election_id);
+        } else {
+            log::warn!("Share for token request {} not accepted: {}", request.id, response.message);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dealing with a small key, to keep the test fast
+    fn small_dealing(set: TrusteeSet) -> (BigUint, Vec<TrusteeShare>) {
+        deal_with(&mut rand::thread_rng(), 256, set)
+    }
+
+    fn sign_with(shares: &[&TrusteeShare], msg: &BigUint) -> Vec<(u32, BigUint, ShareProof)> {
+        shares
+            .iter()
+            .map(|share| {
+                let (signature_share, proof) = share.sign(&msg.to_bytes_be()).unwrap();
+                (share.index, BigUint::from_bytes_be(&signature_share), proof)
+            })
+            .collect()
+    }
+
+    fn verification_of(shares: &[TrusteeShare]) -> VerificationKeys {
+        VerificationKeys {
+            base: shares[0].verification_base().unwrap(),
+            keys: shares.iter().map(|share| share.verification_key().unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_threshold_signatures() {
+        let set = TrusteeSet::parse("2/3").unwrap();
+        let (n, shares) = small_dealing(set);
+        let verification = verification_of(&shares);
+        let e = BigUint::from(PUBLIC_EXPONENT);
+        let msg = rand::thread_rng().gen_biguint_below(&n);
+
+        // Any two trustees sign
+        for pair in [[0, 1], [0, 2], [1, 2]] {
+            let partial = sign_with(&[&shares[pair[0]], &shares[pair[1]]], &msg);
+            let signature = combine(&n, set, &verification, &msg, &partial).unwrap();
+            assert_eq!(signature.modpow(&e, &n), msg);
+        }
+        // One doesn't, nor one twice
+        let one = sign_with(&[&shares[0]], &msg);
+        assert!(combine(&n, set, &verification, &msg, &one).is_none());
+        assert!(combine(&n, set, &verification, &msg, &[one[0].clone(), one[0].clone()]).is_none());
+
+        // The proofs hold for their own share, trustee and message alone
+        let delta = set.delta();
+        let (index, share, proof) = &one[0];
+        assert!(verify_share(&n, &delta, &verification, &msg, *index, share, proof));
+        assert!(!verify_share(&n, &delta, &verification, &msg, 2, share, proof));
+        assert!(!verify_share(&n, &delta, &verification, &(&msg + 1u32), *index, share, proof));
+        assert!(!verify_share(&n, &delta, &verification, &msg, *index, &(share + 1u32), proof));
+        assert_eq!(ShareProof::from_bytes(&proof.to_bytes()).as_ref(), Some(proof));
+        assert!(ShareProof::from_bytes(&[1; 32]).is_none());
+    }
+
+    #[test]
+    fn test_bad_shares_are_dropped() {
+        let set = TrusteeSet::parse("3/20").unwrap();
+        let (n, shares) = small_dealing(set);
+        let verification = verification_of(&shares);
+        let msg = rand::thread_rng().gen_biguint_below(&n);
+        let mut partial = sign_with(&shares.iter().collect::<Vec<_>>(), &msg);
+
+        // A bad share first, with a proof of another message, is dropped and
+        // the first three good ones combined
+        let (_, bad, proof) = sign_with(&[&shares[0]], &(&msg + 1u32)).remove(0);
+        partial[0].1 = bad;
+        partial[0].2 = proof;
+        let signature = combine(&n, set, &verification, &msg, &partial).unwrap();
+        assert_eq!(signature.modpow(&BigUint::from(PUBLIC_EXPONENT), &n), msg);
+        let good: Vec<_> = partial[1..4].to_vec();
+        assert_eq!(combine(&n, set, &verification, &msg, &good), Some(signature));
+
+        // Shares all bad combine into nothing
+        for (_, share, _) in &mut partial {
+            *share += 1u32;
+        }
+        assert!(combine(&n, set, &verification, &msg, &partial).is_none());
+    }
+
+    #[test]
+    fn test_trustee_set() {
+        assert_eq!(TrusteeSet::parse("3/5"), Ok(TrusteeSet { threshold: 3, trustees: 5 }));
+        assert!(TrusteeSet::parse("4/3").is_err());
+        assert!(TrusteeSet::parse("0/3").is_err());
+        assert!(TrusteeSet::parse("2/100").is_err());
+        assert!(TrusteeSet::parse("2").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_combines_submitted_shares() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db = Arc::new(Database::new(temp_file.path()).await.unwrap());
+        let election = crate::election::Election::new(
+            "Trusted".to_string(),
+            vec![crate::types::Candidate::new(1, "Alice")],
+            1000,
+            3600,
+            "key".to_string(),
+        );
+        db.upsert_election(&election).await.unwrap();
+
+        let set = TrusteeSet::parse("2/3").unwrap();
+        let (n, shares) = small_dealing(set);
+        let pk = RSAPublicKey(rsa::RsaPublicKey::new(n.clone(), BigUint::from(PUBLIC_EXPONENT)).unwrap());
+
+        // The trustees' keys are kept with the dealing
+        let dir = tempfile::TempDir::new().unwrap();
+        write_dealing(dir.path(), &pk, set, &shares).unwrap();
+        assert_eq!(TrusteeSet::load(dir.path()).unwrap(), set);
+        let keys = TrusteeSet::load_keys(dir.path()).unwrap();
+        assert_eq!(keys[1], shares[1].auth_keys().unwrap().public_key());
+        let verification = TrusteeSet::load_verification(dir.path()).unwrap();
+        assert_eq!(verification, verification_of(&shares));
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let trustees = Trustees::new(pk, set, keys, verification, db, tx).unwrap();
+        let list = |share: &TrusteeShare, timestamp: u64| {
+            let signature = share.authenticate(LIST_CALL, &[&timestamp.to_be_bytes()]).unwrap();
+            (share.index, timestamp, signature)
+        };
+        let submit = |share: &TrusteeShare, id: &str, bytes: &[u8], proof: &[u8]| {
+            share.authenticate(SUBMIT_CALL, &[id.as_bytes(), bytes, proof]).unwrap()
+        };
+        let now = chrono::Utc::now().timestamp() as u64;
+
+        let msg = rand::thread_rng().gen_biguint_below(&n);
+        let id = trustees.request(&election.id, "voter", "{}", &msg.to_bytes_be()).await.unwrap();
+        let (index, timestamp, signature) = list(&shares[0], now);
+        assert_eq!(trustees.pending(index, timestamp, &signature).await.unwrap().len(), 1);
+
+        // Calls not signed by the trustee, or listings signed long ago, are refused
+        let (_, _, other) = list(&shares[1], now);
+        assert!(trustees.pending(1, now, &other).await.is_err());
+        assert!(trustees.pending(1, now + 1, &signature).await.is_err());
+        let (index, timestamp, stale) = list(&shares[0], now - AUTH_WINDOW - 60);
+        assert!(trustees.pending(index, timestamp, &stale).await.is_err());
+
+        let (first, proof) = shares[0].sign(&msg.to_bytes_be()).unwrap();
+        let proof = proof.to_bytes();
+        assert!(trustees.submit_share(&id, 1, &first, &proof, &submit(&shares[1], &id, &first, &proof)).await.is_err());
+        assert!(trustees.submit_share(&id, 1, &first, &proof, "").await.is_err());
+
+        // A share that doesn't match its trustee's verification key is refused
+        let (second, _) = shares[1].sign(&msg.to_bytes_be()).unwrap();
+        let refused = trustees.submit_share(&id, 1, &second, &proof, &submit(&shares[0], &id, &second, &proof)).await;
+        assert_eq!(refused, Err("The share of trustee 1 doesn't match its verification key".to_string()));
+        let (index, timestamp, signature) = list(&shares[0], now);
+        assert_eq!(trustees.pending(index, timestamp, &signature).await.unwrap().len(), 1);
+
+        assert_eq!(trustees.submit_share(&id, 1, &first, &proof, &submit(&shares[0], &id, &first, &proof)).await, Ok(false));
+        let (index, timestamp, signature) = list(&shares[0], now);
+        assert!(trustees.pending(index, timestamp, &signature).await.unwrap().is_empty());
+        assert!(trustees.submit_share(&id, 1, &first, &proof, &submit(&shares[0], &id, &first, &proof)).await.is_err());
+        assert!(trustees.submit_share(&id, 4, &first, &proof, &submit(&shares[0], &id, &first, &proof)).await.is_err());
+
+        let (third, proof) = shares[2].sign(&msg.to_bytes_be()).unwrap();
+        let proof = proof.to_bytes();
+        assert_eq!(trustees.submit_share(&id, 3, &third, &proof, &submit(&shares[2], &id, &third, &proof)).await, Ok(true));
+        let (request, signature) = rx.try_recv().unwrap();
+        assert_eq!(request.voter_pubkey, "voter");
+        assert_eq!(BigUint::from_bytes_be(&signature.0).modpow(&BigUint::from(PUBLIC_EXPONENT), &n), msg);
+        let (index, timestamp, signature) = list(&shares[1], now);
+        assert!(trustees.pending(index, timestamp, &signature).await.unwrap().is_empty());
+    }
+}
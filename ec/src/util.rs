@@ -1,10 +1,21 @@
 use anyhow::Result;
-use blind_rsa_signatures::{PublicKey as RSAPublicKey, SecretKey as RSASecretKey};
-use chrono::Local;
-use fern::Dispatch;
+use base64::{Engine as _, engine::general_purpose};
+use blind_rsa_signatures::{KeyPair, PublicKey as RSAPublicKey, SecretKey as RSASecretKey};
+use criptocracia_protocol::descriptor::rsa_fingerprint;
+use nostr_sdk::prelude::RelayUrl;
 use std::fs;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 
+use crate::keystore;
+
+/// Sizes, in bits, of the RSA keys `--generate-keys` creates.
+pub const RSA_KEY_SIZES: [usize; 2] = [2048, 4096];
+
+/// Lock file of the EC running in the app directory, with its PID.
+pub const LOCK_FILE: &str = "ec.lock";
+
 /// Loads RSA keys from two PEM files and converts them
 /// to the `blind-rsa-signatures` types.
 pub fn load_keys<P: AsRef<Path>>(
@@ -46,7 +57,7 @@ pub fn load_keys<P: AsRef<Path>>(
     })?;
 
     // Parse the PEM to RSA objects
-    let sk = RSASecretKey::from_pem(&priv_pem).map_err(|e| {
+    let sk = parse_secret_key(&priv_pem).map_err(|e| {
         anyhow::anyhow!(
             "Failed to parse private key from {}: {}",
             priv_path.display(),
@@ -64,13 +75,108 @@ pub fn load_keys<P: AsRef<Path>>(
     Ok((pk, sk))
 }
 
+/// Parses the size of the RSA keys to generate, one of `RSA_KEY_SIZES`
+pub fn parse_key_size(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(bits) if RSA_KEY_SIZES.contains(&bits) => Ok(bits),
+        _ => Err(format!("RSA key size must be one of {:?}", RSA_KEY_SIZES)),
+    }
+}
+
+/// Generates an RSA keypair of `bits` and writes it to `ec_private.pem`
+/// (PKCS#8) and `ec_public.pem` in `app_dir`, readable by the owner only.
+/// Existing files are never overwritten.
+pub fn generate_keys<P: AsRef<Path>>(app_dir: P, bits: usize) -> Result<(RSAPublicKey, RSASecretKey)> {
+    let app_dir = app_dir.as_ref();
+    let key_pair = KeyPair::generate(&mut rand::thread_rng(), bits)
+        .map_err(|e| anyhow::anyhow!("Failed to generate a {}-bit RSA keypair: {}", bits, e))?;
+    write_private_file(&app_dir.join("ec_private.pem"), key_pair.sk.to_pem()?.as_bytes())?;
+    write_private_file(&app_dir.join("ec_public.pem"), key_pair.pk.to_pem()?.as_bytes())?;
+    Ok((key_pair.pk, key_pair.sk))
+}
+
+/// Writes a new file with permissions 0600
+pub fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", path.display(), e))?;
+    file.write_all(contents)?;
+    Ok(())
+}
+
+/// Exclusive lock of an app directory, so two ECs never take the same gift
+/// wraps and issue tokens twice. It's released when dropped or when the
+/// process dies, so there's no stale lock to clean up. Only on Unix.
+#[derive(Debug)]
+pub struct DirLock {
+    _file: fs::File,
+}
+
+impl DirLock {
+    /// Locks `app_dir`, failing if another EC holds it.
+    pub fn acquire(app_dir: &Path) -> Result<Self> {
+        let path = app_dir.join(LOCK_FILE);
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", path.display(), e))?;
+        if !try_lock(&file)? {
+            let pid = fs::read_to_string(&path).unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "Another EC (PID {}) is running in {}, stop it first or start with --force",
+                pid.trim(),
+                app_dir.display()
+            ));
+        }
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file })
+    }
+}
+
+/// Takes an exclusive `flock` on the file, false if it's held already.
+#[cfg(unix)]
+fn try_lock(file: &fs::File) -> std::io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(error),
+    }
+}
+
+#[cfg(not(unix))]
+fn try_lock(_file: &fs::File) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+/// SHA-256 fingerprint of an RSA public key, as published in the EC descriptor
+pub fn key_fingerprint(pk: &RSAPublicKey) -> Result<String> {
+    let der_b64 = general_purpose::STANDARD.encode(pk.to_der()?);
+    rsa_fingerprint(&der_b64).ok_or_else(|| anyhow::anyhow!("Failed to fingerprint the RSA public key"))
+}
+
 /// Loads RSA keys directly from PEM strings (for environment variables)
 pub fn load_keys_from_pem(
     private_pem: &str,
     public_pem: &str,
 ) -> Result<(RSAPublicKey, RSASecretKey)> {
     // Parse the PEM strings to RSA objects
-    let sk = RSASecretKey::from_pem(private_pem).map_err(|e| {
+    let sk = parse_secret_key(private_pem).map_err(|e| {
         anyhow::anyhow!(
             "Failed to parse private key from environment variable: {}",
             e
@@ -86,16 +192,46 @@ pub fn load_keys_from_pem(
     Ok((pk, sk))
 }
 
-/// Validates that all required files exist in the specified directory
-pub fn validate_required_files<P: AsRef<Path>>(app_dir: P) -> Result<()> {
+/// Parses an RSA private key PEM, decrypting it with the EC key passphrase
+/// if it's encrypted
+fn parse_secret_key(pem: &str) -> Result<RSASecretKey> {
+    if keystore::is_encrypted_pem(pem) {
+        let der = keystore::decrypt_pkcs8(pem, &keystore::passphrase()?)?;
+        return Ok(RSASecretKey::from_der(&der)?);
+    }
+    Ok(RSASecretKey::from_pem(pem)?)
+}
+
+/// Loads the RSA public key from the EC_PUBLIC_KEY environment variable, or
+/// from `ec_public.pem` in `app_dir`
+pub fn load_public_key<P: AsRef<Path>>(app_dir: P) -> Result<RSAPublicKey> {
+    let (pem, source) = match std::env::var("EC_PUBLIC_KEY") {
+        Ok(pem) => (pem, "environment variable".to_string()),
+        Err(_) => {
+            let path = app_dir.as_ref().join("ec_public.pem");
+            let pem = fs::read_to_string(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to read public key file {}: {}", path.display(), e))?;
+            (pem, path.display().to_string())
+        }
+    };
+    RSAPublicKey::from_pem(&pem).map_err(|e| anyhow::anyhow!("Failed to parse public key from {}: {}", source, e))
+}
+
+/// Validates that all required files exist in the specified directory.
+/// The private key is not required when it's held by an HSM.
+pub fn validate_required_files<P: AsRef<Path>>(app_dir: P, private_key_required: bool) -> Result<()> {
     let app_dir = app_dir.as_ref();
     let mut missing_files = Vec::new();
 
     let mut required_files = vec![];
 
     // Only require PEM files if environment variables are not set
-    if std::env::var("EC_PRIVATE_KEY").is_err() || std::env::var("EC_PUBLIC_KEY").is_err() {
-        required_files.push(("ec_private.pem", "RSA private key"));
+    if private_key_required {
+        if std::env::var("EC_PRIVATE_KEY").is_err() || std::env::var("EC_PUBLIC_KEY").is_err() {
+            required_files.push(("ec_private.pem", "RSA private key"));
+            required_files.push(("ec_public.pem", "RSA public key"));
+        }
+    } else if std::env::var("EC_PUBLIC_KEY").is_err() {
         required_files.push(("ec_public.pem", "RSA public key"));
     }
 
@@ -113,7 +249,7 @@ pub fn validate_required_files<P: AsRef<Path>>(app_dir: P) -> Result<()> {
 
     if !missing_files.is_empty() {
         return Err(anyhow::anyhow!(
-            "Required files not found in directory: {}\n\nMissing files:\n{}\n\nPlease ensure all required files are in the specified directory.",
+            "Required files not found in directory: {}\n\nMissing files:\n{}\n\nPlease ensure all required files are in the specified directory, or run with --generate-keys to create the RSA keypair.",
             app_dir.display(),
             missing_files.join("\n")
         ));
@@ -122,22 +258,76 @@ pub fn validate_required_files<P: AsRef<Path>>(app_dir: P) -> Result<()> {
     Ok(())
 }
 
-/// Initialize logger function
-pub fn setup_logger<P: AsRef<Path>>(
-    level: log::LevelFilter,
-    log_file_path: P,
-) -> Result<(), fern::InitError> {
-    Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "[{}] [{}] - {}",
-                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                record.level(),
-                message
-            ))
-        })
-        .level(level)
-        .chain(fern::log_file(log_file_path)?)
-        .apply()?;
-    Ok(())
+/// Parses the relay URLs the EC connects to, failing on the first invalid one.
+pub fn parse_relays(urls: &[String]) -> Result<Vec<RelayUrl>> {
+    if urls.is_empty() {
+        return Err(anyhow::anyhow!("At least one relay is required"));
+    }
+    urls.iter()
+        .map(|url| RelayUrl::parse(url).map_err(|e| anyhow::anyhow!("Invalid relay URL {}: {}", url, e)))
+        .collect()
+}
+
+/// URL the EC connects to its own local relay listening on `addr`
+pub fn local_relay_url(addr: SocketAddr) -> String {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => format!("ws://127.0.0.1:{}", addr.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => format!("ws://[::1]:{}", addr.port()),
+        _ => format!("ws://{}", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_relays() {
+        let relays = parse_relays(&["wss://relay.mostro.network".to_string(), "ws://localhost:7000".to_string()]).unwrap();
+        assert_eq!(relays.len(), 2);
+        assert_eq!(relays[1].as_str(), "ws://localhost:7000");
+        assert!(parse_relays(&["https://example.com".to_string()]).is_err());
+        assert!(parse_relays(&[]).is_err());
+    }
+
+    #[test]
+    fn test_generate_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        // Smaller than the sizes offered, to keep the test fast
+        let (pk, _sk) = generate_keys(dir.path(), 1024).unwrap();
+        let (loaded, _) = load_keys(dir.path().join("ec_private.pem"), dir.path().join("ec_public.pem")).unwrap();
+        assert_eq!(key_fingerprint(&loaded).unwrap(), key_fingerprint(&pk).unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.path().join("ec_private.pem")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // Never overwritten
+        assert!(generate_keys(dir.path(), 1024).is_err());
+
+        assert_eq!(parse_key_size("4096"), Ok(4096));
+        assert!(parse_key_size("1024").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dir_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = DirLock::acquire(dir.path()).unwrap();
+        assert_eq!(fs::read_to_string(dir.path().join(LOCK_FILE)).unwrap(), std::process::id().to_string());
+        let error = DirLock::acquire(dir.path()).unwrap_err().to_string();
+        assert!(error.contains(&format!("PID {}", std::process::id())));
+        // Free again once released
+        drop(lock);
+        assert!(DirLock::acquire(dir.path()).is_ok());
+    }
+
+    #[test]
+    fn test_local_relay_url() {
+        assert_eq!(local_relay_url("0.0.0.0:7000".parse().unwrap()), "ws://127.0.0.1:7000");
+        assert_eq!(local_relay_url("[::]:7000".parse().unwrap()), "ws://[::1]:7000");
+        assert_eq!(local_relay_url("192.168.1.10:7000".parse().unwrap()), "ws://192.168.1.10:7000");
+        assert!(parse_relays(&[local_relay_url("0.0.0.0:7000".parse().unwrap())]).is_ok());
+    }
 }
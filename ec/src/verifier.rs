@@ -0,0 +1,149 @@
+/*! verifier.rs — Batched verification of vote tokens
+Votes wait for their token signatures to be verified in batches, collected
+until there are `max_batch` of them or the window closes, and each batch is
+verified on all the cores off the async runtime. This keeps up with the
+surge of votes at the end of an election. */
+
+use blind_rsa_signatures::{MessageRandomizer, Signature as RSASignature};
+use criptocracia_protocol::TokenScheme;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::signer::BlindSigner;
+
+/// Token of a vote, with the message it signs.
+pub struct VoteToken {
+    pub scheme: TokenScheme,
+    pub token: RSASignature,
+    pub msg_randomizer: Option<MessageRandomizer>,
+    pub msg: Vec<u8>,
+}
+
+type Pending = (VoteToken, oneshot::Sender<bool>);
+
+/// Queue of the vote tokens waiting to be verified.
+pub struct BatchVerifier {
+    queue: mpsc::Sender<Pending>,
+}
+
+impl BatchVerifier {
+    /// Starts verifying batches of up to `max_batch` tokens, waiting at most
+    /// `window` for a batch to fill.
+    pub fn new(signer: Arc<dyn BlindSigner>, max_batch: usize, window: Duration) -> Self {
+        let max_batch = max_batch.max(1);
+        let (queue, pending) = mpsc::channel(max_batch * 4);
+        tokio::spawn(run(signer, pending, max_batch, window));
+        Self { queue }
+    }
+
+    /// Whether the token is valid, once its batch is verified.
+    pub async fn verify(&self, token: VoteToken) -> bool {
+        let (reply, valid) = oneshot::channel();
+        if self.queue.send((token, reply)).await.is_err() {
+            log::error!("Vote token verifier stopped");
+            return false;
+        }
+        valid.await.unwrap_or(false)
+    }
+}
+
+async fn run(signer: Arc<dyn BlindSigner>, mut pending: mpsc::Receiver<Pending>, max_batch: usize, window: Duration) {
+    while let Some(first) = pending.recv().await {
+        let mut batch = vec![first];
+        let deadline = tokio::time::Instant::now() + window;
+        while batch.len() < max_batch {
+            match tokio::time::timeout_at(deadline, pending.recv()).await {
+                Ok(Some(next)) => batch.push(next),
+                _ => break,
+            }
+        }
+
+        let (tokens, replies): (Vec<VoteToken>, Vec<oneshot::Sender<bool>>) = batch.into_iter().unzip();
+        log::debug!("Verifying a batch of {} vote token(s)", tokens.len());
+        let signer = Arc::clone(&signer);
+        let count = tokens.len();
+        let results = tokio::task::spawn_blocking(move || verify_all(signer.as_ref(), &tokens))
+            .await
+            .unwrap_or_else(|e| {
+                log::error!("Vote token verification failed: {}", e);
+                vec![false; count]
+            });
+        for (reply, valid) in replies.into_iter().zip(results) {
+            let _ = reply.send(valid);
+        }
+    }
+}
+
+/// Verifies the tokens in parallel, one chunk per core.
+pub fn verify_all(signer: &dyn BlindSigner, tokens: &[VoteToken]) -> Vec<bool> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = tokens.len().div_ceil(threads).max(1);
+    std::thread::scope(|scope| {
+        let chunks: Vec<_> = tokens
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let handle = scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|t| signer.verify(t.scheme, &t.token, t.msg_randomizer, &t.msg))
+                        .collect::<Vec<bool>>()
+                });
+                (chunk.len(), handle)
+            })
+            .collect();
+        chunks
+            .into_iter()
+            .flat_map(|(len, handle)| handle.join().unwrap_or_else(|_| vec![false; len]))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signer::LocalSigner;
+    use crate::util::load_keys;
+    use blind_rsa_signatures::Options;
+    use sha2::{Digest, Sha256};
+
+    fn tokens(signer: &LocalSigner, count: u8) -> Vec<VoteToken> {
+        let rng = &mut rand::thread_rng();
+        let options = Options::default();
+        (0..count)
+            .map(|i| {
+                let msg = Sha256::digest([i]).to_vec();
+                let blinding = signer.public_key().blind(rng, &msg, true, &options).unwrap();
+                let blind_sig = signer.blind_sign(&blinding.blind_msg).unwrap();
+                let token = signer
+                    .public_key()
+                    .finalize(&blind_sig, &blinding.secret, blinding.msg_randomizer, &msg, &options)
+                    .unwrap();
+                VoteToken { scheme: TokenScheme::default(), token, msg_randomizer: blinding.msg_randomizer, msg }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_all() {
+        let (pk, sk) = load_keys("ec_private.pem", "ec_public.pem").unwrap();
+        let signer = LocalSigner::new(pk, sk);
+        let mut tokens = tokens(&signer, 5);
+        tokens[3].msg = Sha256::digest(b"another vote").to_vec();
+
+        assert_eq!(verify_all(&signer, &tokens), vec![true, true, true, false, true]);
+        assert!(verify_all(&signer, &[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_batch_verifier() {
+        let (pk, sk) = load_keys("ec_private.pem", "ec_public.pem").unwrap();
+        let signer = Arc::new(LocalSigner::new(pk, sk));
+        let mut tokens = tokens(&signer, 3);
+        tokens[0].msg = Sha256::digest(b"another vote").to_vec();
+
+        let verifier = BatchVerifier::new(signer, 2, Duration::from_millis(10));
+        let results = futures_util::future::join_all(tokens.into_iter().map(|t| verifier.verify(t))).await;
+        assert_eq!(results, vec![false, true, true]);
+    }
+}
@@ -0,0 +1,137 @@
+/*! voter_import.rs — Voter rolls imported from a file at startup
+`ec --import-voters <file>` adds the voters of a JSON file to the rolls of
+their elections in the database, before the elections are loaded, as the
+AddVoter RPC would one by one: e.g. a roll kept outside the EC, or the
+voters_pubkeys.json of older ECs. The file maps election IDs to voters, each
+a name and a public key (hex or npub) or just the key:
+
+```json
+{ "a1b2": [{ "name": "Alice", "pubkey": "npub1..." }, "3f55f370..."] }
+```
+
+The whole file is checked before anything is written: every election must
+exist and still be open, and every key must be valid. Voters already on a
+roll are left as they are. */
+
+use anyhow::{Context, Result, anyhow};
+use nostr_sdk::PublicKey;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use crate::database::Database;
+use crate::types::Voter;
+
+/// Voter of the file: a name and a key, or just the key.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Voter(Voter),
+    Pubkey(String),
+}
+
+/// Reads the voters of `path` and adds those not on their election's roll
+/// yet to the database. Returns the elections and the number of voters
+/// added to each, for those with new voters.
+pub async fn import_voters(db: &Database, path: &Path) -> Result<Vec<(String, usize)>> {
+    let json = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let rolls: BTreeMap<String, Vec<Entry>> =
+        serde_json::from_str(&json).with_context(|| format!("Invalid voters file {}", path.display()))?;
+
+    // Everything is checked before the first write
+    let open: HashSet<String> = db
+        .load_all_elections()
+        .await?
+        .into_iter()
+        .filter(|e| e.status == "open")
+        .map(|e| e.id)
+        .collect();
+    let mut imports = Vec::new();
+    for (election_id, entries) in rolls {
+        if !open.contains(&election_id) {
+            return Err(anyhow!("Election {} doesn't exist or is no longer open", election_id));
+        }
+        let on_roll: HashSet<String> = db.load_election_voters(&election_id).await?.into_iter().collect();
+        let mut voters: Vec<Voter> = Vec::new();
+        for entry in entries {
+            let (name, pubkey) = match entry {
+                Entry::Voter(voter) => (voter.name, voter.pubkey),
+                Entry::Pubkey(pubkey) => (String::new(), pubkey),
+            };
+            // Stored by hex key, as AddVoter does
+            let pubkey = PublicKey::parse(&pubkey)
+                .with_context(|| format!("Invalid public key {} for election {}", pubkey, election_id))?
+                .to_hex();
+            if !on_roll.contains(&pubkey) && !voters.iter().any(|v| v.pubkey == pubkey) {
+                voters.push(Voter::new(name, pubkey));
+            }
+        }
+        imports.push((election_id, voters));
+    }
+
+    let mut added = Vec::new();
+    for (election_id, voters) in imports {
+        if voters.is_empty() {
+            continue;
+        }
+        db.save_election_voters(&election_id, &voters).await?;
+        log::info!("Imported {} voters into election {}", voters.len(), election_id);
+        added.push((election_id, voters.len()));
+    }
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::election::{Election, Status};
+    use crate::types::Candidate;
+    use nostr_sdk::nips::nip19::ToBech32;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn test_import_voters() {
+        let temp_file = tempfile::NamedTempFile::new().unwrap();
+        let db = Database::new(temp_file.path()).await.unwrap();
+        let candidates = vec![Candidate::new(1, "Alice")];
+        let election = Election::new("Open".to_string(), candidates.clone(), 1_000, 3_600, "key".to_string());
+        let mut started = Election::new("Started".to_string(), candidates, 1_000, 3_600, "key".to_string());
+        started.status = Status::InProgress;
+        db.upsert_election(&election).await.unwrap();
+        db.upsert_election(&started).await.unwrap();
+        let (alice, bob) = (nostr_sdk::Keys::generate().public_key(), nostr_sdk::Keys::generate().public_key());
+        db.save_election_voters(&election.id, &[Voter::new("Alice", alice.to_hex())]).await.unwrap();
+
+        let write = |json: String| {
+            let mut file = tempfile::NamedTempFile::new().unwrap();
+            file.write_all(json.as_bytes()).unwrap();
+            file
+        };
+        let file = write(format!(
+            r#"{{"{}": [{{"name": "Alice again", "pubkey": "{}"}}, "{}", "{}"]}}"#,
+            election.id,
+            alice.to_hex(),
+            bob.to_bech32().unwrap(),
+            bob.to_hex()
+        ));
+        assert_eq!(import_voters(&db, file.path()).await.unwrap(), vec![(election.id.clone(), 1)]);
+        let mut roll = db.load_election_voters(&election.id).await.unwrap();
+        roll.sort();
+        let mut expected = vec![alice.to_hex(), bob.to_hex()];
+        expected.sort();
+        assert_eq!(roll, expected);
+        // Nothing new the second time
+        assert!(import_voters(&db, file.path()).await.unwrap().is_empty());
+
+        // Started elections, unknown elections or bad keys fail the whole file
+        let voter = nostr_sdk::Keys::generate().public_key().to_hex();
+        for json in [
+            format!(r#"{{"{}": ["{}"], "{}": ["{}"]}}"#, election.id, voter, started.id, voter),
+            format!(r#"{{"{}": ["{}"], "none": ["{}"]}}"#, election.id, voter, voter),
+            format!(r#"{{"{}": ["{}", "not a key"]}}"#, election.id, voter),
+        ] {
+            assert!(import_voters(&db, write(json).path()).await.is_err());
+        }
+        assert_eq!(db.load_election_voters(&election.id).await.unwrap().len(), 2);
+    }
+}
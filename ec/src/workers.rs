@@ -0,0 +1,102 @@
+/*! workers.rs — Pool of workers for the inbound Nostr messages
+The relay notifications are put on a bounded queue and taken from it by a
+fixed number of workers, so decrypting, verifying and recording one message
+doesn't hold up the others: a burst of votes no longer delays the token
+requests behind it. When the queue is full the notification loop waits for a
+free slot. On shutdown the queue is closed and the workers finish the
+messages already on it. */
+
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::JoinHandle;
+
+/// Queue of messages, each handled by the first free worker.
+pub struct WorkerPool<T> {
+    queue: mpsc::Sender<T>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    /// Starts `workers` workers (at least one) handling the messages with
+    /// `handle`, with room for `capacity` messages waiting.
+    pub fn start<F, Fut>(workers: usize, capacity: usize, handle: F) -> Self
+    where
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let (queue, pending) = mpsc::channel(capacity.max(1));
+        let pending = Arc::new(Mutex::new(pending));
+        let handle = Arc::new(handle);
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let pending = Arc::clone(&pending);
+                let handle = Arc::clone(&handle);
+                tokio::spawn(async move {
+                    loop {
+                        // The lock is only held while waiting for the next message
+                        let Some(message) = pending.lock().await.recv().await else {
+                            break;
+                        };
+                        handle(message).await;
+                    }
+                })
+            })
+            .collect();
+        Self { queue, workers }
+    }
+
+    /// Queues a message, waiting while the queue is full.
+    pub async fn push(&self, message: T) {
+        if self.queue.send(message).await.is_err() {
+            log::error!("Message workers stopped");
+        }
+    }
+
+    /// Closes the queue and waits for the workers to handle what's left on it.
+    pub async fn close(self) {
+        drop(self.queue);
+        for worker in self.workers {
+            if let Err(e) = worker.await {
+                log::error!("Message worker failed: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_worker_pool() {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let (busy, most_busy) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let pool = {
+            let (handled, busy, most_busy) = (Arc::clone(&handled), Arc::clone(&busy), Arc::clone(&most_busy));
+            WorkerPool::start(3, 2, move |n: u32| {
+                let (handled, busy, most_busy) = (Arc::clone(&handled), Arc::clone(&busy), Arc::clone(&most_busy));
+                async move {
+                    let now_busy = busy.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_busy.fetch_max(now_busy, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    busy.fetch_sub(1, Ordering::SeqCst);
+                    handled.lock().await.push(n);
+                }
+            })
+        };
+        // More messages than workers and queue, so pushing waits for room
+        for n in 0..10 {
+            pool.push(n).await;
+        }
+        // Closing lets the workers finish the queued messages
+        pool.close().await;
+
+        let mut handled = handled.lock().await.clone();
+        handled.sort();
+        assert_eq!(handled, (0..10).collect::<Vec<u32>>());
+        assert_eq!(most_busy.load(Ordering::SeqCst), 3);
+    }
+}
@@ -52,6 +52,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         start_time,
         duration,
         candidates,
+        issuance_log: false,
+        token_scheme: String::new(), // rsa-pss-randomized
+        anonymous_requests: false,
+        encrypted_tally: false,
+        assign_ids: false,
     });
 
     let response = client.add_election(request).await?;
@@ -109,6 +114,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         election_id: election_id.clone(),
         candidate_id: 4,
         name: "Independent Candidate".to_string(),
+        assign_ids: false,
     });
 
     let response = client.add_candidate(request).await?;
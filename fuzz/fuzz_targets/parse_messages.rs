@@ -0,0 +1,53 @@
+//! Everything the EC parses from voters and the voters from the EC: the
+//! messages, the payloads of token requests and votes, and election events.
+//! None of them may panic, and messages that pass validation read back the
+//! same.
+
+#![no_main]
+
+use base64::engine::{Engine, general_purpose};
+use criptocracia_protocol::message::kind;
+use criptocracia_protocol::{AnonymousTokenRequest, ElectionEvent, Message, VotePayload};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(json) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    if let Ok(message) = Message::from_json(json)
+        && message.validate().is_ok()
+    {
+        assert_eq!(Message::from_json(&message.as_json()).unwrap(), message);
+        payload(message.kind, &message.payload);
+    }
+
+    // The input as the payload of each kind, without the message around it
+    for kind in [kind::TOKEN_REQUEST, kind::VOTE, kind::ANONYMOUS_TOKEN_REQUEST] {
+        let message = Message::new_with_election("f".into(), kind, json.to_string(), "a1b2".into());
+        if message.validate().is_ok() {
+            payload(kind, json);
+        }
+    }
+
+    let _ = ElectionEvent::from_json(json);
+});
+
+/// Reads a validated payload the way the EC does
+fn payload(kind: u8, payload: &str) {
+    match kind {
+        // Blinded hash of a token request
+        kind::TOKEN_REQUEST => {
+            let _ = general_purpose::STANDARD.decode(payload);
+        }
+        kind::VOTE => {
+            let _ = VotePayload::parse(payload);
+        }
+        kind::ANONYMOUS_TOKEN_REQUEST => {
+            if let Ok(request) = AnonymousTokenRequest::from_json(payload) {
+                let _ = request.proof.verify("a1b2", request.blinded_h_n.as_bytes());
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,111 @@
+use base64::engine::{Engine, general_purpose};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::roll::encode_hash;
+use crate::tally::EncryptedBallot;
+
+/// Name of the results event tag with the number of ballots counted and the
+/// hash of their list: `["ballots", "<count>", "<hash>"]`.
+pub const BALLOTS_TAG: &str = "ballots";
+
+/// Content of a ballot event (kind 35_002): a ballot the EC accepted, with
+/// nothing that identifies the voter. Anyone can fetch them all to count
+/// the votes again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublishedBallot {
+    pub election_id: String,
+    /// Hash of the voter's nonce, in Base64, as in the vote acknowledgment
+    pub h_n: String,
+    /// Candidate IDs, in order of preference for ranked elections. Empty
+    /// in elections with an encrypted tally
+    pub choices: Vec<u8>,
+    /// Encrypted ballot, in elections with an encrypted tally
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted: Option<EncryptedBallot>,
+}
+
+impl PublishedBallot {
+    pub fn new(election_id: impl Into<String>, h_n: &[u8], choices: Vec<u8>) -> Self {
+        Self {
+            election_id: election_id.into(),
+            h_n: general_purpose::STANDARD.encode(h_n),
+            choices,
+            encrypted: None,
+        }
+    }
+
+    /// Ballot of an election with an encrypted tally
+    pub fn new_encrypted(election_id: impl Into<String>, h_n: &[u8], encrypted: EncryptedBallot) -> Self {
+        Self {
+            election_id: election_id.into(),
+            h_n: general_purpose::STANDARD.encode(h_n),
+            choices: Vec::new(),
+            encrypted: Some(encrypted),
+        }
+    }
+
+    /// Hash of the voter's nonce, decoded
+    pub fn h_n_bytes(&self) -> Option<Vec<u8>> {
+        general_purpose::STANDARD.decode(&self.h_n).ok()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Line of the ballot in the hashed list: `h_n:choices`, or
+    /// `h_n:hash` with the hash of an encrypted ballot
+    pub fn line(&self) -> String {
+        if let Some(encrypted) = &self.encrypted {
+            return format!("{}:{}", self.h_n, encrypted.hash());
+        }
+        let choices: Vec<String> = self.choices.iter().map(u8::to_string).collect();
+        format!("{}:{}", self.h_n, choices.join(","))
+    }
+}
+
+/// Hash of a list of ballots, in hex: SHA-256 of their lines sorted, each
+/// ended by a line feed. It doesn't depend on the order the ballots come in.
+pub fn ballots_hash<'a>(ballots: impl IntoIterator<Item = &'a PublishedBallot>) -> String {
+    let mut lines: Vec<String> = ballots.into_iter().map(PublishedBallot::line).collect();
+    lines.sort();
+    let mut hasher = Sha256::new();
+    for line in lines {
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+    }
+    encode_hash(&hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_published_ballot_json() {
+        let ballot = PublishedBallot::new("a1b2", &[1, 2, 3], vec![2]);
+        let json = ballot.as_json();
+        assert_eq!(json, r#"{"election_id":"a1b2","h_n":"AQID","choices":[2]}"#);
+        assert_eq!(PublishedBallot::from_json(&json).unwrap(), ballot);
+        assert_eq!(PublishedBallot::new("a1b2", &[1, 2, 3], vec![3, 1]).line(), "AQID:3,1");
+    }
+
+    #[test]
+    fn test_ballots_hash() {
+        let ballots = [
+            PublishedBallot::new("a1b2", &[1], vec![1]),
+            PublishedBallot::new("a1b2", &[2], vec![2]),
+        ];
+        let hash = ballots_hash(&ballots);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(ballots_hash(ballots.iter().rev()), hash);
+        assert_ne!(ballots_hash(&ballots[..1]), hash);
+        // Known value of the empty list
+        assert_eq!(ballots_hash([]), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+}
@@ -0,0 +1,64 @@
+use serde::Serialize;
+use serde_json::Value;
+
+/// Canonical JSON of a value: no whitespace, object keys sorted by their
+/// UTF-8 bytes at every level, integers in plain digits and strings escaped
+/// as serde_json does. The same value always gives the same bytes, whatever
+/// the order of its fields or the features serde_json is built with, so
+/// published events can be compared and hashed.
+pub fn to_canonical_json<T: Serialize + ?Sized>(value: &T) -> String {
+    let value = serde_json::to_value(value).unwrap();
+    let mut json = String::new();
+    write_value(&mut json, &value);
+    json
+}
+
+fn write_value(json: &mut String, value: &Value) {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+            entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+            json.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                json.push_str(&serde_json::to_string(key).unwrap());
+                json.push(':');
+                write_value(json, value);
+            }
+            json.push('}');
+        }
+        Value::Array(items) => {
+            json.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    json.push(',');
+                }
+                write_value(json, item);
+            }
+            json.push(']');
+        }
+        _ => json.push_str(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonical_json() {
+        let value: Value = serde_json::from_str(
+            r#"{ "b": [3, {"z": null, "a": "é\n"}], "a": 18446744073709551615, "A": -1, "c": true }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            to_canonical_json(&value),
+            r#"{"A":-1,"a":18446744073709551615,"b":[3,{"a":"é\n","z":null}],"c":true}"#
+        );
+        // Field order doesn't matter
+        let reordered: Value = serde_json::from_str(r#"{"c":true,"A":-1,"b":[3,{"a":"é\n","z":null}],"a":18446744073709551615}"#).unwrap();
+        assert_eq!(to_canonical_json(&reordered), to_canonical_json(&value));
+    }
+}
@@ -0,0 +1,106 @@
+use base64::engine::{Engine, general_purpose};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::roll::encode_hash;
+use crate::version::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+/// Kind of the EC descriptor event: NIP-78 application data, identified by
+/// `DESCRIPTOR_EVENT_ID`.
+pub const DESCRIPTOR_EVENT_KIND: u16 = 30_078;
+
+/// Identifier of the EC descriptor event.
+pub const DESCRIPTOR_EVENT_ID: &str = "criptocracia-ec";
+
+/// Content of the EC descriptor event: what a client needs to talk to the
+/// EC, published so it doesn't have to be configured by hand.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EcDescriptor {
+    /// RSA public key the tokens are signed with, DER in Base64
+    pub rsa_pub_key: String,
+    /// Oldest and newest wire format versions the EC reads
+    pub min_version: u16,
+    pub max_version: u16,
+    /// How to reach the people running the EC, e.g. an email address or npub
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contact: Option<String>,
+    /// RSA public key the content of the election events is signed with,
+    /// DER in Base64. A key of its own: the token key signs any blinded
+    /// message it's sent, so it can't vouch for what the EC publishes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_key: Option<String>,
+}
+
+impl EcDescriptor {
+    /// Descriptor of an EC speaking the versions of this crate.
+    pub fn new(rsa_pub_key: impl Into<String>, contact: Option<String>) -> Self {
+        Self {
+            rsa_pub_key: rsa_pub_key.into(),
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            contact,
+            content_key: None,
+        }
+    }
+
+    /// The descriptor with the key election content is signed with.
+    pub fn with_content_key(mut self, content_key: impl Into<String>) -> Self {
+        self.content_key = Some(content_key.into());
+        self
+    }
+
+    /// Whether the EC reads messages written in `version`.
+    pub fn supports(&self, version: u16) -> bool {
+        (self.min_version..=self.max_version).contains(&version)
+    }
+
+    /// SHA-256 of the RSA public key's DER, in hex, to compare keys at a glance.
+    /// `None` if the key isn't valid Base64.
+    pub fn fingerprint(&self) -> Option<String> {
+        rsa_fingerprint(&self.rsa_pub_key)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// SHA-256 of an RSA public key given as DER in Base64, in hex.
+pub fn rsa_fingerprint(der_b64: &str) -> Option<String> {
+    let der = general_purpose::STANDARD.decode(der_b64).ok()?;
+    Some(encode_hash(&Sha256::digest(der).into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_descriptor_json() {
+        let descriptor = EcDescriptor::new("AQID", Some("admin@example.org".into()));
+        let json = descriptor.as_json();
+        assert_eq!(
+            json,
+            r#"{"rsa_pub_key":"AQID","min_version":1,"max_version":1,"contact":"admin@example.org"}"#
+        );
+        assert_eq!(EcDescriptor::from_json(&json).unwrap(), descriptor);
+        assert!(descriptor.supports(PROTOCOL_VERSION));
+        assert!(!descriptor.supports(PROTOCOL_VERSION + 1));
+
+        assert_eq!(
+            descriptor.fingerprint().unwrap(),
+            "039058c6f2c0cb492c533b0a4d14ef77cc0f78abccced5287d84a1a2011cfb81"
+        );
+        assert!(EcDescriptor::new("not base64!", None).fingerprint().is_none());
+
+        // Descriptors of older ECs have no content key
+        let signing = descriptor.clone().with_content_key("BAUG");
+        assert!(signing.as_json().ends_with(r#","content_key":"BAUG"}"#));
+        assert_eq!(EcDescriptor::from_json(&signing.as_json()).unwrap(), signing);
+        assert!(EcDescriptor::from_json(&json).unwrap().content_key.is_none());
+    }
+}
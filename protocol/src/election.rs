@@ -0,0 +1,573 @@
+use base64::engine::{Engine, general_purpose};
+use blind_rsa_signatures::{Hash, Options, PublicKey, Signature};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::canonical::to_canonical_json;
+use crate::roll::{VoterRoll, encode_hash};
+use crate::scheme::TokenScheme;
+use crate::version::{self, ProtocolError};
+
+/// Kind of the replaceable events announcing an election, identified by its ID.
+pub const ELECTION_EVENT_KIND: u16 = 35_000;
+
+/// Kind of the replaceable events with the results of an election.
+pub const RESULTS_EVENT_KIND: u16 = 35_001;
+
+/// Kind of the events publishing each accepted ballot, identified by the
+/// election ID and the ballot's `h_n`.
+pub const BALLOT_EVENT_KIND: u16 = 35_002;
+
+/// Kind of the events with the changes to the results of an election since
+/// the previous results or delta event, identified by the election ID and
+/// the number of votes counted.
+pub const RESULTS_DELTA_EVENT_KIND: u16 = 35_003;
+
+/// Kind of the replaceable events with the turnout of an election,
+/// identified by its ID.
+pub const TURNOUT_EVENT_KIND: u16 = 35_004;
+
+/// Kind of the NIP-89 handler information event where the EC advertises
+/// the kinds of the events it publishes.
+pub const KINDS_EVENT_KIND: u16 = 31_990;
+
+/// Identifier of the EC's handler information event.
+pub const KINDS_EVENT_ID: &str = "criptocracia";
+
+/// Hashtag (`t` tag) on every event of an EC running a rehearsal: its
+/// elections are drills, not to be taken as real ones. Election events also
+/// say so in their content, see `ElectionEvent::rehearsal`.
+pub const REHEARSAL_TAG: &str = "rehearsal";
+
+/// Kinds of the events an EC publishes. Deployments sharing relays with
+/// other apps may use other kinds than the default ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventKinds {
+    pub election: u16,
+    pub results: u16,
+    pub ballot: u16,
+    /// Missing from the advertisements of ECs that don't publish deltas
+    #[serde(default = "default_delta_kind")]
+    pub delta: u16,
+    /// Missing from the advertisements of ECs that don't publish turnout
+    #[serde(default = "default_turnout_kind")]
+    pub turnout: u16,
+}
+
+fn default_delta_kind() -> u16 {
+    RESULTS_DELTA_EVENT_KIND
+}
+
+fn default_turnout_kind() -> u16 {
+    TURNOUT_EVENT_KIND
+}
+
+impl Default for EventKinds {
+    fn default() -> Self {
+        Self {
+            election: ELECTION_EVENT_KIND,
+            results: RESULTS_EVENT_KIND,
+            ballot: BALLOT_EVENT_KIND,
+            delta: RESULTS_DELTA_EVENT_KIND,
+            turnout: TURNOUT_EVENT_KIND,
+        }
+    }
+}
+
+impl EventKinds {
+    /// Checks the kinds are different and addressable (30000-39999), as the
+    /// events are identified by their `d` tag.
+    pub fn validate(&self) -> Result<(), String> {
+        let kinds = self.all();
+        for (i, kind) in kinds.iter().enumerate() {
+            if !(30_000..40_000).contains(kind) {
+                return Err(format!("Kind {} is not addressable (30000-39999)", kind));
+            }
+            if kinds[..i].contains(kind) {
+                return Err("The election, results, ballot, delta and turnout kinds must be different".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// All the kinds, in the order they are advertised
+    pub fn all(&self) -> [u16; 5] {
+        [self.election, self.results, self.ballot, self.delta, self.turnout]
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// The candidates are represented by numbers
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Candidate {
+    pub id: u8,
+    pub name: String,
+    /// Optional metadata shown by the clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+impl Candidate {
+    pub fn new(id: u8, name: impl Into<String>) -> Self {
+        Self { id, name: name.into(), bio: None, url: None }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Open,
+    InProgress,
+    Finished,
+    Canceled,
+}
+
+impl Status {
+    /// Name of the status in election events
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Open => "open",
+            Status::InProgress => "in-progress",
+            Status::Finished => "finished",
+            Status::Canceled => "canceled",
+        }
+    }
+}
+
+/// How the voter marks the ballot, advertised by the election as `voting_method`.
+/// Elections that don't advertise one are plurality elections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VotingMethod {
+    /// A single candidate
+    #[default]
+    Plurality,
+    /// Any number of candidates, ticked like checkboxes
+    Approval,
+    /// Candidates in order of preference
+    Ranked,
+}
+
+/// Content of an election event (kind 35_000).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ElectionEvent {
+    /// Wire format version, 1 when missing
+    #[serde(default = "version::v1")]
+    pub version: u16,
+    pub id: String,
+    pub name: String,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub candidates: Vec<Candidate>,
+    pub status: Status,
+    /// EC's RSA public key the tokens of the election are signed with
+    pub rsa_pub_key: String,
+    #[serde(default)]
+    pub voting_method: VotingMethod,
+    /// Whether the EC records which voters were issued a token
+    #[serde(default)]
+    pub issuance_log: bool,
+    /// Commitment to the voter roll, missing while it is empty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voter_roll: Option<VoterRoll>,
+    /// Blind signature scheme of the tokens, randomized RSA-PSS when missing
+    #[serde(default, skip_serializing_if = "TokenScheme::is_default")]
+    pub token_scheme: TokenScheme,
+    /// Whether tokens sign `sha256(nonce || id || end_time)` rather than
+    /// the hash of the nonce alone, see [`crate::TokenBinding`]
+    #[serde(default)]
+    pub bound_tokens: bool,
+    /// Whether voters request tokens with a ring proof instead of their
+    /// pubkey, see [`crate::AnonymousTokenRequest`]. The roll is published
+    #[serde(default)]
+    pub anonymous_requests: bool,
+    /// Hex public key the ballots are encrypted to in elections with an
+    /// encrypted tally, see [`crate::EncryptedBallot`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tally_key: Option<String>,
+    /// Whether the election is a drill of an EC running a rehearsal
+    /// (`ec --dry-run`), not a real one
+    #[serde(default)]
+    pub rehearsal: bool,
+    /// Hex SHA-256 of the canonical JSON of the event without this field
+    /// and `content_sig`, so clients tell a change of the election from a
+    /// publication of the same state. Missing in events from older ECs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    /// Signature of the content without this field by the EC's content key,
+    /// Base64 RSA-PSS with SHA-384 and no salt: the election verifies with
+    /// the `content_key` of the EC's descriptor, wherever the event was
+    /// relayed from. Missing in events from older ECs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_sig: Option<String>,
+}
+
+/// Options of the content signatures: RSA-PSS with SHA-384 and no salt, so
+/// each content has a single signature.
+pub fn content_sig_options() -> Options {
+    Options::new(Hash::Sha384, true, 0)
+}
+
+impl ElectionEvent {
+    pub fn from_json(json: &str) -> Result<Self, ProtocolError> {
+        version::from_json(json)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Canonical JSON of the event with its content hash, as published: the
+    /// same election state always gives the same bytes.
+    pub fn as_canonical_json(&self) -> String {
+        let hashed = Self { content_hash: Some(self.compute_content_hash()), ..self.clone() };
+        to_canonical_json(&hashed)
+    }
+
+    /// Hash of the content of the event, see `content_hash`.
+    pub fn compute_content_hash(&self) -> String {
+        hash_content(&serde_json::to_value(self).unwrap())
+    }
+
+    /// Whether the published content `json` has no content hash or the hash
+    /// of its content. Checked on the JSON as published rather than on the
+    /// event it reads as, so fields added by newer ECs are hashed too.
+    pub fn content_hash_matches(json: &str) -> bool {
+        let Ok(content) = serde_json::from_str::<Value>(json) else {
+            return false;
+        };
+        match content.get("content_hash") {
+            None => content.is_object(),
+            Some(Value::String(hash)) => *hash == hash_content(&content),
+            Some(_) => false,
+        }
+    }
+
+    /// What `content_sig` signs: the canonical JSON of the event with its
+    /// content hash and without the signature.
+    pub fn signed_content(&self) -> String {
+        Self { content_sig: None, ..self.clone() }.as_canonical_json()
+    }
+
+    /// Whether the published content `json` is signed by `content_key`, the
+    /// EC's content key pinned by the client. Content without a signature
+    /// doesn't match, or stripping it would pass for an older EC. Checked on
+    /// the JSON as published, so fields added by newer ECs are signed too.
+    pub fn content_sig_matches(json: &str, content_key: &PublicKey) -> bool {
+        let Ok(Value::Object(mut content)) = serde_json::from_str::<Value>(json) else {
+            return false;
+        };
+        let sig = match content.remove("content_sig") {
+            Some(Value::String(sig)) => sig,
+            _ => return false,
+        };
+        match general_purpose::STANDARD.decode(sig) {
+            Ok(sig) => Signature(sig)
+                .verify(content_key, None, to_canonical_json(&content), &content_sig_options())
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Hex SHA-256 of the canonical JSON of election content without its
+/// `content_hash` and `content_sig`, whatever other fields it has.
+fn hash_content(content: &Value) -> String {
+    let mut content = content.clone();
+    if let Value::Object(fields) = &mut content {
+        fields.remove("content_hash");
+        fields.remove("content_sig");
+    }
+    encode_hash(&Sha256::digest(to_canonical_json(&content).as_bytes()).into())
+}
+
+/// Content of a results event (kind 35_001): votes of each candidate,
+/// as `[[candidate_id, votes], ...]` sorted by candidate ID.
+pub fn encode_results(results: &[(u8, u32)]) -> String {
+    let mut results = results.to_vec();
+    results.sort_unstable();
+    serde_json::to_string(&results).unwrap()
+}
+
+/// Reads the content of a results event.
+pub fn parse_results(json: &str) -> Result<Vec<(u8, u32)>, serde_json::Error> {
+    serde_json::from_str(json)
+}
+
+/// Content of a results delta event (kind 35_003): the votes each candidate
+/// got between two results, identified by the number of votes counted in
+/// each. Clients apply the deltas in order to the latest results event.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResultsDelta {
+    pub election_id: String,
+    /// Votes counted in the results the delta applies to
+    pub from: u32,
+    /// Votes counted once it is applied
+    pub to: u32,
+    /// New votes of each candidate that got any, as `[[candidate_id, votes], ...]`
+    pub changes: Vec<(u8, u32)>,
+}
+
+impl ResultsDelta {
+    /// Delta from the results `old` to the results `new` of an election.
+    pub fn between(election_id: impl Into<String>, old: &[(u8, u32)], new: &[(u8, u32)]) -> Self {
+        let mut changes: Vec<(u8, u32)> = new
+            .iter()
+            .filter_map(|(id, votes)| {
+                let before = old.iter().find(|(old_id, _)| old_id == id).map_or(0, |(_, v)| *v);
+                (*votes > before).then(|| (*id, votes - before))
+            })
+            .collect();
+        changes.sort_unstable();
+        Self {
+            election_id: election_id.into(),
+            from: old.iter().map(|(_, v)| v).sum(),
+            to: new.iter().map(|(_, v)| v).sum(),
+            changes,
+        }
+    }
+
+    /// Applies the delta to `results`, which must have `from` votes counted.
+    pub fn apply(&self, results: &mut Vec<(u8, u32)>) -> Result<(), String> {
+        let counted: u32 = results.iter().map(|(_, v)| v).sum();
+        if counted != self.from {
+            return Err(format!("Delta applies to {} votes, the results have {}", self.from, counted));
+        }
+        for (id, votes) in &self.changes {
+            match results.iter_mut().find(|(result_id, _)| result_id == id) {
+                Some((_, count)) => *count += votes,
+                None => results.push((*id, *votes)),
+            }
+        }
+        results.sort_unstable();
+        Ok(())
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Content of a turnout event (kind 35_004): how many voters are on the
+/// roll of an election, were issued a token and cast a ballot, as counted
+/// by the EC.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Turnout {
+    pub election_id: String,
+    /// Voters on the roll
+    pub registered: u64,
+    /// Tokens issued, to voters on the roll or to anonymous requests
+    pub tokens_issued: u64,
+    /// Ballots accepted
+    pub ballots: u64,
+    /// Ballots per hundred registered voters, to two decimals (0 with an empty roll)
+    pub turnout: f64,
+}
+
+impl Turnout {
+    pub fn new(election_id: impl Into<String>, registered: u64, tokens_issued: u64, ballots: u64) -> Self {
+        let turnout = match registered {
+            0 => 0.0,
+            registered => (ballots as f64 * 10_000.0 / registered as f64).round() / 100.0,
+        };
+        Self { election_id: election_id.into(), registered, tokens_issued, ballots, turnout }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_election_event_schema() {
+        let event = ElectionEvent {
+            version: 1,
+            id: "a1b2".into(),
+            name: "Test".into(),
+            start_time: 1_000,
+            end_time: 4_600,
+            candidates: vec![Candidate::new(1, "Alice")],
+            status: Status::InProgress,
+            rsa_pub_key: "key".into(),
+            voting_method: VotingMethod::Plurality,
+            issuance_log: false,
+            voter_roll: None,
+            token_scheme: TokenScheme::RsaPssRandomized,
+            bound_tokens: true,
+            anonymous_requests: false,
+            tally_key: None,
+            rehearsal: true,
+            content_hash: None,
+            content_sig: None,
+        };
+        let value: serde_json::Value = serde_json::from_str(&event.as_json()).unwrap();
+        assert!(value.get("token_scheme").is_none());
+        assert!(value.get("tally_key").is_none());
+        assert_eq!(value["version"], 1);
+        assert_eq!(value["status"], "in-progress");
+        assert_eq!(value["voting_method"], "plurality");
+        assert_eq!(value["bound_tokens"], true);
+        assert_eq!(value["rehearsal"], true);
+        assert_eq!(value["candidates"][0], serde_json::json!({ "id": 1, "name": "Alice" }));
+        assert_eq!(ElectionEvent::from_json(&event.as_json()).unwrap(), event);
+
+        // Older events have no version, voting method nor issuance log
+        let old = r#"{"id":"a1b2","name":"Test","start_time":1,"end_time":2,"status":"open",
+            "rsa_pub_key":"key","candidates":[{"id":2,"name":"Bob","bio":"Engineer"}]}"#;
+        let parsed = ElectionEvent::from_json(old).unwrap();
+        assert_eq!(parsed.voting_method, VotingMethod::Plurality);
+        assert_eq!(parsed.candidates[0].bio.as_deref(), Some("Engineer"));
+        assert_eq!(parsed.version, 1);
+        assert!(!parsed.issuance_log);
+        assert_eq!(parsed.token_scheme, TokenScheme::RsaPssRandomized);
+        assert!(!parsed.bound_tokens);
+        assert!(!parsed.anonymous_requests);
+        assert!(!parsed.rehearsal);
+        assert!(parsed.tally_key.is_none());
+        assert!(parsed.content_hash.is_none() && ElectionEvent::content_hash_matches(old));
+    }
+
+    #[test]
+    fn test_canonical_election_event() {
+        let mut event = ElectionEvent::from_json(
+            r#"{"status":"open","name":"Test","id":"a1b2","start_time":1,"end_time":2,"rsa_pub_key":"key",
+            "candidates":[{"name":"Bob","id":2}]}"#,
+        )
+        .unwrap();
+        let json = event.as_canonical_json();
+        assert!(json.starts_with(r#"{"anonymous_requests":false,"bound_tokens":false,"candidates":[{"id":2,"name":"Bob"}],"content_hash":""#));
+        // Read back, it has the same bytes and a hash that matches
+        let published = ElectionEvent::from_json(&json).unwrap();
+        assert!(ElectionEvent::content_hash_matches(&json));
+        assert_eq!(published.as_canonical_json(), json);
+        assert_eq!(published.content_hash, Some(event.compute_content_hash()));
+
+        // A real change changes the hash, a forged hash doesn't match
+        event.status = Status::InProgress;
+        assert_ne!(Some(event.compute_content_hash()), published.content_hash);
+        let forged = json.replace(r#""status":"open""#, r#""status":"canceled""#);
+        assert!(!ElectionEvent::content_hash_matches(&forged));
+        assert!(!ElectionEvent::content_hash_matches("[]"));
+
+        // A newer EC's field this crate doesn't know is hashed all the same
+        let mut newer: Value = serde_json::from_str(&json).unwrap();
+        newer["ballot_limit"] = serde_json::json!({"per_voter": 3});
+        newer.as_object_mut().unwrap().remove("content_hash");
+        let hash = encode_hash(&Sha256::digest(to_canonical_json(&newer).as_bytes()).into());
+        newer["content_hash"] = Value::String(hash.clone());
+        let newer = to_canonical_json(&newer);
+        assert!(ElectionEvent::content_hash_matches(&newer));
+        assert_ne!(ElectionEvent::from_json(&newer).unwrap().compute_content_hash(), hash);
+        assert!(!ElectionEvent::content_hash_matches(&newer.replace(r#""per_voter":3"#, r#""per_voter":4"#)));
+    }
+
+    #[test]
+    fn test_content_sig() {
+        use crate::scheme::BlindTokenScheme;
+        use blind_rsa_signatures::{DefaultRng, KeyPair};
+
+        let keys = KeyPair::generate(&mut DefaultRng, 2048).unwrap();
+        let mut event = ElectionEvent::from_json(
+            r#"{"id":"a1b2","name":"Test","start_time":1,"end_time":2,"candidates":[],"status":"open","rsa_pub_key":"key"}"#,
+        )
+        .unwrap();
+        // Unsigned content doesn't pass once the key is pinned
+        assert!(!ElectionEvent::content_sig_matches(&event.as_canonical_json(), &keys.pk));
+
+        // The signature of a deterministic token is the same PSS signature
+        let msg = event.signed_content();
+        let scheme = TokenScheme::RsaPssDeterministic;
+        let blinding = scheme.blind(&keys.pk, msg.as_bytes()).unwrap();
+        let blind_sig = scheme.sign(&keys.sk, &blinding.blind_msg).unwrap();
+        let sig = scheme.unblind(&keys.pk, &blind_sig, &blinding.secret, None, msg.as_bytes()).unwrap();
+        event.content_sig = Some(general_purpose::STANDARD.encode(&sig.0));
+
+        let json = event.as_canonical_json();
+        assert!(ElectionEvent::content_hash_matches(&json));
+        assert!(ElectionEvent::content_sig_matches(&json, &keys.pk));
+        let other = KeyPair::generate(&mut DefaultRng, 2048).unwrap();
+        assert!(!ElectionEvent::content_sig_matches(&json, &other.pk));
+        let forged = json.replace(r#""status":"open""#, r#""status":"canceled""#);
+        assert!(!ElectionEvent::content_sig_matches(&forged, &keys.pk));
+        let stripped = ElectionEvent { content_sig: None, ..event.clone() }.as_canonical_json();
+        assert!(!ElectionEvent::content_sig_matches(&stripped, &keys.pk));
+        let garbled = ElectionEvent { content_sig: Some("not base64".to_string()), ..event }.as_canonical_json();
+        assert!(!ElectionEvent::content_sig_matches(&garbled, &keys.pk));
+    }
+
+    #[test]
+    fn test_results_roundtrip() {
+        let json = encode_results(&[(2, 5), (1, 3)]);
+        assert_eq!(json, "[[1,3],[2,5]]");
+        assert_eq!(parse_results(&json).unwrap(), vec![(1, 3), (2, 5)]);
+        assert!(parse_results("{}").is_err());
+    }
+
+    #[test]
+    fn test_event_kinds() {
+        let kinds = EventKinds::default();
+        assert!(kinds.validate().is_ok());
+        assert_eq!(kinds.as_json(), r#"{"election":35000,"results":35001,"ballot":35002,"delta":35003,"turnout":35004}"#);
+        assert_eq!(EventKinds::from_json(&kinds.as_json()).unwrap(), kinds);
+        // Older advertisements have no delta or turnout kind
+        let old = EventKinds::from_json(r#"{"election":35000,"results":35001,"ballot":35002}"#).unwrap();
+        assert_eq!(old, kinds);
+
+        let custom = EventKinds { election: 36_000, results: 36_001, ballot: 36_002, delta: 36_003, turnout: 36_004 };
+        assert!(custom.validate().is_ok());
+        assert!(EventKinds { results: 36_000, ..custom }.validate().unwrap_err().contains("different"));
+        assert!(EventKinds { ballot: 1_000, ..custom }.validate().unwrap_err().contains("addressable"));
+        assert!(EventKinds { delta: 36_002, ..custom }.validate().unwrap_err().contains("different"));
+        assert!(EventKinds { turnout: 36_000, ..custom }.validate().unwrap_err().contains("different"));
+    }
+
+    #[test]
+    fn test_results_delta() {
+        let old = vec![(1, 3), (2, 5)];
+        let new = vec![(1, 4), (2, 5), (3, 2)];
+        let delta = ResultsDelta::between("a1b2", &old, &new);
+        assert_eq!(delta.as_json(), r#"{"election_id":"a1b2","from":8,"to":11,"changes":[[1,1],[3,2]]}"#);
+        assert_eq!(ResultsDelta::from_json(&delta.as_json()).unwrap(), delta);
+
+        let mut results = old.clone();
+        delta.apply(&mut results).unwrap();
+        assert_eq!(results, new);
+        // Not to results it doesn't follow
+        assert!(delta.apply(&mut results).is_err());
+        assert_eq!(results, new);
+    }
+
+    #[test]
+    fn test_turnout() {
+        let turnout = Turnout::new("a1b2", 3, 2, 2);
+        assert_eq!(
+            turnout.as_json(),
+            r#"{"election_id":"a1b2","registered":3,"tokens_issued":2,"ballots":2,"turnout":66.67}"#
+        );
+        assert_eq!(Turnout::from_json(&turnout.as_json()).unwrap(), turnout);
+        assert_eq!(Turnout::new("a1b2", 0, 1, 1).turnout, 0.0);
+        assert_eq!(Turnout::new("a1b2", 8, 8, 8).turnout, 100.0);
+    }
+}
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+
+/// Why the EC rejected a message, machine readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// The voter isn't on the roll, was already issued a token, or the
+    /// token of the vote isn't signed by the EC
+    Unauthorized,
+    /// The token was already used to vote
+    Duplicate,
+    /// The election isn't in progress
+    ElectionClosed,
+    /// The EC has no election with that ID
+    UnknownElection,
+    /// The message or its payload can't be read
+    BadFormat,
+    /// The message was written too long ago, or in the future
+    Expired,
+    /// The EC couldn't record it, the message can be sent again
+    Internal,
+}
+
+impl ErrorCode {
+    /// Name of the code in error messages
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::Duplicate => "duplicate",
+            ErrorCode::ElectionClosed => "election-closed",
+            ErrorCode::UnknownElection => "unknown-election",
+            ErrorCode::BadFormat => "bad-format",
+            ErrorCode::Expired => "expired",
+            ErrorCode::Internal => "internal",
+        }
+    }
+}
+
+/// Payload of an error message (kind 3), sent back to the sender of a
+/// rejected message with its ID and election.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorPayload {
+    pub code: ErrorCode,
+    /// Human readable explanation, for logs
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl ErrorPayload {
+    pub fn new(code: ErrorCode, reason: impl Into<String>) -> Self {
+        Self { code, reason: reason.into() }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_payload_json() {
+        let payload = ErrorPayload::new(ErrorCode::ElectionClosed, "Cannot receive vote: election is not in progress");
+        let json = payload.as_json();
+        assert!(json.starts_with(r#"{"code":"election-closed","#));
+        assert_eq!(ErrorPayload::from_json(&json).unwrap(), payload);
+        assert_eq!(ErrorPayload::from_json(r#"{"code":"duplicate"}"#).unwrap().reason, "");
+        assert!(ErrorPayload::from_json(r#"{"code":"other"}"#).is_err());
+        for code in [ErrorCode::Unauthorized, ErrorCode::BadFormat, ErrorCode::Expired, ErrorCode::Internal] {
+            assert_eq!(serde_json::to_value(code).unwrap(), code.as_str());
+        }
+    }
+}
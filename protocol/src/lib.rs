@@ -0,0 +1,37 @@
+//! Wire protocol of Criptocracia, shared by the EC and the voter clients:
+//! the messages gift wrapped between them, the election and results events
+//! published by the EC, the encoding of vote payloads, the voter roll
+//! commitment, the bulletin board of accepted ballots, the EC descriptor,
+//! the blind signature schemes of voting tokens and the encrypted ballots
+//! of homomorphic tallies. It also has the rotating log file the EC and the
+//! voter TUI write to.
+//! Messages and election events carry the version of the format they were
+//! written in.
+
+pub mod board;
+pub mod canonical;
+pub mod descriptor;
+pub mod election;
+pub mod error;
+pub mod log_file;
+pub mod message;
+pub mod payload;
+pub mod receipt;
+pub mod ring;
+pub mod roll;
+pub mod scheme;
+pub mod tally;
+pub mod version;
+
+pub use board::PublishedBallot;
+pub use descriptor::EcDescriptor;
+pub use election::{Candidate, ElectionEvent, EventKinds, ResultsDelta, Status, Turnout, VotingMethod};
+pub use error::{ErrorCode, ErrorPayload};
+pub use message::{Message, MessageError, Transport};
+pub use payload::{PayloadError, TokenBinding, VotePayload};
+pub use receipt::VoteAck;
+pub use ring::{AnonymousTokenRequest, RING_SIZE, RingError, RingProof};
+pub use roll::{MerkleRoll, RollProof, VoterRoll};
+pub use scheme::{BlindTokenScheme, SchemeError, TokenScheme};
+pub use tally::{EncryptedBallot, TallyError, TallyProof};
+pub use version::{PROTOCOL_VERSION, ProtocolError};
@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// When the log file is rotated, and how many rotated files are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogRotation {
+    /// Size in megabytes the log file is rotated at (0 = no limit)
+    pub max_size_mb: u64,
+    /// Hours after which the log file is rotated (0 = no limit)
+    pub max_age_hours: u64,
+    /// Rotated files kept, as `app.log.1` (the newest) to `app.log.<keep>`
+    pub keep: usize,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        Self {
+            max_size_mb: 10,
+            max_age_hours: 0,
+            keep: 5,
+        }
+    }
+}
+
+/// Log file that rotates itself once it's over the size or age limit.
+/// The limits are checked after each record, on `flush`, so records are
+/// never split across files.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_at: SystemTime,
+    max_size: u64,
+    max_age: Option<Duration>,
+    keep: usize,
+}
+
+impl RotatingFile {
+    /// Opens `path` for appending, rotating it if it's over the limits already.
+    pub fn open(path: impl Into<PathBuf>, rotation: LogRotation) -> io::Result<Self> {
+        let path = path.into();
+        let (file, size, opened_at) = open_log(&path)?;
+        let mut log = Self {
+            path,
+            file,
+            size,
+            opened_at,
+            max_size: rotation.max_size_mb * 1024 * 1024,
+            max_age: (rotation.max_age_hours > 0).then(|| Duration::from_secs(rotation.max_age_hours * 3600)),
+            keep: rotation.keep,
+        };
+        if log.is_due() {
+            log.rotate()?;
+        }
+        Ok(log)
+    }
+
+    fn is_due(&self) -> bool {
+        let too_big = self.max_size > 0 && self.size >= self.max_size;
+        let too_old = self
+            .max_age
+            .is_some_and(|max_age| self.opened_at.elapsed().unwrap_or_default() >= max_age);
+        self.size > 0 && (too_big || too_old)
+    }
+
+    /// Shifts `app.log.N` to `app.log.N+1`, dropping the oldest, and
+    /// starts a new log file.
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        let _ = fs::remove_file(rotated(self.keep.max(1)));
+        for n in (1..self.keep).rev() {
+            let _ = fs::rename(rotated(n), rotated(n + 1));
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, rotated(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        (self.file, self.size, self.opened_at) = open_log(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Opens a log file for appending, with its size and the time it was started.
+fn open_log(path: &Path) -> io::Result<(File, u64, SystemTime)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    let opened_at = metadata.created().unwrap_or_else(|_| SystemTime::now());
+    Ok((file, metadata.len(), opened_at))
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.is_due() {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("criptocracia-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        let rotated = |n: usize| dir.join(format!("app.log.{}", n));
+        let rotation = LogRotation { max_size_mb: 1, max_age_hours: 0, keep: 2 };
+        let mut log = RotatingFile::open(&path, rotation).unwrap();
+        let line = vec![b'x'; 400 * 1024];
+        for _ in 0..3 {
+            log.write_all(&line).unwrap();
+            log.flush().unwrap();
+        }
+        // Rotated after the record that went over 1 MB, not in the middle of it
+        assert_eq!(fs::metadata(rotated(1)).unwrap().len(), 3 * line.len() as u64);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        for _ in 0..6 {
+            log.write_all(&line).unwrap();
+            log.flush().unwrap();
+        }
+        assert!(rotated(2).exists());
+        assert!(!rotated(3).exists());
+
+        // An existing log over the limit is rotated when opened
+        fs::write(&path, vec![b'x'; 2 * 1024 * 1024]).unwrap();
+        drop(RotatingFile::open(&path, rotation).unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+        assert_eq!(fs::metadata(rotated(1)).unwrap().len(), 2 * 1024 * 1024);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
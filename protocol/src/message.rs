@@ -0,0 +1,321 @@
+use serde::{Deserialize, Serialize};
+
+use crate::version::{self, PROTOCOL_VERSION, ProtocolError};
+
+/// Largest payload of a message to the EC, in bytes: more than the biggest
+/// vote with an encrypted ballot that fits in a gift wrap.
+pub const MAX_PAYLOAD_SIZE: usize = 32 * 1024;
+
+/// Longest message ID, in bytes: room for a prefix and a hex key.
+pub const MAX_ID_LENGTH: usize = 128;
+
+/// Longest election ID, in bytes.
+pub const MAX_ELECTION_ID_LENGTH: usize = 64;
+
+/// Largest RSA modulus of a voting token, in bytes (8192 bits), and so the
+/// largest blinded message or token.
+pub const MAX_MODULUS_SIZE: usize = 1024;
+
+/// Longest Base64 encoding of a blinded message, token, hash or nonce.
+const MAX_BASE64_FIELD: usize = MAX_MODULUS_SIZE.div_ceil(3) * 4;
+
+/// Kinds of the messages exchanged with the EC.
+pub mod kind {
+    /// Blinded nonce hash from the voter, blind signature from the EC
+    pub const TOKEN_REQUEST: u8 = 1;
+    /// Vote from the voter
+    pub const VOTE: u8 = 2;
+    /// Rejection of a message from the EC, with an `ErrorPayload`
+    pub const ERROR: u8 = 3;
+    /// Acknowledgment of an accepted vote from the EC, with the receipt event
+    pub const ACK: u8 = 4;
+    /// Eligibility question from the voter, `eligible` or `not_eligible` from the EC
+    pub const ELIGIBILITY: u8 = 5;
+    /// `AnonymousTokenRequest` from throwaway keys, answered like a `TOKEN_REQUEST`
+    pub const ANONYMOUS_TOKEN_REQUEST: u8 = 6;
+}
+
+/// Kind of the direct messages lightweight clients exchange with the EC
+/// instead of gift wraps. Their content is NIP-44 encrypted, not NIP-04.
+pub const DM_EVENT_KIND: u16 = 4;
+
+/// How the EC sends its answers to a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// NIP-59 gift wrap, hiding the sender and the time from the relays
+    GiftWrap,
+    /// NIP-44 encrypted direct message (kind 4), for clients that can't
+    /// unwrap gift wraps. Only token requests may use it.
+    Nip44,
+}
+
+/// Why a message to the EC is refused before it is handled.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum MessageError {
+    /// Not a kind voters send
+    #[error("Unknown message kind: {0}")]
+    Kind(u8),
+    /// Longer than `MAX_ID_LENGTH` or with control characters
+    #[error("Invalid message ID")]
+    Id,
+    /// Not 1 to `MAX_ELECTION_ID_LENGTH` ASCII letters, digits, `-` or `_`
+    #[error("Invalid election ID")]
+    ElectionId,
+    /// Not a hex public key
+    #[error("Invalid reply key")]
+    ReplyTo,
+    /// Payload of that many bytes, over `MAX_PAYLOAD_SIZE`
+    #[error("Payload of {0} bytes, over the {max} byte limit", max = MAX_PAYLOAD_SIZE)]
+    PayloadSize(usize),
+    /// Payload not in the shape of its kind
+    #[error("Invalid payload: {0}")]
+    Payload(&'static str),
+}
+
+/// Whether `id` is in the format of election IDs.
+pub fn is_election_id(id: &str) -> bool {
+    (1..=MAX_ELECTION_ID_LENGTH).contains(&id.len())
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn is_base64(field: &str, max_len: usize) -> bool {
+    field.len() <= max_len && field.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'='))
+}
+
+/// Message gift wrapped (NIP-59) between a voter and the EC, as the JSON
+/// content of the rumor, or NIP-44 encrypted in a direct message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    /// Wire format version, 1 when missing
+    #[serde(default = "version::v1")]
+    pub version: u16,
+    pub id: String,
+    /// One of the `kind` constants
+    pub kind: u8,
+    pub payload: String,
+    /// Election ID for election-specific validation
+    pub election_id: Option<String>,
+    /// Hex public key the EC answers to instead of the sender's, so a vote
+    /// sent with throwaway keys still gets its acknowledgment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// How the answer is sent, the way the message came when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transport: Option<Transport>,
+}
+
+impl Message {
+    pub fn new(id: String, kind: u8, payload: String) -> Self {
+        Self { version: PROTOCOL_VERSION, id, kind, payload, election_id: None, reply_to: None, transport: None }
+    }
+
+    pub fn new_with_election(id: String, kind: u8, payload: String, election_id: String) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            kind,
+            payload,
+            election_id: Some(election_id),
+            reply_to: None,
+            transport: None,
+        }
+    }
+
+    pub fn with_reply_to(mut self, pubkey: String) -> Self {
+        self.reply_to = Some(pubkey);
+        self
+    }
+
+    pub fn with_transport(mut self, transport: Transport) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Answer to this message, in the same version, ID, election and transport.
+    pub fn reply(&self, kind: u8, payload: String) -> Self {
+        Self {
+            version: self.version,
+            id: self.id.clone(),
+            kind,
+            payload,
+            election_id: self.election_id.clone(),
+            reply_to: None,
+            transport: self.transport,
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ProtocolError> {
+        version::from_json(json)
+    }
+
+    /// Checks the fields of a message to the EC against the limits of the
+    /// protocol, so malformed or hostile messages are refused before any
+    /// decoding or cryptography. The payload is checked for the shape of
+    /// its kind, not read.
+    pub fn validate(&self) -> Result<(), MessageError> {
+        if self.id.len() > MAX_ID_LENGTH || self.id.chars().any(char::is_control) {
+            return Err(MessageError::Id);
+        }
+        if self.election_id.as_deref().is_some_and(|id| !is_election_id(id)) {
+            return Err(MessageError::ElectionId);
+        }
+        if self.reply_to.as_deref().is_some_and(|key| key.len() != 64 || !key.bytes().all(|b| b.is_ascii_hexdigit())) {
+            return Err(MessageError::ReplyTo);
+        }
+        if self.payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(MessageError::PayloadSize(self.payload.len()));
+        }
+        match self.kind {
+            kind::TOKEN_REQUEST if !is_base64(&self.payload, MAX_BASE64_FIELD) => {
+                Err(MessageError::Payload("blinded message not Base64 of an RSA modulus"))
+            }
+            kind::VOTE => {
+                // h_n:token:r:choices[:nonce:expiry], the choices checked when read
+                let parts: Vec<&str> = self.payload.split(':').collect();
+                let (fields, expiry) = match parts[..] {
+                    [h_n, token, r, _] => ([h_n, token, r, ""], ""),
+                    [h_n, token, r, _, nonce, expiry] => ([h_n, token, r, nonce], expiry),
+                    _ => return Err(MessageError::Payload("vote not in h_n:token:r:choices")),
+                };
+                if !fields.iter().all(|field| is_base64(field, MAX_BASE64_FIELD)) {
+                    return Err(MessageError::Payload("vote field not Base64 or too long"));
+                }
+                if expiry.len() > 20 || !expiry.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(MessageError::Payload("vote expiry not a timestamp"));
+                }
+                Ok(())
+            }
+            kind::TOKEN_REQUEST | kind::ANONYMOUS_TOKEN_REQUEST | kind::ELIGIBILITY => Ok(()),
+            kind => Err(MessageError::Kind(kind)),
+        }
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_json_roundtrip() {
+        let msg = Message::new("abc".into(), kind::VOTE, "payload".into());
+        let json = msg.as_json();
+        let parsed = Message::from_json(&json).expect("Should parse correctly");
+        assert_eq!(parsed, msg);
+        assert_eq!(parsed.election_id, None);
+    }
+
+    #[test]
+    fn test_message_with_election_id() {
+        let msg = Message::new_with_election("abc".into(), kind::VOTE, "payload".into(), "election123".into());
+        let json = msg.as_json();
+        assert_eq!(
+            json,
+            r#"{"version":1,"id":"abc","kind":2,"payload":"payload","election_id":"election123"}"#
+        );
+        let parsed = Message::from_json(&json).expect("Should parse correctly");
+        assert_eq!(parsed.election_id, Some("election123".to_string()));
+    }
+
+    #[test]
+    fn test_message_before_versioning() {
+        let json = r#"{"id":"abc","kind":1,"payload":"p","election_id":null,"extra":true}"#;
+        let parsed = Message::from_json(json).unwrap();
+        assert_eq!(parsed.version, 1);
+        let reply = parsed.reply(kind::TOKEN_REQUEST, "sig".into());
+        assert_eq!((reply.version, reply.id.as_str(), reply.election_id), (1, "abc", None));
+
+        let vote = Message::new("v".into(), kind::VOTE, "p".into()).with_reply_to("ab".repeat(32));
+        let parsed = Message::from_json(&vote.as_json()).unwrap();
+        assert_eq!(parsed.reply_to, vote.reply_to);
+        assert_eq!(parsed.reply(kind::ACK, String::new()).reply_to, None);
+    }
+
+    #[test]
+    fn test_message_transport() {
+        let request = Message::new("t".into(), kind::TOKEN_REQUEST, "p".into()).with_transport(Transport::Nip44);
+        let json = request.as_json();
+        assert!(json.ends_with(r#""transport":"nip44"}"#));
+        let parsed = Message::from_json(&json).unwrap();
+        // The answer goes the same way
+        assert_eq!(parsed.reply(kind::TOKEN_REQUEST, "sig".into()).transport, Some(Transport::Nip44));
+        assert!(!Message::new("t".into(), kind::VOTE, "p".into()).as_json().contains("transport"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let request = |kind, payload: &str| Message::new_with_election("m_1".into(), kind, payload.into(), "a1b2".into());
+        assert_eq!(request(kind::TOKEN_REQUEST, &"QUJD".repeat(64)).validate(), Ok(()));
+        assert_eq!(request(kind::VOTE, "aGFzaA==:dG9rZW4=::2").validate(), Ok(()));
+        assert_eq!(request(kind::VOTE, "aGFzaA==:dG9rZW4=::1,2:bm9uY2U=:1700000000").validate(), Ok(()));
+        assert_eq!(request(kind::ELIGIBILITY, "").validate(), Ok(()));
+
+        assert_eq!(request(kind::ACK, "").validate(), Err(MessageError::Kind(kind::ACK)));
+        assert_eq!(request(42, "").validate(), Err(MessageError::Kind(42)));
+        assert_eq!(
+            Message::new("x".repeat(MAX_ID_LENGTH + 1), kind::ELIGIBILITY, String::new()).validate(),
+            Err(MessageError::Id)
+        );
+        for election_id in ["", "a b", "a1b2\n", &"a".repeat(MAX_ELECTION_ID_LENGTH + 1)] {
+            let message = Message::new_with_election("m".into(), kind::ELIGIBILITY, String::new(), election_id.into());
+            assert_eq!(message.validate(), Err(MessageError::ElectionId), "{:?}", election_id);
+        }
+        assert_eq!(request(kind::VOTE, "a:b::1").with_reply_to("ab".into()).validate(), Err(MessageError::ReplyTo));
+        let oversized = request(kind::ANONYMOUS_TOKEN_REQUEST, &"{".repeat(MAX_PAYLOAD_SIZE + 1));
+        assert_eq!(oversized.validate(), Err(MessageError::PayloadSize(MAX_PAYLOAD_SIZE + 1)));
+
+        // Blinded messages and vote fields longer than any modulus
+        let long = "A".repeat(MAX_BASE64_FIELD + 4);
+        assert!(matches!(request(kind::TOKEN_REQUEST, &long).validate(), Err(MessageError::Payload(_))));
+        assert!(matches!(request(kind::TOKEN_REQUEST, "not base64!").validate(), Err(MessageError::Payload(_))));
+        assert!(matches!(request(kind::VOTE, &format!("aGFzaA==:{}::2", long)).validate(), Err(MessageError::Payload(_))));
+        assert!(matches!(request(kind::VOTE, "aGFzaA==:dG9rZW4=").validate(), Err(MessageError::Payload(_))));
+        assert!(matches!(request(kind::VOTE, "a:b::1:c:soon").validate(), Err(MessageError::Payload(_))));
+    }
+
+    /// Random mutations of valid messages never make parsing or validation
+    /// panic, and whatever passes both reads back the same.
+    #[test]
+    fn test_fuzz_parse_and_validate() {
+        let seeds = [
+            Message::new_with_election("t_1".into(), kind::TOKEN_REQUEST, "QUJDRA==".into(), "a1b2".into()).as_json(),
+            Message::new_with_election("v_1".into(), kind::VOTE, "aGFzaA==:dG9rZW4=::1,2:bm9uY2U=:9".into(), "a1b2".into())
+                .with_reply_to("ab".repeat(32))
+                .with_transport(Transport::Nip44)
+                .as_json(),
+        ];
+        // xorshift, so failures reproduce
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let alphabet = b"{}[]\":,:0123456789-abcdefAZ+/= \\\x00\n";
+        for _ in 0..20_000 {
+            let mut bytes = seeds[next() as usize % seeds.len()].clone().into_bytes();
+            for _ in 0..=next() % 4 {
+                let at = next() as usize % (bytes.len() + 1);
+                match next() % 3 {
+                    0 if at < bytes.len() => bytes[at] = alphabet[next() as usize % alphabet.len()],
+                    1 if at < bytes.len() => {
+                        bytes.remove(at);
+                    }
+                    _ => bytes.insert(at, alphabet[next() as usize % alphabet.len()]),
+                }
+            }
+            let Ok(json) = String::from_utf8(bytes) else { continue };
+            if let Ok(message) = Message::from_json(&json) {
+                if message.validate().is_ok() {
+                    assert_eq!(Message::from_json(&message.as_json()).unwrap(), message);
+                }
+                let _ = crate::VotePayload::parse(&message.payload);
+            }
+        }
+    }
+}
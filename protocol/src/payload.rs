@@ -0,0 +1,189 @@
+use base64::engine::{Engine, general_purpose};
+use sha2::{Digest, Sha256};
+
+use crate::tally::EncryptedBallot;
+
+/// Payload of a vote message: `h_n:token:r:choices`, each cryptographic part
+/// encoded in Base64 and the chosen candidate IDs separated by commas, a
+/// single ID for plurality elections. `r` is empty in token schemes without
+/// a randomizer. Votes with bound tokens append `:nonce:expiry`. In elections
+/// with an encrypted tally, the choices are replaced by the encrypted ballot
+/// in Base64 JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VotePayload {
+    /// Hash of the voter's nonce, the message the token signs
+    pub h_n: Vec<u8>,
+    /// Unblinded signature of the EC
+    pub token: Vec<u8>,
+    /// Randomizer the hash was signed with, if the token scheme has one
+    pub r: Option<[u8; 32]>,
+    /// Candidate IDs, in order of preference for ranked elections. Empty
+    /// with an encrypted ballot
+    pub choices: Vec<u8>,
+    /// What `h_n` hashes, in elections with bound tokens
+    pub binding: Option<TokenBinding>,
+    /// Ballot, in elections with an encrypted tally
+    pub encrypted: Option<EncryptedBallot>,
+}
+
+/// Nonce and expiry a token is bound to an election with: the token signs
+/// `sha256(nonce || election_id || expiry)`, so it can't be redeemed in
+/// another election or after it expires.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenBinding {
+    pub nonce: Vec<u8>,
+    /// Unix timestamp, the end of the election
+    pub expiry: u64,
+}
+
+impl TokenBinding {
+    /// The hash the token of this binding signs in the election.
+    pub fn hash(&self, election_id: &str) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.nonce);
+        hasher.update(election_id.as_bytes());
+        hasher.update(self.expiry.to_be_bytes());
+        hasher.finalize().to_vec()
+    }
+}
+
+/// Why a vote payload can't be read.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum PayloadError {
+    /// Not four or six parts separated by `:`
+    #[error("Invalid vote format")]
+    Format,
+    #[error("Failed to decode h_n: {0}")]
+    HashEncoding(String),
+    #[error("Failed to decode token: {0}")]
+    TokenEncoding(String),
+    #[error("Failed to decode randomizer: {0}")]
+    RandomizerEncoding(String),
+    #[error("Invalid randomizer length")]
+    RandomizerLength,
+    #[error("Failed to parse vote: {0}")]
+    Choices(String),
+    #[error("Failed to decode nonce: {0}")]
+    NonceEncoding(String),
+    #[error("Failed to parse expiry: {0}")]
+    Expiry(String),
+}
+
+impl VotePayload {
+    pub fn encode(&self) -> String {
+        let b64 = &general_purpose::STANDARD;
+        let choices = match &self.encrypted {
+            Some(encrypted) => b64.encode(encrypted.as_json()),
+            None => self.choices.iter().map(u8::to_string).collect::<Vec<_>>().join(","),
+        };
+        let mut payload = format!(
+            "{}:{}:{}:{}",
+            b64.encode(&self.h_n),
+            b64.encode(&self.token),
+            self.r.map(|r| b64.encode(r)).unwrap_or_default(),
+            choices
+        );
+        if let Some(binding) = &self.binding {
+            payload.push_str(&format!(":{}:{}", b64.encode(&binding.nonce), binding.expiry));
+        }
+        payload
+    }
+
+    pub fn parse(payload: &str) -> Result<Self, PayloadError> {
+        let b64 = &general_purpose::STANDARD;
+        let parts: Vec<&str> = payload.split(':').collect();
+        let (h_n, token, r, choices, binding) = match parts[..] {
+            [h_n, token, r, choices] => (h_n, token, r, choices, None),
+            [h_n, token, r, choices, nonce, expiry] => (h_n, token, r, choices, Some((nonce, expiry))),
+            _ => return Err(PayloadError::Format),
+        };
+        let h_n = b64.decode(h_n).map_err(|e| PayloadError::HashEncoding(e.to_string()))?;
+        let token = b64.decode(token).map_err(|e| PayloadError::TokenEncoding(e.to_string()))?;
+        let r = if r.is_empty() {
+            None
+        } else {
+            let r = b64
+                .decode(r)
+                .map_err(|e| PayloadError::RandomizerEncoding(e.to_string()))?;
+            Some(r.try_into().map_err(|_| PayloadError::RandomizerLength)?)
+        };
+        // Candidate IDs are digits, encrypted ballots Base64 JSON
+        let (choices, encrypted) = if choices.chars().all(|c| c.is_ascii_digit() || c == ',' || c == ' ') {
+            let choices = choices
+                .split(',')
+                .map(|c| c.trim().parse::<u8>())
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|e| PayloadError::Choices(e.to_string()))?;
+            (choices, None)
+        } else {
+            let json = b64.decode(choices).map_err(|e| PayloadError::Choices(e.to_string()))?;
+            let encrypted = std::str::from_utf8(&json)
+                .map_err(|e| e.to_string())
+                .and_then(|json| EncryptedBallot::from_json(json).map_err(|e| e.to_string()))
+                .map_err(PayloadError::Choices)?;
+            (Vec::new(), Some(encrypted))
+        };
+        let binding = match binding {
+            Some((nonce, expiry)) => Some(TokenBinding {
+                nonce: b64.decode(nonce).map_err(|e| PayloadError::NonceEncoding(e.to_string()))?,
+                expiry: expiry.parse().map_err(|e: std::num::ParseIntError| PayloadError::Expiry(e.to_string()))?,
+            }),
+            None => None,
+        };
+        Ok(Self { h_n, token, r, choices, binding, encrypted })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vote_payload_roundtrip() {
+        let payload =
+            VotePayload { h_n: vec![1, 2, 3], token: vec![4, 5], r: Some([7; 32]), choices: vec![3, 1], binding: None, encrypted: None };
+        let encoded = payload.encode();
+        assert!(encoded.starts_with("AQID:BAU=:"));
+        assert!(encoded.ends_with(":3,1"));
+        assert_eq!(VotePayload::parse(&encoded).unwrap(), payload);
+
+        // Deterministic token schemes have no randomizer
+        let payload = VotePayload { r: None, ..payload };
+        assert_eq!(payload.encode(), "AQID:BAU=::3,1");
+        assert_eq!(VotePayload::parse("AQID:BAU=::3,1").unwrap(), payload);
+
+        // Bound tokens carry what the hash was computed from
+        let binding = TokenBinding { nonce: vec![9, 9], expiry: 1_700_000_000 };
+        let payload = VotePayload { binding: Some(binding.clone()), ..payload };
+        assert_eq!(payload.encode(), "AQID:BAU=::3,1:CQk=:1700000000");
+        assert_eq!(VotePayload::parse("AQID:BAU=::3,1:CQk=:1700000000").unwrap(), payload);
+
+        // Encrypted ballots take the place of the choices
+        let key = secp256k1::SecretKey::new(&mut secp256k1::rand::thread_rng()).public_key(&secp256k1::Secp256k1::new());
+        let encrypted = EncryptedBallot::encrypt(&key, 2, 0, "a1b2", &[1, 2, 3]).unwrap();
+        let payload = VotePayload { choices: Vec::new(), encrypted: Some(encrypted), ..payload };
+        assert_eq!(VotePayload::parse(&payload.encode()).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_token_binding() {
+        let binding = TokenBinding { nonce: vec![9; 16], expiry: 1_700_000_000 };
+        assert_eq!(binding.hash("a1b2"), binding.hash("a1b2"));
+        assert_ne!(binding.hash("a1b2"), binding.hash("c3d4"));
+        let later = TokenBinding { expiry: 1_800_000_000, ..binding.clone() };
+        assert_ne!(binding.hash("a1b2"), later.hash("a1b2"));
+    }
+
+    #[test]
+    fn test_vote_payload_errors() {
+        let r = general_purpose::STANDARD.encode([7u8; 32]);
+        assert_eq!(VotePayload::parse("a:b:c"), Err(PayloadError::Format));
+        assert_eq!(VotePayload::parse("a:b:c:1:d"), Err(PayloadError::Format));
+        assert!(matches!(VotePayload::parse("AQID:AQID::1:CQk=:soon"), Err(PayloadError::Expiry(_))));
+        assert!(matches!(VotePayload::parse(&format!("***:AQID:{r}:1")), Err(PayloadError::HashEncoding(_))));
+        assert!(matches!(VotePayload::parse(&format!("AQID:***:{r}:1")), Err(PayloadError::TokenEncoding(_))));
+        assert_eq!(VotePayload::parse("AQID:AQID:AQID:1"), Err(PayloadError::RandomizerLength));
+        let error = VotePayload::parse(&format!("AQID:AQID:{r}:x")).unwrap_err();
+        assert!(error.to_string().starts_with("Failed to parse vote"));
+    }
+}
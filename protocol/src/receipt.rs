@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+
+/// Content of the receipt event the EC signs with its Nostr key when it
+/// accepts a vote, sent back in an acknowledgment (kind 4). Anyone holding
+/// the nonce can check it was counted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VoteAck {
+    pub election_id: String,
+    /// Hash of the voter's nonce, in Base64
+    pub h_n: String,
+    /// Unix time the EC recorded the vote
+    pub accepted_at: u64,
+}
+
+impl VoteAck {
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vote_ack_json() {
+        let ack = VoteAck { election_id: "a1b2".into(), h_n: "AQID".into(), accepted_at: 1_700_000_000 };
+        let json = ack.as_json();
+        assert_eq!(json, r#"{"election_id":"a1b2","h_n":"AQID","accepted_at":1700000000}"#);
+        assert_eq!(VoteAck::from_json(&json).unwrap(), ack);
+        assert!(VoteAck::from_json(r#"{"election_id":"a1b2"}"#).is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use secp256k1::rand::seq::SliceRandom;
+use secp256k1::rand::thread_rng;
+use secp256k1::{Parity, PublicKey, Scalar, Secp256k1, SecretKey, XOnlyPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::roll::{decode_hash, encode_hash};
+
+/// Members of the ring a voter proves membership in, itself included, unless
+/// the roll is smaller. Proofs grow linearly with the ring.
+pub const RING_SIZE: usize = 64;
+
+/// Linkable ring signature (LSAG) over secp256k1, proving that the request
+/// was made by one of the `ring` voters without telling which. The key image
+/// is the same for every proof of a voter in an election, so the EC can
+/// refuse a second token without learning who asked. It is bound to the
+/// election, so requests in different elections can't be linked.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RingProof {
+    /// Hex x-only pubkeys of the ring, sorted
+    pub ring: Vec<String>,
+    /// Key image of the signer in the election, a hex compressed point
+    pub key_image: String,
+    /// Challenge of the first member, in hex
+    pub c: String,
+    /// Response of each member, in hex
+    pub s: Vec<String>,
+}
+
+/// Why a ring proof can't be made.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum RingError {
+    /// A ring member isn't a valid x-only pubkey
+    #[error("Invalid ring member: {0}")]
+    InvalidMember(String),
+    /// The signer isn't in the ring
+    #[error("The voter isn't in the ring")]
+    NotInRing,
+    /// A point or scalar operation failed, which is negligibly unlikely
+    #[error("Ring signature arithmetic failed")]
+    Arithmetic,
+}
+
+impl From<secp256k1::Error> for RingError {
+    fn from(_: secp256k1::Error) -> Self {
+        RingError::Arithmetic
+    }
+}
+
+impl RingProof {
+    /// Signs `msg` in the election as one of `ring`, with the voter's secret key.
+    /// The ring is sorted and deduplicated first, so its order reveals nothing.
+    pub fn sign(secret: &SecretKey, ring: &[String], election_id: &str, msg: &[u8]) -> Result<Self, RingError> {
+        let secp = Secp256k1::new();
+        let mut ring: Vec<String> = ring.iter().map(|pk| pk.to_lowercase()).collect();
+        ring.sort();
+        ring.dedup();
+        let points = ring
+            .iter()
+            .map(|pk| member_point(pk).ok_or_else(|| RingError::InvalidMember(pk.clone())))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Nostr keys are x-only: sign with the secret of the even-y point
+        let (xonly, parity) = secret.x_only_public_key(&secp);
+        let secret = match parity {
+            Parity::Even => *secret,
+            Parity::Odd => secret.negate(),
+        };
+        let own = xonly.to_string();
+        let signer = ring.iter().position(|pk| *pk == own).ok_or(RingError::NotInRing)?;
+
+        let n = ring.len();
+        let hashed: Vec<PublicKey> = ring.iter().map(|pk| hash_to_point(election_id, pk)).collect();
+        let key_image = hashed[signer].mul_tweak(&secp, &Scalar::from(secret))?;
+
+        let rng = &mut thread_rng();
+        let alpha = SecretKey::new(rng);
+        let mut c: Vec<Option<SecretKey>> = vec![None; n];
+        let mut s: Vec<Option<SecretKey>> = vec![None; n];
+        c[(signer + 1) % n] = Some(challenge(
+            election_id,
+            msg,
+            &ring,
+            &key_image,
+            &alpha.public_key(&secp),
+            &hashed[signer].mul_tweak(&secp, &Scalar::from(alpha))?,
+        ));
+        let mut i = (signer + 1) % n;
+        while i != signer {
+            let s_i = SecretKey::new(rng);
+            let c_i = c[i].ok_or(RingError::Arithmetic)?;
+            let (l, r) = commitments(&secp, &s_i, &c_i, &points[i], &hashed[i], &key_image)?;
+            c[(i + 1) % n] = Some(challenge(election_id, msg, &ring, &key_image, &l, &r));
+            s[i] = Some(s_i);
+            i = (i + 1) % n;
+        }
+        // s = alpha - c * x closes the ring
+        let c_signer = c[signer].ok_or(RingError::Arithmetic)?;
+        s[signer] = Some(
+            c_signer
+                .mul_tweak(&Scalar::from(secret))?
+                .negate()
+                .add_tweak(&Scalar::from(alpha))?,
+        );
+
+        Ok(Self {
+            ring,
+            key_image: key_image.to_string(),
+            c: encode_hash(&c[0].ok_or(RingError::Arithmetic)?.secret_bytes()),
+            s: s.into_iter()
+                .map(|s| s.map(|s| encode_hash(&s.secret_bytes())).ok_or(RingError::Arithmetic))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// Whether one of the ring signed `msg` in the election.
+    pub fn verify(&self, election_id: &str, msg: &[u8]) -> bool {
+        self.try_verify(election_id, msg).unwrap_or(false)
+    }
+
+    fn try_verify(&self, election_id: &str, msg: &[u8]) -> Option<bool> {
+        let secp = Secp256k1::new();
+        if self.ring.is_empty() || self.s.len() != self.ring.len() || !self.ring.is_sorted_by(|a, b| a < b) {
+            return Some(false);
+        }
+        let key_image: PublicKey = self.key_image.parse().ok()?;
+        let c0 = SecretKey::from_slice(&decode_hash(&self.c)?).ok()?;
+        let mut c = c0;
+        for (pk, s) in self.ring.iter().zip(&self.s) {
+            let point = member_point(pk)?;
+            let s = SecretKey::from_slice(&decode_hash(s)?).ok()?;
+            let (l, r) = commitments(&secp, &s, &c, &point, &hash_to_point(election_id, pk), &key_image).ok()?;
+            c = challenge(election_id, msg, &self.ring, &key_image, &l, &r);
+        }
+        Some(c == c0)
+    }
+}
+
+/// Token request of a voter who proves to be on the roll without telling
+/// who, sent from throwaway keys (`anonymous_token_request` messages).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnonymousTokenRequest {
+    /// Blinded hash to sign, in Base64
+    pub blinded_h_n: String,
+    /// Ring signature of the blinded hash
+    pub proof: RingProof,
+}
+
+impl AnonymousTokenRequest {
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Ring of up to `size` voters of the roll, the voter and others at random.
+pub fn pick_ring(roll: &[String], own: &str, size: usize) -> Vec<String> {
+    let own = own.to_lowercase();
+    let mut others: Vec<&String> = roll.iter().filter(|pk| pk.to_lowercase() != own).collect();
+    others.shuffle(&mut thread_rng());
+    let mut ring: Vec<String> = others.into_iter().take(size.saturating_sub(1)).cloned().collect();
+    ring.push(own);
+    ring
+}
+
+/// `s*G + c*P` and `s*Hp(P) + c*I` of a ring member
+fn commitments<C: secp256k1::Signing + secp256k1::Verification>(
+    secp: &Secp256k1<C>,
+    s: &SecretKey,
+    c: &SecretKey,
+    point: &PublicKey,
+    hashed: &PublicKey,
+    key_image: &PublicKey,
+) -> Result<(PublicKey, PublicKey), secp256k1::Error> {
+    let c = Scalar::from(*c);
+    let l = s.public_key(secp).combine(&point.mul_tweak(secp, &c)?)?;
+    let r = hashed.mul_tweak(secp, &Scalar::from(*s))?.combine(&key_image.mul_tweak(secp, &c)?)?;
+    Ok((l, r))
+}
+
+/// Even-y point of a hex x-only pubkey
+fn member_point(pubkey: &str) -> Option<PublicKey> {
+    let xonly = XOnlyPublicKey::from_slice(&decode_hash(pubkey)?).ok()?;
+    Some(xonly.public_key(Parity::Even))
+}
+
+/// Point nobody knows the discrete log of, by hashing the election and
+/// pubkey until the hash is the x coordinate of a point
+fn hash_to_point(election_id: &str, pubkey: &str) -> PublicKey {
+    (0u32..)
+        .find_map(|counter| {
+            let mut hasher = Sha256::new();
+            hasher.update(b"criptocracia/ring/point");
+            hasher.update(election_id.as_bytes());
+            hasher.update(pubkey.to_lowercase().as_bytes());
+            hasher.update(counter.to_be_bytes());
+            let mut compressed = [0x02; 33];
+            compressed[1..].copy_from_slice(&hasher.finalize());
+            PublicKey::from_slice(&compressed).ok()
+        })
+        .expect("half of the x coordinates are on the curve")
+}
+
+/// Challenge of the next ring member, over the whole ring and the key image
+/// so that neither can be changed without breaking the proof
+fn challenge(
+    election_id: &str,
+    msg: &[u8],
+    ring: &[String],
+    key_image: &PublicKey,
+    l: &PublicKey,
+    r: &PublicKey,
+) -> SecretKey {
+    (0u32..)
+        .find_map(|counter| {
+            let mut hasher = Sha256::new();
+            hasher.update(b"criptocracia/ring/challenge");
+            hasher.update(election_id.as_bytes());
+            hasher.update(Sha256::digest(msg));
+            for pk in ring {
+                hasher.update(pk.as_bytes());
+            }
+            hasher.update(key_image.serialize());
+            hasher.update(l.serialize());
+            hasher.update(r.serialize());
+            hasher.update(counter.to_be_bytes());
+            SecretKey::from_slice(&hasher.finalize()).ok()
+        })
+        .expect("hashes are almost always valid scalars")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn voters(count: usize) -> (Vec<SecretKey>, Vec<String>) {
+        let secp = Secp256k1::new();
+        let secrets: Vec<SecretKey> = (0..count).map(|_| SecretKey::new(&mut thread_rng())).collect();
+        let pubkeys = secrets.iter().map(|s| s.x_only_public_key(&secp).0.to_string()).collect();
+        (secrets, pubkeys)
+    }
+
+    #[test]
+    fn test_ring_proof() {
+        let (secrets, roll) = voters(5);
+        let proof = RingProof::sign(&secrets[2], &roll, "a1b2", b"blinded").unwrap();
+        assert!(proof.verify("a1b2", b"blinded"));
+        assert!(!proof.verify("a1b2", b"another request"));
+        assert!(!proof.verify("c3d4", b"blinded"));
+
+        let json = serde_json::to_string(&proof).unwrap();
+        let mut tampered: RingProof = serde_json::from_str(&json).unwrap();
+        assert!(tampered.verify("a1b2", b"blinded"));
+        tampered.ring.swap(0, 1);
+        assert!(!tampered.verify("a1b2", b"blinded"));
+        // The challenges commit to the ring, in order, and to the key image
+        let image: PublicKey = proof.key_image.parse().unwrap();
+        let point = member_point(&roll[0]).unwrap();
+        let c = challenge("a1b2", b"blinded", &proof.ring, &image, &point, &point);
+        assert_ne!(challenge("a1b2", b"blinded", &tampered.ring, &image, &point, &point), c);
+        assert_ne!(challenge("a1b2", b"blinded", &proof.ring, &point, &point, &point), c);
+
+        // Outsiders can't sign as the ring
+        let (outsider, _) = voters(1);
+        assert_eq!(RingProof::sign(&outsider[0], &roll, "a1b2", b"blinded"), Err(RingError::NotInRing));
+    }
+
+    #[test]
+    fn test_key_image_links_requests_of_an_election() {
+        let (secrets, roll) = voters(4);
+        let first = RingProof::sign(&secrets[1], &roll, "a1b2", b"first").unwrap();
+        let second = RingProof::sign(&secrets[1], &roll[..2], "a1b2", b"second").unwrap();
+        let other_voter = RingProof::sign(&secrets[0], &roll, "a1b2", b"first").unwrap();
+        let other_election = RingProof::sign(&secrets[1], &roll, "c3d4", b"first").unwrap();
+
+        assert_eq!(first.key_image, second.key_image);
+        assert_ne!(first.key_image, other_voter.key_image);
+        assert_ne!(first.key_image, other_election.key_image);
+    }
+
+    #[test]
+    fn test_pick_ring() {
+        let (_, roll) = voters(10);
+        let ring = pick_ring(&roll, &roll[3], 4);
+        assert_eq!(ring.len(), 4);
+        assert!(ring.contains(&roll[3]));
+        assert_eq!(pick_ring(&roll, &roll[3], RING_SIZE).len(), 10);
+    }
+}
@@ -0,0 +1,207 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Commitment to the voter roll of an election, published in its election
+/// event so observers can tell if the roll changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VoterRoll {
+    /// Merkle root of the roll, in hex
+    pub root: String,
+    /// Number of voters on the roll
+    pub size: u32,
+    /// The voters, published by elections with anonymous token requests
+    /// for voters to pick their ring from
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pubkeys: Vec<String>,
+}
+
+impl VoterRoll {
+    /// Whether the published voters are the roll committed to
+    pub fn pubkeys_match(&self) -> bool {
+        MerkleRoll::new(&self.pubkeys).root().as_deref() == Some(self.root.as_str())
+    }
+}
+
+/// One step from a leaf to the root: the hash to combine with, and whether
+/// it goes on the left.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub hash: String,
+    pub left: bool,
+}
+
+/// Proof that a voter is on a roll with a given root.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RollProof {
+    /// Position of the voter in the sorted roll
+    pub index: u32,
+    pub steps: Vec<ProofStep>,
+}
+
+impl RollProof {
+    /// Whether the proof leads from `pubkey` (hex) to `root` (hex).
+    pub fn verify(&self, root: &str, pubkey: &str) -> bool {
+        let hash = self.steps.iter().try_fold(leaf_hash(pubkey), |hash, step| {
+            let sibling = decode_hash(&step.hash)?;
+            Some(match step.left {
+                true => node_hash(&sibling, &hash),
+                false => node_hash(&hash, &sibling),
+            })
+        });
+        hash.is_some_and(|hash| encode_hash(&hash) == root.to_lowercase())
+    }
+}
+
+/// Merkle tree of a voter roll. Leaves are the voters' hex pubkeys, in
+/// lowercase, sorted and without duplicates, hashed as `sha256(0x00 || pubkey)`.
+/// Nodes are `sha256(0x01 || left || right)`; a node without a sibling is
+/// carried up to the next level as it is.
+#[derive(Debug, Clone)]
+pub struct MerkleRoll {
+    pubkeys: Vec<String>,
+    /// Hashes of each level, from the leaves to the root
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl MerkleRoll {
+    pub fn new<I, S>(pubkeys: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut pubkeys: Vec<String> = pubkeys.into_iter().map(|pk| pk.as_ref().to_lowercase()).collect();
+        pubkeys.sort();
+        pubkeys.dedup();
+
+        let mut levels = vec![pubkeys.iter().map(|pk| leaf_hash(pk)).collect::<Vec<_>>()];
+        while levels[levels.len() - 1].len() > 1 {
+            let next = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        Self { pubkeys, levels }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pubkeys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pubkeys.is_empty()
+    }
+
+    /// Root of the tree in hex, `None` for an empty roll
+    pub fn root(&self) -> Option<String> {
+        self.levels.last()?.first().map(encode_hash)
+    }
+
+    /// Commitment to publish, `None` for an empty roll
+    pub fn commitment(&self) -> Option<VoterRoll> {
+        Some(VoterRoll { root: self.root()?, size: self.len() as u32, pubkeys: Vec::new() })
+    }
+
+    /// Commitment with the voters, for elections with anonymous token requests
+    pub fn published(&self) -> Option<VoterRoll> {
+        Some(VoterRoll { pubkeys: self.pubkeys.clone(), ..self.commitment()? })
+    }
+
+    /// Voters on the roll, sorted
+    pub fn pubkeys(&self) -> &[String] {
+        &self.pubkeys
+    }
+
+    /// Proof that a voter is on the roll, `None` if it isn't
+    pub fn proof(&self, pubkey: &str) -> Option<RollProof> {
+        let index = self.pubkeys.binary_search(&pubkey.to_lowercase()).ok()?;
+        let mut position = index;
+        let mut steps = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = position ^ 1;
+            if let Some(hash) = level.get(sibling) {
+                steps.push(ProofStep { hash: encode_hash(hash), left: sibling < position });
+            }
+            position /= 2;
+        }
+        Some(RollProof { index: index as u32, steps })
+    }
+}
+
+fn leaf_hash(pubkey: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0x00]);
+    hasher.update(pubkey.to_lowercase().as_bytes());
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+pub(crate) fn encode_hash(hash: &[u8; 32]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn decode_hash(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pubkeys(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("{:064x}", i * 7 + 1)).collect()
+    }
+
+    #[test]
+    fn test_proofs_verify_for_every_voter() {
+        for n in [1, 2, 3, 5, 8] {
+            let roll = MerkleRoll::new(pubkeys(n));
+            let root = roll.root().unwrap();
+            for pubkey in pubkeys(n) {
+                let proof = roll.proof(&pubkey).unwrap();
+                assert!(proof.verify(&root, &pubkey), "{} voters", n);
+                assert!(!proof.verify(&root, &format!("{:064x}", 999)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_root_commits_to_the_roll() {
+        let mut keys = pubkeys(4);
+        let roll = MerkleRoll::new(&keys);
+        // Order and case don't matter
+        keys.reverse();
+        keys[0] = keys[0].to_uppercase();
+        assert_eq!(MerkleRoll::new(&keys).root(), roll.root());
+        // Any change does
+        assert_ne!(MerkleRoll::new(pubkeys(5)).root(), roll.root());
+        assert_ne!(MerkleRoll::new(&keys[1..]).root(), roll.root());
+
+        assert_eq!(roll.commitment().unwrap().size, 4);
+        assert!(roll.commitment().unwrap().pubkeys.is_empty());
+        let mut published = roll.published().unwrap();
+        assert!(published.pubkeys_match());
+        published.pubkeys.pop();
+        assert!(!published.pubkeys_match());
+        assert!(MerkleRoll::new(Vec::<String>::new()).commitment().is_none());
+        assert!(roll.proof(&format!("{:064x}", 999)).is_none());
+    }
+}
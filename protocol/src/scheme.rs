@@ -0,0 +1,143 @@
+use blind_rsa_signatures::{
+    BlindSignature, BlindingResult, DefaultRng, Hash, MessageRandomizer, Options, PublicKey, Secret, SecretKey,
+    Signature,
+};
+use serde::{Deserialize, Serialize};
+
+/// Blind signature scheme of voting tokens: the voter blinds the hash of
+/// its nonce, the EC signs it without seeing it, and the voter unblinds
+/// the signature into the token the vote is verified with.
+pub trait BlindTokenScheme {
+    /// Blinds `msg` for the EC, with the secret and randomizer to unblind it.
+    fn blind(&self, pk: &PublicKey, msg: &[u8]) -> Result<BlindingResult, SchemeError>;
+
+    /// Signs a blinded message.
+    fn sign(&self, sk: &SecretKey, blind_msg: &[u8]) -> Result<BlindSignature, SchemeError>;
+
+    /// Unblinds the EC's signature into the token of `msg`.
+    fn unblind(
+        &self,
+        pk: &PublicKey,
+        blind_sig: &BlindSignature,
+        secret: &Secret,
+        msg_randomizer: Option<MessageRandomizer>,
+        msg: &[u8],
+    ) -> Result<Signature, SchemeError>;
+
+    /// Whether `token` is a valid token of `msg`.
+    fn verify(&self, pk: &PublicKey, token: &Signature, msg_randomizer: Option<MessageRandomizer>, msg: &[u8]) -> bool;
+}
+
+/// Why a token can't be blinded, signed or unblinded.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("Blind signature error: {0}")]
+pub struct SchemeError(pub String);
+
+/// Token scheme of an election, advertised by the election as `token_scheme`.
+/// Elections that don't advertise one use randomized RSA-PSS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenScheme {
+    /// RSABSSA-SHA384-PSS-Randomized (RFC 9474): the message is prefixed
+    /// with a random 32-byte randomizer that goes with the vote
+    #[default]
+    RsaPssRandomized,
+    /// RSABSSA-SHA384-PSSZERO-Deterministic (RFC 9474): no randomizer, so
+    /// votes are shorter. Safe for tokens, which sign the hash of a random nonce
+    RsaPssDeterministic,
+}
+
+impl TokenScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenScheme::RsaPssRandomized => "rsa-pss-randomized",
+            TokenScheme::RsaPssDeterministic => "rsa-pss-deterministic",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "rsa-pss-randomized" => Some(TokenScheme::RsaPssRandomized),
+            "rsa-pss-deterministic" => Some(TokenScheme::RsaPssDeterministic),
+            _ => None,
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == TokenScheme::default()
+    }
+
+    /// Whether the message is prefixed with a randomizer
+    pub fn randomized(&self) -> bool {
+        *self == TokenScheme::RsaPssRandomized
+    }
+
+    fn options(&self) -> Options {
+        match self {
+            TokenScheme::RsaPssRandomized => Options::default(),
+            TokenScheme::RsaPssDeterministic => Options::new(Hash::Sha384, true, 0),
+        }
+    }
+}
+
+impl BlindTokenScheme for TokenScheme {
+    fn blind(&self, pk: &PublicKey, msg: &[u8]) -> Result<BlindingResult, SchemeError> {
+        pk.blind(&mut DefaultRng, msg, self.randomized(), &self.options())
+            .map_err(|e| SchemeError(e.to_string()))
+    }
+
+    fn sign(&self, sk: &SecretKey, blind_msg: &[u8]) -> Result<BlindSignature, SchemeError> {
+        sk.blind_sign(&mut DefaultRng, blind_msg, &self.options())
+            .map_err(|e| SchemeError(e.to_string()))
+    }
+
+    fn unblind(
+        &self,
+        pk: &PublicKey,
+        blind_sig: &BlindSignature,
+        secret: &Secret,
+        msg_randomizer: Option<MessageRandomizer>,
+        msg: &[u8],
+    ) -> Result<Signature, SchemeError> {
+        pk.finalize(blind_sig, secret, msg_randomizer, msg, &self.options())
+            .map_err(|e| SchemeError(e.to_string()))
+    }
+
+    fn verify(&self, pk: &PublicKey, token: &Signature, msg_randomizer: Option<MessageRandomizer>, msg: &[u8]) -> bool {
+        if msg_randomizer.is_some() != self.randomized() {
+            return false;
+        }
+        token.verify(pk, msg_randomizer, msg, &self.options()).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blind_rsa_signatures::KeyPair;
+
+    #[test]
+    fn test_token_schemes() {
+        let keys = KeyPair::generate(&mut DefaultRng, 2048).unwrap();
+        let msg = b"hash of the nonce";
+        for scheme in [TokenScheme::RsaPssRandomized, TokenScheme::RsaPssDeterministic] {
+            let blinding = scheme.blind(&keys.pk, msg).unwrap();
+            assert_eq!(blinding.msg_randomizer.is_some(), scheme.randomized());
+            let blind_sig = scheme.sign(&keys.sk, &blinding.blind_msg).unwrap();
+            let token = scheme
+                .unblind(&keys.pk, &blind_sig, &blinding.secret, blinding.msg_randomizer, msg)
+                .unwrap();
+
+            assert!(scheme.verify(&keys.pk, &token, blinding.msg_randomizer, msg));
+            assert!(!scheme.verify(&keys.pk, &token, blinding.msg_randomizer, b"another hash"));
+            // A token only verifies in the scheme it was issued in
+            let other = match scheme {
+                TokenScheme::RsaPssRandomized => TokenScheme::RsaPssDeterministic,
+                TokenScheme::RsaPssDeterministic => TokenScheme::RsaPssRandomized,
+            };
+            assert!(!other.verify(&keys.pk, &token, blinding.msg_randomizer, msg));
+            assert_eq!(TokenScheme::parse(scheme.as_str()), Some(scheme));
+        }
+        assert_eq!(TokenScheme::parse("schnorr"), None);
+    }
+}
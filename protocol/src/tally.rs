@@ -0,0 +1,548 @@
+use secp256k1::rand::thread_rng;
+use secp256k1::{All, PublicKey, Scalar, Secp256k1, SecretKey, constants};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::board::PublishedBallot;
+use crate::roll::{decode_hash, encode_hash};
+
+/// Name of the results event tag with the proof of an encrypted tally:
+/// `["tally", "<proof json>"]`.
+pub const TALLY_TAG: &str = "tally";
+
+/// Candidates of an election with an encrypted tally, so its ballots fit in
+/// a message. Each candidate adds a ciphertext and a proof to the ballot.
+pub const MAX_TALLY_CANDIDATES: usize = 32;
+
+/// Exponential ElGamal encryption of 0 or 1 under the tally key `Y`:
+/// `a = r*G`, `b = m*G + r*Y`, both hex compressed points. Ciphertexts add
+/// up to the encryption of the sum of their plaintexts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ciphertext {
+    pub a: String,
+    pub b: String,
+}
+
+/// Disjunctive Chaum-Pedersen proof that a ciphertext encrypts 0 or 1,
+/// without telling which: a challenge and a response for each case, in hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BitProof {
+    pub c: [String; 2],
+    pub s: [String; 2],
+}
+
+/// Chaum-Pedersen proof that `P = x*G` and `Q = x*H` for the same secret
+/// `x`, in hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EqualityProof {
+    pub c: String,
+    pub s: String,
+}
+
+/// Ballot of an election with an encrypted tally: the encryption of 1 for
+/// the chosen candidate and of 0 for the others, in order of candidate ID.
+/// The proofs are bound to the election and the token hash of the vote, so
+/// the ballot can't be cast again with another token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedBallot {
+    pub ciphertexts: Vec<Ciphertext>,
+    /// Proof that each ciphertext encrypts 0 or 1
+    pub proofs: Vec<BitProof>,
+    /// Proof that the ciphertexts add up to an encryption of 1
+    pub sum_proof: EqualityProof,
+}
+
+/// Count of a candidate decrypted from the sum of the ballots.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Decryption {
+    pub count: u32,
+    /// `x*A` of the sum `(A, B)`, with `B = count*G + x*A`, in hex
+    pub share: String,
+    /// Proof that the share was computed with the secret of the tally key
+    pub proof: EqualityProof,
+}
+
+/// Proof of the results of an election with an encrypted tally: the count
+/// of each candidate, in order of candidate ID, decrypted from the sum of
+/// the published ballots. Empty when there are no ballots.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TallyProof {
+    pub decryptions: Vec<Decryption>,
+}
+
+/// Why an encrypted ballot or tally can't be made or checked.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum TallyError {
+    /// The tally key isn't a valid point
+    #[error("Invalid tally key: {0}")]
+    InvalidKey(String),
+    /// The choice isn't one of the candidates
+    #[error("The choice isn't one of the candidates")]
+    InvalidChoice,
+    /// A ballot doesn't encrypt a single choice among the candidates
+    #[error("Invalid encrypted ballot")]
+    InvalidBallot,
+    /// The proof doesn't match the ballots
+    #[error("The tally proof doesn't match the ballots")]
+    InvalidProof,
+    /// A sum decrypts to more votes than there are ballots
+    #[error("Decrypted count out of range")]
+    CountNotFound,
+    /// A point or scalar operation failed, which is negligibly unlikely
+    #[error("Tally arithmetic failed")]
+    Arithmetic,
+}
+
+impl From<secp256k1::Error> for TallyError {
+    fn from(_: secp256k1::Error) -> Self {
+        TallyError::Arithmetic
+    }
+}
+
+/// Parses a hex compressed tally key, as published in the election event.
+pub fn parse_tally_key(key: &str) -> Result<PublicKey, TallyError> {
+    key.parse().map_err(|_| TallyError::InvalidKey(key.to_string()))
+}
+
+impl Ciphertext {
+    fn points(&self) -> Option<(PublicKey, PublicKey)> {
+        Some((self.a.parse().ok()?, self.b.parse().ok()?))
+    }
+
+    fn from_points(a: &PublicKey, b: &PublicKey) -> Self {
+        Self { a: a.to_string(), b: b.to_string() }
+    }
+}
+
+impl EncryptedBallot {
+    /// Encrypts a vote for the `choice`-th of `candidates` candidates, in
+    /// order of candidate ID, for the vote with token hash `h_n`.
+    pub fn encrypt(
+        key: &PublicKey,
+        candidates: usize,
+        choice: usize,
+        election_id: &str,
+        h_n: &[u8],
+    ) -> Result<Self, TallyError> {
+        if choice >= candidates || candidates > MAX_TALLY_CANDIDATES {
+            return Err(TallyError::InvalidChoice);
+        }
+        let secp = Secp256k1::new();
+        let rng = &mut thread_rng();
+        let g = generator();
+        let mut ciphertexts = Vec::with_capacity(candidates);
+        let mut proofs = Vec::with_capacity(candidates);
+        let mut total: Option<SecretKey> = None;
+        for i in 0..candidates {
+            let vote = i == choice;
+            let r = SecretKey::new(rng);
+            let a = r.public_key(&secp);
+            let b = key.mul_tweak(&secp, &Scalar::from(r))?;
+            let b = if vote { b.combine(&g)? } else { b };
+            proofs.push(BitProof::prove(&secp, key, &a, &b, vote, &r, election_id, h_n)?);
+            ciphertexts.push(Ciphertext::from_points(&a, &b));
+            total = Some(match total {
+                Some(total) => total.add_tweak(&Scalar::from(r))?,
+                None => r,
+            });
+        }
+
+        // The randomness adds up too, so (A, B - G) = (R*G, R*Y)
+        let (sum_a, sum_b) = sum_points(&ciphertexts)?;
+        let sum_b = sum_b.combine(&g.negate(&secp))?;
+        let total = total.ok_or(TallyError::InvalidChoice)?;
+        let sum_proof = EqualityProof::prove(&secp, &total, key, &sum_a, &sum_b, &context(election_id, h_n))?;
+        Ok(Self { ciphertexts, proofs, sum_proof })
+    }
+
+    /// Whether the ballot encrypts a single choice among `candidates`
+    /// candidates, for the vote with token hash `h_n`.
+    pub fn verify(&self, key: &PublicKey, candidates: usize, election_id: &str, h_n: &[u8]) -> bool {
+        self.try_verify(key, candidates, election_id, h_n).unwrap_or(false)
+    }
+
+    fn try_verify(&self, key: &PublicKey, candidates: usize, election_id: &str, h_n: &[u8]) -> Option<bool> {
+        if candidates == 0 || self.ciphertexts.len() != candidates || self.proofs.len() != candidates {
+            return Some(false);
+        }
+        let secp = Secp256k1::new();
+        for (ciphertext, proof) in self.ciphertexts.iter().zip(&self.proofs) {
+            let (a, b) = ciphertext.points()?;
+            if !proof.verify(&secp, key, &a, &b, election_id, h_n) {
+                return Some(false);
+            }
+        }
+        let (sum_a, sum_b) = sum_points(&self.ciphertexts).ok()?;
+        let sum_b = sum_b.combine(&generator().negate(&secp)).ok()?;
+        Some(self.sum_proof.verify(&secp, key, &sum_a, &sum_b, &context(election_id, h_n)))
+    }
+
+    /// Hash of the ballot in hex, its line in the hashed list of ballots.
+    pub fn hash(&self) -> String {
+        encode_hash(&Sha256::digest(self.as_json().as_bytes()).into())
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl TallyProof {
+    /// Decrypts the sum of the ballots of `candidates` candidates with the
+    /// secret of the tally key, proving each count.
+    pub fn decrypt(
+        secret: &SecretKey,
+        candidates: usize,
+        election_id: &str,
+        ballots: &[&EncryptedBallot],
+    ) -> Result<Self, TallyError> {
+        if ballots.is_empty() {
+            return Ok(Self { decryptions: Vec::new() });
+        }
+        let secp = Secp256k1::new();
+        let key = secret.public_key(&secp);
+        let decryptions = (0..candidates)
+            .map(|i| {
+                let (a, b) = slot_sum(ballots, i)?;
+                let share = a.mul_tweak(&secp, &Scalar::from(*secret))?;
+                let count = discrete_log(&secp, &b, &share, ballots.len())?;
+                let proof = EqualityProof::prove(&secp, secret, &a, &key, &share, &context(election_id, &[i as u8]))?;
+                Ok(Decryption { count, share: share.to_string(), proof })
+            })
+            .collect::<Result<_, TallyError>>()?;
+        Ok(Self { decryptions })
+    }
+
+    /// Checks the proof against the ballots of `candidates` candidates,
+    /// returning the count of each candidate, in order of candidate ID.
+    pub fn verify(
+        &self,
+        key: &PublicKey,
+        candidates: usize,
+        election_id: &str,
+        ballots: &[&EncryptedBallot],
+    ) -> Result<Vec<u32>, TallyError> {
+        if ballots.is_empty() {
+            return match self.decryptions.is_empty() {
+                true => Ok(vec![0; candidates]),
+                false => Err(TallyError::InvalidProof),
+            };
+        }
+        if self.decryptions.len() != candidates {
+            return Err(TallyError::InvalidProof);
+        }
+        let secp = Secp256k1::new();
+        let g = generator();
+        self.decryptions
+            .iter()
+            .enumerate()
+            .map(|(i, decryption)| {
+                let (a, b) = slot_sum(ballots, i)?;
+                let share: PublicKey = decryption.share.parse().map_err(|_| TallyError::InvalidProof)?;
+                if !decryption.proof.verify(&secp, &a, key, &share, &context(election_id, &[i as u8])) {
+                    return Err(TallyError::InvalidProof);
+                }
+                // B = count*G + share
+                let expected = match decryption.count {
+                    0 => share,
+                    count => g.mul_tweak(&secp, &Scalar::from(count_scalar(count)?))?.combine(&share)?,
+                };
+                match expected == b {
+                    true => Ok(decryption.count),
+                    false => Err(TallyError::InvalidProof),
+                }
+            })
+            .collect()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn as_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Checks the tally proof of an election against its published ballots,
+/// each of which must be valid, and returns the results it proves as
+/// `(candidate_id, votes)` sorted by candidate ID.
+pub fn verify_tally(
+    key: &PublicKey,
+    election_id: &str,
+    candidate_ids: &[u8],
+    ballots: &[PublishedBallot],
+    proof: &TallyProof,
+) -> Result<Vec<(u8, u32)>, TallyError> {
+    let mut ids = candidate_ids.to_vec();
+    ids.sort_unstable();
+    let encrypted = ballots
+        .iter()
+        .map(|ballot| {
+            let h_n = ballot.h_n_bytes().ok_or(TallyError::InvalidBallot)?;
+            match &ballot.encrypted {
+                Some(encrypted) if ballot.election_id == election_id
+                    && encrypted.verify(key, ids.len(), election_id, &h_n) => Ok(encrypted),
+                _ => Err(TallyError::InvalidBallot),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let counts = proof.verify(key, ids.len(), election_id, &encrypted)?;
+    Ok(ids.into_iter().zip(counts).collect())
+}
+
+impl BitProof {
+    #[allow(clippy::too_many_arguments)]
+    fn prove(
+        secp: &Secp256k1<All>,
+        key: &PublicKey,
+        a: &PublicKey,
+        b: &PublicKey,
+        vote: bool,
+        r: &SecretKey,
+        election_id: &str,
+        h_n: &[u8],
+    ) -> Result<Self, TallyError> {
+        let rng = &mut thread_rng();
+        let (real, fake) = if vote { (1, 0) } else { (0, 1) };
+        let targets = [*b, b.combine(&generator().negate(secp))?];
+
+        // The other case is simulated with its challenge picked first
+        let (fake_c, fake_s) = (SecretKey::new(rng), SecretKey::new(rng));
+        let w = SecretKey::new(rng);
+        let mut commitments = [(w.public_key(secp), key.mul_tweak(secp, &Scalar::from(w))?); 2];
+        commitments[fake] = commitments_of(secp, key, a, &targets[fake], &fake_c, &fake_s)?;
+
+        let c = challenge(&context(election_id, h_n), &[a, b, &commitments[0].0, &commitments[0].1, &commitments[1].0, &commitments[1].1]);
+        let real_c = c.add_tweak(&Scalar::from(fake_c.negate()))?;
+        // s = w - c * r
+        let real_s = real_c.mul_tweak(&Scalar::from(*r))?.negate().add_tweak(&Scalar::from(w))?;
+
+        let mut cs = [String::new(), String::new()];
+        let mut ss = [String::new(), String::new()];
+        cs[real] = encode_hash(&real_c.secret_bytes());
+        ss[real] = encode_hash(&real_s.secret_bytes());
+        cs[fake] = encode_hash(&fake_c.secret_bytes());
+        ss[fake] = encode_hash(&fake_s.secret_bytes());
+        Ok(Self { c: cs, s: ss })
+    }
+
+    fn verify(
+        &self,
+        secp: &Secp256k1<All>,
+        key: &PublicKey,
+        a: &PublicKey,
+        b: &PublicKey,
+        election_id: &str,
+        h_n: &[u8],
+    ) -> bool {
+        (|| {
+            let c = [scalar(&self.c[0])?, scalar(&self.c[1])?];
+            let s = [scalar(&self.s[0])?, scalar(&self.s[1])?];
+            let targets = [*b, b.combine(&generator().negate(secp)).ok()?];
+            let zero = commitments_of(secp, key, a, &targets[0], &c[0], &s[0]).ok()?;
+            let one = commitments_of(secp, key, a, &targets[1], &c[1], &s[1]).ok()?;
+            let expected = challenge(&context(election_id, h_n), &[a, b, &zero.0, &zero.1, &one.0, &one.1]);
+            Some(c[0].add_tweak(&Scalar::from(c[1])).ok()? == expected)
+        })()
+        .unwrap_or(false)
+    }
+}
+
+impl EqualityProof {
+    /// Proves `p = x*G` and `q = x*h`.
+    fn prove(
+        secp: &Secp256k1<All>,
+        x: &SecretKey,
+        h: &PublicKey,
+        p: &PublicKey,
+        q: &PublicKey,
+        context: &[u8],
+    ) -> Result<Self, TallyError> {
+        let w = SecretKey::new(&mut thread_rng());
+        let (t1, t2) = (w.public_key(secp), h.mul_tweak(secp, &Scalar::from(w))?);
+        let c = challenge(context, &[h, p, q, &t1, &t2]);
+        // s = w - c * x
+        let s = c.mul_tweak(&Scalar::from(*x))?.negate().add_tweak(&Scalar::from(w))?;
+        Ok(Self { c: encode_hash(&c.secret_bytes()), s: encode_hash(&s.secret_bytes()) })
+    }
+
+    fn verify(
+        &self,
+        secp: &Secp256k1<All>,
+        h: &PublicKey,
+        p: &PublicKey,
+        q: &PublicKey,
+        context: &[u8],
+    ) -> bool {
+        (|| {
+            let (c, s) = (scalar(&self.c)?, scalar(&self.s)?);
+            let (t1, t2) = commitments_of(secp, h, p, q, &c, &s).ok()?;
+            Some(challenge(context, &[h, p, q, &t1, &t2]) == c)
+        })()
+        .unwrap_or(false)
+    }
+}
+
+/// `s*G + c*p` and `s*h + c*q`, which are the commitments `w*G` and `w*h`
+/// of a valid proof.
+fn commitments_of(
+    secp: &Secp256k1<All>,
+    h: &PublicKey,
+    p: &PublicKey,
+    q: &PublicKey,
+    c: &SecretKey,
+    s: &SecretKey,
+) -> Result<(PublicKey, PublicKey), secp256k1::Error> {
+    let c = Scalar::from(*c);
+    let sg = generator().mul_tweak(secp, &Scalar::from(*s))?;
+    let t1 = sg.combine(&p.mul_tweak(secp, &c)?)?;
+    let t2 = h.mul_tweak(secp, &Scalar::from(*s))?.combine(&q.mul_tweak(secp, &c)?)?;
+    Ok((t1, t2))
+}
+
+/// Sum of the ciphertexts, point by point.
+fn sum_points(ciphertexts: &[Ciphertext]) -> Result<(PublicKey, PublicKey), TallyError> {
+    let points = ciphertexts
+        .iter()
+        .map(|c| c.points().ok_or(TallyError::InvalidBallot))
+        .collect::<Result<Vec<_>, _>>()?;
+    let a: Vec<&PublicKey> = points.iter().map(|(a, _)| a).collect();
+    let b: Vec<&PublicKey> = points.iter().map(|(_, b)| b).collect();
+    Ok((PublicKey::combine_keys(&a)?, PublicKey::combine_keys(&b)?))
+}
+
+/// Sum of the `slot`-th ciphertext of every ballot.
+fn slot_sum(ballots: &[&EncryptedBallot], slot: usize) -> Result<(PublicKey, PublicKey), TallyError> {
+    let ciphertexts = ballots
+        .iter()
+        .map(|ballot| ballot.ciphertexts.get(slot).cloned().ok_or(TallyError::InvalidBallot))
+        .collect::<Result<Vec<_>, _>>()?;
+    sum_points(&ciphertexts)
+}
+
+/// The count `m` in `b = m*G + share`, searched up to `max`.
+fn discrete_log(
+    secp: &Secp256k1<All>,
+    b: &PublicKey,
+    share: &PublicKey,
+    max: usize,
+) -> Result<u32, TallyError> {
+    if b == share {
+        return Ok(0);
+    }
+    let target = b.combine(&share.negate(secp))?;
+    let g = generator();
+    let mut point = g;
+    for count in 1..=max as u32 {
+        if point == target {
+            return Ok(count);
+        }
+        point = point.combine(&g)?;
+    }
+    Err(TallyError::CountNotFound)
+}
+
+fn generator() -> PublicKey {
+    let mut compressed = [0x02; 33];
+    compressed[1..].copy_from_slice(&constants::GENERATOR_X);
+    PublicKey::from_slice(&compressed).expect("G is on the curve")
+}
+
+fn count_scalar(count: u32) -> Result<SecretKey, TallyError> {
+    let mut bytes = [0u8; 32];
+    bytes[28..].copy_from_slice(&count.to_be_bytes());
+    Ok(SecretKey::from_slice(&bytes)?)
+}
+
+fn scalar(hex: &str) -> Option<SecretKey> {
+    SecretKey::from_slice(&decode_hash(hex)?).ok()
+}
+
+fn context(election_id: &str, binding: &[u8]) -> Vec<u8> {
+    let mut context = Sha256::digest(election_id.as_bytes()).to_vec();
+    context.extend_from_slice(binding);
+    context
+}
+
+fn challenge(context: &[u8], points: &[&PublicKey]) -> SecretKey {
+    (0u32..)
+        .find_map(|counter| {
+            let mut hasher = Sha256::new();
+            hasher.update(b"criptocracia/tally/challenge");
+            hasher.update(context);
+            for point in points {
+                hasher.update(PublicKey::serialize(point));
+            }
+            hasher.update(counter.to_be_bytes());
+            SecretKey::from_slice(&hasher.finalize()).ok()
+        })
+        .expect("hashes are almost always valid scalars")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tally_keys() -> (SecretKey, PublicKey) {
+        let secret = SecretKey::new(&mut thread_rng());
+        (secret, secret.public_key(&Secp256k1::new()))
+    }
+
+    #[test]
+    fn test_encrypted_ballot() {
+        let (_, key) = tally_keys();
+        let ballot = EncryptedBallot::encrypt(&key, 3, 1, "a1b2", b"h_n").unwrap();
+        assert!(ballot.verify(&key, 3, "a1b2", b"h_n"));
+        // Bound to the election, the token and the candidates
+        assert!(!ballot.verify(&key, 3, "c3d4", b"h_n"));
+        assert!(!ballot.verify(&key, 3, "a1b2", b"another token"));
+        assert!(!ballot.verify(&key, 2, "a1b2", b"h_n"));
+        assert_eq!(EncryptedBallot::from_json(&ballot.as_json()).unwrap(), ballot);
+        assert_eq!(EncryptedBallot::encrypt(&key, 3, 3, "a1b2", b"h_n"), Err(TallyError::InvalidChoice));
+
+        // Two votes in one ballot don't add up to one
+        let mut double = ballot.clone();
+        let other = EncryptedBallot::encrypt(&key, 3, 2, "a1b2", b"h_n").unwrap();
+        double.ciphertexts[2] = other.ciphertexts[2].clone();
+        double.proofs[2] = other.proofs[2].clone();
+        assert!(!double.verify(&key, 3, "a1b2", b"h_n"));
+    }
+
+    #[test]
+    fn test_tally_proof() {
+        let (secret, key) = tally_keys();
+        let choices = [0, 2, 2, 1, 2];
+        let ballots: Vec<PublishedBallot> = choices
+            .iter()
+            .enumerate()
+            .map(|(i, choice)| {
+                let h_n = [i as u8; 32];
+                let encrypted = EncryptedBallot::encrypt(&key, 3, *choice, "a1b2", &h_n).unwrap();
+                PublishedBallot::new_encrypted("a1b2", &h_n, encrypted)
+            })
+            .collect();
+        let encrypted: Vec<&EncryptedBallot> = ballots.iter().filter_map(|b| b.encrypted.as_ref()).collect();
+        let proof = TallyProof::decrypt(&secret, 3, "a1b2", &encrypted).unwrap();
+        assert_eq!(proof.verify(&key, 3, "a1b2", &encrypted).unwrap(), vec![1, 1, 3]);
+        assert_eq!(
+            verify_tally(&key, "a1b2", &[7, 3, 5], &ballots, &proof).unwrap(),
+            vec![(3, 1), (5, 1), (7, 3)]
+        );
+
+        // The counts can't be changed, nor a ballot left out
+        let mut forged = TallyProof::from_json(&proof.as_json()).unwrap();
+        forged.decryptions[0].count = 2;
+        assert_eq!(forged.verify(&key, 3, "a1b2", &encrypted), Err(TallyError::InvalidProof));
+        assert_eq!(proof.verify(&key, 3, "a1b2", &encrypted[1..]), Err(TallyError::InvalidProof));
+        let (_, other_key) = tally_keys();
+        assert!(verify_tally(&other_key, "a1b2", &[3, 5, 7], &ballots, &proof).is_err());
+
+        let empty = TallyProof::decrypt(&secret, 3, "a1b2", &[]).unwrap();
+        assert_eq!(empty.verify(&key, 3, "a1b2", &[]).unwrap(), vec![0, 0, 0]);
+    }
+}
@@ -0,0 +1,62 @@
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+/// Version of the wire format written by this crate. Messages and events
+/// without a `version` field are version 1, the format before versioning.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest version this crate still reads.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+pub fn is_supported(version: u16) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+/// Serde default of the `version` fields.
+pub(crate) fn v1() -> u16 {
+    1
+}
+
+/// Why a message or an event can't be read.
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// Written in a version this crate doesn't read
+    #[error("Unsupported protocol version {0}, supported versions are {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}")]
+    UnsupportedVersion(u16),
+}
+
+/// Reads the version first, so a newer format is reported as such rather
+/// than as whatever field it changed. Unknown fields are ignored.
+pub(crate) fn from_json<T: DeserializeOwned>(json: &str) -> Result<T, ProtocolError> {
+    #[derive(Deserialize)]
+    struct Header {
+        #[serde(default = "v1")]
+        version: u16,
+    }
+    let header: Header = serde_json::from_str(json)?;
+    if !is_supported(header.version) {
+        return Err(ProtocolError::UnsupportedVersion(header.version));
+    }
+    Ok(serde_json::from_str(json)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+
+    #[test]
+    fn test_versions() {
+        assert!(is_supported(PROTOCOL_VERSION));
+        assert!(!is_supported(0));
+        assert!(!is_supported(PROTOCOL_VERSION + 1));
+
+        let newer = format!(r#"{{"version":{},"id":"a","kind":2,"payload":"p"}}"#, PROTOCOL_VERSION + 1);
+        let error = Message::from_json(&newer).unwrap_err();
+        assert!(matches!(error, ProtocolError::UnsupportedVersion(v) if v == PROTOCOL_VERSION + 1));
+        assert!(error.to_string().starts_with("Unsupported protocol version"));
+        assert!(matches!(Message::from_json("{"), Err(ProtocolError::Json(_))));
+    }
+}
@@ -0,0 +1,303 @@
+//! Checks of an election against its published events, with no trust in
+//! the EC beyond its Nostr key: the signatures of the events, the ballots
+//! the results commit to, and the tally recomputed from those ballots.
+
+use criptocracia_protocol::board::{BALLOTS_TAG, ballots_hash};
+use criptocracia_protocol::election::{REHEARSAL_TAG, parse_results};
+use criptocracia_protocol::tally::{TALLY_TAG, parse_tally_key, verify_tally};
+use criptocracia_protocol::{ElectionEvent, PublishedBallot, TallyProof};
+use nostr_sdk::prelude::*;
+
+/// Outcome of one check, with what was found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// Audit report of an election.
+#[derive(Debug, Clone, Default)]
+pub struct Audit {
+    pub election: Option<ElectionEvent>,
+    /// Whether the election is a drill of an EC's rehearsal, not a real one
+    pub rehearsal: bool,
+    /// Key that signed the events, the EC's unless pinned otherwise
+    pub ec_pubkey: Option<PublicKey>,
+    pub checks: Vec<Check>,
+    /// Votes of each candidate recomputed from the ballots, sorted by candidate ID
+    pub tally: Option<Vec<(u8, u32)>>,
+}
+
+impl Audit {
+    /// Whether every check passed
+    pub fn passed(&self) -> bool {
+        !self.checks.is_empty() && self.checks.iter().all(|c| c.passed)
+    }
+
+    fn check(&mut self, name: &'static str, result: Result<String, String>) -> bool {
+        let passed = result.is_ok();
+        let detail = result.unwrap_or_else(|e| e);
+        self.checks.push(Check { name, passed, detail });
+        passed
+    }
+}
+
+/// Audits the election event, the results event and the ballot events of
+/// an election. With `ec_pubkey`, the events must be signed by that key.
+/// Checks stop at the first one the next depend on.
+pub fn audit(election: &Event, results: &Event, ballots: &[Event], ec_pubkey: Option<&PublicKey>) -> Audit {
+    let mut audit = Audit::default();
+    let all = || std::iter::once(election).chain(std::iter::once(results)).chain(ballots);
+
+    // Signatures and signer
+    let invalid: Vec<String> = all().filter(|e| e.verify().is_err()).map(|e| e.id.to_hex()).collect();
+    let signatures = match invalid.is_empty() {
+        true => Ok(format!("{} events with valid signatures", ballots.len() + 2)),
+        false => Err(format!("Invalid signature of event(s) {}", invalid.join(", "))),
+    };
+    if !audit.check("signatures", signatures) {
+        return audit;
+    }
+    let signer = election.pubkey;
+    let signed = match ec_pubkey {
+        Some(expected) if *expected != signer => Err(format!("Election signed by {}, not the EC {}", signer, expected)),
+        _ if all().any(|e| e.pubkey != signer) => Err("Events signed by more than one key".to_string()),
+        Some(_) => Ok(format!("All signed by the EC {}", signer)),
+        None => Ok(format!("All signed by {} (not pinned with --ec-pubkey)", signer)),
+    };
+    if !audit.check("signer", signed) {
+        return audit;
+    }
+    audit.ec_pubkey = Some(signer);
+
+    // Election and results
+    let data = match ElectionEvent::from_json(&election.content) {
+        Ok(data) if !ElectionEvent::content_hash_matches(&election.content) => {
+            audit.check("election", Err(format!("Election {} doesn't match its content hash", data.id)));
+            return audit;
+        }
+        Ok(data) if election.tags.identifier() == Some(data.id.as_str()) => data,
+        Ok(data) => {
+            audit.check("election", Err(format!("Election {} published under another identifier", data.id)));
+            return audit;
+        }
+        Err(e) => {
+            audit.check("election", Err(format!("Unreadable election event: {}", e)));
+            return audit;
+        }
+    };
+    audit.rehearsal = data.rehearsal || election.tags.hashtags().any(|tag| tag == REHEARSAL_TAG);
+    let drill = if audit.rehearsal { ", a rehearsal" } else { "" };
+    audit.check("election", Ok(format!("{} ({}), {} candidates{}", data.name, data.id, data.candidates.len(), drill)));
+    let published = match parse_results(&results.content) {
+        Ok(published) if results.tags.identifier() == Some(data.id.as_str()) => published,
+        Ok(_) => {
+            audit.check("results", Err("Results of another election".to_string()));
+            return audit;
+        }
+        Err(e) => {
+            audit.check("results", Err(format!("Unreadable results event: {}", e)));
+            return audit;
+        }
+    };
+    audit.check("results", Ok(format!("Published at {}", results.created_at.as_u64())));
+    let id = data.id.clone();
+    audit.election = Some(data);
+
+    // Ballots, a ballot relayed more than once counted once
+    let mut board: Vec<PublishedBallot> = Vec::new();
+    let mut problems = Vec::new();
+    for event in ballots {
+        match PublishedBallot::from_json(&event.content) {
+            Ok(ballot) if ballot.election_id != id => problems.push(format!("ballot {} of another election", event.id)),
+            Ok(ballot) => match board.iter().find(|b| b.h_n == ballot.h_n) {
+                Some(existing) if *existing == ballot => {}
+                Some(_) => problems.push(format!("two ballots with nonce hash {}", ballot.h_n)),
+                None => board.push(ballot),
+            },
+            Err(e) => problems.push(format!("unreadable ballot {}: {}", event.id, e)),
+        }
+    }
+    let read = match problems.is_empty() {
+        true => Ok(format!("{} distinct ballots", board.len())),
+        false => Err(problems.join("; ")),
+    };
+    if !audit.check("ballots", read) {
+        return audit;
+    }
+
+    // The ballots are the ones the results commit to
+    let hash = ballots_hash(&board);
+    let committed = match results.tags.find(TagKind::custom(BALLOTS_TAG)).map(Tag::as_slice) {
+        Some([_, count, committed]) if *count == board.len().to_string() && *committed == hash => {
+            Ok(format!("{} ballots, hash {}", board.len(), hash))
+        }
+        Some([_, count, committed]) => Err(format!(
+            "Results committed to {} ballots with hash {}, the board has {} with hash {}",
+            count,
+            committed,
+            board.len(),
+            hash
+        )),
+        _ => Err("Results don't commit to their ballots".to_string()),
+    };
+    if !audit.check("ballot commitment", committed) {
+        return audit;
+    }
+
+    // Tally
+    let Some(data) = &audit.election else {
+        return audit;
+    };
+    let tally = match &data.tally_key {
+        Some(key) => encrypted_tally(data, key, &board, results),
+        None => plain_tally(data, &board),
+    };
+    let tally = match tally {
+        Ok(tally) => tally,
+        Err(e) => {
+            audit.check("tally", Err(e));
+            return audit;
+        }
+    };
+    let counted = |votes: &[(u8, u32)]| {
+        let mut votes: Vec<(u8, u32)> = votes.iter().copied().filter(|(_, count)| *count > 0).collect();
+        votes.sort_unstable();
+        votes
+    };
+    let matches = match counted(&tally) == counted(&published) {
+        true => Ok(format!("Recomputed tally of {} ballots matches the results", board.len())),
+        false => Err("Recomputed tally doesn't match the results".to_string()),
+    };
+    audit.check("tally", matches);
+    audit.tally = Some(tally);
+    audit
+}
+
+/// Counts plain ballots, each of a single candidate of the election.
+fn plain_tally(election: &ElectionEvent, ballots: &[PublishedBallot]) -> Result<Vec<(u8, u32)>, String> {
+    let mut tally: Vec<(u8, u32)> = election.candidates.iter().map(|c| (c.id, 0)).collect();
+    tally.sort_unstable();
+    for ballot in ballots {
+        if ballot.encrypted.is_some() {
+            return Err(format!("Encrypted ballot {} in an election with a plain tally", ballot.h_n));
+        }
+        match &ballot.choices[..] {
+            [choice] => match tally.iter_mut().find(|(id, _)| id == choice) {
+                Some((_, count)) => *count += 1,
+                None => return Err(format!("Ballot {} for unknown candidate {}", ballot.h_n, choice)),
+            },
+            choices => return Err(format!("Ballot {} with {} choices", ballot.h_n, choices.len())),
+        }
+    }
+    Ok(tally)
+}
+
+/// Counts encrypted ballots through the tally proof of the results, each
+/// ballot proven to hold a single candidate.
+fn encrypted_tally(
+    election: &ElectionEvent,
+    key: &str,
+    ballots: &[PublishedBallot],
+    results: &Event,
+) -> Result<Vec<(u8, u32)>, String> {
+    let key = parse_tally_key(key).map_err(|e| format!("Invalid tally key: {}", e))?;
+    let Some(proof) = results.tags.find(TagKind::custom(TALLY_TAG)).and_then(Tag::content) else {
+        return Err("Results of an encrypted tally without its proof".to_string());
+    };
+    let proof = TallyProof::from_json(proof).map_err(|e| format!("Unreadable tally proof: {}", e))?;
+    let ids: Vec<u8> = election.candidates.iter().map(|c| c.id).collect();
+    verify_tally(&key, &election.id, &ids, ballots, &proof).map_err(|e| format!("Tally proof not verified: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use criptocracia_protocol::Candidate;
+    use criptocracia_protocol::election::encode_results;
+
+    fn election_event(keys: &Keys, rehearsal: bool) -> Event {
+        let data = ElectionEvent {
+            version: 1,
+            id: "a1b2".to_string(),
+            name: "Board".to_string(),
+            start_time: 1_000,
+            end_time: 4_600,
+            candidates: vec![Candidate::new(1, "Alice"), Candidate::new(2, "Bob")],
+            status: criptocracia_protocol::Status::Finished,
+            rsa_pub_key: String::new(),
+            voting_method: Default::default(),
+            issuance_log: false,
+            voter_roll: None,
+            token_scheme: Default::default(),
+            bound_tokens: false,
+            anonymous_requests: false,
+            tally_key: None,
+            rehearsal,
+            content_hash: None,
+            content_sig: None,
+        };
+        EventBuilder::new(Kind::Custom(35_000), data.as_json())
+            .tag(Tag::identifier("a1b2"))
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    fn ballot_events(keys: &Keys, ballots: &[PublishedBallot]) -> Vec<Event> {
+        ballots
+            .iter()
+            .map(|b| EventBuilder::new(Kind::Custom(35_002), b.as_json()).sign_with_keys(keys).unwrap())
+            .collect()
+    }
+
+    fn results_event(keys: &Keys, results: &[(u8, u32)], ballots: &[PublishedBallot]) -> Event {
+        EventBuilder::new(Kind::Custom(35_001), encode_results(results))
+            .tag(Tag::identifier("a1b2"))
+            .tag(Tag::custom(TagKind::custom(BALLOTS_TAG), [ballots.len().to_string(), ballots_hash(ballots)]))
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_audit() {
+        let keys = Keys::generate();
+        let ballots = [
+            PublishedBallot::new("a1b2", &[1], vec![2]),
+            PublishedBallot::new("a1b2", &[2], vec![1]),
+            PublishedBallot::new("a1b2", &[3], vec![2]),
+        ];
+        let election = election_event(&keys, false);
+        let mut events = ballot_events(&keys, &ballots);
+        // A ballot relayed twice is counted once
+        events.push(events[0].clone());
+        let results = results_event(&keys, &[(1, 1), (2, 2)], &ballots);
+
+        let report = audit(&election, &results, &events, Some(&keys.public_key()));
+        assert!(report.passed(), "{:?}", report.checks);
+        assert_eq!(report.tally, Some(vec![(1, 1), (2, 2)]));
+        assert!(!report.rehearsal);
+
+        // A drill audits the same, and is reported as one
+        let report = audit(&election_event(&keys, true), &results, &events, Some(&keys.public_key()));
+        assert!(report.passed() && report.rehearsal);
+        assert!(report.checks.iter().any(|c| c.name == "election" && c.detail.ends_with(", a rehearsal")));
+
+        // Another EC's key
+        let report = audit(&election, &results, &events, Some(&Keys::generate().public_key()));
+        assert!(!report.passed());
+        assert_eq!(report.checks.last().unwrap().name, "signer");
+
+        // Results that don't match their ballots, or committed to others
+        let wrong = results_event(&keys, &[(1, 2), (2, 1)], &ballots);
+        let report = audit(&election, &wrong, &events, None);
+        assert_eq!(report.checks.last().map(|c| (c.name, c.passed)), Some(("tally", false)));
+        let report = audit(&election, &results, &events[1..3], None);
+        assert_eq!(report.checks.last().map(|c| (c.name, c.passed)), Some(("ballot commitment", false)));
+
+        // A ballot signed by someone else
+        let mut forged = events.clone();
+        forged.extend(ballot_events(&Keys::generate(), &[PublishedBallot::new("a1b2", &[4], vec![1])]));
+        assert!(!audit(&election, &results, &forged, None).passed());
+    }
+}
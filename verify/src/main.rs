@@ -0,0 +1,126 @@
+//! criptocracia-verify — independent audit of an election from its published
+//! events: the election event, the signed results event and the ballots of
+//! the bulletin board, as fetched from the relays (e.g. with `nak req`).
+//! Prints a pass/fail report and exits with an error when a check fails.
+
+mod audit;
+
+use anyhow::Context;
+use clap::Parser;
+use nostr_sdk::prelude::*;
+use std::path::{Path, PathBuf};
+
+use audit::Audit;
+
+/// Audits an election: checks the signatures of its events, that the
+/// ballots are the ones the results commit to, and recomputes the tally.
+#[derive(Parser, Debug)]
+#[command(name = "criptocracia-verify", author, version, about, long_about = None)]
+struct Args {
+    /// Election event (kind 35000), as signed JSON
+    #[arg(long)]
+    election: PathBuf,
+
+    /// Results event (kind 35001), as signed JSON
+    #[arg(long)]
+    results: PathBuf,
+
+    /// Ballot events (kind 35002), as a JSON array or one event per line
+    #[arg(long)]
+    ballots: PathBuf,
+
+    /// EC's Nostr public key (hex or npub) the events must be signed by
+    #[arg(long)]
+    ec_pubkey: Option<String>,
+
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let ec_pubkey = match &args.ec_pubkey {
+        Some(key) => Some(PublicKey::parse(key).context("Invalid EC public key")?),
+        None => None,
+    };
+    let election = read_event(&args.election)?;
+    let results = read_event(&args.results)?;
+    let ballots = read_events(&args.ballots)?;
+
+    let report = audit::audit(&election, &results, &ballots, ec_pubkey.as_ref());
+    match args.json {
+        true => print_json(&report)?,
+        false => print_report(&report),
+    }
+    if !report.passed() {
+        return Err(anyhow::anyhow!("Audit failed"));
+    }
+    Ok(())
+}
+
+fn read_event(path: &Path) -> anyhow::Result<Event> {
+    let json = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Event::from_json(json.trim()).with_context(|| format!("Invalid event in {}", path.display()))
+}
+
+/// Events of a file holding a JSON array of them, or one per line.
+fn read_events(path: &Path) -> anyhow::Result<Vec<Event>> {
+    let json = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if json.trim_start().starts_with('[') {
+        return serde_json::from_str(&json).with_context(|| format!("Invalid events in {}", path.display()));
+    }
+    json.lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            Event::from_json(line.trim()).with_context(|| format!("Invalid event on line {} of {}", i + 1, path.display()))
+        })
+        .collect()
+}
+
+fn print_report(report: &Audit) {
+    match &report.election {
+        Some(e) => println!("Audit of election {} ({})", e.name, e.id),
+        None => println!("Audit of election"),
+    }
+    if let Some(key) = &report.ec_pubkey {
+        println!("Signed by: {}", key.to_hex());
+    }
+    if report.rehearsal {
+        println!("Rehearsal: a drill of the EC, not a real election");
+    }
+    println!();
+    for check in &report.checks {
+        let verdict = if check.passed { "PASS" } else { "FAIL" };
+        println!("[{}] {:<18} {}", verdict, check.name, check.detail);
+    }
+    if let (Some(election), Some(tally)) = (&report.election, &report.tally) {
+        println!();
+        println!("{:<4} {:<24} VOTES", "ID", "NAME");
+        for (id, votes) in tally {
+            let name = election.candidates.iter().find(|c| c.id == *id).map_or("-", |c| c.name.as_str());
+            println!("{:<4} {:<24} {}", id, name, votes);
+        }
+    }
+    println!();
+    println!("Result: {}", if report.passed() { "PASS" } else { "FAIL" });
+}
+
+fn print_json(report: &Audit) -> anyhow::Result<()> {
+    let checks: Vec<serde_json::Value> = report
+        .checks
+        .iter()
+        .map(|c| serde_json::json!({ "check": c.name, "passed": c.passed, "detail": c.detail }))
+        .collect();
+    let output = serde_json::json!({
+        "passed": report.passed(),
+        "election_id": report.election.as_ref().map(|e| &e.id),
+        "rehearsal": report.rehearsal,
+        "ec_pubkey": report.ec_pubkey.map(|k| k.to_hex()),
+        "checks": checks,
+        "tally": report.tally,
+    });
+    println!("{}", serde_json::to_string_pretty(&output)?);
+    Ok(())
+}
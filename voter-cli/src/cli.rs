@@ -0,0 +1,277 @@
+use anyhow::Context;
+use clap::{Args, Parser, Subcommand};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use voter::settings::{Settings, ensure_settings_file, load_settings};
+
+use crate::session::WaitOptions;
+
+/// Non-interactive voter client, for scripts and headless devices.
+/// Settings, keys and tokens are shared with the TUI in `~/.voter`.
+#[derive(Parser, Debug)]
+#[command(name = "voter-cli", author, version, about, long_about = None)]
+pub struct Cli {
+    /// Print the output, and errors, as JSON
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// Settings file, instead of ~/.voter/settings.toml
+    #[arg(long, global = true, env = "VOTER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// EC's Nostr public key (hex or npub), instead of the one in the settings
+    #[arg(long, global = true)]
+    pub ec_pubkey: Option<String>,
+
+    /// Relay to use instead of the ones in the settings, can be given several times
+    #[arg(long = "relay", global = true, value_name = "URL")]
+    pub relays: Vec<String>,
+
+    /// File holding the voter's secret key (hex, nsec or ncryptsec), instead of the one in the settings
+    #[arg(long, global = true, env = "VOTER_KEY_FILE")]
+    pub key_file: Option<PathBuf>,
+
+    /// NIP-46 remote signer holding the voter's key (bunker:// URI), instead of a local key
+    #[arg(long, global = true, value_name = "URI")]
+    pub bunker: Option<String>,
+
+    /// SOCKS5 proxy (e.g. Tor at 127.0.0.1:9050) for the relay connections, instead of the one in the settings
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub proxy: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Show the EC's profile and descriptor: RSA public key, protocol versions and contact
+    EcInfo,
+    /// List the elections published by the EC
+    ListElections,
+    /// Show an election and its candidates
+    ShowElection {
+        /// ID of the election
+        election_id: String,
+    },
+    /// Show the latest results of an election, signed by the EC
+    Results {
+        /// ID of the election
+        election_id: String,
+    },
+    /// Check offline that a saved vote receipt is signed by the EC
+    VerifyReceipt {
+        /// Receipt file, as saved in ~/.voter/receipts
+        receipt: PathBuf,
+    },
+    /// Export the token of an election, encrypted with a passphrase, to vote from another device
+    ExportToken {
+        /// ID of the election
+        election_id: String,
+        /// File to write the token to, instead of printing it
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Also print the token as a QR code
+        #[arg(long)]
+        qr: bool,
+    },
+    /// Import a token exported by export-token
+    ImportToken {
+        /// File holding the exported token, or - to read it from stdin
+        input: PathBuf,
+    },
+    /// Request the vote token of an election and wait for the EC to sign it
+    RequestToken {
+        /// ID of the election
+        election_id: String,
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+    /// Cast a vote with the token of an election
+    Vote {
+        /// ID of the election
+        election_id: String,
+        /// Candidate IDs separated by commas, in order of preference for ranked elections
+        #[arg(required = true, value_delimiter = ',')]
+        candidates: Vec<u8>,
+        /// Wait for the receipt of the EC, and fail if it doesn't come
+        #[arg(long)]
+        wait: bool,
+        #[command(flatten)]
+        wait_args: WaitArgs,
+    },
+    /// Load test the EC: drive many voters through the token request and the vote of a test election
+    Simulate {
+        /// ID of the election
+        election_id: String,
+        /// File with the secret key (hex or nsec) of a voter on the roll per line
+        #[arg(long)]
+        voters: PathBuf,
+        /// Voters running at the same time
+        #[arg(long, default_value_t = 10)]
+        concurrency: usize,
+        /// Ballot of every voter, instead of spreading the votes over the candidates
+        #[arg(long, value_delimiter = ',')]
+        candidates: Vec<u8>,
+        #[command(flatten)]
+        wait: WaitArgs,
+    },
+}
+
+/// Options of the commands waiting for an answer of the EC.
+#[derive(Args, Debug, Clone, Copy)]
+pub struct WaitArgs {
+    /// Seconds to wait for each answer of the EC
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub timeout: u64,
+
+    /// Times to send a message again when the EC doesn't answer in time
+    #[arg(long, value_name = "N", default_value_t = 2)]
+    pub retries: u32,
+}
+
+impl WaitArgs {
+    pub fn options(self) -> WaitOptions {
+        WaitOptions { timeout: Duration::from_secs(self.timeout), retries: self.retries }
+    }
+}
+
+impl Cli {
+    /// Settings of the file given with `--config`, or `~/.voter/settings.toml`,
+    /// overridden by the `VOTER_*` environment variables and then by the flags.
+    pub fn settings(&self) -> anyhow::Result<Settings> {
+        let file = match &self.config {
+            Some(path) => path.clone(),
+            None => ensure_settings_file()?,
+        };
+        let mut settings = load_settings(&file, true)?;
+        if !self.relays.is_empty() {
+            settings.relays = self.relays.clone();
+        }
+        if let Some(ec_pubkey) = &self.ec_pubkey {
+            settings.ec_public_key = ec_pubkey.clone();
+        }
+        if let Some(path) = &self.key_file {
+            let secret_key = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the key file {}", path.display()))?;
+            settings.secret_key = secret_key.trim().to_string();
+        }
+        if let Some(bunker) = &self.bunker {
+            settings.bunker = Some(bunker.clone());
+        }
+        if let Some(proxy) = &self.proxy {
+            settings.proxy = Some(proxy.clone());
+        }
+        settings.validate().map_err(|e| anyhow::anyhow!(e))?;
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::prelude::*;
+
+    #[test]
+    fn test_parse_vote() {
+        let cli = Cli::try_parse_from(["voter-cli", "vote", "a1b2", "3,1"]).unwrap();
+        match cli.command {
+            Command::Vote { election_id, candidates, wait, wait_args } => {
+                assert_eq!(election_id, "a1b2");
+                assert_eq!(candidates, vec![3, 1]);
+                assert!(!wait);
+                assert_eq!(wait_args.options(), WaitOptions { timeout: Duration::from_secs(60), retries: 2 });
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+        assert!(Cli::try_parse_from(["voter-cli", "vote", "a1b2"]).is_err());
+        assert!(Cli::try_parse_from(["voter-cli", "vote", "a1b2", "300"]).is_err());
+
+        let cli = Cli::try_parse_from(["voter-cli", "vote", "a1b2", "2", "--wait", "--timeout", "5", "--retries", "0"])
+            .unwrap();
+        let Command::Vote { wait, wait_args, .. } = cli.command else {
+            panic!("Unexpected command {:?}", cli.command);
+        };
+        assert!(wait);
+        assert_eq!(wait_args.options(), WaitOptions { timeout: Duration::from_secs(5), retries: 0 });
+
+        let cli = Cli::try_parse_from(["voter-cli", "simulate", "a1b2", "--voters", "voters.txt", "--concurrency", "50"])
+            .unwrap();
+        let Command::Simulate { concurrency, candidates, .. } = cli.command else {
+            panic!("Unexpected command {:?}", cli.command);
+        };
+        assert_eq!(concurrency, 50);
+        assert!(candidates.is_empty());
+        assert!(Cli::try_parse_from(["voter-cli", "simulate", "a1b2"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_show_election() {
+        let cli = Cli::try_parse_from(["voter-cli", "show-election", "a1b2", "--json"]).unwrap();
+        assert!(cli.json);
+        assert!(matches!(cli.command, Command::ShowElection { .. }));
+        let cli = Cli::try_parse_from(["voter-cli", "--json", "list-elections"]).unwrap();
+        assert!(cli.json);
+        assert!(Cli::try_parse_from(["voter-cli", "show-election"]).is_err());
+    }
+
+    #[test]
+    fn test_flags_override_config_file() {
+        let dir = std::env::temp_dir().join(format!("voter-cli-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("settings.toml");
+        fs::write(
+            &config,
+            r#"
+            secret_key = "30df83c45dee3b379c91be29cbbf6ecdbcfd8e9d96979e98b9e8162505d2047a"
+            ec_public_key = "0000001ace57d0da17fc18562f4658ac6d093b2cc8bb7bd44853d0c196e24a9c"
+            relays = ["wss://relay.mostro.network"]
+            log_level = "info"
+            "#,
+        )
+        .unwrap();
+        let key_file = dir.join("voter.key");
+        let voter_keys = Keys::generate();
+        fs::write(&key_file, format!("{}\n", voter_keys.secret_key().to_bech32().unwrap())).unwrap();
+        let ec_pubkey = &Keys::generate().public_key().to_bech32().unwrap();
+
+        let cli = Cli::try_parse_from([
+            "voter-cli",
+            "list-elections",
+            "--config",
+            config.to_str().unwrap(),
+            "--ec-pubkey",
+            ec_pubkey,
+            "--key-file",
+            key_file.to_str().unwrap(),
+            "--relay",
+            "wss://nos.lol",
+            "--relay",
+            "ws://localhost:7000",
+        ])
+        .unwrap();
+        let settings = cli.settings().unwrap();
+        assert_eq!(&settings.ec_public_key, ec_pubkey);
+        assert_eq!(Keys::parse(&settings.secret_key).unwrap().public_key(), voter_keys.public_key());
+        assert_eq!(settings.relays, vec!["wss://nos.lol", "ws://localhost:7000"]);
+
+        let config = config.to_str().unwrap();
+        let cli = Cli::try_parse_from(["voter-cli", "list-elections", "--config", config, "--ec-pubkey", "bad"]).unwrap();
+        assert!(cli.settings().is_err());
+        let cli = Cli::try_parse_from(["voter-cli", "list-elections", "--config", config]).unwrap();
+        assert_eq!(cli.settings().unwrap().relays, vec!["wss://relay.mostro.network"]);
+        let cli = Cli::try_parse_from(["voter-cli", "list-elections", "--config", config, "--relay", "not a url"]).unwrap();
+        assert!(cli.settings().is_err());
+
+        let bunker = format!("bunker://{}?relay=wss://relay.nsec.app", Keys::generate().public_key().to_hex());
+        let cli = Cli::try_parse_from(["voter-cli", "list-elections", "--config", config, "--bunker", &bunker]).unwrap();
+        assert_eq!(cli.settings().unwrap().bunker, Some(bunker));
+        let cli = Cli::try_parse_from(["voter-cli", "list-elections", "--config", config, "--bunker", "wss://nos.lol"]).unwrap();
+        assert!(cli.settings().is_err());
+
+        let cli = Cli::try_parse_from(["voter-cli", "list-elections", "--config", config, "--proxy", "127.0.0.1:9050"]).unwrap();
+        assert_eq!(cli.settings().unwrap().proxy_addr(), Some("127.0.0.1:9050".parse().unwrap()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,684 @@
+use anyhow::Result;
+use base64::engine::{Engine, general_purpose};
+use chrono::Utc;
+use criptocracia_protocol::message::kind;
+use criptocracia_protocol::tally::{parse_tally_key, verify_tally};
+use criptocracia_protocol::{PublishedBallot, TallyProof};
+use nostr_sdk::prelude::{JsonUtil, NostrSigner, ToBech32};
+use std::sync::Arc;
+use std::time::Duration;
+use std::path::Path;
+use voter::ballot::VotingMethod;
+use voter::election::{Election, Message, Status};
+use voter::history::format_time;
+use voter::receipt::VoteReceipt;
+use voter::settings::{Settings, app_dir};
+use voter::qr::qr_lines;
+use voter::token::VoteToken;
+use voter::transfer;
+use voter::relays::fetch_descriptor;
+use voter::util::get_ec_pubkey;
+
+use crate::error::{Failure, fail};
+use crate::session::{
+    SendReport, Session, WaitOptions, connect, ec_pubkey, fetch_ballots, fetch_elections, fetch_results, open_store, read_passphrase,
+    update_history,
+};
+
+/// Environment variable holding the passphrase of exported tokens.
+const TOKEN_PASSPHRASE_VAR: &str = "VOTER_TOKEN_PASSPHRASE";
+
+/// Prints the EC's profile name and its descriptor, as text or JSON.
+pub async fn ec_info(settings: &Settings, json: bool) -> Result<()> {
+    let client = connect(settings, None).await?;
+    let ec_pubkey = ec_pubkey(settings)?;
+    let Some(descriptor) = fetch_descriptor(&client, &ec_pubkey).await else {
+        return Err(fail(Failure::RelayTimeout, "No EC descriptor on the relays"));
+    };
+    let name = match client.fetch_metadata(ec_pubkey, Duration::from_secs(10)).await {
+        Ok(metadata) => metadata.and_then(|m| m.name),
+        Err(e) => {
+            log::warn!("Failed to fetch the EC's profile: {}", e);
+            None
+        }
+    };
+    let fingerprint = descriptor.fingerprint();
+    if json {
+        let output = serde_json::json!({
+            "pubkey": ec_pubkey.to_hex(),
+            "name": name,
+            "rsa_pub_key": descriptor.rsa_pub_key,
+            "rsa_fingerprint": fingerprint,
+            "min_version": descriptor.min_version,
+            "max_version": descriptor.max_version,
+            "contact": descriptor.contact,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+    println!("EC:              {}", name.as_deref().unwrap_or("-"));
+    println!("Public key:      {}", ec_pubkey.to_bech32()?);
+    println!("RSA key:         {}", fingerprint.as_deref().unwrap_or("invalid"));
+    println!("Protocol:        versions {} to {}", descriptor.min_version, descriptor.max_version);
+    println!("Contact:         {}", descriptor.contact.as_deref().unwrap_or("-"));
+    Ok(())
+}
+
+/// Prints the elections published by the EC, newest first, as a table or JSON.
+pub async fn list_elections(settings: &Settings, json: bool) -> Result<()> {
+    let client = connect(settings, None).await?;
+    let now = Utc::now().timestamp() as u64;
+    let elections: Vec<Election> = fetch_elections(&client, &ec_pubkey(settings)?, None)
+        .await?
+        .into_iter()
+        .map(|e| with_current_status(e, now))
+        .collect();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&elections)?);
+    } else if elections.is_empty() {
+        println!("No elections found");
+    } else {
+        println!("{:<6} {:<12} {:<16} {:<16} NAME", "ID", "STATUS", "START", "END");
+        for e in &elections {
+            println!(
+                "{:<6} {:<12} {:<16} {:<16} {}",
+                e.id,
+                e.status.as_str(),
+                format_time(Some(e.start_time as i64)),
+                format_time(Some(e.end_time as i64)),
+                e.display_name()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Prints an election and its candidates, as text or JSON.
+pub async fn show_election(settings: &Settings, election_id: &str, json: bool) -> Result<()> {
+    let client = connect(settings, None).await?;
+    let now = Utc::now().timestamp() as u64;
+    let Some(election) = fetch_elections(&client, &ec_pubkey(settings)?, Some(election_id)).await?.pop() else {
+        return Err(fail(Failure::InvalidElection, format!("Election {} not found on the relays", election_id)));
+    };
+    let election = with_current_status(election, now);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&election)?);
+        return Ok(());
+    }
+    println!("Election:      {} ({})", election.display_name(), election.id);
+    println!("Status:        {}", election.status.as_str());
+    println!("Starts:        {}", format_time(Some(election.start_time as i64)));
+    println!("Ends:          {}", format_time(Some(election.end_time as i64)));
+    println!("Voting method: {:?}", election.voting_method);
+    println!();
+    println!("{:<4} NAME", "ID");
+    for c in &election.candidates {
+        println!("{:<4} {}", c.id, c.name);
+    }
+    Ok(())
+}
+
+/// Prints the latest results of an election, most voted candidate first,
+/// as text or JSON. Candidate names are taken from the election when found.
+/// The results of an encrypted tally are checked against the ballots on
+/// the bulletin board, and refused if its proof doesn't hold.
+pub async fn results(settings: &Settings, election_id: &str, json: bool) -> Result<()> {
+    let client = connect(settings, None).await?;
+    let ec_pubkey = ec_pubkey(settings)?;
+    let Some((published_at, votes, proof)) = fetch_results(&client, &ec_pubkey, election_id).await? else {
+        return Err(fail(Failure::InvalidElection, format!("No results of election {} on the relays", election_id)));
+    };
+    let election = fetch_elections(&client, &ec_pubkey, Some(election_id)).await?.pop();
+    let verified_ballots = match election.as_ref().and_then(|e| Some((e, e.tally_key.as_ref()?))) {
+        Some((election, tally_key)) => {
+            let Some(proof) = proof else {
+                return Err(anyhow::anyhow!("Results of election {} have no tally proof", election_id));
+            };
+            let ballots = fetch_ballots(&client, &ec_pubkey, election_id).await?;
+            check_tally(election, tally_key, &ballots, &proof, &votes)?;
+            Some(ballots.len())
+        }
+        None => None,
+    };
+    let rows = result_rows(election.as_ref(), votes);
+    if json {
+        let candidates: Vec<serde_json::Value> = rows
+            .iter()
+            .map(|(id, name, votes)| serde_json::json!({ "id": id, "name": name, "votes": votes }))
+            .collect();
+        let output = serde_json::json!({
+            "election_id": election_id,
+            "published_at": published_at,
+            "candidates": candidates,
+            "verified_ballots": verified_ballots,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+    match &election {
+        Some(e) => println!("Results of {} ({})", e.name, e.id),
+        None => println!("Results of election {}", election_id),
+    }
+    println!("Published: {}", format_time(Some(published_at as i64)));
+    if let Some(count) = verified_ballots {
+        println!("Tally verified against {} encrypted ballot(s)", count);
+    }
+    println!();
+    println!("{:<4} {:<24} VOTES", "ID", "NAME");
+    for (id, name, votes) in &rows {
+        println!("{:<4} {:<24} {}", id, name.as_deref().unwrap_or("-"), votes);
+    }
+    Ok(())
+}
+
+/// Checks a saved vote receipt against the EC's Nostr public key, without
+/// connecting to the relays, and prints the verdict with the signed details.
+pub fn verify_receipt(settings: &Settings, path: &Path, json: bool) -> Result<()> {
+    let ec_pubkey = ec_pubkey(settings)?;
+    let event_json = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+    let receipt = VoteReceipt::verify(&event_json, &ec_pubkey).map_err(|e| anyhow::anyhow!("Receipt invalid: {}", e))?;
+    let h_n = general_purpose::STANDARD.encode(&receipt.h_n_bytes);
+    if json {
+        let output = serde_json::json!({
+            "valid": true,
+            "election_id": receipt.election_id,
+            "h_n": h_n,
+            "accepted_at": receipt.accepted_at,
+            "event_id": receipt.event.id.to_hex(),
+            "ec_pubkey": ec_pubkey.to_hex(),
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+    println!("Receipt valid: the vote was accepted by the EC");
+    println!("Election:    {}", receipt.election_id);
+    println!("Nonce hash:  {}", h_n);
+    println!("Accepted at: {}", format_time(Some(receipt.accepted_at as i64)));
+    println!("Event:       {}", receipt.event.id.to_hex());
+    println!("Signed by:   {}", ec_pubkey.to_hex());
+    Ok(())
+}
+
+/// Votes of each candidate with its name, most voted first. Candidates of
+/// the election missing from the results have no votes yet.
+/// Checks that the tally proof decrypts the sum of the published ballots to
+/// the published votes.
+fn check_tally(
+    election: &Election,
+    tally_key: &str,
+    ballots: &[PublishedBallot],
+    proof: &TallyProof,
+    votes: &[(u8, u32)],
+) -> Result<()> {
+    let key = parse_tally_key(tally_key)?;
+    let ids: Vec<u8> = election.candidates.iter().map(|c| c.id).collect();
+    let mut proved = verify_tally(&key, &election.id, &ids, ballots, proof)
+        .map_err(|e| anyhow::anyhow!("Tally of election {} not verified: {}", election.id, e))?;
+    proved.retain(|(_, count)| *count > 0);
+    let mut votes = votes.to_vec();
+    votes.retain(|(_, count)| *count > 0);
+    votes.sort_unstable();
+    if proved != votes {
+        return Err(anyhow::anyhow!("Results of election {} don't match its tally proof", election.id));
+    }
+    Ok(())
+}
+
+fn result_rows(election: Option<&Election>, mut votes: Vec<(u8, u32)>) -> Vec<(u8, Option<String>, u32)> {
+    if let Some(election) = election {
+        for c in &election.candidates {
+            if !votes.iter().any(|(id, _)| *id == c.id) {
+                votes.push((c.id, 0));
+            }
+        }
+    }
+    votes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    votes
+        .into_iter()
+        .map(|(id, count)| {
+            let name = election
+                .and_then(|e| e.candidates.iter().find(|c| c.id == id))
+                .map(|c| c.name.clone());
+            (id, name, count)
+        })
+        .collect()
+}
+
+/// Replaces the published status of an election with the one of the local
+/// clock, the last published event may be out of date.
+fn with_current_status(mut election: Election, now: u64) -> Election {
+    election.status = election.current_status(now);
+    election
+}
+
+/// Requests the vote token of an election: blinds the hash of a fresh nonce,
+/// sends it to the EC, waits for the blind signature and unblinds it.
+/// A request already sent by the TUI or a previous run is waited for instead.
+/// New requests are only sent once the EC confirms the voter is on the roll.
+/// In elections with anonymous token requests the voter's pubkey isn't sent:
+/// the request proves it is on the published roll, from the token's request keys.
+pub async fn request_token(settings: &Settings, election_id: &str, wait: WaitOptions, json: bool) -> Result<()> {
+    let (signer, store) = open_store(settings).await?;
+    let session = Session::open(settings, signer.signer.clone()).await?;
+    let election = session.fetch_election(election_id).await?;
+    let ec_rsa_pubkey = get_ec_pubkey(&election.rsa_pub_key)?;
+
+    // Request to send, when it isn't already pending
+    let (mut vote_token, request) = match store.load_all().await?.remove(election_id) {
+        Some(token) if token.token.is_some() => {
+            print_token_status(election_id, "already_received", None, json);
+            return Ok(());
+        }
+        Some(token) => {
+            if !json {
+                println!("Token of election {} already requested, waiting for the EC", election_id);
+            }
+            (token, None)
+        }
+        None if election.anonymous_requests => {
+            let Some(keys) = signer.local_keys() else {
+                return Err(anyhow::anyhow!("Anonymous token requests need the voter's key, not a remote signer"));
+            };
+            if !election.roll.contains(&keys.public_key().to_hex()) {
+                return Err(fail(
+                    Failure::Unauthorized,
+                    format!("Not on the roll of election {}", election_id),
+                ));
+            }
+            let (vote_token, blinded_b64) =
+                VoteToken::request(&ec_rsa_pubkey, election.token_scheme, election.token_binding())?;
+            let message = election.anonymous_request(keys, blinded_b64)?;
+            store.save(election_id, &vote_token).await?;
+            update_history(&store, election_id, |entry| {
+                entry.election_name = Some(election.name.clone());
+                entry.token_requested_at = Some(Utc::now().timestamp());
+            })
+            .await?;
+            (vote_token, Some(message))
+        }
+        None => {
+            if !session.check_eligibility(election_id, wait).await? {
+                return Err(fail(
+                    Failure::Unauthorized,
+                    format!("Not on the roll of election {}", election_id),
+                ));
+            }
+            let (vote_token, blinded_b64) = VoteToken::request(&ec_rsa_pubkey, election.token_scheme, election.token_binding())?;
+            // The blinding secret is needed to unblind the token, keep it before sending
+            store.save(election_id, &vote_token).await?;
+            update_history(&store, election_id, |entry| {
+                entry.election_name = Some(election.name.clone());
+                entry.token_requested_at = Some(Utc::now().timestamp());
+            })
+            .await?;
+            let message = Message::new_with_election(
+                format!("token_request_{}", Utc::now().timestamp()),
+                kind::TOKEN_REQUEST,
+                blinded_b64,
+                election_id.to_string(),
+            );
+            (vote_token, Some(message))
+        }
+    };
+
+    // Answers to earlier requests can't be unblinded with this nonce and are skipped
+    let signer: Arc<dyn NostrSigner> = match election.anonymous_requests {
+        true => Arc::new(vote_token.request_keys()?),
+        false => session.signer.clone(),
+    };
+    let mut accept = |message: &Message, _| {
+        if message.kind != kind::TOKEN_REQUEST || message.election_id.as_deref() != Some(election_id) {
+            return None;
+        }
+        match vote_token.finalize(&ec_rsa_pubkey, &message.payload) {
+            Ok(()) => Some(()),
+            Err(e) => {
+                log::warn!("Blind signature not for this request: {}", e);
+                None
+            }
+        }
+    };
+    let request_report = match request {
+        Some(message) => {
+            let (report, ()) = session.send_and_wait(&signer, &message, wait, &mut accept).await?;
+            if !json {
+                println!("Token request for election {} sent to {} relays", election_id, report.accepted());
+                report.print();
+            }
+            Some(report)
+        }
+        None => {
+            session.wait_for(&signer, wait.timeout, &mut accept).await?;
+            None
+        }
+    };
+    store.save(election_id, &vote_token).await?;
+    update_history(&store, election_id, |entry| {
+        entry.token_received_at = Some(Utc::now().timestamp());
+    })
+    .await?;
+    print_token_status(election_id, "received", request_report.as_ref(), json);
+    Ok(())
+}
+
+fn print_token_status(election_id: &str, status: &str, request_report: Option<&SendReport>, json: bool) {
+    if json {
+        let relays = request_report.map(SendReport::to_json);
+        println!("{}", serde_json::json!({ "election_id": election_id, "token": status, "relays": relays }));
+    } else {
+        println!("Token of election {} {}", election_id, status.replace('_', " "));
+    }
+}
+
+/// Exports the token of an election encrypted with a passphrase, to a file or
+/// stdout, optionally as a QR code too. The token stays usable here: the EC
+/// only accepts one vote with it, from whichever device casts it first.
+pub async fn export_token(
+    settings: &Settings,
+    election_id: &str,
+    output: Option<&Path>,
+    qr: bool,
+    json: bool,
+) -> Result<()> {
+    let (_, store) = open_store(settings).await?;
+    let Some(vote_token) = store.load_all().await?.remove(election_id) else {
+        return Err(anyhow::anyhow!("No token for election {}, request it first", election_id));
+    };
+    if vote_token.vote_sent {
+        return Err(fail(Failure::DuplicateVote, format!("Vote of election {} already sent", election_id)));
+    }
+    let passphrase = read_passphrase(TOKEN_PASSPHRASE_VAR, "Passphrase to encrypt the token")?;
+    if passphrase.is_empty() {
+        return Err(anyhow::anyhow!("The exported token needs a passphrase"));
+    }
+    let exported = transfer::export(election_id, &vote_token, &passphrase)?;
+
+    if let Some(path) = output {
+        std::fs::write(path, &exported)?;
+    }
+    if json {
+        let output = serde_json::json!({
+            "election_id": election_id,
+            "token": if output.is_none() { Some(&exported) } else { None },
+            "file": output.map(|p| p.display().to_string()),
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+    match output {
+        Some(path) => println!("Token of election {} exported to {}", election_id, path.display()),
+        None => println!("{}", exported),
+    }
+    if qr {
+        // Dark modules are drawn with the foreground color
+        for line in qr_lines(&exported)? {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// Imports a token exported by `export-token`, from a file or stdin, into the
+/// token store. A token already received for the election is kept.
+pub async fn import_token(settings: &Settings, input: &Path, json: bool) -> Result<()> {
+    let data = if input == Path::new("-") {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(input).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", input.display(), e))?
+    };
+    let passphrase = read_passphrase(TOKEN_PASSPHRASE_VAR, "Passphrase of the token")?;
+    let (election_id, vote_token) =
+        transfer::import(&data, &passphrase).map_err(|e| anyhow::anyhow!("Failed to import the token: {}", e))?;
+
+    let (_, store) = open_store(settings).await?;
+    if store.load_all().await?.get(&election_id).is_some_and(|t| t.token.is_some()) {
+        return Err(anyhow::anyhow!("A token of election {} is already stored", election_id));
+    }
+    store.save(&election_id, &vote_token).await?;
+    update_history(&store, &election_id, |entry| {
+        entry.token_received_at = Some(Utc::now().timestamp());
+    })
+    .await?;
+    if json {
+        println!("{}", serde_json::json!({ "election_id": election_id, "token": "imported" }));
+    } else {
+        println!("Token of election {} imported", election_id);
+    }
+    Ok(())
+}
+
+/// Casts a vote with the token of an election, gift wrapped with the
+/// throwaway keys of the token so it can't be linked to the voter. With
+/// `wait`, waits for the receipt of the EC and saves it as the TUI does.
+pub async fn vote(
+    settings: &Settings,
+    election_id: &str,
+    choices: &[u8],
+    wait: Option<WaitOptions>,
+    json: bool,
+) -> Result<()> {
+    let (signer, store) = open_store(settings).await?;
+    let session = Session::open(settings, signer.signer).await?;
+    let election = session.fetch_election(election_id).await?;
+    check_ballot(&election, choices, Utc::now().timestamp() as u64)?;
+
+    let Some(mut vote_token) = store.load_all().await?.remove(election_id) else {
+        return Err(anyhow::anyhow!("No token for election {}, request it first", election_id));
+    };
+    if vote_token.vote_sent {
+        return Err(fail(Failure::DuplicateVote, format!("Vote of election {} already sent", election_id)));
+    }
+    let Some(vote_payload) = election.vote_payload(&vote_token, choices) else {
+        return Err(anyhow::anyhow!("Token of election {} not received yet", election_id));
+    };
+    // Keep the vote keys before sending, they are needed to read the receipt
+    let vote_keys = vote_token.vote_keys();
+    store.save(election_id, &vote_token).await?;
+
+    let message = Message::new_with_election(
+        format!("vote_{}", Utc::now().timestamp()),
+        kind::VOTE,
+        vote_payload,
+        election_id.to_string(),
+    )
+    .with_reply_to(vote_keys.public_key().to_hex());
+    let (report, receipt) = match wait {
+        Some(wait) => {
+            let h_n_bytes = vote_token.h_n_bytes.clone();
+            let ec_pubkey = session.ec_pubkey;
+            let (report, receipt) = session
+                .send_and_wait(&vote_keys, &message, wait, |message, _| {
+                    if message.kind != kind::ACK {
+                        return None;
+                    }
+                    match VoteReceipt::verify(&message.payload, &ec_pubkey) {
+                        Ok(r) if r.election_id == election_id && r.h_n_bytes == h_n_bytes => Some(r),
+                        Ok(_) => {
+                            log::warn!("Receipt not for the vote of election {}", election_id);
+                            None
+                        }
+                        Err(e) => {
+                            log::warn!("Invalid receipt: {}", e);
+                            None
+                        }
+                    }
+                })
+                .await?;
+            (report, Some(receipt))
+        }
+        None => (session.send_to_ec(&vote_keys, &message).await?, None),
+    };
+    vote_token.vote_sent = true;
+    vote_token.receipt = receipt.as_ref().map(|r| r.event.as_json());
+    store.save(election_id, &vote_token).await?;
+    update_history(&store, election_id, |entry| {
+        entry.voted_at = Some(Utc::now().timestamp());
+        if settings.record_choice {
+            entry.choices = choices.to_vec();
+        }
+    })
+    .await?;
+    let receipt_file = match &receipt {
+        Some(receipt) => Some(receipt.save(&app_dir().join("receipts"))?),
+        None => None,
+    };
+
+    if json {
+        let output = serde_json::json!({
+            "election_id": election_id,
+            "vote": if receipt.is_some() { "accepted" } else { "sent" },
+            "relays": report.to_json(),
+            "receipt": receipt.as_ref().map(|r| serde_json::json!({
+                "accepted_at": r.accepted_at,
+                "file": receipt_file.as_ref().map(|p| p.display().to_string()),
+            })),
+        });
+        println!("{}", output);
+    } else {
+        println!("Vote for election {} sent to {} relays", election_id, report.accepted());
+        report.print();
+        if let Some(path) = receipt_file {
+            println!("Vote accepted by the EC, receipt saved to {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the election is in progress and the choices fit its ballot:
+/// candidates of the election, each once, and a single one for plurality.
+pub fn check_ballot(election: &Election, choices: &[u8], now: u64) -> Result<()> {
+    let invalid = |message: String| Err(fail(Failure::InvalidElection, message));
+    let status = election.current_status(now);
+    if status != Status::InProgress {
+        return invalid(format!("Election {} is not in progress ({})", election.id, status.as_str()));
+    }
+    if election.voting_method == VotingMethod::Plurality && choices.len() != 1 {
+        return invalid(format!("Election {} takes a single candidate", election.id));
+    }
+    for (i, choice) in choices.iter().enumerate() {
+        if !election.candidates.iter().any(|c| c.id == *choice) {
+            return invalid(format!("Candidate {} is not in election {}", choice, election.id));
+        }
+        if choices[..i].contains(choice) {
+            return invalid(format!("Candidate {} chosen more than once", choice));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::prelude::*;
+    use voter::election::Candidate;
+
+    #[test]
+    fn test_check_ballot() {
+        let mut election = Election::new(
+            "a1b2".to_string(),
+            "Test".to_string(),
+            vec![Candidate::new(1, "Alice"), Candidate::new(2, "Bob")],
+            1_000,
+            3_600,
+            "key".to_string(),
+        );
+        assert!(check_ballot(&election, &[1], 2_000).is_ok());
+        assert!(check_ballot(&election, &[1], 500).unwrap_err().to_string().contains("not in progress"));
+        assert!(check_ballot(&election, &[1, 2], 2_000).unwrap_err().to_string().contains("single"));
+        assert!(check_ballot(&election, &[3], 2_000).unwrap_err().to_string().contains("not in election"));
+        let error = check_ballot(&election, &[3], 2_000).unwrap_err();
+        assert_eq!(crate::error::failure_of(&error), Some(Failure::InvalidElection));
+
+        election.voting_method = VotingMethod::Ranked;
+        assert!(check_ballot(&election, &[2, 1], 2_000).is_ok());
+        assert!(check_ballot(&election, &[2, 2], 2_000).unwrap_err().to_string().contains("more than once"));
+    }
+
+    #[test]
+    fn test_result_rows_sorted_with_names() {
+        let election = Election::new(
+            "a1b2".to_string(),
+            "Test".to_string(),
+            vec![
+                Candidate::new(1, "Alice"),
+                Candidate::new(2, "Bob"),
+                Candidate::new(3, "Carol"),
+            ],
+            1_000,
+            3_600,
+            "key".to_string(),
+        );
+        let rows = result_rows(Some(&election), vec![(1, 2), (2, 5)]);
+        assert_eq!(
+            rows,
+            vec![
+                (2, Some("Bob".to_string()), 5),
+                (1, Some("Alice".to_string()), 2),
+                (3, Some("Carol".to_string()), 0),
+            ]
+        );
+        assert_eq!(result_rows(None, vec![(4, 1)]), vec![(4, None, 1)]);
+    }
+
+    #[test]
+    fn test_check_tally() {
+        let mut election = Election::new(
+            "a1b2".to_string(),
+            "Test".to_string(),
+            vec![Candidate::new(1, "Alice"), Candidate::new(2, "Bob")],
+            1_000,
+            3_600,
+            "key".to_string(),
+        );
+        let secret = Keys::generate().secret_key().clone();
+        let key = secret.public_key(&secp256k1::Secp256k1::new());
+        election.tally_key = Some(key.to_string());
+        let ballots: Vec<PublishedBallot> = (0..3u8)
+            .map(|i| {
+                let encrypted = criptocracia_protocol::EncryptedBallot::encrypt(&key, 2, 1, "a1b2", &[i]).unwrap();
+                PublishedBallot::new_encrypted("a1b2", &[i], encrypted)
+            })
+            .collect();
+        let encrypted: Vec<_> = ballots.iter().filter_map(|b| b.encrypted.as_ref()).collect();
+        let proof = TallyProof::decrypt(&secret, 2, "a1b2", &encrypted).unwrap();
+
+        let tally_key = key.to_string();
+        assert!(check_tally(&election, &tally_key, &ballots, &proof, &[(2, 3)]).is_ok());
+        let error = check_tally(&election, &tally_key, &ballots, &proof, &[(1, 1), (2, 2)]).unwrap_err();
+        assert!(error.to_string().contains("don't match"));
+        let error = check_tally(&election, &tally_key, &ballots[1..], &proof, &[(2, 3)]).unwrap_err();
+        assert!(error.to_string().contains("not verified"));
+    }
+
+    #[test]
+    fn test_verify_receipt_file() {
+        let ec_keys = Keys::generate();
+        let content = serde_json::json!({ "election_id": "a1b2", "h_n": "AQID", "accepted_at": 1_700_000_000u64 });
+        let event = EventBuilder::text_note(content.to_string()).sign_with_keys(&ec_keys).unwrap();
+        let path = std::env::temp_dir().join(format!("voter-cli-receipt-{}.json", std::process::id()));
+        std::fs::write(&path, event.as_json()).unwrap();
+
+        let settings = Settings {
+            secret_key: String::new(),
+            bunker: None,
+            ec_public_key: ec_keys.public_key().to_hex(),
+            relays: Vec::new(),
+            log_level: "info".into(),
+            log_rotation: Default::default(),
+            language: "en".into(),
+            record_choice: false,
+            keys: Default::default(),
+            pow: 0,
+            proxy: None,
+        };
+        assert!(verify_receipt(&settings, &path, false).is_ok());
+        let other = Settings {
+            ec_public_key: Keys::generate().public_key().to_bech32().unwrap(),
+            ..settings.clone()
+        };
+        assert!(verify_receipt(&other, &path, true).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(verify_receipt(&settings, &path, false).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use criptocracia_protocol::{ErrorCode, ErrorPayload};
+
+/// Exit code of any failure without a code of its own.
+pub const EXIT_FAILURE: u8 = 1;
+
+/// Failures scripts can tell apart by the exit code of voter-cli.
+/// Code 2 is left to clap for invalid arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The voter is not on the roll of the election
+    Unauthorized,
+    /// The vote of the election was already sent
+    DuplicateVote,
+    /// No relay is reachable, or the EC didn't answer in time
+    RelayTimeout,
+    /// The election doesn't exist, isn't in progress, or the ballot doesn't fit it
+    InvalidElection,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Failure::Unauthorized => 3,
+            Failure::DuplicateVote => 4,
+            Failure::RelayTimeout => 5,
+            Failure::InvalidElection => 6,
+        }
+    }
+
+    /// Name of the failure in JSON output
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Failure::Unauthorized => "unauthorized",
+            Failure::DuplicateVote => "duplicate_vote",
+            Failure::RelayTimeout => "relay_timeout",
+            Failure::InvalidElection => "invalid_election",
+        }
+    }
+}
+
+/// Error carrying a `Failure`, recovered from the `anyhow::Error` in main.
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct CliError {
+    pub failure: Failure,
+    pub message: String,
+}
+
+/// Builds the error of a failure with its own exit code.
+pub fn fail(failure: Failure, message: impl Into<String>) -> anyhow::Error {
+    CliError { failure, message: message.into() }.into()
+}
+
+/// Error of a message the EC rejected, with the failure of its code.
+pub fn rejected(payload: &str) -> anyhow::Error {
+    let Ok(error) = ErrorPayload::from_json(payload) else {
+        return anyhow::anyhow!("The EC rejected the message: {}", payload);
+    };
+    let message = format!("The EC rejected the message ({}): {}", error.code.as_str(), error.reason);
+    match error.code {
+        ErrorCode::Unauthorized => fail(Failure::Unauthorized, message),
+        ErrorCode::Duplicate => fail(Failure::DuplicateVote, message),
+        ErrorCode::ElectionClosed | ErrorCode::UnknownElection => fail(Failure::InvalidElection, message),
+        ErrorCode::BadFormat | ErrorCode::Expired | ErrorCode::Internal => anyhow::anyhow!(message),
+    }
+}
+
+/// Failure of an error, if it has one.
+pub fn failure_of(error: &anyhow::Error) -> Option<Failure> {
+    error.downcast_ref::<CliError>().map(|e| e.failure)
+}
+
+/// Exit code of an error: the one of its failure, or `EXIT_FAILURE`.
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    failure_of(error).map_or(EXIT_FAILURE, Failure::exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let error = fail(Failure::DuplicateVote, "Vote already sent");
+        assert_eq!(exit_code(&error), 4);
+        assert_eq!(error.to_string(), "Vote already sent");
+        assert_eq!(failure_of(&error), Some(Failure::DuplicateVote));
+
+        let other = anyhow::anyhow!("Something else");
+        assert_eq!(exit_code(&other), EXIT_FAILURE);
+        assert_eq!(failure_of(&other), None);
+
+        // Context added on the way up keeps the failure
+        let wrapped = fail(Failure::RelayTimeout, "No answer").context("Token request");
+        assert_eq!(exit_code(&wrapped), 5);
+
+        let duplicate = rejected(&ErrorPayload::new(ErrorCode::Duplicate, "duplicated vote").as_json());
+        assert_eq!(failure_of(&duplicate), Some(Failure::DuplicateVote));
+        assert_eq!(duplicate.to_string(), "The EC rejected the message (duplicate): duplicated vote");
+        let closed = rejected(r#"{"code":"election-closed"}"#);
+        assert_eq!(exit_code(&closed), 6);
+        assert_eq!(exit_code(&rejected("not json")), EXIT_FAILURE);
+    }
+}
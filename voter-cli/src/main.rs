@@ -0,0 +1,58 @@
+mod cli;
+mod commands;
+mod error;
+mod session;
+mod simulate;
+
+use clap::Parser;
+use cli::{Cli, Command};
+use std::process::ExitCode;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let json = cli.json;
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // Errors go to stdout with --json, so scripts read a single stream
+            if json {
+                let failure = error::failure_of(&e).map(|f| f.as_str());
+                let output = serde_json::json!({
+                    "error": { "kind": failure.unwrap_or("error"), "message": format!("{:#}", e) },
+                });
+                println!("{}", output);
+            } else {
+                eprintln!("Error: {:#}", e);
+            }
+            ExitCode::from(error::exit_code(&e))
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), anyhow::Error> {
+    let settings = &cli.settings()?;
+    let json = cli.json;
+
+    match cli.command {
+        Command::EcInfo => commands::ec_info(settings, json).await,
+        Command::ListElections => commands::list_elections(settings, json).await,
+        Command::ShowElection { election_id } => commands::show_election(settings, &election_id, json).await,
+        Command::Results { election_id } => commands::results(settings, &election_id, json).await,
+        Command::VerifyReceipt { receipt } => commands::verify_receipt(settings, &receipt, json),
+        Command::ExportToken { election_id, output, qr } => {
+            commands::export_token(settings, &election_id, output.as_deref(), qr, json).await
+        }
+        Command::ImportToken { input } => commands::import_token(settings, &input, json).await,
+        Command::RequestToken { election_id, wait } => {
+            commands::request_token(settings, &election_id, wait.options(), json).await
+        }
+        Command::Vote { election_id, candidates, wait, wait_args } => {
+            let wait = wait.then(|| wait_args.options());
+            commands::vote(settings, &election_id, &candidates, wait, json).await
+        }
+        Command::Simulate { election_id, voters, concurrency, candidates, wait } => {
+            simulate::simulate(settings, &election_id, &voters, concurrency, &candidates, wait.options(), json).await
+        }
+    }
+}
@@ -0,0 +1,466 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use criptocracia_protocol::message::kind;
+use criptocracia_protocol::tally::TALLY_TAG;
+use criptocracia_protocol::{PublishedBallot, TallyProof};
+use nostr_sdk::prelude::*;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::time::Duration;
+use voter::election::{Election, Message, upsert};
+use voter::history::HistoryEntry;
+use voter::keystore;
+use voter::relays::{fetch_event_kinds, is_online, reconnect};
+use voter::settings::{Settings, app_dir};
+use voter::signer::VoterSigner;
+use voter::store::TokenStore;
+use voter::util::{gift_wrap, nostr_client};
+
+use crate::error::{Failure, fail, failure_of, rejected};
+
+/// Time given to the relays to connect and to answer a fetch.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most election events asked to the relays when listing them.
+const ELECTIONS_LIMIT: usize = 500;
+
+/// How far back gift wraps are looked for: NIP-59 randomizes their
+/// timestamps up to two days in the past.
+const GIFT_WRAP_WINDOW: i64 = 2 * 24 * 60 * 60;
+
+/// Tolerance between the clocks of the voter and the EC when telling
+/// answers to this run apart from older ones.
+const CLOCK_SKEW: u64 = 60;
+
+/// Environment variable holding the passphrase of a NIP-49 encrypted key,
+/// so the CLI can run unattended.
+const PASSPHRASE_VAR: &str = "VOTER_PASSPHRASE";
+
+/// Outcome of a send on each relay, sorted by URL: the error of the relays
+/// that didn't accept the event, `None` for those that did.
+#[derive(Debug, Default, PartialEq)]
+pub struct SendReport {
+    pub relays: Vec<(String, Option<String>)>,
+}
+
+impl SendReport {
+    fn from_output(output: &Output<EventId>) -> Self {
+        let mut relays: Vec<(String, Option<String>)> = output
+            .success
+            .iter()
+            .map(|url| (url.to_string(), None))
+            .chain(output.failed.iter().map(|(url, e)| (url.to_string(), Some(e.clone()))))
+            .collect();
+        relays.sort();
+        Self { relays }
+    }
+
+    /// Number of relays that accepted the event
+    pub fn accepted(&self) -> usize {
+        self.relays.iter().filter(|(_, error)| error.is_none()).count()
+    }
+
+    /// Prints a line per relay.
+    pub fn print(&self) {
+        for (url, error) in &self.relays {
+            match error {
+                None => println!("  {}: sent", url),
+                Some(e) => println!("  {}: failed ({})", url, e),
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        self.relays
+            .iter()
+            .map(|(url, error)| serde_json::json!({ "url": url, "sent": error.is_none(), "error": error }))
+            .collect()
+    }
+}
+
+/// How long to wait for each answer of the EC, and how many times to send
+/// a message again when none comes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WaitOptions {
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+/// Connection to the relays with the voter's signer, to exchange messages with the EC.
+pub struct Session {
+    pub client: Client,
+    pub signer: Arc<dyn NostrSigner>,
+    pub ec_pubkey: PublicKey,
+    /// NIP-13 difficulty mined on the gift wraps to the EC
+    pub pow: u8,
+}
+
+impl Session {
+    /// Connects to the relays of the settings with the voter's signer.
+    pub async fn open(settings: &Settings, signer: Arc<dyn NostrSigner>) -> Result<Self> {
+        let ec_pubkey = ec_pubkey(settings)?;
+        let client = connect(settings, Some(signer.clone())).await?;
+        Ok(Self { client, signer, ec_pubkey, pow: settings.pow })
+    }
+
+    /// Fetches the newest event of an election published by the EC.
+    pub async fn fetch_election(&self, election_id: &str) -> Result<Election> {
+        fetch_elections(&self.client, &self.ec_pubkey, Some(election_id))
+            .await?
+            .pop()
+            .ok_or_else(|| fail(Failure::InvalidElection, format!("Election {} not found on the relays", election_id)))
+    }
+
+    /// Asks the EC whether the voter is on the roll of an election.
+    pub async fn check_eligibility(&self, election_id: &str, wait: WaitOptions) -> Result<bool> {
+        let asked_at = Timestamp::now().as_u64().saturating_sub(CLOCK_SKEW);
+        let message = Message::new_with_election(
+            format!("eligibility_{}", Utc::now().timestamp()),
+            kind::ELIGIBILITY,
+            String::new(),
+            election_id.to_string(),
+        );
+        // Answers to earlier checks may be out of date
+        let (_, eligible) = self
+            .send_and_wait(&self.signer, &message, wait, |message, created_at| {
+                (message.kind == kind::ELIGIBILITY
+                    && message.election_id.as_deref() == Some(election_id)
+                    && created_at.as_u64() >= asked_at)
+                    .then(|| message.payload == "eligible")
+            })
+            .await?;
+        Ok(eligible)
+    }
+
+    /// Gift wraps a message to the EC from the given signer and sends it to
+    /// every relay.
+    pub async fn send_to_ec(&self, signer: &impl NostrSigner, message: &Message) -> Result<SendReport> {
+        let gift_wrap = self.gift_wrap(signer, message).await?;
+        self.publish(&gift_wrap).await
+    }
+
+    /// Sends a message to the EC and waits for the answer `accept` takes, as
+    /// `wait_for` does. When no answer comes in time the same gift wrap is sent
+    /// again, up to `wait.retries` times, so the EC never sees two messages.
+    /// An error message from the EC about it fails with the failure of its code.
+    /// Returns the relays of the first send with the answer.
+    pub async fn send_and_wait<T>(
+        &self,
+        signer: &impl NostrSigner,
+        message: &Message,
+        wait: WaitOptions,
+        mut accept: impl FnMut(&Message, Timestamp) -> Option<T>,
+    ) -> Result<(SendReport, T)> {
+        let gift_wrap = self.gift_wrap(signer, message).await?;
+        // Subscribed before sending, so a quick answer isn't missed
+        let mut notifications = self.client.notifications();
+        self.subscribe_answers(signer).await?;
+        let report = self.publish(&gift_wrap).await?;
+        let mut answer = |answer: &Message, created_at| {
+            if answer.kind == kind::ERROR && answer.id == message.id {
+                return Some(Err(rejected(&answer.payload)));
+            }
+            accept(answer, created_at).map(Ok)
+        };
+        let mut attempt = 0;
+        loop {
+            match self.next_answer(&mut notifications, signer, wait.timeout, &mut answer).await {
+                Ok(value) => return Ok((report, value?)),
+                Err(e) if attempt < wait.retries && failure_of(&e) == Some(Failure::RelayTimeout) => {
+                    attempt += 1;
+                    log::warn!("{:#}, sending message {} again ({}/{})", e, message.id, attempt, wait.retries);
+                    self.publish(&gift_wrap).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Waits for gift wrapped messages from the EC to the signer's key and returns the
+    /// first one `accept` takes, given with the time the EC wrote it, or fails after `timeout`.
+    pub async fn wait_for<T>(
+        &self,
+        signer: &impl NostrSigner,
+        timeout: Duration,
+        mut accept: impl FnMut(&Message, Timestamp) -> Option<T>,
+    ) -> Result<T> {
+        let mut notifications = self.client.notifications();
+        self.subscribe_answers(signer).await?;
+        self.next_answer(&mut notifications, signer, timeout, &mut accept).await
+    }
+
+    async fn gift_wrap(&self, signer: &impl NostrSigner, message: &Message) -> Result<Event> {
+        let message_json = serde_json::to_string(message)?;
+        log::info!("Message to the EC: {}", message_json);
+        let rumor: UnsignedEvent = EventBuilder::text_note(message_json).build(signer.get_public_key().await?);
+        gift_wrap(signer, &self.ec_pubkey, rumor, self.pow).await
+    }
+
+    /// Sends an event to every relay. If none accepts it, reconnects the
+    /// relays and tries once more.
+    async fn publish(&self, event: &Event) -> Result<SendReport> {
+        let not_sent = |e: nostr_sdk::client::Error| fail(Failure::RelayTimeout, format!("Message not sent: {}", e));
+        let mut output = self.client.send_event(event).await.map_err(not_sent)?;
+        if output.success.is_empty() {
+            log::warn!("Event {} not accepted by any relay, reconnecting", event.id);
+            reconnect(&self.client).await;
+            output = self.client.send_event(event).await.map_err(not_sent)?;
+        }
+        let report = SendReport::from_output(&output);
+        if report.accepted() == 0 {
+            let errors: Vec<String> = report
+                .relays
+                .iter()
+                .map(|(url, e)| format!("{}: {}", url, e.as_deref().unwrap_or("-")))
+                .collect();
+            return Err(fail(
+                Failure::RelayTimeout,
+                format!("No relay accepted the message ({})", errors.join(", ")),
+            ));
+        }
+        Ok(report)
+    }
+
+    /// Subscribes to the gift wraps sent to the signer's key.
+    async fn subscribe_answers(&self, signer: &impl NostrSigner) -> Result<()> {
+        let since = Timestamp::from((Utc::now().timestamp() - GIFT_WRAP_WINDOW) as u64);
+        let filter = Filter::new()
+            .kind(Kind::GiftWrap)
+            .pubkey(signer.get_public_key().await?)
+            .since(since);
+        self.client.subscribe(filter, None).await?;
+        Ok(())
+    }
+
+    async fn next_answer<T>(
+        &self,
+        notifications: &mut broadcast::Receiver<RelayPoolNotification>,
+        signer: &impl NostrSigner,
+        timeout: Duration,
+        accept: &mut impl FnMut(&Message, Timestamp) -> Option<T>,
+    ) -> Result<T> {
+        let wait = async {
+            loop {
+                let notification = match notifications.recv().await {
+                    Ok(notification) => notification,
+                    // Under load some notifications may be dropped, the retries send the message again
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        log::warn!("Skipped {} relay notifications", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let RelayPoolNotification::Event { event, .. } = notification else {
+                    continue;
+                };
+                if event.kind != Kind::GiftWrap || event.verify().is_err() {
+                    continue;
+                }
+                let Ok(unwrapped) = nip59::extract_rumor(signer, &event).await else {
+                    continue;
+                };
+                if unwrapped.sender != self.ec_pubkey {
+                    log::warn!("Ignoring message from {}", unwrapped.sender);
+                    continue;
+                }
+                match Message::from_json(&unwrapped.rumor.content) {
+                    Ok(message) => {
+                        if let Some(value) = accept(&message, unwrapped.rumor.created_at) {
+                            return Ok(value);
+                        }
+                    }
+                    Err(e) => log::warn!("Error reading message: {}", e),
+                }
+            }
+            Err(fail(Failure::RelayTimeout, "Relay connection closed"))
+        };
+        tokio::time::timeout(timeout, wait).await.map_err(|_| {
+            fail(Failure::RelayTimeout, format!("No answer from the EC after {}s", timeout.as_secs()))
+        })?
+    }
+
+}
+
+/// Unlocks the voter's signer and opens the token store shared with the TUI.
+pub async fn open_store(settings: &Settings) -> Result<(VoterSigner, TokenStore)> {
+    let signer = unlock_signer(settings).await?;
+    let store = TokenStore::open(&app_dir().join("voter.db"), signer.store_keys.clone()).await?;
+    Ok((signer, store))
+}
+
+/// Updates the history entry of an election, creating it if needed.
+pub async fn update_history(
+    store: &TokenStore,
+    election_id: &str,
+    update: impl FnOnce(&mut HistoryEntry),
+) -> Result<()> {
+    let mut history = store.load_history().await?;
+    let mut entry = history
+        .remove(election_id)
+        .unwrap_or_else(|| HistoryEntry::new(election_id));
+    update(&mut entry);
+    store.save_history(&entry).await
+}
+
+/// EC's Nostr public key from the settings.
+pub fn ec_pubkey(settings: &Settings) -> Result<PublicKey> {
+    PublicKey::parse(&settings.ec_public_key).map_err(|e| anyhow::anyhow!("Invalid EC pubkey: {}", e))
+}
+
+/// Connects a client to the relays of the settings, through their proxy if
+/// any. Reading public events needs no signer, so it is only given to send messages.
+pub async fn connect(settings: &Settings, signer: Option<Arc<dyn NostrSigner>>) -> Result<Client> {
+    if settings.relays.is_empty() {
+        return Err(anyhow::anyhow!("No relays configured in settings.toml"));
+    }
+    let client = nostr_client(signer, settings.proxy_addr());
+    for relay in &settings.relays {
+        client.add_relay(relay).await?;
+    }
+    client.connect().await;
+    client.wait_for_connection(RELAY_TIMEOUT).await;
+    if !is_online(&client).await {
+        return Err(fail(Failure::RelayTimeout, "No relay could be reached"));
+    }
+    Ok(client)
+}
+
+/// Fetches the elections published by the EC, all of them or the one with
+/// the given ID, newest start time first. Only the newest event of each
+/// election is kept. The EC's event kinds are looked up first.
+pub async fn fetch_elections(
+    client: &Client,
+    ec_pubkey: &PublicKey,
+    election_id: Option<&str>,
+) -> Result<Vec<Election>> {
+    let kinds = fetch_event_kinds(client, ec_pubkey).await;
+    let filter = Filter::new().kind(Kind::Custom(kinds.election)).author(*ec_pubkey);
+    let filter = match election_id {
+        Some(id) => filter.identifier(id),
+        None => filter.limit(ELECTIONS_LIMIT),
+    };
+    let events = client.fetch_events(filter, RELAY_TIMEOUT).await?;
+    let mut elections = Vec::new();
+    for event in events.iter() {
+        match Election::parse_event(event) {
+            Ok(election) => {
+                upsert(&mut elections, election);
+            }
+            Err(e) => log::warn!("Ignoring event {}: {}", event.id, e),
+        }
+    }
+    // The relays may not honor the identifier filter
+    if let Some(id) = election_id {
+        elections.retain(|e| e.id == id);
+    }
+    Ok(elections)
+}
+
+/// Fetches the newest results event of an election and checks that it is
+/// signed by the EC. Returns the time it was published, the votes of each
+/// candidate and the proof of an encrypted tally.
+pub async fn fetch_results(
+    client: &Client,
+    ec_pubkey: &PublicKey,
+    election_id: &str,
+) -> Result<Option<(u64, Vec<(u8, u32)>, Option<TallyProof>)>> {
+    let kinds = fetch_event_kinds(client, ec_pubkey).await;
+    let filter = Filter::new()
+        .kind(Kind::Custom(kinds.results))
+        .author(*ec_pubkey)
+        .identifier(election_id);
+    let events = client.fetch_events(filter, RELAY_TIMEOUT).await?;
+    let newest = events
+        .into_iter()
+        .filter(|e| e.pubkey == *ec_pubkey && e.tags.identifier() == Some(election_id))
+        .max_by_key(|e| e.created_at);
+    let Some(event) = newest else {
+        return Ok(None);
+    };
+    event
+        .verify()
+        .map_err(|e| anyhow::anyhow!("Invalid signature of results event {}: {}", event.id, e))?;
+    let results = Election::parse_result_event(&event)?;
+    let proof = match event.tags.find(TagKind::custom(TALLY_TAG)).and_then(Tag::content) {
+        Some(json) => Some(TallyProof::from_json(json).context("Invalid tally proof")?),
+        None => None,
+    };
+    Ok(Some((event.created_at.as_u64(), results, proof)))
+}
+
+/// Fetches the ballots of an election from the EC's bulletin board, signed
+/// by the EC. A ballot relayed more than once is kept once.
+pub async fn fetch_ballots(client: &Client, ec_pubkey: &PublicKey, election_id: &str) -> Result<Vec<PublishedBallot>> {
+    let kinds = fetch_event_kinds(client, ec_pubkey).await;
+    let election = Coordinate::new(Kind::Custom(kinds.election), *ec_pubkey).identifier(election_id);
+    let filter = Filter::new()
+        .kind(Kind::Custom(kinds.ballot))
+        .author(*ec_pubkey)
+        .coordinate(&election);
+    let events = client.fetch_events(filter, RELAY_TIMEOUT).await?;
+    let mut ballots: Vec<PublishedBallot> = Vec::new();
+    for event in events.into_iter().filter(|e| e.pubkey == *ec_pubkey && e.verify().is_ok()) {
+        match PublishedBallot::from_json(&event.content) {
+            Ok(ballot) if ballot.election_id == election_id && !ballots.iter().any(|b| b.h_n == ballot.h_n) => {
+                ballots.push(ballot)
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Ignoring ballot event {}: {}", event.id, e),
+        }
+    }
+    Ok(ballots)
+}
+
+/// Signer of the voter: the remote signer of the settings, or the secret key.
+/// A NIP-49 encrypted key is decrypted with the passphrase in `VOTER_PASSPHRASE`,
+/// or asked on stdin.
+async fn unlock_signer(settings: &Settings) -> Result<VoterSigner> {
+    if let Some(bunker) = &settings.bunker {
+        eprintln!("Connecting to the remote signer, approve the connection on your signing device");
+        return VoterSigner::remote(bunker, settings.proxy_addr(), |url| eprintln!("Approve the request at {}", url)).await;
+    }
+    if !keystore::is_encrypted(&settings.secret_key) {
+        return Ok(VoterSigner::local(Keys::parse(&settings.secret_key)?));
+    }
+    let passphrase = read_passphrase(PASSPHRASE_VAR, "Passphrase of the secret key")?;
+    let keys = keystore::decrypt(&settings.secret_key, &passphrase).context("Failed to decrypt the secret key")?;
+    Ok(VoterSigner::local(keys))
+}
+
+/// Reads a passphrase from an environment variable, or asks for it on stdin.
+pub fn read_passphrase(var: &str, prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(var) {
+        return Ok(passphrase);
+    }
+    eprint!("{}: ", prompt);
+    std::io::stderr().flush()?;
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_report_per_relay() {
+        let output = Output {
+            val: EventId::all_zeros(),
+            success: [RelayUrl::parse("wss://relay.mostro.network").unwrap()].into(),
+            failed: [(RelayUrl::parse("wss://nos.lol").unwrap(), "blocked".to_string())].into(),
+        };
+        let report = SendReport::from_output(&output);
+        assert_eq!(
+            report.relays,
+            vec![
+                ("wss://nos.lol".to_string(), Some("blocked".to_string())),
+                ("wss://relay.mostro.network".to_string(), None),
+            ]
+        );
+        assert_eq!(report.accepted(), 1);
+        assert_eq!(report.to_json()[0]["sent"], false);
+        assert_eq!(report.to_json()[1]["error"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::Result;
+use chrono::Utc;
+use criptocracia_protocol::message::kind;
+use nostr_sdk::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{Duration, Instant};
+use voter::ballot::VotingMethod;
+use voter::election::{Election, Message};
+use voter::receipt::VoteReceipt;
+use voter::settings::Settings;
+use voter::token::VoteToken;
+use voter::util::get_ec_pubkey;
+
+use crate::commands::check_ballot;
+use crate::error::{Failure, fail, failure_of};
+use crate::session::{Session, WaitOptions, connect, ec_pubkey, fetch_elections};
+
+/// What one simulated voter went through: the time each step took, and the
+/// error that stopped it.
+#[derive(Debug, Default)]
+struct VoterRun {
+    token: Option<Duration>,
+    vote: Option<Duration>,
+    error: Option<(&'static str, String)>,
+}
+
+/// Latency percentiles of a step, in milliseconds.
+#[derive(Debug, Serialize, PartialEq)]
+struct Latency {
+    count: usize,
+    p50: u128,
+    p90: u128,
+    p99: u128,
+    max: u128,
+}
+
+impl Latency {
+    fn of(mut durations: Vec<Duration>) -> Option<Self> {
+        if durations.is_empty() {
+            return None;
+        }
+        durations.sort();
+        // Nearest rank
+        let percentile = |p: usize| durations[(durations.len() * p).div_ceil(100).max(1) - 1].as_millis();
+        Some(Self {
+            count: durations.len(),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: durations[durations.len() - 1].as_millis(),
+        })
+    }
+}
+
+/// Drives the voters of a file through the token request and the vote of an
+/// election, `concurrency` at a time, each with its own relay connection like
+/// a real device. Tokens are kept in memory, the token store isn't touched.
+/// Prints the latency of each step and the errors by kind.
+pub async fn simulate(
+    settings: &Settings,
+    election_id: &str,
+    voters_file: &Path,
+    concurrency: usize,
+    candidates: &[u8],
+    wait: WaitOptions,
+    json: bool,
+) -> Result<()> {
+    let text = std::fs::read_to_string(voters_file)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", voters_file.display(), e))?;
+    let voters = parse_voters(&text)?;
+    if voters.is_empty() {
+        return Err(anyhow::anyhow!("No voter keys in {}", voters_file.display()));
+    }
+
+    let client = connect(settings, None).await?;
+    let Some(election) = fetch_elections(&client, &ec_pubkey(settings)?, Some(election_id)).await?.pop() else {
+        return Err(fail(Failure::InvalidElection, format!("Election {} not found on the relays", election_id)));
+    };
+    client.disconnect().await;
+    let election = Arc::new(election);
+    let ballot = |i| match candidates.is_empty() {
+        true => spread_choices(&election, i),
+        false => candidates.to_vec(),
+    };
+    check_ballot(&election, &ballot(0), Utc::now().timestamp() as u64)?;
+    if !json {
+        println!(
+            "Simulating {} voters on election {}, {} at a time",
+            voters.len(),
+            election_id,
+            concurrency
+        );
+    }
+
+    let started = Instant::now();
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for (i, keys) in voters.into_iter().enumerate() {
+        let choices = ballot(i);
+        let (settings, election, permits) = (settings.clone(), election.clone(), permits.clone());
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+            let mut run = VoterRun::default();
+            if let Err(e) = run_voter(&settings, &election, keys, &choices, wait, &mut run).await {
+                let kind = failure_of(&e).map_or("error", |f| f.as_str());
+                log::warn!("Voter {} failed: {:#}", i, e);
+                run.error = Some((kind, format!("{:#}", e)));
+            }
+            run
+        });
+    }
+    let mut runs = Vec::new();
+    while let Some(run) = tasks.join_next().await {
+        runs.push(run?);
+    }
+    print_report(&runs, started.elapsed(), json);
+    Ok(())
+}
+
+/// Requests the token of one voter, then votes with it and waits for the receipt.
+async fn run_voter(
+    settings: &Settings,
+    election: &Election,
+    keys: Keys,
+    choices: &[u8],
+    wait: WaitOptions,
+    run: &mut VoterRun,
+) -> Result<()> {
+    let ec_rsa_pubkey = get_ec_pubkey(&election.rsa_pub_key)?;
+    let session = Session::open(settings, Arc::new(keys.clone())).await?;
+
+    let started = Instant::now();
+    let (mut vote_token, blinded_b64) = VoteToken::request(&ec_rsa_pubkey, election.token_scheme, election.token_binding())?;
+    // Anonymous requests prove the voter is on the roll from the token's request keys
+    let (message, request_keys) = match election.anonymous_requests {
+        true => (election.anonymous_request(&keys, blinded_b64)?, vote_token.request_keys()?),
+        false => {
+            let message = Message::new_with_election(
+                format!("token_request_{}", Utc::now().timestamp()),
+                kind::TOKEN_REQUEST,
+                blinded_b64,
+                election.id.clone(),
+            );
+            (message, keys.clone())
+        }
+    };
+    session
+        .send_and_wait(&request_keys, &message, wait, |message, _| {
+            (message.kind == kind::TOKEN_REQUEST && message.election_id.as_deref() == Some(election.id.as_str()))
+                .then(|| vote_token.finalize(&ec_rsa_pubkey, &message.payload).ok())
+                .flatten()
+        })
+        .await?;
+    run.token = Some(started.elapsed());
+
+    let started = Instant::now();
+    let Some(vote_payload) = election.vote_payload(&vote_token, choices) else {
+        return Err(anyhow::anyhow!("Token not unblinded"));
+    };
+    let vote_keys = vote_token.vote_keys();
+    let message = Message::new_with_election(
+        format!("vote_{}", Utc::now().timestamp()),
+        kind::VOTE,
+        vote_payload,
+        election.id.clone(),
+    )
+    .with_reply_to(vote_keys.public_key().to_hex());
+    session
+        .send_and_wait(&vote_keys, &message, wait, |message, _| {
+            if message.kind != kind::ACK {
+                return None;
+            }
+            VoteReceipt::verify(&message.payload, &session.ec_pubkey)
+                .ok()
+                .filter(|r| r.election_id == election.id && r.h_n_bytes == vote_token.h_n_bytes)
+        })
+        .await?;
+    run.vote = Some(started.elapsed());
+    session.client.disconnect().await;
+    Ok(())
+}
+
+/// Reads the voters' secret keys (hex or nsec), one per line. Blank lines and
+/// lines starting with `#` are skipped.
+fn parse_voters(text: &str) -> Result<Vec<Keys>> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| Keys::parse(line).map_err(|e| anyhow::anyhow!("Invalid key on line {}: {}", i + 1, e)))
+        .collect()
+}
+
+/// Ballot of the `i`-th voter when none is given, spreading the votes over
+/// the candidates: one for plurality, all of them rotated for ranked elections.
+fn spread_choices(election: &Election, i: usize) -> Vec<u8> {
+    let mut ids: Vec<u8> = election.candidates.iter().map(|c| c.id).collect();
+    if ids.is_empty() {
+        return ids;
+    }
+    let len = ids.len();
+    ids.rotate_left(i % len);
+    if election.voting_method == VotingMethod::Plurality {
+        ids.truncate(1);
+    }
+    ids
+}
+
+fn print_report(runs: &[VoterRun], elapsed: Duration, json: bool) {
+    let token = Latency::of(runs.iter().filter_map(|r| r.token).collect());
+    let vote = Latency::of(runs.iter().filter_map(|r| r.vote).collect());
+    let mut errors: BTreeMap<&str, usize> = BTreeMap::new();
+    for (kind, _) in runs.iter().filter_map(|r| r.error.as_ref()) {
+        *errors.entry(kind).or_default() += 1;
+    }
+    let failed: usize = errors.values().sum();
+
+    if json {
+        let output = serde_json::json!({
+            "voters": runs.len(),
+            "voted": runs.len() - failed,
+            "failed": failed,
+            "error_rate": failed as f64 / runs.len() as f64,
+            "elapsed_ms": elapsed.as_millis(),
+            "token_ms": token,
+            "vote_ms": vote,
+            "errors": errors,
+        });
+        println!("{}", output);
+        return;
+    }
+    println!(
+        "{} of {} voters voted in {:.1}s ({:.1}% errors)",
+        runs.len() - failed,
+        runs.len(),
+        elapsed.as_secs_f64(),
+        failed as f64 * 100.0 / runs.len() as f64
+    );
+    for (step, latency) in [("Token", token), ("Vote", vote)] {
+        match latency {
+            Some(l) => println!(
+                "  {:<6} {:>5} done  p50 {} ms  p90 {} ms  p99 {} ms  max {} ms",
+                step, l.count, l.p50, l.p90, l.p99, l.max
+            ),
+            None => println!("  {:<6} none done", step),
+        }
+    }
+    for (kind, count) in errors {
+        println!("  {}: {}", kind, count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use voter::election::Candidate;
+
+    #[test]
+    fn test_latency_percentiles() {
+        let durations = (1..=100).rev().map(Duration::from_millis).collect();
+        let latency = Latency::of(durations).unwrap();
+        assert_eq!(latency, Latency { count: 100, p50: 50, p90: 90, p99: 99, max: 100 });
+        let single = Latency::of(vec![Duration::from_millis(7)]).unwrap();
+        assert_eq!((single.p50, single.p99, single.max), (7, 7, 7));
+        assert!(Latency::of(Vec::new()).is_none());
+    }
+
+    #[test]
+    fn test_parse_voters_and_spread() {
+        let keys = Keys::generate();
+        let text = format!("# voters\n\n{}\n  {}  \n", keys.secret_key().to_bech32().unwrap(), keys.secret_key().to_secret_hex());
+        let voters = parse_voters(&text).unwrap();
+        assert_eq!(voters.len(), 2);
+        assert!(voters.iter().all(|k| k.public_key() == keys.public_key()));
+        assert!(parse_voters("nsec1bad").is_err());
+
+        let mut election = Election::new(
+            "a1b2".to_string(),
+            "Test".to_string(),
+            vec![Candidate::new(1, "A".to_string()), Candidate::new(2, "B".to_string()), Candidate::new(3, "C".to_string())],
+            0,
+            0,
+            String::new(),
+        );
+        assert_eq!(spread_choices(&election, 0), vec![1]);
+        assert_eq!(spread_choices(&election, 4), vec![2]);
+        election.voting_method = VotingMethod::Ranked;
+        assert_eq!(spread_choices(&election, 2), vec![3, 1, 2]);
+    }
+}
@@ -0,0 +1,30 @@
+//! Voter-side cryptography of Criptocracia, shared by the TUI (`voter`), the
+//! command line client (`voter-cli`) and web and mobile clients: generating
+//! the nonce of a vote token and blinding its hash for the EC, unblinding the
+//! EC's signature into the token, building the vote payload, and verifying
+//! the receipt the EC signs for an accepted vote.
+//! There is no I/O, async runtime or Nostr client in here, so the crate
+//! builds for `wasm32-unknown-unknown`, where randomness comes from the
+//! browser's crypto API.
+
+pub mod receipt;
+pub mod token;
+
+pub use receipt::{Receipt, verify_receipt};
+pub use token::BlindToken;
+
+
+/// Why a token or a receipt can't be used.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum CoreError {
+    /// Blinding or unblinding failed
+    #[error("{0}")]
+    Blinding(String),
+    /// Base64, JSON or key that can't be read
+    #[error("Invalid encoding: {0}")]
+    Encoding(String),
+    /// Receipt not signed by the EC, or altered
+    #[error("Invalid receipt: {0}")]
+    Receipt(String),
+}
+
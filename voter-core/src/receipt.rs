@@ -0,0 +1,102 @@
+use base64::engine::{Engine, general_purpose};
+use criptocracia_protocol::VoteAck;
+use secp256k1::{Message, Secp256k1, XOnlyPublicKey, schnorr};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+use crate::CoreError;
+
+/// What the EC's receipt of an accepted vote says: the election and the
+/// hash of the nonce the vote was cast with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Receipt {
+    pub election_id: String,
+    pub h_n: Vec<u8>,
+    pub accepted_at: u64,
+}
+
+/// Verifies that the receipt event of a vote, in Nostr JSON, is signed by
+/// the EC's Nostr key (hex) and unaltered, and reads it. The event ID and
+/// BIP-340 signature are checked as NIP-01 defines them.
+pub fn verify_receipt(event_json: &str, ec_pubkey: &str) -> Result<Receipt, CoreError> {
+    let encoding = |e: &dyn std::fmt::Display| CoreError::Encoding(e.to_string());
+    let event: Value = serde_json::from_str(event_json).map_err(|e| encoding(&e))?;
+    let field = |name: &str| event.get(name).ok_or_else(|| CoreError::Encoding(format!("no {} in the event", name)));
+    let (pubkey, content) = (field("pubkey")?.as_str(), field("content")?.as_str());
+    let (id, sig) = (field("id")?.as_str(), field("sig")?.as_str());
+    let (Some(pubkey), Some(content), Some(id), Some(sig)) = (pubkey, content, id, sig) else {
+        return Err(CoreError::Encoding("event fields of the wrong type".to_string()));
+    };
+    if pubkey != ec_pubkey {
+        return Err(CoreError::Receipt("not signed by the EC".to_string()));
+    }
+
+    // The ID is the hash of the event's fields, the signature is of the ID
+    let serialized = json!([0, pubkey, field("created_at")?, field("kind")?, field("tags")?, content]).to_string();
+    let hash: [u8; 32] = Sha256::digest(serialized.as_bytes()).into();
+    if hex(&hash) != id {
+        return Err(CoreError::Receipt("event ID doesn't match its content".to_string()));
+    }
+    let key = XOnlyPublicKey::from_str(pubkey).map_err(|e| encoding(&e))?;
+    let signature = schnorr::Signature::from_str(sig).map_err(|e| encoding(&e))?;
+    Secp256k1::verification_only()
+        .verify_schnorr(&signature, &Message::from_digest(hash), &key)
+        .map_err(|e| CoreError::Receipt(format!("bad signature: {}", e)))?;
+
+    let ack = VoteAck::from_json(content).map_err(|e| encoding(&e))?;
+    Ok(Receipt {
+        election_id: ack.election_id,
+        h_n: general_purpose::STANDARD.decode(ack.h_n).map_err(|e| encoding(&e))?,
+        accepted_at: ack.accepted_at,
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed(keys: &secp256k1::Keypair, content: &str) -> String {
+        let pubkey = keys.x_only_public_key().0.to_string();
+        let serialized = json!([0, pubkey, 1_700_000_000u64, 1, [], content]).to_string();
+        let hash: [u8; 32] = Sha256::digest(serialized.as_bytes()).into();
+        let sig = Secp256k1::new().sign_schnorr_no_aux_rand(&Message::from_digest(hash), keys);
+        json!({
+            "id": hex(&hash),
+            "pubkey": pubkey,
+            "created_at": 1_700_000_000u64,
+            "kind": 1,
+            "tags": [],
+            "content": content,
+            "sig": sig.to_string(),
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_verify_receipt() {
+        let secp = Secp256k1::new();
+        let keys = secp256k1::Keypair::from_seckey_slice(&secp, &[1; 32]).unwrap();
+        let ec_pubkey = keys.x_only_public_key().0.to_string();
+        let content = r#"{"election_id":"a1b2","h_n":"AQID","accepted_at":1700000000}"#;
+        let event = signed(&keys, content);
+
+        let receipt = verify_receipt(&event, &ec_pubkey).unwrap();
+        assert_eq!(receipt, Receipt { election_id: "a1b2".to_string(), h_n: vec![1, 2, 3], accepted_at: 1_700_000_000 });
+
+        // Signed by someone else
+        let other = secp256k1::Keypair::from_seckey_slice(&secp, &[2; 32]).unwrap();
+        let other_pubkey = other.x_only_public_key().0.to_string();
+        assert!(verify_receipt(&event, &other_pubkey).is_err());
+        assert!(matches!(verify_receipt(&signed(&other, content), &ec_pubkey), Err(CoreError::Receipt(_))));
+
+        // Tampered content
+        let tampered = event.replace("a1b2", "a1b3");
+        assert!(matches!(verify_receipt(&tampered, &ec_pubkey), Err(CoreError::Receipt(_))));
+        assert!(verify_receipt("{}", &ec_pubkey).is_err());
+    }
+}
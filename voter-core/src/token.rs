@@ -0,0 +1,139 @@
+use base64::engine::{Engine, general_purpose};
+use blind_rsa_signatures::{BlindSignature, MessageRandomizer, PublicKey as RSAPublicKey, Secret, Signature};
+use criptocracia_protocol::tally::parse_tally_key;
+use criptocracia_protocol::{BlindTokenScheme, EncryptedBallot, TokenBinding, TokenScheme, VotePayload};
+use num_bigint_dig::{BigUint, RandBigInt};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+
+use crate::CoreError;
+
+/// What a voter keeps between the token request and the vote: the nonce,
+/// its hash the EC signs blindly, and the secret and randomizer to unblind
+/// the EC's signature with.
+#[derive(Debug, Clone)]
+pub struct BlindToken {
+    pub nonce: BigUint,
+    /// Hash of the nonce, bound to the election and its end in elections with bound tokens
+    pub h_n: Vec<u8>,
+    pub secret: Secret,
+    pub r: Option<MessageRandomizer>,
+    pub scheme: TokenScheme,
+    /// End of the election the token is bound to
+    pub expiry: Option<u64>,
+}
+
+impl BlindToken {
+    /// Generates a nonce and blinds its hash with the EC's RSA public key, in
+    /// the token scheme of the election. In elections with bound tokens,
+    /// `binding` is the election ID and end the hash is bound to.
+    /// Returns the token and the Base64 blinded hash to send to the EC.
+    pub fn request(
+        ec_pub_key: &RSAPublicKey,
+        scheme: TokenScheme,
+        binding: Option<(&str, u64)>,
+    ) -> Result<(Self, String), CoreError> {
+        let nonce: BigUint = OsRng.gen_biguint(128);
+        let (h_n, expiry) = match binding {
+            Some((election_id, expiry)) => {
+                let binding = TokenBinding { nonce: nonce.to_bytes_be(), expiry };
+                (binding.hash(election_id), Some(expiry))
+            }
+            None => (Sha256::digest(nonce.to_bytes_be()).to_vec(), None),
+        };
+        let blinding = scheme.blind(ec_pub_key, &h_n).map_err(|e| CoreError::Blinding(e.to_string()))?;
+        let blinded_b64 = general_purpose::STANDARD.encode(&blinding.blind_msg);
+        let token = Self {
+            nonce,
+            h_n,
+            secret: blinding.secret,
+            r: blinding.msg_randomizer,
+            scheme,
+            expiry,
+        };
+        Ok((token, blinded_b64))
+    }
+
+    /// Unblinds the Base64 blind signature sent by the EC into the token.
+    pub fn unblind(&self, ec_pub_key: &RSAPublicKey, blind_sig_b64: &str) -> Result<Signature, CoreError> {
+        let blind_sig = general_purpose::STANDARD
+            .decode(blind_sig_b64)
+            .map_err(|e| CoreError::Encoding(e.to_string()))?;
+        self.scheme
+            .unblind(ec_pub_key, &BlindSignature::from(blind_sig), &self.secret, self.r, &self.h_n)
+            .map_err(|e| CoreError::Blinding(e.to_string()))
+    }
+
+    /// Payload of a vote for the choices, a single candidate ID for plurality
+    /// elections, or `None` without choices.
+    pub fn vote_payload(&self, token: &Signature, choices: &[u8]) -> Option<VotePayload> {
+        if choices.is_empty() {
+            return None;
+        }
+        self.payload(token, choices.to_vec(), None)
+    }
+
+    /// Like `vote_payload`, with the choice encrypted to the tally key of an
+    /// election with an encrypted tally, among its `candidates` IDs sorted.
+    /// `None` too unless the choice is a single one of the candidates.
+    pub fn encrypted_vote_payload(
+        &self,
+        token: &Signature,
+        choices: &[u8],
+        election_id: &str,
+        tally_key: &str,
+        candidates: &[u8],
+    ) -> Option<VotePayload> {
+        let [choice] = choices else {
+            return None;
+        };
+        let index = candidates.iter().position(|id| id == choice)?;
+        let key = parse_tally_key(tally_key).ok()?;
+        let encrypted = EncryptedBallot::encrypt(&key, candidates.len(), index, election_id, &self.h_n).ok()?;
+        self.payload(token, Vec::new(), Some(encrypted))
+    }
+
+    fn payload(&self, token: &Signature, choices: Vec<u8>, encrypted: Option<EncryptedBallot>) -> Option<VotePayload> {
+        if self.r.is_some() != self.scheme.randomized() {
+            return None;
+        }
+        Some(VotePayload {
+            h_n: self.h_n.clone(),
+            token: token.to_vec(),
+            r: self.r.map(|r| r.0),
+            choices,
+            binding: self.expiry.map(|expiry| TokenBinding { nonce: self.nonce.to_bytes_be(), expiry }),
+            encrypted,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blind_rsa_signatures::SecretKey as RSASecretKey;
+
+    #[test]
+    fn test_token_round_trip() {
+        let sk = RSASecretKey::from_pem(include_str!("../../ec/ec_private.pem")).unwrap();
+        let pk = sk.public_key().unwrap();
+        for scheme in [TokenScheme::RsaPssRandomized, TokenScheme::RsaPssDeterministic] {
+            let (blind, blinded_b64) = BlindToken::request(&pk, scheme, Some(("a1b2", 4_600))).unwrap();
+            assert_eq!(blind.r.is_some(), scheme.randomized());
+
+            // The EC signs the blinded hash
+            let blinded = general_purpose::STANDARD.decode(blinded_b64).unwrap();
+            let blind_sig = scheme.sign(&sk, &blinded).unwrap();
+            let token = blind.unblind(&pk, &general_purpose::STANDARD.encode(blind_sig)).unwrap();
+            assert!(blind.unblind(&pk, "not base64").is_err());
+
+            // and finds the token valid for the hash bound to the election
+            let payload = VotePayload::parse(&blind.vote_payload(&token, &[2]).unwrap().encode()).unwrap();
+            assert_eq!(payload.choices, vec![2]);
+            assert_eq!(payload.binding.unwrap().hash("a1b2"), payload.h_n);
+            let r = payload.r.map(MessageRandomizer::from);
+            assert!(scheme.verify(&pk, &Signature::from(payload.token), r, &payload.h_n));
+            assert!(blind.vote_payload(&token, &[]).is_none());
+        }
+    }
+}
@@ -0,0 +1,379 @@
+//! Kotlin and Swift bindings of the voter core, generated with UniFFI, for
+//! mobile voter apps. They read election events, build the token request
+//! and the vote, unblind the EC's signature and verify its receipts: the app
+//! only gift wraps the messages to the EC and unwraps its answers, with its
+//! own Nostr library. The token state is a plain record the app stores
+//! between the token request and the vote.
+//!
+//! Generate the bindings from the built library:
+//! `cargo run -p criptocracia-voter-ffi --features bindgen --bin uniffi-bindgen -- generate --library <lib> --language kotlin --out-dir <dir>`
+
+use base64::engine::{Engine, general_purpose};
+use blind_rsa_signatures::{MessageRandomizer, PublicKey as RSAPublicKey, Secret, Signature};
+use criptocracia_protocol::message::kind;
+use criptocracia_protocol::{ElectionEvent, ErrorPayload, Message, TokenScheme};
+use criptocracia_voter_core::{BlindToken, CoreError};
+use num_bigint_dig::BigUint;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+uniffi::setup_scaffolding!();
+
+/// Why an election, token, answer or receipt can't be used.
+#[derive(Debug, uniffi::Error, thiserror::Error)]
+#[uniffi(flat_error)]
+pub enum VoterError {
+    /// The election event can't be read, or has no usable RSA key
+    #[error("Invalid election: {0}")]
+    InvalidElection(String),
+    /// The token state or the EC's signature can't be used
+    #[error("Invalid token: {0}")]
+    InvalidToken(String),
+    /// The EC's answer can't be read or isn't the one expected
+    #[error("Invalid answer: {0}")]
+    InvalidAnswer(String),
+    /// The EC rejected the message, with its error code and reason
+    #[error("Rejected by the EC: {0}")]
+    Rejected(String),
+    /// The receipt isn't signed by the EC or isn't of this vote
+    #[error("Invalid receipt: {0}")]
+    InvalidReceipt(String),
+    /// The election needs something the bindings don't do yet
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+}
+
+fn invalid_token(e: impl fmt::Display) -> VoterError {
+    VoterError::InvalidToken(e.to_string())
+}
+
+/// Status of an election.
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Enum)]
+pub enum Status {
+    Open,
+    InProgress,
+    Finished,
+    Canceled,
+}
+
+/// How the ballot is marked.
+#[derive(Debug, Clone, Copy, PartialEq, uniffi::Enum)]
+pub enum VotingMethod {
+    /// A single candidate
+    Plurality,
+    /// Any number of candidates
+    Approval,
+    /// Candidates in order of preference
+    Ranked,
+}
+
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct Candidate {
+    pub id: u8,
+    pub name: String,
+}
+
+/// An election, as announced by the EC.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct Election {
+    pub id: String,
+    pub name: String,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub status: Status,
+    pub candidates: Vec<Candidate>,
+    pub voting_method: VotingMethod,
+    /// EC's RSA public key, Base64 DER
+    pub rsa_pub_key: String,
+    pub token_scheme: String,
+    pub bound_tokens: bool,
+    pub anonymous_requests: bool,
+    /// Key the ballots are encrypted to, in elections with an encrypted tally
+    pub tally_key: Option<String>,
+    /// Whether the election is a drill of an EC's rehearsal, not a real one
+    pub rehearsal: bool,
+}
+
+/// What the app keeps between the token request and the vote, binary
+/// fields in Base64. It holds the secret that links the vote to the voter,
+/// so it's stored encrypted and deleted once the vote is acknowledged.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct TokenState {
+    pub election_id: String,
+    pub nonce: String,
+    /// Hash of the nonce, which the receipt of the vote names
+    pub h_n: String,
+    pub secret: String,
+    pub r: Option<String>,
+    pub token_scheme: String,
+    pub expiry: Option<u64>,
+    /// Unblinded token, once the EC answered
+    pub token: Option<String>,
+}
+
+/// A token request: the message to gift wrap to the EC from the voter's
+/// keys, and the state to keep.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct TokenRequest {
+    pub message: String,
+    pub state: TokenState,
+}
+
+/// Receipt of an accepted vote, signed by the EC.
+#[derive(Debug, Clone, PartialEq, uniffi::Record)]
+pub struct Receipt {
+    pub election_id: String,
+    /// Base64 hash of the nonce the vote was cast with
+    pub h_n: String,
+    pub accepted_at: u64,
+}
+
+/// Reads the content of an election event.
+#[uniffi::export]
+pub fn parse_election(json: String) -> Result<Election, VoterError> {
+    let event = ElectionEvent::from_json(&json).map_err(|e| VoterError::InvalidElection(e.to_string()))?;
+    use criptocracia_protocol::{Status as S, VotingMethod as V};
+    Ok(Election {
+        id: event.id,
+        name: event.name,
+        start_time: event.start_time,
+        end_time: event.end_time,
+        status: match event.status {
+            S::Open => Status::Open,
+            S::InProgress => Status::InProgress,
+            S::Finished => Status::Finished,
+            S::Canceled => Status::Canceled,
+        },
+        candidates: event.candidates.into_iter().map(|c| Candidate { id: c.id, name: c.name }).collect(),
+        voting_method: match event.voting_method {
+            V::Plurality => VotingMethod::Plurality,
+            V::Approval => VotingMethod::Approval,
+            V::Ranked => VotingMethod::Ranked,
+        },
+        rsa_pub_key: event.rsa_pub_key,
+        token_scheme: event.token_scheme.as_str().to_string(),
+        bound_tokens: event.bound_tokens,
+        anonymous_requests: event.anonymous_requests,
+        tally_key: event.tally_key,
+        rehearsal: event.rehearsal,
+    })
+}
+
+/// Starts a token request for an election: the nonce is generated and its
+/// hash blinded with the EC's RSA key.
+#[uniffi::export]
+pub fn request_token(election: &Election) -> Result<TokenRequest, VoterError> {
+    if election.anonymous_requests {
+        return Err(VoterError::Unsupported("anonymous token requests".to_string()));
+    }
+    let pk = rsa_key(election)?;
+    let scheme = scheme(&election.token_scheme)?;
+    let binding = election.bound_tokens.then_some((election.id.as_str(), election.end_time));
+    let (blind, blinded_b64) = BlindToken::request(&pk, scheme, binding).map_err(invalid_token)?;
+    let message = Message::new_with_election(
+        format!("token_request_{}", now()),
+        kind::TOKEN_REQUEST,
+        blinded_b64,
+        election.id.clone(),
+    );
+    Ok(TokenRequest { message: message.as_json(), state: state(&election.id, &blind, None) })
+}
+
+/// Unblinds the token in the EC's answer to the token request, the JSON
+/// content of the gift wrap, and returns the state with the token.
+#[uniffi::export]
+pub fn receive_token(election: &Election, state: TokenState, answer: String) -> Result<TokenState, VoterError> {
+    let answer = read_answer(&answer, &election.id)?;
+    if answer.kind != kind::TOKEN_REQUEST {
+        return Err(VoterError::InvalidAnswer(format!("message of kind {} instead of a token", answer.kind)));
+    }
+    let blind = blind_token(&state)?;
+    let token = blind.unblind(&rsa_key(election)?, &answer.payload).map_err(invalid_token)?;
+    Ok(TokenState { token: Some(general_purpose::STANDARD.encode(token)), ..state })
+}
+
+/// The vote message for the choices, to gift wrap to the EC from throwaway
+/// keys whose hex public key is `reply_to`: the EC sends its receipt there.
+/// In elections with an encrypted tally, the choice is encrypted.
+#[uniffi::export]
+pub fn vote_message(
+    election: &Election,
+    state: &TokenState,
+    choices: Vec<u8>,
+    reply_to: String,
+) -> Result<String, VoterError> {
+    let blind = blind_token(state)?;
+    let token = match &state.token {
+        Some(token) => Signature::from(general_purpose::STANDARD.decode(token).map_err(invalid_token)?),
+        None => return Err(VoterError::InvalidToken("no token received yet".to_string())),
+    };
+    let payload = match &election.tally_key {
+        Some(tally_key) => {
+            let mut ids: Vec<u8> = election.candidates.iter().map(|c| c.id).collect();
+            ids.sort_unstable();
+            blind.encrypted_vote_payload(&token, &choices, &election.id, tally_key, &ids)
+        }
+        None => blind.vote_payload(&token, &choices),
+    };
+    let Some(payload) = payload else {
+        return Err(VoterError::InvalidToken("no vote payload for these choices".to_string()));
+    };
+    let message =
+        Message::new_with_election(format!("vote_{}", now()), kind::VOTE, payload.encode(), election.id.clone())
+            .with_reply_to(reply_to);
+    Ok(message.as_json())
+}
+
+/// Reads the EC's answer to the vote, the JSON content of the gift wrap,
+/// and verifies that its receipt is signed by the EC (hex Nostr key) for
+/// this vote.
+#[uniffi::export]
+pub fn receive_receipt(state: &TokenState, answer: String, ec_pubkey: String) -> Result<Receipt, VoterError> {
+    let answer = read_answer(&answer, &state.election_id)?;
+    if answer.kind != kind::ACK {
+        return Err(VoterError::InvalidAnswer(format!("message of kind {} instead of a receipt", answer.kind)));
+    }
+    let receipt = verify_receipt(answer.payload, ec_pubkey)?;
+    if receipt.election_id != state.election_id || receipt.h_n != state.h_n {
+        return Err(VoterError::InvalidReceipt("receipt of another vote".to_string()));
+    }
+    Ok(receipt)
+}
+
+/// Verifies that a receipt event is signed by the EC (hex Nostr key) and
+/// reads it.
+#[uniffi::export]
+pub fn verify_receipt(event_json: String, ec_pubkey: String) -> Result<Receipt, VoterError> {
+    let receipt = criptocracia_voter_core::verify_receipt(&event_json, &ec_pubkey).map_err(|e| match e {
+        CoreError::Receipt(e) | CoreError::Encoding(e) | CoreError::Blinding(e) => VoterError::InvalidReceipt(e),
+    })?;
+    Ok(Receipt {
+        election_id: receipt.election_id,
+        h_n: general_purpose::STANDARD.encode(receipt.h_n),
+        accepted_at: receipt.accepted_at,
+    })
+}
+
+/// Reads an answer of the EC about an election, turning its errors into `Rejected`.
+fn read_answer(json: &str, election_id: &str) -> Result<Message, VoterError> {
+    let answer = Message::from_json(json).map_err(|e| VoterError::InvalidAnswer(e.to_string()))?;
+    if answer.election_id.as_deref() != Some(election_id) {
+        return Err(VoterError::InvalidAnswer("answer about another election".to_string()));
+    }
+    if answer.kind == kind::ERROR {
+        return Err(VoterError::Rejected(match ErrorPayload::from_json(&answer.payload) {
+            Ok(error) => format!("{}: {}", error.code.as_str(), error.reason),
+            Err(_) => answer.payload,
+        }));
+    }
+    Ok(answer)
+}
+
+fn rsa_key(election: &Election) -> Result<RSAPublicKey, VoterError> {
+    let invalid = |e: &dyn fmt::Display| VoterError::InvalidElection(format!("RSA key: {}", e));
+    let der = general_purpose::STANDARD.decode(&election.rsa_pub_key).map_err(|e| invalid(&e))?;
+    RSAPublicKey::from_der(&der).map_err(|e| invalid(&e))
+}
+
+fn scheme(name: &str) -> Result<TokenScheme, VoterError> {
+    TokenScheme::parse(name).ok_or_else(|| VoterError::Unsupported(format!("token scheme {}", name)))
+}
+
+fn state(election_id: &str, blind: &BlindToken, token: Option<String>) -> TokenState {
+    let b64 = &general_purpose::STANDARD;
+    TokenState {
+        election_id: election_id.to_string(),
+        nonce: b64.encode(blind.nonce.to_bytes_be()),
+        h_n: b64.encode(&blind.h_n),
+        secret: b64.encode(&blind.secret.0),
+        r: blind.r.map(|r| b64.encode(r.0)),
+        token_scheme: blind.scheme.as_str().to_string(),
+        expiry: blind.expiry,
+        token,
+    }
+}
+
+fn blind_token(state: &TokenState) -> Result<BlindToken, VoterError> {
+    let b64 = &general_purpose::STANDARD;
+    let r = match &state.r {
+        Some(r) => {
+            let bytes: [u8; 32] = b64
+                .decode(r)
+                .map_err(invalid_token)?
+                .try_into()
+                .map_err(|_| invalid_token("randomizer of the wrong length"))?;
+            Some(MessageRandomizer::from(bytes))
+        }
+        None => None,
+    };
+    Ok(BlindToken {
+        nonce: BigUint::from_bytes_be(&b64.decode(&state.nonce).map_err(invalid_token)?),
+        h_n: b64.decode(&state.h_n).map_err(invalid_token)?,
+        secret: Secret::from(b64.decode(&state.secret).map_err(invalid_token)?),
+        r,
+        scheme: scheme(&state.token_scheme)?,
+        expiry: state.expiry,
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blind_rsa_signatures::SecretKey as RSASecretKey;
+    use criptocracia_protocol::{BlindTokenScheme, VotePayload};
+
+    fn election(rsa_pub_key: &str) -> String {
+        serde_json::json!({
+            "id": "a1b2",
+            "name": "Board",
+            "start_time": 1_000,
+            "end_time": 4_600,
+            "candidates": [{"id": 1, "name": "Alice"}, {"id": 2, "name": "Bob"}],
+            "status": "in-progress",
+            "rsa_pub_key": rsa_pub_key,
+            "token_scheme": "rsa-pss-deterministic",
+            "bound_tokens": true,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_token_and_vote() {
+        let sk = RSASecretKey::from_pem(include_str!("../../ec/ec_private.pem")).unwrap();
+        let pk = sk.public_key().unwrap();
+        let election = parse_election(election(&general_purpose::STANDARD.encode(pk.to_der().unwrap()))).unwrap();
+        assert_eq!(election.status, Status::InProgress);
+        assert_eq!(election.voting_method, VotingMethod::Plurality);
+        assert!(!election.rehearsal);
+        assert!(parse_election("{}".to_string()).is_err());
+
+        // The EC signs the blinded hash of the request
+        let request = request_token(&election).unwrap();
+        let message = Message::from_json(&request.message).unwrap();
+        assert_eq!((message.kind, message.election_id.as_deref()), (kind::TOKEN_REQUEST, Some("a1b2")));
+        let scheme = TokenScheme::RsaPssDeterministic;
+        let blinded = general_purpose::STANDARD.decode(&message.payload).unwrap();
+        let blind_sig = general_purpose::STANDARD.encode(scheme.sign(&sk, &blinded).unwrap());
+        let answer = message.reply(kind::TOKEN_REQUEST, blind_sig);
+        let state = receive_token(&election, request.state.clone(), answer.as_json()).unwrap();
+        assert!(state.token.is_some());
+
+        // A rejection reads as one
+        let error = message.reply(kind::ERROR, r#"{"code":"unauthorized","reason":"Not on the roll"}"#.to_string());
+        let rejected = receive_token(&election, request.state.clone(), error.as_json());
+        assert!(matches!(rejected, Err(VoterError::Rejected(reason)) if reason == "unauthorized: Not on the roll"));
+
+        // The EC finds the token of the vote valid for the hash bound to the election
+        assert!(vote_message(&election, &request.state, vec![2], "ab".to_string()).is_err());
+        let vote = Message::from_json(&vote_message(&election, &state, vec![2], "ab".to_string()).unwrap()).unwrap();
+        assert_eq!((vote.kind, vote.reply_to.as_deref()), (kind::VOTE, Some("ab")));
+        let payload = VotePayload::parse(&vote.payload).unwrap();
+        assert_eq!(payload.choices, vec![2]);
+        assert_eq!(payload.binding.unwrap().hash("a1b2"), payload.h_n);
+        assert!(scheme.verify(&pk, &Signature::from(payload.token), None, &payload.h_n));
+    }
+}
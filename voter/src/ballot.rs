@@ -0,0 +1,125 @@
+
+pub use criptocracia_protocol::VotingMethod;
+
+/// Choices of the voter on the ballot of one election.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Ballot {
+    pub method: VotingMethod,
+    /// Candidate IDs, in order of preference for ranked ballots
+    pub choices: Vec<u8>,
+}
+
+impl Ballot {
+    pub fn new(method: VotingMethod) -> Self {
+        Self { method, choices: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.choices.is_empty()
+    }
+
+    /// Marks a candidate: plurality replaces the choice, approval and
+    /// ranked ballots add the candidate (at the end of the ranking) or remove it.
+    pub fn toggle(&mut self, candidate_id: u8) {
+        match self.method {
+            VotingMethod::Plurality => self.choices = vec![candidate_id],
+            VotingMethod::Approval | VotingMethod::Ranked => {
+                match self.choices.iter().position(|c| *c == candidate_id) {
+                    Some(i) => {
+                        self.choices.remove(i);
+                    }
+                    None => self.choices.push(candidate_id),
+                }
+            }
+        }
+    }
+
+    /// Moves a ranked candidate one place up the ranking.
+    pub fn rank_up(&mut self, candidate_id: u8) {
+        if let Some(i) = self.rank_index(candidate_id).filter(|i| *i > 0) {
+            self.choices.swap(i, i - 1);
+        }
+    }
+
+    /// Moves a ranked candidate one place down the ranking.
+    pub fn rank_down(&mut self, candidate_id: u8) {
+        if let Some(i) = self.rank_index(candidate_id).filter(|i| i + 1 < self.choices.len()) {
+            self.choices.swap(i, i + 1);
+        }
+    }
+
+    fn rank_index(&self, candidate_id: u8) -> Option<usize> {
+        if self.method != VotingMethod::Ranked {
+            return None;
+        }
+        self.choices.iter().position(|c| *c == candidate_id)
+    }
+
+    /// Mark of a candidate in the candidates table: a checkbox for
+    /// approval ballots, the position for ranked ones.
+    pub fn mark(&self, candidate_id: u8) -> String {
+        let position = self.choices.iter().position(|c| *c == candidate_id);
+        match (self.method, position) {
+            (VotingMethod::Plurality, Some(_)) => "*".to_string(),
+            (VotingMethod::Plurality, None) => String::new(),
+            (VotingMethod::Approval, Some(_)) => "[x]".to_string(),
+            (VotingMethod::Approval, None) => "[ ]".to_string(),
+            (VotingMethod::Ranked, Some(i)) => format!("{}.", i + 1),
+            (VotingMethod::Ranked, None) => "-".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plurality_keeps_one_choice() {
+        let mut ballot = Ballot::new(VotingMethod::Plurality);
+        ballot.toggle(1);
+        ballot.toggle(2);
+        assert_eq!(ballot.choices, vec![2]);
+        assert_eq!(ballot.mark(2), "*");
+        // Ranking keys do nothing on a plurality ballot
+        ballot.rank_up(2);
+        assert_eq!(ballot.choices, vec![2]);
+    }
+
+    #[test]
+    fn test_approval_toggles() {
+        let mut ballot = Ballot::new(VotingMethod::Approval);
+        ballot.toggle(3);
+        ballot.toggle(1);
+        ballot.toggle(2);
+        ballot.toggle(3);
+        assert_eq!(ballot.choices, vec![1, 2]);
+        assert_eq!(ballot.mark(1), "[x]");
+        assert_eq!(ballot.mark(3), "[ ]");
+    }
+
+    #[test]
+    fn test_ranked_reorders() {
+        let mut ballot = Ballot::new(VotingMethod::Ranked);
+        ballot.toggle(1);
+        ballot.toggle(2);
+        ballot.toggle(3);
+        ballot.rank_up(3);
+        assert_eq!(ballot.choices, vec![1, 3, 2]);
+        // The first can't go up nor the last down
+        ballot.rank_up(1);
+        ballot.rank_down(2);
+        assert_eq!(ballot.choices, vec![1, 3, 2]);
+        ballot.rank_down(1);
+        assert_eq!(ballot.choices, vec![3, 1, 2]);
+        assert_eq!(ballot.mark(1), "2.");
+        assert_eq!(ballot.mark(4), "-");
+    }
+
+    #[test]
+    fn test_voting_method_from_json() {
+        let method: VotingMethod = serde_json::from_str(r#""ranked""#).unwrap();
+        assert_eq!(method, VotingMethod::Ranked);
+        assert!(serde_json::from_str::<VotingMethod>(r#""borda""#).is_err());
+    }
+}
@@ -1,27 +1,31 @@
+use crate::ballot::VotingMethod;
+use crate::i18n::{Text, tr, trf};
+use crate::token::VoteToken;
+use base64::engine::{Engine, general_purpose};
+use blind_rsa_signatures::PublicKey as RSAPublicKey;
+use criptocracia_protocol::election::{REHEARSAL_TAG, parse_results};
+use criptocracia_protocol::message::kind;
+use criptocracia_protocol::ring::pick_ring;
+use criptocracia_protocol::{AnonymousTokenRequest, ElectionEvent, RING_SIZE, RingProof};
 use nostr_sdk::event::Event;
+use nostr_sdk::prelude::Keys;
 
-#[derive(Debug, serde::Deserialize)]
-#[serde(rename_all = "kebab-case")]
-pub enum Status {
-    Open,
-    InProgress,
-    Finished,
-    Canceled,
-}
-
-#[derive(Debug, serde::Deserialize, Clone)]
-pub struct Candidate {
-    pub id: u8,
-    pub name: String,
-}
+pub use criptocracia_protocol::{Candidate, Message, Status, TokenScheme};
 
-impl Candidate {
-    pub fn new(id: u8, name: String) -> Self {
-        Self { id, name }
+/// Text of the candidate detail pane
+pub fn candidate_details(candidate: &Candidate) -> String {
+    let mut text = format!("{} (#{})", candidate.name, candidate.id);
+    if let Some(url) = &candidate.url {
+        text.push_str(&format!("\n{}", url));
     }
+    match &candidate.bio {
+        Some(bio) => text.push_str(&format!("\n\n{}", bio)),
+        None => text.push_str(&format!("\n\n{}", tr(Text::NoDescription))),
+    }
+    text
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize, Clone)]
 pub struct Election {
     pub id: String,
     pub name: String,
@@ -30,6 +34,29 @@ pub struct Election {
     pub end_time: u64,
     pub status: Status,
     pub rsa_pub_key: String,
+    #[serde(default)]
+    pub voting_method: VotingMethod,
+    /// Blind signature scheme of the election's tokens
+    #[serde(default)]
+    pub token_scheme: TokenScheme,
+    /// Whether tokens are bound to the election ID and end
+    #[serde(default)]
+    pub bound_tokens: bool,
+    /// Whether tokens are requested with a ring proof instead of the voter's pubkey
+    #[serde(default)]
+    pub anonymous_requests: bool,
+    /// Voters on the roll, published by elections with anonymous token requests
+    #[serde(default)]
+    pub roll: Vec<String>,
+    /// Key the ballots are encrypted to, in elections with an encrypted tally
+    #[serde(default)]
+    pub tally_key: Option<String>,
+    /// Whether the election is a drill of an EC's rehearsal, not a real one
+    #[serde(default)]
+    pub rehearsal: bool,
+    /// Creation time of the event the election was read from
+    #[serde(skip)]
+    pub published_at: u64,
 }
 
 impl Election {
@@ -55,28 +82,133 @@ impl Election {
             end_time,
             status: Status::Open,
             rsa_pub_key,
+            voting_method: VotingMethod::Plurality,
+            token_scheme: TokenScheme::default(),
+            bound_tokens: false,
+            anonymous_requests: false,
+            roll: Vec::new(),
+            tally_key: None,
+            rehearsal: false,
+            published_at: 0,
         }
     }
 
-    pub fn parse_event(event: &Event) -> Result<Self, anyhow::Error> {
-        let data = event.content.clone();
-        let election = serde_json::from_str(&data);
+    /// Vote payload of a token for the choices. In elections with an
+    /// encrypted tally, the choice is encrypted to the tally key.
+    pub fn vote_payload(&self, token: &VoteToken, choices: &[u8]) -> Option<String> {
+        let Some(tally_key) = &self.tally_key else {
+            return token.vote_payload(choices);
+        };
+        let mut ids: Vec<u8> = self.candidates.iter().map(|c| c.id).collect();
+        ids.sort_unstable();
+        token.encrypted_vote_payload(choices, &self.id, tally_key, &ids)
+    }
+
+    /// Name of the election as shown to voters, marked when it's a drill
+    pub fn display_name(&self) -> String {
+        match self.rehearsal {
+            true => trf(Text::RehearsalName, &[&self.name]),
+            false => self.name.clone(),
+        }
+    }
+
+    /// Election ID and end a token of the election is bound to, if it binds them
+    pub fn token_binding(&self) -> Option<(&str, u64)> {
+        self.bound_tokens.then_some((self.id.as_str(), self.end_time))
+    }
 
-        let election = match election {
+    /// Token request proving the voter is on the published roll, hidden in
+    /// a ring of up to `RING_SIZE` voters, for elections with anonymous token
+    /// requests. It must be sent from the token's request keys.
+    pub fn anonymous_request(&self, keys: &Keys, blinded_b64: String) -> anyhow::Result<Message> {
+        let own = keys.public_key().to_hex();
+        if !self.roll.contains(&own) {
+            return Err(anyhow::anyhow!("Not on the roll of election {}", self.id));
+        }
+        let blinded = general_purpose::STANDARD.decode(&blinded_b64)?;
+        let ring = pick_ring(&self.roll, &own, RING_SIZE);
+        let proof = RingProof::sign(keys.secret_key(), &ring, &self.id, &blinded)?;
+        let request = AnonymousTokenRequest { blinded_h_n: blinded_b64, proof };
+        Ok(Message::new_with_election(
+            format!("token_request_{}", chrono::Utc::now().timestamp()),
+            kind::ANONYMOUS_TOKEN_REQUEST,
+            request.as_json(),
+            self.id.clone(),
+        ))
+    }
+
+    /// Status computed from the local clock, so it doesn't depend on the
+    /// last published event being up to date. Canceled elections stay canceled.
+    pub fn current_status(&self, now: u64) -> Status {
+        if self.status == Status::Canceled {
+            Status::Canceled
+        } else if now >= self.end_time {
+            Status::Finished
+        } else if now >= self.start_time {
+            Status::InProgress
+        } else {
+            Status::Open
+        }
+    }
+
+    /// Time left until the election starts or ends
+    pub fn countdown(&self, now: u64) -> String {
+        match self.current_status(now) {
+            Status::Open => trf(Text::StartsIn, &[&format_duration(self.start_time - now)]),
+            Status::InProgress => trf(Text::EndsIn, &[&format_duration(self.end_time - now)]),
+            Status::Finished => tr(Text::Ended).to_string(),
+            Status::Canceled => tr(Text::StatusCanceled).to_string(),
+        }
+    }
+
+    /// Whether the content of the election event is signed with `pk`, the
+    /// EC's content key. Events relayed by others can't be altered then.
+    pub fn signed_by(event: &Event, pk: &RSAPublicKey) -> bool {
+        ElectionEvent::content_sig_matches(&event.content, pk)
+    }
+
+    pub fn parse_event(event: &Event) -> Result<Self, anyhow::Error> {
+        let data = match ElectionEvent::from_json(&event.content) {
             Ok(e) => e,
             Err(e) => {
                 return Err(anyhow::anyhow!("Failed to parse election event: {}", e));
             }
         };
 
-        Ok(election)
+        // The published voters are only trusted if they are the committed roll
+        let roll = match data.voter_roll {
+            Some(roll) if roll.pubkeys_match() => roll.pubkeys,
+            Some(roll) if !roll.pubkeys.is_empty() => {
+                log::warn!("Voters of election {} don't match its roll", data.id);
+                Vec::new()
+            }
+            _ => Vec::new(),
+        };
+
+        // Older ECs only mark a rehearsal with the tag of its events
+        let rehearsal = data.rehearsal || event.tags.hashtags().any(|tag| tag == REHEARSAL_TAG);
+
+        Ok(Election {
+            id: data.id,
+            name: data.name,
+            candidates: data.candidates,
+            start_time: data.start_time,
+            end_time: data.end_time,
+            status: data.status,
+            rsa_pub_key: data.rsa_pub_key,
+            voting_method: data.voting_method,
+            token_scheme: data.token_scheme,
+            bound_tokens: data.bound_tokens,
+            anonymous_requests: data.anonymous_requests,
+            roll,
+            tally_key: data.tally_key,
+            rehearsal,
+            published_at: event.created_at.as_u64(),
+        })
     }
 
     pub fn parse_result_event(event: &Event) -> Result<Vec<(u8, u32)>, anyhow::Error> {
-        let data = event.content.clone();
-        let results: Result<Vec<(u8, u32)>, serde_json::Error> = serde_json::from_str(&data);
-
-        let results = match results {
+        let results = match parse_results(&event.content) {
             Ok(r) => r,
             Err(e) => {
                 return Err(anyhow::anyhow!("Failed to parse results event: {}", e));
@@ -87,30 +219,150 @@ impl Election {
     }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub struct Message {
-    pub id: String,
-    /// 1: Token request, 2: Vote
-    pub kind: u8,
-    pub payload: String,
-    /// Election ID for election-specific validation
-    pub election_id: Option<String>,
+/// Adds an election to the list, newest start time first, or replaces the one
+/// with the same ID. Elections are replaceable events, so an event older than
+/// the one already in the list is stale and ignored. Returns whether the list changed.
+pub fn upsert(elections: &mut Vec<Election>, election: Election) -> bool {
+    match elections.iter_mut().find(|e| e.id == election.id) {
+        Some(existing) if existing.published_at > election.published_at => return false,
+        Some(existing) => *existing = election,
+        None => elections.push(election),
+    }
+    elections.sort_by_key(|e| std::cmp::Reverse(e.start_time));
+    true
 }
 
-impl Message {
-    pub fn new(id: String, kind: u8, payload: String) -> Self {
-        Self { id, kind, payload, election_id: None }
+/// Formats a number of seconds with its two most significant units
+fn format_duration(secs: u64) -> String {
+    let (days, hours, minutes, seconds) = (secs / 86_400, secs / 3_600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {:02}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {:02}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr_sdk::prelude::{EventBuilder, Kind, Tag};
+
+    fn make_election() -> Election {
+        Election::new(
+            "abcd".to_string(),
+            "Test".to_string(),
+            vec![Candidate::new(1, "Alice".to_string())],
+            1_000,
+            3_600,
+            "key".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_current_status_follows_clock() {
+        let mut e = make_election();
+        assert_eq!(e.current_status(999), Status::Open);
+        assert_eq!(e.current_status(1_000), Status::InProgress);
+        assert_eq!(e.current_status(4_600), Status::Finished);
+
+        // A stale published status is overridden by the clock
+        e.status = Status::Open;
+        assert_eq!(e.current_status(5_000), Status::Finished);
+
+        e.status = Status::Canceled;
+        assert_eq!(e.current_status(2_000), Status::Canceled);
     }
 
-    pub fn new_with_election(id: String, kind: u8, payload: String, election_id: String) -> Self {
-        Self { id, kind, payload, election_id: Some(election_id) }
+    #[test]
+    fn test_anonymous_request() {
+        let voters: Vec<Keys> = (0..3).map(|_| Keys::generate()).collect();
+        let mut e = make_election();
+        e.roll = voters.iter().map(|k| k.public_key().to_hex()).collect();
+
+        let blinded = general_purpose::STANDARD.encode(b"blinded");
+        let message = e.anonymous_request(&voters[2], blinded.clone()).unwrap();
+        assert_eq!(message.kind, kind::ANONYMOUS_TOKEN_REQUEST);
+        let request = AnonymousTokenRequest::from_json(&message.payload).unwrap();
+        assert_eq!(request.proof.ring.len(), 3);
+        assert!(request.proof.verify("abcd", b"blinded"));
+
+        assert!(e.anonymous_request(&Keys::generate(), blinded).is_err());
+    }
+
+    #[test]
+    fn test_rehearsal_election() {
+        let keys = Keys::generate();
+        let mut data = ElectionEvent::from_json(
+            r#"{"id":"abcd","name":"Drill","start_time":1,"end_time":2,"status":"open","rsa_pub_key":"key","candidates":[]}"#,
+        )
+        .unwrap();
+        let publish = |data: &ElectionEvent, tags: Vec<Tag>| {
+            let event = EventBuilder::new(Kind::Custom(35_000), data.as_json()).tags(tags).sign_with_keys(&keys).unwrap();
+            Election::parse_event(&event).unwrap()
+        };
+        let real = publish(&data, vec![]);
+        assert!(!real.rehearsal);
+        assert_eq!(real.display_name(), "Drill");
+
+        // A drill says so in its content, or only in the tag of older ECs
+        data.rehearsal = true;
+        let drill = publish(&data, vec![]);
+        assert!(drill.rehearsal);
+        assert_eq!(drill.display_name(), "Drill (rehearsal)");
+        data.rehearsal = false;
+        assert!(publish(&data, vec![Tag::hashtag(REHEARSAL_TAG)]).rehearsal);
     }
 
-    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
-        serde_json::from_str(json)
+    #[test]
+    fn test_countdown() {
+        let e = make_election();
+        assert_eq!(e.countdown(990), "Starts in 10s");
+        assert_eq!(e.countdown(1_000), "Ends in 1h 00m");
+        assert_eq!(e.countdown(4_599), "Ends in 1s");
+        assert_eq!(e.countdown(4_600), "Ended");
+        assert_eq!(format_duration(2 * 86_400 + 3 * 3_600), "2d 03h");
+        assert_eq!(format_duration(125), "2m 05s");
     }
 
-    pub fn as_json(&self) -> String {
-        serde_json::to_string(self).unwrap()
+    #[test]
+    fn test_upsert_keeps_newest_event() {
+        let mut elections = Vec::new();
+        let mut first = make_election();
+        first.published_at = 10;
+        assert!(upsert(&mut elections, first.clone()));
+
+        // A newer event replaces the election
+        let mut renamed = first.clone();
+        renamed.name = "Renamed".to_string();
+        renamed.published_at = 20;
+        assert!(upsert(&mut elections, renamed));
+
+        // The old event, received later from another relay, doesn't
+        assert!(!upsert(&mut elections, first));
+        assert_eq!(elections.len(), 1);
+        assert_eq!(elections[0].name, "Renamed");
+
+        let mut later = make_election();
+        later.id = "ef01".to_string();
+        later.start_time = 5_000;
+        assert!(upsert(&mut elections, later));
+        assert_eq!(elections[0].id, "ef01");
+    }
+
+    #[test]
+    fn test_candidate_metadata_is_optional() {
+        let plain: Candidate = serde_json::from_str(r#"{"id":1,"name":"Alice"}"#).unwrap();
+        assert!(plain.bio.is_none());
+        assert!(candidate_details(&plain).ends_with("No description available"));
+
+        let with_metadata: Candidate = serde_json::from_str(
+            r#"{"id":2,"name":"Bob","bio":"Engineer","url":"https://bob.example"}"#,
+        )
+        .unwrap();
+        assert_eq!(candidate_details(&with_metadata), "Bob (#2)\nhttps://bob.example\n\nEngineer");
     }
 }
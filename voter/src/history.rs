@@ -0,0 +1,95 @@
+use crate::i18n::{Text, tr};
+use crate::token::VoteToken;
+use serde::{Deserialize, Serialize};
+
+/// Participation of the voter in one election, kept next to the tokens
+/// so the History view survives restarts. Timestamps are Unix seconds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub election_id: String,
+    #[serde(default)]
+    pub election_name: Option<String>,
+    #[serde(default)]
+    pub token_requested_at: Option<i64>,
+    #[serde(default)]
+    pub token_received_at: Option<i64>,
+    #[serde(default)]
+    pub voted_at: Option<i64>,
+    /// Candidates marked on the ballot, only recorded when
+    /// `record_choice` is enabled in the settings
+    #[serde(default)]
+    pub choices: Vec<u8>,
+}
+
+impl HistoryEntry {
+    pub fn new(election_id: &str) -> Self {
+        Self {
+            election_id: election_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Next step the voter has to take, or wait for, in this election.
+    pub fn pending_action(&self, token: Option<&VoteToken>) -> &'static str {
+        match token {
+            None => tr(Text::PendingNone),
+            Some(t) if t.token.is_none() => tr(Text::PendingToken),
+            Some(t) if !t.vote_sent => tr(Text::PendingVote),
+            Some(t) if t.receipt.is_none() => tr(Text::PendingReceipt),
+            Some(_) => tr(Text::PendingNone),
+        }
+    }
+}
+
+/// Formats an optional timestamp for the History view.
+pub fn format_time(timestamp: Option<i64>) -> String {
+    timestamp
+        .and_then(|t| chrono::DateTime::from_timestamp(t, 0))
+        .map(|dt| dt.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::{Engine, general_purpose};
+    use blind_rsa_signatures::{Options, SecretKey as RSASecretKey};
+
+    #[test]
+    fn test_pending_action_follows_token() {
+        let sk = RSASecretKey::from_pem(include_str!("../../ec/ec_private.pem")).unwrap();
+        let pk = sk.public_key().unwrap();
+        let entry = HistoryEntry::new("a1b2");
+        assert_eq!(entry.pending_action(None), "-");
+
+        let (mut token, blinded_b64) = VoteToken::request(&pk, criptocracia_protocol::TokenScheme::default(), None).unwrap();
+        assert_eq!(entry.pending_action(Some(&token)), "Wait for the token");
+
+        let blinded = general_purpose::STANDARD.decode(blinded_b64).unwrap();
+        let blind_sig = sk
+            .blind_sign(&mut rand::thread_rng(), &blinded, &Options::default())
+            .unwrap();
+        token
+            .finalize(&pk, &general_purpose::STANDARD.encode(blind_sig))
+            .unwrap();
+        assert_eq!(entry.pending_action(Some(&token)), "Cast your vote");
+
+        token.vote_sent = true;
+        assert_eq!(entry.pending_action(Some(&token)), "Wait for the receipt");
+
+        token.receipt = Some("{}".into());
+        assert_eq!(entry.pending_action(Some(&token)), "-");
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(None), "-");
+        assert_eq!(format_time(Some(0)), "1970-01-01 00:00");
+    }
+
+    #[test]
+    fn test_old_entries_deserialize() {
+        let entry: HistoryEntry = serde_json::from_str(r#"{"election_id":"a1b2"}"#).unwrap();
+        assert_eq!(entry, HistoryEntry::new("a1b2"));
+    }
+}
@@ -0,0 +1,298 @@
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Languages bundled with the voter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Locale {
+    En,
+    Es,
+}
+
+/// Language codes accepted in the settings
+pub const LANGUAGES: [&str; 2] = ["en", "es"];
+
+impl Locale {
+    pub fn from_code(code: &str) -> Option<Locale> {
+        match code.to_lowercase().as_str() {
+            "en" => Some(Locale::En),
+            "es" => Some(Locale::Es),
+            _ => None,
+        }
+    }
+}
+
+static LOCALE: AtomicU8 = AtomicU8::new(Locale::En as u8);
+
+/// Selects the language of the UI, it can be changed while running.
+pub fn set_locale(locale: Locale) {
+    LOCALE.store(locale as u8, Ordering::Relaxed);
+}
+
+pub fn locale() -> Locale {
+    match LOCALE.load(Ordering::Relaxed) {
+        x if x == Locale::Es as u8 => Locale::Es,
+        _ => Locale::En,
+    }
+}
+
+/// Declares the UI texts with their English and Spanish versions.
+macro_rules! texts {
+    ($($key:ident => $en:expr, $es:expr;)*) => {
+        /// UI texts. `{}` in a text is replaced by the arguments given to [`trf`].
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum Text {
+            $($key),*
+        }
+
+        impl Text {
+            #[cfg(test)]
+            const ALL: &'static [Text] = &[$(Text::$key),*];
+
+            pub fn get(self, locale: Locale) -> &'static str {
+                match locale {
+                    Locale::En => match self {
+                        $(Text::$key => $en),*
+                    },
+                    Locale::Es => match self {
+                        $(Text::$key => $es),*
+                    },
+                }
+            }
+        }
+    };
+}
+
+texts! {
+    // Areas and columns
+    Elections => "Elections", "Elecciones";
+    ElectionsEligibleOnly => "Elections (eligible only)", "Elecciones (solo habilitadas)";
+    Candidates => "Candidates", "Candidatos";
+    Candidate => "Candidate", "Candidato";
+    Ballot => "Ballot", "Boleta";
+    Results => "Results", "Resultados";
+    Messages => "Messages", "Mensajes";
+    Relays => "Relays {}/{}", "Relays {}/{}";
+    PendingSends => " - {} pending", " - {} pendiente(s)";
+    Settings => "Settings", "Configuración";
+    HelpTitle => "Help - press any key to close", "Ayuda - pulsa cualquier tecla para cerrar";
+    ColumnId => "Id", "Id";
+    ColumnName => "Name", "Nombre";
+    ColumnStatus => "Status", "Estado";
+    ColumnStarts => "Starts", "Inicio";
+    ColumnCountdown => "Countdown", "Cuenta atrás";
+    ColumnRoll => "Roll", "Padrón";
+    ColumnKeys => "Keys", "Teclas";
+    ColumnAction => "Action", "Acción";
+    ColumnTokenRequested => "Token requested", "Token solicitado";
+    ColumnTokenReceived => "Token received", "Token recibido";
+    ColumnVoted => "Voted", "Votó";
+    ColumnPending => "Pending", "Pendiente";
+    ColumnChoice => "Choice", "Marca";
+    Yes => "Yes", "Sí";
+    No => "No", "No";
+    Unknown => "Unknown", "Desconocido";
+    Invalid => "Invalid", "Inválida";
+
+    // Election status and countdown
+    StatusOpen => "Open", "Abierta";
+    StatusInProgress => "In Progress", "En curso";
+    StatusFinished => "Finished", "Finalizada";
+    StatusCanceled => "Canceled", "Cancelada";
+    StartsIn => "Starts in {}", "Empieza en {}";
+    EndsIn => "Ends in {}", "Termina en {}";
+    Ended => "Ended", "Terminada";
+    RehearsalName => "{} (rehearsal)", "{} (simulacro)";
+
+    // Ballot and results
+    BallotEmpty => "Select an election and a candidate to fill in your ballot",
+        "Selecciona una elección y un candidato para completar tu boleta";
+    BallotText => "Election: {} ({})\n{}\nToken: {}",
+        "Elección: {} ({})\n{}\nToken: {}";
+    BallotCandidate => "Candidate: {}", "Candidato: {}";
+    BallotApproved => "Approved: {}", "Aprobados: {}";
+    BallotRanking => "Ranking: {}", "Orden de preferencia: {}";
+    TokenReceived => "Received", "Recibido";
+    TokenRequested => "Requested, waiting for the EC", "Solicitado, esperando a la CE";
+    TokenNotRequested => "Not requested", "No solicitado";
+    ReceiptVerified => "Receipt: Verified, saved in ~/.voter/receipts",
+        "Comprobante: Verificado, guardado en ~/.voter/receipts";
+    ReceiptWaiting => "Receipt: Waiting for the EC", "Comprobante: Esperando a la CE";
+    PromptVoteSent => "Vote sent, waiting for results", "Voto enviado, esperando resultados";
+    PromptConfirm => "Press 'y' to confirm your vote or 'n' to go back",
+        "Pulsa 'y' para confirmar tu voto o 'n' para volver";
+    PromptWaitToken => "Waiting for the token before the vote can be sent ('n' to go back)",
+        "Esperando el token para poder enviar el voto ('n' para volver)";
+    CandidateVotes => "Candidate {}: {} votes", "Candidato {}: {} votos";
+    NoResults => "No results yet", "Aún no hay resultados";
+    NoDescription => "No description available", "Sin descripción";
+
+    // Overlays
+    SettingsRelays => "Relays (comma separated)", "Relays (separados por comas)";
+    SettingsEcPubkey => "EC public key", "Clave pública de la CE";
+    SettingsLogLevel => "Log level", "Nivel de log";
+    SettingsLanguage => "Language (en, es)", "Idioma (en, es)";
+    SettingsRecordChoice => "Record chosen candidate in history (yes, no)",
+        "Guardar el candidato elegido en el historial (yes, no)";
+    SettingsHelp => "Up/Down: select field | Enter: save | Esc: cancel",
+        "Arriba/Abajo: elegir campo | Enter: guardar | Esc: cancelar";
+    QrTitle => "{} ({}/{}) - c: next, any key: close", "{} ({}/{}) - c: siguiente, cualquier tecla: cerrar";
+    QrElectionId => "Election ID", "ID de la elección";
+    QrEcPubkey => "EC public key", "Clave pública de la CE";
+    QrReceipt => "Vote receipt", "Comprobante de voto";
+    QrTooSmall => "Enlarge the terminal to show this QR code",
+        "Agranda la terminal para mostrar este código QR";
+
+    HistoryTitle => "History - any key to close", "Historial - cualquier tecla para cerrar";
+    HistoryEmpty => "You haven't taken part in any election yet",
+        "Aún no has participado en ninguna elección";
+    LogsTitle => "Log {}/{} - PageUp/PageDown: scroll, any key: close",
+        "Log {}/{} - RePág/AvPág: desplazar, cualquier tecla: cerrar";
+    LogsEmpty => "Nothing logged yet", "Aún no hay nada en el log";
+    PendingToken => "Wait for the token", "Esperar el token";
+    PendingVote => "Cast your vote", "Emitir tu voto";
+    PendingReceipt => "Wait for the receipt", "Esperar el comprobante";
+    PendingNone => "-", "-";
+
+    // Secret key
+    Passphrase => "Passphrase", "Frase de contraseña";
+    EnterPassphrase => "Enter the passphrase of your secret key (Esc to quit)",
+        "Introduce la frase de contraseña de tu clave secreta (Esc para salir)";
+    WrongPassphrase => "Wrong passphrase, try again", "Frase de contraseña incorrecta, inténtalo de nuevo";
+    ChoosePassphrase => "Your secret key is stored in plain text. Choose a passphrase to encrypt it (NIP-49), or press Enter with no passphrase to keep it as is",
+        "Tu clave secreta está guardada en texto plano. Elige una frase de contraseña para cifrarla (NIP-49), o pulsa Enter sin escribir nada para dejarla como está";
+    ConfirmPassphrase => "Repeat the passphrase", "Repite la frase de contraseña";
+    PassphraseMismatch => "The passphrases don't match", "Las frases de contraseña no coinciden";
+    RemoteSigner => "Remote signer", "Firmante remoto";
+    RemoteSignerWaiting => "Connecting to the remote signer (NIP-46), approve the connection on your signing device",
+        "Conectando con el firmante remoto (NIP-46), aprueba la conexión en tu dispositivo de firma";
+
+    // Key bindings
+    ActionUp => "Move up", "Subir";
+    ActionDown => "Move down", "Bajar";
+    ActionScrollUp => "Scroll the candidate details up", "Desplazar los detalles del candidato hacia arriba";
+    ActionScrollDown => "Scroll the candidate details down", "Desplazar los detalles del candidato hacia abajo";
+    ActionSelect => "Request token / mark candidate on the ballot", "Pedir token / marcar candidato en la boleta";
+    ActionConfirm => "Confirm and send the vote", "Confirmar y enviar el voto";
+    ActionBack => "Back to the candidates", "Volver a los candidatos";
+    ActionNextArea => "Switch area", "Cambiar de área";
+    ActionRefresh => "Reconnect relays and reload elections", "Reconectar relays y recargar elecciones";
+    ActionEligibleOnly => "Show only elections you can vote in", "Mostrar solo elecciones en las que puedes votar";
+    ActionSettings => "Open settings", "Abrir configuración";
+    ActionQr => "QR codes: election ID, EC pubkey, receipt", "Códigos QR: ID de elección, clave de la CE, comprobante";
+    ActionRankUp => "Move the candidate up the ranking", "Subir al candidato en el orden de preferencia";
+    ActionRankDown => "Move the candidate down the ranking", "Bajar al candidato en el orden de preferencia";
+    ActionHistory => "Show your voting history", "Mostrar tu historial de votación";
+    ActionLogs => "Show the log", "Mostrar el log";
+    ActionHelp => "Show this help", "Mostrar esta ayuda";
+    ActionQuit => "Quit", "Salir";
+
+    // Notices
+    TokenWithoutElection => "Token received without election ID, ignored", "Token recibido sin ID de elección, ignorado";
+    TokenWithoutRsaKey => "Token received but the EC RSA public key is not available",
+        "Token recibido pero la clave pública RSA de la CE no está disponible";
+    TokenNotPending => "Token received for election {} but no request is pending",
+        "Token recibido para la elección {} sin una solicitud pendiente";
+    TokenInvalid => "Invalid token received for election {}: {}", "Token inválido recibido para la elección {}: {}";
+    TokenReady => "Token received for election {}, you can vote now", "Token recibido para la elección {}, ya puedes votar";
+    TokenSaveFailed => "Failed to save token state: {}", "No se pudo guardar el estado del token: {}";
+    HistorySaveFailed => "Failed to save the vote history: {}", "No se pudo guardar el historial de votación: {}";
+    EcMessage => "EC: {}", "CE: {}";
+    ReceiptMismatch => "Receipt for election {} doesn't match the vote", "El comprobante de la elección {} no corresponde al voto";
+    ReceiptInvalid => "Invalid receipt for election {}: {}", "Comprobante inválido para la elección {}: {}";
+    ReceiptSaved => "Vote receipt for election {} verified and saved to {}",
+        "Comprobante de voto de la elección {} verificado y guardado en {}";
+    ReceiptSaveFailed => "Failed to save receipt: {}", "No se pudo guardar el comprobante: {}";
+    RejectedUnauthorized => "The EC rejected your message for election {}: you are not on the roll or already got a token",
+        "La CE rechazó tu mensaje para la elección {}: no estás en el padrón o ya recibiste un token";
+    RejectedDuplicate => "The EC rejected your vote for election {}: the token was already used",
+        "La CE rechazó tu voto para la elección {}: el token ya fue usado";
+    RejectedElectionClosed => "The EC rejected your message for election {}: the election is not in progress",
+        "La CE rechazó tu mensaje para la elección {}: la elección no está en curso";
+    RejectedUnknownElection => "The EC doesn't know election {}", "La CE no conoce la elección {}";
+    RejectedBadFormat => "The EC couldn't read your message for election {}, check the voter is up to date",
+        "La CE no pudo leer tu mensaje para la elección {}, comprueba que el votante esté actualizado";
+    RejectedExpired => "The EC rejected your message for election {} as too old, check your clock and send it again",
+        "La CE rechazó tu mensaje para la elección {} por ser demasiado antiguo, revisa tu reloj y vuelve a enviarlo";
+    RejectedInternal => "The EC couldn't record your message for election {}, try again",
+        "La CE no pudo registrar tu mensaje para la elección {}, inténtalo de nuevo";
+    ResultsUpdated => "Results updated", "Resultados actualizados";
+    SettingsSaved => "Settings saved, relay and EC public key changes apply after restart",
+        "Configuración guardada, los cambios de relays y clave de la CE se aplican al reiniciar";
+    SettingsSaveFailed => "Failed to save settings: {}", "No se pudo guardar la configuración: {}";
+    QrFailed => "Failed to encode QR code: {}", "No se pudo generar el código QR: {}";
+    ReconnectingRelays => "Reconnecting relays and reloading elections", "Reconectando relays y recargando elecciones";
+    ReloadFailed => "Failed to reload elections: {}", "No se pudieron recargar las elecciones: {}";
+    ShowingEligibleOnly => "Showing only elections you can vote in", "Mostrando solo elecciones en las que puedes votar";
+    ShowingAll => "Showing all elections", "Mostrando todas las elecciones";
+    RsaKeyMissing => "EC RSA public key not available yet, can't request a token",
+        "La clave pública RSA de la CE aún no está disponible, no se puede pedir el token";
+    BlindingFailed => "Blinding failed: {}", "Falló el cegado: {}";
+    AnonymousNeedsKey => "Anonymous token requests need your key, not a remote signer", "Las solicitudes de token anónimas necesitan tu clave, no un firmante remoto";
+    TokenNotRequestedSaveFailed => "Failed to save token state, token not requested: {}",
+        "No se pudo guardar el estado del token, token no solicitado: {}";
+    TokenRequestQueued => "No relay reachable, the token request for election {} will be sent when they are back",
+        "Ningún relay disponible, la solicitud de token para la elección {} se enviará cuando vuelvan";
+    TokenRequestSent => "Token request sent for election {} to {} relay(s), waiting for the EC",
+        "Solicitud de token enviada para la elección {} a {} relay(s), esperando a la CE";
+    TokenRequestFailed => "Failed to send token request: {}", "No se pudo enviar la solicitud de token: {}";
+    VoteAlreadySent => "Your vote for this election was already sent", "Tu voto para esta elección ya fue enviado";
+    VoteNotSentSaveFailed => "Failed to save token state, vote not sent: {}",
+        "No se pudo guardar el estado del token, voto no enviado: {}";
+    VoteSendFailed => "Failed to send vote: {}", "No se pudo enviar el voto: {}";
+    VoteQueued => "No relay reachable, the vote for election {} will be sent when they are back",
+        "Ningún relay disponible, el voto para la elección {} se enviará cuando vuelvan";
+    VoteSent => "Vote sent for election {} to {} relay(s)", "Voto enviado para la elección {} a {} relay(s)";
+    VoteNotReady => "Vote can't be sent yet: token not received", "Aún no se puede enviar el voto: token no recibido";
+    KeymapInvalid => "{}, using the default key bindings", "{}, se usan las teclas por defecto";
+}
+
+/// Text in the current language
+pub fn tr(text: Text) -> &'static str {
+    text.get(locale())
+}
+
+/// Text in the current language with its `{}` replaced by the arguments, in order
+pub fn trf(text: Text, args: &[&dyn Display]) -> String {
+    fill(tr(text), args)
+}
+
+fn fill(template: &str, args: &[&dyn Display]) -> String {
+    let mut parts = template.split("{}");
+    let mut out = parts.next().unwrap_or_default().to_string();
+    let mut args = args.iter();
+    for part in parts {
+        if let Some(arg) = args.next() {
+            out.push_str(&arg.to_string());
+        }
+        out.push_str(part);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translations_have_same_placeholders() {
+        for text in Text::ALL {
+            assert_eq!(
+                text.get(Locale::En).matches("{}").count(),
+                text.get(Locale::Es).matches("{}").count(),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_fill() {
+        assert_eq!(
+            fill(Text::VoteSent.get(Locale::Es), &[&"a1b2", &3]),
+            "Voto enviado para la elección a1b2 a 3 relay(s)"
+        );
+        assert_eq!(fill("{} and {}", &[&1]), "1 and ");
+        assert_eq!(Locale::from_code("ES"), Some(Locale::Es));
+        assert_eq!(Locale::from_code("fr"), None);
+    }
+}
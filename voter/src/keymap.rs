@@ -0,0 +1,264 @@
+use crate::i18n::{Text, tr};
+use crossterm::event::KeyCode;
+use std::collections::HashMap;
+
+/// Actions of the voter TUI that can be bound to keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Up,
+    Down,
+    ScrollUp,
+    ScrollDown,
+    Select,
+    Confirm,
+    Back,
+    NextArea,
+    Refresh,
+    EligibleOnly,
+    Settings,
+    Qr,
+    RankUp,
+    RankDown,
+    History,
+    Logs,
+    Help,
+    Quit,
+}
+
+impl Action {
+    pub const ALL: [Action; 18] = [
+        Action::Up,
+        Action::Down,
+        Action::ScrollUp,
+        Action::ScrollDown,
+        Action::Select,
+        Action::Confirm,
+        Action::Back,
+        Action::NextArea,
+        Action::Refresh,
+        Action::EligibleOnly,
+        Action::Settings,
+        Action::Qr,
+        Action::RankUp,
+        Action::RankDown,
+        Action::History,
+        Action::Logs,
+        Action::Help,
+        Action::Quit,
+    ];
+
+    /// Name of the action in the `[keys]` table of settings.toml
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Up => "up",
+            Action::Down => "down",
+            Action::ScrollUp => "scroll_up",
+            Action::ScrollDown => "scroll_down",
+            Action::Select => "select",
+            Action::Confirm => "confirm",
+            Action::Back => "back",
+            Action::NextArea => "next_area",
+            Action::Refresh => "refresh",
+            Action::EligibleOnly => "eligible_only",
+            Action::Settings => "settings",
+            Action::Qr => "qr",
+            Action::RankUp => "rank_up",
+            Action::RankDown => "rank_down",
+            Action::History => "history",
+            Action::Logs => "logs",
+            Action::Help => "help",
+            Action::Quit => "quit",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        tr(match self {
+            Action::Up => Text::ActionUp,
+            Action::Down => Text::ActionDown,
+            Action::ScrollUp => Text::ActionScrollUp,
+            Action::ScrollDown => Text::ActionScrollDown,
+            Action::Select => Text::ActionSelect,
+            Action::Confirm => Text::ActionConfirm,
+            Action::Back => Text::ActionBack,
+            Action::NextArea => Text::ActionNextArea,
+            Action::Refresh => Text::ActionRefresh,
+            Action::EligibleOnly => Text::ActionEligibleOnly,
+            Action::Settings => Text::ActionSettings,
+            Action::Qr => Text::ActionQr,
+            Action::RankUp => Text::ActionRankUp,
+            Action::RankDown => Text::ActionRankDown,
+            Action::History => Text::ActionHistory,
+            Action::Logs => Text::ActionLogs,
+            Action::Help => Text::ActionHelp,
+            Action::Quit => Text::ActionQuit,
+        })
+    }
+
+    fn default_keys(&self) -> &'static [&'static str] {
+        match self {
+            Action::Up => &["up", "k"],
+            Action::Down => &["down", "j"],
+            Action::ScrollUp => &["pageup"],
+            Action::ScrollDown => &["pagedown"],
+            Action::Select => &["enter"],
+            Action::Confirm => &["y"],
+            Action::Back => &["n", "backspace"],
+            Action::NextArea => &["tab"],
+            Action::Refresh => &["r"],
+            Action::EligibleOnly => &["e"],
+            Action::Settings => &["s"],
+            Action::Qr => &["c"],
+            Action::RankUp => &["+"],
+            Action::RankDown => &["-"],
+            Action::History => &["h"],
+            Action::Logs => &["l"],
+            Action::Help => &["?"],
+            Action::Quit => &["q", "esc"],
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Action> {
+        Action::ALL.into_iter().find(|a| a.name() == name)
+    }
+}
+
+/// Key bindings of the voter TUI: the defaults, with the actions
+/// listed in the `[keys]` table of settings.toml replaced.
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    bindings: Vec<(KeyCode, Action)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::from_config(&HashMap::new()).expect("Default key bindings are valid")
+    }
+}
+
+impl Keymap {
+    /// Builds the keymap from the `[keys]` table, e.g. `quit = ["q", "ctrl-c"]`.
+    pub fn from_config(overrides: &HashMap<String, Vec<String>>) -> Result<Self, String> {
+        for name in overrides.keys() {
+            if Action::from_name(name).is_none() {
+                return Err(format!("Unknown key binding action: {}", name));
+            }
+        }
+
+        let mut bindings: Vec<(KeyCode, Action)> = Vec::new();
+        for action in Action::ALL {
+            let keys: Vec<&str> = match overrides.get(action.name()) {
+                Some(keys) => keys.iter().map(String::as_str).collect(),
+                None => action.default_keys().to_vec(),
+            };
+            for key in keys {
+                let code = parse_key(key)
+                    .ok_or_else(|| format!("Invalid key {} for action {}", key, action.name()))?;
+                if let Some((_, other)) = bindings.iter().find(|(c, _)| *c == code) {
+                    return Err(format!(
+                        "Key {} is bound to both {} and {}",
+                        key,
+                        other.name(),
+                        action.name()
+                    ));
+                }
+                bindings.push((code, action));
+            }
+        }
+        Ok(Self { bindings })
+    }
+
+    /// Action bound to a key, if any
+    pub fn action(&self, code: KeyCode) -> Option<Action> {
+        self.bindings
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, action)| *action)
+    }
+
+    /// Names of the keys bound to an action, for the help overlay
+    pub fn keys(&self, action: Action) -> Vec<String> {
+        self.bindings
+            .iter()
+            .filter(|(_, a)| *a == action)
+            .map(|(code, _)| key_name(*code))
+            .collect()
+    }
+}
+
+fn parse_key(key: &str) -> Option<KeyCode> {
+    let code = match key.to_lowercase().as_str() {
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "enter" => KeyCode::Enter,
+        "esc" => KeyCode::Esc,
+        "backspace" => KeyCode::Backspace,
+        "tab" => KeyCode::Tab,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "space" => KeyCode::Char(' '),
+        _ => {
+            let mut chars = key.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => KeyCode::Char(c),
+                _ => return None,
+            }
+        }
+    };
+    Some(code)
+}
+
+fn key_name(code: KeyCode) -> String {
+    match code {
+        KeyCode::Up => "Up".into(),
+        KeyCode::Down => "Down".into(),
+        KeyCode::Left => "Left".into(),
+        KeyCode::Right => "Right".into(),
+        KeyCode::Enter => "Enter".into(),
+        KeyCode::Esc => "Esc".into(),
+        KeyCode::Backspace => "Backspace".into(),
+        KeyCode::Tab => "Tab".into(),
+        KeyCode::PageUp => "PageUp".into(),
+        KeyCode::PageDown => "PageDown".into(),
+        KeyCode::Char(' ') => "Space".into(),
+        KeyCode::Char(c) => c.to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_bindings() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.action(KeyCode::Char('q')), Some(Action::Quit));
+        assert_eq!(keymap.action(KeyCode::Esc), Some(Action::Quit));
+        assert_eq!(keymap.action(KeyCode::Char('?')), Some(Action::Help));
+        assert_eq!(keymap.action(KeyCode::Char('x')), None);
+        assert_eq!(keymap.keys(Action::Back), vec!["n", "Backspace"]);
+    }
+
+    #[test]
+    fn test_overrides_replace_defaults() {
+        let overrides = HashMap::from([("quit".to_string(), vec!["x".to_string()])]);
+        let keymap = Keymap::from_config(&overrides).unwrap();
+        assert_eq!(keymap.action(KeyCode::Char('x')), Some(Action::Quit));
+        assert_eq!(keymap.action(KeyCode::Char('q')), None);
+        assert_eq!(keymap.action(KeyCode::Up), Some(Action::Up));
+    }
+
+    #[test]
+    fn test_invalid_overrides() {
+        let unknown = HashMap::from([("jump".to_string(), vec!["x".to_string()])]);
+        assert!(Keymap::from_config(&unknown).unwrap_err().contains("Unknown"));
+
+        let invalid = HashMap::from([("quit".to_string(), vec!["ctrl-q".to_string()])]);
+        assert!(Keymap::from_config(&invalid).unwrap_err().contains("Invalid key"));
+
+        let conflict = HashMap::from([("refresh".to_string(), vec!["q".to_string()])]);
+        assert!(Keymap::from_config(&conflict).unwrap_err().contains("bound to both"));
+    }
+}
@@ -0,0 +1,44 @@
+use anyhow::Result;
+use nostr_sdk::prelude::*;
+
+/// scrypt work factor used to encrypt the key, as recommended by NIP-49.
+const LOG_N: u8 = 16;
+
+/// Whether a secret key from the settings is encrypted with NIP-49 (`ncryptsec1...`).
+pub fn is_encrypted(secret_key: &str) -> bool {
+    secret_key.starts_with("ncryptsec1")
+}
+
+/// Encrypts the secret key with a passphrase, returns it as `ncryptsec1...`.
+pub fn encrypt(keys: &Keys, passphrase: &str) -> Result<String> {
+    encrypt_with(keys, passphrase, LOG_N)
+}
+
+pub(crate) fn encrypt_with(keys: &Keys, passphrase: &str, log_n: u8) -> Result<String> {
+    let encrypted =
+        EncryptedSecretKey::new(keys.secret_key(), passphrase, log_n, KeySecurity::Unknown)?;
+    Ok(encrypted.to_bech32()?)
+}
+
+/// Decrypts a `ncryptsec1...` secret key with its passphrase.
+pub fn decrypt(ncryptsec: &str, passphrase: &str) -> Result<Keys> {
+    let encrypted = EncryptedSecretKey::from_bech32(ncryptsec)?;
+    Ok(Keys::new(encrypted.decrypt(passphrase)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let keys = Keys::generate();
+        let ncryptsec = encrypt_with(&keys, "correct horse", 4).unwrap();
+        assert!(is_encrypted(&ncryptsec));
+        assert!(!is_encrypted(&keys.secret_key().to_secret_hex()));
+
+        let decrypted = decrypt(&ncryptsec, "correct horse").unwrap();
+        assert_eq!(decrypted.public_key(), keys.public_key());
+        assert!(decrypt(&ncryptsec, "wrong horse").is_err());
+    }
+}
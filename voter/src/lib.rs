@@ -0,0 +1,27 @@
+//! Voter client of Criptocracia: elections, vote tokens, local storage and
+//! settings shared by the TUI (`voter`) and the command line client (`voter-cli`).
+
+pub mod ballot;
+pub mod election;
+pub mod history;
+pub mod i18n;
+pub mod keymap;
+pub mod keystore;
+pub mod log_buffer;
+pub mod notice;
+pub mod qr;
+pub mod receipt;
+pub mod relays;
+pub mod settings;
+pub mod signer;
+pub mod store;
+pub mod terminal;
+pub mod token;
+pub mod transfer;
+pub mod util;
+
+use settings::Settings;
+use std::sync::OnceLock;
+
+/// Constructs (or copies) the configuration file and loads it.
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
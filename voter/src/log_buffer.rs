@@ -0,0 +1,56 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Number of log records kept in memory for the Logs pane, older ones are dropped.
+const MAX_RECORDS: usize = 500;
+
+/// Formatted log records, oldest first, written by the logger next to app.log.
+static RECORDS: Mutex<VecDeque<(log::Level, String)>> = Mutex::new(VecDeque::new());
+
+/// Adds a formatted log record.
+pub fn push(level: log::Level, line: String) {
+    let Ok(mut records) = RECORDS.lock() else {
+        return;
+    };
+    if records.len() == MAX_RECORDS {
+        records.pop_front();
+    }
+    records.push_back((level, line));
+}
+
+/// Returns up to `count` records, oldest first, ending `skip` records before the newest.
+pub fn tail(count: usize, skip: usize) -> Vec<(log::Level, String)> {
+    let Ok(records) = RECORDS.lock() else {
+        return Vec::new();
+    };
+    let end = records.len().saturating_sub(skip);
+    let start = end.saturating_sub(count);
+    records.range(start..end).cloned().collect()
+}
+
+/// Number of records in memory
+pub fn len() -> usize {
+    RECORDS.lock().map_or(0, |records| records.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_is_bounded_and_tails() {
+        for i in 0..MAX_RECORDS + 10 {
+            push(log::Level::Info, format!("line {}", i));
+        }
+        push(log::Level::Error, "last".into());
+        assert_eq!(len(), MAX_RECORDS);
+
+        let newest = tail(2, 0);
+        assert_eq!(newest[0].1, format!("line {}", MAX_RECORDS + 9));
+        assert_eq!(newest[1], (log::Level::Error, "last".to_string()));
+
+        let scrolled = tail(1, 1);
+        assert_eq!(scrolled[0].1, format!("line {}", MAX_RECORDS + 9));
+        assert!(tail(5, MAX_RECORDS).is_empty());
+    }
+}
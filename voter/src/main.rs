@@ -1,56 +1,308 @@
-pub mod election;
-pub mod settings;
-pub mod util;
+use criptocracia_protocol::message::kind;
+use criptocracia_protocol::{ErrorCode, ErrorPayload};
+use voter::ballot::{Ballot, VotingMethod};
+use voter::election::{Election, Message, Status, candidate_details, upsert};
+use voter::history::{HistoryEntry, format_time};
+use voter::i18n::{Locale, Text, set_locale, tr, trf};
+use voter::keymap::{Action, Keymap};
+use voter::notice::{Level, Notices};
+use voter::qr::QrView;
+use voter::receipt::VoteReceipt;
+use voter::relays::{fetch_descriptor, fetch_event_kinds, keep_alive, relay_statuses, send_with_failover};
+use voter::settings::{Settings, SettingsForm, app_dir, init_settings, settings_file};
+use voter::signer::VoterSigner;
+use voter::store::TokenStore;
+use voter::terminal::{TerminalGuard, install_panic_hook};
+use voter::token::VoteToken;
+use voter::util::{get_ec_pubkey, gift_wrap, lock, log_level_filter, nostr_client, setup_logger};
+use voter::{keystore, log_buffer};
 
-use crate::election::{Election, Message, Status};
-use crate::settings::{Settings, init_settings};
-use crate::util::{get_ec_pubkey, setup_logger};
-
-use base64::engine::{Engine, general_purpose};
 use blind_rsa_signatures::PublicKey as RSAPublicKey;
-use blind_rsa_signatures::{BlindSignature, MessageRandomizer, Options, Secret, Signature};
 use chrono::{Duration as ChronoDuration, Utc};
-use crossterm::event::{Event as CEvent, EventStream, KeyCode, KeyEvent};
-use crossterm::execute;
-use crossterm::terminal::{
-    EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode,
-};
+use crossterm::event::{Event as CEvent, EventStream, KeyCode, KeyEvent, KeyEventKind};
 use futures::StreamExt;
 use nostr_sdk::prelude::RelayPoolNotification;
 use nostr_sdk::prelude::*;
-use num_bigint_dig::{BigUint, RandBigInt};
-use rand::rngs::OsRng;
 use ratatui::Terminal;
 use ratatui::backend::CrosstermBackend;
-use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::layout::{Constraint, Direction, Flex, Layout};
 use ratatui::style::{Color, Modifier, Style};
-use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Table};
-use sha2::{Digest, Sha256};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Cell, Clear, Paragraph, Row, Table, Wrap};
 use std::cmp::Reverse;
-use std::io::stdout;
+use std::collections::HashMap;
+use std::io::{Stdout, stdout};
+use std::net::SocketAddr;
 use std::str::FromStr;
-use std::sync::OnceLock;
 use std::sync::{Arc, Mutex};
 use tokio::time::{Duration, interval};
 
-/// Constructs (or copies) the configuration file and loads it.
-static SETTINGS: OnceLock<Settings> = OnceLock::new();
-
 // Official Mostro colors.
 const PRIMARY_COLOR: Color = Color::Rgb(3, 255, 254); // #03fffe
 const BACKGROUND_COLOR: Color = Color::Rgb(5, 35, 39); // #052327
 
+/// Elections and results asked to the relays when reloading, with no time window.
+const BACKFILL_LIMIT: usize = 500;
+
+/// Window drawn on top of the main screen.
+enum Overlay<'a> {
+    None,
+    Settings(&'a SettingsForm),
+    Help(&'a Keymap),
+    Qr(&'a QrView),
+    History,
+    Logs(usize),
+}
+
+/// Answer of the EC to an eligibility check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Eligibility {
+    Checking,
+    Eligible,
+    NotEligible,
+}
+
 #[derive(Default)]
 struct App {
-    nonce: Option<BigUint>,       // Nonce generated by the voter
-    h_n_bytes: Option<Vec<u8>>,   // Hash of the nonce
-    r: Option<MessageRandomizer>, // Randomizer used to blind the nonce
-    token: Option<Signature>,     // Blind signature received from the EC
-    secret: Option<Secret>,       // Secret used to blind the nonce
+    tokens: HashMap<String, VoteToken>, // Blind signature state per election ID
     election_id: Option<String>,
-    candidate_id: Option<u8>,
-    results: Option<Vec<(u8, u32)>>,      // Results of the election
+    ballot: Ballot,                       // Candidates marked on the ballot
+    results: HashMap<String, (u64, Vec<(u8, u32)>)>, // Latest results per election ID, with their publication time
     ec_rsa_pub_key: Option<RSAPublicKey>, // EC's RSA public key
+    content_key: Option<RSAPublicKey>,    // Key of election content, from the EC's descriptor
+    notices: Notices,                     // Messages shown in the Messages area
+    relays: Vec<(String, RelayStatus)>,   // Connection status of each relay
+    eligibility: HashMap<String, Eligibility>, // Roll check per election ID
+    eligible_only: bool,                  // Hide elections the voter can't vote in
+    detail_scroll: u16,                   // Scroll of the candidate detail pane
+    history: HashMap<String, HistoryEntry>, // Participation per election ID
+    pending_sends: usize,                 // Messages queued until a relay accepts them
+}
+
+impl App {
+    /// Whether an election is hidden by the eligibility filter.
+    /// Elections the voter already holds a token for are always shown.
+    fn is_hidden(&self, election_id: &str) -> bool {
+        self.eligible_only
+            && !self.tokens.contains_key(election_id)
+            && self.eligibility.get(election_id) == Some(&Eligibility::NotEligible)
+    }
+
+    /// Text of the Roll column of the Elections table
+    fn roll_status(&self, election_id: &str) -> &'static str {
+        if self.tokens.contains_key(election_id) {
+            return tr(Text::Yes);
+        }
+        match self.eligibility.get(election_id) {
+            Some(Eligibility::Eligible) => tr(Text::Yes),
+            Some(Eligibility::NotEligible) => tr(Text::No),
+            Some(Eligibility::Checking) => "...",
+            None => "?",
+        }
+    }
+}
+
+/// Elections shown in the Elections table, the selection index refers to this list.
+fn visible_elections<'a>(elections: &'a [Election], app: &App) -> Vec<&'a Election> {
+    elections.iter().filter(|e| !app.is_hidden(&e.id)).collect()
+}
+
+/// Election highlighted in the Elections table.
+/// Locks the app before the elections, like the drawing code.
+fn selected_election(
+    elections: &Mutex<Vec<Election>>,
+    app: &Mutex<App>,
+    selected_election_idx: usize,
+) -> Option<Election> {
+    let app = lock(app);
+    let elections = lock(elections);
+    visible_elections(&elections, &app)
+        .get(selected_election_idx)
+        .map(|e| (*e).clone())
+}
+
+/// Updates the history entry of an election and saves it.
+async fn update_history(
+    store: &TokenStore,
+    app: &Mutex<App>,
+    election_id: &str,
+    update: impl FnOnce(&mut HistoryEntry),
+) {
+    let entry = {
+        let mut app = lock(app);
+        let entry = app
+            .history
+            .entry(election_id.to_string())
+            .or_insert_with(|| HistoryEntry::new(election_id));
+        update(entry);
+        entry.clone()
+    }; // Mutex guard is dropped here
+    if let Err(e) = store.save_history(&entry).await {
+        lock(app).notices.error(trf(Text::HistorySaveFailed, &[&e]));
+    }
+}
+
+/// Builds the content of the Ballot area: the marked candidates,
+/// the token status and the confirmation prompt.
+fn ballot_text(app: &App, elections: &[Election]) -> String {
+    let Some(election_id) = app.election_id.as_ref().filter(|_| !app.ballot.is_empty()) else {
+        return tr(Text::BallotEmpty).into();
+    };
+    let election = elections.iter().find(|e| &e.id == election_id);
+    let election_name = election.map(Election::display_name).unwrap_or_else(|| tr(Text::Unknown).to_string());
+    let choices: Vec<String> = app
+        .ballot
+        .choices
+        .iter()
+        .enumerate()
+        .map(|(i, candidate_id)| {
+            let name = election
+                .and_then(|e| e.candidates.iter().find(|c| c.id == *candidate_id))
+                .map(|c| c.name.as_str())
+                .unwrap_or(tr(Text::Unknown));
+            match app.ballot.method {
+                VotingMethod::Ranked => format!("{}. {} - {}", i + 1, candidate_id, name),
+                _ => format!("{} - {}", candidate_id, name),
+            }
+        })
+        .collect();
+    let choices = trf(
+        match app.ballot.method {
+            VotingMethod::Plurality => Text::BallotCandidate,
+            VotingMethod::Approval => Text::BallotApproved,
+            VotingMethod::Ranked => Text::BallotRanking,
+        },
+        &[&choices.join(", ")],
+    );
+
+    let token = app.tokens.get(election_id);
+    let token_received = token.is_some_and(|t| t.token.is_some());
+    let vote_sent = token.is_some_and(|t| t.vote_sent);
+    let token_status = if token_received {
+        tr(Text::TokenReceived)
+    } else if token.is_some() {
+        tr(Text::TokenRequested)
+    } else {
+        tr(Text::TokenNotRequested)
+    };
+
+    let receipt_status = match token.and_then(|t| t.receipt.as_ref()) {
+        Some(_) => format!("\n{}", tr(Text::ReceiptVerified)),
+        None if vote_sent => format!("\n{}", tr(Text::ReceiptWaiting)),
+        None => String::new(),
+    };
+
+    let prompt = if vote_sent {
+        tr(Text::PromptVoteSent)
+    } else if token_received {
+        tr(Text::PromptConfirm)
+    } else {
+        tr(Text::PromptWaitToken)
+    };
+
+    format!(
+        "{}{}\n\n{}",
+        trf(
+            Text::BallotText,
+            &[&election_name, election_id, &choices, &token_status]
+        ),
+        receipt_status,
+        prompt
+    )
+}
+
+/// How a message to the EC left the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Delivery {
+    /// Accepted by this number of relays
+    Sent(usize),
+    /// No relay accepted it, it is kept in the outbox and retried
+    Queued,
+}
+
+/// Gift wraps a vote with throwaway keys, so it can't be linked to the voter, and sends it.
+async fn send_vote(
+    client: &Client,
+    store: &TokenStore,
+    vote_keys: &Keys,
+    ec_pubkey: &PublicKey,
+    pow: u8,
+    election_id: String,
+    vote_payload: String,
+) -> Result<Delivery, anyhow::Error> {
+    // The acknowledgment comes back to the throwaway keys
+    let message = Message::new_with_election(
+        format!("vote_{}", chrono::Utc::now().timestamp()),
+        kind::VOTE,
+        vote_payload,
+        election_id,
+    )
+    .with_reply_to(vote_keys.public_key().to_hex());
+    let delivery = send_to_ec(client, store, vote_keys, ec_pubkey, pow, &message).await?;
+
+    log::info!("Vote sent!");
+    Ok(delivery)
+}
+
+/// Keys other than the voter's the EC answers to: those each vote was cast
+/// with, for the receipts, and those the pending anonymous token requests
+/// were sent from, for the tokens.
+fn reply_keys(tokens: &HashMap<String, VoteToken>) -> Vec<(String, Keys)> {
+    tokens
+        .iter()
+        .flat_map(|(id, t)| {
+            let request_keys = match t.token {
+                None => t.request_keys().ok(),
+                Some(_) => None,
+            };
+            t.vote_keys.clone().into_iter().chain(request_keys).map(move |k| (id.clone(), k))
+        })
+        .collect()
+}
+
+/// Gift wraps a message to the EC from the given signer and sends it.
+/// If no relay accepts it, the gift wrap is queued in the outbox to be sent later.
+async fn send_to_ec<T>(
+    client: &Client,
+    store: &TokenStore,
+    signer: &T,
+    ec_pubkey: &PublicKey,
+    pow: u8,
+    message: &Message,
+) -> Result<Delivery, anyhow::Error>
+where
+    T: NostrSigner,
+{
+    let message_json = serde_json::to_string(message)?;
+    log::info!("Message to the EC: {}", message_json);
+    // Creates a "rumor" with the message.
+    let rumor: UnsignedEvent = EventBuilder::text_note(message_json).build(signer.get_public_key().await?);
+
+    // Wraps the rumor in a Gift Wrap.
+    let gift_wrap: Event = gift_wrap(signer, ec_pubkey, rumor, pow).await?;
+
+    // Send the Gift Wrap
+    match send_with_failover(client, &gift_wrap).await {
+        Ok(relays) => Ok(Delivery::Sent(relays.len())),
+        Err(e) => {
+            log::warn!("Message {} queued: {}", message.id, e);
+            store.queue_event(&gift_wrap).await?;
+            Ok(Delivery::Queued)
+        }
+    }
+}
+
+/// Notice shown when the EC rejects a message, by error code
+fn rejection_text(code: ErrorCode) -> Text {
+    match code {
+        ErrorCode::Unauthorized => Text::RejectedUnauthorized,
+        ErrorCode::Duplicate => Text::RejectedDuplicate,
+        ErrorCode::ElectionClosed => Text::RejectedElectionClosed,
+        ErrorCode::UnknownElection => Text::RejectedUnknownElection,
+        ErrorCode::BadFormat => Text::RejectedBadFormat,
+        ErrorCode::Expired => Text::RejectedExpired,
+        ErrorCode::Internal => Text::RejectedInternal,
+    }
 }
 
 /// Draws the TUI interface with tabs and active content.
@@ -62,54 +314,67 @@ fn ui_draw(
     app: &Arc<Mutex<App>>,
     selected_election_idx: usize,
     selected_candidate_idx: usize,
+    overlay: Overlay,
 ) {
-    let app = app.lock().unwrap();
-    let ballot_text = if let (Some(eid), Some(cid)) = (&app.election_id, app.candidate_id) {
-        format!("Election: {}, Candidate voted: {}", eid, cid)
-    } else {
-        "No vote yet".into()
-    };
-    let results_text = if let Some(results) = &app.results {
+    let app = lock(app);
+    let election_results = app.election_id.as_ref().and_then(|id| app.results.get(id));
+    let results_text = if let Some((_, results)) = election_results {
         results
             .iter()
-            .map(|(id, votes)| format!("Candidate {}: {} votes", id, votes))
+            .map(|(id, votes)| trf(Text::CandidateVotes, &[id, votes]))
             .collect::<Vec<_>>()
             .join("\n")
     } else {
-        "No results yet".into()
+        tr(Text::NoResults).into()
     };
     let chunks = Layout::new(
         Direction::Vertical,
-        [30, 40, 30].map(Constraint::Percentage),
+        [
+            Constraint::Percentage(30),
+            Constraint::Percentage(30),
+            Constraint::Min(8),
+            Constraint::Length(7),
+        ],
     )
     .split(f.area());
 
     // === AREA 0: Elections ===
     let header = Row::new(
-        ["Id", "Name", "Status", "Starts"]
+        [
+            Text::ColumnId,
+            Text::ColumnName,
+            Text::ColumnStatus,
+            Text::ColumnStarts,
+            Text::ColumnCountdown,
+            Text::ColumnRoll,
+        ]
             .iter()
-            .map(|h| Cell::from(*h))
+            .map(|h| Cell::from(tr(*h)))
             .collect::<Vec<_>>(),
     )
     .style(Style::default().add_modifier(Modifier::BOLD));
 
-    let elections_lock = elections.lock().unwrap();
-    let mut rows = Vec::with_capacity(elections_lock.len());
-    for (i, e) in elections_lock.iter().enumerate() {
+    let now = Utc::now().timestamp().max(0) as u64;
+    let elections_lock = lock(elections);
+    let visible = visible_elections(&elections_lock, &app);
+    let mut rows = Vec::with_capacity(visible.len());
+    for (i, e) in visible.iter().enumerate() {
         let mut row = Row::new(vec![
             Cell::from(e.id.to_string()),
-            Cell::from(e.name.clone()),
-            Cell::from(match e.status {
-                Status::Open => "Open",
-                Status::InProgress => "In Progress",
-                Status::Finished => "Finished",
-                Status::Canceled => "Canceled",
-            }),
+            Cell::from(e.display_name()),
+            Cell::from(tr(match e.current_status(now) {
+                Status::Open => Text::StatusOpen,
+                Status::InProgress => Text::StatusInProgress,
+                Status::Finished => Text::StatusFinished,
+                Status::Canceled => Text::StatusCanceled,
+            })),
             Cell::from(
                 chrono::DateTime::from_timestamp(e.start_time as i64, 0)
                     .map(|dt| dt.format("%Y-%m-%d %H:%M").to_string())
-                    .unwrap_or_else(|| "Invalid".into()),
+                    .unwrap_or_else(|| tr(Text::Invalid).into()),
             ),
+            Cell::from(e.countdown(now)),
+            Cell::from(app.roll_status(&e.id)),
         ]);
         if active_area == 0 && i == selected_election_idx {
             row = row.style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black));
@@ -118,7 +383,11 @@ fn ui_draw(
     }
 
     let mut block_e = Block::default()
-        .title("Elections")
+        .title(tr(if app.eligible_only {
+            Text::ElectionsEligibleOnly
+        } else {
+            Text::Elections
+        }))
         .borders(Borders::ALL)
         .border_type(ratatui::widgets::BorderType::Rounded)
         .style(Style::default().bg(BACKGROUND_COLOR));
@@ -135,6 +404,8 @@ fn ui_draw(
             Constraint::Min(10),
             Constraint::Length(12),
             Constraint::Length(18),
+            Constraint::Length(16),
+            Constraint::Length(5),
         ],
     )
     .header(header)
@@ -144,11 +415,15 @@ fn ui_draw(
     // === AREA 1: Candidates ===
     // If a valid election is selected, display its candidates:
     let mut cand_rows = Vec::new();
-    if let Some(e) = elections_lock.get(selected_election_idx) {
+    if let Some(e) = visible.get(selected_election_idx) {
+        // The ballot belongs to the election chosen with Enter
+        let on_ballot = app.election_id.as_ref() == Some(&e.id);
         for (i, c) in e.candidates.iter().enumerate() {
+            let mark = if on_ballot { app.ballot.mark(c.id) } else { String::new() };
             let mut row = Row::new(vec![
                 Cell::from(c.id.to_string()),
                 Cell::from(c.name.clone()),
+                Cell::from(mark),
             ]);
             if active_area == 1 && i == selected_candidate_idx {
                 row = row.style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black));
@@ -158,7 +433,7 @@ fn ui_draw(
     }
 
     let mut block_c = Block::default()
-        .title("Candidates")
+        .title(tr(Text::Candidates))
         .borders(Borders::ALL)
         .border_type(ratatui::widgets::BorderType::Rounded)
         .style(Style::default().bg(BACKGROUND_COLOR));
@@ -168,18 +443,38 @@ fn ui_draw(
             .border_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black));
     }
 
-    let table_c = Table::new(cand_rows, &[Constraint::Length(5), Constraint::Min(10)])
+    let widths_c = [Constraint::Length(5), Constraint::Min(10), Constraint::Length(6)];
+    let table_c = Table::new(cand_rows, widths_c)
         .header(
             Row::new(
-                ["Id", "Name"]
+                [Text::ColumnId, Text::ColumnName, Text::ColumnChoice]
                     .iter()
-                    .map(|h| Cell::from(*h))
+                    .map(|h| Cell::from(tr(*h)))
                     .collect::<Vec<_>>(),
             )
             .style(Style::default().add_modifier(Modifier::BOLD)),
         )
         .block(block_c);
-    f.render_widget(table_c, chunks[1]);
+    let candidate_layout = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(chunks[1]);
+    f.render_widget(table_c, candidate_layout[0]);
+
+    // === Candidate details ===
+    let details = visible
+        .get(selected_election_idx)
+        .and_then(|e| e.candidates.get(selected_candidate_idx))
+        .map(candidate_details)
+        .unwrap_or_default();
+    let block_d = Block::default()
+        .title(tr(Text::Candidate))
+        .borders(Borders::ALL)
+        .border_type(ratatui::widgets::BorderType::Rounded)
+        .style(Style::default().bg(BACKGROUND_COLOR));
+    let paragraph = Paragraph::new(details)
+        .block(block_d)
+        .wrap(Wrap { trim: false })
+        .scroll((app.detail_scroll, 0));
+    f.render_widget(paragraph, candidate_layout[1]);
 
     let bottom_layout = Layout::default()
         .direction(Direction::Horizontal)
@@ -187,36 +482,445 @@ fn ui_draw(
         .split(chunks[2]);
 
     // === AREA 2: Ballot ===
-    let block_b = Block::default()
-        .title("Ballot")
+    let mut block_b = Block::default()
+        .title(tr(Text::Ballot))
         .borders(Borders::ALL)
         .border_type(ratatui::widgets::BorderType::Rounded)
         .style(Style::default().bg(BACKGROUND_COLOR));
+    if active_area == 2 {
+        block_b = block_b
+            .title_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black))
+            .border_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black));
+    }
 
-    let paragraph = Paragraph::new(ballot_text).block(block_b);
+    let paragraph = Paragraph::new(ballot_text(&app, &elections_lock)).block(block_b);
     f.render_widget(paragraph, bottom_layout[0]);
 
     let block_r = Block::default()
-        .title("Results")
+        .title(tr(Text::Results))
         .borders(Borders::ALL)
         .border_type(ratatui::widgets::BorderType::Rounded)
         .style(Style::default().bg(BACKGROUND_COLOR));
 
     let paragraph = Paragraph::new(results_text).block(block_r);
     f.render_widget(paragraph, bottom_layout[1]);
+
+    // === Messages ===
+    let lines: Vec<Line> = app
+        .notices
+        .latest()
+        .map(|n| {
+            let color = match n.level {
+                Level::Info => PRIMARY_COLOR,
+                Level::Success => Color::Green,
+                Level::Error => Color::Red,
+            };
+            Line::from(vec![
+                Span::raw(format!("{} ", n.time.format("%H:%M:%S"))),
+                Span::styled(n.text.clone(), Style::default().fg(color)),
+            ])
+        })
+        .collect();
+    let block_m = Block::default()
+        .title(tr(Text::Messages))
+        .borders(Borders::ALL)
+        .border_type(ratatui::widgets::BorderType::Rounded)
+        .style(Style::default().bg(BACKGROUND_COLOR));
+    let status_layout = Layout::horizontal([Constraint::Percentage(65), Constraint::Percentage(35)])
+        .split(chunks[3]);
+    f.render_widget(Paragraph::new(lines).block(block_m), status_layout[0]);
+
+    // === Relays ===
+    let relay_lines: Vec<Line> = app
+        .relays
+        .iter()
+        .map(|(url, status)| {
+            let color = match status {
+                RelayStatus::Connected => Color::Green,
+                RelayStatus::Initialized | RelayStatus::Pending | RelayStatus::Connecting => {
+                    Color::Yellow
+                }
+                _ => Color::Red,
+            };
+            Line::from(vec![
+                Span::styled("● ", Style::default().fg(color)),
+                Span::raw(format!("{} ({})", url, status)),
+            ])
+        })
+        .collect();
+    let connected = app
+        .relays
+        .iter()
+        .filter(|(_, status)| *status == RelayStatus::Connected)
+        .count();
+    let mut relays_title = trf(Text::Relays, &[&connected, &app.relays.len()]);
+    if app.pending_sends > 0 {
+        relays_title.push_str(&trf(Text::PendingSends, &[&app.pending_sends]));
+    }
+    let block_relays = Block::default()
+        .title(relays_title)
+        .borders(Borders::ALL)
+        .border_type(ratatui::widgets::BorderType::Rounded)
+        .style(Style::default().bg(BACKGROUND_COLOR));
+    f.render_widget(Paragraph::new(relay_lines).block(block_relays), status_layout[1]);
+
+    match overlay {
+        Overlay::Settings(form) => draw_settings(f, form),
+        Overlay::Help(keymap) => draw_help(f, keymap),
+        Overlay::Qr(view) => draw_qr(f, view),
+        Overlay::History => draw_history(f, &app),
+        Overlay::Logs(scroll) => draw_logs(f, scroll),
+        Overlay::None => {}
+    }
+}
+
+/// Area of the given height centered on the screen.
+fn centered_area(f: &ratatui::Frame, height: u16) -> ratatui::layout::Rect {
+    let [area] = Layout::vertical([Constraint::Length(height)])
+        .flex(Flex::Center)
+        .areas(f.area());
+    let [area] = Layout::horizontal([Constraint::Percentage(80)])
+        .flex(Flex::Center)
+        .areas(area);
+    area
+}
+
+/// Draws a QR code on top of the main screen, dark modules on a light background.
+fn draw_qr(f: &mut ratatui::Frame, view: &QrView) {
+    let screen = f.area();
+    let width = view.width() as u16 + 2;
+    let height = view.lines.len() as u16 + 2;
+    let block = Block::default()
+        .title(view.title.as_str())
+        .borders(Borders::ALL)
+        .title_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black))
+        .border_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black));
+
+    if width > screen.width || height > screen.height {
+        let area = centered_area(f, 3);
+        f.render_widget(Clear, area);
+        f.render_widget(
+            Paragraph::new(tr(Text::QrTooSmall))
+                .block(block)
+                .style(Style::default().bg(BACKGROUND_COLOR)),
+            area,
+        );
+        return;
+    }
+
+    let area = ratatui::layout::Rect::new(
+        screen.x + (screen.width - width) / 2,
+        screen.y + (screen.height - height) / 2,
+        width,
+        height,
+    );
+    let lines: Vec<Line> = view.lines.iter().map(|l| Line::from(l.as_str())).collect();
+    f.render_widget(Clear, area);
+    f.render_widget(
+        Paragraph::new(lines)
+            .block(block)
+            .style(Style::default().bg(Color::White).fg(Color::Black)),
+        area,
+    );
+}
+
+/// Asks for a passphrase in a masked input box. Returns `None` if the voter presses Esc.
+async fn read_passphrase(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    events: &mut EventStream,
+    prompt: &str,
+    error: Option<&str>,
+) -> Result<Option<String>, anyhow::Error> {
+    let mut passphrase = String::new();
+    loop {
+        terminal.draw(|f| {
+            let area = centered_area(f, 9);
+            let mut lines = vec![
+                Line::from(prompt),
+                Line::from(""),
+                Line::from(Span::styled(
+                    format!("{}_", "*".repeat(passphrase.chars().count())),
+                    Style::default().bg(PRIMARY_COLOR).fg(Color::Black),
+                )),
+            ];
+            if let Some(error) = error {
+                lines.push(Line::from(Span::styled(error, Style::default().fg(Color::Red))));
+            }
+            let block = Block::default()
+                .title(tr(Text::Passphrase))
+                .borders(Borders::ALL)
+                .border_type(ratatui::widgets::BorderType::Rounded)
+                .style(Style::default().bg(BACKGROUND_COLOR));
+            f.render_widget(Clear, area);
+            f.render_widget(Paragraph::new(lines).block(block).wrap(Wrap { trim: false }), area);
+        })?;
+
+        match events.next().await {
+            Some(Ok(CEvent::Key(KeyEvent { code, kind: KeyEventKind::Press, .. }))) => match code {
+                KeyCode::Enter => return Ok(Some(passphrase)),
+                KeyCode::Esc => return Ok(None),
+                KeyCode::Backspace => {
+                    passphrase.pop();
+                }
+                KeyCode::Char(c) => passphrase.push(c),
+                _ => {}
+            },
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e.into()),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Connects to the remote signer of the settings, showing a message while
+/// the voter approves the connection on the signing device.
+async fn connect_remote_signer(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    bunker: &str,
+    proxy: Option<SocketAddr>,
+) -> Result<VoterSigner, anyhow::Error> {
+    terminal.draw(|f| {
+        let area = centered_area(f, 7);
+        let block = Block::default()
+            .title(tr(Text::RemoteSigner))
+            .borders(Borders::ALL)
+            .border_type(ratatui::widgets::BorderType::Rounded)
+            .style(Style::default().bg(BACKGROUND_COLOR));
+        f.render_widget(Clear, area);
+        f.render_widget(
+            Paragraph::new(tr(Text::RemoteSignerWaiting)).block(block).wrap(Wrap { trim: false }),
+            area,
+        );
+    })?;
+    VoterSigner::remote(bunker, proxy, |url| log::warn!("Approve the remote signer request at {}", url)).await
+}
+
+/// Unlocks the voter's keys. A key encrypted with NIP-49 asks for its passphrase,
+/// a plaintext key is offered to be encrypted and saved back to the settings file.
+/// Returns `None` if the voter gives up.
+async fn unlock_keys(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    events: &mut EventStream,
+    settings: &mut Settings,
+) -> Result<Option<Keys>, anyhow::Error> {
+    if keystore::is_encrypted(&settings.secret_key) {
+        let mut error = None;
+        loop {
+            let Some(passphrase) =
+                read_passphrase(terminal, events, tr(Text::EnterPassphrase), error).await?
+            else {
+                return Ok(None);
+            };
+            match keystore::decrypt(&settings.secret_key, &passphrase) {
+                Ok(keys) => return Ok(Some(keys)),
+                Err(e) => {
+                    log::warn!("Failed to decrypt the secret key: {}", e);
+                    error = Some(tr(Text::WrongPassphrase));
+                }
+            }
+        }
+    }
+
+    // Migration from a plaintext key: empty passphrase or Esc keeps it as is
+    let keys = Keys::parse(&settings.secret_key)?;
+    let mut error = None;
+    loop {
+        let passphrase = match read_passphrase(terminal, events, tr(Text::ChoosePassphrase), error).await? {
+            Some(p) if !p.is_empty() => p,
+            _ => return Ok(Some(keys)),
+        };
+        let Some(confirmation) = read_passphrase(terminal, events, tr(Text::ConfirmPassphrase), None).await? else {
+            return Ok(Some(keys));
+        };
+        if passphrase != confirmation {
+            error = Some(tr(Text::PassphraseMismatch));
+            continue;
+        }
+        settings.secret_key = keystore::encrypt(&keys, &passphrase)?;
+        settings.save(&settings_file())?;
+        log::info!("Secret key encrypted with NIP-49 in {}", settings_file().display());
+        return Ok(Some(keys));
+    }
+}
+
+/// Draws the list of key bindings on top of the main screen.
+fn draw_help(f: &mut ratatui::Frame, keymap: &Keymap) {
+    let rows: Vec<Row> = Action::ALL
+        .iter()
+        .map(|action| {
+            Row::new(vec![
+                Cell::from(keymap.keys(*action).join(", ")),
+                Cell::from(action.description()),
+            ])
+        })
+        .collect();
+    let area = centered_area(f, rows.len() as u16 + 4);
+
+    let block = Block::default()
+        .title(tr(Text::HelpTitle))
+        .borders(Borders::ALL)
+        .border_type(ratatui::widgets::BorderType::Rounded)
+        .title_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black))
+        .border_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black))
+        .style(Style::default().bg(BACKGROUND_COLOR));
+    let table = Table::new(rows, &[Constraint::Length(20), Constraint::Min(10)])
+        .header(
+            Row::new(vec![Cell::from(tr(Text::ColumnKeys)), Cell::from(tr(Text::ColumnAction))])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(block);
+    f.render_widget(Clear, area);
+    f.render_widget(table, area);
+}
+
+/// Draws the elections the voter took part in, newest first, with the next step in each.
+/// Elections with a stored token but no history, from older versions, are listed too.
+fn draw_history(f: &mut ratatui::Frame, app: &App) {
+    let mut entries: Vec<HistoryEntry> = app.history.values().cloned().collect();
+    for election_id in app.tokens.keys() {
+        if !app.history.contains_key(election_id) {
+            entries.push(HistoryEntry::new(election_id));
+        }
+    }
+    entries.sort_by_key(|e| Reverse(e.token_requested_at));
+
+    let rows: Vec<Row> = entries
+        .iter()
+        .map(|e| {
+            Row::new(vec![
+                Cell::from(e.election_id.clone()),
+                Cell::from(e.election_name.clone().unwrap_or_else(|| tr(Text::Unknown).into())),
+                Cell::from(format_time(e.token_requested_at)),
+                Cell::from(format_time(e.token_received_at)),
+                Cell::from(format_time(e.voted_at)),
+                Cell::from(if e.choices.is_empty() {
+                    "-".to_string()
+                } else {
+                    e.choices.iter().map(u8::to_string).collect::<Vec<_>>().join(",")
+                }),
+                Cell::from(e.pending_action(app.tokens.get(&e.election_id))),
+            ])
+        })
+        .collect();
+    let area = centered_area(f, rows.len().max(1) as u16 + 4);
+
+    let block = Block::default()
+        .title(tr(Text::HistoryTitle))
+        .borders(Borders::ALL)
+        .border_type(ratatui::widgets::BorderType::Rounded)
+        .title_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black))
+        .border_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black))
+        .style(Style::default().bg(BACKGROUND_COLOR));
+    f.render_widget(Clear, area);
+    if rows.is_empty() {
+        f.render_widget(Paragraph::new(tr(Text::HistoryEmpty)).block(block), area);
+        return;
+    }
+    let header = [
+        Text::ColumnId,
+        Text::ColumnName,
+        Text::ColumnTokenRequested,
+        Text::ColumnTokenReceived,
+        Text::ColumnVoted,
+        Text::Candidate,
+        Text::ColumnPending,
+    ];
+    let widths = [
+        Constraint::Length(6),
+        Constraint::Min(10),
+        Constraint::Length(16),
+        Constraint::Length(16),
+        Constraint::Length(16),
+        Constraint::Length(9),
+        Constraint::Length(20),
+    ];
+    let table = Table::new(rows, widths)
+        .header(
+            Row::new(header.into_iter().map(|t| Cell::from(tr(t))))
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(block);
+    f.render_widget(table, area);
+}
+
+/// Draws the newest log records that fit on the screen, `scroll` records back from the end.
+fn draw_logs(f: &mut ratatui::Frame, scroll: usize) {
+    let area = centered_area(f, f.area().height.saturating_sub(2));
+    let count = area.height.saturating_sub(2) as usize;
+    let total = log_buffer::len();
+    let lines: Vec<Line> = log_buffer::tail(count, scroll)
+        .into_iter()
+        .map(|(level, line)| {
+            let color = match level {
+                log::Level::Error => Color::Red,
+                log::Level::Warn => Color::Yellow,
+                log::Level::Info => Color::White,
+                log::Level::Debug | log::Level::Trace => Color::DarkGray,
+            };
+            Line::from(Span::styled(line, Style::default().fg(color)))
+        })
+        .collect();
+
+    let block = Block::default()
+        .title(trf(Text::LogsTitle, &[&total.saturating_sub(scroll), &total]))
+        .borders(Borders::ALL)
+        .border_type(ratatui::widgets::BorderType::Rounded)
+        .title_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black))
+        .border_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black))
+        .style(Style::default().bg(BACKGROUND_COLOR));
+    f.render_widget(Clear, area);
+    if lines.is_empty() {
+        f.render_widget(Paragraph::new(tr(Text::LogsEmpty)).block(block), area);
+        return;
+    }
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// Draws the settings editor on top of the main screen.
+fn draw_settings(f: &mut ratatui::Frame, form: &SettingsForm) {
+    let area = centered_area(f, form.fields.len() as u16 * 2 + 4);
+
+    let mut lines = Vec::new();
+    for (i, (label, value)) in form.fields.iter().enumerate() {
+        lines.push(Line::from(Span::styled(
+            *label,
+            Style::default().add_modifier(Modifier::BOLD),
+        )));
+        if i == form.selected {
+            lines.push(Line::from(Span::styled(
+                format!("{}_", value),
+                Style::default().bg(PRIMARY_COLOR).fg(Color::Black),
+            )));
+        } else {
+            lines.push(Line::from(value.as_str()));
+        }
+    }
+    lines.push(Line::from(""));
+    lines.push(Line::from(tr(Text::SettingsHelp)));
+
+    let block = Block::default()
+        .title(tr(Text::Settings))
+        .borders(Borders::ALL)
+        .border_type(ratatui::widgets::BorderType::Rounded)
+        .title_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black))
+        .border_style(Style::default().bg(PRIMARY_COLOR).fg(Color::Black))
+        .style(Style::default().bg(BACKGROUND_COLOR));
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).block(block), area);
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let settings = init_settings();
+    let settings = init_settings()?;
     // Initialize logger
-    setup_logger(&settings.log_level).expect("Can't initialize logger");
+    setup_logger(&settings.log_level, settings.log_rotation)?;
+    install_panic_hook();
+    set_locale(Locale::from_code(&settings.language).unwrap_or(Locale::En));
     log::info!("Criptocracia started");
-    // Set the terminal in raw mode and switch to the alternate screen.
-    enable_raw_mode()?;
-    let mut stdout = stdout();
-    execute!(stdout, EnterAlternateScreen)?;
-    let backend = CrosstermBackend::new(stdout);
+    // Set the terminal in raw mode and switch to the alternate screen,
+    // the guard restores it on every way out of main.
+    let _terminal_guard = TerminalGuard::enter()?;
+    let backend = CrosstermBackend::new(stdout());
     let mut terminal = Terminal::new(backend)?;
 
     // Shared state: elections are stored in memory.
@@ -225,17 +929,66 @@ async fn main() -> Result<(), anyhow::Error> {
     let mut active_area = 0; // 0 = Elections, 1 = Candidates, 2 = Ballot
     let mut selected_election_idx: usize = 0;
     let mut selected_candidate_idx: usize = 0;
+    // Settings editor, open while Some
+    let mut settings_form: Option<SettingsForm> = None;
+    let mut current_settings = settings.clone();
+    let mut show_help = false;
+    let mut show_history = false;
+    // Log pane, open while Some, with the number of records scrolled back
+    let mut log_scroll: Option<usize> = None;
+    // QR codes that can be shown and the one on screen
+    let mut qr_views: Vec<QrView> = Vec::new();
+    let mut qr_index: Option<usize> = None;
+    let keymap = Keymap::from_config(&settings.keys).unwrap_or_else(|e| {
+        lock(&app).notices.error(trf(Text::KeymapInvalid, &[&e]));
+        Keymap::default()
+    });
+    let mut events = EventStream::new();
+
+    // Configure Nostr client, with the local key or a remote signer holding it.
+    let voter_signer = match &settings.bunker {
+        Some(bunker) => connect_remote_signer(&mut terminal, bunker, settings.proxy_addr()).await?,
+        None => {
+            let Some(keys) = unlock_keys(&mut terminal, &mut events, &mut current_settings).await? else {
+                return Ok(());
+            };
+            VoterSigner::local(keys)
+        }
+    };
+    let my_signer = voter_signer.signer.clone();
+    let voter_keys = my_signer.clone();
+    // Anonymous token requests are signed with the key itself
+    let local_keys = voter_signer.local_keys().cloned();
 
-    // Configure Nostr client.
-    let my_keys = Keys::parse(&settings.secret_key)?;
-    let client = Client::new(my_keys.clone());
-    // Add the Mostro relay.
-    client.add_relay("wss://relay.mostro.network").await?;
+    // Restore the tokens of previous sessions
+    let token_store = Arc::new(TokenStore::open(&app_dir().join("voter.db"), voter_signer.store_keys.clone()).await?);
+    lock(&app).tokens = token_store.load_all().await?;
+    lock(&app).history = token_store.load_history().await?;
+    let client = nostr_client(Some(my_signer.clone()), settings.proxy_addr());
+    // Add the configured relays, events are sent to all of them.
+    if settings.relays.is_empty() {
+        return Err(anyhow::anyhow!("No relays configured in settings.toml"));
+    }
+    for relay in &settings.relays {
+        client.add_relay(relay).await?;
+    }
     client.connect().await;
 
     // EC Pubkey.
     let ec_pubkey = PublicKey::from_str(settings.ec_public_key.as_str())
         .map_err(|e| anyhow::anyhow!("Invalid EC pubkey: {}", e))?;
+    let pow = settings.pow;
+    // Kinds of the EC's events, when it doesn't use the default ones
+    let kinds = fetch_event_kinds(&client, &ec_pubkey).await;
+    // Key election content is signed with, pinned from the EC's descriptor
+    let content_key = fetch_descriptor(&client, &ec_pubkey).await.and_then(|d| d.content_key);
+    lock(&app).content_key = content_key.and_then(|key| match get_ec_pubkey(&key) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            log::error!("Failed to parse the content key of the EC: {}", e);
+            None
+        }
+    });
 
     // Calculate timestamp for events in the last two day.
     let since_time = Utc::now()
@@ -246,7 +999,7 @@ async fn main() -> Result<(), anyhow::Error> {
 
     // Build the filter for to get Elections events from the Electoral Commission.
     let filter = Filter::new()
-        .kinds([Kind::Custom(35_000), Kind::Custom(35_001)])
+        .kinds([Kind::Custom(kinds.election), Kind::Custom(kinds.results)])
         .author(ec_pubkey)
         .limit(20)
         .since(timestamp);
@@ -257,16 +1010,36 @@ async fn main() -> Result<(), anyhow::Error> {
     // Build the filter for NIP-59 events from the Electoral commission.
     let filter = Filter::new()
         .kind(Kind::GiftWrap)
-        .pubkey(my_keys.public_key())
+        .pubkey(voter_signer.public_key)
         .limit(20)
         .since(timestamp);
     client.subscribe(filter, None).await?;
 
+    // Receipts and anonymous tokens are sent to throwaway keys
+    let vote_pubkeys: Vec<PublicKey> = reply_keys(&lock(&app).tokens)
+        .into_iter()
+        .map(|(_, k)| k.public_key())
+        .collect();
+    if !vote_pubkeys.is_empty() {
+        let filter = Filter::new()
+            .kind(Kind::GiftWrap)
+            .pubkeys(vote_pubkeys)
+            .since(timestamp);
+        client.subscribe(filter, None).await?;
+    }
+
     let cloned_client = client.clone();
 
+    // Reconnect with backoff while the relays are down and send the queued messages
+    let app_clone = Arc::clone(&app);
+    tokio::spawn(keep_alive(client.clone(), Arc::clone(&token_store), move |pending| {
+        lock(&app_clone).pending_sends = pending;
+    }));
+
     // Asynchronous task to handle incoming notifications.
     let elections_clone = Arc::clone(&elections);
     let app_clone = Arc::clone(&app);
+    let store_clone = Arc::clone(&token_store);
     tokio::spawn(async move {
         let mut notifications = client.notifications();
         while let Ok(n) = notifications.recv().await {
@@ -277,12 +1050,20 @@ async fn main() -> Result<(), anyhow::Error> {
                         log::warn!("Invalid event signature: {}", event.id);
                         continue;
                     }
-                    let event = match nip59::extract_rumor(&my_keys, &event).await {
-                        Ok(u) => u,
-                        Err(_) => {
-                            log::warn!("Error unwrapping gift");
-                            continue;
+                    // Messages to the voter's key, or to the throwaway keys of a vote or anonymous request
+                    let vote_keys = reply_keys(&lock(&app_clone).tokens);
+                    let mut unwrapped = nip59::extract_rumor(&my_signer, &event).await.ok().map(|u| (u, None));
+                    for (election_id, keys) in vote_keys {
+                        if unwrapped.is_some() {
+                            break;
+                        }
+                        if let Ok(u) = nip59::extract_rumor(&keys, &event).await {
+                            unwrapped = Some((u, Some(election_id)));
                         }
+                    }
+                    let Some((event, vote_election)) = unwrapped else {
+                        log::warn!("Error unwrapping gift");
+                        continue;
                     };
                     let message = match Message::from_json(&event.rumor.content) {
                         Ok(m) => m,
@@ -293,79 +1074,121 @@ async fn main() -> Result<(), anyhow::Error> {
                     };
                     log::info!("Received message: {:#?}", message);
                     match message.kind {
-                        1 => {
+                        kind::TOKEN_REQUEST => {
                             log::info!("Blind signature from EC received");
-                            let mut app = app_clone.lock().unwrap();
-                            let blind_sig_b64 = message.payload;
-                            // 1) Decode Base64 into raw bytes
-                            let blind_sig_bytes = general_purpose::STANDARD
-                                .decode(&blind_sig_b64)
-                                .expect("Invalid Base64 in blind signature");
-
-                            // 2) Reconstruct the BlindSignature properly
-                            //    (assumes impl From<Vec<u8>> for BlindSignature)
-                            let blind_sig = BlindSignature::from(blind_sig_bytes);
-
-                            // 3) Finalize to get a non-blind signature (token)
-                            let secret = match app.secret.as_ref() {
-                                Some(s) => s,
-                                None => {
-                                    log::warn!("Missing secret for token finalization");
+                            let (election_id, vote_token) = {
+                                let mut app = lock(&app_clone);
+                                let Some(election_id) = message.election_id.clone().or_else(|| app.election_id.clone()) else {
+                                    app.notices.error(tr(Text::TokenWithoutElection));
+                                    continue;
+                                };
+                                let Some(ec_key) = app.ec_rsa_pub_key.clone() else {
+                                    app.notices.error(tr(Text::TokenWithoutRsaKey));
+                                    continue;
+                                };
+                                let Some(vote_token) = app.tokens.get_mut(&election_id) else {
+                                    app.notices.error(trf(Text::TokenNotPending, &[&election_id]));
+                                    continue;
+                                };
+                                // Unblind the signature to get the token
+                                if let Err(e) = vote_token.finalize(&ec_key, &message.payload) {
+                                    app.notices.error(trf(Text::TokenInvalid, &[&election_id, &e]));
                                     continue;
                                 }
+                                let vote_token = vote_token.clone();
+                                app.notices.success(trf(Text::TokenReady, &[&election_id]));
+                                (election_id, vote_token)
+                            }; // Mutex guard is dropped here
+                            if let Err(e) = store_clone.save(&election_id, &vote_token).await {
+                                lock(&app_clone).notices.error(trf(Text::TokenSaveFailed, &[&e]));
+                            }
+                            update_history(&store_clone, &app_clone, &election_id, |entry| {
+                                entry.token_received_at = Some(chrono::Utc::now().timestamp());
+                            })
+                            .await;
+                        }
+                        kind::ACK => {
+                            let Some(election_id) = vote_election else {
+                                lock(&app_clone).notices.info(trf(Text::EcMessage, &[&message.payload]));
+                                continue;
                             };
-                            let msg_rand = match app.r.as_ref() {
-                                Some(r) => *r,
-                                None => {
-                                    log::warn!("Missing message randomizer for token finalization");
+                            // Receipt of the vote, signed by the EC
+                            let receipt = match VoteReceipt::verify(&message.payload, &ec_pubkey) {
+                                Ok(r) if event.sender == ec_pubkey && r.election_id == election_id => r,
+                                Ok(_) => {
+                                    lock(&app_clone).notices.error(trf(Text::ReceiptMismatch, &[&election_id]));
                                     continue;
                                 }
-                            };
-                            // We must pass the original message bytes
-                            let h_n_bytes = app
-                                .h_n_bytes
-                                .as_ref()
-                                .expect("Missing h_n_bytes for token finalization");
-
-                            let options = Options::default();
-                            let ec_key = match app.ec_rsa_pub_key.as_ref() {
-                                Some(key) => key,
-                                None => {
-                                    log::warn!(
-                                        "EC RSA public key not available for token finalization"
-                                    );
+                                Err(e) => {
+                                    lock(&app_clone).notices.error(trf(Text::ReceiptInvalid, &[&election_id, &e]));
                                     continue;
                                 }
                             };
-                            let token = match ec_key.finalize(
-                                &blind_sig,
-                                secret,
-                                Some(msg_rand),
-                                h_n_bytes,
-                                &options,
-                            ) {
-                                Ok(t) => t,
-                                Err(e) => {
-                                    log::warn!("Error finalizing blind signature: {}", e);
+                            let vote_token = {
+                                let mut app = lock(&app_clone);
+                                let Some(vote_token) = app.tokens.get_mut(&election_id) else {
+                                    continue;
+                                };
+                                if vote_token.h_n_bytes != receipt.h_n_bytes {
+                                    app.notices.error(trf(Text::ReceiptMismatch, &[&election_id]));
                                     continue;
                                 }
+                                vote_token.receipt = Some(receipt.event.as_json());
+                                vote_token.clone()
+                            }; // Mutex guard is dropped here
+                            if let Err(e) = store_clone.save(&election_id, &vote_token).await {
+                                lock(&app_clone).notices.error(trf(Text::TokenSaveFailed, &[&e]));
+                            }
+                            let mut app = lock(&app_clone);
+                            match receipt.save(&app_dir().join("receipts")) {
+                                Ok(path) => app.notices.success(trf(
+                                    Text::ReceiptSaved,
+                                    &[&election_id, &path.display()]
+                                )),
+                                Err(e) => app.notices.error(trf(Text::ReceiptSaveFailed, &[&e])),
+                            }
+                        }
+                        kind::ELIGIBILITY => {
+                            let Some(election_id) = message.election_id.clone() else {
+                                continue;
                             };
-
-                            app.token = Some(token);
-                            log::info!("Token generated and stored");
+                            let eligibility = if message.payload == "eligible" {
+                                Eligibility::Eligible
+                            } else {
+                                Eligibility::NotEligible
+                            };
+                            log::info!("Eligibility for election {}: {:?}", election_id, eligibility);
+                            lock(&app_clone).eligibility.insert(election_id, eligibility);
                         }
-                        2 => {
-                            log::info!("Voter response {}", message.payload);
+                        kind::ERROR => {
+                            let election_id = message.election_id.clone().or(vote_election).unwrap_or_default();
+                            let Ok(error) = ErrorPayload::from_json(&message.payload) else {
+                                lock(&app_clone).notices.error(trf(Text::EcMessage, &[&message.payload]));
+                                continue;
+                            };
+                            log::warn!("EC rejected message {} ({}): {}", message.id, error.code.as_str(), error.reason);
+                            lock(&app_clone).notices.error(trf(rejection_text(error.code), &[&election_id]));
                         }
                         _ => log::warn!("Unknown response {}", message.payload),
                     }
 
                     continue;
-                } else if let (Kind::Custom(35_000), Ok(e)) =
-                    (event.kind, Election::parse_event(&event))
+                } else if let (true, Ok(e)) =
+                    (event.kind == Kind::Custom(kinds.election), Election::parse_event(&event))
                 {
-                    let mut lock = elections_clone.lock().unwrap();
-                    let mut app = app_clone.lock().unwrap();
+                    let mut app = lock(&app_clone);
+                    // Once the content key is pinned, content not signed with it is forged
+                    if let Some(pk) = &app.content_key {
+                        if !Election::signed_by(&event, pk) {
+                            log::warn!("Ignoring event {} of election {}: bad content signature", event.id, e.id);
+                            continue;
+                        }
+                    }
+                    let mut elections = lock(&elections_clone);
+                    if !upsert(&mut elections, e.clone()) {
+                        log::debug!("Ignoring stale event {} of election {}", event.id, e.id);
+                        continue;
+                    }
                     app.ec_rsa_pub_key = match get_ec_pubkey(e.rsa_pub_key.as_str()) {
                         Ok(key) => Some(key),
                         Err(err) => {
@@ -374,18 +1197,24 @@ async fn main() -> Result<(), anyhow::Error> {
                         }
                     };
 
-                    // If we already have the election, update it
-                    if let Some(existing) = lock.iter_mut().find(|x| x.id == e.id) {
-                        // Update the existing election
-                        *existing = e;
-                    } else {
-                        // If is a new election, add it to the list
-                        lock.push(e);
+                    // Ask the EC once whether we are on the roll, unless we already hold a token
+                    let check_eligibility = !app.tokens.contains_key(&e.id) && !app.eligibility.contains_key(&e.id);
+                    if check_eligibility {
+                        app.eligibility.insert(e.id.clone(), Eligibility::Checking);
+                        let message = Message::new_with_election(
+                            format!("eligibility_{}", chrono::Utc::now().timestamp()),
+                            kind::ELIGIBILITY,
+                            String::new(),
+                            e.id.clone(),
+                        );
+                        let (client, store, keys) = (client.clone(), Arc::clone(&store_clone), my_signer.clone());
+                        tokio::spawn(async move {
+                            if let Err(err) = send_to_ec(&client, &store, &keys, &ec_pubkey, pow, &message).await {
+                                log::warn!("Failed to send eligibility check: {}", err);
+                            }
+                        });
                     }
-
-                    // re-order elections by start time
-                    lock.sort_by_key(|e| Reverse(e.start_time));
-                } else if Kind::Custom(35_001) == event.kind {
+                } else if Kind::Custom(kinds.results) == event.kind {
                     // This is a result event
                     let results = match Election::parse_result_event(&event) {
                         Ok(r) => r,
@@ -394,12 +1223,21 @@ async fn main() -> Result<(), anyhow::Error> {
                             continue;
                         }
                     };
-                    let mut app = app_clone.lock().unwrap();
-                    if app.election_id.is_none() {
+                    let Some(election_id) = event.tags.identifier().map(str::to_string) else {
+                        log::warn!("Result event {} without election ID", event.id);
+                        continue;
+                    };
+                    // Results are replaceable too, keep the newest ones
+                    let published_at = event.created_at.as_u64();
+                    let mut app = lock(&app_clone);
+                    if app.results.get(&election_id).is_some_and(|(t, _)| *t > published_at) {
                         continue;
                     }
-                    app.results = Some(results);
-                    log::info!("Results received: {:?}", app.results);
+                    log::info!("Results received for election {}: {:?}", election_id, results);
+                    app.results.insert(election_id.clone(), (published_at, results));
+                    if app.election_id.as_ref() == Some(&election_id) {
+                        app.notices.info(tr(Text::ResultsUpdated));
+                    }
                 } else {
                     continue;
                 }
@@ -408,172 +1246,401 @@ async fn main() -> Result<(), anyhow::Error> {
     });
 
     // Event handling: keyboard input and periodic UI refresh.
-    let mut events = EventStream::new();
     let mut refresh_interval = interval(Duration::from_millis(200));
 
     loop {
         tokio::select! {
             maybe_event = events.next() => {
-                if let Some(Ok(CEvent::Key(KeyEvent { code, .. }))) = maybe_event {
-                    match code {
-                        KeyCode::Char('q') | KeyCode::Esc => break,
-                        KeyCode::Up => {
+                if let Some(Ok(CEvent::Key(KeyEvent { code, kind: KeyEventKind::Press, .. }))) = maybe_event {
+                    match keymap.action(code) {
+                        // The settings editor takes all keys while it is open
+                        _ if settings_form.is_some() => {
+                            let Some(form) = settings_form.as_mut() else {
+                                continue;
+                            };
+                            match code {
+                                KeyCode::Esc => settings_form = None,
+                                KeyCode::Up => form.select_prev(),
+                                KeyCode::Down | KeyCode::Tab => form.select_next(),
+                                KeyCode::Backspace => form.pop(),
+                                KeyCode::Char(c) => form.push(c),
+                                KeyCode::Enter => {
+                                    let mut app = lock(&app);
+                                    match form.to_settings(&current_settings) {
+                                        Ok(new_settings) => match new_settings.save(&settings_file()) {
+                                            Ok(()) => {
+                                                log::set_max_level(log_level_filter(&new_settings.log_level));
+                                                if let Some(locale) = Locale::from_code(&new_settings.language) {
+                                                    set_locale(locale);
+                                                }
+                                                current_settings = new_settings;
+                                                settings_form = None;
+                                                app.notices.success(tr(Text::SettingsSaved));
+                                            }
+                                            Err(e) => app.notices.error(trf(Text::SettingsSaveFailed, &[&e])),
+                                        },
+                                        Err(e) => app.notices.error(e),
+                                    }
+                                }
+                                _ => {}
+                            }
+                        }
+                        _ if show_help => show_help = false,
+                        _ if show_history => show_history = false,
+                        Some(action @ (Action::ScrollUp | Action::Up)) if log_scroll.is_some() => {
+                            let step = if action == Action::Up { 1 } else { 10 };
+                            let max = log_buffer::len().saturating_sub(1);
+                            log_scroll = log_scroll.map(|s| (s + step).min(max));
+                        }
+                        Some(action @ (Action::ScrollDown | Action::Down)) if log_scroll.is_some() => {
+                            let step = if action == Action::Down { 1 } else { 10 };
+                            log_scroll = log_scroll.map(|s| s.saturating_sub(step));
+                        }
+                        _ if log_scroll.is_some() => log_scroll = None,
+                        Some(Action::Qr) if qr_index.is_some() => {
+                            qr_index = qr_index.map(|i| i + 1).filter(|i| *i < qr_views.len());
+                        }
+                        _ if qr_index.is_some() => qr_index = None,
+                        Some(Action::Qr) => {
+                            // Election ID, EC pubkey and, once received, the receipt of the selected election
+                            let mut data = Vec::new();
+                            let selected_id = selected_election(&elections, &app, selected_election_idx).map(|e| e.id);
+                            if let Some(election_id) = &selected_id {
+                                data.push((tr(Text::QrElectionId), election_id.clone()));
+                            }
+                            data.push((
+                                tr(Text::QrEcPubkey),
+                                ec_pubkey.to_bech32().unwrap_or_else(|_| ec_pubkey.to_hex()),
+                            ));
+                            let mut app = lock(&app);
+                            if let Some(receipt) = selected_id
+                                .and_then(|id| app.tokens.get(&id))
+                                .and_then(|t| t.receipt.clone())
+                            {
+                                data.push((tr(Text::QrReceipt), receipt));
+                            }
+                            let total = data.len();
+                            qr_views.clear();
+                            for (i, (title, value)) in data.into_iter().enumerate() {
+                                let title = trf(Text::QrTitle, &[&title, &(i + 1), &total]);
+                                match QrView::new(title, &value) {
+                                    Ok(view) => qr_views.push(view),
+                                    Err(e) => app.notices.error(trf(Text::QrFailed, &[&e])),
+                                }
+                            }
+                            qr_index = (!qr_views.is_empty()).then_some(0);
+                        }
+                        Some(Action::Help) => show_help = true,
+                        Some(Action::History) => show_history = true,
+                        Some(Action::Logs) => log_scroll = Some(0),
+                        Some(Action::Settings) => {
+                            settings_form = Some(SettingsForm::new(&current_settings));
+                        }
+                        Some(Action::Refresh) => {
+                            lock(&app).notices.info(tr(Text::ReconnectingRelays));
+                            cloned_client.connect().await;
+                            // Ask again for all the elections and results, beyond the window of the
+                            // first subscription. New events arrive through the notifications.
+                            let filter = Filter::new()
+                                .kinds([Kind::Custom(kinds.election), Kind::Custom(kinds.results)])
+                                .author(ec_pubkey)
+                                .limit(BACKFILL_LIMIT);
+                            let opts = SubscribeAutoCloseOptions::default().exit_policy(ReqExitPolicy::ExitOnEOSE);
+                            if let Err(e) = cloned_client.subscribe(filter, Some(opts)).await {
+                                lock(&app).notices.error(trf(Text::ReloadFailed, &[&e]));
+                            }
+                        }
+                        Some(Action::NextArea) => {
+                            // Only move to the areas already reached from the elections table
+                            let selected_id = selected_election(&elections, &app, selected_election_idx).map(|e| e.id);
+                            let (election_chosen, candidate_chosen) = {
+                                let app = lock(&app);
+                                (
+                                    app.election_id.is_some() && app.election_id == selected_id,
+                                    !app.ballot.is_empty(),
+                                )
+                            };
+                            active_area = match active_area {
+                                0 if election_chosen => 1,
+                                1 if candidate_chosen => 2,
+                                _ => 0,
+                            };
+                        }
+                        Some(Action::EligibleOnly) => {
+                            let mut app = lock(&app);
+                            app.eligible_only = !app.eligible_only;
+                            selected_election_idx = 0;
+                            active_area = 0;
+                            let text = if app.eligible_only {
+                                Text::ShowingEligibleOnly
+                            } else {
+                                Text::ShowingAll
+                            };
+                            app.notices.info(tr(text));
+                        }
+                        Some(Action::Quit) => break,
+                        Some(Action::Up) => {
                             if active_area == 0 {
                                 selected_election_idx = selected_election_idx.saturating_sub(1);
                             } else if active_area == 1 && selected_candidate_idx > 0 {
                                 selected_candidate_idx = selected_candidate_idx.saturating_sub(1);
                             }
+                            lock(&app).detail_scroll = 0;
+                        }
+                        Some(Action::ScrollUp) => {
+                            let mut app = lock(&app);
+                            app.detail_scroll = app.detail_scroll.saturating_sub(1);
                         }
-                        KeyCode::Down => {
+                        Some(Action::ScrollDown) => {
+                            let mut app = lock(&app);
+                            app.detail_scroll = app.detail_scroll.saturating_add(1);
+                        }
+                        Some(Action::Down) => {
                             if active_area == 0 {
-                                let len = elections.lock().unwrap().len();
+                                let len = {
+                                    let app = lock(&app);
+                                    visible_elections(&lock(&elections), &app).len()
+                                };
                                 if selected_election_idx + 1 < len {
                                     selected_election_idx += 1;
                                 }
                             } else if active_area == 1 {
-                                if let Some(e) = elections.lock().unwrap().get(selected_election_idx) {
+                                if let Some(e) = selected_election(&elections, &app, selected_election_idx) {
                                     if selected_candidate_idx + 1 < e.candidates.len() {
                                         selected_candidate_idx += 1;
                                     }
                                 }
                             }
+                            lock(&app).detail_scroll = 0;
                         }
-                        KeyCode::Enter => {
+                        Some(Action::Select) => {
                             if active_area == 0 {
                                 // Extract needed data from app state first
-                                let (pk, election_id) = {
-                                    let app = app.lock().unwrap();
+                                let (pk, election) = {
+                                    let mut app = lock(&app);
                                     let pk = match app.ec_rsa_pub_key.as_ref() {
                                         Some(key) => key.clone(),
                                         None => {
-                                            log::error!("EC RSA public key not available - cannot request token");
+                                            app.notices.error(tr(Text::RsaKeyMissing));
                                             continue;
                                         }
                                     };
-                                    let elections_lock = elections.lock().unwrap();
-                                    let election_id = elections_lock.get(selected_election_idx).map(|e| e.id.clone());
-                                    (pk, election_id)
+                                    let election = visible_elections(&lock(&elections), &app)
+                                        .get(selected_election_idx)
+                                        .map(|e| {
+                                            let expiry = e.bound_tokens.then_some(e.end_time);
+                                            let anonymous = e.anonymous_requests.then(|| (*e).clone());
+                                            (e.id.clone(), e.name.clone(), e.voting_method, e.token_scheme, expiry, anonymous)
+                                        });
+                                    (pk, election)
                                 }; // Mutex guard is dropped here
 
-                                let options = Options::default();
-                                let rng = &mut rand::thread_rng();
-                                // 1) Generate nonce and its hash
-                                let nonce: BigUint = OsRng.gen_biguint(128);
-                                // 2) Hash the nonce
-                                let h_n_bytes = Sha256::digest(nonce.to_bytes_be()).to_vec();
-
-                                // 3) Blind the hash with EC's RSA public key
-                                let blinding_result =
-                                    pk.blind(rng, h_n_bytes.clone(), true, &options).expect("Blinding failed");
-                                let blinded_h_n = blinding_result.blind_msg;
-                                let blinded_b64 = general_purpose::STANDARD.encode(blinded_h_n);
-
-                                if let Some(ref election_id) = election_id {
-                                    let message = Message::new_with_election(
-                                        format!("token_request_{}", chrono::Utc::now().timestamp()),
-                                        1,
-                                        blinded_b64,
-                                        election_id.clone(),
-                                    );
-                                    let message_json = serde_json::to_string(&message)?;
-                                    log::info!("Token request content: {}", message_json);
-                                    let my_keys = Keys::parse(&settings.secret_key)?;
-                                    // Creates a "rumor" with the hash of the nonce.
-                                    let rumor: UnsignedEvent = EventBuilder::text_note(message_json).build(my_keys.public_key());
-
-                                    // Wraps the rumor in a Gift Wrap.
-                                    let gift_wrap: Event = EventBuilder::gift_wrap(&my_keys, &ec_pubkey, rumor, None).await?;
-
-                                    // Send the Gift Wrap
-                                    cloned_client.send_event(&gift_wrap).await?;
-
-                                    log::info!("Token request sent!");
+                                let Some((election_id, election_name, voting_method, token_scheme, expiry, anonymous)) = election else {
+                                    continue;
+                                };
+
+                                // A token is only requested once per election: the EC won't
+                                // sign a second one, so a pending request must be kept
+                                let already_requested = lock(&app).tokens.contains_key(&election_id);
+                                if !already_requested {
+                                    // Blind the hash of a fresh nonce with EC's RSA public key
+                                    let (vote_token, blinded_b64) = match VoteToken::request(&pk, token_scheme, expiry.map(|e| (election_id.as_str(), e))) {
+                                        Ok(request) => request,
+                                        Err(e) => {
+                                            lock(&app).notices.error(trf(Text::BlindingFailed, &[&e]));
+                                            continue;
+                                        }
+                                    };
+                                    // Anonymous requests prove the voter is on the roll, from the token's request keys
+                                    let anonymous_request = match &anonymous {
+                                        Some(election) => {
+                                            let request = match &local_keys {
+                                                Some(keys) => election
+                                                    .anonymous_request(keys, blinded_b64.clone())
+                                                    .and_then(|message| Ok((message, vote_token.request_keys()?))),
+                                                None => Err(anyhow::anyhow!(tr(Text::AnonymousNeedsKey))),
+                                            };
+                                            match request {
+                                                Ok(request) => Some(request),
+                                                Err(e) => {
+                                                    lock(&app).notices.error(trf(Text::TokenRequestFailed, &[&e]));
+                                                    continue;
+                                                }
+                                            }
+                                        }
+                                        None => None,
+                                    };
+                                    // Keep the blinding state before the EC can answer, on disk too
+                                    // so a restart doesn't lose the secret needed to unblind the token
+                                    if let Err(e) = token_store.save(&election_id, &vote_token).await {
+                                        lock(&app).notices.error(trf(Text::TokenNotRequestedSaveFailed, &[&e]));
+                                        continue;
+                                    }
+                                    lock(&app).tokens.insert(election_id.clone(), vote_token);
+                                    update_history(&token_store, &app, &election_id, |entry| {
+                                        entry.election_name = Some(election_name);
+                                        entry.token_requested_at = Some(chrono::Utc::now().timestamp());
+                                    })
+                                    .await;
+
+                                    let delivery = match anonymous_request {
+                                        Some((message, request_keys)) => {
+                                            let filter = Filter::new().kind(Kind::GiftWrap).pubkey(request_keys.public_key());
+                                            if let Err(e) = cloned_client.subscribe(filter, None).await {
+                                                log::warn!("Failed to subscribe to the token: {}", e);
+                                            }
+                                            send_to_ec(&cloned_client, &token_store, &request_keys, &ec_pubkey, pow, &message).await
+                                        }
+                                        None => {
+                                            let message = Message::new_with_election(
+                                                format!("token_request_{}", chrono::Utc::now().timestamp()),
+                                                kind::TOKEN_REQUEST,
+                                                blinded_b64,
+                                                election_id.clone(),
+                                            );
+                                            send_to_ec(&cloned_client, &token_store, &voter_keys, &ec_pubkey, pow, &message).await
+                                        }
+                                    };
+                                    match delivery {
+                                        Ok(Delivery::Sent(relays)) => lock(&app).notices.info(trf(
+                                            Text::TokenRequestSent,
+                                            &[&election_id, &relays]
+                                        )),
+                                        Ok(Delivery::Queued) => {
+                                            let mut app = lock(&app);
+                                            app.pending_sends += 1;
+                                            app.notices.info(trf(Text::TokenRequestQueued, &[&election_id]));
+                                        }
+                                        Err(e) => lock(&app).notices.error(trf(Text::TokenRequestFailed, &[&e])),
+                                    }
                                     // Wait for the Gift Wrap to be unwrapped.
                                 }
 
                                 // Update app state after async operations
                                 {
-                                    let mut app = app.lock().unwrap();
-                                    app.nonce = Some(nonce);
-                                    app.h_n_bytes = Some(h_n_bytes);
-                                    app.secret = Some(blinding_result.secret);
-                                    app.r = blinding_result.msg_randomizer;
-                                    app.election_id = election_id;
+                                    let mut app = lock(&app);
+                                    if app.election_id.as_ref() != Some(&election_id) {
+                                        app.ballot = Ballot::new(voting_method);
+                                    }
+                                    app.election_id = Some(election_id);
                                 }
 
                                 active_area = 1;
                                 selected_candidate_idx = 0;
+                                lock(&app).detail_scroll = 0;
                             } else if active_area == 1 {
-                                // Extract needed data from app state first
-                                let (selected_candidate, token, r, h_n_bytes) = {
-                                    let app = app.lock().unwrap();
-                                    let elections_lock = elections.lock().unwrap();
-                                    let selected_candidate = elections_lock.get(selected_election_idx).map(|e| {
-                                        let candidate = e.candidates[selected_candidate_idx].clone();
-                                        let election_id = e.id.clone();
-                                        (candidate, election_id)
-                                    });
-                                    (
-                                        selected_candidate, 
-                                        app.token.clone(), 
-                                        app.r, 
-                                        app.h_n_bytes.clone()
-                                    )
-                                }; // Mutex guards are dropped here
-
-                                // Update candidate_id in app state
-                                {
-                                    let mut app = app.lock().unwrap();
-                                    app.candidate_id = selected_candidate.as_ref().map(|(c, _)| c.id);
+                                // Mark the highlighted candidate on the ballot
+                                let candidate_id = selected_election(&elections, &app, selected_election_idx)
+                                    .and_then(|e| e.candidates.get(selected_candidate_idx).map(|c| c.id));
+                                if let Some(candidate_id) = candidate_id {
+                                    let mut app = lock(&app);
+                                    let vote_sent = app
+                                        .election_id
+                                        .as_ref()
+                                        .and_then(|id| app.tokens.get(id))
+                                        .is_some_and(|t| t.vote_sent);
+                                    if vote_sent {
+                                        app.notices.info(tr(Text::VoteAlreadySent));
+                                    } else {
+                                        log::info!("Candidate {} marked", candidate_id);
+                                        app.ballot.toggle(candidate_id);
+                                        // Approval and ranked ballots take several candidates, Tab moves on
+                                        if app.ballot.method == VotingMethod::Plurality {
+                                            active_area = 2;
+                                        }
+                                    }
                                 }
+                            }
+                        }
+                        Some(action @ (Action::RankUp | Action::RankDown)) if active_area == 1 => {
+                            let candidate_id = selected_election(&elections, &app, selected_election_idx)
+                                .and_then(|e| e.candidates.get(selected_candidate_idx).map(|c| c.id));
+                            if let Some(candidate_id) = candidate_id {
+                                let mut app = lock(&app);
+                                if action == Action::RankUp {
+                                    app.ballot.rank_up(candidate_id);
+                                } else {
+                                    app.ballot.rank_down(candidate_id);
+                                }
+                            }
+                        }
+                        Some(Action::Confirm) if active_area == 2 => {
+                            // Build the vote payload with the unblinded token
+                            let election = selected_election(&elections, &app, selected_election_idx);
+                            let vote = {
+                                let mut app = lock(&app);
+                                let app = &mut *app;
+                                let choices = app.ballot.choices.clone();
+                                match &app.election_id {
+                                    Some(election_id) => app
+                                        .tokens
+                                        .get_mut(election_id)
+                                        .filter(|t| !t.vote_sent)
+                                        .and_then(|t| {
+                                            let election = election.as_ref().filter(|e| e.id == *election_id)?;
+                                            let payload = election.vote_payload(t, &choices)?;
+                                            Some((election_id.clone(), choices, payload, t.vote_keys(), t.clone()))
+                                        }),
+                                    None => None,
+                                }
+                            }; // Mutex guard is dropped here
 
-                                if let Some((c, election_id)) = selected_candidate {
-                                    log::info!("Selected candidate: {:#?}", c);
-                                    let token = match token.as_ref() {
-                                        Some(t) => t,
-                                        None => {
-                                            log::warn!("Token not generated");
+                            match vote {
+                                Some((election_id, choices, vote_payload, vote_keys, vote_token)) => {
+                                    // Keep the vote keys before sending, they are needed to read the receipt
+                                    if let Err(e) = token_store.save(&election_id, &vote_token).await {
+                                        lock(&app).notices.error(trf(Text::VoteNotSentSaveFailed, &[&e]));
+                                        continue;
+                                    }
+                                    let filter = Filter::new().kind(Kind::GiftWrap).pubkey(vote_keys.public_key());
+                                    if let Err(e) = cloned_client.subscribe(filter, None).await {
+                                        log::warn!("Failed to subscribe to vote receipts: {}", e);
+                                    }
+                                    match send_vote(&cloned_client, &token_store, &vote_keys, &ec_pubkey, pow, election_id.clone(), vote_payload).await {
+                                        Ok(Delivery::Sent(relays)) => {
+                                            // Success is told when the EC acknowledges the vote
+                                            lock(&app).notices.info(trf(Text::VoteSent, &[&election_id, &relays]))
+                                        }
+                                        Ok(Delivery::Queued) => {
+                                            let mut app = lock(&app);
+                                            app.pending_sends += 1;
+                                            app.notices.info(trf(Text::VoteQueued, &[&election_id]));
+                                        }
+                                        Err(e) => {
+                                            lock(&app).notices.error(trf(Text::VoteSendFailed, &[&e]));
                                             continue;
                                         }
-                                    };
-                                    let r = r.as_ref().unwrap();
-                                    let h_n_b64 = general_purpose::STANDARD.encode(h_n_bytes.as_ref().unwrap());
-                                    let token_b64 = general_purpose::STANDARD.encode(token);
-                                    let r_b64 = general_purpose::STANDARD.encode(r);
-                                    // h_n:token:r:candidate_id
-                                    let candidate_id = c.id;
-                                    let vote_payload = format!("{h_n_b64}:{token_b64}:{r_b64}:{candidate_id}");
-                                    let message = Message::new_with_election(
-                                        format!("vote_{}", chrono::Utc::now().timestamp()),
-                                        2,
-                                        vote_payload,
-                                        election_id,
-                                    );
-                                    let message_json = serde_json::to_string(&message)?;
-                                    log::info!("Vote to be sent: {}", message_json);
-                                    // We generate a random key to keep the vote secret
-                                    let random_keys = Keys::generate();
-                                    // Creates a "rumor" with the hash of the nonce.
-                                    let rumor: UnsignedEvent = EventBuilder::text_note(message_json).build(random_keys.public_key());
-
-                                    // Wraps the rumor in a Gift Wrap.
-                                    let gift_wrap: Event = EventBuilder::gift_wrap(&random_keys, &ec_pubkey, rumor, None).await?;
-
-                                    // Send the Gift Wrap
-                                    cloned_client.send_event(&gift_wrap).await?;
-
-                                    log::info!("Vote sent!");
-                                    // Wait for the Gift Wrap to be unwrapped.
+                                    }
+                                    let sent = lock(&app).tokens.get_mut(&election_id).map(|t| {
+                                        t.vote_sent = true;
+                                        t.clone()
+                                    });
+                                    if let Some(token) = sent {
+                                        if let Err(e) = token_store.save(&election_id, &token).await {
+                                            lock(&app).notices.error(trf(Text::TokenSaveFailed, &[&e]));
+                                        }
+                                    }
+                                    let record_choice = current_settings.record_choice;
+                                    update_history(&token_store, &app, &election_id, |entry| {
+                                        entry.voted_at = Some(chrono::Utc::now().timestamp());
+                                        entry.choices = if record_choice { choices } else { Vec::new() };
+                                    })
+                                    .await;
                                 }
-                                // TODO: handle candidate confirmation or switch to Ballot area
+                                None => lock(&app).notices.error(tr(Text::VoteNotReady)),
                             }
                         }
+                        Some(Action::Back) if active_area == 2 => {
+                            active_area = 1;
+                        }
                         _ => {}
                     }
                 }
             },
             _ = refresh_interval.tick() => {
                 // Refresh the UI even if there is no input.
+                let relays = relay_statuses(&cloned_client).await;
+                lock(&app).relays = relays;
             }
         }
 
@@ -585,14 +1652,38 @@ async fn main() -> Result<(), anyhow::Error> {
                 &app,
                 selected_election_idx,
                 selected_candidate_idx,
+                match (&settings_form, show_help, qr_index.and_then(|i| qr_views.get(i))) {
+                    (Some(form), _, _) => Overlay::Settings(form),
+                    (None, true, _) => Overlay::Help(&keymap),
+                    (None, false, Some(view)) => Overlay::Qr(view),
+                    (None, false, None) if show_history => Overlay::History,
+                    (None, false, None) => log_scroll.map_or(Overlay::None, Overlay::Logs),
+                },
             )
         })?;
     }
 
-    // Restore terminal to its original state.
-    disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
-    terminal.show_cursor()?;
-
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eligibility_filter() {
+        let mut app = App::default();
+        app.eligibility.insert("a".into(), Eligibility::NotEligible);
+        app.eligibility.insert("b".into(), Eligibility::Eligible);
+        app.eligibility.insert("c".into(), Eligibility::Checking);
+        assert!(!app.is_hidden("a"));
+
+        app.eligible_only = true;
+        assert!(app.is_hidden("a"));
+        assert!(!app.is_hidden("b"));
+        assert!(!app.is_hidden("c"));
+        assert!(!app.is_hidden("unknown"));
+        assert_eq!(app.roll_status("a"), "No");
+        assert_eq!(app.roll_status("c"), "...");
+    }
+}
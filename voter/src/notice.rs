@@ -0,0 +1,83 @@
+use chrono::{DateTime, Local};
+use std::collections::VecDeque;
+
+/// Number of notices kept in memory, older ones are dropped.
+const MAX_NOTICES: usize = 50;
+
+/// Severity of a notice, used to pick its color in the Messages area.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Info,
+    Success,
+    Error,
+}
+
+/// A message shown to the voter about the progress of the voting flow.
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub time: DateTime<Local>,
+    pub level: Level,
+    pub text: String,
+}
+
+/// Bounded list of notices, newest last.
+#[derive(Debug, Default)]
+pub struct Notices {
+    items: VecDeque<Notice>,
+}
+
+impl Notices {
+    /// Adds a notice and mirrors it to the log file.
+    pub fn push(&mut self, level: Level, text: impl Into<String>) {
+        let text = text.into();
+        match level {
+            Level::Error => log::error!("{}", text),
+            _ => log::info!("{}", text),
+        }
+        if self.items.len() == MAX_NOTICES {
+            self.items.pop_front();
+        }
+        self.items.push_back(Notice {
+            time: Local::now(),
+            level,
+            text,
+        });
+    }
+
+    pub fn info(&mut self, text: impl Into<String>) {
+        self.push(Level::Info, text);
+    }
+
+    pub fn success(&mut self, text: impl Into<String>) {
+        self.push(Level::Success, text);
+    }
+
+    pub fn error(&mut self, text: impl Into<String>) {
+        self.push(Level::Error, text);
+    }
+
+    /// Returns the newest notices first.
+    pub fn latest(&self) -> impl Iterator<Item = &Notice> {
+        self.items.iter().rev()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_notices_are_bounded_and_newest_first() {
+        let mut notices = Notices::default();
+        for i in 0..MAX_NOTICES + 5 {
+            notices.info(format!("notice {}", i));
+        }
+        notices.error("vote rejected");
+
+        let latest: Vec<&Notice> = notices.latest().collect();
+        assert_eq!(latest.len(), MAX_NOTICES);
+        assert_eq!(latest[0].text, "vote rejected");
+        assert_eq!(latest[0].level, Level::Error);
+        assert_eq!(latest[1].text, format!("notice {}", MAX_NOTICES + 4));
+    }
+}
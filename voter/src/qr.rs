@@ -0,0 +1,74 @@
+use anyhow::Result;
+use qrcode::{Color, QrCode};
+
+/// Light modules around the code, needed by scanners to find it.
+const QUIET_ZONE: usize = 2;
+
+/// A QR code ready to be drawn in the terminal.
+#[derive(Debug, Clone)]
+pub struct QrView {
+    pub title: String,
+    pub lines: Vec<String>,
+}
+
+impl QrView {
+    pub fn new(title: impl Into<String>, data: &str) -> Result<Self> {
+        Ok(Self {
+            title: title.into(),
+            lines: qr_lines(data)?,
+        })
+    }
+
+    /// Width in terminal columns
+    pub fn width(&self) -> usize {
+        self.lines.first().map_or(0, |l| l.chars().count())
+    }
+}
+
+/// Encodes data as a QR code drawn with half blocks, two rows of modules
+/// per line of text. Dark modules are drawn with the foreground color,
+/// so it must be rendered dark on a light background.
+pub fn qr_lines(data: &str) -> Result<Vec<String>> {
+    let code = QrCode::new(data)?;
+    let width = code.width();
+    let colors = code.to_colors();
+    let size = width + 2 * QUIET_ZONE;
+    let dark = |x: usize, y: usize| {
+        if x < QUIET_ZONE || y < QUIET_ZONE || x >= width + QUIET_ZONE || y >= width + QUIET_ZONE {
+            return false;
+        }
+        colors[(y - QUIET_ZONE) * width + (x - QUIET_ZONE)] == Color::Dark
+    };
+
+    let lines = (0..size)
+        .step_by(2)
+        .map(|y| {
+            (0..size)
+                .map(|x| match (dark(x, y), dark(x, y + 1)) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                })
+                .collect()
+        })
+        .collect();
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qr_lines_size() {
+        let view = QrView::new("Election", "a1b2").unwrap();
+        // Version 1 codes are 21 modules wide
+        let size = 21 + 2 * QUIET_ZONE;
+        assert_eq!(view.width(), size);
+        assert_eq!(view.lines.len(), size.div_ceil(2));
+        // The quiet zone is blank and the finder pattern starts after it
+        assert!(view.lines[0].chars().all(|c| c == ' '));
+        assert!(view.lines[1].chars().nth(QUIET_ZONE).is_some_and(|c| c != ' '));
+    }
+}
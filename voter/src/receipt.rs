@@ -0,0 +1,88 @@
+use anyhow::Result;
+use criptocracia_voter_core::verify_receipt;
+use nostr_sdk::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Receipt of an accepted vote: a Nostr event signed by the EC whose content
+/// names the election and the hash of the nonce the vote was cast with.
+/// Anyone holding the nonce can check the receipt against the EC's public key.
+#[derive(Debug, Clone)]
+pub struct VoteReceipt {
+    pub election_id: String,
+    pub h_n_bytes: Vec<u8>,
+    pub accepted_at: u64,
+    pub event: Event,
+}
+
+impl VoteReceipt {
+    /// Parses the receipt event of the EC's acknowledgment and verifies that
+    /// it is signed by the EC's Nostr key.
+    pub fn verify(event_json: &str, ec_pubkey: &PublicKey) -> Result<Self> {
+        let receipt = verify_receipt(event_json, &ec_pubkey.to_hex())?;
+        Ok(Self {
+            election_id: receipt.election_id,
+            h_n_bytes: receipt.h_n,
+            accepted_at: receipt.accepted_at,
+            event: Event::from_json(event_json)?,
+        })
+    }
+
+    /// Writes the signed receipt event to `<dir>/<election_id>.json`.
+    pub fn save(&self, dir: &Path) -> Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.json", self.election_id));
+        fs::write(&path, self.event.as_pretty_json())?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::{Engine, general_purpose};
+
+    fn receipt_event(keys: &Keys, h_n: &[u8]) -> Event {
+        let content = serde_json::json!({
+            "election_id": "a1b2",
+            "h_n": general_purpose::STANDARD.encode(h_n),
+            "accepted_at": 1_700_000_000u64,
+        });
+        EventBuilder::text_note(content.to_string())
+            .sign_with_keys(keys)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_verify_receipt() {
+        let ec_keys = Keys::generate();
+        let event = receipt_event(&ec_keys, &[1, 2, 3]);
+
+        let receipt = VoteReceipt::verify(&event.as_json(), &ec_keys.public_key()).unwrap();
+        assert_eq!(receipt.election_id, "a1b2");
+        assert_eq!(receipt.h_n_bytes, vec![1, 2, 3]);
+        assert_eq!(receipt.accepted_at, 1_700_000_000);
+
+        // Signed by someone else
+        let other = Keys::generate();
+        assert!(VoteReceipt::verify(&event.as_json(), &other.public_key()).is_err());
+
+        // Tampered content
+        let tampered = event.as_json().replace("a1b2", "a1b3");
+        assert!(VoteReceipt::verify(&tampered, &ec_keys.public_key()).is_err());
+    }
+
+    #[test]
+    fn test_save_receipt() {
+        let ec_keys = Keys::generate();
+        let receipt =
+            VoteReceipt::verify(&receipt_event(&ec_keys, &[4]).as_json(), &ec_keys.public_key())
+                .unwrap();
+        let dir = std::env::temp_dir().join(format!("voter-receipts-{}", std::process::id()));
+
+        let path = receipt.save(&dir).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(VoteReceipt::verify(&saved, &ec_keys.public_key()).is_ok());
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow::Result;
+use criptocracia_protocol::{EcDescriptor, EventKinds};
+use criptocracia_protocol::descriptor::{DESCRIPTOR_EVENT_ID, DESCRIPTOR_EVENT_KIND};
+use criptocracia_protocol::election::{KINDS_EVENT_ID, KINDS_EVENT_KIND};
+use nostr_sdk::prelude::*;
+use std::sync::Arc;
+use tokio::time::Duration;
+
+use crate::store::TokenStore;
+
+/// Time given to the relays to reconnect before retrying a failed send.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// First and longest wait between reconnection attempts.
+const MIN_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Wait between checks while the relays are up and nothing is queued.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time given to the relays to connect and answer the lookup of the EC's
+/// event kinds or descriptor.
+const KINDS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connection status of every relay of the client, sorted by URL.
+pub async fn relay_statuses(client: &Client) -> Vec<(String, RelayStatus)> {
+    let mut statuses: Vec<(String, RelayStatus)> = client
+        .relays()
+        .await
+        .iter()
+        .map(|(url, relay)| (url.to_string(), relay.status()))
+        .collect();
+    statuses.sort_by(|a, b| a.0.cmp(&b.0));
+    statuses
+}
+
+/// Kinds of the events the EC publishes, as advertised in its NIP-89 handler
+/// information event. The default kinds if it advertises none, or no relay answers.
+pub async fn fetch_event_kinds(client: &Client, ec_pubkey: &PublicKey) -> EventKinds {
+    client.wait_for_connection(KINDS_TIMEOUT).await;
+    let filter = Filter::new()
+        .kind(Kind::Custom(KINDS_EVENT_KIND))
+        .author(*ec_pubkey)
+        .identifier(KINDS_EVENT_ID);
+    match client.fetch_events(filter, KINDS_TIMEOUT).await {
+        Ok(events) => advertised_kinds(events, ec_pubkey).unwrap_or_default(),
+        Err(e) => {
+            log::warn!("Failed to fetch the EC's event kinds, using the defaults: {}", e);
+            EventKinds::default()
+        }
+    }
+}
+
+/// Valid kinds of the newest handler information event signed by the EC
+fn advertised_kinds(events: impl IntoIterator<Item = Event>, ec_pubkey: &PublicKey) -> Option<EventKinds> {
+    let event = newest_signed_by(events, ec_pubkey)?;
+    EventKinds::from_json(&event.content).ok().filter(|kinds| kinds.validate().is_ok())
+}
+
+/// The EC's descriptor, with its RSA public key and protocol versions.
+/// `None` if it publishes none, or no relay answers.
+pub async fn fetch_descriptor(client: &Client, ec_pubkey: &PublicKey) -> Option<EcDescriptor> {
+    client.wait_for_connection(KINDS_TIMEOUT).await;
+    let filter = Filter::new()
+        .kind(Kind::Custom(DESCRIPTOR_EVENT_KIND))
+        .author(*ec_pubkey)
+        .identifier(DESCRIPTOR_EVENT_ID);
+    match client.fetch_events(filter, KINDS_TIMEOUT).await {
+        Ok(events) => published_descriptor(events, ec_pubkey),
+        Err(e) => {
+            log::warn!("Failed to fetch the EC's descriptor: {}", e);
+            None
+        }
+    }
+}
+
+/// Descriptor in the newest descriptor event signed by the EC
+fn published_descriptor(events: impl IntoIterator<Item = Event>, ec_pubkey: &PublicKey) -> Option<EcDescriptor> {
+    EcDescriptor::from_json(&newest_signed_by(events, ec_pubkey)?.content).ok()
+}
+
+fn newest_signed_by(events: impl IntoIterator<Item = Event>, pubkey: &PublicKey) -> Option<Event> {
+    events
+        .into_iter()
+        .filter(|e| e.pubkey == *pubkey && e.verify().is_ok())
+        .max_by_key(|e| e.created_at)
+}
+
+/// Sends an event to all the relays. If no relay accepts it, reconnects
+/// the relays and tries once more, so a single dead relay doesn't lose the message.
+/// Returns the relays that accepted the event.
+pub async fn send_with_failover(client: &Client, event: &Event) -> Result<Vec<RelayUrl>> {
+    match try_send(client, event).await {
+        Ok(relays) => return Ok(relays),
+        Err(e) => log::warn!("Event {} not published, reconnecting relays: {}", event.id, e),
+    }
+    reconnect(client).await;
+    try_send(client, event).await
+}
+
+/// Connects the relays that are down and waits a moment for them.
+pub async fn reconnect(client: &Client) {
+    client.connect().await;
+    client.wait_for_connection(RECONNECT_TIMEOUT).await;
+}
+
+/// Keeps the client connected and the outbox empty, for as long as it runs:
+/// reconnects while no relay is connected and sends the queued events,
+/// waiting longer after each failed round. `on_pending` gets the number of
+/// events still queued after every round.
+pub async fn keep_alive(client: Client, store: Arc<TokenStore>, on_pending: impl Fn(usize)) {
+    let mut attempt = 0;
+    loop {
+        if !is_online(&client).await {
+            log::info!("No relay connected, reconnecting (attempt {})", attempt + 1);
+            reconnect(&client).await;
+        }
+        let pending = match flush_outbox(&client, &store).await {
+            Ok(pending) => pending,
+            Err(e) => {
+                log::error!("Failed to read the outbox: {}", e);
+                0
+            }
+        };
+        on_pending(pending);
+
+        if pending == 0 && is_online(&client).await {
+            attempt = 0;
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        } else {
+            tokio::time::sleep(backoff(attempt)).await;
+            attempt = attempt.saturating_add(1);
+        }
+    }
+}
+
+/// Wait before the given reconnection attempt, starting at 0:
+/// doubles from `MIN_BACKOFF` up to `MAX_BACKOFF`.
+pub fn backoff(attempt: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_BACKOFF)
+}
+
+/// Whether at least one relay is connected.
+pub async fn is_online(client: &Client) -> bool {
+    relay_statuses(client)
+        .await
+        .iter()
+        .any(|(_, status)| *status == RelayStatus::Connected)
+}
+
+/// Sends the events queued while the relays were unreachable, oldest first,
+/// removing each one that a relay accepts. Stops at the first failure.
+/// Returns the number of events still queued.
+pub async fn flush_outbox(client: &Client, store: &TokenStore) -> Result<usize> {
+    let events = store.queued_events().await?;
+    let mut pending = events.len();
+    for event in events {
+        if let Err(e) = try_send(client, &event).await {
+            log::warn!("Queued event {} still not published: {}", event.id, e);
+            break;
+        }
+        store.dequeue_event(&event.id).await?;
+        log::info!("Queued event {} published", event.id);
+        pending -= 1;
+    }
+    Ok(pending)
+}
+
+async fn try_send(client: &Client, event: &Event) -> Result<Vec<RelayUrl>> {
+    let output = client.send_event(event).await?;
+    for (url, error) in output.failed.iter() {
+        log::warn!("Relay {} rejected event {}: {}", url, event.id, error);
+    }
+    if output.success.is_empty() {
+        return Err(anyhow::anyhow!("No relay accepted the event"));
+    }
+    Ok(output.success.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_relay_statuses_sorted() {
+        let client = Client::default();
+        client.add_relay("wss://relay.mostro.network").await.unwrap();
+        client.add_relay("wss://nos.lol").await.unwrap();
+
+        let statuses = relay_statuses(&client).await;
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0].0, "wss://nos.lol");
+        assert_eq!(statuses[1].1, RelayStatus::Initialized);
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        assert_eq!(backoff(0), MIN_BACKOFF);
+        assert_eq!(backoff(1), MIN_BACKOFF * 2);
+        assert_eq!(backoff(3), MIN_BACKOFF * 8);
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_send_fails_without_relays() {
+        let client = Client::default();
+        let event = EventBuilder::text_note("test")
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+        assert!(send_with_failover(&client, &event).await.is_err());
+    }
+
+    #[test]
+    fn test_advertised_kinds() {
+        let ec_keys = Keys::generate();
+        let kinds_event = |keys: &Keys, content: &str, created_at: u64| {
+            EventBuilder::new(Kind::Custom(KINDS_EVENT_KIND), content)
+                .tag(Tag::identifier(KINDS_EVENT_ID))
+                .custom_created_at(Timestamp::from(created_at))
+                .sign_with_keys(keys)
+                .unwrap()
+        };
+        let custom = EventKinds { election: 36_000, results: 36_001, ballot: 36_002, delta: 36_003, turnout: 36_004 };
+        let events = vec![
+            kinds_event(&ec_keys, &EventKinds::default().as_json(), 1_000),
+            kinds_event(&ec_keys, &custom.as_json(), 2_000),
+            // Not signed by the EC
+            kinds_event(&Keys::generate(), &EventKinds { election: 37_000, ..custom }.as_json(), 3_000),
+        ];
+        assert_eq!(advertised_kinds(events, &ec_keys.public_key()), Some(custom));
+
+        let invalid = kinds_event(&ec_keys, &EventKinds { results: 36_000, ..custom }.as_json(), 1_000);
+        assert_eq!(advertised_kinds([invalid], &ec_keys.public_key()), None);
+        assert_eq!(advertised_kinds([], &ec_keys.public_key()), None);
+    }
+
+    #[test]
+    fn test_published_descriptor() {
+        let ec_keys = Keys::generate();
+        let descriptor_event = |keys: &Keys, content: &str, created_at: u64| {
+            EventBuilder::new(Kind::Custom(DESCRIPTOR_EVENT_KIND), content)
+                .tag(Tag::identifier(DESCRIPTOR_EVENT_ID))
+                .custom_created_at(Timestamp::from(created_at))
+                .sign_with_keys(keys)
+                .unwrap()
+        };
+        let descriptor = EcDescriptor::new("AQID", None);
+        let events = vec![
+            descriptor_event(&ec_keys, &EcDescriptor::new("old", None).as_json(), 1_000),
+            descriptor_event(&ec_keys, &descriptor.as_json(), 2_000),
+            // Not signed by the EC
+            descriptor_event(&Keys::generate(), &EcDescriptor::new("fake", None).as_json(), 3_000),
+        ];
+        assert_eq!(published_descriptor(events, &ec_keys.public_key()), Some(descriptor));
+        assert_eq!(published_descriptor([descriptor_event(&ec_keys, "{}", 1_000)], &ec_keys.public_key()), None);
+    }
+}
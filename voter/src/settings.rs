@@ -1,48 +1,388 @@
 use crate::SETTINGS;
+use crate::i18n::{LANGUAGES, Locale, Text, tr};
+use crate::keymap::Keymap;
+use criptocracia_protocol::log_file::LogRotation;
 
-use serde::Deserialize;
+use anyhow::Context;
+use nostr_connect::prelude::NostrConnectURI;
+use nostr_sdk::prelude::{PublicKey, RelayUrl};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
     env, fs,
+    net::SocketAddr,
     path::{Path, PathBuf},
 };
 
-#[derive(Debug, Deserialize)]
+/// Prefix of the environment variables that override the settings in
+/// `load_settings`, e.g. `VOTER_EC_PUBLIC_KEY` or `VOTER_RELAYS` (comma separated)
+pub const ENV_PREFIX: &str = "VOTER";
+
+/// Log levels accepted in the settings
+pub const LOG_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Settings {
     pub secret_key: String,
+    /// NIP-46 remote signer (`bunker://` URI) holding the voter's key, used
+    /// instead of `secret_key` so the client never holds it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bunker: Option<String>,
     pub ec_public_key: String,
     pub relays: Vec<String>,
     pub log_level: String,
+    /// Size and age app.log is rotated at, and rotated files kept
+    #[serde(default)]
+    pub log_rotation: LogRotation,
+    /// Language of the UI
+    #[serde(default = "default_language")]
+    pub language: String,
+    /// Keep the candidate chosen in each election in the vote history
+    #[serde(default)]
+    pub record_choice: bool,
+    /// Key bindings replacing the defaults, by action name
+    #[serde(default)]
+    pub keys: HashMap<String, Vec<String>>,
+    /// NIP-13 difficulty mined on the messages to the EC, when it requires
+    /// proof of work (0 = none)
+    #[serde(default)]
+    pub pow: u8,
+    /// SOCKS5 proxy every relay connection goes through, e.g. Tor at
+    /// `127.0.0.1:9050`. Needed for `.onion` relays
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
 }
 
-/// Constructs (or copies) the configuration file and loads it
-pub fn init_settings() -> &'static Settings {
-    SETTINGS.get_or_init(|| {
-        // HOME and package name at compile time
-        let home_dir = dirs::home_dir().expect("Could not find home directory");
-        let package_name = env!("CARGO_PKG_NAME");
-        let hidden_dir = home_dir.join(format!(".{package_name}"));
-        let hidden_file = hidden_dir.join("settings.toml");
+fn default_language() -> String {
+    "en".to_string()
+}
+
+impl Settings {
+    /// Checks the EC pubkey, relay URLs, log level, language and key bindings
+    pub fn validate(&self) -> Result<(), String> {
+        PublicKey::parse(&self.ec_public_key)
+            .map_err(|e| format!("Invalid EC public key: {}", e))?;
+        if let Some(bunker) = &self.bunker {
+            match NostrConnectURI::parse(bunker) {
+                Ok(uri) if uri.is_bunker() => {}
+                Ok(_) => return Err(format!("Not a bunker URI: {}", bunker)),
+                Err(e) => return Err(format!("Invalid bunker URI {}: {}", bunker, e)),
+            }
+        }
+        if self.relays.is_empty() {
+            return Err("At least one relay is required".into());
+        }
+        if let Some(proxy) = &self.proxy {
+            proxy
+                .parse::<SocketAddr>()
+                .map_err(|e| format!("Invalid proxy address {}: {}", proxy, e))?;
+        }
+        for relay in &self.relays {
+            let url = RelayUrl::parse(relay).map_err(|e| format!("Invalid relay URL {}: {}", relay, e))?;
+            if url.is_onion() && self.proxy.is_none() {
+                return Err(format!("Onion relay {} needs a proxy", relay));
+            }
+        }
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            return Err(format!(
+                "Invalid log level {}, expected one of: {}",
+                self.log_level,
+                LOG_LEVELS.join(", ")
+            ));
+        }
+        if Locale::from_code(&self.language).is_none() {
+            return Err(format!(
+                "Invalid language {}, expected one of: {}",
+                self.language,
+                LANGUAGES.join(", ")
+            ));
+        }
+        Keymap::from_config(&self.keys)?;
+        Ok(())
+    }
+
+    /// Address of the SOCKS5 proxy, if one is set and valid
+    pub fn proxy_addr(&self) -> Option<SocketAddr> {
+        self.proxy.as_deref().and_then(|proxy| proxy.parse().ok())
+    }
+
+    /// Writes the settings to a TOML file
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+}
 
-        // Path to the settings.toml included in the repo (next to Cargo.toml)
-        let default_file: PathBuf = Path::new(env!("CARGO_MANIFEST_DIR")).join("settings.toml");
+/// Fields of the settings screen, edited as text
+pub struct SettingsForm {
+    pub fields: [(&'static str, String); 5],
+    pub selected: usize,
+}
 
-        // Create ~/.voter if it doesn't exist
-        if !hidden_dir.exists() {
-            fs::create_dir(&hidden_dir).expect("The configuration directory could not be created");
+impl SettingsForm {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            fields: [
+                (tr(Text::SettingsRelays), settings.relays.join(", ")),
+                (tr(Text::SettingsEcPubkey), settings.ec_public_key.clone()),
+                (tr(Text::SettingsLogLevel), settings.log_level.clone()),
+                (tr(Text::SettingsLanguage), settings.language.clone()),
+                (
+                    tr(Text::SettingsRecordChoice),
+                    if settings.record_choice { "yes" } else { "no" }.to_string(),
+                ),
+            ],
+            selected: 0,
         }
+    }
+
+    pub fn select_prev(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn select_next(&mut self) {
+        if self.selected + 1 < self.fields.len() {
+            self.selected += 1;
+        }
+    }
+
+    /// Appends a character to the selected field
+    pub fn push(&mut self, c: char) {
+        self.fields[self.selected].1.push(c);
+    }
+
+    /// Removes the last character of the selected field
+    pub fn pop(&mut self) {
+        self.fields[self.selected].1.pop();
+    }
+
+    /// Builds the edited settings, keeping the values not shown in the form,
+    /// and validates them.
+    pub fn to_settings(&self, current: &Settings) -> Result<Settings, String> {
+        let relays = self.fields[0]
+            .1
+            .split(',')
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .map(String::from)
+            .collect();
+        let record_choice = match self.fields[4].1.trim().to_lowercase().as_str() {
+            "yes" => true,
+            "no" => false,
+            other => {
+                return Err(format!(
+                    "Invalid value {} to record the choice, expected yes or no",
+                    other
+                ));
+            }
+        };
+        let settings = Settings {
+            secret_key: current.secret_key.clone(),
+            bunker: current.bunker.clone(),
+            ec_public_key: self.fields[1].1.trim().to_string(),
+            relays,
+            log_level: self.fields[2].1.trim().to_lowercase(),
+            log_rotation: current.log_rotation,
+            language: self.fields[3].1.trim().to_lowercase(),
+            record_choice,
+            keys: current.keys.clone(),
+            pow: current.pow,
+            proxy: current.proxy.clone(),
+        };
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+/// Directory holding the voter's settings and local data (`~/.voter`)
+pub fn app_dir() -> PathBuf {
+    // HOME and package name at compile time
+    let home_dir = dirs::home_dir().expect("Could not find home directory");
+    let package_name = env!("CARGO_PKG_NAME");
+    home_dir.join(format!(".{package_name}"))
+}
+
+/// Path of the voter's settings file (`~/.voter/settings.toml`)
+pub fn settings_file() -> PathBuf {
+    app_dir().join("settings.toml")
+}
 
-        // Copy settings.toml if it isn't already in ~/.voter
-        if !hidden_file.exists() {
-            fs::copy(&default_file, &hidden_file).expect("Could not copy default settings.toml");
+/// Constructs (or copies) the configuration file and loads it
+pub fn init_settings() -> anyhow::Result<&'static Settings> {
+    if let Some(settings) = SETTINGS.get() {
+        return Ok(settings);
+    }
+    let settings = load_settings(&ensure_settings_file()?, false)?;
+    Ok(SETTINGS.get_or_init(|| settings))
+}
+
+/// Copies the default settings to `~/.voter/settings.toml` if the file
+/// doesn't exist yet, and returns its path.
+pub fn ensure_settings_file() -> anyhow::Result<PathBuf> {
+    let hidden_dir = app_dir();
+    let hidden_file = settings_file();
+
+    // Path to the settings.toml included in the repo (next to Cargo.toml)
+    let default_file: PathBuf = Path::new(env!("CARGO_MANIFEST_DIR")).join("settings.toml");
+
+    // Create ~/.voter if it doesn't exist
+    if !hidden_dir.exists() {
+        fs::create_dir(&hidden_dir).context("The configuration directory could not be created")?;
+    }
+
+    // Copy settings.toml if it isn't already in ~/.voter
+    if !hidden_file.exists() {
+        fs::copy(&default_file, &hidden_file).context("Could not copy default settings.toml")?;
+    }
+    Ok(hidden_file)
+}
+
+/// Loads the settings from a TOML file. With `with_env`, the `VOTER_*`
+/// environment variables take precedence over the values of the file.
+pub fn load_settings(file: &Path, with_env: bool) -> anyhow::Result<Settings> {
+    load(file, with_env.then(|| env::vars().collect()))
+}
+
+fn load(file: &Path, env_vars: Option<HashMap<String, String>>) -> anyhow::Result<Settings> {
+    // Use the `config` crate to deserialize to the Settings struct
+    let mut builder = config::Config::builder().add_source(config::File::from(file));
+    if let Some(vars) = env_vars {
+        builder = builder.add_source(
+            config::Environment::with_prefix(ENV_PREFIX)
+                .try_parsing(true)
+                .list_separator(",")
+                .with_list_parse_key("relays")
+                .source(Some(vars)),
+        );
+    }
+    let cfg = builder.build().context("settings.toml malformed")?;
+
+    cfg.try_deserialize::<Settings>()
+        .context("Error deserializing settings.toml")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings {
+            secret_key: "30df83c45dee3b379c91be29cbbf6ecdbcfd8e9d96979e98b9e8162505d2047a".into(),
+            bunker: None,
+            ec_public_key: "0000001ace57d0da17fc18562f4658ac6d093b2cc8bb7bd44853d0c196e24a9c".into(),
+            relays: vec!["wss://relay.mostro.network".into()],
+            log_level: "info".into(),
+            log_rotation: LogRotation::default(),
+            language: "es".into(),
+            record_choice: true,
+            keys: HashMap::from([("quit".into(), vec!["x".into()])]),
+            pow: 0,
+            proxy: None,
         }
+    }
+
+    #[test]
+    fn test_form_validates_edited_values() {
+        let current = settings();
+        let mut form = SettingsForm::new(&current);
+        form.push(',');
+        form.push(' ');
+        "wss://nos.lol".chars().for_each(|c| form.push(c));
+        let edited = form.to_settings(&current).unwrap();
+        assert_eq!(edited.relays, vec!["wss://relay.mostro.network", "wss://nos.lol"]);
+        assert_eq!(edited.secret_key, current.secret_key);
+
+        form.select_next();
+        form.pop();
+        assert!(form.to_settings(&current).unwrap_err().contains("EC public key"));
+        form.push('c');
+
+        form.select_next();
+        assert_eq!(form.selected, 2);
+        form.fields[2].1 = "verbose".into();
+        assert!(form.to_settings(&current).unwrap_err().contains("log level"));
+
+        form.fields[0].1 = "not a url".into();
+        form.fields[2].1 = "DEBUG".into();
+        assert!(form.to_settings(&current).unwrap_err().contains("relay URL"));
+
+        form.fields[0].1 = "wss://nos.lol".into();
+        form.fields[3].1 = "fr".into();
+        assert!(form.to_settings(&current).unwrap_err().contains("language"));
+
+        form.fields[3].1 = "en".into();
+        assert_eq!(form.fields[4].1, "yes");
+        form.fields[4].1 = "No".into();
+        assert!(!form.to_settings(&current).unwrap().record_choice);
+        form.fields[4].1 = "maybe".into();
+        assert!(form.to_settings(&current).unwrap_err().contains("record the choice"));
+    }
 
-        // Use the `config` crate to deserialize to the Settings struct
-        let cfg = config::Config::builder()
-            .add_source(config::File::from(hidden_file))
+    #[test]
+    fn test_save_and_reload() {
+        let path = env::temp_dir().join(format!("voter-settings-{}.toml", std::process::id()));
+        let saved = settings();
+        saved.save(&path).unwrap();
+
+        let loaded: Settings = config::Config::builder()
+            .add_source(config::File::from(path.clone()))
             .build()
-            .expect("settings.toml malformed");
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.ec_public_key, saved.ec_public_key);
+        assert_eq!(loaded.relays, saved.relays);
+        assert_eq!(loaded.log_level, saved.log_level);
+        assert_eq!(loaded.language, saved.language);
+        assert_eq!(loaded.record_choice, saved.record_choice);
+        assert_eq!(loaded.keys, saved.keys);
+        assert_eq!(loaded.bunker, None);
+
+        let mut remote = settings();
+        remote.bunker = Some("bunker://79dff8f82963424e0bb02708a22e44b4980893e3a4be0fa3cb60a43b946764e3?relay=wss://relay.nsec.app".into());
+        assert!(remote.validate().is_ok());
+        remote.save(&path).unwrap();
+        let loaded = load(&path, None).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.bunker, remote.bunker);
+        remote.bunker = Some("nsec1abc".into());
+        assert!(remote.validate().unwrap_err().contains("bunker URI"));
+    }
+
+    #[test]
+    fn test_proxy_and_onion_relays() {
+        let mut settings = settings();
+        settings.relays.push("ws://2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion".into());
+        assert!(settings.validate().unwrap_err().contains("needs a proxy"));
+        assert_eq!(settings.proxy_addr(), None);
+
+        settings.proxy = Some("127.0.0.1:9050".into());
+        assert!(settings.validate().is_ok());
+        assert_eq!(settings.proxy_addr(), Some("127.0.0.1:9050".parse().unwrap()));
+
+        settings.proxy = Some("localhost".into());
+        assert!(settings.validate().unwrap_err().contains("proxy address"));
+    }
+
+    #[test]
+    fn test_environment_overrides_file() {
+        let path = env::temp_dir().join(format!("voter-settings-env-{}.toml", std::process::id()));
+        settings().save(&path).unwrap();
+        let vars = HashMap::from([
+            ("VOTER_RELAYS".to_string(), "wss://nos.lol,wss://relay.damus.io".to_string()),
+            ("VOTER_LOG_LEVEL".to_string(), "debug".to_string()),
+            ("VOTER_RECORD_CHOICE".to_string(), "false".to_string()),
+            ("OTHER_LOG_LEVEL".to_string(), "error".to_string()),
+        ]);
 
-        cfg.try_deserialize::<Settings>()
-            .expect("Error deserializing settings.toml")
-    })
+        let loaded = load(&path, Some(vars)).unwrap();
+        let unchanged = load(&path, None).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.relays, vec!["wss://nos.lol", "wss://relay.damus.io"]);
+        assert_eq!(loaded.log_level, "debug");
+        assert!(!loaded.record_choice);
+        assert_eq!(loaded.ec_public_key, settings().ec_public_key);
+        assert_eq!(unchanged.log_level, "info");
+    }
 }
@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use nostr_connect::prelude::*;
+use nostr_sdk::prelude::{ConnectionMode, RelayOptions};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::settings::app_dir;
+
+/// Time the remote signer is given to answer each request, long enough for
+/// the voter to approve it on the signing device.
+const REMOTE_SIGNER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Signs and decrypts the voter's messages: the local key, or a NIP-46
+/// remote signer ("bunker") so the client never holds it.
+#[derive(Debug, Clone)]
+pub struct VoterSigner {
+    pub signer: Arc<dyn NostrSigner>,
+    pub public_key: PublicKey,
+    /// Keys the local token store is encrypted with. With a remote signer
+    /// these are the client's own NIP-46 keys, so reading the stored tokens
+    /// doesn't need a round trip to the signing device.
+    pub store_keys: Keys,
+}
+
+impl VoterSigner {
+    pub fn local(keys: Keys) -> Self {
+        Self {
+            signer: Arc::new(keys.clone()),
+            public_key: keys.public_key(),
+            store_keys: keys,
+        }
+    }
+
+    /// The voter's own keys, unless they are held by a remote signer
+    pub fn local_keys(&self) -> Option<&Keys> {
+        (self.store_keys.public_key() == self.public_key).then_some(&self.store_keys)
+    }
+
+    /// Connects to the remote signer of a `bunker://` URI, through the SOCKS5
+    /// `proxy` if any, and asks for the voter's public key. The signer may
+    /// give an URL to approve the connection, passed to `on_auth_url`.
+    pub async fn remote(bunker_uri: &str, proxy: Option<SocketAddr>, on_auth_url: fn(&Url)) -> Result<Self> {
+        let uri = NostrConnectURI::parse(bunker_uri).context("Invalid bunker URI")?;
+        let app_keys = load_app_keys(&app_dir().join("nostr-connect.key"))?;
+        let opts = proxy.map(|addr| RelayOptions::new().connection_mode(ConnectionMode::proxy(addr)));
+        let mut connect = NostrConnect::new(uri, app_keys.clone(), REMOTE_SIGNER_TIMEOUT, opts)?;
+        connect.auth_url_handler(AuthUrl(on_auth_url));
+        let public_key = connect
+            .get_public_key()
+            .await
+            .context("The remote signer didn't answer")?;
+        log::info!("Remote signer connected for {}", public_key);
+        Ok(Self {
+            signer: Arc::new(connect),
+            public_key,
+            store_keys: app_keys,
+        })
+    }
+}
+
+/// Passes the URL given by the remote signer to approve a request.
+#[derive(Debug, Clone)]
+struct AuthUrl(fn(&Url));
+
+impl AuthUrlHandler for AuthUrl {
+    fn on_auth_url(&self, auth_url: Url) -> BoxedFuture<'_, Result<(), Box<dyn std::error::Error>>> {
+        let notify = self.0;
+        Box::pin(async move {
+            notify(&auth_url);
+            Ok(())
+        })
+    }
+}
+
+/// Reads the keys this client uses to talk to remote signers, creating them
+/// the first time. Keeping them lets the signer remember its approval.
+fn load_app_keys(path: &Path) -> Result<Keys> {
+    if let Ok(secret) = fs::read_to_string(path) {
+        return Keys::parse(secret.trim()).with_context(|| format!("Invalid keys in {}", path.display()));
+    }
+    let keys = Keys::generate();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, keys.secret_key().to_secret_hex())?;
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_app_keys_are_kept() {
+        let path = std::env::temp_dir().join(format!("voter-nostr-connect-{}.key", std::process::id()));
+        let created = load_app_keys(&path).unwrap();
+        let loaded = load_app_keys(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(created.public_key(), loaded.public_key());
+
+        let local = VoterSigner::local(created.clone());
+        assert_eq!(local.public_key, created.public_key());
+        assert_eq!(local.store_keys.public_key(), created.public_key());
+    }
+}
@@ -0,0 +1,299 @@
+use anyhow::Result;
+use nostr_sdk::prelude::*;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{Row, SqlitePool};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::history::HistoryEntry;
+use crate::token::VoteToken;
+
+/// Local storage of the voter's tokens, so a restart between the token
+/// request and the vote doesn't lock the voter out of an election.
+/// Token state and vote history are encrypted to the voter's own Nostr key with NIP-44.
+pub struct TokenStore {
+    pool: SqlitePool,
+    keys: Keys,
+}
+
+impl TokenStore {
+    /// Opens (or creates) the token database at `path`.
+    pub async fn open(path: &Path, keys: Keys) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        Self::with_pool(pool, keys).await
+    }
+
+    async fn with_pool(pool: SqlitePool, keys: Keys) -> Result<Self> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS vote_tokens (
+                election_id TEXT PRIMARY KEY,
+                data TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS vote_history (
+                election_id TEXT PRIMARY KEY,
+                data TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS outbox (
+                event_id TEXT PRIMARY KEY,
+                event TEXT NOT NULL,
+                queued_at INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(Self { pool, keys })
+    }
+
+    /// Saves the token state of an election, replacing any previous one.
+    pub async fn save(&self, election_id: &str, token: &VoteToken) -> Result<()> {
+        let data = self.encrypt(token.to_json()?)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO vote_tokens (election_id, data, updated_at)
+            VALUES (?, ?, ?)
+            ON CONFLICT(election_id) DO UPDATE SET
+            data = excluded.data, updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(election_id)
+        .bind(data)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&self.pool)
+        .await?;
+
+        log::debug!("Token state saved for election {}", election_id);
+        Ok(())
+    }
+
+    /// Loads the token state of every election.
+    /// Entries that can't be decrypted with the current key are skipped.
+    pub async fn load_all(&self) -> Result<HashMap<String, VoteToken>> {
+        let rows = sqlx::query("SELECT election_id, data FROM vote_tokens")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut tokens = HashMap::new();
+        for row in rows {
+            let election_id: String = row.get("election_id");
+            let data: String = row.get("data");
+            let token = self.decrypt(data).and_then(|json| VoteToken::from_json(&json));
+            match token {
+                Ok(token) => {
+                    tokens.insert(election_id, token);
+                }
+                Err(e) => log::warn!("Skipping stored token for election {}: {}", election_id, e),
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    /// Saves the history entry of an election, replacing any previous one.
+    pub async fn save_history(&self, entry: &HistoryEntry) -> Result<()> {
+        let data = self.encrypt(serde_json::to_string(entry)?)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO vote_history (election_id, data, updated_at)
+            VALUES (?, ?, ?)
+            ON CONFLICT(election_id) DO UPDATE SET
+            data = excluded.data, updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(&entry.election_id)
+        .bind(data)
+        .bind(chrono::Utc::now().timestamp())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Loads the vote history, by election ID.
+    /// Entries that can't be decrypted with the current key are skipped.
+    pub async fn load_history(&self) -> Result<HashMap<String, HistoryEntry>> {
+        let rows = sqlx::query("SELECT election_id, data FROM vote_history")
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut history = HashMap::new();
+        for row in rows {
+            let election_id: String = row.get("election_id");
+            let data: String = row.get("data");
+            let entry = self
+                .decrypt(data)
+                .and_then(|json| Ok(serde_json::from_str::<HistoryEntry>(&json)?));
+            match entry {
+                Ok(entry) => {
+                    history.insert(election_id, entry);
+                }
+                Err(e) => log::warn!("Skipping history of election {}: {}", election_id, e),
+            }
+        }
+
+        Ok(history)
+    }
+
+    /// Queues a signed event that no relay accepted, to be sent once they are back.
+    /// Events are gift wraps, already encrypted to the EC, so they are stored as they are.
+    pub async fn queue_event(&self, event: &Event) -> Result<()> {
+        sqlx::query("INSERT OR IGNORE INTO outbox (event_id, event, queued_at) VALUES (?, ?, ?)")
+            .bind(event.id.to_hex())
+            .bind(event.as_json())
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Events waiting to be sent, oldest first.
+    pub async fn queued_events(&self) -> Result<Vec<Event>> {
+        let rows = sqlx::query("SELECT event FROM outbox ORDER BY queued_at, rowid")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut events = Vec::new();
+        for row in rows {
+            let json: String = row.get("event");
+            events.push(Event::from_json(json)?);
+        }
+        Ok(events)
+    }
+
+    /// Removes a sent event from the queue.
+    pub async fn dequeue_event(&self, event_id: &EventId) -> Result<()> {
+        sqlx::query("DELETE FROM outbox WHERE event_id = ?")
+            .bind(event_id.to_hex())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    fn encrypt(&self, json: String) -> Result<String> {
+        Ok(nip44::encrypt(
+            self.keys.secret_key(),
+            &self.keys.public_key(),
+            json,
+            nip44::Version::V2,
+        )?)
+    }
+
+    fn decrypt(&self, data: String) -> Result<String> {
+        Ok(nip44::decrypt(self.keys.secret_key(), &self.keys.public_key(), data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blind_rsa_signatures::SecretKey as RSASecretKey;
+
+    async fn memory_store(keys: Keys) -> (TokenStore, SqlitePool) {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        (TokenStore::with_pool(pool.clone(), keys).await.unwrap(), pool)
+    }
+
+    #[tokio::test]
+    async fn test_tokens_are_encrypted_and_restored() {
+        let sk = RSASecretKey::from_pem(include_str!("../../ec/ec_private.pem")).unwrap();
+        let pk = sk.public_key().unwrap();
+        let keys = Keys::generate();
+        let (store, pool) = memory_store(keys.clone()).await;
+
+        let (mut token, _) = VoteToken::request(&pk, criptocracia_protocol::TokenScheme::default(), None).unwrap();
+        store.save("abcd", &token).await.unwrap();
+        token.vote_sent = true;
+        store.save("abcd", &token).await.unwrap();
+
+        // Nothing is stored in clear text
+        let data: String = sqlx::query("SELECT data FROM vote_tokens")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("data");
+        assert!(!data.contains("vote_sent"));
+
+        let tokens = store.load_all().await.unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert!(tokens["abcd"].vote_sent);
+        assert_eq!(tokens["abcd"].nonce, token.nonce);
+
+        // Another key can't read the tokens
+        let other = TokenStore::with_pool(pool, Keys::generate()).await.unwrap();
+        assert!(other.load_all().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_history_is_encrypted_and_restored() {
+        let keys = Keys::generate();
+        let (store, pool) = memory_store(keys).await;
+
+        let mut entry = HistoryEntry::new("abcd");
+        entry.token_requested_at = Some(1_700_000_000);
+        store.save_history(&entry).await.unwrap();
+        entry.voted_at = Some(1_700_000_100);
+        entry.choices = vec![3];
+        store.save_history(&entry).await.unwrap();
+
+        let data: String = sqlx::query("SELECT data FROM vote_history")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("data");
+        assert!(!data.contains("choices"));
+
+        let history = store.load_history().await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history["abcd"], entry);
+
+        let other = TokenStore::with_pool(pool, Keys::generate()).await.unwrap();
+        assert!(other.load_history().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_outbox_keeps_order_until_sent() {
+        let keys = Keys::generate();
+        let (store, _) = memory_store(keys.clone()).await;
+        let first = EventBuilder::text_note("first").sign_with_keys(&keys).unwrap();
+        let second = EventBuilder::text_note("second").sign_with_keys(&keys).unwrap();
+
+        store.queue_event(&first).await.unwrap();
+        store.queue_event(&second).await.unwrap();
+        // Queuing the same event twice keeps one copy
+        store.queue_event(&first).await.unwrap();
+        let queued = store.queued_events().await.unwrap();
+        assert_eq!(queued.len(), 2);
+        assert_eq!(queued[0].id, first.id);
+
+        store.dequeue_event(&first.id).await.unwrap();
+        let queued = store.queued_events().await.unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].id, second.id);
+    }
+}
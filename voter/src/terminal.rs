@@ -0,0 +1,50 @@
+use anyhow::Result;
+use crossterm::cursor::Show;
+use crossterm::execute;
+use crossterm::terminal::{
+    EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode,
+};
+use std::io::stdout;
+
+/// Raw mode and alternate screen of the TUI, restored when dropped so the
+/// terminal is left usable whether the voter quits, returns an error or panics.
+pub struct TerminalGuard;
+
+impl TerminalGuard {
+    /// Sets the terminal in raw mode and switches to the alternate screen.
+    pub fn enter() -> Result<Self> {
+        enable_raw_mode()?;
+        if let Err(e) = execute!(stdout(), EnterAlternateScreen) {
+            restore();
+            return Err(e.into());
+        }
+        Ok(Self)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Leaves the alternate screen and raw mode. Errors are ignored, there is
+/// nothing left to do with them while shutting down.
+pub fn restore() {
+    let _ = disable_raw_mode();
+    let _ = execute!(stdout(), LeaveAlternateScreen, Show);
+}
+
+/// Logs every panic. A panic of the TUI loop, on the main thread, also restores
+/// the terminal before the message is printed, so it can be read. Panics of
+/// background tasks only end that task and would garble the screen if printed.
+pub fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        log::error!("Panic: {}", info);
+        if std::thread::current().name() == Some("main") {
+            restore();
+            default_hook(info);
+        }
+    }));
+}
@@ -0,0 +1,337 @@
+use anyhow::Result;
+use base64::engine::{Engine, general_purpose};
+use blind_rsa_signatures::PublicKey as RSAPublicKey;
+use blind_rsa_signatures::{MessageRandomizer, Secret, Signature};
+use criptocracia_protocol::TokenScheme;
+use criptocracia_voter_core::BlindToken;
+use nostr_sdk::prelude::{Keys, SecretKey};
+use num_bigint_dig::BigUint;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Blind signature state of a voter for one election.
+/// The hash of the nonce is blinded, sent to the EC, and the returned
+/// blind signature is unblinded into the token attached to the vote.
+#[derive(Debug, Clone)]
+pub struct VoteToken {
+    pub nonce: BigUint,                   // Nonce generated by the voter
+    pub h_n_bytes: Vec<u8>,               // Hash of the nonce
+    pub secret: Secret,                   // Secret used to blind the nonce
+    pub r: Option<MessageRandomizer>,     // Randomizer used to blind the nonce
+    pub token: Option<Signature>,         // Unblinded signature received from the EC
+    pub vote_sent: bool,                  // Vote already cast with this token
+    pub vote_keys: Option<Keys>,          // Throwaway keys the vote is sent with
+    pub receipt: Option<String>,          // Receipt event signed by the EC
+    pub scheme: TokenScheme,              // Blind signature scheme of the election
+    pub expiry: Option<u64>,              // End of the election the token is bound to
+}
+
+/// Serialized form of a `VoteToken`, binary fields encoded in Base64.
+#[derive(Serialize, Deserialize)]
+struct StoredVoteToken {
+    nonce: String,
+    h_n: String,
+    secret: String,
+    r: Option<String>,
+    token: Option<String>,
+    vote_sent: bool,
+    #[serde(default)]
+    vote_key: Option<String>,
+    #[serde(default)]
+    receipt: Option<String>,
+    #[serde(default)]
+    scheme: TokenScheme,
+    #[serde(default)]
+    expiry: Option<u64>,
+}
+
+impl VoteToken {
+    /// Generates a nonce and blinds its hash with the EC's RSA public key, in
+    /// the token scheme of the election. In elections with bound tokens,
+    /// `binding` is the election ID and end the hash is bound to.
+    /// Returns the token state and the Base64 blinded hash to send to the EC.
+    pub fn request(
+        ec_pub_key: &RSAPublicKey,
+        scheme: TokenScheme,
+        binding: Option<(&str, u64)>,
+    ) -> Result<(Self, String)> {
+        let (blind, blinded_b64) = BlindToken::request(ec_pub_key, scheme, binding)?;
+        let token = Self {
+            nonce: blind.nonce,
+            h_n_bytes: blind.h_n,
+            secret: blind.secret,
+            r: blind.r,
+            token: None,
+            vote_sent: false,
+            vote_keys: None,
+            receipt: None,
+            scheme,
+            expiry: blind.expiry,
+        };
+        Ok((token, blinded_b64))
+    }
+
+    /// Unblinds the Base64 blind signature sent by the EC and stores the token.
+    pub fn finalize(&mut self, ec_pub_key: &RSAPublicKey, blind_sig_b64: &str) -> Result<()> {
+        self.token = Some(self.blind().unblind(ec_pub_key, blind_sig_b64)?);
+        Ok(())
+    }
+
+    /// Builds the vote payload expected by the EC: `h_n:token:r:choices`,
+    /// each cryptographic part encoded in Base64 and the chosen candidate IDs
+    /// separated by commas, a single ID for plurality elections. Bound tokens
+    /// add `:nonce:expiry` for the EC to check the binding.
+    /// Returns `None` until the token has been received or without choices.
+    pub fn vote_payload(&self, choices: &[u8]) -> Option<String> {
+        Some(self.blind().vote_payload(self.token.as_ref()?, choices)?.encode())
+    }
+
+    /// Like `vote_payload`, with the choice encrypted to the tally key of an
+    /// election with an encrypted tally, among its `candidates` IDs sorted.
+    /// Returns `None` too unless the choice is a single one of the candidates.
+    pub fn encrypted_vote_payload(
+        &self,
+        choices: &[u8],
+        election_id: &str,
+        tally_key: &str,
+        candidates: &[u8],
+    ) -> Option<String> {
+        let token = self.token.as_ref()?;
+        Some(self.blind().encrypted_vote_payload(token, choices, election_id, tally_key, candidates)?.encode())
+    }
+
+    /// The blinding state, which the voter core builds the token and votes from
+    fn blind(&self) -> BlindToken {
+        BlindToken {
+            nonce: self.nonce.clone(),
+            h_n: self.h_n_bytes.clone(),
+            secret: self.secret.clone(),
+            r: self.r,
+            scheme: self.scheme,
+            expiry: self.expiry,
+        }
+    }
+
+    /// Keys the vote is sent with, generated on first use. They aren't linked
+    /// to the voter and are kept to read the EC's receipt for the vote.
+    pub fn vote_keys(&mut self) -> Keys {
+        self.vote_keys.get_or_insert_with(Keys::generate).clone()
+    }
+
+    /// Keys an anonymous token request is sent from, and answered to. They
+    /// are derived from the blinding secret, which the EC never sees, so a
+    /// restart can still read the answer and the vote can't be linked to them.
+    pub fn request_keys(&self) -> Result<Keys> {
+        let mut hasher = Sha256::new();
+        hasher.update(b"criptocracia/request-keys");
+        hasher.update(&self.secret.0);
+        Ok(Keys::new(SecretKey::from_slice(&hasher.finalize())?))
+    }
+
+    /// Serializes the token state to JSON.
+    pub fn to_json(&self) -> Result<String> {
+        let b64 = &general_purpose::STANDARD;
+        let stored = StoredVoteToken {
+            nonce: b64.encode(self.nonce.to_bytes_be()),
+            h_n: b64.encode(&self.h_n_bytes),
+            secret: b64.encode(&self.secret),
+            r: self.r.map(|r| b64.encode(r)),
+            token: self.token.as_ref().map(|t| b64.encode(t)),
+            vote_sent: self.vote_sent,
+            vote_key: self.vote_keys.as_ref().map(|k| k.secret_key().to_secret_hex()),
+            receipt: self.receipt.clone(),
+            scheme: self.scheme,
+            expiry: self.expiry,
+        };
+        Ok(serde_json::to_string(&stored)?)
+    }
+
+    /// Restores the token state from its JSON form.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let b64 = &general_purpose::STANDARD;
+        let stored: StoredVoteToken = serde_json::from_str(json)?;
+        let r = match stored.r {
+            Some(r) => {
+                let bytes: [u8; 32] = b64
+                    .decode(r)?
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Invalid randomizer length"))?;
+                Some(MessageRandomizer::from(bytes))
+            }
+            None => None,
+        };
+        let token = match stored.token {
+            Some(token) => Some(Signature::from(b64.decode(token)?)),
+            None => None,
+        };
+        Ok(Self {
+            nonce: BigUint::from_bytes_be(&b64.decode(stored.nonce)?),
+            h_n_bytes: b64.decode(stored.h_n)?,
+            secret: Secret::from(b64.decode(stored.secret)?),
+            r,
+            token,
+            vote_sent: stored.vote_sent,
+            vote_keys: stored.vote_key.map(|k| Keys::parse(&k)).transpose()?,
+            receipt: stored.receipt,
+            scheme: stored.scheme,
+            expiry: stored.expiry,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blind_rsa_signatures::{Options, SecretKey as RSASecretKey};
+    use criptocracia_protocol::{BlindTokenScheme, VotePayload};
+
+    #[test]
+    fn test_vote_payload_carries_valid_token() {
+        let sk = RSASecretKey::from_pem(include_str!("../../ec/ec_private.pem")).unwrap();
+        let pk = sk.public_key().unwrap();
+
+        let (mut vote_token, blinded_b64) = VoteToken::request(&pk, TokenScheme::default(), None).unwrap();
+        assert!(vote_token.vote_payload(&[1]).is_none());
+
+        // The EC signs the blinded hash
+        let blinded = general_purpose::STANDARD.decode(blinded_b64).unwrap();
+        let blind_sig = sk
+            .blind_sign(&mut rand::thread_rng(), &blinded, &Options::default())
+            .unwrap();
+        vote_token
+            .finalize(&pk, &general_purpose::STANDARD.encode(blind_sig))
+            .unwrap();
+
+        let payload = vote_token.vote_payload(&[2]).unwrap();
+        let parts: Vec<&str> = payload.split(':').collect();
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[3], "2");
+        assert!(vote_token.vote_payload(&[3, 1]).unwrap().ends_with(":3,1"));
+        assert!(vote_token.vote_payload(&[]).is_none());
+
+        // The EC verifies the token against h_n and the randomizer
+        let h_n = general_purpose::STANDARD.decode(parts[0]).unwrap();
+        let token = Signature::from(general_purpose::STANDARD.decode(parts[1]).unwrap());
+        let r: [u8; 32] = general_purpose::STANDARD.decode(parts[2]).unwrap().try_into().unwrap();
+        assert!(
+            token
+                .verify(&pk, Some(MessageRandomizer::from(r)), h_n, &Options::default())
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_deterministic_token_has_no_randomizer() {
+        let sk = RSASecretKey::from_pem(include_str!("../../ec/ec_private.pem")).unwrap();
+        let pk = sk.public_key().unwrap();
+        let scheme = TokenScheme::RsaPssDeterministic;
+
+        let (vote_token, blinded_b64) = VoteToken::request(&pk, scheme, None).unwrap();
+        assert!(vote_token.r.is_none());
+        let blinded = general_purpose::STANDARD.decode(blinded_b64).unwrap();
+        let blind_sig = scheme.sign(&sk, &blinded).unwrap();
+        let mut vote_token = VoteToken::from_json(&vote_token.to_json().unwrap()).unwrap();
+        vote_token
+            .finalize(&pk, &general_purpose::STANDARD.encode(blind_sig))
+            .unwrap();
+
+        let payload = VotePayload::parse(&vote_token.vote_payload(&[2]).unwrap()).unwrap();
+        assert!(payload.r.is_none());
+        let token = Signature::from(payload.token);
+        assert!(scheme.verify(&pk, &token, None, &payload.h_n));
+        assert!(!TokenScheme::RsaPssRandomized.verify(&pk, &token, None, &payload.h_n));
+    }
+
+    #[test]
+    fn test_bound_token() {
+        let sk = RSASecretKey::from_pem(include_str!("../../ec/ec_private.pem")).unwrap();
+        let pk = sk.public_key().unwrap();
+        let scheme = TokenScheme::RsaPssDeterministic;
+
+        let (vote_token, blinded_b64) = VoteToken::request(&pk, scheme, Some(("a1b2", 4_600))).unwrap();
+        let blinded = general_purpose::STANDARD.decode(blinded_b64).unwrap();
+        let blind_sig = scheme.sign(&sk, &blinded).unwrap();
+        let mut vote_token = VoteToken::from_json(&vote_token.to_json().unwrap()).unwrap();
+        vote_token
+            .finalize(&pk, &general_purpose::STANDARD.encode(blind_sig))
+            .unwrap();
+
+        // The EC recomputes the signed hash from the nonce, election and expiry
+        let payload = VotePayload::parse(&vote_token.vote_payload(&[2]).unwrap()).unwrap();
+        let binding = payload.binding.unwrap();
+        assert_eq!(binding.expiry, 4_600);
+        assert_eq!(binding.hash("a1b2"), payload.h_n);
+        assert_ne!(binding.hash("c3d4"), payload.h_n);
+        assert!(scheme.verify(&pk, &Signature::from(payload.token), None, &payload.h_n));
+    }
+
+    #[test]
+    fn test_encrypted_vote_payload() {
+        let sk = RSASecretKey::from_pem(include_str!("../../ec/ec_private.pem")).unwrap();
+        let pk = sk.public_key().unwrap();
+        let scheme = TokenScheme::RsaPssDeterministic;
+        let (mut vote_token, blinded_b64) = VoteToken::request(&pk, scheme, Some(("a1b2", 4_600))).unwrap();
+        let blinded = general_purpose::STANDARD.decode(blinded_b64).unwrap();
+        let blind_sig = scheme.sign(&sk, &blinded).unwrap();
+        vote_token
+            .finalize(&pk, &general_purpose::STANDARD.encode(blind_sig))
+            .unwrap();
+
+        let secret = Keys::generate().secret_key().clone();
+        let key = secret.public_key(&nostr_sdk::secp256k1::Secp256k1::new());
+        let payload = vote_token.encrypted_vote_payload(&[5], "a1b2", &key.to_string(), &[2, 5, 7]).unwrap();
+        let payload = VotePayload::parse(&payload).unwrap();
+        assert!(payload.choices.is_empty());
+        assert!(payload.encrypted.unwrap().verify(&key, 3, "a1b2", &payload.h_n));
+
+        // A single candidate of the election
+        assert!(vote_token.encrypted_vote_payload(&[4], "a1b2", &key.to_string(), &[2, 5, 7]).is_none());
+        assert!(vote_token.encrypted_vote_payload(&[2, 5], "a1b2", &key.to_string(), &[2, 5, 7]).is_none());
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let sk = RSASecretKey::from_pem(include_str!("../../ec/ec_private.pem")).unwrap();
+        let pk = sk.public_key().unwrap();
+
+        let (mut vote_token, blinded_b64) = VoteToken::request(&pk, TokenScheme::default(), None).unwrap();
+        let restored = VoteToken::from_json(&vote_token.to_json().unwrap()).unwrap();
+        assert_eq!(restored.nonce, vote_token.nonce);
+        assert!(restored.token.is_none());
+
+        // A restored pending request can still be finalized
+        let blinded = general_purpose::STANDARD.decode(blinded_b64).unwrap();
+        let blind_sig = sk
+            .blind_sign(&mut rand::thread_rng(), &blinded, &Options::default())
+            .unwrap();
+        let mut restored = restored;
+        restored
+            .finalize(&pk, &general_purpose::STANDARD.encode(&blind_sig))
+            .unwrap();
+        vote_token
+            .finalize(&pk, &general_purpose::STANDARD.encode(&blind_sig))
+            .unwrap();
+        restored.vote_sent = true;
+        let vote_keys = restored.vote_keys();
+
+        let mut restored = VoteToken::from_json(&restored.to_json().unwrap()).unwrap();
+        assert!(restored.vote_sent);
+        assert_eq!(restored.vote_keys().public_key(), vote_keys.public_key());
+        assert_eq!(restored.vote_payload(&[1]), vote_token.vote_payload(&[1]));
+    }
+
+    #[test]
+    fn test_finalize_rejects_invalid_signature() {
+        let sk = RSASecretKey::from_pem(include_str!("../../ec/ec_private.pem")).unwrap();
+        let pk = sk.public_key().unwrap();
+
+        let (mut vote_token, _) = VoteToken::request(&pk, TokenScheme::default(), None).unwrap();
+        assert!(vote_token.finalize(&pk, "not base64!").is_err());
+        assert!(
+            vote_token
+                .finalize(&pk, &general_purpose::STANDARD.encode([0u8; 256]))
+                .is_err()
+        );
+        assert!(vote_token.token.is_none());
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::Result;
+use base64::engine::{Engine, general_purpose};
+use blind_rsa_signatures::{MessageRandomizer, Secret, Signature};
+use criptocracia_protocol::TokenScheme;
+use nostr_sdk::prelude::*;
+use num_bigint_dig::BigUint;
+use serde::{Deserialize, Serialize};
+
+use crate::keystore;
+use crate::token::VoteToken;
+
+/// Prefix of exported tokens, so they are recognized when pasted or scanned.
+const PREFIX: &str = "criptocracia-token";
+
+/// scrypt work factor used to encrypt the bundle key, as for the voter's key.
+const LOG_N: u8 = 16;
+
+/// Unblinded token of an election, all a client needs to cast the vote.
+/// The blinding secret is left out: it isn't needed once the token is
+/// unblinded, and would link the token to the request seen by the EC.
+#[derive(Serialize, Deserialize)]
+struct TokenBundle {
+    election_id: String,
+    nonce: String,
+    h_n: String,
+    token: String,
+    r: Option<String>,
+    #[serde(default, skip_serializing_if = "TokenScheme::is_default")]
+    scheme: TokenScheme,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expiry: Option<u64>,
+}
+
+/// Exports the unblinded token of an election encrypted with a passphrase,
+/// as `criptocracia-token:<ncryptsec>:<payload>`. The bundle is encrypted with
+/// NIP-44 to throwaway keys, and their secret key with NIP-49 to the passphrase,
+/// so it fits in a QR code and any Nostr library can read it.
+pub fn export(election_id: &str, token: &VoteToken, passphrase: &str) -> Result<String> {
+    export_with(election_id, token, passphrase, LOG_N)
+}
+
+fn export_with(election_id: &str, token: &VoteToken, passphrase: &str, log_n: u8) -> Result<String> {
+    let b64 = &general_purpose::STANDARD;
+    let Some(signature) = &token.token else {
+        return Err(anyhow::anyhow!("Token of election {} not received yet", election_id));
+    };
+    let bundle = TokenBundle {
+        election_id: election_id.to_string(),
+        nonce: b64.encode(token.nonce.to_bytes_be()),
+        h_n: b64.encode(&token.h_n_bytes),
+        token: b64.encode(signature),
+        r: token.r.map(|r| b64.encode(r)),
+        scheme: token.scheme,
+        expiry: token.expiry,
+    };
+    let keys = Keys::generate();
+    let payload = nip44::encrypt(
+        keys.secret_key(),
+        &keys.public_key(),
+        serde_json::to_string(&bundle)?,
+        nip44::Version::V2,
+    )?;
+    let ncryptsec = keystore::encrypt_with(&keys, passphrase, log_n)?;
+    Ok(format!("{PREFIX}:{ncryptsec}:{payload}"))
+}
+
+/// Imports a token exported with `export`. Returns the election ID and
+/// the token, ready to vote.
+pub fn import(data: &str, passphrase: &str) -> Result<(String, VoteToken)> {
+    let b64 = &general_purpose::STANDARD;
+    let mut parts = data.trim().splitn(3, ':');
+    let (Some(PREFIX), Some(ncryptsec), Some(payload)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(anyhow::anyhow!("Not an exported token"));
+    };
+    let keys = keystore::decrypt(ncryptsec, passphrase)?;
+    let json = nip44::decrypt(keys.secret_key(), &keys.public_key(), payload)?;
+    let bundle: TokenBundle = serde_json::from_str(&json)?;
+    let r = match bundle.r {
+        Some(r) => {
+            let bytes: [u8; 32] = b64
+                .decode(r)?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid randomizer length"))?;
+            Some(MessageRandomizer::from(bytes))
+        }
+        None => None,
+    };
+    let token = VoteToken {
+        nonce: BigUint::from_bytes_be(&b64.decode(bundle.nonce)?),
+        h_n_bytes: b64.decode(bundle.h_n)?,
+        secret: Secret::from(Vec::new()),
+        r,
+        token: Some(Signature::from(b64.decode(bundle.token)?)),
+        vote_sent: false,
+        vote_keys: None,
+        receipt: None,
+        scheme: bundle.scheme,
+        expiry: bundle.expiry,
+    };
+    Ok((bundle.election_id, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blind_rsa_signatures::{Options, SecretKey as RSASecretKey};
+
+    #[test]
+    fn test_export_and_import() {
+        let sk = RSASecretKey::from_pem(include_str!("../../ec/ec_private.pem")).unwrap();
+        let pk = sk.public_key().unwrap();
+        let (mut token, blinded_b64) = VoteToken::request(&pk, TokenScheme::default(), Some(("a1b2", 4_600))).unwrap();
+        assert!(export_with("a1b2", &token, "secret", 4).is_err());
+
+        let blinded = general_purpose::STANDARD.decode(blinded_b64).unwrap();
+        let blind_sig = sk
+            .blind_sign(&mut rand::thread_rng(), &blinded, &Options::default())
+            .unwrap();
+        token
+            .finalize(&pk, &general_purpose::STANDARD.encode(blind_sig))
+            .unwrap();
+
+        let exported = export_with("a1b2", &token, "secret", 4).unwrap();
+        assert!(exported.starts_with("criptocracia-token:ncryptsec1"));
+        assert!(import(&exported, "wrong").is_err());
+        assert!(import("ncryptsec1abc", "secret").is_err());
+
+        let (election_id, imported) = import(&exported, "secret").unwrap();
+        assert_eq!(election_id, "a1b2");
+        assert_eq!(imported.nonce, token.nonce);
+        assert!(imported.secret.0.is_empty());
+        assert_eq!(imported.vote_payload(&[2]), token.vote_payload(&[2]));
+    }
+}
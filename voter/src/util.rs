@@ -3,17 +3,27 @@ use base64::engine::{Engine, general_purpose};
 use blind_rsa_signatures::PublicKey as RSAPublicKey;
 use chrono::Local;
 use fern::Dispatch;
+use nostr_sdk::prelude::*;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
 
-/// Initialize logger function
-pub fn setup_logger(level: &str) -> Result<(), fern::InitError> {
-    let log_level = match level.to_lowercase().as_str() {
+use crate::log_buffer;
+use criptocracia_protocol::log_file::{LogRotation, RotatingFile};
+
+/// Maps a log level name from the settings to a level filter
+pub fn log_level_filter(level: &str) -> log::LevelFilter {
+    match level.to_lowercase().as_str() {
         "trace" => log::LevelFilter::Trace,
         "debug" => log::LevelFilter::Debug,
         "info" => log::LevelFilter::Info,
         "warn" => log::LevelFilter::Warn,
         "error" => log::LevelFilter::Error,
         _ => log::LevelFilter::Info, // Default to Info for invalid values
-    };
+    }
+}
+
+/// Initialize logger function, rotating app.log as set in `rotation`
+pub fn setup_logger(level: &str, rotation: LogRotation) -> Result<(), fern::InitError> {
     Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
@@ -23,12 +33,26 @@ pub fn setup_logger(level: &str) -> Result<(), fern::InitError> {
                 message
             ))
         })
-        .level(log_level)
-        .chain(fern::log_file("app.log")?) // Guarda en logs/app.log
+        .chain(Box::new(RotatingFile::open("app.log", rotation)?) as Box<dyn std::io::Write + Send>)
+        // Also kept in memory for the Logs pane of the TUI
+        .chain(fern::Output::call(|record| {
+            log_buffer::push(record.level(), record.args().to_string())
+        }))
         .apply()?;
+    // Filter with the global max level, so it can be changed from the settings screen
+    log::set_max_level(log_level_filter(level));
     Ok(())
 }
 
+/// Locks a mutex, recovering the data if a task panicked while holding it.
+/// The state is still usable, and one failed task shouldn't take the TUI down.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        log::error!("Recovering state left by a panicked task");
+        poisoned.into_inner()
+    })
+}
+
 /// Loads RSA public key from Base64 enconded der public key and converts it
 /// to the `blind-rsa-signatures` type.
 pub fn get_ec_pubkey(b64_pubkey: &str) -> Result<RSAPublicKey> {
@@ -36,3 +60,69 @@ pub fn get_ec_pubkey(b64_pubkey: &str) -> Result<RSAPublicKey> {
 
     Ok(RSAPublicKey::from_der(&pub_der)?)
 }
+
+/// Nostr client whose relay connections go through the SOCKS5 `proxy`, if
+/// any. Reading public events needs no signer.
+pub fn nostr_client(signer: Option<Arc<dyn NostrSigner>>, proxy: Option<SocketAddr>) -> Client {
+    let mut opts = Options::new();
+    if let Some(addr) = proxy {
+        opts = opts.connection(Connection::new().proxy(addr).target(ConnectionTarget::All));
+    }
+    match signer {
+        Some(signer) => Client::builder().signer(signer).opts(opts).build(),
+        None => Client::builder().opts(opts).build(),
+    }
+}
+
+/// Gift wraps a rumor like `EventBuilder::gift_wrap`, mining the wrap to
+/// the given NIP-13 difficulty when the EC requires proof of work.
+pub async fn gift_wrap<T>(signer: &T, receiver: &PublicKey, rumor: UnsignedEvent, pow: u8) -> Result<Event>
+where
+    T: NostrSigner,
+{
+    if pow == 0 {
+        return Ok(EventBuilder::gift_wrap(signer, receiver, rumor, None).await?);
+    }
+    let seal = EventBuilder::seal(signer, receiver, rumor).await?.sign(signer).await?;
+    // Same as `EventBuilder::gift_wrap_from_seal`, which can't mine
+    let keys = Keys::generate();
+    let content = nip44::encrypt(keys.secret_key(), receiver, seal.as_json(), nip44::Version::default())?;
+    Ok(EventBuilder::new(Kind::GiftWrap, content)
+        .tag(Tag::public_key(*receiver))
+        .custom_created_at(Timestamp::tweaked(nip59::RANGE_RANDOM_TIMESTAMP_TWEAK))
+        .pow(pow)
+        .sign_with_keys(&keys)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_recovers_poisoned_mutex() {
+        let state = Arc::new(Mutex::new(1));
+        let cloned = Arc::clone(&state);
+        let _ = std::thread::spawn(move || {
+            let mut value = cloned.lock().unwrap();
+            *value = 2;
+            panic!("task failed while holding the lock");
+        })
+        .join();
+
+        assert!(state.is_poisoned());
+        assert_eq!(*lock(&state), 2);
+    }
+
+    #[tokio::test]
+    async fn test_gift_wrap_with_pow() {
+        let (voter, ec) = (Keys::generate(), Keys::generate());
+        let rumor = EventBuilder::text_note("hello").build(voter.public_key());
+        let event = gift_wrap(&voter, &ec.public_key(), rumor, 8).await.unwrap();
+        assert!(event.check_pow(8));
+        assert_eq!(event.kind, Kind::GiftWrap);
+
+        let unwrapped = nip59::extract_rumor(&ec, &event).await.unwrap();
+        assert_eq!(unwrapped.sender, voter.public_key());
+        assert_eq!(unwrapped.rumor.content, "hello");
+    }
+}